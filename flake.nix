@@ -15,8 +15,112 @@
     };
   };
 
-  outputs = { self, nixpkgs, utils, ... }:
+  outputs = { self, nixpkgs, utils, ... }@inputs:
   {
+    nixosModules.agent = { config, lib, pkgs, ... }:
+      let
+        cfg = config.services.deploy-rs-agent;
+      in
+      {
+        options.services.deploy-rs-agent = {
+          enable = lib.mkEnableOption "the deploy-rs agent, accepting deployments over mutual TLS";
+
+          package = lib.mkOption {
+            type = lib.types.package;
+            default = self.packages.${pkgs.stdenv.hostPlatform.system}.default;
+            description = "The deploy-rs package providing `deploy-rs-agent`.";
+          };
+
+          port = lib.mkOption {
+            type = lib.types.port;
+            default = 7422;
+            description = "Port the agent listens on, has to match `agentPort` in the deploy configuration.";
+          };
+
+          openFirewall = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = "Whether to open the agent port in the firewall.";
+          };
+
+          cert = lib.mkOption {
+            type = lib.types.str;
+            description = "Path to the certificate of this node.";
+          };
+
+          key = lib.mkOption {
+            type = lib.types.str;
+            description = "Path to the private key of the node certificate.";
+          };
+
+          ca = lib.mkOption {
+            type = lib.types.str;
+            description = "Path to the CA certificate deployer certificates have to be signed by.";
+          };
+
+          trustedKeys = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Keys closures have to be signed by. The keys from `nix.settings.trusted-public-keys` apply if empty.";
+          };
+
+          allowCommands = lib.mkOption {
+            type = lib.types.bool;
+            default = false;
+            description = "Whether to run any command deployers send as root, needed by e.g. `deploy exec`, snapshots, migrations and profiles of users other than root. Otherwise deployers can only activate closures signed by a trusted key.";
+          };
+
+          allowedDeployers = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Certificate common names of the deployers allowed to use the agent. Any deployer certificate (`issue-cert --deployer`) signed by the CA is accepted if empty.";
+          };
+
+          allowedEnv = lib.mkOption {
+            type = lib.types.listOf lib.types.str;
+            default = [ ];
+            description = "Variables activations may be given, such as the names in `activationEnv` of the profiles deployed through the agent. `PATH` and `LD_*` never are.";
+          };
+
+          tempPath = lib.mkOption {
+            type = lib.types.str;
+            default = "/tmp";
+            description = "Directory activations keep their temporary files in, has to match `tempPath` in the deploy configuration.";
+          };
+        };
+
+        config = lib.mkIf cfg.enable {
+          networking.firewall.allowedTCPPorts = lib.optional cfg.openFirewall cfg.port;
+
+          systemd.services.deploy-rs-agent = {
+            description = "deploy-rs agent";
+            wantedBy = [ "multi-user.target" ];
+            after = [ "network-online.target" ];
+            wants = [ "network-online.target" ];
+            path = [ config.nix.package pkgs.socat pkgs.bash pkgs.gnutar ];
+
+            # Activation restarts services, the agent must not be stopped halfway through it
+            restartIfChanged = false;
+
+            serviceConfig = {
+              ExecStart = lib.concatStringsSep " " ([
+                "${cfg.package}/bin/deploy-rs-agent serve"
+                "--port ${toString cfg.port}"
+                "--cert ${cfg.cert}"
+                "--key ${cfg.key}"
+                "--ca ${cfg.ca}"
+                "--temp-path ${lib.escapeShellArg cfg.tempPath}"
+              ]
+              ++ map (key: "--trusted-key ${lib.escapeShellArg key}") cfg.trustedKeys
+              ++ map (name: "--allow-deployer ${lib.escapeShellArg name}") cfg.allowedDeployers
+              ++ map (name: "--allow-env ${lib.escapeShellArg name}") cfg.allowedEnv
+              ++ lib.optional cfg.allowCommands "--allow-commands");
+              Restart = "always";
+            };
+          };
+        };
+      };
+
     overlay = final: prev:
     let
       system = final.stdenv.hostPlatform.system;
@@ -107,7 +211,7 @@
 
             activate = deploy:
               let
-                profiles = builtins.concatLists (final.lib.mapAttrsToList (nodeName: node: final.lib.mapAttrsToList (profileName: profile: [ (toString profile.path) nodeName profileName ]) node.profiles) deploy.nodes);
+                profiles = builtins.concatLists (final.lib.mapAttrsToList (nodeName: node: final.lib.mapAttrsToList (profileName: profile: [ (toString profile.path) nodeName profileName ]) node.profiles) ((deploy.nodes or { }) // (deploy.nodeGroups or { })));
               in
               final.runCommand "deploy-rs-check-activate" { } ''
                 for x in ${builtins.concatStringsSep " " (map (p: builtins.concatStringsSep ":" p) profiles)}; do
@@ -160,6 +264,9 @@
 
         checks = {
           deploy-rs = self.packages.${system}.default.overrideAttrs (super: { doCheck = true; });
+        } // pkgs.lib.optionalAttrs pkgs.stdenv.isLinux {
+          # Boots VMs, so it's left out of `cargo test` and only runs here
+          vm-activation = import ./nix/tests { inherit pkgs inputs; };
         };
 
         lib = pkgs.deploy-rs.lib;
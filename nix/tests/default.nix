@@ -0,0 +1,101 @@
+# SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+#
+# SPDX-License-Identifier: MPL-2.0
+
+# End-to-end tests deploying to a NixOS VM, run with `nix build .#checks.x86_64-linux.vm-activation`
+{ pkgs, inputs }:
+let
+  inherit (pkgs) lib system;
+  inherit (import "${pkgs.path}/nixos/tests/ssh-keys.nix" pkgs) snakeOilPrivateKey;
+
+  profiles = import ./profiles.nix {
+    inherit (inputs) nixpkgs;
+    inherit system;
+    activate = inputs.self.lib.${system}.activate;
+  };
+
+  # Everything is pinned to what this flake uses, as the deployer VM has no network access
+  flakeInputs = ''
+    nixpkgs.url = "path:${inputs.nixpkgs}";
+    utils.url = "path:${inputs.utils}";
+    flake-compat = { url = "path:${inputs.flake-compat}"; flake = false; };
+    deploy-rs = {
+      url = "path:${inputs.self}";
+      inputs.nixpkgs.follows = "nixpkgs";
+      inputs.utils.follows = "utils";
+      inputs.flake-compat.follows = "flake-compat";
+    };
+  '';
+
+  flake = pkgs.writeText "flake.nix" (lib.replaceStrings
+    [ "##inputs##" "##system##" ]
+    [ flakeInputs system ]
+    (builtins.readFile ./deploy-flake.nix));
+in
+pkgs.nixosTest {
+  name = "deploy-rs-activation";
+
+  nodes = {
+    server = { ... }: {
+      imports = [ ./server.nix ];
+    };
+
+    deployer = { ... }: {
+      networking.extraHosts = "192.168.1.2 server";
+
+      nix.settings.experimental-features = [ "nix-command" "flakes" ];
+      environment.systemPackages = [ pkgs.deploy-rs.deploy-rs ];
+
+      # The deployer can evaluate the flake, but building has to be avoided, so every profile is put
+      # into its store up front
+      virtualisation.additionalPaths = builtins.attrValues profiles ++ [
+        inputs.nixpkgs
+        inputs.utils
+        inputs.flake-compat
+        inputs.self
+      ];
+
+      virtualisation.memorySize = 2048;
+      virtualisation.diskSize = 4096;
+    };
+  };
+
+  testScript = ''
+    start_all()
+
+    deployer.succeed("mkdir -p /root/deploy")
+    deployer.succeed("cp ${flake} /root/deploy/flake.nix")
+    deployer.succeed("cp ${./profiles.nix} /root/deploy/profiles.nix")
+    deployer.succeed("cp ${./server.nix} /root/deploy/server.nix")
+    deployer.succeed("cd /root/deploy && nix flake lock")
+
+    deployer.succeed("mkdir -m 700 -p /root/.ssh")
+    deployer.succeed("cp ${snakeOilPrivateKey} /root/.ssh/id_ecdsa")
+    deployer.succeed("chmod 600 /root/.ssh/id_ecdsa")
+
+    server.wait_for_unit("sshd.service")
+    deployer.wait_until_succeeds("ssh -o StrictHostKeyChecking=no root@server true", timeout=60)
+
+    def deploy(profile):
+        return deployer.execute(f"cd /root/deploy && deploy --skip-checks .#server.{profile} >&2")[0]
+
+    with subtest("the confirm path activates the new system"):
+        server.fail("test -e /run/current-system/sw/bin/hello")
+        assert deploy("hello") == 0, "deploying hello failed"
+        server.succeed("test -e /run/current-system/sw/bin/hello")
+        # Wherever the canary went, whether `/tmp` for root or a user's own directory in there, it's gone once confirmed
+        server.succeed("test -z \"$(find /tmp -name 'deploy-rs-canary-*')\"")
+
+    with subtest("magic rollback undoes a system the deployer can't reach"):
+        assert deploy("locked-out") != 0, "deploying a locked out system succeeded"
+        server.wait_until_succeeds("systemctl is-active sshd.service")
+        deployer.wait_until_succeeds("ssh -o StrictHostKeyChecking=no root@server true", timeout=60)
+        server.succeed("test -e /run/current-system/sw/bin/hello")
+        server.fail("test -e /run/current-system/sw/bin/figlet")
+
+    with subtest("auto rollback undoes a system failing its health check"):
+        assert deploy("unhealthy") != 0, "deploying an unhealthy system succeeded"
+        server.succeed("test -e /run/current-system/sw/bin/hello")
+        server.fail("test -e /run/current-system/sw/bin/figlet")
+  '';
+}
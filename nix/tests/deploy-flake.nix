@@ -0,0 +1,36 @@
+# SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+#
+# SPDX-License-Identifier: MPL-2.0
+
+# Copied into the deployer VM, `##inputs##` and `##system##` are filled in by ./default.nix
+{
+  inputs = {
+    ##inputs##
+  };
+
+  outputs = { self, nixpkgs, deploy-rs, ... }:
+    let
+      system = "##system##";
+
+      profiles = import ./profiles.nix {
+        inherit nixpkgs system;
+        activate = deploy-rs.lib.${system}.activate;
+      };
+    in
+    {
+      deploy.nodes.server = {
+        hostname = "server";
+        sshUser = "root";
+        sshOpts = [ "-o" "StrictHostKeyChecking=no" ];
+        fastConnection = true;
+        confirmTimeout = 10;
+
+        profiles = builtins.mapAttrs
+          (_: path: {
+            inherit path;
+            profilePath = "/nix/var/nix/profiles/system";
+          })
+          profiles;
+      };
+    };
+}
@@ -0,0 +1,35 @@
+# SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+#
+# SPDX-License-Identifier: MPL-2.0
+
+# Profiles deployed to the server, evaluated both by the test (to have them built ahead of time)
+# and by the flake in the deployer VM, which can't build anything without network access
+{ nixpkgs, system, activate }:
+let
+  mkServer = module: nixpkgs.lib.nixosSystem {
+    inherit system;
+    modules = [ ./server.nix module ];
+  };
+in
+{
+  hello = activate.nixos (mkServer ({ pkgs, ... }: {
+    environment.systemPackages = [ pkgs.hello ];
+  }));
+
+  # The new system works, but the deployer can't reach it anymore, so magic rollback has to kick in
+  locked-out = activate.nixos (mkServer ({ pkgs, ... }: {
+    environment.systemPackages = [ pkgs.hello pkgs.figlet ];
+    services.openssh.openFirewall = false;
+  }));
+
+  unhealthy = activate.custom
+    (mkServer ({ pkgs, ... }: {
+      environment.systemPackages = [ pkgs.hello pkgs.figlet ];
+    })).config.system.build.toplevel
+    ''
+      $PROFILE/bin/switch-to-configuration switch
+
+      # A health check for a service which never comes up
+      systemctl is-active --quiet never-started.service
+    '';
+}
@@ -0,0 +1,30 @@
+# SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+#
+# SPDX-License-Identifier: MPL-2.0
+
+# The target node, both as the VM the test starts and as the base of every system deployed to it,
+# so deployments keep the test driver's backdoor and the node's address
+{ pkgs, lib, modulesPath, ... }:
+let
+  inherit (import "${pkgs.path}/nixos/tests/ssh-keys.nix" pkgs) snakeOilPublicKey;
+in
+{
+  imports = [
+    "${modulesPath}/virtualisation/qemu-vm.nix"
+    "${modulesPath}/testing/test-instrumentation.nix"
+  ];
+
+  networking.hostName = "server";
+  networking.useDHCP = false;
+  networking.interfaces.eth1.ipv4.addresses = lib.mkForce [
+    { address = "192.168.1.2"; prefixLength = 24; }
+  ];
+
+  nix.settings.experimental-features = [ "nix-command" "flakes" ];
+
+  services.openssh.enable = true;
+  users.users.root.openssh.authorizedKeys.keys = [ snakeOilPublicKey ];
+
+  virtualisation.memorySize = 1024;
+  virtualisation.writableStore = true;
+}
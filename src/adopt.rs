@@ -0,0 +1,99 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use thiserror::Error;
+
+use crate::report::CommandFailure;
+use crate::transport::{NodeTarget, RunOnNodeError, Transport};
+
+/// How the closure of a profile found on a node is activated again, when a later deployment is rolled back to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Activation {
+    /// It was deployed with deploy-rs after all
+    DeployRs,
+    /// A NixOS system, e.g. from nixos-rebuild or colmena, which `bin/switch-to-configuration` switches to
+    NixOS,
+    /// Nothing that can be run, rolling back to it only points the profile back at it
+    None,
+}
+
+/// A profile made by another tool, as `deploy adopt` finds it on a node
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExistingProfile {
+    pub closure: String,
+    pub activation: Activation,
+}
+
+#[derive(Error, Debug)]
+pub enum AdoptError {
+    #[error("Failed to inspect the profile on the node: {0}")]
+    Run(RunOnNodeError),
+    #[error("Inspecting the profile on the node failed with {0}")]
+    Exit(CommandFailure),
+}
+
+/// Prints the closure the profile given as `$1` points to and how it's activated, or nothing if there's no profile
+const INSPECT_SCRIPT: &str = r#"[ -e "$1" ] || exit 0; p=$(readlink -f "$1"); echo "$p"; if [ -e "$p/deploy-rs-activate" ]; then echo deploy-rs; elif [ -x "$p/bin/switch-to-configuration" ]; then echo nixos; else echo none; fi"#;
+
+pub fn parse_existing_profile(output: &str) -> Option<ExistingProfile> {
+    let mut lines = output.lines().map(str::trim).filter(|x| !x.is_empty());
+
+    let closure = lines.next()?.to_string();
+    let activation = match lines.next() {
+        Some("deploy-rs") => Activation::DeployRs,
+        Some("nixos") => Activation::NixOS,
+        _ => Activation::None,
+    };
+
+    Some(ExistingProfile {
+        closure,
+        activation,
+    })
+}
+
+#[test]
+fn test_parse_existing_profile() {
+    assert_eq!(
+        parse_existing_profile("/nix/store/blah-nixos-system-web-1-23.11\nnixos\n"),
+        Some(ExistingProfile {
+            closure: "/nix/store/blah-nixos-system-web-1-23.11".to_string(),
+            activation: Activation::NixOS,
+        })
+    );
+    assert_eq!(
+        parse_existing_profile("/nix/store/blah-hello\nnone\n"),
+        Some(ExistingProfile {
+            closure: "/nix/store/blah-hello".to_string(),
+            activation: Activation::None,
+        })
+    );
+    assert_eq!(parse_existing_profile(""), None);
+}
+
+/// Finds out what the profile at `profile_path` on a node points to, if the node has it
+pub async fn inspect_profile(
+    transport: &dyn Transport,
+    target: &NodeTarget<'_>,
+    profile_path: &str,
+) -> Result<Option<ExistingProfile>, AdoptError> {
+    let command = format!(
+        "sh -c {} sh {}",
+        crate::shell_quote(INSPECT_SCRIPT),
+        crate::shell_quote(profile_path)
+    );
+
+    let output = transport
+        .run_on_node(target, &command, true)
+        .await
+        .map_err(AdoptError::Run)?;
+
+    match output.code {
+        Some(0) => (),
+        _ => return Err(AdoptError::Exit(CommandFailure::new(&command, &output))),
+    };
+
+    Ok(parse_existing_profile(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
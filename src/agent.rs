@@ -0,0 +1,696 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+pub const DEFAULT_AGENT_PORT: u16 = 7422;
+
+/// The first line a client sends to `deploy-rs-agent`, socat takes care of mutual TLS around it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AgentRequest {
+    /// Run a shell command as the agent's user, which agents only do when started with `--allow-commands`
+    Run { command: String },
+    /// Run the `activate-rs` of `closure`, a store path or a profile linking to one, with `args` and `env`, once the
+    /// closure is found to be signed by a key the agent trusts
+    Activate {
+        closure: String,
+        args: Vec<String>,
+        #[serde(default)]
+        env: ActivationEnv,
+    },
+    /// Remove the canary file at `path`, confirming an activation to its magic rollback
+    Confirm { path: String },
+    /// Report which of the given store paths are not valid on the node
+    QueryInvalid { paths: Vec<String> },
+    /// Copy `paths` from a binary cache sent as a tar archive of `size` bytes following the request line
+    ///
+    /// Going through a binary cache keeps the signatures, so the node checks them against its trusted keys.
+    Import { size: u64, paths: Vec<String> },
+}
+
+/// The variables an activation runs with, which can hold secrets and so are never printed with their values
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct ActivationEnv(pub Vec<(String, String)>);
+
+impl std::fmt::Debug for ActivationEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(key, _)| key))
+            .finish()
+    }
+}
+
+/// What the agent answers with, one per line, always ending with `AgentFrame::Exit`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AgentFrame {
+    Stdout { line: String },
+    Stderr { line: String },
+    Invalid { paths: Vec<String> },
+    Exit { code: Option<i32> },
+}
+
+#[test]
+fn test_agent_protocol() {
+    assert_eq!(
+        serde_json::to_string(&AgentRequest::Run {
+            command: "true".to_string()
+        })
+        .unwrap(),
+        r#"{"action":"run","command":"true"}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&AgentRequest::Import {
+            size: 10240,
+            paths: vec!["/nix/store/00000000000000000000000000000000-hello".to_string()]
+        })
+        .unwrap(),
+        r#"{"action":"import","size":10240,"paths":["/nix/store/00000000000000000000000000000000-hello"]}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<AgentFrame>(r#"{"type":"exit","code":0}"#).unwrap(),
+        AgentFrame::Exit { code: Some(0) }
+    );
+}
+
+/// What the names of the canary files of activations waiting for confirmation start with, see `make_lock_path`
+pub const CANARY_PREFIX: &str = "deploy-rs-canary-";
+
+/// Splits a command built by deploy into its words, undoing `shell_quote`. Nothing if it has anything else in it a
+/// shell would interpret, like variables or operators
+pub fn command_words(command: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => {
+                if let Some(x) = word.take() {
+                    words.push(x);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            'A'..='Z'
+            | 'a'..='z'
+            | '0'..='9'
+            | '/'
+            | '.'
+            | '_'
+            | '-'
+            | '='
+            | ':'
+            | '@'
+            | '+'
+            | ',' => word.get_or_insert_with(String::new).push(c),
+            _ => return None,
+        }
+    }
+
+    if let Some(x) = word {
+        words.push(x);
+    }
+
+    Some(words)
+}
+
+/// The typed request doing what `command` does, for the commands agents run without `--allow-commands`: running an
+/// `activate-rs` without sudo, with `env` setting its environment, and confirming activations
+pub fn typed_request(command: &str) -> Option<AgentRequest> {
+    let words = command_words(command)?;
+
+    if let [rm, path] = &words[..] {
+        let is_canary = path
+            .rsplit('/')
+            .next()
+            .map(|x| x.starts_with(CANARY_PREFIX))
+            == Some(true);
+
+        return match rm == "rm" && is_canary {
+            true => Some(AgentRequest::Confirm { path: path.clone() }),
+            false => None,
+        };
+    }
+
+    let mut words = words.into_iter().peekable();
+    let mut env = Vec::new();
+
+    while words.peek().map(|x| x.as_str()) == Some("env") {
+        words.next();
+
+        while let Some(i) = words.peek().and_then(|x| x.find('=')).filter(|i| *i > 0) {
+            let assignment = words.next()?;
+            env.push((assignment[..i].to_string(), assignment[i + 1..].to_string()));
+        }
+    }
+
+    let closure = words.next()?.strip_suffix("/activate-rs")?.to_string();
+
+    Some(AgentRequest::Activate {
+        closure,
+        args: words.collect(),
+        env: ActivationEnv(env),
+    })
+}
+
+#[test]
+fn test_typed_request() {
+    assert_eq!(
+        command_words(
+            r#"env 'DEPLOY_NOTE=it'\''s friday' /nix/store/abc-system/activate-rs activate"#
+        ),
+        Some(vec![
+            "env".to_string(),
+            "DEPLOY_NOTE=it's friday".to_string(),
+            "/nix/store/abc-system/activate-rs".to_string(),
+            "activate".to_string(),
+        ])
+    );
+    assert_eq!(command_words("echo $HOME"), None);
+    assert_eq!(command_words("true && rm -rf /"), None);
+
+    assert_eq!(
+        typed_request(
+            "env DEPLOY_RS_COLOR=never env 'DEPLOY_GIT_REV=abc123' /nix/store/abc-system/activate-rs activate \
+             '/nix/store/abc-system' '/nix/var/nix/profiles/system' --temp-path '/run/deploy-rs' --magic-rollback"
+        ),
+        Some(AgentRequest::Activate {
+            closure: "/nix/store/abc-system".to_string(),
+            args: vec![
+                "activate".to_string(),
+                "/nix/store/abc-system".to_string(),
+                "/nix/var/nix/profiles/system".to_string(),
+                "--temp-path".to_string(),
+                "/run/deploy-rs".to_string(),
+                "--magic-rollback".to_string(),
+            ],
+            env: ActivationEnv(vec![
+                ("DEPLOY_RS_COLOR".to_string(), "never".to_string()),
+                ("DEPLOY_GIT_REV".to_string(), "abc123".to_string()),
+            ]),
+        })
+    );
+    assert_eq!(
+        typed_request("rm '/run/deploy-rs/deploy-rs-canary-abc'"),
+        Some(AgentRequest::Confirm {
+            path: "/run/deploy-rs/deploy-rs-canary-abc".to_string()
+        })
+    );
+    assert_eq!(typed_request("rm '/etc/shadow'"), None);
+    assert_eq!(
+        typed_request("sudo -u alice /nix/store/abc-home/activate-rs activate"),
+        None
+    );
+    assert_eq!(typed_request("systemctl restart nginx"), None);
+
+    assert_eq!(
+        format!(
+            "{:?}",
+            ActivationEnv(vec![("DB_PASSWORD".to_string(), "hunter2".to_string())])
+        ),
+        r#"["DB_PASSWORD"]"#
+    );
+}
+
+/// The deployer's side of the mutual TLS connection to a node's agent
+#[derive(Debug, Clone)]
+pub struct AgentConnection<'a> {
+    pub hostname: &'a str,
+    pub port: u16,
+    pub cert: &'a str,
+    pub key: &'a str,
+    pub ca: &'a str,
+    /// Seconds of idle time after which TCP keep-alives are sent, so long requests survive idle timeouts
+    pub keep_alive_interval: Option<u16>,
+}
+
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("Failed to run socat to connect to the agent: {0}")]
+    Connect(std::io::Error),
+    #[error("Failed to talk to the agent: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Received an invalid frame from the agent: {0}")]
+    Frame(#[from] serde_json::Error),
+    #[error("The connection to the agent was closed before the request finished")]
+    Closed,
+}
+
+/// What a request produced on the node
+#[derive(Debug, Default)]
+pub struct AgentOutput {
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr_tail: Vec<String>,
+    pub invalid: Vec<String>,
+}
+
+pub fn build_socat_address(connection: &AgentConnection) -> String {
+    let mut address = format!(
+        "OPENSSL:{}:{},cert={},key={},cafile={},verify=1",
+        connection.hostname, connection.port, connection.cert, connection.key, connection.ca
+    );
+
+    if let Some(interval) = connection.keep_alive_interval {
+        address = format!(
+            "{},keepalive,keepidle={},keepintvl={}",
+            address, interval, interval
+        );
+    }
+
+    address
+}
+
+#[test]
+fn test_socat_address_builder() {
+    assert_eq!(
+        build_socat_address(&AgentConnection {
+            hostname: "web-1.example.com",
+            port: 7422,
+            cert: "/etc/deploy/deployer.crt",
+            key: "/etc/deploy/deployer.key",
+            ca: "/etc/deploy/ca.crt",
+            keep_alive_interval: None,
+        }),
+        "OPENSSL:web-1.example.com:7422,cert=/etc/deploy/deployer.crt,key=/etc/deploy/deployer.key,cafile=/etc/deploy/ca.crt,verify=1"
+    );
+
+    assert_eq!(
+        build_socat_address(&AgentConnection {
+            hostname: "web-1.example.com",
+            port: 7422,
+            cert: "/etc/deploy/deployer.crt",
+            key: "/etc/deploy/deployer.key",
+            ca: "/etc/deploy/ca.crt",
+            keep_alive_interval: Some(30),
+        }),
+        "OPENSSL:web-1.example.com:7422,cert=/etc/deploy/deployer.crt,key=/etc/deploy/deployer.key,cafile=/etc/deploy/ca.crt,verify=1,keepalive,keepidle=30,keepintvl=30"
+    );
+}
+
+/// Sends a request to the agent, relaying the command output unless `capture_stdout` is set
+pub async fn request(
+    connection: &AgentConnection<'_>,
+    request: &AgentRequest,
+    payload: Option<&str>,
+    capture_stdout: bool,
+) -> Result<AgentOutput, AgentError> {
+    let mut socat_command = Command::new("socat");
+    socat_command
+        .arg("-")
+        .arg(build_socat_address(connection))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+
+    debug!(
+        "Sending {:?} to the agent on {}:{}",
+        request, connection.hostname, connection.port
+    );
+
+    let mut socat = socat_command.spawn().map_err(AgentError::Connect)?;
+
+    // Stdin stays open until the exit frame arrives, as socat tears down the connection shortly after EOF
+    let mut stdin = socat.stdin.take().ok_or(AgentError::Closed)?;
+    let stdout = socat.stdout.take().ok_or(AgentError::Closed)?;
+
+    stdin
+        .write_all(format!("{}\n", serde_json::to_string(request)?).as_bytes())
+        .await?;
+
+    if let Some(payload) = payload {
+        let mut file = tokio::fs::File::open(payload).await?;
+        tokio::io::copy(&mut file, &mut stdin).await?;
+    }
+
+    stdin.flush().await?;
+
+    let mut output = AgentOutput::default();
+    let mut lines = BufReader::new(stdout).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            AgentFrame::Stdout { line } if capture_stdout => {
+                output.stdout.extend_from_slice(line.as_bytes());
+                output.stdout.push(b'\n');
+            }
+            AgentFrame::Stdout { line } => println!("{}", line),
+            AgentFrame::Stderr { line } => {
+                eprintln!("{}", line);
+                crate::transport::push_stderr_line(&mut output.stderr_tail, line);
+            }
+            AgentFrame::Invalid { paths } => output.invalid = paths,
+            AgentFrame::Exit { code } => {
+                output.code = code;
+
+                drop(stdin);
+                socat.wait().await?;
+
+                return Ok(output);
+            }
+        }
+    }
+
+    Err(AgentError::Closed)
+}
+
+/// A store path as reported by `nix path-info --json --sigs`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PathInfo {
+    #[serde(default)]
+    pub path: String,
+    pub nar_hash: String,
+    pub nar_size: u64,
+    #[serde(default)]
+    pub references: Vec<String>,
+    pub deriver: Option<String>,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    pub ca: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PathInfos {
+    List(Vec<PathInfo>),
+    Map(BTreeMap<String, Option<PathInfo>>),
+}
+
+/// Parses `nix path-info --json` output, which is a list in older Nix versions and a map keyed by path in newer ones
+pub fn parse_path_info(json: &str) -> Result<Vec<PathInfo>, serde_json::Error> {
+    Ok(match serde_json::from_str(json)? {
+        PathInfos::List(infos) => infos,
+        PathInfos::Map(infos) => infos
+            .into_iter()
+            .filter_map(|(path, info)| info.map(|info| PathInfo { path, ..info }))
+            .collect(),
+    })
+}
+
+fn store_path_hash(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.split('-').next().unwrap_or(name)
+}
+
+fn store_path_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+pub fn narinfo_file(path: &str) -> String {
+    format!("{}.narinfo", store_path_hash(path))
+}
+
+pub fn nar_file(path: &str) -> String {
+    format!("nar/{}.nar", store_path_hash(path))
+}
+
+/// Describes a path for an uncompressed `file://` binary cache, the NAR itself comes from `nix-store --dump`
+pub fn format_narinfo(info: &PathInfo) -> String {
+    let mut narinfo = format!(
+        "StorePath: {}\nURL: {}\nCompression: none\nNarHash: {}\nNarSize: {}\nReferences: {}\n",
+        info.path,
+        nar_file(&info.path),
+        info.nar_hash,
+        info.nar_size,
+        info.references
+            .iter()
+            .map(|x| store_path_name(x))
+            .collect::<Vec<&str>>()
+            .join(" ")
+    );
+
+    if let Some(ref deriver) = info.deriver {
+        narinfo += &format!("Deriver: {}\n", store_path_name(deriver));
+    }
+
+    for signature in &info.signatures {
+        narinfo += &format!("Sig: {}\n", signature);
+    }
+
+    if let Some(ref ca) = info.ca {
+        narinfo += &format!("CA: {}\n", ca);
+    }
+
+    narinfo
+}
+
+#[test]
+fn test_narinfo() {
+    let old_format = r#"[{"path":"/nix/store/00000000000000000000000000000000-hello","narHash":"sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s","narSize":120,"references":["/nix/store/11111111111111111111111111111111-glibc"],"deriver":"/nix/store/22222222222222222222222222222222-hello.drv","signatures":["cache.example.com-1:c2lnbmF0dXJl"]}]"#;
+    let new_format = r#"{"/nix/store/00000000000000000000000000000000-hello":{"narHash":"sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s","narSize":120,"references":["/nix/store/11111111111111111111111111111111-glibc"],"deriver":"/nix/store/22222222222222222222222222222222-hello.drv","signatures":["cache.example.com-1:c2lnbmF0dXJl"],"ca":null}}"#;
+
+    let infos = parse_path_info(old_format).unwrap();
+    assert_eq!(infos, parse_path_info(new_format).unwrap());
+
+    assert_eq!(
+        narinfo_file(&infos[0].path),
+        "00000000000000000000000000000000.narinfo"
+    );
+    assert_eq!(
+        format_narinfo(&infos[0]),
+        "StorePath: /nix/store/00000000000000000000000000000000-hello\n\
+         URL: nar/00000000000000000000000000000000.nar\n\
+         Compression: none\n\
+         NarHash: sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s\n\
+         NarSize: 120\n\
+         References: 11111111111111111111111111111111-glibc\n\
+         Deriver: 22222222222222222222222222222222-hello.drv\n\
+         Sig: cache.example.com-1:c2lnbmF0dXJl\n"
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum CertError {
+    #[error("Failed to write to the certificate directory: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to run openssl: {0}")]
+    Openssl(std::io::Error),
+    #[error("openssl resulted in a bad exit code: {0:?}")]
+    OpensslExit(Option<i32>),
+    #[error(
+        "`{0}` can't be a certificate name, only letters, digits and `.`, `-`, `_` and `:` can"
+    )]
+    InvalidName(String),
+}
+
+/// What a certificate signed by the CA is for. Node certificates can't be used to connect to agents, so a node's
+/// certificate getting out doesn't let anyone deploy to the other nodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertRole {
+    Node,
+    Deployer,
+}
+
+impl CertRole {
+    /// The organizational unit certificates are issued with, which agents check deployer certificates for
+    pub fn unit(self) -> &'static str {
+        match self {
+            CertRole::Node => "deploy-rs node",
+            CertRole::Deployer => "deploy-rs deployer",
+        }
+    }
+
+    fn key_usage(self) -> &'static str {
+        match self {
+            CertRole::Node => "serverAuth",
+            CertRole::Deployer => "clientAuth",
+        }
+    }
+}
+
+/// Subject alternative names for a certificate, so socat can check it against the address it connects to, and what
+/// the certificate may be used for
+fn build_cert_extensions(name: &str, role: CertRole) -> String {
+    let subject_alt_name = match name.parse::<std::net::IpAddr>() {
+        Ok(_) => format!("IP:{}", name),
+        Err(_) => format!("DNS:{}", name),
+    };
+
+    format!(
+        "subjectAltName={}\nextendedKeyUsage={}\n",
+        subject_alt_name,
+        role.key_usage()
+    )
+}
+
+#[test]
+fn test_cert_extensions_builder() {
+    assert_eq!(
+        build_cert_extensions("web-1.example.com", CertRole::Node),
+        "subjectAltName=DNS:web-1.example.com\nextendedKeyUsage=serverAuth\n"
+    );
+    assert_eq!(
+        build_cert_extensions("10.0.0.1", CertRole::Node),
+        "subjectAltName=IP:10.0.0.1\nextendedKeyUsage=serverAuth\n"
+    );
+    assert_eq!(
+        build_cert_extensions("ci", CertRole::Deployer),
+        "subjectAltName=DNS:ci\nextendedKeyUsage=clientAuth\n"
+    );
+}
+
+async fn run_openssl(args: &[&str]) -> Result<(), CertError> {
+    let mut openssl_command = Command::new("openssl");
+    openssl_command.args(args);
+
+    debug!("Running openssl command: {:?}", openssl_command);
+
+    let openssl_exit_status = openssl_command.status().await.map_err(CertError::Openssl)?;
+
+    match openssl_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(CertError::OpensslExit(a)),
+    }
+}
+
+/// Makes the file openssl writes a private key to readable only by its owner, before the key is in it. openssl keeps
+/// the mode of a file that's there already, and would make a new one readable by others with the usual umask
+fn private_key_file(path: &str) -> Result<(), CertError> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(())
+}
+
+/// Checks that `name` is a hostname, an IP address or a plain deployer name. It goes into the certificate's subject,
+/// where `/` or `=` would add components such as another organizational unit, and into the names of its files
+fn check_cert_name(name: &str) -> Result<(), CertError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':'));
+
+    match valid {
+        true => Ok(()),
+        false => Err(CertError::InvalidName(name.to_string())),
+    }
+}
+
+#[test]
+fn test_check_cert_name() {
+    assert!(check_cert_name("web-1.example.com").is_ok());
+    assert!(check_cert_name("10.0.0.1").is_ok());
+    assert!(check_cert_name("fd00::1").is_ok());
+    assert!(check_cert_name("ci_runner").is_ok());
+
+    assert!(check_cert_name("").is_err());
+    assert!(check_cert_name("evil/OU=deploy-rs deployer").is_err());
+    assert!(check_cert_name("a=b").is_err());
+    assert!(check_cert_name("../ca").is_err());
+    assert!(check_cert_name("..").is_err());
+}
+
+/// Creates `ca.crt` and `ca.key` in `dir`, the CA every deployer and agent certificate is signed by
+pub async fn init_ca(dir: &str) -> Result<(), CertError> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let key = format!("{}/ca.key", dir);
+    let cert = format!("{}/ca.crt", dir);
+
+    private_key_file(&key)?;
+
+    run_openssl(&[
+        "req",
+        "-x509",
+        "-newkey",
+        "ec",
+        "-pkeyopt",
+        "ec_paramgen_curve:prime256v1",
+        "-nodes",
+        "-keyout",
+        &key,
+        "-out",
+        &cert,
+        "-days",
+        "3650",
+        "-subj",
+        "/CN=deploy-rs CA",
+    ])
+    .await
+}
+
+/// Issues `<name>.crt` and `<name>.key` in `dir`, signed by the CA in the same directory
+///
+/// For nodes, `name` has to be the hostname deployers connect to. For deployers, it is the name
+/// agents allow with `--allow-deployer`.
+pub async fn issue_cert(dir: &str, name: &str, role: CertRole) -> Result<(), CertError> {
+    check_cert_name(name)?;
+
+    let key = format!("{}/{}.key", dir, name);
+    let csr = format!("{}/{}.csr", dir, name);
+    let cert = format!("{}/{}.crt", dir, name);
+    let extensions = format!("{}/{}.ext", dir, name);
+
+    private_key_file(&key)?;
+
+    run_openssl(&[
+        "req",
+        "-newkey",
+        "ec",
+        "-pkeyopt",
+        "ec_paramgen_curve:prime256v1",
+        "-nodes",
+        "-keyout",
+        &key,
+        "-out",
+        &csr,
+        "-subj",
+        &format!("/OU={}/CN={}", role.unit(), name),
+    ])
+    .await?;
+
+    tokio::fs::write(&extensions, build_cert_extensions(name, role)).await?;
+
+    let result = run_openssl(&[
+        "x509",
+        "-req",
+        "-in",
+        &csr,
+        "-CA",
+        &format!("{}/ca.crt", dir),
+        "-CAkey",
+        &format!("{}/ca.key", dir),
+        "-CAcreateserial",
+        "-out",
+        &cert,
+        "-days",
+        "825",
+        "-extfile",
+        &extensions,
+    ])
+    .await;
+
+    let _ = tokio::fs::remove_file(&csr).await;
+    let _ = tokio::fs::remove_file(&extensions).await;
+
+    result
+}
@@ -9,13 +9,16 @@ use signal_hook::{consts::signal::SIGHUP, iterator::Signals};
 use clap::Clap;
 
 use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
 use tokio::sync::mpsc;
 use tokio::time::timeout;
 
 use std::time::Duration;
 
-use std::path::Path;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 
@@ -23,6 +26,11 @@ use thiserror::Error;
 
 use log::{debug, error, info, warn};
 
+use deploy::diff::{ClosureDiff, ClosureDiffOutput, ClosurePath};
+use deploy::pull::PullDescriptor;
+use deploy::schema::{Versioned, SCHEMA_VERSION};
+use deploy::units::UnitChanges;
+
 /// Remote activation utility for deploy-rs
 #[derive(Clap, Debug)]
 #[clap(version = "1.0", author = "Serokell <https://serokell.io/>")]
@@ -33,6 +41,13 @@ struct Opts {
     /// Directory to print logs to
     #[clap(long)]
     log_dir: Option<String>,
+    /// When to color log output, either `auto`, `always` or `never`, taken from `DEPLOY_RS_COLOR` if not given.
+    /// Colors are always used by default, as the output is usually relayed to a terminal through SSH
+    #[clap(long)]
+    color: Option<deploy::ColorChoice>,
+    /// Print log output without emoji
+    #[clap(long)]
+    ascii: bool,
 
     #[clap(subcommand)]
     subcmd: SubCommand,
@@ -43,6 +58,11 @@ enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
     Revoke(RevokeOpts),
+    DiffEtc(DiffEtcOpts),
+    DiffClosures(DiffClosuresOpts),
+    Pull(PullOpts),
+    Check(CheckOpts),
+    Abort(AbortOpts),
 }
 
 /// Activate a profile
@@ -57,6 +77,10 @@ struct ActivateOpts {
     #[clap(long)]
     confirm_timeout: u16,
 
+    /// Maximum time the activation script may run, after which it's stopped and activation fails
+    #[clap(long)]
+    activation_timeout: Option<u16>,
+
     /// Wait for confirmation after deployment and rollback if not confirmed
     #[clap(long)]
     magic_rollback: bool,
@@ -72,6 +96,42 @@ struct ActivateOpts {
     /// Path for any temporary files that may be needed during activation
     #[clap(long)]
     temp_path: String,
+
+    /// Systemd unit which has to be active before activation counts as successful
+    #[clap(long)]
+    wait_for_unit: Option<String>,
+
+    /// Port on localhost which has to accept connections before activation counts as successful
+    #[clap(long)]
+    wait_for_port: Option<u16>,
+
+    /// http:// URL which has to answer successfully before activation counts as successful
+    #[clap(long)]
+    wait_for_url: Option<String>,
+
+    /// How many seconds to wait for the unit, port and URL
+    #[clap(long, default_value = "60")]
+    wait_timeout: u16,
+
+    /// Work out which systemd units the closure changes before activating, and record which ones activation restarts
+    #[clap(long)]
+    restart_changed_only: bool,
+
+    /// Label for the generation made, written next to its link, e.g. to `system-42-link.label`
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Give the closure's store paths the labels the SELinux policy has for them before activating
+    #[clap(long)]
+    relabel: bool,
+
+    /// File with variables for the activation, `-` for standard input, kept off the command line as they can be secret
+    #[clap(long)]
+    env_file: Option<String>,
+
+    /// Snapshots to restore when rolling back, as JSON
+    #[clap(long)]
+    restore_snapshots: Option<String>,
 }
 
 /// Activate a profile
@@ -80,9 +140,17 @@ struct WaitOpts {
     /// The closure to wait for
     closure: String,
 
+    /// The profile path the closure is activated in
+    #[clap(long)]
+    profile_path: String,
+
     /// Path for any temporary files that may be needed during activation
     #[clap(long)]
     temp_path: String,
+
+    /// How many seconds to wait for the activation to finish
+    #[clap(long, default_value = "240")]
+    timeout: u32,
 }
 
 /// Activate a profile
@@ -92,6 +160,78 @@ struct RevokeOpts {
     profile_path: String,
 }
 
+/// Stop the activation script of a closure, if it's still running
+#[derive(Clap, Debug)]
+struct AbortOpts {
+    /// The closure being activated
+    closure: String,
+
+    /// The profile path the closure is activated in
+    #[clap(long)]
+    profile_path: String,
+
+    /// Path for any temporary files that may be needed during activation
+    #[clap(long)]
+    temp_path: String,
+}
+
+/// Show the changes to /etc (including systemd units) a closure would make
+#[derive(Clap, Debug)]
+struct DiffEtcOpts {
+    /// The closure to compare against the current profile
+    closure: String,
+    /// The profile path the closure would be installed into
+    profile_path: String,
+}
+
+/// Show the package changes between the current profile closure and a new one
+#[derive(Clap, Debug)]
+struct DiffClosuresOpts {
+    /// The closure to compare against the current profile
+    closure: String,
+    /// The profile path the closure would be installed into
+    profile_path: String,
+}
+
+/// Fetch a pull descriptor, substitute its closure and activate it
+#[derive(Clap, Debug)]
+struct PullOpts {
+    /// Path or http(s) URL of the descriptor written by `deploy --pull-descriptors`
+    descriptor: String,
+
+    /// Auto rollback if failure
+    #[clap(long)]
+    auto_rollback: bool,
+
+    /// Only activate closures signed by this key (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    trusted_key: Vec<String>,
+
+    /// The SSH keys of the deployers whose descriptors are acted on, in the allowed signers format of `ssh-keygen`
+    #[clap(long)]
+    allowed_signers: String,
+}
+
+/// Check whether what activation waits for is up, once unless given a timeout
+#[derive(Clap, Debug)]
+struct CheckOpts {
+    /// Systemd unit which has to be active
+    #[clap(long)]
+    wait_for_unit: Option<String>,
+
+    /// Port on localhost which has to accept connections
+    #[clap(long)]
+    wait_for_port: Option<u16>,
+
+    /// http:// URL which has to answer successfully
+    #[clap(long)]
+    wait_for_url: Option<String>,
+
+    /// How many seconds to keep checking for the unit, port and URL
+    #[clap(long, default_value = "0")]
+    wait_timeout: u16,
+}
+
 #[derive(Error, Debug)]
 pub enum DeactivateError {
     #[error("Failed to execute the rollback command: {0}")]
@@ -112,9 +252,20 @@ pub enum DeactivateError {
     Reactivate(std::io::Error),
     #[error("Command for re-activating the last generation resulted in a bad exit code: {0:?}")]
     ReactivateExit(Option<i32>),
+    #[error("Failed to run the command restoring the snapshots: {0}")]
+    RestoreSnapshots(std::io::Error),
+    #[error(
+        "Restoring the snapshots resulted in a bad exit code: {0:?}, they're still on the node"
+    )]
+    RestoreSnapshotsExit(Option<i32>),
 }
 
-pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
+/// Rolls the profile back to its previous generation, and its `snapshots` back to `restore` if given, once the services
+/// of that generation are up again
+pub async fn deactivate(
+    profile_path: &str,
+    restore: Option<&deploy::snapshot::Restore>,
+) -> Result<(), DeactivateError> {
     warn!("De-activating due to error");
 
     let nix_env_rollback_exit_status = Command::new("nix-env")
@@ -177,7 +328,23 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     info!("Attempting to re-activate the last generation");
 
-    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
+    // Generations adopted from nixos-rebuild or colmena have no activation of deploy-rs, NixOS systems switch to
+    // themselves instead
+    let deploy_rs_activate = format!("{}/deploy-rs-activate", profile_path);
+    let switch_to_configuration = format!("{}/bin/switch-to-configuration", profile_path);
+
+    let mut re_activate_command = match Path::new(&deploy_rs_activate).exists()
+        || !Path::new(&switch_to_configuration).exists()
+    {
+        true => Command::new(deploy_rs_activate),
+        false => {
+            let mut command = Command::new(switch_to_configuration);
+            command.arg("switch");
+            command
+        }
+    };
+
+    let re_activate_exit_status = re_activate_command
         .env("PROFILE", &profile_path)
         .current_dir(&profile_path)
         .status()
@@ -189,13 +356,91 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         a => return Err(DeactivateError::ReactivateExit(a)),
     };
 
+    if let Some(restore) = restore {
+        info!(
+            "Restoring {} datasets and subvolumes to `{}`",
+            restore.snapshots.len(),
+            restore.name
+        );
+
+        let restore_exit_status = Command::new("sh")
+            .arg("-c")
+            .arg(deploy::snapshot::restore_script(
+                &restore.snapshots,
+                &restore.name,
+            ))
+            .status()
+            .await
+            .map_err(DeactivateError::RestoreSnapshots)?;
+
+        match restore_exit_status.code() {
+            Some(0) => (),
+            a => return Err(DeactivateError::RestoreSnapshotsExit(a)),
+        };
+    }
+
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum TempDirError {
+    #[error("Failed to create the temporary directory {0}: {1}")]
+    Create(String, std::io::Error),
+    #[error("Failed to look at the temporary directory {0}: {1}")]
+    Inspect(String, std::io::Error),
+    #[error(
+        "The temporary directory {0} could be written to by another user, refusing to use it. \
+         Remove it, or set `tempPath` to a directory only the profile user can write to"
+    )]
+    NotPrivate(String),
+}
+
+/// Creates `temp_path` for only this user, or makes sure the one there is this user's and nobody else can write to it
+///
+/// Anyone who could would be able to confirm an activation by removing its canary, or to plant the process group
+/// `abort` stops. A sticky directory of root's like `/tmp` is fine, as nobody can remove what they don't own there.
+async fn ensure_temp_dir(temp_path: &str) -> Result<(), TempDirError> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(temp_path)
+        .map_err(|e| TempDirError::Create(temp_path.to_string(), e))?;
+
+    let metadata = fs::symlink_metadata(temp_path)
+        .await
+        .map_err(|e| TempDirError::Inspect(temp_path.to_string(), e))?;
+
+    let uid = unsafe { libc::geteuid() };
+    let private = metadata.uid() == uid && metadata.mode() & 0o022 == 0;
+    let sticky = (metadata.uid() == uid || metadata.uid() == 0) && metadata.mode() & 0o1000 != 0;
+
+    match metadata.is_dir() && (private || sticky) {
+        true => Ok(()),
+        false => Err(TempDirError::NotPrivate(temp_path.to_string())),
+    }
+}
+
+/// Creates a file of activate-rs's in its temporary directory, which mustn't be there already: in a sticky directory,
+/// one left by another user would stay theirs to change or remove
+async fn create_new_file(path: &str) -> std::io::Result<fs::File> {
+    if let Err(e) = fs::remove_file(path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            debug!("Failed to remove the old {}: {}", path, e);
+        }
+    }
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+}
+
 #[derive(Error, Debug)]
 pub enum ActivationConfirmationError {
-    #[error("Failed to create activation confirmation directory: {0}")]
-    CreateConfirmDir(std::io::Error),
+    #[error("Can't use the temporary directory for the canary file: {0}")]
+    TempDir(#[from] TempDirError),
     #[error("Failed to create activation confirmation file: {0}")]
     CreateConfirmFile(std::io::Error),
     #[error("Could not watch for activation sentinel: {0}")]
@@ -214,11 +459,11 @@ pub enum DangerZoneError {
 
 async fn danger_zone(
     mut events: mpsc::Receiver<Result<(), notify::Error>>,
-    confirm_timeout: u16,
+    seconds: u64,
 ) -> Result<(), DangerZoneError> {
     info!("Waiting for confirmation event...");
 
-    match timeout(Duration::from_secs(confirm_timeout as u64), events.recv()).await {
+    match timeout(Duration::from_secs(seconds), events.recv()).await {
         Ok(Some(Ok(()))) => Ok(()),
         Ok(Some(Err(e))) => Err(DangerZoneError::Watch(e)),
         Ok(None) => Err(DangerZoneError::NoConfirmation),
@@ -231,20 +476,17 @@ pub async fn activation_confirmation(
     temp_path: String,
     confirm_timeout: u16,
     closure: String,
+    restore: Option<&deploy::snapshot::Restore>,
 ) -> Result<(), ActivationConfirmationError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, &profile_path);
 
-    debug!("Ensuring parent directory exists for canary file");
+    debug!("Ensuring the temporary directory for the canary file is there and private");
 
-    if let Some(parent) = Path::new(&lock_path).parent() {
-        fs::create_dir_all(parent)
-            .await
-            .map_err(ActivationConfirmationError::CreateConfirmDir)?;
-    }
+    ensure_temp_dir(&temp_path).await?;
 
     debug!("Creating canary file");
 
-    fs::File::create(&lock_path)
+    create_new_file(&lock_path)
         .await
         .map_err(ActivationConfirmationError::CreateConfirmFile)?;
 
@@ -273,15 +515,20 @@ pub async fn activation_confirmation(
 
     watcher.watch(&lock_path, RecursiveMode::NonRecursive)?;
 
-    if let Err(err) = danger_zone(done, confirm_timeout).await {
+    if let Err(err) = danger_zone(done, confirm_timeout as u64).await {
         error!("Error waiting for confirmation event: {}", err);
 
-        if let Err(err) = deactivate(&profile_path).await {
+        if let Err(err) = deactivate(&profile_path, restore).await {
             error!(
                 "Error de-activating due to another error waiting for confirmation, oh no...: {}",
                 err
             );
         }
+
+        // The canary marks an activation that may still roll back, which this one is done with now
+        if let Err(err) = fs::remove_file(&lock_path).await {
+            debug!("Failed to remove the canary file: {}", err);
+        }
     }
 
     Ok(())
@@ -289,13 +536,23 @@ pub async fn activation_confirmation(
 
 #[derive(Error, Debug)]
 pub enum WaitError {
+    #[error("Can't use the temporary directory to wait in: {0}")]
+    TempDir(#[from] TempDirError),
     #[error("Error creating watcher for activation: {0}")]
     Watcher(#[from] notify::Error),
     #[error("Error waiting for activation: {0}")]
     Waiting(#[from] DangerZoneError),
 }
-pub async fn wait(temp_path: String, closure: String) -> Result<(), WaitError> {
-    let lock_path = deploy::make_lock_path(&temp_path, &closure);
+pub async fn wait(
+    temp_path: String,
+    closure: String,
+    profile_path: String,
+    wait_timeout: u32,
+) -> Result<(), WaitError> {
+    let lock_path = deploy::make_lock_path(&temp_path, &closure, &profile_path);
+
+    // This starts alongside the activation, which may not have created the directory yet
+    ensure_temp_dir(&temp_path).await?;
 
     let (created, done) = mpsc::channel(1);
 
@@ -329,7 +586,7 @@ pub async fn wait(temp_path: String, closure: String) -> Result<(), WaitError> {
         return Ok(());
     }
 
-    danger_zone(done, 240).await?;
+    danger_zone(done, wait_timeout as u64).await?;
 
     info!("Found canary file, done waiting!");
 
@@ -347,23 +604,326 @@ pub enum ActivateError {
     RunActivate(std::io::Error),
     #[error("The activation script resulted in a bad exit code: {0:?}")]
     RunActivateExit(Option<i32>),
+    #[error("The activation script resulted in a bad exit code: {0:?}, after {1} denied it:\n{2}")]
+    RunActivateDenied(Option<i32>, deploy::mac::Mac, String),
+
+    #[error("Failed to relabel the closure: {0}")]
+    Relabel(#[from] deploy::mac::MacError),
+    #[error("The activation script didn't finish within {0} seconds, it was stopped")]
+    ActivationTimeout(u16),
 
     #[error("There was an error de-activating after an error was encountered: {0}")]
     Deactivate(#[from] DeactivateError),
 
+    #[error("The activated profile did not become ready: {0}")]
+    Readiness(deploy::readiness::ReadinessError),
+
     #[error("Failed to get activation confirmation: {0}")]
     ActivationConfirmation(#[from] ActivationConfirmationError),
+
+    #[error("Failed to read the activation's variables: {0}")]
+    ReadEnv(std::io::Error),
+    #[error("Failed to parse the activation's variables: {0}")]
+    ParseEnv(serde_json::Error),
+    #[error("Failed to parse the snapshots to restore: {0}")]
+    ParseRestoreSnapshots(serde_json::Error),
+}
+
+/// Sends `signal` to every process of the group led by `pgid`
+fn kill_group(pgid: i32, signal: i32) -> std::io::Result<()> {
+    match unsafe { libc::kill(-pgid, signal) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Runs the profile's activation script, stopping it if it's still running after `activation_timeout` seconds
+///
+/// The script leads its own session, so whatever it starts is stopped along with it, and its process group is
+/// recorded at `pid_path` for `activate-rs abort` while it runs.
+///
+/// With `unit_changes`, the script is told the changed units in `DEPLOY_RS_CHANGED_UNITS`, and its output is passed
+/// through and picked up on for what `switch-to-configuration` does to units.
+async fn run_activation_script(
+    activation_location: &str,
+    dry_activate: bool,
+    activation_timeout: Option<u16>,
+    pid_path: &str,
+    mut unit_changes: Option<&mut UnitChanges>,
+) -> Result<std::process::ExitStatus, ActivateError> {
+    let mut command = Command::new(format!("{}/deploy-rs-activate", activation_location));
+    command
+        .env("PROFILE", activation_location)
+        .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
+        .current_dir(activation_location);
+
+    if let Some(ref unit_changes) = unit_changes {
+        command
+            .env("DEPLOY_RS_CHANGED_UNITS", unit_changes.changed.join(" "))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+
+    // Without its own session the script would share activate-rs's process group, which stopping it would kill too
+    unsafe {
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+
+    let mut child = command.spawn().map_err(ActivateError::RunActivate)?;
+    let pgid = child.id().map(|x| x as i32);
+
+    let temp_dir = match Path::new(pid_path).parent() {
+        Some(parent) => ensure_temp_dir(&parent.to_string_lossy()).await,
+        None => Ok(()),
+    };
+
+    // Passed through as they come, keeping only what's about units
+    let stdout = child.stdout.take().map(|stdout| {
+        tokio::spawn(pass_through(BufReader::new(stdout), |line| {
+            println!("{}", line)
+        }))
+    });
+    let stderr = child.stderr.take().map(|stderr| {
+        tokio::spawn(pass_through(BufReader::new(stderr), |line| {
+            eprintln!("{}", line)
+        }))
+    });
+
+    match (pgid, temp_dir) {
+        (Some(pgid), Ok(())) => {
+            let recorded = match create_new_file(pid_path).await {
+                Ok(mut file) => file.write_all(pgid.to_string().as_bytes()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(err) = recorded {
+                warn!(
+                    "Failed to record the activation script at {}, it can't be aborted: {}",
+                    pid_path, err
+                );
+            }
+        }
+        (Some(_), Err(err)) => warn!(
+            "Not recording the activation script, it can't be aborted: {}",
+            err
+        ),
+        (None, _) => (),
+    }
+
+    let status = match activation_timeout {
+        None => child.wait().await.map_err(ActivateError::RunActivate),
+        Some(activation_timeout) => {
+            match timeout(Duration::from_secs(activation_timeout as u64), child.wait()).await {
+                Ok(x) => x.map_err(ActivateError::RunActivate),
+                Err(_) => {
+                    let stopped = match pgid {
+                        Some(pgid) => kill_group(pgid, libc::SIGKILL),
+                        None => child.kill().await,
+                    };
+                    if let Err(err) = stopped {
+                        error!("Failed to stop the activation script: {}", err);
+                    }
+                    let _ = child.wait().await;
+
+                    Err(ActivateError::ActivationTimeout(activation_timeout))
+                }
+            }
+        }
+    };
+
+    let _ = fs::remove_file(pid_path).await;
+
+    for output in stdout.into_iter().chain(stderr) {
+        if let (Ok(lines), Some(unit_changes)) = (output.await, unit_changes.as_mut()) {
+            for line in lines {
+                unit_changes.parse_line(&line);
+            }
+        }
+    }
+
+    status
+}
+
+/// Prints each line of the activation script's output with `print`, returning those mentioning units
+async fn pass_through<R: tokio::io::AsyncBufRead + Unpin>(
+    output: R,
+    print: impl Fn(&str),
+) -> Vec<String> {
+    let mut lines = output.lines();
+    let mut about_units = Vec::new();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        print(&line);
+
+        if line.contains("the following") {
+            about_units.push(line);
+        }
+    }
+
+    about_units
+}
+
+#[derive(Error, Debug)]
+pub enum AbortError {
+    #[error("Can't trust the temporary directory with the recorded activation script: {0}")]
+    TempDir(#[from] TempDirError),
+    #[error("The recorded activation script at {0} isn't one activate-rs left, not stopping it")]
+    NotOwn(String),
+    #[error("Failed to read the recorded activation script at {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("The recorded activation script at {0} is not a process id")]
+    Parse(String),
+    #[error("Failed to stop the activation script: {0}")]
+    Kill(std::io::Error),
+}
+
+/// Stops the activation script of `closure` and everything it started, which makes the activation fail (and roll
+/// back, with `autoRollback`) as if the script had
+pub async fn abort(
+    temp_path: String,
+    closure: String,
+    profile_path: String,
+) -> Result<(), AbortError> {
+    let pid_path = deploy::make_activation_pid_path(&temp_path, &closure, &profile_path);
+
+    ensure_temp_dir(&temp_path).await?;
+
+    match fs::symlink_metadata(&pid_path).await {
+        Ok(metadata) if !metadata.is_file() || metadata.uid() != unsafe { libc::geteuid() } => {
+            return Err(AbortError::NotOwn(pid_path))
+        }
+        _ => (),
+    }
+
+    let pgid = match fs::read_to_string(&pid_path).await {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("The activation script isn't running, nothing to abort");
+            return Ok(());
+        }
+        Err(e) => return Err(AbortError::Read(pid_path, e)),
+    };
+
+    let pgid: i32 = match pgid.trim().parse() {
+        Ok(x) if x > 1 => x,
+        _ => return Err(AbortError::Parse(pid_path)),
+    };
+
+    info!("Aborting the activation script (process group {})", pgid);
+
+    match kill_group(pgid, libc::SIGTERM) {
+        Ok(()) => Ok(()),
+        // It finished in the meantime
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        Err(e) => Err(AbortError::Kill(e)),
+    }
+}
+
+/// Leaves `label` next to the generation the profile points to now, e.g. in `system-42-link.label`, where Nix doesn't
+/// take it for a generation
+fn write_generation_label(profile_path: &str, label: &str) -> std::io::Result<PathBuf> {
+    let generation = std::fs::read_link(profile_path)?;
+    let generation = match Path::new(profile_path).parent() {
+        Some(dir) => dir.join(generation),
+        None => generation,
+    };
+
+    let label_path = PathBuf::from(format!("{}.label", generation.display()));
+    std::fs::write(&label_path, format!("{}\n", label))?;
+
+    Ok(label_path)
+}
+
+/// Sets the variables the deployer handed over in `env_file`, which is removed once read as it only carries them here
+async fn load_activation_env(env_file: &str) -> Result<(), ActivateError> {
+    let input = match env_file {
+        "-" => {
+            let mut input = String::new();
+            tokio::io::stdin()
+                .read_to_string(&mut input)
+                .await
+                .map_err(ActivateError::ReadEnv)?;
+            input
+        }
+        _ => {
+            let input = fs::read_to_string(env_file)
+                .await
+                .map_err(ActivateError::ReadEnv)?;
+
+            if let Err(e) = fs::remove_file(env_file).await {
+                warn!("Failed to remove {}: {}", env_file, e);
+            }
+
+            input
+        }
+    };
+
+    for (key, value) in deploy::parse_activation_env(&input).map_err(ActivateError::ParseEnv)? {
+        std::env::set_var(key, value);
+    }
+
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
     auto_rollback: bool,
     temp_path: String,
     confirm_timeout: u16,
+    activation_timeout: Option<u16>,
     magic_rollback: bool,
     dry_activate: bool,
+    readiness: deploy::readiness::Readiness,
+    restart_changed_only: bool,
+    label: Option<String>,
+    relabel: bool,
+    env_file: Option<String>,
+    restore_snapshots: Option<String>,
 ) -> Result<(), ActivateError> {
+    if let Some(ref env_file) = env_file {
+        load_activation_env(env_file).await?;
+    }
+
+    let restore: Option<deploy::snapshot::Restore> = match restore_snapshots {
+        Some(x) => Some(serde_json::from_str(&x).map_err(ActivateError::ParseRestoreSnapshots)?),
+        None => None,
+    };
+
+    let mac = deploy::mac::enforced();
+
+    // Before the profile is switched over, while it still has the units that are running
+    let mut unit_changes = match restart_changed_only {
+        true => {
+            let changed =
+                deploy::units::changed_units(Path::new(&profile_path), Path::new(&closure));
+
+            match changed.is_empty() {
+                true => info!("No unit files changed"),
+                false => info!("Unit files changed: {}", changed.join(", ")),
+            }
+
+            Some(UnitChanges {
+                changed,
+                ..UnitChanges::default()
+            })
+        }
+        false => None,
+    };
+
+    if relabel && !dry_activate {
+        match mac {
+            Some(mac) => {
+                info!("Relabelling the closure for {}", mac);
+                deploy::mac::relabel(mac, &closure).await?;
+            }
+            None => debug!("No access control is enforced, not relabelling the closure"),
+        }
+    }
+
     if !dry_activate {
         info!("Activating profile");
         let nix_env_set_exit_status = Command::new("nix-env")
@@ -378,33 +938,47 @@ pub async fn activate(
             Some(0) => (),
             a => {
                 if auto_rollback && !dry_activate {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, restore.as_ref()).await?;
                 }
                 return Err(ActivateError::SetProfileExit(a));
             }
         };
+
+        if let Some(ref label) = label {
+            match write_generation_label(&profile_path, label) {
+                Ok(label_path) => debug!("Labelled the generation in {}", label_path.display()),
+                Err(e) => warn!("Failed to label the generation: {}", e),
+            }
+        }
     }
 
     debug!("Running activation script");
 
+    // Denials logged from here on are what the activation script ran into
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
     let activation_location = if dry_activate {
         &closure
     } else {
         &profile_path
     };
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", activation_location))
-        .env("PROFILE", activation_location)
-        .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
-        .current_dir(activation_location)
-        .status()
-        .await
-        .map_err(ActivateError::RunActivate)
+    let activate_status = match run_activation_script(
+        activation_location,
+        dry_activate,
+        activation_timeout,
+        &deploy::make_activation_pid_path(&temp_path, &closure, &profile_path),
+        unit_changes.as_mut(),
+    )
+    .await
     {
         Ok(x) => x,
         Err(e) => {
             if auto_rollback && !dry_activate {
-                deactivate(&profile_path).await?;
+                deactivate(&profile_path, restore.as_ref()).await?;
             }
             return Err(e);
         }
@@ -414,13 +988,59 @@ pub async fn activate(
         match activate_status.code() {
             Some(0) => (),
             a => {
+                // Before rolling back, which can be denied all the same
+                let denied = match mac {
+                    Some(mac) => Some((mac, deploy::mac::recent_denials(mac, started).await))
+                        .filter(|(_, denials)| !denials.is_empty()),
+                    None => None,
+                };
+
                 if auto_rollback {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, restore.as_ref()).await?;
                 }
-                return Err(ActivateError::RunActivateExit(a));
+
+                return Err(match denied {
+                    Some((mac, denials)) => {
+                        ActivateError::RunActivateDenied(a, mac, denials.join("\n"))
+                    }
+                    None => ActivateError::RunActivateExit(a),
+                });
             }
         };
 
+        if let Some(unit_changes) = unit_changes {
+            info!("Activation {}", unit_changes);
+
+            let unit_changes_path =
+                deploy::make_unit_changes_path(&temp_path, &closure, &profile_path);
+
+            let recorded = match create_new_file(&unit_changes_path).await {
+                Ok(mut file) => {
+                    file.write_all(&serde_json::to_vec(&unit_changes).unwrap_or_default())
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(err) = recorded {
+                warn!(
+                    "Failed to record what activation did to units at {}: {}",
+                    unit_changes_path, err
+                );
+            }
+        }
+
+        if !readiness.is_empty() {
+            info!("Waiting for the activated profile to become ready...");
+
+            if let Err(err) = deploy::readiness::wait_until_ready(&readiness).await {
+                if auto_rollback {
+                    deactivate(&profile_path, restore.as_ref()).await?;
+                }
+                return Err(ActivateError::Readiness(err));
+            }
+        }
+
         if !dry_activate {
             info!("Activation succeeded!");
         }
@@ -428,12 +1048,18 @@ pub async fn activate(
         if magic_rollback {
             info!("Magic rollback is enabled, setting up confirmation hook...");
 
-            match activation_confirmation(profile_path.clone(), temp_path, confirm_timeout, closure)
-                .await
+            match activation_confirmation(
+                profile_path.clone(),
+                temp_path,
+                confirm_timeout,
+                closure,
+                restore.as_ref(),
+            )
+            .await
             {
                 Ok(()) => {}
                 Err(err) => {
-                    deactivate(&profile_path).await?;
+                    deactivate(&profile_path, restore.as_ref()).await?;
                     return Err(ActivateError::ActivationConfirmation(err));
                 }
             };
@@ -443,8 +1069,314 @@ pub async fn activate(
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum DiffEtcError {
+    #[error("The new closure does not contain an /etc directory: {0}")]
+    NoNewEtc(String),
+    #[error("Failed to execute the diff command: {0}")]
+    Diff(std::io::Error),
+    #[error("The diff command resulted in a bad exit code: {0:?}")]
+    DiffExit(Option<i32>),
+}
+
+pub async fn diff_etc(profile_path: String, closure: String) -> Result<(), DiffEtcError> {
+    let new_etc = format!("{}/etc", closure);
+    let current_etc = format!("{}/etc", profile_path);
+
+    if fs::metadata(&new_etc).await.is_err() {
+        return Err(DiffEtcError::NoNewEtc(new_etc));
+    }
+
+    let mut diff_command = Command::new("diff");
+    diff_command.arg("-ruN");
+
+    // On the first deployment there is nothing to compare against, so everything is new
+    if fs::metadata(&current_etc).await.is_ok() {
+        diff_command.arg(&current_etc);
+    } else {
+        info!("No /etc found in the current profile, showing all files as new");
+        diff_command.arg("/var/empty");
+    }
+
+    debug!("Comparing {} with {}", current_etc, new_etc);
+
+    let diff_exit_status = diff_command
+        .arg(&new_etc)
+        .status()
+        .await
+        .map_err(DiffEtcError::Diff)?;
+
+    // diff exits with 1 when the inputs differ, which is exactly what we're after
+    match diff_exit_status.code() {
+        Some(0) | Some(1) => (),
+        a => return Err(DiffEtcError::DiffExit(a)),
+    };
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DiffClosuresError {
+    #[error("Failed to run command for querying closure requisites: {0}")]
+    QueryRequisites(std::io::Error),
+    #[error("Command for querying closure requisites resulted in a bad exit code: {0:?}")]
+    QueryRequisitesExit(Option<i32>),
+    #[error("Failed to run command for querying path sizes: {0}")]
+    QuerySize(std::io::Error),
+    #[error("Command for querying path sizes resulted in a bad exit code: {0:?}")]
+    QuerySizeExit(Option<i32>),
+    #[error("Error converting closure query output to utf8: {0}")]
+    DecodeUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Failed to parse path size: {0}")]
+    ParseSize(#[from] std::num::ParseIntError),
+    #[error("Failed to serialize closure diff: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+async fn query_closure(path: &str) -> Result<Vec<ClosurePath>, DiffClosuresError> {
+    let requisites_output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .arg(path)
+        .output()
+        .await
+        .map_err(DiffClosuresError::QueryRequisites)?;
+
+    match requisites_output.status.code() {
+        Some(0) => (),
+        a => return Err(DiffClosuresError::QueryRequisitesExit(a)),
+    };
+
+    let paths: Vec<String> = String::from_utf8(requisites_output.stdout)?
+        .lines()
+        .map(|x| x.to_owned())
+        .collect();
+
+    let size_output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--size")
+        .args(&paths)
+        .output()
+        .await
+        .map_err(DiffClosuresError::QuerySize)?;
+
+    match size_output.status.code() {
+        Some(0) => (),
+        a => return Err(DiffClosuresError::QuerySizeExit(a)),
+    };
+
+    let sizes = String::from_utf8(size_output.stdout)?
+        .lines()
+        .map(|x| x.trim().parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()?;
+
+    Ok(paths
+        .into_iter()
+        .zip(sizes)
+        .map(|(path, size)| ClosurePath { path, size })
+        .collect())
+}
+
+async fn builtin_diff_closures(
+    profile_path: &str,
+    closure: &str,
+) -> Result<ClosureDiff, DiffClosuresError> {
+    // There is no current closure on the first deployment of a profile
+    let old = match fs::metadata(profile_path).await {
+        Ok(_) => query_closure(profile_path).await?,
+        Err(_) => Vec::new(),
+    };
+    let new = query_closure(closure).await?;
+
+    Ok(deploy::diff::diff_closures(&old, &new))
+}
+
+pub async fn diff_closures(profile_path: String, closure: String) -> Result<(), DiffClosuresError> {
+    let output = match builtin_diff_closures(&profile_path, &closure).await {
+        Ok(diff) => ClosureDiffOutput::Builtin(diff),
+        Err(err) => {
+            warn!("Could not compute closure diff ({}), trying nvd", err);
+
+            match Command::new("nvd")
+                .arg("diff")
+                .arg(&profile_path)
+                .arg(&closure)
+                .output()
+                .await
+            {
+                Ok(x) if x.status.success() => ClosureDiffOutput::Nvd {
+                    text: String::from_utf8(x.stdout)?,
+                },
+                _ => return Err(err),
+            }
+        }
+    };
+
+    println!("{}", serde_json::to_string(&output)?);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum PullError {
+    #[error("Failed to run curl to fetch the descriptor: {0}")]
+    Fetch(std::io::Error),
+    #[error("Fetching the descriptor with curl resulted in a bad exit code: {0:?}")]
+    FetchExit(Option<i32>),
+    #[error("Failed to read the descriptor: {0}")]
+    Read(std::io::Error),
+    #[error("The descriptor is not valid UTF-8: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("Not acting on the descriptor: {0}")]
+    Signature(#[from] deploy::pull::DescriptorSignatureError),
+    #[error(
+        "The descriptor is of schema version {0}, but this activate-rs only understands up to {1}"
+    )]
+    SchemaVersion(u32, u32),
+    #[error("Failed to run nix-store to substitute the closure: {0}")]
+    Realise(std::io::Error),
+    #[error("Substituting the closure resulted in a bad exit code: {0:?}")]
+    RealiseExit(Option<i32>),
+    #[error("Failed to run Nix verify command: {0}")]
+    Verify(std::io::Error),
+    #[error("The closure is not signed by a trusted key, Nix verify exit code: {0:?}")]
+    VerifyExit(Option<i32>),
+    #[error("{0}")]
+    Activate(#[from] ActivateError),
+}
+
+async fn fetch_descriptor(descriptor: &str) -> Result<String, PullError> {
+    if !descriptor.starts_with("http://") && !descriptor.starts_with("https://") {
+        return fs::read_to_string(descriptor)
+            .await
+            .map_err(PullError::Read);
+    }
+
+    let fetch_output = Command::new("curl")
+        .arg("--fail")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--location")
+        .arg(descriptor)
+        .output()
+        .await
+        .map_err(PullError::Fetch)?;
+
+    match fetch_output.status.code() {
+        Some(0) => (),
+        a => return Err(PullError::FetchExit(a)),
+    };
+
+    Ok(String::from_utf8(fetch_output.stdout)?)
+}
+
+pub async fn pull(
+    descriptor: String,
+    auto_rollback: bool,
+    trusted_keys: Vec<String>,
+    allowed_signers: String,
+) -> Result<(), PullError> {
+    let descriptor: Versioned<PullDescriptor> = deploy::pull::verify_descriptor(
+        &deploy::transport::SystemTransport,
+        &fetch_descriptor(&descriptor).await?,
+        &allowed_signers,
+    )
+    .await?;
+
+    if descriptor.schema_version > SCHEMA_VERSION {
+        return Err(PullError::SchemaVersion(
+            descriptor.schema_version,
+            SCHEMA_VERSION,
+        ));
+    }
+
+    let descriptor = descriptor.inner;
+
+    let current = fs::canonicalize(&descriptor.profile_path).await.ok();
+
+    if current.as_deref() == Some(Path::new(&descriptor.closure)) {
+        info!(
+            "Profile `{}` is already at {}, nothing to do",
+            descriptor.profile, descriptor.closure
+        );
+        return Ok(());
+    }
+
+    info!(
+        "Substituting {} for profile `{}`",
+        descriptor.closure, descriptor.profile
+    );
+
+    let mut realise_command = Command::new("nix-store");
+    realise_command.arg("--realise").arg(&descriptor.closure);
+
+    if !descriptor.substituters.is_empty() {
+        realise_command
+            .arg("--option")
+            .arg("extra-substituters")
+            .arg(descriptor.substituters.join(" "));
+    }
+
+    let realise_exit_status = realise_command
+        .stdout(Stdio::null())
+        .status()
+        .await
+        .map_err(PullError::Realise)?;
+
+    match realise_exit_status.code() {
+        Some(0) => (),
+        a => return Err(PullError::RealiseExit(a)),
+    };
+
+    // The closure may have been in the store already, so this checks the signatures Nix has recorded for it
+    if !trusted_keys.is_empty() {
+        let verify_exit_status = Command::new("nix")
+            .arg("--extra-experimental-features")
+            .arg("nix-command")
+            .arg("store")
+            .arg("verify")
+            .arg("--no-contents")
+            .arg("--recursive")
+            .arg("--sigs-needed")
+            .arg("1")
+            .arg("--option")
+            .arg("trusted-public-keys")
+            .arg(trusted_keys.join(" "))
+            .arg(&descriptor.closure)
+            .status()
+            .await
+            .map_err(PullError::Verify)?;
+
+        match verify_exit_status.code() {
+            Some(0) => (),
+            a => return Err(PullError::VerifyExit(a)),
+        };
+    }
+
+    // Nobody is around to confirm the activation, so magic rollback doesn't apply here
+    activate(
+        descriptor.profile_path,
+        descriptor.closure,
+        auto_rollback,
+        "/tmp".to_string(),
+        0,
+        None,
+        false,
+        false,
+        deploy::readiness::Readiness::default(),
+        false,
+        None,
+        false,
+        None,
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
 async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
-    deactivate(profile_path.as_str()).await?;
+    deactivate(profile_path.as_str(), None).await?;
     Ok(())
 }
 
@@ -460,14 +1392,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let opts: Opts = Opts::parse();
 
+    let color = match opts.color {
+        Some(color) => color,
+        None => std::env::var(deploy::COLOR_ENV)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(deploy::ColorChoice::Always),
+    };
+
+    deploy::set_output_style(
+        color,
+        opts.ascii || std::env::var_os(deploy::ASCII_ENV).is_some(),
+    );
+
     deploy::init_logger(
-        opts.debug_logs,
+        match opts.debug_logs {
+            true => log::LevelFilter::Debug,
+            false => log::LevelFilter::Info,
+        },
+        None,
         opts.log_dir.as_deref(),
         &match opts.subcmd {
-            SubCommand::Activate(_) => deploy::LoggerType::Activate,
-            SubCommand::Wait(_) => deploy::LoggerType::Wait,
-            SubCommand::Revoke(_) => deploy::LoggerType::Revoke,
+            SubCommand::Activate(_) | SubCommand::Pull(_) => deploy::LoggerType::Activate,
+            SubCommand::Wait(_) | SubCommand::Check(_) => deploy::LoggerType::Wait,
+            SubCommand::Revoke(_) | SubCommand::Abort(_) => deploy::LoggerType::Revoke,
+            SubCommand::DiffEtc(_) | SubCommand::DiffClosures(_) => deploy::LoggerType::Diff,
         },
+        None,
     )?;
 
     let r = match opts.subcmd {
@@ -477,19 +1428,76 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             activate_opts.auto_rollback,
             activate_opts.temp_path,
             activate_opts.confirm_timeout,
+            activate_opts.activation_timeout,
             activate_opts.magic_rollback,
             activate_opts.dry_activate,
+            deploy::readiness::Readiness {
+                unit: activate_opts.wait_for_unit,
+                port: activate_opts.wait_for_port,
+                url: activate_opts.wait_for_url,
+                timeout: activate_opts.wait_timeout,
+            },
+            activate_opts.restart_changed_only,
+            activate_opts.label,
+            activate_opts.relabel,
+            activate_opts.env_file,
+            activate_opts.restore_snapshots,
         )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
-        SubCommand::Wait(wait_opts) => wait(wait_opts.temp_path, wait_opts.closure)
-            .await
-            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Wait(wait_opts) => wait(
+            wait_opts.temp_path,
+            wait_opts.closure,
+            wait_opts.profile_path,
+            wait_opts.timeout,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
 
         SubCommand::Revoke(revoke_opts) => revoke(revoke_opts.profile_path)
             .await
             .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::DiffEtc(diff_etc_opts) => {
+            diff_etc(diff_etc_opts.profile_path, diff_etc_opts.closure)
+                .await
+                .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
+
+        SubCommand::DiffClosures(diff_closures_opts) => {
+            diff_closures(diff_closures_opts.profile_path, diff_closures_opts.closure)
+                .await
+                .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
+
+        SubCommand::Pull(pull_opts) => pull(
+            pull_opts.descriptor,
+            pull_opts.auto_rollback,
+            pull_opts.trusted_key,
+            pull_opts.allowed_signers,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Abort(abort_opts) => abort(
+            abort_opts.temp_path,
+            abort_opts.closure,
+            abort_opts.profile_path,
+        )
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Check(check_opts) => {
+            deploy::readiness::wait_until_ready(&deploy::readiness::Readiness {
+                unit: check_opts.wait_for_unit,
+                port: check_opts.wait_for_port,
+                url: check_opts.wait_for_url,
+                timeout: check_opts.wait_timeout,
+            })
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
     };
 
     match r {
@@ -0,0 +1,1144 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use clap::Clap;
+
+use std::process::Stdio;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+use thiserror::Error;
+
+use log::{debug, error, info};
+
+use deploy::agent::{self, AgentFrame, AgentRequest};
+
+/// Agent for deploy-rs, accepting deployments over mutual TLS instead of SSH
+#[derive(Clap, Debug)]
+#[clap(version = "1.0", author = "Serokell <https://serokell.io/>")]
+struct Opts {
+    /// Print debug logs to output
+    #[clap(short, long)]
+    debug_logs: bool,
+
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Clap, Debug)]
+enum SubCommand {
+    Serve(ServeOpts),
+    Handle(HandleOpts),
+    InitCa(InitCaOpts),
+    IssueCert(IssueCertOpts),
+}
+
+/// Listen for deployers, handling each connection with `handle`
+#[derive(Clap, Debug)]
+struct ServeOpts {
+    /// Port to listen on
+    #[clap(long, default_value = "7422")]
+    port: u16,
+    /// Certificate of this node
+    #[clap(long)]
+    cert: String,
+    /// Private key of the node certificate
+    #[clap(long)]
+    key: String,
+    /// CA certificate deployer certificates have to be signed by
+    #[clap(long)]
+    ca: String,
+    /// Only accept closures signed by this key, instead of the keys trusted in nix.conf (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    trusted_key: Vec<String>,
+    /// Only accept deployers with this certificate common name (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    allow_deployer: Vec<String>,
+    /// Run any command deployers send, as needed by e.g. `deploy exec`, snapshots, migrations and profiles of users
+    /// other than root. Without it, deployers can only activate closures signed by a trusted key
+    #[clap(long)]
+    allow_commands: bool,
+    /// Variable activations may be given, e.g. one of the node's `activationEnv` (can be given multiple times). `PATH`
+    /// and `LD_*` never are
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    allow_env: Vec<String>,
+    /// Directory activations keep their temporary files in, the `tempPath` of the profiles deployed through the agent
+    #[clap(long, default_value = "/tmp")]
+    temp_path: String,
+}
+
+/// Handle a single request on stdin, answering on stdout
+#[derive(Clap, Debug)]
+struct HandleOpts {}
+
+/// Create a CA for signing deployer and agent certificates
+#[derive(Clap, Debug)]
+struct InitCaOpts {
+    /// Directory to create `ca.crt` and `ca.key` in
+    #[clap(long)]
+    dir: String,
+}
+
+/// Issue a certificate signed by the CA
+#[derive(Clap, Debug)]
+struct IssueCertOpts {
+    /// Directory containing the CA, the certificate and key are written next to it
+    #[clap(long)]
+    dir: String,
+    /// Hostname of the node, or name of the deployer
+    #[clap(long)]
+    name: String,
+    /// Issue a certificate for a deployer to connect to agents with, instead of one for a node's agent
+    #[clap(long)]
+    deployer: bool,
+}
+
+// `serve` hands these to the handlers through the environment, as socat's EXEC can't take arbitrary arguments
+const TRUSTED_KEYS_ENV: &str = "DEPLOY_RS_AGENT_TRUSTED_KEYS";
+const ALLOWED_DEPLOYERS_ENV: &str = "DEPLOY_RS_AGENT_ALLOWED_DEPLOYERS";
+const ALLOW_COMMANDS_ENV: &str = "DEPLOY_RS_AGENT_ALLOW_COMMANDS";
+const ALLOWED_ENV_ENV: &str = "DEPLOY_RS_AGENT_ALLOWED_ENV";
+const TEMP_PATH_ENV: &str = "DEPLOY_RS_AGENT_TEMP_PATH";
+
+// Set by socat from the verified client certificate
+const PEER_COMMON_NAME_ENV: &str = "SOCAT_OPENSSL_X509_COMMONNAME";
+const PEER_UNIT_ENV: &str = "SOCAT_OPENSSL_X509_ORGANIZATIONALUNITNAME";
+
+#[derive(Error, Debug)]
+pub enum ServeError {
+    #[error("Failed to find the agent executable: {0}")]
+    CurrentExe(std::io::Error),
+    #[error("Failed to run socat: {0}")]
+    Socat(std::io::Error),
+    #[error("socat resulted in a bad exit code: {0:?}")]
+    SocatExit(Option<i32>),
+}
+
+fn build_listen_address(port: u16, cert: &str, key: &str, ca: &str) -> String {
+    format!(
+        "OPENSSL-LISTEN:{},fork,reuseaddr,cert={},key={},cafile={},verify=1",
+        port, cert, key, ca
+    )
+}
+
+#[test]
+fn test_listen_address_builder() {
+    assert_eq!(
+        build_listen_address(
+            7422,
+            "/etc/deploy-rs/node.crt",
+            "/etc/deploy-rs/node.key",
+            "/etc/deploy-rs/ca.crt"
+        ),
+        "OPENSSL-LISTEN:7422,fork,reuseaddr,cert=/etc/deploy-rs/node.crt,key=/etc/deploy-rs/node.key,cafile=/etc/deploy-rs/ca.crt,verify=1"
+    );
+}
+
+async fn serve(serve_opts: ServeOpts, debug_logs: bool) -> Result<(), ServeError> {
+    let current_exe = std::env::current_exe().map_err(ServeError::CurrentExe)?;
+
+    let mut handle_command = format!("{} handle", current_exe.display());
+    if debug_logs {
+        handle_command = format!("{} --debug-logs handle", current_exe.display());
+    }
+
+    info!("Listening for deployers on port {}", serve_opts.port);
+
+    // socat does the TLS handshake, checks the deployer's certificate and forks a handler per connection
+    let socat_exit_status = Command::new("socat")
+        .arg(build_listen_address(
+            serve_opts.port,
+            &serve_opts.cert,
+            &serve_opts.key,
+            &serve_opts.ca,
+        ))
+        .arg(format!("EXEC:{}", handle_command))
+        .env(TRUSTED_KEYS_ENV, serve_opts.trusted_key.join(" "))
+        .env(ALLOWED_DEPLOYERS_ENV, serve_opts.allow_deployer.join(" "))
+        .env(ALLOWED_ENV_ENV, serve_opts.allow_env.join(" "))
+        .env(TEMP_PATH_ENV, &serve_opts.temp_path)
+        .env(
+            ALLOW_COMMANDS_ENV,
+            match serve_opts.allow_commands {
+                true => "1",
+                false => "",
+            },
+        )
+        .status()
+        .await
+        .map_err(ServeError::Socat)?;
+
+    match socat_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(ServeError::SocatExit(a)),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HandleError {
+    #[error("Failed to communicate with the deployer: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse the request: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("The deployer closed the connection without sending a request")]
+    NoRequest,
+    #[error("Failed to run `{0}`: {1}")]
+    Spawn(String, std::io::Error),
+    #[error("Deployer `{0}` is not allowed to use this agent")]
+    DeployerNotAllowed(String),
+    #[error(
+        "`{0}` was not issued a deployer certificate (`deploy-rs-agent issue-cert --deployer`)"
+    )]
+    NotDeployer(String),
+    #[error("Running commands isn't allowed by this agent, only activating signed closures (see `--allow-commands`): {0}")]
+    CommandsNotAllowed(String),
+    #[error("{0} is not a store path or a profile linking to one")]
+    NotStorePath(String),
+    #[error("{0} is not the canary file of an activation in the agent's `--temp-path`")]
+    NotCanary(String),
+    #[error("activate-rs can't be run with {0:?} without `--allow-commands`")]
+    SubcommandNotAllowed(Vec<String>),
+    #[error("activate-rs can't be given `{0}` without `--allow-commands`")]
+    ArgNotAllowed(String),
+    #[error("activate-rs was asked to work on {0}, but {1} is the closure that was verified")]
+    ClosureMismatch(String, String),
+    #[error("{0} is not a profile in /nix/var/nix/profiles, which deployers can only use with `--allow-commands`")]
+    ProfileNotAllowed(String),
+    #[error("Activations keep their temporary files in the agent's `--temp-path`, not {0}")]
+    TempPathNotAllowed(String),
+    #[error("Activations can't be given `{0}` without it in `--allow-env` or `--allow-commands`")]
+    EnvNotAllowed(String),
+}
+
+/// Reads a space separated list handed down by `serve`
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+/// Checks that the peer's certificate, with `unit` and `common_name` in its subject, was issued to a deployer, and one
+/// of `allowed_deployers` if any are given. Node certificates are signed by the same CA, but mustn't be able to deploy
+fn check_deployer(
+    allowed_deployers: &[String],
+    unit: &str,
+    common_name: &str,
+) -> Result<(), HandleError> {
+    if unit != agent::CertRole::Deployer.unit() {
+        return Err(HandleError::NotDeployer(common_name.to_string()));
+    }
+
+    match allowed_deployers.is_empty() || allowed_deployers.iter().any(|x| x == common_name) {
+        true => Ok(()),
+        false => Err(HandleError::DeployerNotAllowed(common_name.to_string())),
+    }
+}
+
+#[test]
+fn test_check_deployer() {
+    assert!(check_deployer(&[], "deploy-rs deployer", "ci").is_ok());
+    assert!(matches!(
+        check_deployer(&[], "deploy-rs node", "web-1.example.com"),
+        Err(HandleError::NotDeployer(_))
+    ));
+    assert!(matches!(
+        check_deployer(&[], "", "ci"),
+        Err(HandleError::NotDeployer(_))
+    ));
+
+    let allowed = vec!["ci".to_string()];
+    assert!(check_deployer(&allowed, "deploy-rs deployer", "ci").is_ok());
+    assert!(matches!(
+        check_deployer(&allowed, "deploy-rs deployer", "laptop"),
+        Err(HandleError::DeployerNotAllowed(_))
+    ));
+}
+
+fn allow_commands() -> bool {
+    !std::env::var(ALLOW_COMMANDS_ENV)
+        .unwrap_or_default()
+        .is_empty()
+}
+
+fn temp_path() -> String {
+    std::env::var(TEMP_PATH_ENV).unwrap_or_else(|_| "/tmp".to_string())
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    frame: &AgentFrame,
+) -> Result<(), HandleError> {
+    w.write_all(format!("{}\n", serde_json::to_string(frame)?).as_bytes())
+        .await?;
+    w.flush().await?;
+
+    Ok(())
+}
+
+/// Runs a command, relaying its output to the deployer as it comes in
+///
+/// It's logged as `name`, as the command itself would show the variables it's given, which can be secrets.
+async fn relay<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    name: &str,
+    command: &mut Command,
+) -> Result<Option<i32>, HandleError> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| HandleError::Spawn(name.to_string(), e))?;
+
+    let mut stdout = child.stdout.take().map(|x| BufReader::new(x).lines());
+    let mut stderr = child.stderr.take().map(|x| BufReader::new(x).lines());
+
+    // Both streams are relayed as they come in, so activation logs show up on the deployer live
+    while stdout.is_some() || stderr.is_some() {
+        tokio::select! {
+            line = async { stdout.as_mut().unwrap().next_line().await }, if stdout.is_some() => {
+                match line? {
+                    Some(line) => write_frame(w, &AgentFrame::Stdout { line }).await?,
+                    None => stdout = None,
+                }
+            },
+            line = async { stderr.as_mut().unwrap().next_line().await }, if stderr.is_some() => {
+                match line? {
+                    Some(line) => write_frame(w, &AgentFrame::Stderr { line }).await?,
+                    None => stderr = None,
+                }
+            },
+        }
+    }
+
+    let status = child.wait().await?;
+
+    debug!("`{}` exited with {:?}", name, status.code());
+
+    Ok(status.code())
+}
+
+async fn handle_run<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    command: String,
+    allow_commands: bool,
+) -> Result<(), HandleError> {
+    if !allow_commands {
+        return Err(HandleError::CommandsNotAllowed(command));
+    }
+
+    info!("Running `{}`", command);
+
+    let code = relay(w, &command, Command::new("sh").arg("-c").arg(&command)).await?;
+
+    write_frame(w, &AgentFrame::Exit { code }).await
+}
+
+/// What an activate-rs subcommand deployers may run without `--allow-commands` can be given: switches, options taking
+/// a value, and how many positional arguments, the first of which is the closure if `takes_closure`
+struct SubcommandArgs {
+    name: &'static str,
+    switches: &'static [&'static str],
+    options: &'static [&'static str],
+    positionals: usize,
+    takes_closure: bool,
+}
+
+/// The activate-rs subcommands deployers may run without `--allow-commands`. `pull` isn't one of them, as it activates
+/// whatever the descriptor it's given points to
+const ACTIVATE_SUBCOMMANDS: [SubcommandArgs; 7] = [
+    SubcommandArgs {
+        name: "activate",
+        switches: &[
+            "--magic-rollback",
+            "--auto-rollback",
+            "--dry-activate",
+            "--restart-changed-only",
+            "--relabel",
+        ],
+        options: &[
+            "--confirm-timeout",
+            "--activation-timeout",
+            "--temp-path",
+            "--wait-for-unit",
+            "--wait-for-port",
+            "--wait-for-url",
+            "--wait-timeout",
+            "--label",
+            "--env-file",
+        ],
+        positionals: 2,
+        takes_closure: true,
+    },
+    SubcommandArgs {
+        name: "wait",
+        switches: &[],
+        options: &["--profile-path", "--temp-path", "--timeout"],
+        positionals: 1,
+        takes_closure: true,
+    },
+    SubcommandArgs {
+        name: "revoke",
+        switches: &[],
+        options: &[],
+        positionals: 1,
+        takes_closure: false,
+    },
+    SubcommandArgs {
+        name: "abort",
+        switches: &[],
+        options: &["--profile-path", "--temp-path"],
+        positionals: 1,
+        takes_closure: true,
+    },
+    SubcommandArgs {
+        name: "check",
+        switches: &[],
+        options: &[
+            "--wait-for-unit",
+            "--wait-for-port",
+            "--wait-for-url",
+            "--wait-timeout",
+        ],
+        positionals: 0,
+        takes_closure: false,
+    },
+    SubcommandArgs {
+        name: "diff-etc",
+        switches: &[],
+        options: &[],
+        positionals: 2,
+        takes_closure: true,
+    },
+    SubcommandArgs {
+        name: "diff-closures",
+        switches: &[],
+        options: &[],
+        positionals: 2,
+        takes_closure: true,
+    },
+];
+
+const GLOBAL_SWITCHES: [&str; 3] = ["-d", "--debug-logs", "--ascii"];
+// `--log-dir` isn't one of them, as activate-rs would write its log into whatever directory it's given, as root
+const GLOBAL_OPTIONS: [&str; 1] = ["--color"];
+
+/// Where the profiles deployers may activate, roll back and compare against are, the system's and the users'
+const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+
+/// Whether `path` is a profile in `PROFILES_DIR`, and not a way out of it
+fn profile_path_allowed(path: &str) -> bool {
+    match std::path::Path::new(path).strip_prefix(PROFILES_DIR) {
+        Ok(rest) => {
+            rest.components().next().is_some()
+                && rest
+                    .components()
+                    .all(|x| matches!(x, std::path::Component::Normal(_)))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Checks that `args` only runs one of `ACTIVATE_SUBCOMMANDS` with the options it's allowed, on `closure`, the verified
+/// store path, and a profile in `PROFILES_DIR`, with its temporary files in the agent's `temp_path`. The variables are read from the request, never
+/// from a file another activation left on the node
+fn check_activate_args(args: &[String], closure: &str, temp_path: &str) -> Result<(), HandleError> {
+    let not_allowed = |x: &str| HandleError::ArgNotAllowed(x.to_string());
+
+    let mut words = args.iter();
+
+    let subcommand = loop {
+        match words.next().map(|x| x.as_str()) {
+            Some(x) if GLOBAL_SWITCHES.contains(&x) => (),
+            Some(x) if GLOBAL_OPTIONS.contains(&x) => {
+                words.next().ok_or_else(|| not_allowed(x))?;
+            }
+            Some(x) if x.starts_with('-') => return Err(not_allowed(x)),
+            x => match ACTIVATE_SUBCOMMANDS.iter().find(|s| Some(s.name) == x) {
+                Some(s) => break s,
+                None => return Err(HandleError::SubcommandNotAllowed(args.to_vec())),
+            },
+        }
+    };
+
+    let mut positionals = 0;
+
+    while let Some(arg) = words.next() {
+        if subcommand.switches.contains(&arg.as_str()) {
+            continue;
+        }
+
+        if subcommand.options.contains(&arg.as_str()) {
+            let value = words.next().ok_or_else(|| not_allowed(arg))?;
+
+            match arg.as_str() {
+                "--temp-path" if value.trim_end_matches('/') != temp_path.trim_end_matches('/') => {
+                    return Err(HandleError::TempPathNotAllowed(value.clone()))
+                }
+                "--env-file" if value != "-" => {
+                    return Err(not_allowed(&format!("--env-file {}", value)))
+                }
+                "--profile-path" if !profile_path_allowed(value) => {
+                    return Err(HandleError::ProfileNotAllowed(value.clone()))
+                }
+                _ => (),
+            }
+
+            continue;
+        }
+
+        if arg.starts_with('-') || positionals == subcommand.positionals {
+            return Err(not_allowed(arg));
+        }
+
+        // Whatever follows the closure is the profile
+        match positionals == 0 && subcommand.takes_closure {
+            true if arg != closure => {
+                return Err(HandleError::ClosureMismatch(
+                    arg.clone(),
+                    closure.to_string(),
+                ))
+            }
+            false if !profile_path_allowed(arg) => {
+                return Err(HandleError::ProfileNotAllowed(arg.clone()))
+            }
+            _ => (),
+        }
+
+        positionals += 1;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_check_activate_args() {
+    let args = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+    let closure = "/nix/store/abc-system";
+
+    assert!(check_activate_args(
+        &args(&[
+            "--debug-logs",
+            "--color",
+            "never",
+            "activate",
+            "/nix/store/abc-system",
+            "/nix/var/nix/profiles/system",
+            "--temp-path",
+            "/tmp/",
+            "--confirm-timeout",
+            "30",
+            "--magic-rollback",
+            "--env-file",
+            "-"
+        ]),
+        closure,
+        "/tmp"
+    )
+    .is_ok());
+    assert!(check_activate_args(
+        &args(&[
+            "wait",
+            "/nix/store/abc-system",
+            "--profile-path",
+            "/nix/var/nix/profiles/system",
+            "--temp-path",
+            "/tmp"
+        ]),
+        closure,
+        "/tmp"
+    )
+    .is_ok());
+    assert!(check_activate_args(
+        &args(&["revoke", "/nix/var/nix/profiles/system"]),
+        closure,
+        "/tmp"
+    )
+    .is_ok());
+    assert!(check_activate_args(
+        &args(&["check", "--wait-for-unit", "postgresql.service"]),
+        "/nix/var/nix/profiles/system",
+        "/tmp"
+    )
+    .is_ok());
+
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "activate",
+                "/nix/store/unsigned-system",
+                "/nix/var/nix/profiles/system",
+                "--temp-path",
+                "/tmp"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ClosureMismatch(_, _))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&["--color", "never", "pull", "https://example.com"]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::SubcommandNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(&args(&["--ascii"]), closure, "/tmp"),
+        Err(HandleError::SubcommandNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "activate",
+                "/nix/store/abc-system",
+                "/nix/var/nix/profiles/system",
+                "--temp-path",
+                "/tmp",
+                "--restore-snapshots",
+                "{}"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ArgNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "activate",
+                "/nix/store/abc-system",
+                "/nix/var/nix/profiles/system",
+                "--temp-path",
+                "/tmp",
+                "--env-file",
+                "/etc/shadow"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ArgNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "activate",
+                "/nix/store/abc-system",
+                "/nix/var/nix/profiles/system",
+                "/nix/store/unsigned-system"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ArgNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "abort",
+                "/nix/store/abc-system",
+                "--profile-path",
+                "/nix/var/nix/profiles/system",
+                "--temp-path",
+                "/etc"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::TempPathNotAllowed(_))
+    ));
+    assert!(check_activate_args(
+        &args(&[
+            "revoke",
+            "/nix/var/nix/profiles/per-user/alice/home-manager"
+        ]),
+        closure,
+        "/tmp"
+    )
+    .is_ok());
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "activate",
+                "/nix/store/abc-system",
+                "/etc/profile",
+                "--temp-path",
+                "/tmp"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ProfileNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&["revoke", "/nix/var/nix/profiles/../../../etc/shadow"]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ProfileNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "wait",
+                "/nix/store/abc-system",
+                "--profile-path",
+                "/root/profile",
+                "--temp-path",
+                "/tmp"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ProfileNotAllowed(_))
+    ));
+    assert!(matches!(
+        check_activate_args(
+            &args(&[
+                "--log-dir",
+                "/etc/cron.d",
+                "activate",
+                "/nix/store/abc-system",
+                "/nix/var/nix/profiles/system"
+            ]),
+            closure,
+            "/tmp"
+        ),
+        Err(HandleError::ArgNotAllowed(_))
+    ));
+}
+
+/// Whether activations may be given the variable `name`: the ones deploy sets for the output style and those the agent
+/// was started with `--allow-env` for, but never any changing what the root activate-rs loads or runs
+fn env_allowed(name: &str, allowed_env: &[String]) -> bool {
+    let changes_execution = name == "PATH" || name.starts_with("LD_");
+
+    !changes_execution
+        && (name == deploy::COLOR_ENV
+            || name == deploy::ASCII_ENV
+            || allowed_env.iter().any(|x| x == name))
+}
+
+#[test]
+fn test_env_allowed() {
+    let allowed = vec!["DEPLOY_NOTE".to_string(), "LD_PRELOAD".to_string()];
+
+    assert!(env_allowed("DEPLOY_RS_COLOR", &[]));
+    assert!(env_allowed("DEPLOY_NOTE", &allowed));
+    assert!(!env_allowed("DEPLOY_NOTE", &[]));
+    assert!(!env_allowed("LD_PRELOAD", &allowed));
+    assert!(!env_allowed("LD_LIBRARY_PATH", &[]));
+    assert!(!env_allowed("PATH", &[]));
+}
+
+/// The store path `path` is or links to, the closure of a profile
+fn resolve_store_path(path: &str) -> Result<String, HandleError> {
+    let resolved = std::fs::canonicalize(path)?;
+
+    match resolved.strip_prefix("/nix/store") {
+        Ok(rest) if rest.components().count() == 1 => Ok(resolved.to_string_lossy().to_string()),
+        _ => Err(HandleError::NotStorePath(path.to_string())),
+    }
+}
+
+/// What `handle_activate` lets deployers do, from the options `serve` was started with
+struct ActivatePolicy {
+    trusted_keys: Vec<String>,
+    allowed_env: Vec<String>,
+    temp_path: String,
+    allow_commands: bool,
+}
+
+/// Runs the `activate-rs` of `closure` once `nix store verify` finds every path in its closure signed by a trusted key,
+/// the agent's own if it was given some, or built on the node
+///
+/// Unless deployers may run any command anyway, `args` and `env` are checked too, as they go to a process running as
+/// root: they can't point activate-rs at another closure than the verified one, and can't change what it loads or runs.
+async fn handle_activate<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    closure: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    policy: &ActivatePolicy,
+) -> Result<(), HandleError> {
+    let closure = resolve_store_path(&closure)?;
+
+    if !policy.allow_commands {
+        check_activate_args(&args, &closure, &policy.temp_path)?;
+
+        if let Some((key, _)) = env
+            .iter()
+            .find(|(key, _)| !env_allowed(key, &policy.allowed_env))
+        {
+            return Err(HandleError::EnvNotAllowed(key.clone()));
+        }
+    }
+
+    let trusted_keys = &policy.trusted_keys;
+
+    let mut verify_command = Command::new("nix");
+    verify_command
+        .arg("--extra-experimental-features")
+        .arg("nix-command")
+        .arg("store")
+        .arg("verify")
+        .arg("--recursive")
+        .arg("--no-contents")
+        .arg("--sigs-needed")
+        .arg("1");
+
+    if !trusted_keys.is_empty() {
+        verify_command
+            .arg("--option")
+            .arg("trusted-public-keys")
+            .arg(trusted_keys.join(" "));
+    }
+
+    let code = relay(w, "nix store verify", verify_command.arg(&closure)).await?;
+
+    if code != Some(0) {
+        write_frame(
+            w,
+            &AgentFrame::Stderr {
+                line: format!(
+                    "deploy-rs-agent: {} isn't signed by a trusted key, not activating it",
+                    closure
+                ),
+            },
+        )
+        .await?;
+
+        return write_frame(w, &AgentFrame::Exit { code }).await;
+    }
+
+    info!("Running the activate-rs of {} with {:?}", closure, args);
+
+    let code = relay(
+        w,
+        &format!("{}/activate-rs", closure),
+        Command::new(format!("{}/activate-rs", closure))
+            .args(&args)
+            .envs(env),
+    )
+    .await?;
+
+    write_frame(w, &AgentFrame::Exit { code }).await
+}
+
+/// The canary file `path` is, once its directory is found to be the agent's `temp_path` with links resolved. Only the
+/// directory is, so a canary that's a link is removed itself rather than what it points to
+fn canary_path(path: &str, temp_path: &str) -> Result<std::path::PathBuf, HandleError> {
+    let not_canary = || HandleError::NotCanary(path.to_string());
+
+    let path = std::path::Path::new(path);
+
+    let file_name = path
+        .file_name()
+        .filter(|x| x.to_string_lossy().starts_with(agent::CANARY_PREFIX))
+        .ok_or_else(not_canary)?;
+
+    let dir = path
+        .parent()
+        .and_then(|x| std::fs::canonicalize(x).ok())
+        .ok_or_else(not_canary)?;
+
+    match std::fs::canonicalize(temp_path) {
+        Ok(x) if x == dir => Ok(dir.join(file_name)),
+        _ => Err(not_canary()),
+    }
+}
+
+#[test]
+fn test_canary_path() {
+    let temp_path = std::env::temp_dir();
+    let temp_path = temp_path.to_str().unwrap();
+    let canary = |x: &str| canary_path(x, temp_path).map_err(|e| e.to_string());
+
+    assert_eq!(
+        canary(&format!("{}/deploy-rs-canary-abc", temp_path)),
+        Ok(std::fs::canonicalize(temp_path)
+            .unwrap()
+            .join("deploy-rs-canary-abc"))
+    );
+    assert!(canary(&format!("{}/shadow", temp_path)).is_err());
+    assert!(canary("/etc/deploy-rs-canary-abc").is_err());
+    assert!(canary(&format!("{}/../etc/deploy-rs-canary-abc", temp_path)).is_err());
+    assert!(canary("deploy-rs-canary-abc").is_err());
+}
+
+/// Removes the canary file of an activation, which `path` has to be, in the agent's `temp_path`
+async fn handle_confirm<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    path: String,
+    temp_path: &str,
+) -> Result<(), HandleError> {
+    let canary = canary_path(&path, temp_path)?;
+
+    info!("Confirming the activation waiting on {}", canary.display());
+
+    let code = match tokio::fs::remove_file(&canary).await {
+        Ok(()) => Some(0),
+        Err(e) => {
+            write_frame(
+                w,
+                &AgentFrame::Stderr {
+                    line: format!(
+                        "deploy-rs-agent: failed to remove {}: {}",
+                        canary.display(),
+                        e
+                    ),
+                },
+            )
+            .await?;
+
+            Some(1)
+        }
+    };
+
+    write_frame(w, &AgentFrame::Exit { code }).await
+}
+
+async fn handle_query_invalid<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    paths: Vec<String>,
+) -> Result<(), HandleError> {
+    let output = Command::new("nix-store")
+        .arg("--check-validity")
+        .arg("--print-invalid")
+        .args(&paths)
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .map_err(|e| HandleError::Spawn("nix-store --check-validity".to_string(), e))?;
+
+    if output.status.success() {
+        let invalid = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|x| x.to_string())
+            .collect();
+
+        write_frame(w, &AgentFrame::Invalid { paths: invalid }).await?;
+    }
+
+    write_frame(
+        w,
+        &AgentFrame::Exit {
+            code: output.status.code(),
+        },
+    )
+    .await
+}
+
+/// Makes a new directory only the agent can get at to unpack an import in. A directory that's already there is never
+/// used, as it could be one another user of the node put there, or a link to somewhere root shouldn't write to
+fn make_import_dir() -> Result<String, HandleError> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+
+    for attempt in 0..16 {
+        let dir = std::env::temp_dir().join(format!(
+            "deploy-rs-agent-import-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            attempt
+        ));
+
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir.to_string_lossy().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "no unused directory to unpack the import in",
+    )
+    .into())
+}
+
+async fn unpack_cache<R: AsyncBufReadExt + Unpin>(
+    r: R,
+    size: u64,
+    import_dir: &str,
+) -> Result<Option<i32>, HandleError> {
+    let mut child = Command::new("tar")
+        .arg("-x")
+        .arg("-C")
+        .arg(import_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| HandleError::Spawn("tar -x".to_string(), e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::io::copy(&mut r.take(size), &mut stdin).await?;
+    }
+
+    Ok(child.wait().await?.code())
+}
+
+async fn handle_import<R: AsyncBufReadExt + Unpin, W: AsyncWrite + Unpin>(
+    r: R,
+    w: &mut W,
+    size: u64,
+    paths: Vec<String>,
+    trusted_keys: &[String],
+) -> Result<(), HandleError> {
+    info!("Importing {} store paths", paths.len());
+
+    let import_dir = make_import_dir()?;
+
+    let code = match unpack_cache(r, size, &import_dir).await {
+        Ok(Some(0)) => {
+            let mut copy_command = Command::new("nix");
+            copy_command
+                .arg("copy")
+                .arg("--from")
+                .arg(format!("file://{}", import_dir));
+
+            // Signatures are always checked, the node's own trusted keys apply unless the agent was given some
+            if !trusted_keys.is_empty() {
+                copy_command
+                    .arg("--option")
+                    .arg("trusted-public-keys")
+                    .arg(trusted_keys.join(" "))
+                    .arg("--option")
+                    .arg("require-sigs")
+                    .arg("true");
+            }
+
+            relay(w, "nix copy", copy_command.args(&paths)).await
+        }
+        a => a,
+    };
+
+    let _ = tokio::fs::remove_dir_all(&import_dir).await;
+
+    write_frame(w, &AgentFrame::Exit { code: code? }).await
+}
+
+async fn handle() -> Result<(), HandleError> {
+    let mut reader = BufReader::new(tokio::io::stdin());
+    let mut stdout = tokio::io::stdout();
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await? == 0 {
+        return Err(HandleError::NoRequest);
+    }
+
+    let request: AgentRequest = serde_json::from_str(&request_line)?;
+
+    debug!("Received {:?}", request);
+
+    let result = match check_deployer(
+        &env_list(ALLOWED_DEPLOYERS_ENV),
+        &std::env::var(PEER_UNIT_ENV).unwrap_or_default(),
+        &std::env::var(PEER_COMMON_NAME_ENV).unwrap_or_default(),
+    ) {
+        Ok(()) => match request {
+            AgentRequest::Run { command } => {
+                handle_run(&mut stdout, command, allow_commands()).await
+            }
+            AgentRequest::Activate { closure, args, env } => {
+                handle_activate(
+                    &mut stdout,
+                    closure,
+                    args,
+                    env.0,
+                    &ActivatePolicy {
+                        trusted_keys: env_list(TRUSTED_KEYS_ENV),
+                        allowed_env: env_list(ALLOWED_ENV_ENV),
+                        temp_path: temp_path(),
+                        allow_commands: allow_commands(),
+                    },
+                )
+                .await
+            }
+            AgentRequest::Confirm { path } => handle_confirm(&mut stdout, path, &temp_path()).await,
+            AgentRequest::QueryInvalid { paths } => handle_query_invalid(&mut stdout, paths).await,
+            AgentRequest::Import { size, paths } => {
+                handle_import(
+                    reader,
+                    &mut stdout,
+                    size,
+                    paths,
+                    &env_list(TRUSTED_KEYS_ENV),
+                )
+                .await
+            }
+        },
+        Err(e) => Err(e),
+    };
+
+    // Let the deployer know why the request failed, the connection is closed without an exit frame
+    if let Err(ref e) = result {
+        let _ = write_frame(
+            &mut stdout,
+            &AgentFrame::Stderr {
+                line: format!("deploy-rs-agent: {}", e),
+            },
+        )
+        .await;
+    }
+
+    result
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let opts: Opts = Opts::parse();
+
+    deploy::init_logger(
+        match opts.debug_logs {
+            true => log::LevelFilter::Debug,
+            false => log::LevelFilter::Info,
+        },
+        None,
+        None,
+        &deploy::LoggerType::Agent,
+        None,
+    )?;
+
+    let r = match opts.subcmd {
+        SubCommand::Serve(serve_opts) => serve(serve_opts, opts.debug_logs)
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Handle(_) => handle()
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::InitCa(init_ca_opts) => agent::init_ca(&init_ca_opts.dir)
+            .await
+            .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::IssueCert(issue_cert_opts) => {
+            let role = match issue_cert_opts.deployer {
+                true => agent::CertRole::Deployer,
+                false => agent::CertRole::Node,
+            };
+
+            agent::issue_cert(&issue_cert_opts.dir, &issue_cert_opts.name, role)
+                .await
+                .map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
+    };
+
+    match r {
+        Ok(()) => (),
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1)
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,418 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::deploy::NixEnv;
+use crate::report::CommandFailure;
+use crate::transport::{InputMode, Invocation, NodeTarget, OutputMode, RunOnNodeError, Transport};
+
+/// What `deploy gen-bootstrap` writes the first boot of a node for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    CloudInit,
+    Ignition,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cloud-init" => Ok(Format::CloudInit),
+            "ignition" => Ok(Format::Ignition),
+            _ => Err(format!("`{}` is not one of `cloud-init` and `ignition`", s)),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BootstrapError {
+    #[error("Failed to read the public key {0}: {1}")]
+    ReadKey(String, std::io::Error),
+    #[error("{0} is not an SSH public key")]
+    NotPublicKey(String),
+    #[error("Found no SSH public key in ~/.ssh, give one with --key")]
+    NoKey,
+    #[error("Failed to look for Nix on the node: {0}")]
+    Check(RunOnNodeError),
+    #[error("Failed to run nix build for the Nix to upload: {0}")]
+    Build(std::io::Error),
+    #[error("Building the Nix to upload failed with {0}")]
+    BuildExit(CommandFailure),
+    #[error("{0} has neither bin/nix nor bin/nix-portable")]
+    NoBinary(String),
+    #[error("Nix can only be uploaded over SSH, not to {0}")]
+    NotSsh(String),
+    #[error("Failed to upload Nix: {0}")]
+    Upload(std::io::Error),
+    #[error("Uploading and setting up Nix failed with {0}")]
+    UploadExit(CommandFailure),
+}
+
+/// The public keys tried when none are given, in the order `ssh` tries their private keys
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519.pub", "id_ecdsa.pub", "id_rsa.pub"];
+
+/// What nodes need configured before the first deployment, in `/etc/nix/nix.conf`
+const NIX_CONF: &str = "experimental-features = nix-command flakes\n";
+
+/// Where nodes given Nix by `bootstrapNix` have it, which their `remoteNixPath` defaults to
+pub const NIX_DIR: &str = "/nix/var/deploy-rs/bin";
+
+/// The names the uploaded Nix is linked to. Static Nix and nix-portable both tell what to run by the name they're
+/// called by
+const NIX_PROGRAMS: [&str; 8] = [
+    "nix",
+    "nix-build",
+    "nix-collect-garbage",
+    "nix-copy-closure",
+    "nix-daemon",
+    "nix-env",
+    "nix-instantiate",
+    "nix-store",
+];
+
+/// The public keys in `key_files`, or the first of the deployer's own keys in `~/.ssh` if there are none
+pub fn public_keys(key_files: &[String]) -> Result<Vec<String>, BootstrapError> {
+    let key_files: Vec<PathBuf> = match key_files.is_empty() {
+        false => key_files.iter().map(PathBuf::from).collect(),
+        true => {
+            let ssh_dir = match std::env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".ssh"),
+                None => return Err(BootstrapError::NoKey),
+            };
+
+            let found = DEFAULT_KEYS
+                .iter()
+                .map(|x| ssh_dir.join(x))
+                .find(|x| x.exists())
+                .ok_or(BootstrapError::NoKey)?;
+
+            vec![found]
+        }
+    };
+
+    let mut keys = Vec::new();
+
+    for key_file in key_files {
+        let name = key_file.to_string_lossy().to_string();
+        let key = std::fs::read_to_string(&key_file)
+            .map_err(|e| BootstrapError::ReadKey(name.clone(), e))?;
+        let key = key.trim();
+
+        // A private key given by mistake would end up on every node made from the snippet
+        if !key.starts_with("ssh-") && !key.starts_with("ecdsa-") && !key.starts_with("sk-") {
+            return Err(BootstrapError::NotPublicKey(name));
+        }
+
+        keys.push(key.to_string());
+    }
+
+    Ok(keys)
+}
+
+/// Writes `s` as a YAML string that's taken literally
+fn yaml_string(s: &str) -> String {
+    serde_json::Value::String(s.to_string()).to_string()
+}
+
+/// A `#cloud-config` that lets `ssh_user` log in with `keys`, allowing it to sudo if it isn't root, and enables flakes
+pub fn cloud_init(ssh_user: &str, keys: &[String]) -> String {
+    let mut lines = vec![
+        "#cloud-config".to_string(),
+        "users:".to_string(),
+        format!("  - name: {}", yaml_string(ssh_user)),
+        "    ssh_authorized_keys:".to_string(),
+    ];
+
+    for key in keys {
+        lines.push(format!("      - {}", yaml_string(key)));
+    }
+
+    match ssh_user {
+        "root" => lines.push("disable_root: false".to_string()),
+        // Deployments run sudo without a terminal to type a password into
+        _ => lines.push("    sudo: \"ALL=(ALL) NOPASSWD:ALL\"".to_string()),
+    }
+
+    lines.push("write_files:".to_string());
+    lines.push("  - path: /etc/nix/nix.conf".to_string());
+    lines.push("    append: true".to_string());
+    lines.push("    content: |".to_string());
+    for line in NIX_CONF.lines() {
+        lines.push(format!("      {}", line));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Percent-encodes `s` for a `data:` URL
+fn data_url(s: &str) -> String {
+    let mut url = "data:,".to_string();
+
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    url
+}
+
+/// An Ignition config doing what `cloud_init` does, for e.g. Fedora CoreOS and Flatcar
+pub fn ignition(ssh_user: &str, keys: &[String]) -> String {
+    let mut user = serde_json::json!({
+        "name": ssh_user,
+        "sshAuthorizedKeys": keys,
+    });
+
+    if ssh_user != "root" {
+        user["groups"] = serde_json::json!(["wheel"]);
+    }
+
+    let config = serde_json::json!({
+        "ignition": { "version": "3.3.0" },
+        "passwd": { "users": [user] },
+        "storage": {
+            "files": [{
+                "path": "/etc/nix/nix.conf",
+                "append": [{ "source": data_url(NIX_CONF) }],
+            }],
+        },
+    });
+
+    serde_json::to_string_pretty(&config).unwrap_or_default() + "\n"
+}
+
+pub fn render(format: Format, ssh_user: &str, keys: &[String]) -> String {
+    match format {
+        Format::CloudInit => cloud_init(ssh_user, keys),
+        Format::Ignition => ignition(ssh_user, keys),
+    }
+}
+
+/// Whether `nix-store` can be found on the node, in `remoteNixPath` if that's set
+pub async fn has_nix(
+    transport: &dyn Transport,
+    target: &NodeTarget<'_>,
+    nix_env: NixEnv<'_>,
+) -> Result<bool, BootstrapError> {
+    let command = crate::deploy::with_nix_env(
+        nix_env,
+        "sh -c 'command -v nix-store >/dev/null'".to_string(),
+    );
+
+    debug!("Looking for Nix with: {}", command);
+
+    let output = transport
+        .run_on_node(target, &command, true)
+        .await
+        .map_err(BootstrapError::Check)?;
+
+    Ok(output.code == Some(0))
+}
+
+/// Builds `installable`, returning the static Nix binary it has
+pub async fn build_nix(
+    transport: &dyn Transport,
+    installable: &str,
+) -> Result<PathBuf, BootstrapError> {
+    let mut build_command = Invocation::new("nix");
+    build_command
+        .arg("build")
+        .arg("--no-link")
+        .arg("--print-out-paths")
+        .arg(installable)
+        .stdout(OutputMode::Capture);
+
+    debug!("Building Nix to upload with: {}", build_command);
+
+    let output = transport
+        .run(&build_command)
+        .await
+        .map_err(BootstrapError::Build)?;
+
+    match output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BootstrapError::BuildExit(CommandFailure::new(
+                &build_command,
+                &output,
+            )))
+        }
+    };
+
+    let out_path = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+
+    ["bin/nix", "bin/nix-portable"]
+        .iter()
+        .map(|x| Path::new(&out_path).join(x))
+        .find(|x| x.exists())
+        .ok_or(BootstrapError::NoBinary(out_path))
+}
+
+/// Run on the node with the Nix binary on standard input: makes `/nix` for `ssh_user` with `sudo` if it can't write
+/// there, puts the binary in `NIX_DIR` with the links to it, and initializes a single-user store
+pub fn install_script(ssh_user: &str, sudo: Option<&str>) -> String {
+    let sudo = match sudo {
+        Some(x) => format!("{} ", x),
+        None => String::new(),
+    };
+
+    let mut lines = vec![
+        "set -e".to_string(),
+        format!(
+            "[ -w /nix ] || {{ {sudo}mkdir -p -m 0755 /nix && {sudo}chown {user} /nix; }}",
+            sudo = sudo,
+            user = crate::shell_quote(ssh_user)
+        ),
+        format!("mkdir -p {}", NIX_DIR),
+        format!("cat > {}/.nix-bootstrap.tmp", NIX_DIR),
+        format!("chmod 0755 {}/.nix-bootstrap.tmp", NIX_DIR),
+        format!(
+            "mv {dir}/.nix-bootstrap.tmp {dir}/nix-bootstrap",
+            dir = NIX_DIR
+        ),
+    ];
+
+    for program in NIX_PROGRAMS.iter() {
+        lines.push(format!("ln -sfn nix-bootstrap {}/{}", NIX_DIR, program));
+    }
+
+    lines.push(format!("{}/nix-store --init", NIX_DIR));
+
+    lines.join("\n")
+}
+
+/// Uploads the Nix `binary` to the node and sets it up with `install_script`, with `sudo` running commands as root
+pub async fn install_nix(
+    transport: &dyn Transport,
+    target: &NodeTarget<'_>,
+    ssh_user: &str,
+    sudo: Option<&str>,
+    binary: &Path,
+) -> Result<(), BootstrapError> {
+    let (address, opts) = match target {
+        NodeTarget::Ssh { address, opts, .. } => (address, opts),
+        NodeTarget::Agent(_) => return Err(BootstrapError::NotSsh(target.to_string())),
+    };
+
+    let mut upload_command = Invocation::new("ssh");
+    upload_command
+        .arg(address)
+        .args(opts)
+        .arg(format!(
+            "sh -c {}",
+            crate::shell_quote(&install_script(ssh_user, sudo))
+        ))
+        .stdin(InputMode::File(binary.to_path_buf()));
+
+    info!("Uploading {} to {}", binary.display(), NIX_DIR);
+
+    let output = transport
+        .run(&upload_command)
+        .await
+        .map_err(BootstrapError::Upload)?;
+
+    match output.code {
+        Some(0) => Ok(()),
+        _ => Err(BootstrapError::UploadExit(CommandFailure::new(
+            &upload_command,
+            &output,
+        ))),
+    }
+}
+
+#[test]
+fn test_render() {
+    let keys = vec!["ssh-ed25519 AAAAC3Nz me@laptop".to_string()];
+
+    assert_eq!(
+        render(Format::CloudInit, "deploy", &keys),
+        r#"#cloud-config
+users:
+  - name: "deploy"
+    ssh_authorized_keys:
+      - "ssh-ed25519 AAAAC3Nz me@laptop"
+    sudo: "ALL=(ALL) NOPASSWD:ALL"
+write_files:
+  - path: /etc/nix/nix.conf
+    append: true
+    content: |
+      experimental-features = nix-command flakes
+"#
+    );
+    assert!(render(Format::CloudInit, "root", &keys).contains("\ndisable_root: false\n"));
+
+    let ignition: serde_json::Value =
+        serde_json::from_str(&render(Format::Ignition, "root", &keys)).unwrap();
+    assert_eq!(
+        ignition["passwd"]["users"][0],
+        serde_json::json!({ "name": "root", "sshAuthorizedKeys": ["ssh-ed25519 AAAAC3Nz me@laptop"] })
+    );
+    assert_eq!(
+        ignition["storage"]["files"][0]["append"][0]["source"],
+        "data:,experimental-features%20%3D%20nix-command%20flakes%0A"
+    );
+
+    assert_eq!("ignition".parse(), Ok(Format::Ignition));
+    assert!("butane".parse::<Format>().is_err());
+}
+
+#[test]
+fn test_install_nix() {
+    let transport = crate::transport::RecordingTransport::default();
+    let opts = vec!["-p".to_string(), "2222".to_string()];
+    let target = NodeTarget::Ssh {
+        address: "deploy@10.0.0.5".to_string(),
+        opts: &opts,
+        sudo_password: None,
+    };
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    transport.respond("command -v nix-store", Some(1), "");
+    assert!(!runtime
+        .block_on(has_nix(
+            &transport,
+            &target,
+            NixEnv {
+                path: Some(NIX_DIR),
+                store_root: None
+            }
+        ))
+        .unwrap());
+
+    runtime
+        .block_on(install_nix(
+            &transport,
+            &target,
+            "deploy",
+            Some("sudo -u root"),
+            Path::new("/nix/store/abc-nix-static/bin/nix"),
+        ))
+        .unwrap();
+
+    let commands = transport.commands();
+    assert!(commands[0].contains("env PATH='/nix/var/deploy-rs/bin':"));
+    assert!(commands[1].starts_with("ssh deploy@10.0.0.5 -p 2222 "));
+    assert!(commands[1].ends_with("< /nix/store/abc-nix-static/bin/nix"));
+
+    let script = install_script("deploy", Some("sudo -u root"));
+    assert!(script.contains(
+        "[ -w /nix ] || { sudo -u root mkdir -p -m 0755 /nix && sudo -u root chown 'deploy' /nix; }"
+    ));
+    assert!(script.contains("ln -sfn nix-bootstrap /nix/var/deploy-rs/bin/nix-store\n"));
+    assert!(script.ends_with("/nix/var/deploy-rs/bin/nix-store --init"));
+    assert!(install_script("root", None).contains("[ -w /nix ] || { mkdir -p -m 0755 /nix"));
+}
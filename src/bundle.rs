@@ -0,0 +1,764 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::agent;
+use crate::push::PushProfileError;
+use crate::report::CommandFailure;
+use crate::schema::{Versioned, SCHEMA_VERSION};
+use crate::transport::{InputMode, Invocation, OutputMode, RunOnNodeError, Transport};
+
+/// Name of the metadata file inside a bundle, next to the binary cache holding its closures
+pub const BUNDLE_FILE: &str = "bundle.json";
+
+/// Namespace of receipt signatures, so a signature made for anything else doesn't pass for one on a receipt
+pub const RECEIPT_NAMESPACE: &str = "deploy-rs-receipt";
+
+/// What `deploy bundle apply` needs to deploy a node without evaluating anything
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bundle {
+    pub node: String,
+    /// Profiles in the order they're activated
+    pub profiles: Vec<String>,
+    /// The evaluated `deploy` output, narrowed down to the node
+    pub deploy: serde_json::Value,
+    /// Missing from bundles made before it was recorded
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+}
+
+/// Where a bundle comes from, recorded in the receipts of the profiles deployed from it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    /// Revision of the flake, unless its tree was dirty
+    pub rev: Option<String>,
+    /// User who created the bundle
+    pub creator: String,
+    pub created: String,
+    /// Signatures of each profile's closure, by its store path
+    #[serde(default)]
+    pub signatures: BTreeMap<String, Vec<String>>,
+}
+
+/// What was deployed to a profile from a bundle, kept next to the profile on the node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub node: String,
+    pub profile: String,
+    pub closure: String,
+    pub activated: String,
+    /// Whether the closure's signatures were checked against trusted keys before importing it
+    pub signatures_verified: bool,
+    pub provenance: Option<Provenance>,
+    /// What the deployment was labelled with by `--label`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Whether the profile was made by another tool and adopted by `deploy adopt`, rather than deployed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub adopted: bool,
+    /// The fingerprint of the node's host key pinned with `pinHostKey` when the profile was deployed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
+    /// Who signed the receipt, as named in the allowed signers file it's checked against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+    /// SSH signature of the receipt without this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Bundle {
+    /// The deployment data the bundle was made from
+    pub fn data(&self) -> Result<crate::data::Data, BundleError> {
+        serde_json::from_value(self.deploy.clone()).map_err(BundleError::Parse)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BundleError {
+    #[error("Failed to query the paths of the bundle: {0}")]
+    PathInfo(std::io::Error),
+    #[error("Querying the paths of the bundle failed with {0}")]
+    PathInfoExit(CommandFailure),
+    #[error("Failed to parse the paths of the bundle: {0}")]
+    PathInfoParse(serde_json::Error),
+    #[error("Failed to export the closures: {0}")]
+    Export(#[from] PushProfileError),
+    #[error("Failed to write the bundle: {0}")]
+    Write(std::io::Error),
+    #[error("Failed to unpack the bundle: {0}")]
+    Unpack(std::io::Error),
+    #[error("Unpacking the bundle failed with {0}")]
+    UnpackExit(CommandFailure),
+    #[error("Failed to read the bundle's metadata: {0}")]
+    Read(std::io::Error),
+    #[error("Failed to parse the bundle's metadata: {0}")]
+    Parse(serde_json::Error),
+    #[error("The bundle is of schema version {0}, but this deploy only understands up to {1}")]
+    SchemaVersion(u32, u32),
+    #[error("Failed to import profile `{0}` from the bundle: {1}")]
+    Import(String, std::io::Error),
+    #[error("Importing a profile from the bundle failed with {0}")]
+    ImportExit(CommandFailure),
+    #[error("Failed to verify profile `{0}` in the bundle: {1}")]
+    Verify(String, std::io::Error),
+    #[error("Verifying a profile in the bundle failed with {0}, it's corrupted or not signed by a trusted key")]
+    VerifyExit(CommandFailure),
+    #[error("Failed to write the receipt over SSH: {0}")]
+    SSHReceipt(RunOnNodeError),
+    #[error("Writing the receipt over SSH failed with {0}")]
+    SSHReceiptExit(CommandFailure),
+    #[error("Failed to write the receipt: {0}")]
+    Receipt(std::io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ReceiptSignatureError {
+    #[error("The receipt isn't signed")]
+    Unsigned,
+    #[error("Failed to parse the receipt: {0}")]
+    Parse(serde_json::Error),
+    #[error("Failed to write the receipt to sign or verify it: {0}")]
+    Write(std::io::Error),
+    #[error("Failed to run ssh-keygen to sign the receipt: {0}")]
+    Sign(std::io::Error),
+    #[error("Signing the receipt failed with {0}")]
+    SignExit(CommandFailure),
+    #[error("Failed to read the signature of the receipt: {0}")]
+    ReadSignature(std::io::Error),
+    #[error("Failed to run ssh-keygen to verify the receipt: {0}")]
+    Verify(std::io::Error),
+    #[error("The receipt's signature isn't valid, or not by an allowed signer: {0}")]
+    VerifyExit(CommandFailure),
+}
+
+/// What a receipt's signature is made over: the receipt as written, without the signature
+fn signed_payload(receipt: &Versioned<Receipt>) -> Result<Vec<u8>, ReceiptSignatureError> {
+    let mut unsigned = receipt.clone();
+    unsigned.inner.signature = None;
+
+    serde_json::to_vec(&unsigned).map_err(ReceiptSignatureError::Parse)
+}
+
+/// A file to hand ssh-keygen what it signs or verifies, unique to this process
+fn payload_file(
+    receipt: &Receipt,
+    payload: &[u8],
+) -> Result<std::path::PathBuf, ReceiptSignatureError> {
+    let path = std::env::temp_dir().join(format!(
+        "deploy-rs-receipt-{}-{}-{}",
+        std::process::id(),
+        receipt.node,
+        receipt.profile
+    ));
+
+    std::fs::write(&path, payload).map_err(ReceiptSignatureError::Write)?;
+
+    Ok(path)
+}
+
+/// Signs a receipt with an SSH private key as `signer`, which auditors then check with `deploy verify-receipt`
+pub async fn sign_receipt(
+    transport: &dyn Transport,
+    receipt: &Receipt,
+    key: &str,
+    signer: &str,
+) -> Result<Receipt, ReceiptSignatureError> {
+    let mut signed = Receipt {
+        signer: Some(signer.to_string()),
+        signature: None,
+        ..receipt.clone()
+    };
+
+    let path = payload_file(&signed, &signed_payload(&Versioned::new(signed.clone()))?)?;
+    let signature_path = format!("{}.sig", path.to_string_lossy());
+
+    let mut sign_command = Invocation::new("ssh-keygen");
+    sign_command
+        .arg("-Y")
+        .arg("sign")
+        .arg("-f")
+        .arg(key)
+        .arg("-n")
+        .arg(RECEIPT_NAMESPACE)
+        .arg(path.to_string_lossy())
+        .stdout(OutputMode::Null);
+
+    let result = transport.run(&sign_command).await;
+    let signature = std::fs::read_to_string(&signature_path);
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&signature_path);
+
+    let sign_output = result.map_err(ReceiptSignatureError::Sign)?;
+
+    match sign_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(ReceiptSignatureError::SignExit(CommandFailure::new(
+                &sign_command,
+                &sign_output,
+            )))
+        }
+    };
+
+    signed.signature = Some(signature.map_err(ReceiptSignatureError::ReadSignature)?);
+
+    Ok(signed)
+}
+
+/// Checks the signature of a receipt against an allowed signers file (see `ssh-keygen(1)`), returning the receipt
+pub async fn verify_receipt(
+    transport: &dyn Transport,
+    receipt_json: &str,
+    allowed_signers: &str,
+) -> Result<Receipt, ReceiptSignatureError> {
+    let receipt: Versioned<Receipt> =
+        serde_json::from_str(receipt_json).map_err(ReceiptSignatureError::Parse)?;
+
+    let (signer, signature) = match (&receipt.inner.signer, &receipt.inner.signature) {
+        (Some(signer), Some(signature)) => (signer, signature),
+        _ => return Err(ReceiptSignatureError::Unsigned),
+    };
+
+    let path = payload_file(&receipt.inner, &signed_payload(&receipt)?)?;
+    let signature_path = format!("{}.sig", path.to_string_lossy());
+
+    let mut verify_command = Invocation::new("ssh-keygen");
+    verify_command
+        .arg("-Y")
+        .arg("verify")
+        .arg("-f")
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(signer)
+        .arg("-n")
+        .arg(RECEIPT_NAMESPACE)
+        .arg("-s")
+        .arg(&signature_path)
+        .stdin(InputMode::File(path.clone()))
+        .stdout(OutputMode::Null);
+
+    let result = match std::fs::write(&signature_path, signature) {
+        Ok(()) => transport
+            .run(&verify_command)
+            .await
+            .map_err(ReceiptSignatureError::Verify),
+        Err(e) => Err(ReceiptSignatureError::Write(e)),
+    };
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&signature_path);
+
+    let verify_output = result?;
+
+    match verify_output.code {
+        Some(0) => Ok(receipt.inner),
+        _ => Err(ReceiptSignatureError::VerifyExit(CommandFailure::new(
+            &verify_command,
+            &verify_output,
+        ))),
+    }
+}
+
+/// Revision of a flake as `nix flake metadata` reports it, which it doesn't for dirty trees
+///
+/// This is only recorded for reference, so anything going wrong is just a warning.
+pub async fn flake_revision(
+    transport: &dyn Transport,
+    repo: &str,
+    offline: bool,
+) -> Option<String> {
+    let mut metadata_command = Invocation::new("nix");
+    metadata_command.arg("flake").arg("metadata").arg("--json");
+
+    if offline {
+        metadata_command.arg("--offline");
+    }
+
+    metadata_command.arg(repo).stdout(OutputMode::Capture);
+
+    let metadata_output = match transport.run(&metadata_command).await {
+        Ok(x) if x.code == Some(0) => x,
+        Ok(x) => {
+            warn!(
+                "Not recording the flake's revision: {}",
+                CommandFailure::new(&metadata_command, &x)
+            );
+            return None;
+        }
+        Err(e) => {
+            warn!("Not recording the flake's revision: {}", e);
+            return None;
+        }
+    };
+
+    let metadata: serde_json::Value = serde_json::from_slice(&metadata_output.stdout).ok()?;
+
+    metadata["revision"].as_str().map(|x| x.to_string())
+}
+
+/// Writes the closures of a bundle, signatures included, as a `file://` binary cache next to its metadata, and
+/// packs both into the archive at `output`, returning its size
+pub async fn create_bundle(
+    transport: &dyn Transport,
+    mut bundle: Bundle,
+    closures: &[&str],
+    output: &Path,
+) -> Result<u64, BundleError> {
+    let mut path_info_command = Invocation::new("nix");
+    path_info_command
+        .arg("path-info")
+        .arg("--json")
+        .arg("--sigs")
+        .arg("--recursive");
+
+    for closure in closures {
+        path_info_command.arg(closure);
+    }
+
+    path_info_command.stdout(OutputMode::Capture);
+
+    let path_info_output = transport
+        .run(&path_info_command)
+        .await
+        .map_err(BundleError::PathInfo)?;
+
+    match path_info_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BundleError::PathInfoExit(CommandFailure::new(
+                &path_info_command,
+                &path_info_output,
+            )))
+        }
+    };
+
+    let path_infos = agent::parse_path_info(&String::from_utf8_lossy(&path_info_output.stdout))
+        .map_err(BundleError::PathInfoParse)?;
+
+    if let Some(ref mut provenance) = bundle.provenance {
+        for path_info in &path_infos {
+            if closures.contains(&path_info.path.as_str()) {
+                provenance
+                    .signatures
+                    .insert(path_info.path.clone(), path_info.signatures.clone());
+            }
+        }
+    }
+
+    info!(
+        "Bundling {} paths for node `{}`",
+        path_infos.len(),
+        bundle.node
+    );
+
+    let cache_dir = std::env::temp_dir().join(format!("deploy-rs-bundle-{}", std::process::id()));
+
+    let result = async {
+        std::fs::create_dir_all(&cache_dir).map_err(BundleError::Write)?;
+
+        std::fs::write(
+            cache_dir.join(BUNDLE_FILE),
+            serde_json::to_vec_pretty(&Versioned::new(&bundle)).map_err(BundleError::Parse)?,
+        )
+        .map_err(BundleError::Write)?;
+
+        // Air-gapped nodes have nothing yet, so every NAR goes in
+        let paths: Vec<String> = path_infos.iter().map(|x| x.path.clone()).collect();
+
+        Ok(crate::push::export_cache(transport, &cache_dir, output, &path_infos, &paths).await?)
+    }
+    .await;
+
+    let _ = std::fs::remove_dir_all(&cache_dir);
+
+    result
+}
+
+/// Unpacks a bundle into `dir`, returning its metadata
+pub async fn unpack_bundle(
+    transport: &dyn Transport,
+    archive: &Path,
+    dir: &Path,
+) -> Result<Bundle, BundleError> {
+    std::fs::create_dir_all(dir).map_err(BundleError::Unpack)?;
+
+    let mut tar_command = Invocation::new("tar");
+    tar_command
+        .arg("-C")
+        .arg(dir.to_string_lossy())
+        .arg("-xf")
+        .arg(archive.to_string_lossy());
+
+    let tar_output = transport
+        .run(&tar_command)
+        .await
+        .map_err(BundleError::Unpack)?;
+
+    match tar_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BundleError::UnpackExit(CommandFailure::new(
+                &tar_command,
+                &tar_output,
+            )))
+        }
+    };
+
+    parse_bundle(&std::fs::read_to_string(dir.join(BUNDLE_FILE)).map_err(BundleError::Read)?)
+}
+
+fn parse_bundle(json: &str) -> Result<Bundle, BundleError> {
+    let bundle: Versioned<Bundle> = serde_json::from_str(json).map_err(BundleError::Parse)?;
+
+    if bundle.schema_version > SCHEMA_VERSION {
+        return Err(BundleError::SchemaVersion(
+            bundle.schema_version,
+            SCHEMA_VERSION,
+        ));
+    }
+
+    Ok(bundle.inner)
+}
+
+/// Checks the contents of a profile's closure in an unpacked bundle, and that it's signed by a key the machine
+/// applying the bundle trusts unless the profile's `checkSigs` is off, before anything of it is imported
+///
+/// Returns whether the signatures were checked.
+pub async fn verify_profile(
+    deploy_data: &crate::DeployData<'_>,
+    dir: &Path,
+) -> Result<bool, BundleError> {
+    let check_sigs = deploy_data.merged_settings.check_sigs.unwrap_or(false);
+
+    let mut verify_command = Invocation::new("nix");
+    verify_command
+        .arg("verify")
+        .arg("--store")
+        .arg(format!("file://{}", dir.to_string_lossy()))
+        .arg("--recursive");
+
+    if check_sigs {
+        verify_command.arg("--sigs-needed").arg("1");
+    } else {
+        verify_command.arg("--no-trust");
+    }
+
+    verify_command.arg(&deploy_data.profile.profile_settings.path);
+
+    debug!("Running verify command: {}", verify_command);
+
+    let verify_output = deploy_data
+        .transport
+        .run(&verify_command)
+        .await
+        .map_err(|e| BundleError::Verify(deploy_data.profile_name.to_string(), e))?;
+
+    match verify_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BundleError::VerifyExit(CommandFailure::new(
+                &verify_command,
+                &verify_output,
+            )))
+        }
+    };
+
+    Ok(check_sigs)
+}
+
+/// Where the receipt of a profile is kept, next to its generations
+pub fn receipt_path(profile_path: &str) -> String {
+    format!("{}-receipt.json", profile_path)
+}
+
+fn build_receipt_command(sudo: &Option<String>, path: &str, receipt_json: &str) -> String {
+    let mut receipt_command = format!(
+        "sh -c {}",
+        crate::shell_quote(&format!(
+            "printf '%s\\n' {} > {}",
+            crate::shell_quote(receipt_json),
+            crate::shell_quote(path)
+        ))
+    );
+
+    if let Some(sudo_cmd) = sudo {
+        receipt_command = format!("{} {}", sudo_cmd, receipt_command);
+    }
+
+    receipt_command
+}
+
+/// Writes the receipt of a profile deployed from a bundle or adopted, on the node over SSH or, with `local`, on this
+/// machine
+pub async fn write_receipt(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+    receipt: &Receipt,
+    local: bool,
+) -> Result<(), BundleError> {
+    let path = receipt_path(&deploy_defs.profile_path);
+    let receipt_json =
+        serde_json::to_string(&Versioned::new(receipt)).map_err(BundleError::Parse)?;
+
+    if local {
+        return std::fs::write(&path, format!("{}\n", receipt_json)).map_err(BundleError::Receipt);
+    }
+
+    let receipt_command = build_receipt_command(&deploy_defs.sudo, &path, &receipt_json);
+
+    let target = deploy_data
+        .node_target(deploy_defs)
+        .map_err(|e| BundleError::SSHReceipt(e.into()))?;
+
+    let receipt_output = deploy_data
+        .transport
+        .run_on_node(&target, &receipt_command, false)
+        .await
+        .map_err(BundleError::SSHReceipt)?;
+
+    match receipt_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BundleError::SSHReceiptExit(CommandFailure::new(
+                &receipt_command,
+                &receipt_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+/// Copies a profile out of an unpacked bundle, into the local store or, given a `destination`, into another one
+///
+/// Signatures are checked against the destination's trusted keys unless the profile's `checkSigs` is off.
+pub async fn import_profile(
+    deploy_data: &crate::DeployData<'_>,
+    dir: &Path,
+    destination: Option<&str>,
+) -> Result<(), BundleError> {
+    let mut import_command = Invocation::new("nix");
+    import_command
+        .arg("copy")
+        .arg("--from")
+        .arg(format!("file://{}", dir.to_string_lossy()));
+
+    if let Some(destination) = destination {
+        import_command.arg("--to").arg(destination).env(
+            "NIX_SSHOPTS",
+            &crate::push::nix_sshopts(
+                &deploy_data.merged_settings.ssh_opts,
+                deploy_data.cmd_overrides,
+            ),
+        );
+
+        crate::push::set_nix_env(&mut import_command, &deploy_data.merged_settings.copy_env);
+    }
+
+    if !deploy_data.merged_settings.check_sigs.unwrap_or(false) {
+        import_command.arg("--no-check-sigs");
+    }
+
+    import_command.arg(&deploy_data.profile.profile_settings.path);
+
+    debug!("Running import command: {}", import_command);
+
+    let import_output = deploy_data
+        .transport
+        .run(&import_command)
+        .await
+        .map_err(|e| BundleError::Import(deploy_data.profile_name.to_string(), e))?;
+
+    match import_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(BundleError::ImportExit(CommandFailure::new(
+                &import_command,
+                &import_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+#[test]
+fn test_bundle_metadata() {
+    let bundle = Bundle {
+        node: "web-1".to_string(),
+        profiles: vec!["system".to_string()],
+        deploy: serde_json::json!({
+            "sshUser": "deploy",
+            "nodes": {
+                "web-1": {
+                    "hostname": "web-1.example.com",
+                    "profiles": { "system": { "path": "/nix/store/blah-system" } }
+                }
+            }
+        }),
+        provenance: Some(Provenance {
+            rev: Some("abc123".to_string()),
+            creator: "alice".to_string(),
+            created: "2021-03-01T00:00:00.250Z".to_string(),
+            signatures: BTreeMap::new(),
+        }),
+    };
+
+    let json = serde_json::to_string(&Versioned::new(&bundle)).unwrap();
+    assert_eq!(parse_bundle(&json).unwrap(), bundle);
+
+    let data = bundle.data().unwrap();
+    assert_eq!(
+        data.nodes["web-1"].node_settings.profiles["system"]
+            .profile_settings
+            .path,
+        "/nix/store/blah-system"
+    );
+
+    assert!(matches!(
+        parse_bundle(r#"{"schemaVersion":999,"node":"web-1","profiles":[],"deploy":{}}"#),
+        Err(BundleError::SchemaVersion(999, SCHEMA_VERSION))
+    ));
+
+    // Bundles from before provenance was recorded still apply
+    assert_eq!(
+        parse_bundle(r#"{"schemaVersion":1,"node":"web-1","profiles":[],"deploy":{}}"#)
+            .unwrap()
+            .provenance,
+        None
+    );
+}
+
+#[test]
+fn test_receipt_command_builder() {
+    assert_eq!(
+        build_receipt_command(
+            &Some("sudo -u root".to_string()),
+            "/nix/var/nix/profiles/system-receipt.json",
+            r#"{"profile":"system"}"#
+        ),
+        r#"sudo -u root sh -c 'printf '\''%s\n'\'' '\''{"profile":"system"}'\'' > '\''/nix/var/nix/profiles/system-receipt.json'\'''"#
+    );
+    assert_eq!(
+        build_receipt_command(&None, "/tmp/p-receipt.json", "{}"),
+        r#"sh -c 'printf '\''%s\n'\'' '\''{}'\'' > '\''/tmp/p-receipt.json'\'''"#
+    );
+}
+
+#[test]
+fn test_import_profile() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web-1",
+        "sshOpts": ["-p", "2222"],
+        "profiles": { "system": { "path": "/nix/store/blah-system" } }
+    }))
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+
+    let dir = Path::new("/tmp/bundle");
+
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        import_profile(&deploy_data, dir, None).await.unwrap();
+        import_profile(&deploy_data, dir, Some("ssh://root@web-1"))
+            .await
+            .unwrap();
+    });
+
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "nix copy --from file:///tmp/bundle --no-check-sigs /nix/store/blah-system",
+            "NIX_SSHOPTS=-p 2222 nix copy --from file:///tmp/bundle --to ssh://root@web-1 --no-check-sigs /nix/store/blah-system",
+        ]
+    );
+}
+
+#[test]
+fn test_verify_receipt() {
+    let receipt = Receipt {
+        node: "web-1".to_string(),
+        profile: "system".to_string(),
+        closure: "/nix/store/blah-system".to_string(),
+        activated: "2021-03-01T00:00:00.250Z".to_string(),
+        signatures_verified: true,
+        provenance: None,
+        label: Some("v1.4.0".to_string()),
+        adopted: false,
+        host_key: None,
+        signer: Some("alice".to_string()),
+        signature: Some("-----BEGIN SSH SIGNATURE-----".to_string()),
+    };
+
+    let json = serde_json::to_string(&Versioned::new(&receipt)).unwrap();
+
+    // The signature covers everything else, the label and the signer included
+    let payload = signed_payload(&serde_json::from_str(&json).unwrap()).unwrap();
+    assert_eq!(
+        String::from_utf8(payload).unwrap(),
+        format!(
+            r#"{{"schemaVersion":{},"node":"web-1","profile":"system","closure":"/nix/store/blah-system","activated":"2021-03-01T00:00:00.250Z","signatures_verified":true,"provenance":null,"label":"v1.4.0","signer":"alice"}}"#,
+            SCHEMA_VERSION
+        )
+    );
+
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("ssh-keygen -Y verify", Some(255), "");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    assert!(matches!(
+        runtime.block_on(verify_receipt(&transport, &json, "/etc/allowed_signers")),
+        Err(ReceiptSignatureError::VerifyExit(_))
+    ));
+
+    let payload_path = std::env::temp_dir().join(format!(
+        "deploy-rs-receipt-{}-web-1-system",
+        std::process::id()
+    ));
+    assert_eq!(
+        transport.commands(),
+        vec![format!(
+            "ssh-keygen -Y verify -f /etc/allowed_signers -I alice -n deploy-rs-receipt -s {}.sig < {}",
+            payload_path.display(),
+            payload_path.display()
+        )]
+    );
+    assert!(!payload_path.exists());
+
+    let unsigned = Receipt {
+        signer: None,
+        signature: None,
+        ..receipt
+    };
+    assert!(matches!(
+        runtime.block_on(verify_receipt(
+            &transport,
+            &serde_json::to_string(&Versioned::new(&unsigned)).unwrap(),
+            "/etc/allowed_signers"
+        )),
+        Err(ReceiptSignatureError::Unsigned)
+    ));
+}
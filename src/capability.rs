@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use thiserror::Error;
+
+/// The environment variable turning on read-only mode, for CI jobs which mustn't be able to turn it off
+pub const READ_ONLY_ENV: &str = "DEPLOY_RS_READ_ONLY";
+
+/// Something done to nodes which read-only mode doesn't allow. Building, copying closures to nodes and showing what
+/// a deployment would change are always allowed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Activating profiles, deferred or not, also by `promote`, `resume` and `bundle apply`
+    Activate,
+    /// Rolling back the profiles of a run with `--rollback-all`
+    RollBack,
+    /// Running commands on nodes with `exec`
+    Exec,
+    /// Writing files on nodes with `copy-file`
+    CopyFile,
+    /// Recording profiles as deployed with `adopt`
+    Adopt,
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Action::Activate => "Activating profiles",
+            Action::RollBack => "Rolling back profiles",
+            Action::Exec => "Running commands on nodes",
+            Action::CopyFile => "Copying files to nodes",
+            Action::Adopt => "Adopting profiles",
+        })
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CapabilityError {
+    #[error(
+        "{0} isn't allowed in read-only mode (--read-only or {})",
+        READ_ONLY_ENV
+    )]
+    ReadOnly(Action),
+}
+
+/// What this run of deploy may do to nodes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    /// Only build, copy and show what would change, activating nothing
+    pub read_only: bool,
+}
+
+impl Capabilities {
+    /// Read-only if `--read-only` was given or the value of `READ_ONLY_ENV` is set to anything but `0` or `false`
+    pub fn new(read_only_flag: bool, read_only_env: Option<&str>) -> Self {
+        let read_only_env = match read_only_env {
+            Some("") | Some("0") | Some("false") | None => false,
+            Some(_) => true,
+        };
+
+        Capabilities {
+            read_only: read_only_flag || read_only_env,
+        }
+    }
+
+    pub fn from_env(read_only_flag: bool) -> Self {
+        Capabilities::new(read_only_flag, std::env::var(READ_ONLY_ENV).ok().as_deref())
+    }
+
+    pub fn check(&self, action: Action) -> Result<(), CapabilityError> {
+        match self.read_only {
+            true => Err(CapabilityError::ReadOnly(action)),
+            false => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn test_capabilities() {
+    assert!(!Capabilities::new(false, None).read_only);
+    assert!(!Capabilities::new(false, Some("0")).read_only);
+    assert!(!Capabilities::new(false, Some("false")).read_only);
+    assert!(Capabilities::new(false, Some("1")).read_only);
+    assert!(Capabilities::new(true, Some("0")).read_only);
+
+    assert_eq!(Capabilities::new(false, None).check(Action::Exec), Ok(()));
+    assert_eq!(
+        Capabilities::new(true, None)
+            .check(Action::Activate)
+            .unwrap_err()
+            .to_string(),
+        "Activating profiles isn't allowed in read-only mode (--read-only or DEPLOY_RS_READ_ONLY)"
+    );
+}
@@ -0,0 +1,280 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::data::Data;
+use crate::state::StateError;
+
+/// A profile confirmed to work on a node of a channel, by activating it from this machine
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmedProfile {
+    pub node: String,
+    pub profile: String,
+    pub closure: String,
+    /// Seconds since the epoch
+    pub confirmed_at: u64,
+}
+
+/// The closures last confirmed on the nodes of a channel
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Channel {
+    #[serde(default)]
+    pub profiles: Vec<ConfirmedProfile>,
+}
+
+fn channel_path(dir: &Path, channel: &str) -> PathBuf {
+    dir.join("channels").join(format!("{}.json", channel))
+}
+
+/// Loads what was confirmed in a channel, which is nothing if it was never deployed to
+pub fn load_channel(dir: &Path, channel: &str) -> Result<Channel, StateError> {
+    match std::fs::read(channel_path(dir, channel)) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Channel::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records a profile as confirmed in a channel, replacing what was confirmed for it before
+pub fn confirm(dir: &Path, channel: &str, confirmed: ConfirmedProfile) -> Result<(), StateError> {
+    let mut state = load_channel(dir, channel)?;
+
+    state
+        .profiles
+        .retain(|x| x.node != confirmed.node || x.profile != confirmed.profile);
+    state.profiles.push(confirmed);
+
+    let path = channel_path(dir, channel);
+    let temp_path = path.with_extension("json.tmp");
+
+    std::fs::create_dir_all(dir.join("channels"))?;
+
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(&state)?)?;
+    std::fs::rename(&temp_path, &path)?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum PromoteError {
+    #[error("{0}")]
+    State(#[from] StateError),
+    #[error("Nothing was confirmed in channel `{0}` yet")]
+    NothingConfirmed(String),
+    #[error("No node has profiles in channel `{0}`, set with `channel`")]
+    NoProfiles(String),
+    #[error("Profile `{0}` was confirmed in channel `{1}` with different closures ({2}), so which to promote is unclear")]
+    Ambiguous(String, String, String),
+    #[error("None of the profiles in channel `{0}` were confirmed in channel `{1}`")]
+    NothingToPromote(String, String),
+}
+
+/// Narrows a deployment down to the profiles in channel `to`, and has each deploy the closure confirmed for the
+/// profile of the same name in `from` instead of what it evaluates to now
+///
+/// The nodes of both channels needn't be the same, but all nodes of `from` have to agree on a profile's closure.
+/// Profiles nothing was confirmed for are left out, with a warning.
+pub fn promote(
+    data: &mut Data,
+    from: &str,
+    confirmed: &Channel,
+    to: &str,
+) -> Result<(), PromoteError> {
+    if confirmed.profiles.is_empty() {
+        return Err(PromoteError::NothingConfirmed(from.to_string()));
+    }
+
+    let top_channel = data.generic_settings.channel.clone();
+    let mut in_channel = 0;
+    let mut promoted = 0;
+
+    for (node_name, node) in data.nodes.iter_mut() {
+        let node_channel = node
+            .generic_settings
+            .channel
+            .as_ref()
+            .or(top_channel.as_ref());
+        let mut left_out = Vec::new();
+
+        for (profile_name, profile) in node.node_settings.profiles.iter_mut() {
+            let channel = profile.generic_settings.channel.as_ref().or(node_channel);
+
+            if channel.map(|x| x.as_str()) != Some(to) {
+                left_out.push(profile_name.clone());
+                continue;
+            }
+
+            in_channel += 1;
+
+            let mut closures: Vec<&str> = confirmed
+                .profiles
+                .iter()
+                .filter(|x| &x.profile == profile_name)
+                .map(|x| x.closure.as_str())
+                .collect();
+            closures.sort_unstable();
+            closures.dedup();
+
+            match closures.as_slice() {
+                [] => {
+                    warn!(
+                        "Profile `{}` of node `{}` was never confirmed in channel `{}`, not promoting it",
+                        profile_name, node_name, from
+                    );
+                    left_out.push(profile_name.clone());
+                }
+                [closure] => {
+                    profile.profile_settings.path = closure.to_string();
+                    promoted += 1;
+                }
+                _ => {
+                    return Err(PromoteError::Ambiguous(
+                        profile_name.clone(),
+                        from.to_string(),
+                        closures.join(", "),
+                    ))
+                }
+            }
+        }
+
+        for profile_name in left_out {
+            node.node_settings.profiles.remove(&profile_name);
+        }
+    }
+
+    data.nodes
+        .retain(|_, node| !node.node_settings.profiles.is_empty());
+
+    match (in_channel, promoted) {
+        (0, _) => Err(PromoteError::NoProfiles(to.to_string())),
+        (_, 0) => Err(PromoteError::NothingToPromote(
+            to.to_string(),
+            from.to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_promote() {
+    let mut data: Data = serde_json::from_str(
+        r#"{
+            "channel": "prod",
+            "nodes": {
+                "staging-1": {
+                    "hostname": "staging-1",
+                    "channel": "staging",
+                    "profiles": { "system": { "path": "/nix/store/new-system" } }
+                },
+                "web-1": {
+                    "hostname": "web-1",
+                    "profiles": {
+                        "system": { "path": "/nix/store/new-system" },
+                        "app": { "path": "/nix/store/new-app" }
+                    }
+                },
+                "web-2": {
+                    "hostname": "web-2",
+                    "profiles": {
+                        "system": { "path": "/nix/store/new-system" },
+                        "debug": { "path": "/nix/store/new-debug", "channel": "staging" }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let confirmed = |node: &str, profile: &str, closure: &str| ConfirmedProfile {
+        node: node.to_string(),
+        profile: profile.to_string(),
+        closure: closure.to_string(),
+        confirmed_at: 0,
+    };
+
+    let staging = Channel {
+        profiles: vec![
+            confirmed("staging-1", "system", "/nix/store/old-system"),
+            confirmed("staging-2", "system", "/nix/store/old-system"),
+            confirmed("staging-1", "debug", "/nix/store/old-debug"),
+        ],
+    };
+
+    promote(&mut data, "staging", &staging, "prod").unwrap();
+
+    let mut promoted: Vec<(&str, &str, &str)> = data
+        .nodes
+        .iter()
+        .flat_map(|(node_name, node)| {
+            node.node_settings
+                .profiles
+                .iter()
+                .map(move |(profile_name, profile)| {
+                    (
+                        node_name.as_str(),
+                        profile_name.as_str(),
+                        profile.profile_settings.path.as_str(),
+                    )
+                })
+        })
+        .collect();
+    promoted.sort_unstable();
+
+    assert_eq!(
+        promoted,
+        vec![
+            ("web-1", "system", "/nix/store/old-system"),
+            ("web-2", "system", "/nix/store/old-system"),
+        ]
+    );
+
+    let diverged = Channel {
+        profiles: vec![
+            confirmed("staging-1", "system", "/nix/store/old-system"),
+            confirmed("staging-2", "system", "/nix/store/other-system"),
+        ],
+    };
+
+    match promote(&mut data, "staging", &diverged, "prod") {
+        Err(PromoteError::Ambiguous(profile, _, _)) => assert_eq!(profile, "system"),
+        x => panic!("expected an ambiguous promotion, got {:?}", x),
+    }
+
+    match promote(&mut data, "staging", &staging, "canary") {
+        Err(PromoteError::NoProfiles(channel)) => assert_eq!(channel, "canary"),
+        x => panic!("expected no profiles in the channel, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_confirm() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-channel-{}", std::process::id()));
+
+    assert_eq!(load_channel(&dir, "staging").unwrap(), Channel::default());
+
+    for (closure, confirmed_at) in &[("/nix/store/a", 1), ("/nix/store/b", 2)] {
+        confirm(
+            &dir,
+            "staging",
+            ConfirmedProfile {
+                node: "web-1".to_string(),
+                profile: "system".to_string(),
+                closure: closure.to_string(),
+                confirmed_at: *confirmed_at,
+            },
+        )
+        .unwrap();
+    }
+
+    let channel = load_channel(&dir, "staging").unwrap();
+    assert_eq!(channel.profiles.len(), 1);
+    assert_eq!(channel.profiles[0].closure, "/nix/store/b");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
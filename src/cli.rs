@@ -4,13 +4,19 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use std::collections::HashMap;
-use std::io::{stdin, stdout, Write};
+use std::io::{stdin, stdout, Read, Write};
+use std::path::Path;
 
 use clap::{ArgMatches, Clap, FromArgMatches};
 
 use crate as deploy;
 
-use self::deploy::{DeployFlake, ParseFlakeError};
+use self::deploy::data::ProfileFailurePolicy;
+use self::deploy::events::{record_failure, record_phase, EventLog, Phase, Status};
+use self::deploy::report::Reported;
+use self::deploy::transport::Transport;
+use self::deploy::warnings::{raise, Warning};
+use self::deploy::{DeployFlake, ExitCode, ParseFlakeError};
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
 use serde::Serialize;
@@ -29,7 +35,7 @@ pub struct Opts {
     /// A list of flakes to deploy alternatively
     #[clap(long, group = "deploy")]
     targets: Option<Vec<String>>,
-    /// Check signatures when using `nix copy`
+    /// Check signatures when using `nix copy` on every node, regardless of `checkSigs`
     #[clap(short, long)]
     checksigs: bool,
     /// Use the interactive prompt before deployment
@@ -38,23 +44,54 @@ pub struct Opts {
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
 
-    /// Print debug logs to output
+    /// Print debug logs to output (same as `-v`)
     #[clap(short, long)]
     debug_logs: bool,
+    /// Print more logs, `-v` for debug logs and `-vv` for trace logs
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Only print warnings and errors
+    #[clap(short, long, conflicts_with_all = &["verbose", "debug-logs"])]
+    quiet: bool,
+    /// When to color log output, either `auto`, `always` or `never`
+    #[clap(long, default_value = "auto")]
+    color: deploy::ColorChoice,
+    /// Print log output without emoji, e.g. for CI systems
+    #[clap(long)]
+    ascii: bool,
+    /// Per-module log levels on top of the verbosity, e.g. `nix_copy=debug,deploy::deploy=trace`
+    #[clap(long)]
+    log_filter: Option<String>,
     /// Directory to print logs to (including the background activation process)
     #[clap(long)]
     log_dir: Option<String>,
+    /// File to append structured records of every log message and deployment phase to, regardless of verbosity
+    #[clap(long)]
+    log_file: Option<String>,
+    /// Format of the records written to `--log-file`, either `json` or `logfmt`
+    #[clap(long, default_value = "json")]
+    log_file_format: deploy::events::LogFileFormat,
+    /// Print the JSON Schema of the JSON deploy-rs writes (event log records, pull descriptors and
+    /// `diff-remote --json`) and exit
+    #[clap(long)]
+    output_schema: bool,
 
     /// Keep the build outputs of each built profile
     #[clap(short, long)]
     keep_result: bool,
-    /// Location to keep outputs from built profiles in
+    /// Location to keep outputs from built profiles in, `gcroots` in the state directory by default
     #[clap(short, long)]
     result_path: Option<String>,
 
-    /// Skip the automatic pre-build checks
+    /// Skip the automatic pre-build checks, except the `requiredChecks` of the deployed nodes
     #[clap(short, long)]
     skip_checks: bool,
+    /// Only run the given flake checks (from `checks.<system>`) instead of all of them, can be repeated
+    #[clap(long = "check", multiple_occurrences(true), number_of_values(1))]
+    checks: Vec<String>,
+    /// Don't check that every node can be reached, logged in to and sudo'd on before building anything
+    #[clap(long)]
+    skip_preflight: bool,
 
     /// Override the SSH user with the given value
     #[clap(long)]
@@ -62,15 +99,61 @@ pub struct Opts {
     /// Override the profile user with the given value
     #[clap(long)]
     profile_user: Option<String>,
-    /// Override the SSH options used
+    /// Override the SSH options used, split at spaces
     #[clap(long)]
     ssh_opts: Option<String>,
+    /// Override the SSH options used with a single argument, which may contain spaces (can be given multiple
+    /// times, e.g. `--ssh-opt -o --ssh-opt 'ProxyCommand=ssh -W %h:%p bastion'`)
+    #[clap(
+        long,
+        multiple_occurrences(true),
+        number_of_values(1),
+        allow_hyphen_values(true)
+    )]
+    ssh_opt: Vec<String>,
+    /// Don't add the options in `NIX_SSHOPTS` from the environment to those copies to nodes use
+    #[clap(long)]
+    ignore_nix_sshopts: bool,
+    /// The flake output holding the deployment, `deploy` or else `deployments` by default
+    #[clap(long)]
+    deploy_attr: Option<String>,
+    /// Keep Nix off the network, for deploying from hermetic build environments: evaluations and builds don't use
+    /// substituters or fetch anything again, fail if the flake's lock file is incomplete, and nodes are sent whole
+    /// closures instead of substituting them
+    #[clap(long)]
+    offline: bool,
+    /// Fail evaluations which build derivations to import Nix from (import from derivation), e.g. to keep them fast
+    /// in CI, instead of warning about them
+    #[clap(long)]
+    forbid_ifd: bool,
+    /// Authenticate to one node at a time and share that connection, so keys that need a touch (e.g. FIDO2) only
+    /// ask once per node. This is turned on by itself when the SSH agent holds such keys
+    #[clap(long)]
+    serial_auth: bool,
     /// Override if the connecting to the target node should be considered fast
     #[clap(long)]
     fast_connection: Option<bool>,
+    /// Override if the node should substitute the closure from a temporary binary cache served by this machine
+    #[clap(long)]
+    serve_store: Option<bool>,
+    /// Override if the node should build its profiles itself, from their derivations copied to it
+    #[clap(long)]
+    remote_build: Option<bool>,
+    /// Override the cores each local build job may use
+    #[clap(long)]
+    build_cores: Option<u32>,
+    /// Override how many local build jobs run at once
+    #[clap(long)]
+    build_max_jobs: Option<u32>,
+    /// Override the niceness local builds run with, 0 to 19
+    #[clap(long)]
+    build_nice: Option<u8>,
     /// Override if a rollback should be attempted if activation fails
     #[clap(long)]
     auto_rollback: Option<bool>,
+    /// Skip activating profiles whose closure is active on their node already
+    #[clap(long)]
+    skip_active: Option<bool>,
     /// Override hostname used for the node
     #[clap(long)]
     hostname: Option<String>,
@@ -80,18 +163,430 @@ pub struct Opts {
     /// How long activation should wait for confirmation (if using magic-rollback)
     #[clap(long)]
     confirm_timeout: Option<u16>,
+    /// How long the activation script may run before it's stopped and the profile is rolled back
+    #[clap(long)]
+    activation_timeout: Option<u16>,
+    /// How long to wait for SSH connections to nodes to be established
+    #[clap(long)]
+    ssh_connect_timeout: Option<u16>,
+    /// Pin the host keys of nodes in deploy's state the first time they're connected to, and refuse to connect
+    /// when they change, whatever ~/.ssh/known_hosts says
+    #[clap(long)]
+    pin_host_keys: bool,
     /// Where to store temporary files (only used by magic-rollback)
     #[clap(long)]
     temp_path: Option<String>,
     /// Show what will be activated on the machines
     #[clap(long)]
     dry_activate: bool,
+    /// For nodes outside their `maintenanceWindow`, push the profile now and have the node activate it by itself
+    /// when the window opens
+    #[clap(long, conflicts_with = "force")]
+    defer: bool,
+    /// Activate nodes outside their `maintenanceWindow` anyway
+    #[clap(long)]
+    force: bool,
+    /// Deploy to nodes with `protected` set, rather than being asked to type each of their names
+    #[clap(long)]
+    production: bool,
+    /// Fail on warnings, without activating anything if there are some before activating
+    #[clap(long)]
+    deny_warnings: bool,
+    /// Only build, copy and show what would change, leaving the profiles on the nodes without activating them, and
+    /// refuse to run commands on nodes. Setting `DEPLOY_RS_READ_ONLY` does the same, and can't be undone by flags
+    #[clap(long)]
+    read_only: bool,
+    /// Label the profile generations made, e.g. with a git tag or ticket number, which is kept next to them on the
+    /// nodes and in the history of each node
+    #[clap(long)]
+    label: Option<String>,
+    /// Sign the receipts left next to the profiles on the nodes with this SSH private key, so `deploy verify-receipt`
+    /// can check who deployed them from which revision. Deployments only leave receipts when they're signed,
+    /// `deploy adopt` and `deploy bundle apply` always do
+    #[clap(long)]
+    sign_receipts: Option<String>,
+    /// Who signs the receipts, as named in the allowed signers file they're checked against, the user running deploy
+    /// by default
+    #[clap(long)]
+    receipt_signer: Option<String>,
+    /// Print how long evaluating, and building, signing, copying, activating and confirming on each node took at the
+    /// end
+    #[clap(long)]
+    timings: bool,
+    /// Deploy only this share of the nodes of each flake, e.g. `10%`, picked the same way for the same revision
+    #[clap(long)]
+    canary: Option<deploy::rollout::Percentage>,
+    /// Deploy only the nodes named in this file, one per line or as a JSON array, `-` reading them from standard input
+    #[clap(long)]
+    nodes_from: Option<String>,
+    /// Pick canaries by the value of a `key=value` tag with weights, e.g. `region=eu:2,us:1`; nodes with other
+    /// values aren't picked
+    #[clap(long, requires = "canary")]
+    weight: Option<deploy::rollout::Weights>,
+    /// Show the changes to /etc and systemd units on the machines, without activating
+    #[clap(long)]
+    diff_etc: bool,
+    /// Show package changes between the deployed and new closures (defaults to true in interactive mode)
+    #[clap(long)]
+    closure_diff: Option<bool>,
+    /// Instead of pushing and activating, write descriptors for nodes to pull their profiles with into this directory
+    #[clap(long, requires = "pull-sign-key")]
+    pull_descriptors: Option<String>,
+    /// Sign the pull descriptors with this SSH private key, nodes only act on those signed by a key in the allowed
+    /// signers file given to `activate-rs pull --allowed-signers`
+    #[clap(long, requires = "pull-descriptors")]
+    pull_sign_key: Option<String>,
+    /// Who signs the pull descriptors, as named in the allowed signers file nodes check them against, the user running
+    /// deploy by default
+    #[clap(long, requires = "pull-descriptors")]
+    pull_signer: Option<String>,
+    /// Store to copy the built profiles to in pull mode, e.g. `s3://my-cache`
+    #[clap(long, requires = "pull-descriptors")]
+    pull_copy_to: Option<String>,
+    /// Binary cache the nodes should substitute from in pull mode (can be given multiple times)
+    #[clap(
+        long,
+        requires = "pull-descriptors",
+        multiple_occurrences(true),
+        number_of_values(1)
+    )]
+    pull_substituter: Vec<String>,
     /// Revoke all previously succeeded deploys when deploying multiple profiles
     #[clap(long)]
     rollback_succeeded: Option<bool>,
+    /// Instead of deploying, roll back every profile a recorded run activated to the generation before, the last one
+    /// activated first
+    #[clap(long)]
+    rollback_all: Option<String>,
+    /// Certificate to authenticate to node agents with (for nodes with `agentPort` set)
+    #[clap(long, env = "DEPLOY_RS_AGENT_CERT")]
+    agent_cert: Option<String>,
+    /// Private key of the certificate given with --agent-cert
+    #[clap(long, env = "DEPLOY_RS_AGENT_KEY")]
+    agent_key: Option<String>,
+    /// CA certificate the node agents' certificates are checked against
+    #[clap(long, env = "DEPLOY_RS_AGENT_CA")]
+    agent_ca: Option<String>,
+    /// Program to ask for SSH passphrases and sudo passwords instead of the terminal. Like `SSH_ASKPASS`, it gets
+    /// the prompt as its argument and prints the answer
+    #[clap(long, env = "DEPLOY_RS_ASKPASS")]
+    askpass: Option<String>,
     /// Which sudo command to use. Must accept at least two arguments: user name to execute commands as and the rest is the command to execute
     #[clap(long)]
     sudo: Option<String>,
+
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(Clap, Debug, Clone)]
+pub enum SubCommand {
+    Exec(ExecOpts),
+    CopyFile(CopyFileOpts),
+    DiffRemote(DiffRemoteOpts),
+    Graph(GraphOpts),
+    Bundle(BundleOpts),
+    Verify(VerifyOpts),
+    State(StateOpts),
+    Facts(FactsOpts),
+    BenchCopy(BenchCopyOpts),
+    Adopt(AdoptOpts),
+    Import(ImportOpts),
+    GenBootstrap(GenBootstrapOpts),
+    CachePush(CachePushOpts),
+    DiffRuns(DiffRunsOpts),
+    Promote(PromoteOpts),
+    VerifyReceipt(VerifyReceiptOpts),
+    Pause(PauseOpts),
+    Resume(ResumeOpts),
+}
+
+/// Run a command on every selected node
+#[derive(Clap, Debug, Clone)]
+pub struct ExecOpts {
+    /// The flake to take nodes from, optionally narrowed down to a single node
+    #[clap(default_value = ".")]
+    target: String,
+    /// Only run on nodes with this tag (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    tag: Vec<String>,
+    /// How many nodes to run the command on at the same time
+    #[clap(short, long, default_value = "8")]
+    jobs: usize,
+    /// The command to run
+    #[clap(last(true), required(true))]
+    command: Vec<String>,
+}
+
+/// Copy a file to a node, using the node's connection settings
+#[derive(Clap, Debug, Clone)]
+pub struct CopyFileOpts {
+    /// The local file to copy
+    source: String,
+    /// Where to copy the file to, in the form `<node>:<path>`
+    destination: String,
+    /// The flake to take the node from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+    /// Octal file mode to set on the copied file
+    #[clap(long)]
+    mode: Option<String>,
+    /// Write the file as this user (using the node's sudo command)
+    #[clap(long)]
+    as_user: Option<String>,
+}
+
+/// Show the package differences between the closures a profile is deployed with on two nodes
+#[derive(Clap, Debug, Clone)]
+pub struct DiffRemoteOpts {
+    /// The node to compare from
+    from: String,
+    /// The node to compare to
+    to: String,
+    /// The profile to compare
+    #[clap(short, long, default_value = "system")]
+    profile: String,
+    /// The flake to take the nodes from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+    /// Print the differences as JSON instead
+    #[clap(long)]
+    json: bool,
+}
+
+/// Print the nodes of a flake with their profiles and the order they're deployed in, as a graph
+#[derive(Clap, Debug, Clone)]
+pub struct GraphOpts {
+    /// The flake to take nodes from, optionally narrowed down to a single node
+    #[clap(default_value = ".")]
+    target: String,
+    /// Format of the graph, either `dot` (Graphviz) or `d2`
+    #[clap(long, default_value = "dot")]
+    format: deploy::graph::GraphFormat,
+}
+
+/// Write a node's profiles into a file, and deploy them from it to nodes deploy can't reach otherwise
+#[derive(Clap, Debug, Clone)]
+pub struct BundleOpts {
+    #[clap(subcommand)]
+    action: BundleAction,
+}
+
+#[derive(Clap, Debug, Clone)]
+pub enum BundleAction {
+    Create(BundleCreateOpts),
+    Apply(BundleApplyOpts),
+}
+
+/// Build a node's profiles and write them, with everything needed to activate them, into a bundle
+#[derive(Clap, Debug, Clone)]
+pub struct BundleCreateOpts {
+    /// The node to bundle
+    node: String,
+    /// Where to write the bundle, `<node>.closure` by default
+    #[clap(short, long)]
+    output: Option<String>,
+    /// The flake to take the node from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+    /// Only bundle this profile of the node
+    #[clap(long)]
+    profile: Option<String>,
+}
+
+/// Import a bundle and activate its profiles, from a machine which can reach the node or with `--local` on the
+/// node itself
+#[derive(Clap, Debug, Clone)]
+pub struct BundleApplyOpts {
+    /// The bundle to apply
+    file: String,
+    /// Import and activate the bundle on this machine, which has to be the node, running as the profiles' user
+    #[clap(long)]
+    local: bool,
+    /// Sign the receipts left on the node with this SSH private key, so `deploy verify-receipt` can check them
+    #[clap(long)]
+    sign_key: Option<String>,
+    /// Who signs the receipts, as named in the allowed signers file they're checked against, the user running
+    /// deploy by default
+    #[clap(long)]
+    signer: Option<String>,
+}
+
+/// Run the health checks of deployed profiles again, without deploying anything: what activation waits for
+/// (`waitForUnit`, `waitForPort` and `waitForUrl`, checked once) and the `postActivateChecks`
+#[derive(Clap, Debug, Clone)]
+pub struct VerifyOpts {
+    /// The flake to take nodes from, optionally narrowed down to a single node or profile
+    #[clap(default_value = ".")]
+    target: String,
+    /// Only verify nodes with this tag (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    tag: Vec<String>,
+    /// How many profiles to verify at the same time
+    #[clap(short, long, default_value = "8")]
+    jobs: usize,
+}
+
+/// Show or forget what deploy remembers about nodes between runs: connection measurements, the history of
+/// deployments and kept build results
+#[derive(Clap, Debug, Clone)]
+pub struct StateOpts {
+    #[clap(subcommand)]
+    action: StateAction,
+}
+
+#[derive(Clap, Debug, Clone)]
+pub enum StateAction {
+    Show(StateShowOpts),
+    Clean(StateCleanOpts),
+}
+
+/// Show where the state is kept and what it says about each node
+#[derive(Clap, Debug, Clone)]
+pub struct StateShowOpts {
+    /// Only show this node
+    node: Option<String>,
+}
+
+/// Forget everything about a node, or about all nodes, removing their kept build results so Nix can collect them
+#[derive(Clap, Debug, Clone)]
+pub struct StateCleanOpts {
+    /// Only forget this node
+    node: Option<String>,
+}
+
+/// Show what deploy knows about a node's system, which decides how some commands are run on it
+#[derive(Clap, Debug, Clone)]
+pub struct FactsOpts {
+    /// The node to show the facts of
+    node: String,
+    /// The flake to take the node from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+    /// Gather the facts again even if recent ones are recorded
+    #[clap(long)]
+    refresh: bool,
+}
+
+/// Measure how quickly a sample closure is copied to a node plainly, substituting on the node and compressed, and
+/// record the quickest as the way of copying to it
+#[derive(Clap, Debug, Clone)]
+pub struct BenchCopyOpts {
+    /// The node to copy to
+    node: String,
+    /// The flake to take the node from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+}
+
+/// Take over the profiles of a node made by another tool, like nixos-rebuild or colmena, recording what they point to
+/// as if deploy had deployed it, so later deployments can be rolled back to it
+#[derive(Clap, Debug, Clone)]
+pub struct AdoptOpts {
+    /// The node to adopt the profiles of
+    node: String,
+    /// The flake to take the node from, whose profiles of the node are looked for on it
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+}
+
+/// Print a cloud-init or Ignition config for creating a node with, which lets the node's SSH user log in with the
+/// deployer's key and enables flakes, so it can be deployed to as soon as it's up
+#[derive(Clap, Debug, Clone)]
+pub struct GenBootstrapOpts {
+    /// The node to write the config for
+    node: String,
+    /// The flake to take the node from
+    #[clap(short, long, default_value = ".")]
+    flake: String,
+    /// What to write the config for, `cloud-init` or `ignition`
+    #[clap(long, default_value = "cloud-init")]
+    format: deploy::bootstrap::Format,
+    /// A public key to let log in (can be given multiple times), the first of `~/.ssh/id_*.pub` if none is given
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    key: Vec<String>,
+    /// Write the config to this file instead of standard output
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+/// Build the profiles of every selected node and copy them to a binary cache, without connecting to any node, so
+/// they can be deployed from it later
+#[derive(Clap, Debug, Clone)]
+pub struct CachePushOpts {
+    /// The flake to take nodes from, optionally narrowed down to a single node or profile
+    #[clap(default_value = ".")]
+    target: String,
+    /// The store to copy to, e.g. `s3://cache?region=eu-west-1` or `file:///srv/cache`
+    #[clap(long)]
+    to: String,
+    /// Only push nodes with this tag (can be given multiple times)
+    #[clap(long, multiple_occurrences(true), number_of_values(1))]
+    tag: Vec<String>,
+}
+
+/// Compare two recorded deployment runs: which profiles changed closure, which were skipped and which took noticeably
+/// longer to push or activate
+#[derive(Clap, Debug, Clone)]
+pub struct DiffRunsOpts {
+    /// The earlier run, by its id or the path of its manifest, the second to last run by default
+    run_a: Option<String>,
+    /// The later run, by its id or the path of its manifest, the last run by default
+    run_b: Option<String>,
+}
+
+/// Deploy the closures last confirmed on the profiles of one channel to the profiles of another, rather than what
+/// the flake evaluates to now. Profiles are in a channel through their `channel` setting
+#[derive(Clap, Debug, Clone)]
+pub struct PromoteOpts {
+    /// The channel to take closures from, e.g. `staging`
+    from: String,
+    /// The channel to deploy them to, e.g. `prod`
+    to: String,
+    /// The flake to take nodes from, optionally narrowed down to a single node or profile
+    #[clap(default_value = ".")]
+    target: String,
+}
+
+/// Check the signature of a receipt `deploy bundle apply --sign-key` left on a node, and show what it says was
+/// deployed
+#[derive(Clap, Debug, Clone)]
+pub struct VerifyReceiptOpts {
+    /// The receipt, e.g. a copy of `/nix/var/nix/profiles/system-receipt.json` from the node
+    receipt: String,
+    /// The SSH keys of the deployers whose signatures are accepted, in the allowed signers format of `ssh-keygen`
+    #[clap(long)]
+    allowed_signers: String,
+}
+
+/// Translate the nodes of a colmena, morph or NixOps network into a `deploy` output to start a flake's from
+#[derive(Clap, Debug, Clone)]
+pub struct ImportOpts {
+    /// The tool the network is for, `colmena`, `morph` or `nixops`
+    #[clap(long)]
+    from: deploy::import::Tool,
+    /// The file of the network, e.g. `hive.nix`
+    file: String,
+    /// The system the nodes run, for the activation of their profiles
+    #[clap(long, default_value = "x86_64-linux")]
+    system: String,
+    /// Write the `deploy` output to this file instead of standard output
+    #[clap(short, long)]
+    output: Option<String>,
+}
+
+/// Stop a deployment in progress once the profiles it's activating now are done, so it can be resumed later
+#[derive(Clap, Debug, Clone)]
+pub struct PauseOpts {
+    /// The id of the run, which the deployment logs when it starts
+    run: String,
+}
+
+/// Continue a paused deployment with the profiles it had left to activate, evaluating them from the flake again
+#[derive(Clap, Debug, Clone)]
+pub struct ResumeOpts {
+    /// The id of the paused run
+    run: String,
 }
 
 /// Returns if the available Nix installation supports flakes
@@ -116,37 +611,212 @@ pub enum CheckDeploymentError {
     NixCheck(#[from] std::io::Error),
     #[error("Nix checking command resulted in a bad exit code: {0:?}")]
     NixCheckExit(Option<i32>),
+    #[error("The checks failed to evaluate, the error is above")]
+    NixCheckEval,
+    #[error("Building {0} for the checks failed")]
+    NixCheckBuild(String),
+    #[error("The checks failed to fetch something, check that the substituters can be reached")]
+    NixCheckNetwork,
+}
+
+/// Which of a flake's checks to run before deploying it
+#[derive(Debug, PartialEq)]
+enum CheckSelection {
+    All,
+    Only(Vec<String>),
+}
+
+/// Checks that have to pass before deploying the selected nodes, even if checks are skipped
+fn required_checks(deploy_flake: &DeployFlake<'_>, data: &deploy::data::Data) -> Vec<String> {
+    let mut checks = data.generic_settings.required_checks.clone();
+
+    for (node_name, node) in &data.nodes {
+        if matches!(deploy_flake.node, Some(ref n) if n != node_name) {
+            continue;
+        }
+
+        checks.extend(node.generic_settings.required_checks.iter().cloned());
+
+        for (profile_name, profile) in &node.node_settings.profiles {
+            if matches!(deploy_flake.profile, Some(ref p) if p != profile_name) {
+                continue;
+            }
+
+            checks.extend(profile.generic_settings.required_checks.iter().cloned());
+        }
+    }
+
+    checks
+}
+
+fn select_checks(skip_checks: bool, checks: &[String], required: Vec<String>) -> CheckSelection {
+    let mut selected = match (skip_checks, checks.is_empty()) {
+        (false, true) => return CheckSelection::All,
+        (true, _) => required,
+        (false, false) => checks.iter().cloned().chain(required).collect(),
+    };
+
+    selected.sort();
+    selected.dedup();
+
+    CheckSelection::Only(selected)
+}
+
+#[test]
+fn test_check_selection() {
+    let data: deploy::data::Data = serde_json::from_str(
+        r#"{
+            "requiredChecks": ["deploy-schema"],
+            "nodes": {
+                "web-1": {
+                    "hostname": "web-1",
+                    "requiredChecks": ["web-vm-test"],
+                    "profiles": { "system": { "path": "/nix/store/blah" } }
+                },
+                "db-1": {
+                    "hostname": "db-1",
+                    "profiles": {
+                        "system": { "path": "/nix/store/blah", "requiredChecks": ["db-vm-test"] }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let web = DeployFlake {
+        repo: ".",
+        node: Some("web-1".to_string()),
+        profile: None,
+    };
+    let everything = DeployFlake {
+        repo: ".",
+        node: None,
+        profile: None,
+    };
+
+    let mut required = required_checks(&everything, &data);
+    required.sort();
+    assert_eq!(required, vec!["db-vm-test", "deploy-schema", "web-vm-test"]);
+
+    assert_eq!(
+        select_checks(false, &[], required_checks(&web, &data)),
+        CheckSelection::All
+    );
+    assert_eq!(
+        select_checks(true, &[], required_checks(&web, &data)),
+        CheckSelection::Only(vec!["deploy-schema".to_string(), "web-vm-test".to_string()])
+    );
+    assert_eq!(
+        select_checks(
+            false,
+            &["deploy-activate".to_string(), "deploy-schema".to_string()],
+            required_checks(&web, &data)
+        ),
+        CheckSelection::Only(vec![
+            "deploy-activate".to_string(),
+            "deploy-schema".to_string(),
+            "web-vm-test".to_string()
+        ])
+    );
+}
+
+/// The system the checks of a flake are built for, which `nix flake check` finds out by itself
+async fn current_system() -> Result<String, CheckDeploymentError> {
+    let output = Command::new("nix")
+        .arg("eval")
+        .arg("--impure")
+        .arg("--raw")
+        .arg("--expr")
+        .arg("builtins.currentSystem")
+        .stdout(Stdio::piped())
+        .output()
+        .await?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(CheckDeploymentError::NixCheckExit(a)),
+    };
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
 async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
+    checks: &CheckSelection,
     extra_build_args: &[String],
 ) -> Result<(), CheckDeploymentError> {
-    info!("Running checks for flake in {}", repo);
+    match checks {
+        CheckSelection::All => info!("Running checks for flake in {}", repo),
+        CheckSelection::Only(names) => {
+            info!("Running checks {} for flake in {}", names.join(", "), repo)
+        }
+    }
 
     let mut check_command = match supports_flakes {
-        true => Command::new("nix"),
-        false => Command::new("nix-build"),
+        true => deploy::transport::Invocation::new("nix"),
+        false => deploy::transport::Invocation::new("nix-build"),
     };
 
-    if supports_flakes {
-        check_command.arg("flake").arg("check").arg(repo);
-    } else {
-        check_command.arg("-E")
+    match (supports_flakes, checks) {
+        (true, CheckSelection::All) => {
+            check_command.arg("flake").arg("check").arg(repo);
+        }
+        (true, CheckSelection::Only(names)) => {
+            let system = current_system().await?;
+
+            check_command.arg("build").arg("--no-link");
+
+            for name in names {
+                check_command.arg(format!(
+                    "{}#checks.{}.{}",
+                    repo,
+                    system,
+                    deploy::nix_attr(name)
+                ));
+            }
+        }
+        (false, _) => {
+            check_command.arg("-E")
                 .arg("--no-out-link")
                 .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo));
+
+            if let CheckSelection::Only(names) = checks {
+                for name in names {
+                    check_command.arg("-A").arg(deploy::nix_attr(name));
+                }
+            }
+        }
     }
 
     for extra_arg in extra_build_args {
         check_command.arg(extra_arg);
     }
 
-    let check_status = check_command.status().await?;
+    // So that a failure can be told apart by what Nix logged
+    if supports_flakes {
+        check_command.args(&deploy::nixlog::LOG_FORMAT_ARGS);
+    }
+
+    let check_output = deploy::transport::SystemTransport
+        .run(&check_command)
+        .await?;
 
-    match check_status.code() {
+    match check_output.code {
         Some(0) => (),
-        a => return Err(CheckDeploymentError::NixCheckExit(a)),
+        a => {
+            return Err(match deploy::nixlog::classify(&check_output.stderr_tail) {
+                Some(deploy::nixlog::NixFailure::Eval) => CheckDeploymentError::NixCheckEval,
+                Some(deploy::nixlog::NixFailure::Build(derivation)) => {
+                    CheckDeploymentError::NixCheckBuild(derivation)
+                }
+                Some(deploy::nixlog::NixFailure::Network) => CheckDeploymentError::NixCheckNetwork,
+                Some(deploy::nixlog::NixFailure::Daemon) | None => {
+                    CheckDeploymentError::NixCheckExit(a)
+                }
+            })
+        }
     };
 
     Ok(())
@@ -156,158 +826,372 @@ async fn check_deployment(
 pub enum GetDeploymentDataError {
     #[error("Failed to execute nix eval command: {0}")]
     NixEval(std::io::Error),
-    #[error("Failed to read output from evaluation: {0}")]
-    NixEvalOut(std::io::Error),
     #[error("Evaluation resulted in a bad exit code: {0:?}")]
     NixEvalExit(Option<i32>),
+    #[error(
+        "Evaluating the deployment builds {0} to import from, which --forbid-ifd doesn't allow"
+    )]
+    ForbiddenIfd(String),
     #[error("Error converting evaluation output to utf8: {0}")]
     DecodeUtf8(#[from] std::string::FromUtf8Error),
     #[error("Error decoding the JSON from evaluation: {0}")]
     DecodeJson(#[from] serde_json::error::Error),
     #[error("Impossible happened: profile is set but node is not")]
     ProfileNoNode,
+    #[error("{0}")]
+    Terraform(#[from] deploy::terraform::TerraformError),
 }
 
 /// Evaluates the Nix in the given `repo` and return the processed Data from it
 async fn get_deployment_data(
     supports_flakes: bool,
     flakes: &[deploy::DeployFlake<'_>],
+    deploy_attr: Option<&str>,
     extra_build_args: &[String],
 ) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
-    futures_util::stream::iter(flakes).then(|flake| async move {
+    let mut data = Vec::new();
 
-    info!("Evaluating flake in {}", flake.repo);
+    for deploy_json in
+        evaluate_deployments(supports_flakes, flakes, deploy_attr, extra_build_args).await?
+    {
+        let mut flake_data: deploy::data::Data = serde_json::from_value(deploy_json)?;
+        deploy::terraform::resolve(&deploy::transport::SystemTransport, &mut flake_data).await?;
+        data.push(flake_data);
+    }
 
-    let mut c = if supports_flakes {
-        Command::new("nix")
-    } else {
-        Command::new("nix-instantiate")
-    };
+    Ok(data)
+}
 
-    if supports_flakes {
-        c.arg("eval")
-            .arg("--json")
-            .arg(format!("{}#deploy", flake.repo))
-            // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
-            .arg("--apply");
-        match (&flake.node, &flake.profile) {
-            (Some(node), Some(profile)) => {
-                // Ignore all nodes and all profiles but the one we're evaluating
-                c.arg(format!(
-                    r#"
-                      deploy:
-                      (deploy // {{
-                        nodes = {{
-                          "{0}" = deploy.nodes."{0}" // {{
-                            profiles = {{
-                              inherit (deploy.nodes."{0}".profiles) "{1}";
-                            }};
-                          }};
-                        }};
-                      }})
-                     "#,
-                    node, profile
-                ))
-            }
-            (Some(node), None) => {
-                // Ignore all nodes but the one we're evaluating
-                c.arg(format!(
-                    r#"
-                      deploy:
-                      (deploy // {{
-                        nodes = {{
-                          inherit (deploy.nodes) "{}";
-                        }};
-                      }})
-                    "#,
-                    node
-                ))
-            }
-            (None, None) => {
-                // We need to evaluate all profiles of all nodes anyway, so just do it strictly
-                c.arg("deploy: deploy")
-            }
-            (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
-        }
-    } else {
-        c
-            .arg("--strict")
-            .arg("--read-write-mode")
-            .arg("--json")
-            .arg("--eval")
-            .arg("-E")
-            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo))
+/// The function `nix eval --apply`s to the `deploy` output of a flake, leaving out every node and profile but the
+/// ones it names so they aren't evaluated
+fn deploy_selection(flake: &deploy::DeployFlake<'_>) -> Result<String, GetDeploymentDataError> {
+    let node = match (&flake.node, &flake.profile) {
+        (Some(node), _) => deploy::nix_string(node),
+        // We need to evaluate all profiles of all nodes anyway, so just do it strictly
+        (None, None) => return Ok("deploy: deploy".to_string()),
+        (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
     };
 
-    for extra_arg in extra_build_args {
-        c.arg(extra_arg);
+    let profiles = flake.profile.as_ref().map(|x| {
+        format!(
+            "profiles = {{ inherit (x.profiles) {}; }};",
+            deploy::nix_string(x)
+        )
+    });
+
+    let nodes = format!(
+        "builtins.intersectAttrs {{ {} = null; }} (deploy.nodes or {{ }})",
+        node
+    );
+
+    // The node is either one of `nodes` or a host of one of `nodeGroups`, the groups without it are left empty
+    Ok(format!(
+        "deploy: deploy // {{ nodes = {}; nodeGroups = builtins.mapAttrs (_: x: if x.hosts ? {node} then x // {{ hosts = {{ inherit (x.hosts) {node}; }}; {profiles}}} else {{ profiles = {{ }}; hosts = {{ }}; }}) (deploy.nodeGroups or {{ }}); }}",
+        match profiles {
+            Some(ref profiles) => format!("builtins.mapAttrs (_: x: x // {{ {} }}) ({})", profiles, nodes),
+            None => nodes,
+        },
+        node = node,
+        profiles = profiles.as_ref().map(|x| format!("{} ", x)).unwrap_or_default(),
+    ))
+}
+
+#[test]
+fn test_deploy_selection() {
+    assert_eq!(
+        deploy_selection(&deploy::parse_flake(r#".#"web.example.com".system"#).unwrap()).unwrap(),
+        r#"deploy: deploy // { nodes = builtins.mapAttrs (_: x: x // { profiles = { inherit (x.profiles) "system"; }; }) (builtins.intersectAttrs { "web.example.com" = null; } (deploy.nodes or { })); nodeGroups = builtins.mapAttrs (_: x: if x.hosts ? "web.example.com" then x // { hosts = { inherit (x.hosts) "web.example.com"; }; profiles = { inherit (x.profiles) "system"; }; } else { profiles = { }; hosts = { }; }) (deploy.nodeGroups or { }); }"#
+    );
+    assert_eq!(
+        deploy_selection(&deploy::parse_flake(".#Web-1").unwrap()).unwrap(),
+        r#"deploy: deploy // { nodes = builtins.intersectAttrs { "Web-1" = null; } (deploy.nodes or { }); nodeGroups = builtins.mapAttrs (_: x: if x.hosts ? "Web-1" then x // { hosts = { inherit (x.hosts) "Web-1"; }; } else { profiles = { }; hosts = { }; }) (deploy.nodeGroups or { }); }"#
+    );
+    assert_eq!(
+        deploy_selection(&deploy::DeployFlake {
+            repo: ".",
+            node: Some(r#"odd"${name}"#.to_string()),
+            profile: None,
+        })
+        .unwrap(),
+        r#"deploy: deploy // { nodes = builtins.intersectAttrs { "odd\"\${name}" = null; } (deploy.nodes or { }); nodeGroups = builtins.mapAttrs (_: x: if x.hosts ? "odd\"\${name}" then x // { hosts = { inherit (x.hosts) "odd\"\${name}"; }; } else { profiles = { }; hosts = { }; }) (deploy.nodeGroups or { }); }"#
+    );
+    assert_eq!(
+        deploy_selection(&deploy::parse_flake(".").unwrap()).unwrap(),
+        "deploy: deploy"
+    );
+}
+
+/// Outputs a flake's deployment is looked for in, in this order, unless `--deploy-attr` names another one
+const DEPLOY_ATTRS: &[&str] = &["deploy", "deployments"];
+
+/// The first of `DEPLOY_ATTRS` a flake has, without evaluating more of it than needed to tell
+async fn find_deploy_attr(repo: &str, extra_build_args: &[String]) -> &'static str {
+    for attr in DEPLOY_ATTRS {
+        let status = Command::new("nix")
+            .arg("eval")
+            .arg(format!("{}#{}", repo, attr))
+            .arg("--apply")
+            .arg("_: null")
+            .args(extra_build_args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+
+        if matches!(status, Ok(ref x) if x.success()) {
+            return attr;
+        }
     }
 
-    let build_child = c
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(GetDeploymentDataError::NixEval)?;
+    // Evaluating it for real shows why it's missing
+    DEPLOY_ATTRS[0]
+}
+
+/// What the evaluation of a flake's deployment can be reused by later runs with, `None` when it's impure or the flake
+/// can't be locked to the hash of its contents
+async fn evaluation_key(
+    repo: &str,
+    attr: &str,
+    selection: &str,
+    extra_build_args: &[String],
+) -> Option<deploy::state::EvaluationKey> {
+    if extra_build_args
+        .iter()
+        .any(|x| x == "--impure" || x == "--no-eval-cache")
+    {
+        return None;
+    }
 
-    let build_output = build_child
-        .wait_with_output()
+    let output = Command::new("nix")
+        .arg("flake")
+        .arg("metadata")
+        .arg("--json")
+        .arg(repo)
+        .stderr(Stdio::null())
+        .output()
         .await
-        .map_err(GetDeploymentDataError::NixEvalOut)?;
+        .ok()?;
 
-    match build_output.status.code() {
-        Some(0) => (),
-        a => return Err(GetDeploymentDataError::NixEvalExit(a)),
-    };
+    if !output.status.success() {
+        return None;
+    }
 
-    let data_json = String::from_utf8(build_output.stdout)?;
+    let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
 
-    Ok(serde_json::from_str(&data_json)?)
-}).try_collect().await
+    Some(deploy::state::EvaluationKey {
+        nar_hash: metadata.pointer("/locked/narHash")?.as_str()?.to_string(),
+        attr: attr.to_string(),
+        selection: selection.to_string(),
+        extra_build_args: extra_build_args.to_vec(),
+    })
 }
 
-#[derive(Serialize)]
-struct PromptPart<'a> {
-    user: &'a str,
-    ssh_user: &'a str,
-    path: &'a str,
-    hostname: &'a str,
-    ssh_opts: &'a [String],
+/// The expression `nix-instantiate` evaluates for the deployment of a repository without flakes
+fn legacy_deploy_expr(repo: &str, deploy_attr: Option<&str>) -> String {
+    let select = match deploy_attr {
+        Some(attr) => format!("x.{}", deploy::nix_attr(attr)),
+        None => "if x ? deploy then x.deploy else x.deployments".to_string(),
+    };
+
+    format!(
+        "let r = import {}/.; x = if builtins.isFunction r then r {{}} else r; in {}",
+        repo, select
+    )
 }
 
-fn print_deployment(
-    parts: &[(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )],
-) -> Result<(), toml::ser::Error> {
-    let mut part_map: HashMap<String, HashMap<String, PromptPart>> = HashMap::new();
+#[test]
+fn test_legacy_deploy_expr() {
+    assert_eq!(
+        legacy_deploy_expr("./infra", None),
+        "let r = import ./infra/.; x = if builtins.isFunction r then r {} else r; in if x ? deploy then x.deploy else x.deployments"
+    );
+    assert_eq!(
+        legacy_deploy_expr("./infra", Some("staging-deploy")),
+        "let r = import ./infra/.; x = if builtins.isFunction r then r {} else r; in x.staging-deploy"
+    );
+}
 
-    for (_, data, defs) in parts {
-        part_map
-            .entry(data.node_name.to_string())
-            .or_insert_with(HashMap::new)
-            .insert(
-                data.profile_name.to_string(),
-                PromptPart {
-                    user: &defs.profile_user,
-                    ssh_user: &defs.ssh_user,
-                    path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
-                    ssh_opts: &data.merged_settings.ssh_opts,
-                },
-            );
-    }
+/// Evaluates the `deploy` output of flakes to JSON, narrowed down to the node and profile they name
+async fn evaluate_deployments(
+    supports_flakes: bool,
+    flakes: &[deploy::DeployFlake<'_>],
+    deploy_attr: Option<&str>,
+    extra_build_args: &[String],
+) -> Result<Vec<serde_json::Value>, GetDeploymentDataError> {
+    futures_util::stream::iter(flakes.iter().enumerate())
+        .then(|(i, flake)| async move {
+            info!("Evaluating flake in {}", flake.repo);
 
-    let toml = toml::to_string(&part_map)?;
+            let mut c = if supports_flakes {
+                deploy::transport::Invocation::new("nix")
+            } else {
+                deploy::transport::Invocation::new("nix-instantiate")
+            };
 
-    info!("The following profiles are going to be deployed:\n{}", toml);
+            let mut reusable = None;
+
+            if supports_flakes {
+                let attr = match deploy_attr {
+                    Some(x) => deploy::nix_attr(x),
+                    None => find_deploy_attr(flake.repo, extra_build_args)
+                        .await
+                        .to_string(),
+                };
+                let selection = deploy_selection(flake)?;
+
+                reusable = match deploy::state::state_dir() {
+                    Ok(dir) => evaluation_key(flake.repo, &attr, &selection, extra_build_args)
+                        .await
+                        .map(|key| (dir, key)),
+                    Err(_) => None,
+                };
+
+                if let Some((ref dir, ref key)) = reusable {
+                    match deploy::state::load_evaluation(dir, key) {
+                        Ok(Some(deployment)) => {
+                            info!("Reusing the evaluation of {} from an earlier run", flake.repo);
+                            return Ok(deployment);
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("Failed to read the earlier evaluation of {}: {}", flake.repo, e),
+                    }
+                }
+
+                c.arg("eval")
+                    .arg("--json")
+                    .arg(format!("{}#{}", flake.repo, attr))
+                    // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
+                    .arg("--apply")
+                    .arg(selection)
+                    .args(&deploy::nixlog::LOG_FORMAT_ARGS)
+                    .args(&deploy::nixlog::TRACE_IFD_ARGS);
+            } else {
+                c.arg("--strict")
+                    .arg("--read-write-mode")
+                    .arg("--json")
+                    .arg("--eval")
+                    .arg("-E")
+                    .arg(legacy_deploy_expr(flake.repo, deploy_attr));
+            }
+
+            for extra_arg in extra_build_args {
+                c.arg(extra_arg);
+            }
+
+            let stats_path = std::env::temp_dir().join(format!(
+                "deploy-rs-eval-stats-{}-{}",
+                std::process::id(),
+                i
+            ));
+            for (key, value) in deploy::nixlog::stats_env(&stats_path.to_string_lossy()).iter() {
+                c.env(key, value);
+            }
+            c.stdout(deploy::transport::OutputMode::Capture);
+
+            let build_output = deploy::transport::SystemTransport
+                .run(&c)
+                .await
+                .map_err(GetDeploymentDataError::NixEval)?;
+
+            let stats = std::fs::read_to_string(&stats_path).ok();
+            let _ = std::fs::remove_file(&stats_path);
+
+            match build_output.code {
+                Some(0) => (),
+                a => {
+                    return Err(
+                        match deploy::nixlog::forbidden_import(&build_output.stderr_tail) {
+                            Some(derivation) => GetDeploymentDataError::ForbiddenIfd(derivation),
+                            None => GetDeploymentDataError::NixEvalExit(a),
+                        },
+                    )
+                }
+            };
+
+            let imports = deploy::nixlog::imports_from_derivation(&build_output.stderr_tail);
+            if !imports.is_empty() {
+                raise(
+                    Warning::ImportFromDerivation,
+                    format_args!(
+                        "Evaluating the flake in {} built {} to import from, which makes evaluating it slow. Use --forbid-ifd to fail instead",
+                        flake.repo,
+                        imports.join(", ")
+                    ),
+                );
+            }
+
+            if let Some(heap_size) = stats.as_deref().and_then(deploy::nixlog::heap_size) {
+                debug!("Evaluating the flake in {} took a heap of {} bytes", flake.repo, heap_size);
+
+                if heap_size > deploy::warnings::LARGE_EVALUATION_BYTES {
+                    raise(
+                        Warning::LargeEvaluation,
+                        format_args!(
+                            "Evaluating the flake in {} took {} MiB of memory, which makes evaluating it slow. Deploying fewer nodes at once evaluates less of it",
+                            flake.repo,
+                            heap_size >> 20
+                        ),
+                    );
+                }
+            }
+
+            let data_json = String::from_utf8(build_output.stdout)?;
+            let deployment = serde_json::from_str(&data_json)?;
+
+            if let Some((ref dir, ref key)) = reusable {
+                if let Err(e) = deploy::state::save_evaluation(dir, key, &deployment) {
+                    warn!("Failed to keep the evaluation of {} for later runs: {}", flake.repo, e);
+                }
+            }
+
+            Ok(deployment)
+        })
+        .try_collect()
+        .await
+}
+
+#[derive(Serialize)]
+struct PromptPart<'a> {
+    user: &'a str,
+    ssh_user: &'a str,
+    path: &'a str,
+    hostname: &'a str,
+    ssh_opts: &'a [String],
+}
+
+fn print_deployment(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), toml::ser::Error> {
+    let mut part_map: HashMap<String, HashMap<String, PromptPart>> = HashMap::new();
+
+    for (_, data, defs) in parts {
+        part_map
+            .entry(data.node_name.to_string())
+            .or_insert_with(HashMap::new)
+            .insert(
+                data.profile_name.to_string(),
+                PromptPart {
+                    user: &defs.profile_user,
+                    ssh_user: &defs.ssh_user,
+                    path: &data.profile.profile_settings.path,
+                    hostname: &data.node.node_settings.hostname,
+                    ssh_opts: &data.merged_settings.ssh_opts,
+                },
+            );
+    }
+
+    let toml = toml::to_string(&part_map)?;
+
+    info!("The following profiles are going to be deployed:\n{}", toml);
 
     Ok(())
 }
 #[derive(Error, Debug)]
 pub enum PromptDeploymentError {
-    #[error("Failed to make printable TOML of deployment: {0}")]
-    TomlFormat(#[from] toml::ser::Error),
     #[error("Failed to flush stdout prior to query: {0}")]
     StdoutFlush(std::io::Error),
     #[error("Failed to read line from stdin: {0}")]
@@ -316,16 +1200,8 @@ pub enum PromptDeploymentError {
     Cancelled,
 }
 
-fn prompt_deployment(
-    parts: &[(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )],
-) -> Result<(), PromptDeploymentError> {
-    print_deployment(parts)?;
-
-    info!("Are you sure you want to deploy these profiles?");
+fn prompt_deployment() -> Result<(), PromptDeploymentError> {
+    info!("Are you sure you want to activate these profiles?");
     print!("> ");
 
     stdout()
@@ -339,7 +1215,7 @@ fn prompt_deployment(
 
     if !yn::yes(&s) {
         if yn::is_somewhat_yes(&s) {
-            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". Do you want to deploy these profiles?");
+            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". Do you want to activate these profiles?");
             print!("> ");
 
             stdout()
@@ -368,18 +1244,82 @@ fn prompt_deployment(
     Ok(())
 }
 
+#[derive(Error, Debug)]
+pub enum ProtectedNodeError {
+    #[error("Node `{0}` is protected, use --production to deploy to it")]
+    Refused(String),
+    #[error("Failed to flush stdout prior to query: {0}")]
+    StdoutFlush(std::io::Error),
+    #[error("Failed to read line from stdin: {0}")]
+    StdinRead(std::io::Error),
+}
+
+/// Makes sure the protected ones among `nodes` are meant to be touched, by `--production` or by typing each of
+/// their names when there's a terminal to ask on
+fn confirm_protected<'a>(
+    nodes: impl IntoIterator<Item = (&'a str, &'a deploy::data::Node)>,
+    production: bool,
+) -> Result<(), ProtectedNodeError> {
+    let mut protected: Vec<&str> = nodes
+        .into_iter()
+        .filter(|(_, node)| node.node_settings.protected)
+        .map(|(node_name, _)| node_name)
+        .collect();
+    protected.sort_unstable();
+    protected.dedup();
+
+    if production || protected.is_empty() {
+        return Ok(());
+    }
+
+    for node_name in protected {
+        if !atty::is(atty::Stream::Stdin) {
+            return Err(ProtectedNodeError::Refused(node_name.to_string()));
+        }
+
+        info!(
+            "Node `{}` is protected, type its name to go on with it",
+            node_name
+        );
+        print!("> ");
+
+        stdout().flush().map_err(ProtectedNodeError::StdoutFlush)?;
+
+        let mut s = String::new();
+        stdin()
+            .read_line(&mut s)
+            .map_err(ProtectedNodeError::StdinRead)?;
+
+        if s.trim() != node_name {
+            return Err(ProtectedNodeError::Refused(node_name.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum RunDeployError {
     #[error("Failed to deploy profile: {0}")]
-    DeployProfile(#[from] deploy::deploy::DeployProfileError),
+    DeployProfile(Reported<deploy::deploy::DeployProfileError>),
+    #[error("Failed to deploy profile, it was rolled back: {0}")]
+    DeployProfileRolledBack(Reported<deploy::deploy::DeployProfileError>),
+    #[error("Failed to deploy profile, {1} profiles activated before it were left in place: {0}")]
+    DeployProfilePartial(Reported<deploy::deploy::DeployProfileError>, usize),
+    #[error("Failed to deploy {0} profiles, {1} other profiles were activated")]
+    DeployProfiles(usize, usize),
     #[error("Failed to push profile: {0}")]
-    PushProfile(#[from] deploy::push::PushProfileError),
+    PushProfile(Reported<deploy::push::PushProfileError>),
     #[error("No profile named `{0}` was found")]
     ProfileNotFound(String),
     #[error("No node named `{0}` was found")]
     NodeNotFound(String),
     #[error("Profile was provided without a node name")]
     ProfileWithoutNode,
+    #[error("{0} of {1} nodes can't be deployed to, nothing was built")]
+    Preflight(usize, usize),
+    #[error("Failed to check deployment: {0}")]
+    CheckDeployment(#[from] CheckDeploymentError),
     #[error("Error processing deployment definitions: {0}")]
     DeployDataDefs(#[from] deploy::DeployDataDefsError),
     #[error("Failed to make printable TOML of deployment: {0}")]
@@ -387,7 +1327,74 @@ pub enum RunDeployError {
     #[error("{0}")]
     PromptDeployment(#[from] PromptDeploymentError),
     #[error("Failed to revoke profile: {0}")]
-    RevokeProfile(#[from] deploy::deploy::RevokeProfileError),
+    RevokeProfile(Reported<deploy::deploy::RevokeProfileError>),
+    #[error("Failed to compute /etc changes: {0}")]
+    DiffEtc(#[from] deploy::deploy::DiffEtcError),
+    #[error("Failed to copy profile to the pull cache: {0}")]
+    CopyToCache(#[from] deploy::pull::CopyToCacheError),
+    #[error("Failed to write pull descriptor: {0}")]
+    WriteDescriptor(#[from] deploy::pull::WriteDescriptorError),
+    #[error("Failed to sign pull descriptor: {0}")]
+    SignDescriptor(#[from] deploy::pull::DescriptorSignatureError),
+    #[error("Failed to get the sudo password: {0}")]
+    Askpass(#[from] deploy::transport::AskpassError),
+    #[error("{0}")]
+    MaintenanceWindow(#[from] deploy::window::ParseWindowError),
+    #[error("Node `{0}` is outside its maintenance window `{1}`, use --defer to activate it when the window opens or --force to activate it now")]
+    OutsideMaintenanceWindow(String, String),
+    #[error("Node `{1}` of cluster group `{0}` still has an activation awaiting confirmation, not activating node `{2}`")]
+    ClusterGroupBusy(String, String, String),
+    #[error("Couldn't check node `{1}` of cluster group `{0}` for an activation awaiting confirmation: {2}")]
+    ClusterGroupCheck(String, String, Box<deploy::deploy::ConfirmationWindowError>),
+    #[error(
+        "Paused run `{0}` with {1} profiles left to activate, `deploy resume {0}` continues it"
+    )]
+    Paused(String, usize),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+    #[error("Not activating anything with --deny-warnings, after {0}")]
+    DeniedWarnings(String),
+    #[error("Failed to install Nix on node `{0}`: {1}")]
+    BootstrapNix(String, deploy::bootstrap::BootstrapError),
+    #[error("{0}")]
+    HostKey(#[from] deploy::hostkey::HostKeyError),
+    #[error("Failed to find the state directory to pin host keys in: {0}")]
+    HostKeyState(deploy::state::StateError),
+}
+
+impl RunDeployError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunDeployError::DeployProfile(_) => ExitCode::Activation,
+            RunDeployError::DeployProfileRolledBack(_) => ExitCode::RolledBack,
+            RunDeployError::DeployProfilePartial(_, _) => ExitCode::PartialFailure,
+            RunDeployError::DeployProfiles(_, 0) => ExitCode::Activation,
+            RunDeployError::DeployProfiles(_, _) => ExitCode::PartialFailure,
+            RunDeployError::PushProfile(e) => e.error.exit_code(),
+            RunDeployError::RevokeProfile(_) => ExitCode::RollbackFailed,
+            RunDeployError::CopyToCache(_) => ExitCode::Copy,
+            RunDeployError::CheckDeployment(_) => ExitCode::Evaluation,
+            RunDeployError::Paused(_, _) => ExitCode::Paused,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// What to do with nodes that are deployed outside their maintenance window
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutsideWindow {
+    Refuse,
+    Defer,
+    Force,
+}
+
+/// Where target-pull mode puts its descriptors, and where nodes should get the closures from
+struct PullSettings<'a> {
+    descriptors: &'a str,
+    copy_to: Option<&'a str>,
+    substituters: &'a [String],
+    sign_key: &'a str,
+    signer: String,
 }
 
 type ToDeploy<'a> = Vec<(
@@ -397,201 +1404,3652 @@ type ToDeploy<'a> = Vec<(
     (&'a str, &'a deploy::data::Profile),
 )>;
 
-async fn run_deploy(
-    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
-    data: Vec<deploy::data::Data>,
-    supports_flakes: bool,
-    check_sigs: bool,
-    interactive: bool,
-    cmd_overrides: &deploy::CmdOverrides,
-    keep_result: bool,
-    result_path: Option<&str>,
-    extra_build_args: &[String],
-    debug_logs: bool,
-    dry_activate: bool,
-    log_dir: &Option<String>,
-    rollback_succeeded: bool,
-) -> Result<(), RunDeployError> {
-    let to_deploy: ToDeploy = deploy_flakes
-        .iter()
-        .zip(&data)
-        .map(|(deploy_flake, data)| {
-            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
-                (Some(node_name), Some(profile_name)) => {
-                    let node = match data.nodes.get(node_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
-                    };
-                    let profile = match node.node_settings.profiles.get(profile_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
-                    };
+/// Connects to each node once, one after the other, so that the connections made afterwards (possibly
+/// several at a time) reuse an already authenticated one instead of each asking for a touch or passphrase
+async fn authenticate_serially(nodes: &[(&str, String, &[String])]) {
+    let mut authenticated: Vec<&str> = Vec::new();
 
-                    vec![(
-                        deploy_flake,
-                        data,
-                        (node_name.as_str(), node),
-                        (profile_name.as_str(), profile),
-                    )]
-                }
-                (Some(node_name), None) => {
-                    let node = match data.nodes.get(node_name) {
-                        Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
-                    };
+    for (node_name, ssh_addr, ssh_opts) in nodes {
+        if authenticated.contains(&ssh_addr.as_str()) {
+            continue;
+        }
 
-                    let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
-
-                    for profile_name in [
-                        node.node_settings.profiles_order.iter().collect(),
-                        node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                    ]
-                    .concat()
-                    {
-                        let profile = match node.node_settings.profiles.get(profile_name) {
-                            Some(x) => x,
-                            None => {
-                                return Err(RunDeployError::ProfileNotFound(profile_name.clone()))
-                            }
-                        };
-
-                        if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                            profiles_list.push((profile_name, profile));
-                        }
-                    }
+        info!("Authenticating to node `{}`", node_name);
 
-                    profiles_list
-                        .into_iter()
-                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                        .collect()
-                }
-                (None, None) => {
-                    let mut l = Vec::new();
+        let mut ssh_command = deploy::transport::Invocation::new("ssh");
+        ssh_command.arg(ssh_addr).args(ssh_opts).arg("true");
 
-                    for (node_name, node) in &data.nodes {
-                        let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
+        // Whatever goes wrong here will go wrong again, and be reported properly, when the node is actually used
+        match deploy::transport::SystemTransport.run(&ssh_command).await {
+            Ok(output) if output.code == Some(0) => (),
+            Ok(output) => warn!(
+                "Authenticating to node `{}` failed with {}",
+                node_name,
+                deploy::report::CommandFailure::new(&ssh_command, &output)
+            ),
+            Err(e) => warn!("Authenticating to node `{}` failed: {}", node_name, e),
+        }
 
-                        for profile_name in [
-                            node.node_settings.profiles_order.iter().collect(),
-                            node.node_settings.profiles.keys().collect::<Vec<&String>>(),
-                        ]
-                        .concat()
-                        {
-                            let profile = match node.node_settings.profiles.get(profile_name) {
-                                Some(x) => x,
-                                None => {
-                                    return Err(RunDeployError::ProfileNotFound(
-                                        profile_name.clone(),
-                                    ))
-                                }
-                            };
-
-                            if !profiles_list.iter().any(|(n, _)| n == profile_name) {
-                                profiles_list.push((profile_name, profile));
-                            }
-                        }
+        authenticated.push(ssh_addr);
+    }
+}
 
-                        let ll: ToDeploy = profiles_list
-                            .into_iter()
-                            .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
-                            .collect();
+/// Installs the Nix built from `bootstrapNix` on the nodes which have it set but no Nix yet, building each installable
+/// once
+async fn bootstrap_nix(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), RunDeployError> {
+    let transport = &deploy::transport::SystemTransport;
+    let mut checked: Vec<&str> = Vec::new();
+    let mut built: HashMap<&str, std::path::PathBuf> = HashMap::new();
 
-                        l.extend(ll);
-                    }
+    for (_, deploy_data, deploy_defs) in parts {
+        let installable = match deploy_data.merged_settings.bootstrap_nix {
+            Some(ref x) if !checked.contains(&deploy_data.node_name) => x,
+            _ => continue,
+        };
+        checked.push(deploy_data.node_name);
 
-                    l
-                }
-                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
-            };
-            Ok(to_deploys)
-        })
-        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
-        .into_iter()
-        .flatten()
-        .collect();
+        let failed = |e| RunDeployError::BootstrapNix(deploy_data.node_name.to_string(), e);
+        let target = deploy_data.node_target(deploy_defs)?;
 
-    let mut parts: Vec<(
-        &deploy::DeployFlake<'_>,
-        deploy::DeployData,
-        deploy::DeployDefs,
-    )> = Vec::new();
+        if deploy::bootstrap::has_nix(
+            transport,
+            &target,
+            deploy::deploy::NixEnv::new(&deploy_data.merged_settings),
+        )
+        .await
+        .map_err(failed)?
+        {
+            continue;
+        }
 
-    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
-        let deploy_data = deploy::make_deploy_data(
-            &data.generic_settings,
-            node,
-            node_name,
-            profile,
-            profile_name,
-            cmd_overrides,
-            debug_logs,
-            log_dir.as_deref(),
+        info!(
+            "Node `{}` has no Nix, installing the one from {}",
+            deploy_data.node_name, installable
         );
 
-        let deploy_defs = deploy_data.defs()?;
-
-        parts.push((deploy_flake, deploy_data, deploy_defs));
-    }
+        let binary = match built.get(installable.as_str()) {
+            Some(x) => x.clone(),
+            None => {
+                let binary = deploy::bootstrap::build_nix(transport, installable)
+                    .await
+                    .map_err(failed)?;
+                built.insert(installable, binary.clone());
+                binary
+            }
+        };
 
-    if interactive {
-        prompt_deployment(&parts[..])?;
-    } else {
-        print_deployment(&parts[..])?;
+        deploy::bootstrap::install_nix(
+            transport,
+            &target,
+            &deploy_defs.ssh_user,
+            deploy_data.sudo_for(deploy_defs, "root").as_deref(),
+            &binary,
+        )
+        .await
+        .map_err(failed)?;
     }
 
-    for (deploy_flake, deploy_data, deploy_defs) in &parts {
-        deploy::push::push_profile(deploy::push::PushProfileData {
-            supports_flakes,
-            check_sigs,
-            repo: deploy_flake.repo,
-            deploy_data,
-            deploy_defs,
-            keep_result,
-            result_path,
-            extra_build_args,
-        })
-        .await?;
-    }
+    Ok(())
+}
 
-    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+/// Checks that every node can be deployed to, all at the same time, so that unreachable nodes are found out about
+/// before anything is built
+async fn run_preflight(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), RunDeployError> {
+    // Each node is checked once, with the sudo commands of all of its profiles
+    let mut nodes: Vec<(&deploy::DeployData, &deploy::DeployDefs, Vec<String>)> = Vec::new();
 
-    // Run all deployments
-    // In case of an error rollback any previoulsy made deployment.
-    // Rollbacks adhere to the global seeting to auto_rollback and secondary
-    // the profile's configuration
-    for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate).await
+    for (_, deploy_data, deploy_defs) in parts {
+        let i = match nodes
+            .iter()
+            .position(|(x, _, _)| x.node_name == deploy_data.node_name)
         {
-            error!("{}", e);
-            if dry_activate {
-                info!("dry run, not rolling back");
+            Some(i) => i,
+            None => {
+                nodes.push((deploy_data, deploy_defs, Vec::new()));
+                nodes.len() - 1
             }
-            info!("Revoking previous deploys");
-            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
-                // revoking all previous deploys
-                // (adheres to profile configuration if not set explicitely by
-                //  the command line)
-                for (deploy_data, deploy_defs) in &succeeded {
-                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
-                        deploy::deploy::revoke(*deploy_data, *deploy_defs).await?;
-                    }
-                }
+        };
+
+        if let Some(ref sudo) = deploy_defs.sudo {
+            if !nodes[i].2.contains(sudo) {
+                nodes[i].2.push(sudo.clone());
             }
-            break;
         }
-        succeeded.push((deploy_data, deploy_defs))
     }
 
-    Ok(())
-}
+    info!("Checking that {} nodes can be deployed to", nodes.len());
 
-#[derive(Error, Debug)]
-pub enum RunError {
-    #[error("Failed to deploy profile: {0}")]
-    DeployProfile(#[from] deploy::deploy::DeployProfileError),
-    #[error("Failed to push profile: {0}")]
-    PushProfile(#[from] deploy::push::PushProfileError),
-    #[error("Failed to test for flake support: {0}")]
+    let results = futures_util::future::join_all(nodes.iter().map(
+        |(deploy_data, deploy_defs, sudo_commands)| {
+            deploy::preflight::preflight_node(deploy_data, deploy_defs, sudo_commands)
+        },
+    ))
+    .await;
+
+    let mut failed = 0;
+
+    for ((deploy_data, _, _), result) in nodes.iter().zip(results) {
+        if let Err(e) = result {
+            match deploy::report::Diagnostic::hint(&e) {
+                Some(hint) => error!(
+                    "Node `{}` can't be deployed to: {}\n  help:    {}",
+                    deploy_data.node_name, e, hint
+                ),
+                None => error!(
+                    "Node `{}` can't be deployed to: {}",
+                    deploy_data.node_name, e
+                ),
+            }
+
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        _ => Err(RunDeployError::Preflight(failed, nodes.len())),
+    }
+}
+
+/// Pins the host key of every node with `pinHostKey` the first time it's connected to, recording its fingerprint in
+/// the node's state, and fails if a node's key isn't the one pinned before
+async fn pin_host_keys(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), RunDeployError> {
+    let mut nodes: Vec<(&deploy::DeployData, deploy::transport::NodeTarget)> = Vec::new();
+
+    for (_, deploy_data, deploy_defs) in parts {
+        if deploy_data.merged_settings.pin_host_key != Some(true)
+            || nodes
+                .iter()
+                .any(|(x, _)| x.node_name == deploy_data.node_name)
+        {
+            continue;
+        }
+
+        // Agents are checked against their CA instead
+        if let target @ deploy::transport::NodeTarget::Ssh { .. } =
+            deploy_data.node_target(deploy_defs)?
+        {
+            nodes.push((deploy_data, target));
+        }
+    }
+
+    if nodes.is_empty() {
+        return Ok(());
+    }
+
+    let dir = deploy::state::state_dir().map_err(RunDeployError::HostKeyState)?;
+
+    for (deploy_data, target) in &nodes {
+        let mut state =
+            deploy::state::load_node_state(&dir, deploy_data.node_name).unwrap_or_default();
+        let known_hosts = deploy::hostkey::known_hosts_path(&dir, deploy_data.node_name);
+
+        let fingerprint = match deploy::hostkey::pin_host_key(
+            deploy_data.transport,
+            target,
+            deploy_data.node_name,
+            &known_hosts,
+            state.host_key.as_deref(),
+        )
+        .await
+        {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        // A lost known hosts file learns whatever key answers, which mustn't replace the one pinned before
+        match state.host_key {
+            Some(ref pinned) if *pinned == fingerprint => continue,
+            Some(pinned) => {
+                return Err(deploy::hostkey::HostKeyError::Changed(
+                    deploy_data.node_name.to_string(),
+                    known_hosts.display().to_string(),
+                    Some(pinned),
+                )
+                .into())
+            }
+            None => info!(
+                "Pinned the host key {} of node `{}`",
+                fingerprint, deploy_data.node_name
+            ),
+        }
+
+        state.host_key = Some(fingerprint);
+
+        if let Err(e) = deploy::state::save_node_state(&dir, deploy_data.node_name, &state) {
+            warn!(
+                "Could not record the host key of node `{}`: {}",
+                deploy_data.node_name, e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Gathers the facts of every node that has none recorded recently enough, all at the same time
+///
+/// Nodes whose facts can't be gathered are deployed to without them, going by their settings alone.
+async fn refresh_facts(
+    parts: &mut [(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), RunDeployError> {
+    let mut nodes: Vec<(&deploy::DeployData, deploy::transport::NodeTarget)> = Vec::new();
+
+    for (_, deploy_data, deploy_defs) in parts.iter() {
+        if deploy_data.facts.is_some()
+            || nodes
+                .iter()
+                .any(|(x, _)| x.node_name == deploy_data.node_name)
+        {
+            continue;
+        }
+
+        nodes.push((deploy_data, deploy_data.node_target(deploy_defs)?));
+    }
+
+    if nodes.is_empty() {
+        return Ok(());
+    }
+
+    info!("Gathering facts of {} nodes", nodes.len());
+
+    let results = futures_util::future::join_all(nodes.iter().map(|(deploy_data, target)| {
+        deploy::facts::gather_facts(
+            deploy_data.transport,
+            target,
+            deploy::deploy::NixEnv::new(&deploy_data.merged_settings),
+            deploy_data.node_name,
+        )
+    }))
+    .await;
+
+    let mut gathered: HashMap<String, deploy::facts::NodeFacts> = HashMap::new();
+
+    for ((deploy_data, _), result) in nodes.iter().zip(results) {
+        match result {
+            Ok(facts) => {
+                if let Some(free) = facts.store_free_kib {
+                    if free < LOW_STORE_SPACE_KIB {
+                        raise(
+                            Warning::LowStoreSpace,
+                            format_args!(
+                                "Node `{}` has only {} MiB free for its Nix store",
+                                deploy_data.node_name,
+                                free / 1024
+                            ),
+                        );
+                    }
+                }
+
+                gathered.insert(deploy_data.node_name.to_string(), facts);
+            }
+            Err(e) => raise(
+                Warning::NoFacts,
+                format_args!(
+                    "Could not gather the facts of node `{}`, going by its settings alone: {}",
+                    deploy_data.node_name, e
+                ),
+            ),
+        }
+    }
+
+    for (_, deploy_data, deploy_defs) in parts.iter_mut() {
+        if let Some(facts) = gathered.get(deploy_data.node_name) {
+            deploy_data.facts = Some(facts.clone());
+            *deploy_defs = deploy_data.defs()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Warns about each node whose facts, recorded or just gathered, say it runs an old version of Nix
+fn check_nix_versions(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) {
+    let mut checked: Vec<&str> = Vec::new();
+
+    for (_, deploy_data, _) in parts {
+        if checked.contains(&deploy_data.node_name) {
+            continue;
+        }
+        checked.push(deploy_data.node_name);
+
+        let version = match deploy_data
+            .facts
+            .as_ref()
+            .and_then(|x| x.nix_version.as_ref())
+        {
+            Some(x) => x,
+            None => continue,
+        };
+
+        if deploy::warnings::is_old_nix(version) {
+            raise(
+                Warning::OldNix,
+                format_args!(
+                    "Node `{}` runs Nix {}, which is older than {}.{}",
+                    deploy_data.node_name,
+                    version,
+                    deploy::warnings::MIN_NIX_VERSION.0,
+                    deploy::warnings::MIN_NIX_VERSION.1
+                ),
+            );
+        }
+    }
+}
+
+/// Free space in the Nix store of a node below which deploying to it is warned about, 1 GiB
+const LOW_STORE_SPACE_KIB: u64 = 1024 * 1024;
+
+/// Asks for the sudo password of every node that needs one once, and has sudo read it from standard input
+async fn ask_sudo_passwords(
+    askpass: &str,
+    parts: &mut [(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+) -> Result<(), RunDeployError> {
+    let mut passwords: HashMap<String, String> = HashMap::new();
+
+    for (_, deploy_data, deploy_defs) in parts.iter_mut() {
+        // Agents run commands as root, so they never need a password
+        if deploy_defs.sudo.is_none() || deploy_data.agent_connection()?.is_some() {
+            continue;
+        }
+
+        let password = match passwords.get(deploy_data.node_name) {
+            Some(password) => password.clone(),
+            None => {
+                let password = deploy::transport::ask_credential(
+                    deploy_data.transport,
+                    askpass,
+                    &format!(
+                        "[sudo] password for {} on {}: ",
+                        deploy_defs.ssh_user, deploy_data.node_name
+                    ),
+                )
+                .await?;
+
+                passwords.insert(deploy_data.node_name.to_string(), password.clone());
+                password
+            }
+        };
+
+        if !deploy_defs.feed_sudo_password(password) {
+            warn!(
+                "The sudo command of node `{}` is not `sudo`, it can't be given a password by --askpass",
+                deploy_data.node_name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// The profiles `deploy` activates on a node, in order, refusing a `profilesOrder` that names profiles which don't exist
+fn deployed_profiles(
+    node: &deploy::data::Node,
+) -> Result<Vec<(&str, &deploy::data::Profile)>, RunDeployError> {
+    if let Some(profile_name) = node
+        .node_settings
+        .profiles_order
+        .iter()
+        .find(|x| !node.node_settings.profiles.contains_key(*x))
+    {
+        return Err(RunDeployError::ProfileNotFound(profile_name.clone()));
+    }
+
+    Ok(ordered_profile_names(node)
+        .iter()
+        .filter_map(|x| node.node_settings.profiles.get_key_value(x))
+        .map(|(profile_name, profile)| (profile_name.as_str(), profile))
+        .collect())
+}
+
+/// One profile of each node with a `clusterGroup` in the flakes deployed from, for each temporary directory, to look
+/// for activations awaiting confirmation on, whether they're deployed now or not
+fn cluster_members<'a>(
+    data: &'a [deploy::data::Data],
+    cmd_overrides: &'a deploy::CmdOverrides,
+) -> Result<Vec<(deploy::DeployData<'a>, deploy::DeployDefs)>, RunDeployError> {
+    let mut members: Vec<(deploy::DeployData, deploy::DeployDefs)> = Vec::new();
+
+    for data in data {
+        for (node_name, node) in &data.nodes {
+            for (profile_name, profile) in &node.node_settings.profiles {
+                let deploy_data = deploy::make_deploy_data(
+                    &data.generic_settings,
+                    node,
+                    node_name,
+                    profile,
+                    profile_name,
+                    cmd_overrides,
+                    false,
+                    None,
+                );
+
+                if deploy_data.merged_settings.cluster_group.is_none() {
+                    continue;
+                }
+
+                let deploy_defs = deploy_data.defs()?;
+
+                if !members.iter().any(|(x, x_defs)| {
+                    x.node_name == deploy_data.node_name
+                        && x.merged_settings.cluster_group
+                            == deploy_data.merged_settings.cluster_group
+                        && x_defs.temp_path == deploy_defs.temp_path
+                }) {
+                    members.push((deploy_data, deploy_defs));
+                }
+            }
+        }
+    }
+
+    Ok(members)
+}
+
+/// Waits until no other member of the profile's cluster group has an activation awaiting confirmation, so that a bad
+/// deployment can only ever take down one member at a time before it rolls back
+async fn wait_for_cluster_group(
+    deploy_data: &deploy::DeployData<'_>,
+    members: &[(deploy::DeployData<'_>, deploy::DeployDefs)],
+) -> Result<(), RunDeployError> {
+    let group = match deploy_data.merged_settings.cluster_group {
+        Some(ref x) => x,
+        None => return Ok(()),
+    };
+
+    for (member, member_defs) in members.iter().filter(|(x, _)| {
+        x.node_name != deploy_data.node_name
+            && x.merged_settings.cluster_group.as_ref() == Some(group)
+    }) {
+        // Long enough for an activation that just started to be confirmed, or to roll back for want of confirmation
+        let settings = &member.merged_settings;
+        let patience = std::time::Duration::from_secs(
+            settings.activation_timeout.unwrap_or(240) as u64
+                + settings.confirm_timeout.unwrap_or(30) as u64,
+        );
+        let started = std::time::Instant::now();
+        let mut waiting = false;
+
+        loop {
+            match deploy::deploy::awaiting_confirmation(member, member_defs).await {
+                Ok(false) => break,
+                Ok(true) if started.elapsed() >= patience => {
+                    return Err(RunDeployError::ClusterGroupBusy(
+                        group.clone(),
+                        member.node_name.to_string(),
+                        deploy_data.node_name.to_string(),
+                    ))
+                }
+                Ok(true) => {
+                    if !waiting {
+                        waiting = true;
+                        info!(
+                            "Waiting for the activation on node `{}` of cluster group `{}` to be confirmed or rolled back",
+                            member.node_name, group
+                        );
+                    }
+
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                }
+                Err(e) => {
+                    return Err(RunDeployError::ClusterGroupCheck(
+                        group.clone(),
+                        member.node_name.to_string(),
+                        Box::new(e),
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits the profiles to activate, given by their node and its `activationConcurrency`, into the groups which are
+/// activated at the same time: profiles of the same node which follow each other, up to its concurrency
+fn activation_batches(profiles: &[(&str, usize)]) -> Vec<std::ops::Range<usize>> {
+    let mut batches: Vec<std::ops::Range<usize>> = Vec::new();
+
+    for (i, (node_name, concurrency)) in profiles.iter().enumerate() {
+        match batches.last_mut() {
+            Some(batch)
+                if profiles[batch.start].0 == *node_name && batch.len() < (*concurrency).max(1) =>
+            {
+                batch.end = i + 1
+            }
+            _ => batches.push(i..i + 1),
+        }
+    }
+
+    batches
+}
+
+#[test]
+fn test_activation_batches() {
+    assert_eq!(
+        activation_batches(&[("web-1", 1), ("web-1", 1), ("db-1", 1)]),
+        vec![0..1, 1..2, 2..3]
+    );
+    assert_eq!(
+        activation_batches(&[
+            ("web-1", 2),
+            ("web-1", 2),
+            ("web-1", 2),
+            ("db-1", 0),
+            ("web-1", 2)
+        ]),
+        vec![0..2, 2..3, 3..4, 4..5]
+    );
+}
+
+/// Portable service and netboot images have no activate-rs to show their changes with
+fn has_activate_rs(deploy_data: &deploy::DeployData<'_>) -> bool {
+    matches!(
+        deploy_data.profile.profile_settings.profile_type,
+        Some(deploy::data::ProfileType::Profile) | None
+    )
+}
+
+/// What became of a profile that was activated without failing
+#[derive(Debug, Clone, PartialEq)]
+enum Activation {
+    /// Along with what it did to systemd units, with `restartChangedOnly`
+    Activated(Option<deploy::units::UnitChanges>),
+    Deferred,
+    /// Its closure was active on the node already, so nothing was done
+    AlreadyActive,
+}
+
+impl std::fmt::Display for Activation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Activation::Activated(Some(unit_changes)) => write!(f, "activated, {}", unit_changes),
+            Activation::Activated(None) => f.write_str("activated"),
+            Activation::Deferred => f.write_str("deferred"),
+            Activation::AlreadyActive => f.write_str("already active"),
+        }
+    }
+}
+
+/// Activates a profile, or leaves its activation to the node when it's deferred to the node's maintenance window
+///
+/// With `skipActive`, which is the default, a profile whose closure the node has active already isn't activated
+/// again, so deploying the same thing twice doesn't restart its services.
+async fn activate_profile(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &deploy::DeployDefs,
+    opening: Option<u64>,
+    dry_activate: bool,
+    event_log: Option<&EventLog>,
+) -> Result<Activation, deploy::deploy::DeployProfileError> {
+    let node = deploy_data.node_name;
+    let profile = Some(deploy_data.profile_name);
+
+    let phase = match opening {
+        Some(_) => Phase::Defer,
+        None => Phase::Activate,
+    };
+
+    record_phase(event_log, node, profile, phase, Status::Started);
+
+    if opening.is_none()
+        && !dry_activate
+        && deploy_data.merged_settings.skip_active.unwrap_or(true)
+        && has_activate_rs(deploy_data)
+        && deploy::deploy::is_active(deploy_data, deploy_defs).await
+    {
+        info!(
+            "Profile `{}` of node `{}` is already active, not activating it again",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+        record_phase(event_log, node, profile, phase, Status::AlreadyActive);
+
+        return Ok(Activation::AlreadyActive);
+    }
+
+    let result = match opening {
+        Some(opening) => deploy::deploy::defer_activation(deploy_data, deploy_defs, opening).await,
+        None => deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate).await,
+    };
+
+    match result {
+        Ok(()) => {
+            record_phase(event_log, node, profile, phase, Status::Succeeded);
+
+            if !dry_activate {
+                record_deployment(deploy_data, opening.is_some());
+            }
+        }
+        Err(ref e) => record_failure(event_log, node, profile, phase, e),
+    }
+
+    result?;
+
+    if opening.is_some() {
+        return Ok(Activation::Deferred);
+    }
+
+    let unit_changes = match !dry_activate
+        && deploy_data
+            .merged_settings
+            .restart_changed_only
+            .unwrap_or(false)
+        && has_activate_rs(deploy_data)
+    {
+        true => deploy::deploy::unit_changes(deploy_data, deploy_defs).await,
+        false => None,
+    };
+
+    if let Some(ref unit_changes) = unit_changes {
+        info!(
+            "Activating profile `{}` of node `{}` {}",
+            deploy_data.profile_name, deploy_data.node_name, unit_changes
+        );
+    }
+
+    Ok(Activation::Activated(unit_changes))
+}
+
+/// How `run_deploy` goes about a deployment, from the options it was started with
+struct DeployRunOptions<'a> {
+    supports_flakes: bool,
+    interactive: bool,
+    keep_result: bool,
+    result_path: Option<&'a str>,
+    extra_build_args: &'a [String],
+    debug_logs: bool,
+    dry_activate: bool,
+    diff_etc: bool,
+    closure_diff: Option<bool>,
+    log_dir: &'a Option<String>,
+    rollback_succeeded: bool,
+    event_log: Option<&'a EventLog>,
+    pull: Option<PullSettings<'a>>,
+    outside_window: OutsideWindow,
+    /// The checks to run for each of the flakes
+    checks: &'a [CheckSelection],
+    preflight: bool,
+    run_id: Option<&'a str>,
+    production: bool,
+    deny_warnings: bool,
+    timings: Option<&'a deploy::timings::Timings>,
+    read_only: bool,
+}
+
+async fn run_deploy(
+    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
+    data: Vec<deploy::data::Data>,
+    cmd_overrides: &deploy::CmdOverrides,
+    options: DeployRunOptions<'_>,
+) -> Result<(), RunDeployError> {
+    let DeployRunOptions {
+        supports_flakes,
+        interactive,
+        keep_result,
+        result_path,
+        extra_build_args,
+        debug_logs,
+        dry_activate,
+        diff_etc,
+        closure_diff,
+        log_dir,
+        rollback_succeeded,
+        event_log,
+        pull,
+        outside_window,
+        checks,
+        preflight,
+        run_id,
+        production,
+        deny_warnings,
+        timings,
+        read_only,
+    } = options;
+
+    let to_deploy: ToDeploy = deploy_flakes
+        .iter()
+        .zip(&data)
+        .map(|(deploy_flake, data)| {
+            let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
+                (Some(node_name), Some(profile_name)) => {
+                    let node = match data.nodes.get(node_name) {
+                        Some(x) => x,
+                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                    };
+                    let profile = match node.node_settings.profiles.get(profile_name) {
+                        Some(x) => x,
+                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
+                    };
+
+                    vec![(
+                        deploy_flake,
+                        data,
+                        (node_name.as_str(), node),
+                        (profile_name.as_str(), profile),
+                    )]
+                }
+                (Some(node_name), None) => {
+                    let node = match data.nodes.get(node_name) {
+                        Some(x) => x,
+                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                    };
+
+                    deployed_profiles(node)?
+                        .into_iter()
+                        .map(|x| (deploy_flake, data, (node_name.as_str(), node), x))
+                        .collect()
+                }
+                (None, None) => {
+                    let mut nodes: Vec<(&String, &deploy::data::Node)> =
+                        data.nodes.iter().collect();
+                    nodes.sort_by_key(|(node_name, _)| *node_name);
+
+                    let mut l = Vec::new();
+
+                    for (node_name, node) in nodes {
+                        l.extend(
+                            deployed_profiles(node)?
+                                .into_iter()
+                                .map(|x| (deploy_flake, data, (node_name.as_str(), node), x)),
+                        );
+                    }
+
+                    l
+                }
+                (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
+            };
+            Ok(to_deploys)
+        })
+        .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // Each flake is evaluated once per run, and settings are merged once per profile right here. Pushing,
+    // activating and rolling back all work off these parts, so no phase evaluates or merges anything again
+    let mut parts: Vec<(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData,
+        deploy::DeployDefs,
+    )> = Vec::new();
+
+    let mut cached_facts: HashMap<&str, Option<deploy::facts::NodeFacts>> = HashMap::new();
+
+    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+        let mut deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        );
+
+        deploy_data.facts = cached_facts
+            .entry(node_name)
+            .or_insert_with(|| deploy::facts::cached_facts(node_name))
+            .clone();
+        deploy_data.timings = timings;
+
+        let deploy_defs = deploy_data.defs()?;
+
+        parts.push((deploy_flake, deploy_data, deploy_defs));
+    }
+
+    print_deployment(&parts[..])?;
+
+    // Nothing is done on the nodes until the deployment is confirmed
+    if interactive {
+        prompt_deployment()?;
+    }
+
+    confirm_protected(
+        parts
+            .iter()
+            .map(|(_, deploy_data, _)| (deploy_data.node_name, deploy_data.node)),
+        production,
+    )?;
+
+    if let Some(event_log) = event_log {
+        for (_, deploy_data, _) in &parts {
+            event_log.plan(
+                deploy_data.node_name,
+                deploy_data.profile_name,
+                &deploy_data.profile.profile_settings.path,
+            );
+        }
+    }
+
+    // Nodes pulling their profiles aren't connected to
+    if pull.is_none() {
+        if cmd_overrides.ssh_control_dir.is_some() {
+            let mut nodes = Vec::new();
+
+            for (_, deploy_data, deploy_defs) in &parts {
+                if let deploy::transport::NodeTarget::Ssh { address, opts, .. } =
+                    deploy_data.node_target(deploy_defs)?
+                {
+                    nodes.push((deploy_data.node_name, address, opts));
+                }
+            }
+
+            authenticate_serially(&nodes).await;
+        }
+
+        pin_host_keys(&parts).await?;
+
+        // Everything else done on the nodes needs Nix there. Installing it is a change to the node, which read-only
+        // mode doesn't get to make
+        if !read_only {
+            bootstrap_nix(&parts).await?;
+        }
+
+        // Before anything is done with the sudo commands, which can depend on the facts
+        if preflight {
+            refresh_facts(&mut parts).await?;
+        }
+
+        check_nix_versions(&parts);
+
+        if let Some(ref askpass) = cmd_overrides.askpass {
+            ask_sudo_passwords(askpass, &mut parts).await?;
+        }
+
+        if preflight {
+            run_preflight(&parts).await?;
+        }
+    }
+
+    // Checks are run after the preflight, which is quicker, and before building anything
+    for (deploy_flake, checks) in deploy_flakes.iter().zip(checks) {
+        if *checks != CheckSelection::Only(Vec::new()) {
+            check_deployment(supports_flakes, deploy_flake.repo, checks, extra_build_args).await?;
+        }
+    }
+
+    // In target-pull mode nodes fetch and activate their profiles themselves, we only build and describe them
+    if let Some(pull) = pull {
+        for (deploy_flake, deploy_data, deploy_defs) in &parts {
+            deploy::push::build_profile(&deploy::push::PushProfileData {
+                supports_flakes,
+                repo: deploy_flake.repo,
+                deploy_data,
+                deploy_defs,
+                keep_result,
+                result_path,
+                extra_build_args,
+            })
+            .await
+            .map_err(|e| RunDeployError::PushProfile(Reported::new(e, deploy_data)))?;
+
+            let closure = &deploy_data.profile.profile_settings.path;
+
+            if let Some(copy_to) = pull.copy_to {
+                deploy::pull::copy_to_cache(closure, copy_to).await?;
+            }
+
+            let descriptor = deploy::pull::sign_descriptor(
+                deploy_data.transport,
+                &deploy::pull::PullDescriptor {
+                    node: deploy_data.node_name.to_string(),
+                    profile: deploy_data.profile_name.to_string(),
+                    closure: closure.clone(),
+                    profile_path: deploy_defs.profile_path.clone(),
+                    substituters: pull.substituters.to_vec(),
+                    signer: None,
+                    signature: None,
+                },
+                pull.sign_key,
+                &pull.signer,
+            )
+            .await?;
+
+            let path = deploy::pull::write_descriptor(pull.descriptors, &descriptor).await?;
+
+            info!(
+                "Wrote pull descriptor for profile `{}` on node `{}` to {}",
+                deploy_data.profile_name, deploy_data.node_name, path
+            );
+        }
+
+        return Ok(());
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+
+    // When the maintenance window of each part opens, for those whose activation is deferred until then.
+    // Nothing is activated by dry activation, showing /etc changes or read-only mode, so windows don't matter to them
+    let mut openings: Vec<Option<u64>> = Vec::new();
+
+    for (_, deploy_data, _) in &parts {
+        let opening = match deploy_data.merged_settings.maintenance_window {
+            Some(ref window) if !dry_activate && !diff_etc && !read_only => {
+                let maintenance_window: deploy::window::MaintenanceWindow = window.parse()?;
+
+                match (maintenance_window.contains(now), outside_window) {
+                    (true, _) => None,
+                    (false, OutsideWindow::Refuse) => {
+                        return Err(RunDeployError::OutsideMaintenanceWindow(
+                            deploy_data.node_name.to_string(),
+                            window.clone(),
+                        ))
+                    }
+                    (false, OutsideWindow::Defer) => Some(maintenance_window.next_opening(now)),
+                    (false, OutsideWindow::Force) => {
+                        warn!(
+                            "Node `{}` is outside its maintenance window `{}`, activating anyway",
+                            deploy_data.node_name, window
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        openings.push(opening);
+    }
+
+    // Profiles built here already, as nodes of a node group share theirs. Kept build results are linked per node
+    let mut built: Vec<&str> = Vec::new();
+
+    // Everything is built before anything is copied, so all nodes can be asked what they're missing at once
+    for (deploy_flake, deploy_data, deploy_defs) in &parts {
+        let node = deploy_data.node_name;
+        let profile = Some(deploy_data.profile_name);
+        let path = deploy_data.profile.profile_settings.path.as_str();
+
+        record_phase(event_log, node, profile, Phase::Push, Status::Started);
+
+        let remote = deploy::push::builds_remotely(deploy_data);
+
+        if !remote && !keep_result && built.contains(&path) {
+            debug!(
+                "Profile `{}` of node `{}` is built already",
+                deploy_data.profile_name, node
+            );
+            continue;
+        }
+
+        let push_data = deploy::push::PushProfileData {
+            supports_flakes,
+            repo: deploy_flake.repo,
+            deploy_data,
+            deploy_defs,
+            keep_result,
+            result_path,
+            extra_build_args,
+        };
+
+        let result = match remote {
+            true => deploy::push::build_remotely(&push_data).await,
+            false => deploy::push::build_profile(&push_data).await,
+        };
+
+        if let Err(e) = result {
+            record_failure(event_log, node, profile, Phase::Push, &e);
+            return Err(RunDeployError::PushProfile(Reported::new(e, deploy_data)));
+        }
+
+        if !remote {
+            built.push(path);
+        }
+    }
+
+    let presences =
+        futures_util::future::join_all(parts.iter().map(|(_, deploy_data, deploy_defs)| {
+            deploy::push::prefetch_presence(deploy_data, deploy_defs)
+        }))
+        .await;
+
+    for ((deploy_flake, deploy_data, deploy_defs), presence) in parts.iter().zip(&presences) {
+        let node = deploy_data.node_name;
+        let profile = Some(deploy_data.profile_name);
+        let started = std::time::Instant::now();
+
+        if let Err(e) = deploy::push::copy_profile(
+            &deploy::push::PushProfileData {
+                supports_flakes,
+                repo: deploy_flake.repo,
+                deploy_data,
+                deploy_defs,
+                keep_result,
+                result_path,
+                extra_build_args,
+            },
+            presence.as_ref(),
+        )
+        .await
+        {
+            record_failure(event_log, node, profile, Phase::Push, &e);
+            return Err(RunDeployError::PushProfile(Reported::new(e, deploy_data)));
+        }
+
+        deploy::timings::record(timings, node, deploy::timings::Step::Copy, started);
+
+        record_phase(event_log, node, profile, Phase::Push, Status::Succeeded);
+    }
+
+    if diff_etc {
+        for (_, deploy_data, deploy_defs) in &parts {
+            if !has_activate_rs(deploy_data) {
+                continue;
+            }
+
+            deploy::deploy::diff_etc(deploy_data, deploy_defs).await?;
+        }
+
+        return Ok(());
+    }
+
+    let show_closure_diff = closure_diff.unwrap_or(interactive);
+
+    if show_closure_diff {
+        for (_, deploy_data, deploy_defs) in &parts {
+            if !has_activate_rs(deploy_data) {
+                continue;
+            }
+
+            match deploy::deploy::diff_closures(deploy_data, deploy_defs).await {
+                Ok(diff) => {
+                    info!(
+                        "Changes for profile `{}` on node `{}`:\n{}",
+                        deploy_data.profile_name,
+                        deploy_data.node_name,
+                        diff.render()
+                    );
+
+                    if let Some(event_log) = event_log {
+                        event_log.phase(
+                            deploy_data.node_name,
+                            Some(deploy_data.profile_name),
+                            Phase::Diff,
+                            Status::Succeeded,
+                            None,
+                            serde_json::to_value(&diff).ok(),
+                        );
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not compute changes for profile `{}` on node `{}`: {}",
+                        deploy_data.profile_name, deploy_data.node_name, e
+                    );
+
+                    record_failure(
+                        event_log,
+                        deploy_data.node_name,
+                        Some(deploy_data.profile_name),
+                        Phase::Diff,
+                        &e,
+                    );
+                }
+            }
+        }
+    }
+
+    // Strict pipelines stop before any node is changed
+    let raised = deploy::warnings::raised();
+    if deny_warnings && !raised.is_empty() {
+        return Err(RunDeployError::DeniedWarnings(deploy::warnings::summary(
+            &raised,
+        )));
+    }
+
+    // The closures are staged on the nodes, for a deployment that's allowed to activate them to find there
+    if read_only && !dry_activate {
+        info!(
+            "Read-only mode, leaving {} profiles copied to their nodes without activating them",
+            parts.len()
+        );
+
+        return Ok(());
+    }
+
+    // Asked again once the changes are known, which takes the profiles being copied to the nodes
+    if interactive && show_closure_diff {
+        prompt_deployment()?;
+    }
+
+    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+
+    // Run all deployments
+    // In case of an error rollback any previoulsy made deployment.
+    // Rollbacks adhere to the global seeting to auto_rollback and secondary
+    // the profile's configuration
+    let batches = activation_batches(
+        &parts
+            .iter()
+            .map(|(_, deploy_data, _)| {
+                (
+                    deploy_data.node_name,
+                    deploy_data
+                        .merged_settings
+                        .activation_concurrency
+                        .unwrap_or(1),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    let label = |deploy_data: &deploy::DeployData| {
+        format!("{}.{}", deploy_data.node_name, deploy_data.profile_name)
+    };
+
+    let cluster_members = cluster_members(&data, cmd_overrides)?;
+
+    // Signed receipts tell which revision the profiles were deployed from, which is looked up once per flake
+    let mut revisions: HashMap<&str, Option<String>> = HashMap::new();
+    if cmd_overrides.receipt_key.is_some() && !dry_activate && supports_flakes {
+        for deploy_flake in &deploy_flakes {
+            if !revisions.contains_key(deploy_flake.repo) {
+                let rev = deploy::bundle::flake_revision(
+                    &deploy::transport::SystemTransport,
+                    deploy_flake.repo,
+                    cmd_overrides.offline,
+                )
+                .await;
+                revisions.insert(deploy_flake.repo, rev);
+            }
+        }
+    }
+
+    // Profiles which failed without stopping the deployment, and the ones they made `onProfileFailure` skip
+    let mut failed = 0;
+    let mut skipped: Vec<String> = Vec::new();
+    let mut given_up: Vec<&str> = Vec::new();
+    // The profiles left to activate when `deploy pause` stopped the run
+    let mut paused: Option<Vec<String>> = None;
+
+    for batch in batches {
+        if let Some(run_id) = run_id {
+            if deploy::state::state_dir()
+                .map(|dir| deploy::runs::pause_requested(&dir, run_id))
+                .unwrap_or(false)
+            {
+                paused = Some(
+                    parts[batch.start..]
+                        .iter()
+                        .filter(|(_, deploy_data, _)| !given_up.contains(&deploy_data.node_name))
+                        .map(|(deploy_flake, deploy_data, _)| {
+                            format!(
+                                "{}#{}.{}",
+                                deploy_flake.repo,
+                                deploy::nix_string(deploy_data.node_name),
+                                deploy::nix_string(deploy_data.profile_name)
+                            )
+                        })
+                        .collect(),
+                );
+                break;
+            }
+        }
+
+        let node_name = parts[batch.start].1.node_name;
+
+        if given_up.contains(&node_name) {
+            skipped.extend(
+                parts[batch]
+                    .iter()
+                    .map(|(_, deploy_data, _)| label(deploy_data)),
+            );
+            continue;
+        }
+
+        let remaining = &parts[batch.end..];
+        let batch: Vec<_> = parts[batch.clone()]
+            .iter()
+            .zip(openings[batch].iter().copied())
+            .collect();
+
+        // Deferred activations happen later, when whatever is waiting now is long done
+        if !dry_activate {
+            for ((_, deploy_data, _), _) in batch.iter().filter(|(_, opening)| opening.is_none()) {
+                wait_for_cluster_group(deploy_data, &cluster_members).await?;
+            }
+        }
+
+        let results = futures_util::future::join_all(batch.iter().map(
+            |((_, deploy_data, deploy_defs), opening)| {
+                activate_profile(deploy_data, deploy_defs, *opening, dry_activate, event_log)
+            },
+        ))
+        .await;
+
+        if batch.len() > 1 {
+            let statuses: Vec<String> = batch
+                .iter()
+                .zip(&results)
+                .map(|(((_, deploy_data, _), _), result)| {
+                    format!(
+                        "  {}: {}",
+                        deploy_data.profile_name,
+                        match result {
+                            Ok(activation) => activation.to_string(),
+                            Err(e) => format!("failed, {}", e),
+                        }
+                    )
+                })
+                .collect();
+
+            info!("Profiles on node `{}`:\n{}", node_name, statuses.join("\n"));
+        }
+
+        let mut failure = None;
+
+        for (((deploy_flake, deploy_data, deploy_defs), _), result) in
+            batch.into_iter().zip(results)
+        {
+            match result {
+                // A deferred activation hasn't changed anything yet, and a profile that was active already wasn't
+                // changed at all, so there's nothing to revoke
+                Ok(Activation::Deferred) | Ok(Activation::AlreadyActive) => (),
+                Ok(Activation::Activated(_)) => {
+                    if !dry_activate && cmd_overrides.receipt_key.is_some() {
+                        let rev = revisions.get(deploy_flake.repo).cloned().flatten();
+                        record_deploy_receipt(deploy_data, deploy_defs, rev).await;
+                    }
+
+                    succeeded.push((deploy_data, deploy_defs))
+                }
+                Err(e) => match deploy_data
+                    .merged_settings
+                    .on_profile_failure
+                    .unwrap_or(ProfileFailurePolicy::Abort)
+                {
+                    ProfileFailurePolicy::Abort if failure.is_none() => {
+                        failure = Some((deploy_data, e))
+                    }
+                    ProfileFailurePolicy::Abort => (),
+                    policy => {
+                        error!(
+                            "Failed to deploy profile: {}",
+                            Reported::new(e, deploy_data)
+                        );
+                        failed += 1;
+
+                        if policy == ProfileFailurePolicy::SkipNode
+                            && !given_up.contains(&node_name)
+                        {
+                            given_up.push(node_name);
+                        }
+                    }
+                },
+            }
+        }
+
+        if let Some((deploy_data, e)) = failure {
+            skipped.extend(
+                remaining
+                    .iter()
+                    .map(|(_, deploy_data, _)| label(deploy_data)),
+            );
+
+            if !skipped.is_empty() {
+                warn!(
+                    "Not deploying, because of the failure: {}",
+                    skipped.join(", ")
+                );
+            }
+
+            if dry_activate {
+                info!("dry run, not rolling back");
+                return Err(RunDeployError::DeployProfile(Reported::new(e, deploy_data)));
+            }
+
+            // The node rolls back by itself if activation fails, or if it can't be confirmed
+            let settings = &deploy_data.merged_settings;
+            let node_rolled_back =
+                settings.auto_rollback.unwrap_or(true) || settings.magic_rollback.unwrap_or(true);
+
+            let mut left_in_place = succeeded.len();
+
+            info!("Revoking previous deploys");
+            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
+                // revoking all previous deploys
+                // (adheres to profile configuration if not set explicitely by
+                //  the command line)
+                for (deploy_data, deploy_defs) in &succeeded {
+                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+                        let node = deploy_data.node_name;
+                        let profile = Some(deploy_data.profile_name);
+
+                        record_phase(event_log, node, profile, Phase::Revoke, Status::Started);
+
+                        if let Err(e) = deploy::deploy::revoke(*deploy_data, *deploy_defs).await {
+                            record_failure(event_log, node, profile, Phase::Revoke, &e);
+                            return Err(RunDeployError::RevokeProfile(Reported::new(
+                                e,
+                                deploy_data,
+                            )));
+                        }
+
+                        record_phase(event_log, node, profile, Phase::Revoke, Status::Succeeded);
+
+                        left_in_place -= 1;
+                    }
+                }
+            }
+
+            let e = Reported::new(e, deploy_data);
+
+            return Err(match (left_in_place, node_rolled_back) {
+                (0, false) if succeeded.is_empty() => RunDeployError::DeployProfile(e),
+                (0, _) => RunDeployError::DeployProfileRolledBack(e),
+                (n, _) => RunDeployError::DeployProfilePartial(e, n),
+            });
+        }
+    }
+
+    if !dry_activate {
+        optimise_stores(&succeeded).await;
+
+        for (deploy_data, _) in &succeeded {
+            confirm_in_channel(deploy_data);
+        }
+    }
+
+    if !skipped.is_empty() {
+        warn!("Skipped after failures: {}", skipped.join(", "));
+    }
+
+    if let (Some(run_id), Some(targets)) = (run_id, paused) {
+        warn!("Paused, not deploying: {}", targets.join(", "));
+
+        let paused = deploy::runs::PausedRun { targets };
+        if let Err(e) = deploy::state::state_dir()
+            .and_then(|dir| deploy::runs::save_paused(&dir, run_id, &paused))
+        {
+            warn!("Could not record where the run was paused: {}", e);
+        }
+
+        return Err(RunDeployError::Paused(
+            run_id.to_string(),
+            paused.targets.len(),
+        ));
+    }
+
+    if failed > 0 {
+        return Err(RunDeployError::DeployProfiles(failed, succeeded.len()));
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunRollbackAllError {
+    #[error("{0}")]
+    State(#[from] deploy::state::StateError),
+    #[error("Run `{0}` left no profiles activated")]
+    NothingActivated(String),
+    #[error("{0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Failed to roll back {0} of {1} profiles")]
+    RollBack(usize, usize),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+}
+
+impl RunRollbackAllError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunRollbackAllError::RollBack(_, _) => ExitCode::RollbackFailed,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// Rolls back a profile `run` activated, unless it was deployed again since, and returns whether it was
+async fn roll_back_profile(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &deploy::DeployDefs,
+    run: &str,
+    event_log: Option<&EventLog>,
+) -> Result<bool, deploy::deploy::RevokeProfileError> {
+    let node = deploy_data.node_name;
+    let profile = Some(deploy_data.profile_name);
+
+    // Going back a generation from anything else wouldn't go back to where the node was before the run
+    if !deploy::deploy::is_active(deploy_data, deploy_defs).await {
+        warn!(
+            "Not rolling back profile `{}` of node `{}`, it isn't on the closure run `{}` activated anymore",
+            deploy_data.profile_name, node, run
+        );
+
+        return Ok(false);
+    }
+
+    info!(
+        "Rolling back profile `{}` of node `{}`",
+        deploy_data.profile_name, node
+    );
+
+    record_phase(event_log, node, profile, Phase::Revoke, Status::Started);
+
+    match deploy::deploy::revoke(deploy_data, deploy_defs).await {
+        Ok(()) => {
+            record_phase(event_log, node, profile, Phase::Revoke, Status::Succeeded);
+
+            Ok(true)
+        }
+        Err(e) => {
+            record_failure(event_log, node, profile, Phase::Revoke, &e);
+
+            Err(e)
+        }
+    }
+}
+
+/// Rolls back every profile a recorded run left activated, the last one activated first, with the same batches of
+/// profiles on a node as deploying
+async fn run_rollback_all(
+    mut data: Vec<deploy::data::Data>,
+    run: &str,
+    cmd_overrides: &deploy::CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<&str>,
+    event_log: Option<&EventLog>,
+    production: bool,
+) -> Result<(), RunRollbackAllError> {
+    let manifest = deploy::runs::load_run(&deploy::state::state_dir()?, run)?;
+    let to_roll_back = deploy::runs::rollback_order(&manifest);
+
+    if to_roll_back.is_empty() {
+        return Err(RunRollbackAllError::NothingActivated(run.to_string()));
+    }
+
+    // The closure the run activated is what's on the node to roll back with, the flake may have moved on since
+    for run_profile in &to_roll_back {
+        for data in &mut data {
+            if let Some(profile) = data
+                .nodes
+                .get_mut(&run_profile.node)
+                .and_then(|node| node.node_settings.profiles.get_mut(&run_profile.profile))
+            {
+                profile.profile_settings.path = run_profile.closure.clone();
+            }
+        }
+    }
+
+    let mut parts: Vec<(deploy::DeployData, deploy::DeployDefs)> = Vec::new();
+
+    for run_profile in to_roll_back {
+        let found = data.iter().find_map(|data| {
+            let node = data.nodes.get(&run_profile.node)?;
+            let profile = node.node_settings.profiles.get(&run_profile.profile)?;
+
+            Some((data, node, profile))
+        });
+
+        let (data, node, profile) = match found {
+            Some(x) => x,
+            None => {
+                warn!(
+                    "Not rolling back profile `{}` of node `{}`, the flake doesn't have it anymore",
+                    run_profile.profile, run_profile.node
+                );
+                continue;
+            }
+        };
+
+        let mut deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            &run_profile.node,
+            profile,
+            &run_profile.profile,
+            cmd_overrides,
+            debug_logs,
+            log_dir,
+        );
+        deploy_data.facts = deploy::facts::cached_facts(&run_profile.node);
+
+        let deploy_defs = deploy_data.defs()?;
+
+        parts.push((deploy_data, deploy_defs));
+    }
+
+    confirm_protected(
+        parts
+            .iter()
+            .map(|(deploy_data, _)| (deploy_data.node_name, deploy_data.node)),
+        production,
+    )?;
+
+    let batches = activation_batches(
+        &parts
+            .iter()
+            .map(|(deploy_data, _)| {
+                (
+                    deploy_data.node_name,
+                    deploy_data
+                        .merged_settings
+                        .activation_concurrency
+                        .unwrap_or(1),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    let mut rolled_back = 0;
+    let mut failed = 0;
+
+    for batch in batches {
+        let batch = &parts[batch];
+
+        let results =
+            futures_util::future::join_all(batch.iter().map(|(deploy_data, deploy_defs)| {
+                roll_back_profile(deploy_data, deploy_defs, run, event_log)
+            }))
+            .await;
+
+        for ((deploy_data, _), result) in batch.iter().zip(results) {
+            match result {
+                Ok(true) => rolled_back += 1,
+                Ok(false) => (),
+                Err(e) => {
+                    error!(
+                        "Failed to roll back profile: {}",
+                        Reported::new(e, deploy_data)
+                    );
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    info!(
+        "Rolled back {} of the {} profiles run `{}` activated",
+        rolled_back,
+        parts.len(),
+        run
+    );
+
+    if failed > 0 {
+        return Err(RunRollbackAllError::RollBack(failed, parts.len()));
+    }
+
+    Ok(())
+}
+
+/// Optimises the Nix store of each node with `optimiseStore` that had a profile activated, all at the same time
+///
+/// The deployment is done by now, so anything going wrong is just a warning.
+async fn optimise_stores(succeeded: &[(&deploy::DeployData<'_>, &deploy::DeployDefs)]) {
+    let mut nodes: Vec<&(&deploy::DeployData, &deploy::DeployDefs)> = Vec::new();
+
+    for part in succeeded {
+        if part.0.merged_settings.optimise_store == Some(true)
+            && !nodes.iter().any(|x| x.0.node_name == part.0.node_name)
+        {
+            nodes.push(part);
+        }
+    }
+
+    let results = futures_util::future::join_all(nodes.iter().map(|(deploy_data, deploy_defs)| {
+        info!(
+            "Optimising the Nix store of node `{}`",
+            deploy_data.node_name
+        );
+
+        deploy::deploy::optimise_store(deploy_data, deploy_defs)
+    }))
+    .await;
+
+    for ((deploy_data, _), result) in nodes.iter().zip(results) {
+        match result {
+            Ok(true) => info!("Optimised the Nix store of node `{}`", deploy_data.node_name),
+            Ok(false) => info!(
+                "Stopped optimising the Nix store of node `{}` after its `optimiseTimeout`, the next deployment goes on",
+                deploy_data.node_name
+            ),
+            Err(e) => warn!(
+                "Could not optimise the Nix store of node `{}`: {}",
+                deploy_data.node_name, e
+            ),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunExecError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("No nodes matched the selection")]
+    NoNodes,
+    #[error("Command failed on {0} of {1} nodes")]
+    Failed(usize, usize),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+}
+
+impl RunExecError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunExecError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunExecError::Failed(failed, total) if failed < total => ExitCode::PartialFailure,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_exec(
+    supports_flakes: bool,
+    exec_opts: &ExecOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    event_log: Option<&EventLog>,
+    production: bool,
+) -> Result<(), RunExecError> {
+    let deploy_flake = deploy::parse_flake(&exec_opts.target)?;
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let mut nodes: Vec<(&String, &deploy::data::Node)> = match deploy_flake.node {
+        Some(ref node_name) => match data.nodes.get_key_value(node_name) {
+            Some(x) => vec![x],
+            None => return Err(RunExecError::NodeNotFound(node_name.clone())),
+        },
+        None => data.nodes.iter().collect(),
+    };
+
+    if !exec_opts.tag.is_empty() {
+        nodes.retain(|(_, node)| {
+            node.node_settings
+                .tags
+                .iter()
+                .any(|t| exec_opts.tag.contains(t))
+        });
+    }
+
+    if nodes.is_empty() {
+        return Err(RunExecError::NoNodes);
+    }
+
+    confirm_protected(
+        nodes
+            .iter()
+            .map(|(node_name, node)| (node_name.as_str(), *node)),
+        production,
+    )?;
+
+    nodes.sort_by_key(|(node_name, _)| *node_name);
+
+    let node_count = nodes.len();
+
+    if cmd_overrides.ssh_control_dir.is_some() {
+        let node_datas: Vec<deploy::NodeData> = nodes
+            .iter()
+            .map(|(node_name, node)| {
+                deploy::make_node_data(&data.generic_settings, node, node_name, cmd_overrides)
+            })
+            .collect();
+
+        authenticate_serially(
+            &node_datas
+                .iter()
+                .map(|x| (x.node_name, x.ssh_addr(), &x.merged_settings.ssh_opts[..]))
+                .collect::<Vec<_>>(),
+        )
+        .await;
+    }
+
+    let results: Vec<Result<(), deploy::exec::ExecError>> = futures_util::stream::iter(nodes)
+        .map(|(node_name, node)| {
+            let node_data =
+                deploy::make_node_data(&data.generic_settings, node, node_name, cmd_overrides);
+
+            async move {
+                record_phase(event_log, node_name, None, Phase::Exec, Status::Started);
+
+                let result = deploy::exec::exec_node(deploy::exec::ExecData {
+                    node_name,
+                    hostname: node_data.hostname(),
+                    ssh_user: &node_data.ssh_user(),
+                    ssh_opts: &node_data.merged_settings.ssh_opts,
+                    command: &exec_opts.command,
+                })
+                .await;
+
+                match result {
+                    Ok(()) => {
+                        record_phase(event_log, node_name, None, Phase::Exec, Status::Succeeded)
+                    }
+                    Err(ref e) => record_failure(event_log, node_name, None, Phase::Exec, e),
+                }
+
+                result
+            }
+        })
+        .buffer_unordered(exec_opts.jobs.max(1))
+        .collect()
+        .await;
+
+    let mut failed = 0;
+
+    for result in results {
+        if let Err(e) = result {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(RunExecError::Failed(failed, node_count));
+    }
+
+    info!("Command succeeded on all {} nodes", node_count);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunCopyFileError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("Destination `{0}` should be in the form `<node>:<path>`")]
+    InvalidDestination(String),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Failed to copy file: {0}")]
+    CopyFile(#[from] deploy::exec::CopyFileError),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+}
+
+impl RunCopyFileError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunCopyFileError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunCopyFileError::CopyFile(_) => ExitCode::Copy,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_copy_file(
+    supports_flakes: bool,
+    copy_file_opts: &CopyFileOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    production: bool,
+) -> Result<(), RunCopyFileError> {
+    let (node_name, path) = match copy_file_opts.destination.split_once(':') {
+        Some((node_name, path)) if !node_name.is_empty() && !path.is_empty() => (node_name, path),
+        _ => {
+            return Err(RunCopyFileError::InvalidDestination(
+                copy_file_opts.destination.clone(),
+            ))
+        }
+    };
+
+    let deploy_flake = DeployFlake {
+        node: Some(node_name.to_string()),
+        ..deploy::parse_flake(&copy_file_opts.flake)?
+    };
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunCopyFileError::NodeNotFound(node_name.to_string())),
+    };
+
+    confirm_protected(std::iter::once((node_name, node)), production)?;
+
+    let mut node_data =
+        deploy::make_node_data(&data.generic_settings, node, node_name, cmd_overrides);
+    node_data.facts = deploy::facts::cached_facts(node_name);
+
+    info!(
+        "Copying `{}` to `{}` on node `{}`",
+        copy_file_opts.source, path, node_name
+    );
+
+    deploy::exec::copy_file(deploy::exec::CopyFileData {
+        source: &copy_file_opts.source,
+        destination: path,
+        mode: copy_file_opts.mode.as_deref(),
+        sudo: copy_file_opts
+            .as_user
+            .as_ref()
+            .and_then(|user| node_data.sudo_for(user)),
+        hostname: node_data.hostname(),
+        ssh_user: &node_data.ssh_user(),
+        ssh_opts: &node_data.merged_settings.ssh_opts,
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunDiffRemoteError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Node `{0}` has no profile named `{1}`")]
+    ProfileNotFound(String, String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Failed to query the closure deployed to node `{0}`: {1}")]
+    QueryClosure(String, deploy::deploy::QueryClosureError),
+    #[error("Failed to serialize the differences: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl RunDiffRemoteError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunDiffRemoteError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_diff_remote(
+    supports_flakes: bool,
+    diff_remote_opts: &DiffRemoteOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunDiffRemoteError> {
+    let deploy_flake = deploy::parse_flake(&diff_remote_opts.flake)?;
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let profile_name = &diff_remote_opts.profile;
+
+    let mut closures = Vec::new();
+
+    for node_name in &[&diff_remote_opts.from, &diff_remote_opts.to] {
+        let node = match data.nodes.get(*node_name) {
+            Some(x) => x,
+            None => return Err(RunDiffRemoteError::NodeNotFound(node_name.to_string())),
+        };
+
+        let profile = match node.node_settings.profiles.get(profile_name) {
+            Some(x) => x,
+            None => {
+                return Err(RunDiffRemoteError::ProfileNotFound(
+                    node_name.to_string(),
+                    profile_name.clone(),
+                ))
+            }
+        };
+
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        );
+        let deploy_defs = deploy_data.defs()?;
+
+        info!(
+            "Querying the closure of profile `{}` on node `{}`",
+            profile_name, node_name
+        );
+
+        closures.push(
+            deploy::deploy::query_deployed_closure(&deploy_data, &deploy_defs)
+                .await
+                .map_err(|e| RunDiffRemoteError::QueryClosure(node_name.to_string(), e))?,
+        );
+    }
+
+    let diff = deploy::diff::diff_closures(&closures[0], &closures[1]);
+
+    match diff_remote_opts.json {
+        true => println!(
+            "{}",
+            serde_json::to_string_pretty(&deploy::schema::Versioned::new(&diff))?
+        ),
+        false => println!(
+            "Profile `{}` from node `{}` to node `{}`:\n{}",
+            profile_name,
+            diff_remote_opts.from,
+            diff_remote_opts.to,
+            deploy::diff::render_closure_diff(&diff)
+        ),
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunGraphError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+}
+
+impl RunGraphError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunGraphError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_graph(
+    supports_flakes: bool,
+    graph_opts: &GraphOpts,
+    deploy_attr: Option<&str>,
+    extra_build_args: &[String],
+) -> Result<(), RunGraphError> {
+    let deploy_flake = deploy::parse_flake(&graph_opts.target)?;
+
+    let mut data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        deploy_attr,
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    if let Some(ref node_name) = deploy_flake.node {
+        if !data.nodes.contains_key(node_name) {
+            return Err(RunGraphError::NodeNotFound(node_name.clone()));
+        }
+
+        data.nodes.retain(|name, _| name == node_name);
+    }
+
+    print!("{}", deploy::graph::render_graph(&data, graph_opts.format));
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunBundleError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Node `{0}` has no profile named `{1}`")]
+    ProfileNotFound(String, String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Failed to build profile: {0}")]
+    Build(#[from] deploy::push::PushProfileError),
+    #[error("{0}")]
+    Bundle(#[from] deploy::bundle::BundleError),
+    #[error("Failed to deploy profile: {0}")]
+    DeployProfile(#[from] deploy::deploy::DeployProfileError),
+    #[error("Failed to revoke profile after a failed deployment: {0}")]
+    RevokeProfile(#[from] deploy::deploy::RevokeProfileError),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+}
+
+impl RunBundleError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunBundleError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunBundleError::Build(e) => e.exit_code(),
+            RunBundleError::Bundle(deploy::bundle::BundleError::Import(..))
+            | RunBundleError::Bundle(deploy::bundle::BundleError::ImportExit(_)) => ExitCode::Copy,
+            RunBundleError::DeployProfile(_) => ExitCode::Activation,
+            RunBundleError::RevokeProfile(_) => ExitCode::RollbackFailed,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// Profiles of a node in the order `profilesOrder` asks for, followed by the rest sorted by name
+fn ordered_profile_names(node: &deploy::data::Node) -> Vec<String> {
+    let mut profiles: Vec<String> = Vec::new();
+
+    for profile_name in &node.node_settings.profiles_order {
+        if node.node_settings.profiles.contains_key(profile_name)
+            && !profiles.contains(profile_name)
+        {
+            profiles.push(profile_name.clone());
+        }
+    }
+
+    let mut rest: Vec<String> = node
+        .node_settings
+        .profiles
+        .keys()
+        .filter(|x| !profiles.contains(x))
+        .cloned()
+        .collect();
+    rest.sort_unstable();
+    profiles.extend(rest);
+
+    profiles
+}
+
+#[test]
+fn test_ordered_profile_names() {
+    let node: deploy::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1.example.com",
+            "profilesOrder": ["system", "app", "system"],
+            "profiles": {
+                "monitoring": { "path": "/nix/store/blah-monitoring" },
+                "app": { "path": "/nix/store/blah-app" },
+                "home": { "path": "/nix/store/blah-home" },
+                "system": { "path": "/nix/store/blah-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        ordered_profile_names(&node),
+        vec!["system", "app", "home", "monitoring"]
+    );
+}
+
+async fn run_bundle_create(
+    supports_flakes: bool,
+    create_opts: &BundleCreateOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunBundleError> {
+    let deploy_flake = deploy::DeployFlake {
+        node: Some(create_opts.node.clone()),
+        profile: create_opts.profile.clone(),
+        ..deploy::parse_flake(&create_opts.flake)?
+    };
+
+    let deploy_json = evaluate_deployments(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let mut data: deploy::data::Data =
+        serde_json::from_value(deploy_json.clone()).map_err(GetDeploymentDataError::DecodeJson)?;
+    deploy::terraform::resolve(&deploy::transport::SystemTransport, &mut data)
+        .await
+        .map_err(GetDeploymentDataError::Terraform)?;
+
+    let node_name = &create_opts.node;
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunBundleError::NodeNotFound(node_name.clone())),
+    };
+
+    let profile_names = match create_opts.profile {
+        Some(ref profile_name) if !node.node_settings.profiles.contains_key(profile_name) => {
+            return Err(RunBundleError::ProfileNotFound(
+                node_name.clone(),
+                profile_name.clone(),
+            ))
+        }
+        Some(ref profile_name) => vec![profile_name.clone()],
+        None => ordered_profile_names(node),
+    };
+
+    let mut closures = Vec::new();
+
+    for profile_name in &profile_names {
+        let profile = &node.node_settings.profiles[profile_name];
+
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        );
+        let deploy_defs = deploy_data.defs()?;
+
+        deploy::push::build_profile(&deploy::push::PushProfileData {
+            supports_flakes,
+            repo: deploy_flake.repo,
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args,
+        })
+        .await?;
+
+        closures.push(profile.profile_settings.path.as_str());
+    }
+
+    let output = match create_opts.output {
+        Some(ref x) => x.clone(),
+        None => format!("{}.closure", node_name),
+    };
+
+    let rev = match supports_flakes {
+        true => {
+            deploy::bundle::flake_revision(
+                &deploy::transport::SystemTransport,
+                deploy_flake.repo,
+                cmd_overrides.offline,
+            )
+            .await
+        }
+        false => None,
+    };
+
+    let bundle = deploy::bundle::Bundle {
+        node: node_name.clone(),
+        profiles: profile_names.clone(),
+        deploy: deploy_json,
+        provenance: Some(deploy::bundle::Provenance {
+            rev,
+            creator: whoami::username(),
+            created: deploy::events::format_timestamp(std::time::SystemTime::now()),
+            signatures: Default::default(),
+        }),
+    };
+
+    let size = deploy::bundle::create_bundle(
+        &deploy::transport::SystemTransport,
+        bundle,
+        &closures,
+        Path::new(&output),
+    )
+    .await?;
+
+    info!(
+        "Wrote bundle of node `{}` with profiles {} to {} ({})",
+        node_name,
+        profile_names.join(", "),
+        output,
+        deploy::diff::format_size(size)
+    );
+
+    Ok(())
+}
+
+async fn run_bundle_apply(
+    apply_opts: &BundleApplyOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    production: bool,
+) -> Result<(), RunBundleError> {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-bundle-apply-{}", std::process::id()));
+
+    let result = apply_bundle(&dir, apply_opts, cmd_overrides, production).await;
+
+    let _ = std::fs::remove_dir_all(&dir);
+
+    result
+}
+
+async fn apply_bundle(
+    dir: &Path,
+    apply_opts: &BundleApplyOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    production: bool,
+) -> Result<(), RunBundleError> {
+    let bundle = deploy::bundle::unpack_bundle(
+        &deploy::transport::SystemTransport,
+        Path::new(&apply_opts.file),
+        dir,
+    )
+    .await?;
+
+    let data = bundle.data()?;
+
+    let node = match data.nodes.get(&bundle.node) {
+        Some(x) => x,
+        None => return Err(RunBundleError::NodeNotFound(bundle.node.clone())),
+    };
+
+    confirm_protected(std::iter::once((bundle.node.as_str(), node)), production)?;
+
+    let mut parts = Vec::new();
+
+    for profile_name in &bundle.profiles {
+        let profile = match node.node_settings.profiles.get(profile_name) {
+            Some(x) => x,
+            None => {
+                return Err(RunBundleError::ProfileNotFound(
+                    bundle.node.clone(),
+                    profile_name.clone(),
+                ))
+            }
+        };
+
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            &bundle.node,
+            profile,
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        );
+        let deploy_defs = deploy_data.defs()?;
+
+        parts.push((deploy_data, deploy_defs));
+    }
+
+    // Nothing is imported unless all of the bundle checks out
+    let mut verified = Vec::new();
+
+    for (deploy_data, _) in &parts {
+        verified.push(deploy::bundle::verify_profile(deploy_data, dir).await?);
+    }
+
+    let mut succeeded: Vec<&(deploy::DeployData, deploy::DeployDefs)> = Vec::new();
+
+    for (part, signatures_verified) in parts.iter().zip(verified) {
+        let (deploy_data, deploy_defs) = part;
+
+        let receipt = deploy::bundle::Receipt {
+            node: bundle.node.clone(),
+            profile: deploy_data.profile_name.to_string(),
+            closure: deploy_data.profile.profile_settings.path.clone(),
+            activated: String::new(),
+            signatures_verified,
+            provenance: bundle.provenance.clone(),
+            label: cmd_overrides.label.clone(),
+            adopted: false,
+            host_key: pinned_host_key(&bundle.node),
+            signer: None,
+            signature: None,
+        };
+
+        if apply_opts.local {
+            deploy::bundle::import_profile(deploy_data, dir, None).await?;
+            // activate-rs rolls back a profile which fails to activate, on the node there's nothing else to do
+            deploy::deploy::activate_locally(deploy_data, deploy_defs).await?;
+            record_receipt(
+                deploy_data,
+                deploy_defs,
+                receipt,
+                apply_opts.sign_key.as_deref(),
+                apply_opts.signer.as_deref(),
+                true,
+            )
+            .await;
+            continue;
+        }
+
+        let hostname = match cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &deploy_data.node.node_settings.hostname,
+        };
+        let node_store = deploy::push::node_store(
+            &deploy_data.merged_settings,
+            &format!("{}@{}", deploy_defs.ssh_user, hostname),
+        );
+
+        info!(
+            "Importing profile `{}` from the bundle to node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+
+        deploy::bundle::import_profile(deploy_data, dir, Some(&node_store)).await?;
+
+        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, false).await {
+            error!("{}", e);
+
+            if cmd_overrides.auto_rollback.unwrap_or(true) {
+                info!("Revoking previous deploys");
+
+                for (deploy_data, deploy_defs) in &succeeded {
+                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+                        deploy::deploy::revoke(deploy_data, deploy_defs).await?;
+                    }
+                }
+            }
+
+            return Err(e.into());
+        }
+
+        record_receipt(
+            deploy_data,
+            deploy_defs,
+            receipt,
+            apply_opts.sign_key.as_deref(),
+            apply_opts.signer.as_deref(),
+            false,
+        )
+        .await;
+
+        succeeded.push(part);
+    }
+
+    Ok(())
+}
+
+/// Leaves a receipt for a profile activated by a deployment, saying who deployed it from which revision
+async fn record_deploy_receipt(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &deploy::DeployDefs,
+    rev: Option<String>,
+) {
+    let receipt = deploy::bundle::Receipt {
+        node: deploy_data.node_name.to_string(),
+        profile: deploy_data.profile_name.to_string(),
+        closure: deploy_data.profile.profile_settings.path.clone(),
+        activated: String::new(),
+        signatures_verified: deploy_data.merged_settings.check_sigs.unwrap_or(false),
+        provenance: Some(deploy::bundle::Provenance {
+            rev,
+            creator: whoami::username(),
+            created: deploy::events::format_timestamp(std::time::SystemTime::now()),
+            signatures: Default::default(),
+        }),
+        label: deploy_data.cmd_overrides.label.clone(),
+        adopted: false,
+        host_key: pinned_host_key(deploy_data.node_name),
+        signer: None,
+        signature: None,
+    };
+
+    record_receipt(deploy_data, deploy_defs, receipt, None, None, false).await;
+}
+
+/// Signs a receipt with `key`, or `--sign-receipts` if that isn't given, as `signer` or `--receipt-signer`
+///
+/// A receipt that can't be signed is left unsigned, or unsigned altogether when there's no key.
+async fn sign_receipt(
+    deploy_data: &deploy::DeployData<'_>,
+    receipt: deploy::bundle::Receipt,
+    key: Option<&str>,
+    signer: Option<&str>,
+) -> deploy::bundle::Receipt {
+    let overrides = deploy_data.cmd_overrides;
+
+    let key = match key.or(overrides.receipt_key.as_deref()) {
+        Some(x) => x,
+        None => return receipt,
+    };
+    let signer = signer
+        .map(|x| x.to_string())
+        .or_else(|| overrides.receipt_signer.clone())
+        .unwrap_or_else(whoami::username);
+
+    match deploy::bundle::sign_receipt(deploy_data.transport, &receipt, key, &signer).await {
+        Ok(signed) => signed,
+        Err(e) => {
+            warn!(
+                "Failed to sign the receipt of profile `{}`: {}",
+                deploy_data.profile_name, e
+            );
+            receipt
+        }
+    }
+}
+
+/// Leaves a receipt for an activated profile, which isn't worth failing the deployment over
+async fn record_receipt(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &deploy::DeployDefs,
+    receipt: deploy::bundle::Receipt,
+    key: Option<&str>,
+    signer: Option<&str>,
+    local: bool,
+) {
+    let receipt = deploy::bundle::Receipt {
+        activated: deploy::events::format_timestamp(std::time::SystemTime::now()),
+        ..receipt
+    };
+    let receipt = sign_receipt(deploy_data, receipt, key, signer).await;
+
+    if let Err(e) = deploy::bundle::write_receipt(deploy_data, deploy_defs, &receipt, local).await {
+        warn!(
+            "Failed to write the receipt of profile `{}`: {}",
+            deploy_data.profile_name, e
+        );
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunVerifyError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("No profiles matched the selection")]
+    NoProfiles,
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Checks failed for {0} of {1} profiles")]
+    Failed(usize, usize),
+}
+
+impl RunVerifyError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunVerifyError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunVerifyError::Failed(failed, total) if failed < total => ExitCode::PartialFailure,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_verify(
+    supports_flakes: bool,
+    verify_opts: &VerifyOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunVerifyError> {
+    let deploy_flake = deploy::parse_flake(&verify_opts.target)?;
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let mut nodes: Vec<(&String, &deploy::data::Node)> = match deploy_flake.node {
+        Some(ref node_name) => match data.nodes.get_key_value(node_name) {
+            Some(x) => vec![x],
+            None => return Err(RunVerifyError::NodeNotFound(node_name.clone())),
+        },
+        None => data.nodes.iter().collect(),
+    };
+
+    if !verify_opts.tag.is_empty() {
+        nodes.retain(|(_, node)| {
+            node.node_settings
+                .tags
+                .iter()
+                .any(|t| verify_opts.tag.contains(t))
+        });
+    }
+
+    nodes.sort_by_key(|(node_name, _)| *node_name);
+
+    let mut parts = Vec::new();
+
+    for (node_name, node) in nodes {
+        for profile_name in ordered_profile_names(node) {
+            if deploy_flake.profile.is_none()
+                || deploy_flake.profile.as_ref() == Some(&profile_name)
+            {
+                parts.push((node_name, node, profile_name));
+            }
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(RunVerifyError::NoProfiles);
+    }
+
+    let profile_count = parts.len();
+
+    let results: Vec<(&String, String, Vec<deploy::deploy::CheckOutcome>)> =
+        futures_util::stream::iter(parts)
+            .map(|(node_name, node, profile_name)| {
+                let data = &data;
+
+                async move {
+                    let deploy_data = deploy::make_deploy_data(
+                        &data.generic_settings,
+                        node,
+                        node_name,
+                        &node.node_settings.profiles[&profile_name],
+                        &profile_name,
+                        cmd_overrides,
+                        false,
+                        None,
+                    );
+                    let deploy_defs = deploy_data.defs()?;
+
+                    info!(
+                        "Verifying profile `{}` for node `{}`",
+                        profile_name, node_name
+                    );
+
+                    let outcomes = deploy::deploy::verify_profile(&deploy_data, &deploy_defs).await;
+
+                    Ok::<_, RunVerifyError>((node_name, profile_name, outcomes))
+                }
+            })
+            .buffered(verify_opts.jobs.max(1))
+            .try_collect()
+            .await?;
+
+    let mut failed = 0;
+
+    for (node_name, profile_name, outcomes) in results {
+        println!("Node `{}`, profile `{}`:", node_name, profile_name);
+
+        if outcomes.is_empty() {
+            println!("  (no checks declared)");
+        }
+
+        for outcome in &outcomes {
+            match outcome.error {
+                None => println!("  ok      {}", outcome.check),
+                Some(ref error) => println!("  FAILED  {} ({})", outcome.check, error),
+            }
+        }
+
+        if outcomes.iter().any(|x| x.error.is_some()) {
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        return Err(RunVerifyError::Failed(failed, profile_count));
+    }
+
+    info!("All checks passed for {} profiles", profile_count);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunStateError {
+    #[error("{0}")]
+    State(#[from] deploy::state::StateError),
+}
+
+impl RunStateError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::Failure
+    }
+}
+
+fn run_state_show(show_opts: &StateShowOpts) -> Result<(), RunStateError> {
+    let dir = deploy::state::state_dir()?;
+
+    println!("State directory: {}", dir.display());
+
+    if show_opts.node.is_none() {
+        match deploy::runs::known_runs(&dir)?.last() {
+            Some(latest) => println!("Last recorded run: {}", latest),
+            None => println!("No runs recorded"),
+        }
+    }
+
+    let nodes = match show_opts.node {
+        Some(ref node_name) => vec![node_name.clone()],
+        None => deploy::state::known_nodes(&dir)?,
+    };
+
+    for node_name in nodes {
+        let node_state = deploy::state::load_node_state(&dir, &node_name)?;
+
+        println!("\nNode `{}`:", node_name);
+
+        match node_state.link {
+            Some(link) => println!(
+                "  connection: {} KiB/s, {} ms to connect, measured {}",
+                link.throughput_kib_s,
+                link.connect_ms,
+                format_seconds(link.measured_at)
+            ),
+            None => println!("  connection: not measured"),
+        }
+
+        if let Some(copy_strategy) = node_state.copy_strategy {
+            println!("  copied: {}", copy_strategy.name());
+        }
+
+        match node_state.facts {
+            Some(ref facts) => {
+                println!("  facts:");
+                print_facts(facts);
+            }
+            None => println!("  facts: not gathered"),
+        }
+
+        let gc_roots = deploy::state::gc_roots_dir(&dir).join(&node_name);
+        if gc_roots.exists() {
+            println!("  kept build results: {}", gc_roots.display());
+        }
+
+        if node_state.history.is_empty() {
+            println!("  no deployments recorded");
+        }
+
+        for deployment in &node_state.history {
+            println!(
+                "  {} {} {}{}{}",
+                format_seconds(deployment.deployed_at),
+                deployment.profile,
+                deployment.closure,
+                match deployment.label {
+                    Some(ref label) => format!(" [{}]", label),
+                    None => String::new(),
+                },
+                match (deployment.deferred, deployment.adopted) {
+                    (true, _) => " (deferred)",
+                    (false, true) => " (adopted)",
+                    (false, false) => "",
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunFactsError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Node `{0}` has no profiles")]
+    NoProfiles(String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("{0}")]
+    Facts(#[from] deploy::facts::FactsError),
+}
+
+impl RunFactsError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunFactsError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_facts(
+    supports_flakes: bool,
+    facts_opts: &FactsOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunFactsError> {
+    let node_name = facts_opts.node.as_str();
+
+    let deploy_flake = DeployFlake {
+        node: Some(node_name.to_string()),
+        ..deploy::parse_flake(&facts_opts.flake)?
+    };
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunFactsError::NodeNotFound(node_name.to_string())),
+    };
+
+    let facts = match deploy::facts::cached_facts(node_name) {
+        Some(facts) if !facts_opts.refresh => facts,
+        _ => {
+            // Nodes are connected to the same way for all of their profiles
+            let profile_name = match ordered_profile_names(node).into_iter().next() {
+                Some(x) => x,
+                None => return Err(RunFactsError::NoProfiles(node_name.to_string())),
+            };
+
+            let deploy_data = deploy::make_deploy_data(
+                &data.generic_settings,
+                node,
+                node_name,
+                &node.node_settings.profiles[&profile_name],
+                &profile_name,
+                cmd_overrides,
+                false,
+                None,
+            );
+            let deploy_defs = deploy_data.defs()?;
+
+            info!("Gathering facts of node `{}`", node_name);
+
+            deploy::facts::gather_facts(
+                deploy_data.transport,
+                &deploy_data.node_target(&deploy_defs)?,
+                deploy::deploy::NixEnv::new(&deploy_data.merged_settings),
+                node_name,
+            )
+            .await?
+        }
+    };
+
+    println!("Node `{}`:", node_name);
+    print_facts(&facts);
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunBenchCopyError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Node `{0}` has no profiles")]
+    NoProfiles(String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("Node `{0}` is reached through its agent, copying to it can't be measured")]
+    Agent(String),
+    #[error("Failed to measure copying to the node: {0}")]
+    Probe(#[from] deploy::probe::ProbeError),
+    #[error("Failed to record the quickest way of copying: {0}")]
+    State(#[from] deploy::state::StateError),
+}
+
+impl RunBenchCopyError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunBenchCopyError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_bench_copy(
+    supports_flakes: bool,
+    bench_opts: &BenchCopyOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunBenchCopyError> {
+    let node_name = bench_opts.node.as_str();
+
+    let deploy_flake = DeployFlake {
+        node: Some(node_name.to_string()),
+        ..deploy::parse_flake(&bench_opts.flake)?
+    };
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunBenchCopyError::NodeNotFound(node_name.to_string())),
+    };
+
+    // Nodes are copied to the same way for all of their profiles
+    let profile_name = match ordered_profile_names(node).into_iter().next() {
+        Some(x) => x,
+        None => return Err(RunBenchCopyError::NoProfiles(node_name.to_string())),
+    };
+
+    let deploy_data = deploy::make_deploy_data(
+        &data.generic_settings,
+        node,
+        node_name,
+        &node.node_settings.profiles[&profile_name],
+        &profile_name,
+        cmd_overrides,
+        false,
+        None,
+    );
+    let deploy_defs = deploy_data.defs()?;
+
+    let ssh_addr = match deploy_data.node_target(&deploy_defs)? {
+        deploy::transport::NodeTarget::Ssh { address, .. } => address,
+        deploy::transport::NodeTarget::Agent(_) => {
+            return Err(RunBenchCopyError::Agent(node_name.to_string()))
+        }
+    };
+
+    info!(
+        "Measuring how quickly a sample closure is copied to node `{}`",
+        node_name
+    );
+
+    let measured = deploy::probe::bench_copy(
+        deploy_data.transport,
+        &deploy::push::node_store(&deploy_data.merged_settings, &ssh_addr),
+        &deploy::push::nix_sshopts(&deploy_data.merged_settings.ssh_opts, cmd_overrides),
+        deploy_data.merged_settings.check_sigs.unwrap_or(false),
+    )
+    .await?;
+
+    for (strategy, took) in &measured {
+        println!("  {:<26} {:.1}s", strategy.name(), took.as_secs_f64());
+    }
+
+    if let Some(fastest) = deploy::probe::fastest(&measured) {
+        let dir = deploy::state::state_dir()?;
+        let mut node_state = deploy::state::load_node_state(&dir, node_name)?;
+        node_state.copy_strategy = Some(fastest);
+        deploy::state::save_node_state(&dir, node_name, &node_state)?;
+
+        println!(
+            "Copying to node `{}` {} from now on, unless `substituteOnDestination` or `fastConnection` is set",
+            node_name,
+            fastest.name()
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunAdoptError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("{0}")]
+    Adopt(#[from] deploy::adopt::AdoptError),
+    #[error("Failed to record the adopted profile: {0}")]
+    State(#[from] deploy::state::StateError),
+    #[error("Failed to write the receipt of the adopted profile: {0}")]
+    Receipt(#[from] deploy::bundle::BundleError),
+    #[error("Node `{0}` has none of its profiles yet, there's nothing to adopt")]
+    NothingToAdopt(String),
+    #[error("{0}")]
+    Protected(#[from] ProtectedNodeError),
+}
+
+impl RunAdoptError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunAdoptError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_adopt(
+    supports_flakes: bool,
+    adopt_opts: &AdoptOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    production: bool,
+) -> Result<(), RunAdoptError> {
+    let node_name = adopt_opts.node.as_str();
+
+    let deploy_flake = DeployFlake {
+        node: Some(node_name.to_string()),
+        ..deploy::parse_flake(&adopt_opts.flake)?
+    };
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunAdoptError::NodeNotFound(node_name.to_string())),
+    };
+
+    confirm_protected(std::iter::once((node_name, node)), production)?;
+
+    let state_dir = deploy::state::state_dir()?;
+    let mut adopted = 0;
+
+    println!("Node `{}`:", node_name);
+
+    for profile_name in ordered_profile_names(node) {
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            node,
+            node_name,
+            &node.node_settings.profiles[&profile_name],
+            &profile_name,
+            cmd_overrides,
+            false,
+            None,
+        );
+        let deploy_defs = deploy_data.defs()?;
+
+        let existing = deploy::adopt::inspect_profile(
+            deploy_data.transport,
+            &deploy_data.node_target(&deploy_defs)?,
+            &deploy_defs.profile_path,
+        )
+        .await?;
+
+        let existing = match existing {
+            Some(x) => x,
+            None => {
+                println!("  {}: not on the node", profile_name);
+                continue;
+            }
+        };
+
+        let now = std::time::SystemTime::now();
+
+        deploy::state::record_deployment(
+            &state_dir,
+            node_name,
+            deploy::state::Deployment {
+                profile: profile_name.clone(),
+                closure: existing.closure.clone(),
+                deployed_at: now
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                deferred: false,
+                label: cmd_overrides.label.clone(),
+                adopted: true,
+            },
+        )?;
+
+        let receipt = deploy::bundle::Receipt {
+            node: node_name.to_string(),
+            profile: profile_name.clone(),
+            closure: existing.closure.clone(),
+            activated: deploy::events::format_timestamp(now),
+            signatures_verified: false,
+            provenance: None,
+            label: cmd_overrides.label.clone(),
+            adopted: true,
+            host_key: pinned_host_key(node_name),
+            signer: None,
+            signature: None,
+        };
+        let receipt = sign_receipt(&deploy_data, receipt, None, None).await;
+
+        deploy::bundle::write_receipt(&deploy_data, &deploy_defs, &receipt, false).await?;
+
+        println!("  {}: {}, adopted", profile_name, existing.closure);
+
+        if existing.activation == deploy::adopt::Activation::None {
+            warn!(
+                "Profile `{}` of node `{}` has neither the activation of deploy-rs nor `bin/switch-to-configuration`, \
+                 rolling back to it only points the profile back at it",
+                profile_name, node_name
+            );
+        }
+
+        adopted += 1;
+    }
+
+    if adopted == 0 {
+        return Err(RunAdoptError::NothingToAdopt(node_name.to_string()));
+    }
+
+    Ok(())
+}
+
+fn print_facts(facts: &deploy::facts::NodeFacts) {
+    println!(
+        "  nix: {}",
+        facts.nix_version.as_deref().unwrap_or("not found")
+    );
+    println!("  os: {}", facts.os.as_deref().unwrap_or("unknown"));
+    println!(
+        "  init system: {}",
+        match facts.init_system {
+            deploy::facts::InitSystem::Systemd => "systemd",
+            deploy::facts::InitSystem::Other => "other",
+        }
+    );
+    println!(
+        "  escalation: {}",
+        facts.escalation.as_deref().unwrap_or("none found")
+    );
+    match facts.store_free_kib {
+        Some(free) => println!("  free store space: {} MiB", free / 1024),
+        None => println!("  free store space: unknown"),
+    }
+    println!(
+        "  trusted by the Nix daemon: {}",
+        match facts.trusted {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
+        }
+    );
+    println!("  gathered: {}", format_seconds(facts.gathered_at));
+}
+
+#[derive(Error, Debug)]
+pub enum RunCachePushError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("No profiles matched the selection")]
+    NoProfiles,
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("{0}")]
+    PushProfile(#[from] deploy::push::PushProfileError),
+}
+
+impl RunCachePushError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunCachePushError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunCachePushError::PushProfile(e) => e.exit_code(),
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_cache_push(
+    supports_flakes: bool,
+    cache_push_opts: &CachePushOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunCachePushError> {
+    let deploy_flake = deploy::parse_flake(&cache_push_opts.target)?;
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let mut nodes: Vec<(&String, &deploy::data::Node)> = match deploy_flake.node {
+        Some(ref node_name) => match data.nodes.get_key_value(node_name) {
+            Some(x) => vec![x],
+            None => return Err(RunCachePushError::NodeNotFound(node_name.clone())),
+        },
+        None => data.nodes.iter().collect(),
+    };
+
+    if !cache_push_opts.tag.is_empty() {
+        nodes.retain(|(_, node)| {
+            node.node_settings
+                .tags
+                .iter()
+                .any(|t| cache_push_opts.tag.contains(t))
+        });
+    }
+
+    nodes.sort_by_key(|(node_name, _)| *node_name);
+
+    let mut closures: Vec<&str> = Vec::new();
+
+    for (node_name, node) in nodes {
+        for profile_name in ordered_profile_names(node) {
+            if deploy_flake.profile.is_some()
+                && deploy_flake.profile.as_ref() != Some(&profile_name)
+            {
+                continue;
+            }
+
+            let profile = &node.node_settings.profiles[&profile_name];
+
+            let deploy_data = deploy::make_deploy_data(
+                &data.generic_settings,
+                node,
+                node_name,
+                profile,
+                &profile_name,
+                cmd_overrides,
+                false,
+                None,
+            );
+            let deploy_defs = deploy_data.defs()?;
+
+            deploy::push::build_profile(&deploy::push::PushProfileData {
+                supports_flakes,
+                repo: deploy_flake.repo,
+                deploy_data: &deploy_data,
+                deploy_defs: &deploy_defs,
+                keep_result: false,
+                result_path: None,
+                extra_build_args,
+            })
+            .await?;
+
+            // Nodes sharing a profile build it once and push it once
+            if !closures.contains(&profile.profile_settings.path.as_str()) {
+                closures.push(&profile.profile_settings.path);
+            }
+        }
+    }
+
+    if closures.is_empty() {
+        return Err(RunCachePushError::NoProfiles);
+    }
+
+    info!(
+        target: "nix_copy",
+        "Copying {} profiles to `{}`",
+        closures.len(),
+        cache_push_opts.to
+    );
+
+    deploy::push::copy_to_cache(
+        &deploy::transport::SystemTransport,
+        &cache_push_opts.to,
+        &closures,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunDiffRunsError {
+    #[error("{0}")]
+    State(#[from] deploy::state::StateError),
+    #[error("There are not enough recorded runs to compare, only {0}")]
+    NotEnoughRuns(usize),
+}
+
+impl RunDiffRunsError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::Failure
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunGenBootstrapError {
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("No node named `{0}` was found")]
+    NodeNotFound(String),
+    #[error("Node `{0}` has no profiles")]
+    NoProfiles(String),
+    #[error("Failed to deduce deployment settings: {0}")]
+    DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("{0}")]
+    Bootstrap(#[from] deploy::bootstrap::BootstrapError),
+    #[error("Failed to write the config: {0}")]
+    Write(std::io::Error),
+}
+
+impl RunGenBootstrapError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunGenBootstrapError::GetDeploymentData(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_gen_bootstrap(
+    supports_flakes: bool,
+    bootstrap_opts: &GenBootstrapOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+) -> Result<(), RunGenBootstrapError> {
+    let node_name = bootstrap_opts.node.as_str();
+
+    let deploy_flake = DeployFlake {
+        node: Some(node_name.to_string()),
+        ..deploy::parse_flake(&bootstrap_opts.flake)?
+    };
+
+    let data = get_deployment_data(
+        supports_flakes,
+        std::slice::from_ref(&deploy_flake),
+        cmd_overrides.deploy_attr.as_deref(),
+        extra_build_args,
+    )
+    .await?
+    .pop()
+    .expect("Evaluated one flake, but didn't get its data");
+
+    let node = match data.nodes.get(node_name) {
+        Some(x) => x,
+        None => return Err(RunGenBootstrapError::NodeNotFound(node_name.to_string())),
+    };
+
+    // Nodes are logged in to as the same user for all of their profiles
+    let profile_name = match ordered_profile_names(node).into_iter().next() {
+        Some(x) => x,
+        None => return Err(RunGenBootstrapError::NoProfiles(node_name.to_string())),
+    };
+
+    let deploy_data = deploy::make_deploy_data(
+        &data.generic_settings,
+        node,
+        node_name,
+        &node.node_settings.profiles[&profile_name],
+        &profile_name,
+        cmd_overrides,
+        false,
+        None,
+    );
+    let deploy_defs = deploy_data.defs()?;
+
+    let keys = deploy::bootstrap::public_keys(&bootstrap_opts.key)?;
+    let config = deploy::bootstrap::render(bootstrap_opts.format, &deploy_defs.ssh_user, &keys);
+
+    match bootstrap_opts.output {
+        Some(ref output) => {
+            std::fs::write(output, config).map_err(RunGenBootstrapError::Write)?;
+            info!(
+                "Wrote the config for node `{}`, logged in to as `{}`, to {}",
+                node_name, deploy_defs.ssh_user, output
+            );
+        }
+        None => print!("{}", config),
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunImportError {
+    #[error("Failed to find the network file: {0}")]
+    File(std::io::Error),
+    #[error("Failed to import the nodes: {0}")]
+    Import(#[from] deploy::import::ImportError),
+    #[error("Found no nodes in the network")]
+    NoNodes,
+    #[error("Failed to write the `deploy` output: {0}")]
+    Write(std::io::Error),
+}
+
+impl RunImportError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunImportError::Import(_) => ExitCode::Evaluation,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+async fn run_import(import_opts: &ImportOpts) -> Result<(), RunImportError> {
+    // `import` in Nix takes strings only if they're absolute paths
+    let file = std::fs::canonicalize(&import_opts.file)
+        .map_err(RunImportError::File)?
+        .to_string_lossy()
+        .to_string();
+
+    info!(
+        "Importing the nodes of the {} network in {}",
+        import_opts.from.name(),
+        file
+    );
+
+    let nodes =
+        deploy::import::import_nodes(&deploy::transport::SystemTransport, import_opts.from, &file)
+            .await?;
+
+    if nodes.is_empty() {
+        return Err(RunImportError::NoNodes);
+    }
+
+    let rendered = deploy::import::render(import_opts.from, &file, &import_opts.system, &nodes);
+
+    match import_opts.output {
+        Some(ref output) => {
+            std::fs::write(output, rendered).map_err(RunImportError::Write)?;
+            info!("Wrote {} nodes to {}", nodes.len(), output);
+        }
+        None => print!("{}", rendered),
+    }
+
+    Ok(())
+}
+
+fn run_diff_runs(diff_opts: &DiffRunsOpts) -> Result<(), RunDiffRunsError> {
+    let dir = deploy::state::state_dir()?;
+
+    let known = deploy::runs::known_runs(&dir)?;
+    let latest = |back: usize| match known.len().checked_sub(back + 1) {
+        Some(i) => Ok(known[i].clone()),
+        None => Err(RunDiffRunsError::NotEnoughRuns(known.len())),
+    };
+
+    let (run_a, run_b) = match (&diff_opts.run_a, &diff_opts.run_b) {
+        (Some(a), Some(b)) => (a.clone(), b.clone()),
+        (Some(a), None) => (a.clone(), latest(0)?),
+        (None, _) => (latest(1)?, latest(0)?),
+    };
+
+    let a = deploy::runs::load_run(&dir, &run_a)?;
+    let b = deploy::runs::load_run(&dir, &run_b)?;
+
+    println!(
+        "Comparing run {} ({}) with run {} ({})",
+        run_a,
+        format_seconds(a.started_at),
+        run_b,
+        format_seconds(b.started_at)
+    );
+
+    let changes = deploy::runs::diff_runs(&a, &b);
+
+    if changes.is_empty() {
+        println!("No differences");
+    }
+
+    for (node, profile, change) in changes {
+        println!("  {}.{}: {}", node, profile, change);
+    }
+
+    Ok(())
+}
+
+/// Marks the run `event_log` records as in progress, and returns its id for `deploy pause`
+fn start_run(event_log: &EventLog) -> Option<String> {
+    let started_at = event_log.run_manifest()?.started_at;
+
+    match deploy::state::state_dir().and_then(|dir| deploy::runs::start_run(&dir, started_at)) {
+        Ok(id) => {
+            info!(
+                "Recording this run as `{}`, `deploy pause {}` stops it after the profiles it's activating",
+                id, id
+            );
+
+            Some(id)
+        }
+        Err(e) => {
+            warn!("Could not record this run: {}", e);
+
+            None
+        }
+    }
+}
+
+/// Records what a deployment run did, so `deploy diff-runs` can compare it with others
+fn save_run(event_log: &EventLog, run_id: Option<&str>) {
+    let manifest = event_log.run_manifest().filter(|x| !x.profiles.is_empty());
+
+    let dir = match deploy::state::state_dir() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Could not record this run: {}", e);
+            return;
+        }
+    };
+
+    let saved = match (run_id, manifest) {
+        (Some(id), manifest) => {
+            deploy::runs::finish_run(&dir, id, manifest.as_ref()).map(|_| id.to_string())
+        }
+        (None, Some(manifest)) => deploy::runs::save_run(&dir, &manifest),
+        (None, None) => return,
+    };
+
+    match saved {
+        Ok(id) => debug!("Recorded this run as `{}`", id),
+        Err(e) => warn!("Could not record this run: {}", e),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunPauseError {
+    #[error("{0}")]
+    State(#[from] deploy::state::StateError),
+    #[error("Run `{0}` isn't in progress")]
+    NotRunning(String),
+    #[error("Run `{0}` wasn't paused, or was resumed already")]
+    NotPaused(String),
+}
+
+impl RunPauseError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::Failure
+    }
+}
+
+fn run_pause(pause_opts: &PauseOpts) -> Result<(), RunPauseError> {
+    let dir = deploy::state::state_dir()?;
+
+    if !deploy::runs::pause_run(&dir, &pause_opts.run)? {
+        return Err(RunPauseError::NotRunning(pause_opts.run.clone()));
+    }
+
+    println!(
+        "Run `{}` stops once the profiles it's activating are done, `deploy resume {}` continues it",
+        pause_opts.run, pause_opts.run
+    );
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RunVerifyReceiptError {
+    #[error("Failed to read the receipt: {0}")]
+    Read(std::io::Error),
+    #[error("{0}")]
+    Signature(#[from] deploy::bundle::ReceiptSignatureError),
+}
+
+impl RunVerifyReceiptError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::Failure
+    }
+}
+
+async fn run_verify_receipt(verify_opts: &VerifyReceiptOpts) -> Result<(), RunVerifyReceiptError> {
+    let receipt_json =
+        std::fs::read_to_string(&verify_opts.receipt).map_err(RunVerifyReceiptError::Read)?;
+
+    let receipt = deploy::bundle::verify_receipt(
+        &deploy::transport::SystemTransport,
+        &receipt_json,
+        &verify_opts.allowed_signers,
+    )
+    .await?;
+
+    println!(
+        "Good signature by `{}`",
+        receipt.signer.as_deref().unwrap_or_default()
+    );
+    println!("  node: {}", receipt.node);
+    println!("  profile: {}", receipt.profile);
+    println!("  closure: {}", receipt.closure);
+    println!("  activated: {}", receipt.activated);
+
+    if let Some(ref label) = receipt.label {
+        println!("  label: {}", label);
+    }
+
+    if receipt.adopted {
+        println!("  adopted, made by another tool");
+    }
+
+    if let Some(ref provenance) = receipt.provenance {
+        println!(
+            "  revision: {}",
+            provenance
+                .rev
+                .as_deref()
+                .unwrap_or("unknown, the flake was dirty")
+        );
+        println!(
+            "  bundle: created by {} at {}",
+            provenance.creator, provenance.created
+        );
+    }
+
+    Ok(())
+}
+
+fn format_seconds(secs: u64) -> String {
+    deploy::events::format_timestamp(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+}
+
+fn run_state_clean(clean_opts: &StateCleanOpts) -> Result<(), RunStateError> {
+    let dir = deploy::state::state_dir()?;
+
+    deploy::state::clean(&dir, clean_opts.node.as_deref())?;
+
+    match clean_opts.node {
+        Some(ref node_name) => info!("Forgot everything about node `{}`", node_name),
+        None => info!("Removed {}", dir.display()),
+    }
+
+    Ok(())
+}
+
+/// Remembers the closure of an activated profile as confirmed in its channel, for `deploy promote`
+fn confirm_in_channel(deploy_data: &deploy::DeployData<'_>) {
+    let channel = match deploy_data.merged_settings.channel {
+        Some(ref x) => x,
+        None => return,
+    };
+
+    let confirmed = deploy::channel::ConfirmedProfile {
+        node: deploy_data.node_name.to_string(),
+        profile: deploy_data.profile_name.to_string(),
+        closure: deploy_data.profile.profile_settings.path.clone(),
+        confirmed_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+
+    if let Err(e) = deploy::state::state_dir()
+        .and_then(|dir| deploy::channel::confirm(&dir, channel, confirmed))
+    {
+        warn!(
+            "Could not record profile `{}` of node `{}` as confirmed in channel `{}`: {}",
+            deploy_data.profile_name, deploy_data.node_name, channel, e
+        );
+    }
+}
+
+/// The fingerprint of the host key pinned for `node_name` in deploy's state, for receipts
+fn pinned_host_key(node_name: &str) -> Option<String> {
+    deploy::state::state_dir()
+        .and_then(|dir| deploy::state::load_node_state(&dir, node_name))
+        .ok()
+        .and_then(|state| state.host_key)
+}
+
+/// Remembers a deployment in the node's history, which is only ever shown to the user
+fn record_deployment(deploy_data: &deploy::DeployData<'_>, deferred: bool) {
+    let deployment = deploy::state::Deployment {
+        profile: deploy_data.profile_name.to_string(),
+        closure: deploy_data.profile.profile_settings.path.clone(),
+        deployed_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        deferred,
+        label: deploy_data.cmd_overrides.label.clone(),
+        adopted: false,
+    };
+
+    if let Err(e) = deploy::state::state_dir()
+        .and_then(|dir| deploy::state::record_deployment(&dir, deploy_data.node_name, deployment))
+    {
+        warn!(
+            "Could not record the deployment of profile `{}` to node `{}`: {}",
+            deploy_data.profile_name, deploy_data.node_name, e
+        );
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("Failed to deploy profile: {0}")]
+    DeployProfile(#[from] deploy::deploy::DeployProfileError),
+    #[error("Failed to push profile: {0}")]
+    PushProfile(#[from] deploy::push::PushProfileError),
+    #[error("Failed to test for flake support: {0}")]
     FlakeTest(std::io::Error),
     #[error("Failed to check deployment: {0}")]
     CheckDeployment(#[from] CheckDeploymentError),
@@ -601,8 +5059,283 @@ pub enum RunError {
     ParseFlake(#[from] deploy::ParseFlakeError),
     #[error("Error initiating logger: {0}")]
     Logger(#[from] flexi_logger::FlexiLoggerError),
+    #[error("Failed to open log file: {0}")]
+    LogFile(std::io::Error),
+    #[error("Failed to create the directory for SSH control sockets: {0}")]
+    SSHControlDir(std::io::Error),
     #[error("{0}")]
     RunDeploy(#[from] RunDeployError),
+    #[error("{0}")]
+    RunExec(#[from] RunExecError),
+    #[error("{0}")]
+    RunCopyFile(#[from] RunCopyFileError),
+    #[error("{0}")]
+    RunDiffRemote(#[from] RunDiffRemoteError),
+    #[error("{0}")]
+    RunGraph(#[from] RunGraphError),
+    #[error("{0}")]
+    RunBundle(#[from] RunBundleError),
+    #[error("{0}")]
+    RunVerify(#[from] RunVerifyError),
+    #[error("{0}")]
+    RunState(#[from] RunStateError),
+    #[error("{0}")]
+    RunFacts(#[from] RunFactsError),
+    #[error("{0}")]
+    RunBenchCopy(#[from] RunBenchCopyError),
+    #[error("{0}")]
+    RunAdopt(#[from] RunAdoptError),
+    #[error("{0}")]
+    RunImport(#[from] RunImportError),
+    #[error("{0}")]
+    RunGenBootstrap(#[from] RunGenBootstrapError),
+    #[error("{0}")]
+    RunCachePush(#[from] RunCachePushError),
+    #[error("{0}")]
+    RunDiffRuns(#[from] RunDiffRunsError),
+    #[error("{0}")]
+    RunVerifyReceipt(#[from] RunVerifyReceiptError),
+    #[error("{0}")]
+    RunPause(#[from] RunPauseError),
+    #[error("{0}")]
+    RunRollbackAll(#[from] RunRollbackAllError),
+    #[error("Failed to promote: {0}")]
+    Promote(#[from] deploy::channel::PromoteError),
+    #[error("No node of `{0}` has a weight, so there are no canaries to deploy to")]
+    NoCanaries(String),
+    #[error("{0}")]
+    Selector(#[from] deploy::selector::SelectorError),
+    #[error("Failed to read the nodes to deploy: {0}")]
+    NodesFromRead(std::io::Error),
+    #[error("Failed to parse the nodes to deploy: {0}")]
+    NodesFromParse(serde_json::Error),
+    #[error("No nodes named {0} were found")]
+    NodesFromNotFound(String),
+    #[error("Failing with --deny-warnings, after {0}")]
+    DeniedWarnings(String),
+    #[error("{0}")]
+    Capability(#[from] deploy::capability::CapabilityError),
+}
+
+impl RunError {
+    /// The exit code `deploy` should exit with for this error, see `ExitCode`
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            RunError::DeployProfile(_) => ExitCode::Activation,
+            RunError::PushProfile(e) => e.exit_code(),
+            RunError::CheckDeployment(_) | RunError::GetDeploymentData(_) => ExitCode::Evaluation,
+            RunError::RunDeploy(e) => e.exit_code(),
+            RunError::RunExec(e) => e.exit_code(),
+            RunError::RunCopyFile(e) => e.exit_code(),
+            RunError::RunDiffRemote(e) => e.exit_code(),
+            RunError::RunGraph(e) => e.exit_code(),
+            RunError::RunBundle(e) => e.exit_code(),
+            RunError::RunVerify(e) => e.exit_code(),
+            RunError::RunState(e) => e.exit_code(),
+            RunError::RunFacts(e) => e.exit_code(),
+            RunError::RunBenchCopy(e) => e.exit_code(),
+            RunError::RunAdopt(e) => e.exit_code(),
+            RunError::RunImport(e) => e.exit_code(),
+            RunError::RunGenBootstrap(e) => e.exit_code(),
+            RunError::RunCachePush(e) => e.exit_code(),
+            RunError::RunDiffRuns(e) => e.exit_code(),
+            RunError::RunVerifyReceipt(e) => e.exit_code(),
+            RunError::RunPause(e) => e.exit_code(),
+            RunError::RunRollbackAll(e) => e.exit_code(),
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+/// SSH options given on the command line, `--ssh-opts` split at spaces followed by each `--ssh-opt` as it is
+fn ssh_opts_override(ssh_opts: Option<&str>, ssh_opt: &[String]) -> Option<Vec<String>> {
+    if ssh_opts.is_none() && ssh_opt.is_empty() {
+        return None;
+    }
+
+    Some(
+        ssh_opts
+            .into_iter()
+            .flat_map(|x| x.split(' '))
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .chain(ssh_opt.iter().cloned())
+            .collect(),
+    )
+}
+
+#[test]
+fn test_ssh_opts_override() {
+    assert_eq!(ssh_opts_override(None, &[]), None);
+    assert_eq!(
+        ssh_opts_override(Some("-p 2121"), &[]),
+        Some(vec!["-p".to_string(), "2121".to_string()])
+    );
+    assert_eq!(
+        ssh_opts_override(
+            Some("-C"),
+            &[
+                "-o".to_string(),
+                "ProxyCommand=ssh -W %h:%p bastion".to_string()
+            ]
+        ),
+        Some(vec![
+            "-C".to_string(),
+            "-o".to_string(),
+            "ProxyCommand=ssh -W %h:%p bastion".to_string()
+        ])
+    );
+}
+
+#[test]
+fn test_exit_codes() {
+    assert_eq!(ExitCode::Evaluation as i32, 2);
+    assert_eq!(ExitCode::RollbackFailed as i32, 8);
+    assert_eq!(ExitCode::Paused as i32, 9);
+
+    assert_eq!(
+        RunError::PushProfile(deploy::push::PushProfileError::BuildExit(
+            deploy::report::CommandFailure::new("nix build", &Default::default())
+        ))
+        .exit_code(),
+        ExitCode::Build
+    );
+    assert_eq!(
+        RunError::RunDeploy(RunDeployError::PushProfile(Reported {
+            error: deploy::push::PushProfileError::CopyExit(deploy::report::CommandFailure::new(
+                "nix copy",
+                &Default::default()
+            )),
+            node: "web-1".to_string(),
+            profile: "system".to_string(),
+        }))
+        .exit_code(),
+        ExitCode::Copy
+    );
+    assert_eq!(
+        RunError::RunExec(RunExecError::Failed(1, 3)).exit_code(),
+        ExitCode::PartialFailure
+    );
+    assert_eq!(
+        RunError::RunExec(RunExecError::Failed(3, 3)).exit_code(),
+        ExitCode::Failure
+    );
+}
+
+/// What the command given changes on nodes that read-only mode doesn't allow, if anything. Deploying itself is
+/// allowed, as read-only mode stops it before activating
+/// The directory SSH keeps the sockets of its shared connections in for a run, removed again when the run ends
+struct ControlDir(std::path::PathBuf);
+
+impl ControlDir {
+    /// Makes a new directory only this user can get at, as whoever controls it can use the connections. A directory
+    /// that's already there is never used, as another user of the machine could have put it there
+    fn create() -> std::io::Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+
+        for attempt in 0..16 {
+            let dir = std::env::temp_dir().join(format!(
+                "deploy-rs-ssh-{}-{}-{}",
+                std::process::id(),
+                nanos,
+                attempt
+            ));
+
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => return Ok(ControlDir(dir)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "no unused directory for the SSH control sockets",
+        ))
+    }
+}
+
+impl Drop for ControlDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            debug!("Could not remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_control_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let control_dir = ControlDir::create().unwrap();
+    let dir = control_dir.0.clone();
+
+    assert_eq!(
+        std::fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+        0o700
+    );
+    assert_ne!(ControlDir::create().unwrap().0, dir);
+
+    drop(control_dir);
+    assert!(!dir.exists());
+}
+
+fn required_action(opts: &Opts) -> Option<deploy::capability::Action> {
+    use deploy::capability::Action;
+
+    match opts.subcmd {
+        Some(SubCommand::Exec(_)) => Some(Action::Exec),
+        Some(SubCommand::CopyFile(_)) => Some(Action::CopyFile),
+        Some(SubCommand::Adopt(_)) => Some(Action::Adopt),
+        Some(SubCommand::Bundle(BundleOpts {
+            action: BundleAction::Apply(_),
+        }))
+        | Some(SubCommand::Promote(_))
+        | Some(SubCommand::Resume(_)) => Some(Action::Activate),
+        None if opts.rollback_all.is_some() => Some(Action::RollBack),
+        // Nodes activate the descriptors written for them by themselves
+        None if opts.pull_descriptors.is_some() => Some(Action::Activate),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_required_action() {
+    use deploy::capability::Action;
+
+    let action = |args: &[&str]| required_action(&Opts::parse_from(args));
+
+    assert_eq!(action(&["deploy", ".#web-1"]), None);
+    assert_eq!(action(&["deploy", "--dry-activate", "."]), None);
+    assert_eq!(
+        action(&["deploy", "--rollback-all", "1700000000-123"]),
+        Some(Action::RollBack)
+    );
+    assert_eq!(
+        action(&["deploy", "exec", ".", "--", "uptime"]),
+        Some(Action::Exec)
+    );
+    assert_eq!(
+        action(&["deploy", "promote", "staging", "prod"]),
+        Some(Action::Activate)
+    );
+    assert_eq!(
+        action(&[
+            "deploy",
+            "--pull-descriptors",
+            "./descriptors",
+            "--pull-sign-key",
+            "/run/keys/deploy",
+            "."
+        ]),
+        Some(Action::Activate)
+    );
+    assert_eq!(action(&["deploy", "facts", "web-1"]), None);
 }
 
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
@@ -611,34 +5344,162 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         None => Opts::parse(),
     };
 
+    if opts.output_schema {
+        print!("{}", deploy::schema::OUTPUT_SCHEMA);
+
+        return Ok(());
+    }
+
+    let capabilities = deploy::capability::Capabilities::from_env(opts.read_only);
+
+    if let Some(action) = required_action(&opts) {
+        capabilities.check(action)?;
+    }
+
+    // Nothing to evaluate or connect to
+    match opts.subcmd {
+        Some(SubCommand::State(StateOpts {
+            action: StateAction::Show(ref show_opts),
+        })) => return Ok(run_state_show(show_opts)?),
+        Some(SubCommand::State(StateOpts {
+            action: StateAction::Clean(ref clean_opts),
+        })) => return Ok(run_state_clean(clean_opts)?),
+        Some(SubCommand::DiffRuns(ref diff_opts)) => return Ok(run_diff_runs(diff_opts)?),
+        Some(SubCommand::VerifyReceipt(ref verify_opts)) => {
+            return Ok(run_verify_receipt(verify_opts).await?)
+        }
+        Some(SubCommand::Pause(ref pause_opts)) => return Ok(run_pause(pause_opts)?),
+        Some(SubCommand::Import(ref import_opts)) => return Ok(run_import(import_opts).await?),
+        _ => (),
+    }
+
+    let event_log = match opts.log_file {
+        Some(ref log_file) => {
+            Some(EventLog::open(log_file, opts.log_file_format).map_err(RunError::LogFile)?)
+        }
+        None => None,
+    };
+
+    let log_level = match (opts.quiet, opts.verbose, opts.debug_logs) {
+        (true, _, _) => log::LevelFilter::Warn,
+        (false, 0, false) => log::LevelFilter::Info,
+        (false, 0, true) | (false, 1, _) => log::LevelFilter::Debug,
+        (false, _, _) => log::LevelFilter::Trace,
+    };
+
+    deploy::set_output_style(opts.color, opts.ascii);
+
     deploy::init_logger(
-        opts.debug_logs,
+        log_level,
+        opts.log_filter.as_deref(),
         opts.log_dir.as_deref(),
         &deploy::LoggerType::Deploy,
+        event_log.as_ref(),
     )?;
 
-    let deploys = opts
-        .clone()
-        .targets
-        .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]);
+    let deploys = match opts.subcmd {
+        Some(SubCommand::Promote(ref promote_opts)) => vec![promote_opts.target.clone()],
+        Some(SubCommand::Resume(ref resume_opts)) => {
+            let dir = deploy::state::state_dir().map_err(RunPauseError::State)?;
+
+            match deploy::runs::load_paused(&dir, &resume_opts.run).map_err(RunPauseError::State)? {
+                Some(paused) => {
+                    info!(
+                        "Resuming run `{}` with {} profiles left to activate",
+                        resume_opts.run,
+                        paused.targets.len()
+                    );
+
+                    paused.targets
+                }
+                None => return Err(RunPauseError::NotPaused(resume_opts.run.clone()).into()),
+            }
+        }
+        _ => opts
+            .clone()
+            .targets
+            .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]),
+    };
+
+    // Nodes picked by a pattern or group are narrowed down to once the flake is evaluated
+    let mut selectors: Vec<Option<deploy::selector::Selector>> = Vec::new();
+    let deploys: Vec<String> = deploys
+        .into_iter()
+        .map(|target| match deploy::selector::Selector::split(&target)? {
+            Some((repo, selector)) => {
+                selectors.push(Some(selector));
+                Ok(repo)
+            }
+            None => {
+                selectors.push(None);
+                Ok(target)
+            }
+        })
+        .collect::<Result<Vec<String>, deploy::selector::SelectorError>>()?;
 
     let deploy_flakes: Vec<DeployFlake> = deploys
         .iter()
         .map(|f| deploy::parse_flake(f.as_str()))
         .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?;
 
-    let cmd_overrides = deploy::CmdOverrides {
+    // SSH asks for passphrases and passwords through the askpass program, also when it's run by Nix
+    if let Some(ref askpass) = opts.askpass {
+        std::env::set_var("SSH_ASKPASS", askpass);
+        std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+    }
+
+    let serial_auth = opts.serial_auth
+        || deploy::transport::agent_has_security_keys(&deploy::transport::SystemTransport).await;
+
+    // Kept until the run ends, which removes the directory
+    let control_dir = match serial_auth {
+        true => {
+            if !opts.serial_auth {
+                info!("The SSH agent holds keys on a security token, authenticating to one node at a time");
+            }
+
+            Some(ControlDir::create().map_err(RunError::SSHControlDir)?)
+        }
+        false => None,
+    };
+    let ssh_control_dir = control_dir
+        .as_ref()
+        .map(|x| x.0.to_string_lossy().to_string());
+
+    let mut cmd_overrides = deploy::CmdOverrides {
         ssh_user: opts.ssh_user,
         profile_user: opts.profile_user,
-        ssh_opts: opts.ssh_opts,
+        ssh_opts: ssh_opts_override(opts.ssh_opts.as_deref(), &opts.ssh_opt),
         fast_connection: opts.fast_connection,
+        serve_store: opts.serve_store,
+        remote_build: opts.remote_build,
+        build_cores: opts.build_cores,
+        build_max_jobs: opts.build_max_jobs,
+        build_nice: opts.build_nice,
         auto_rollback: opts.auto_rollback,
+        skip_active: opts.skip_active,
         hostname: opts.hostname,
         magic_rollback: opts.magic_rollback,
         temp_path: opts.temp_path,
         confirm_timeout: opts.confirm_timeout,
+        activation_timeout: opts.activation_timeout,
+        ssh_connect_timeout: opts.ssh_connect_timeout,
+        pin_host_key: if opts.pin_host_keys { Some(true) } else { None },
         dry_activate: opts.dry_activate,
+        check_sigs: if opts.checksigs { Some(true) } else { None },
         sudo: opts.sudo,
+        agent_cert: opts.agent_cert,
+        agent_key: opts.agent_key,
+        agent_ca: opts.agent_ca,
+        ssh_control_dir,
+        askpass: opts.askpass.clone(),
+        ignore_nix_sshopts: opts.ignore_nix_sshopts,
+        offline: opts.offline,
+        deploy_attr: opts.deploy_attr,
+        label: opts.label,
+        deployment_id: None,
+        receipt_key: opts.sign_receipts,
+        receipt_signer: opts.receipt_signer,
     };
 
     let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
@@ -647,29 +5508,404 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         warn!("A Nix version without flakes support was detected, support for this is work in progress");
     }
 
-    if !opts.skip_checks {
-        for deploy_flake in &deploy_flakes {
-            check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args).await?;
+    let mut extra_build_args = opts.extra_build_args.clone();
+
+    if opts.offline {
+        extra_build_args.extend(deploy::offline_args(supports_flakes));
+    }
+
+    if opts.forbid_ifd {
+        extra_build_args.extend(
+            deploy::nixlog::FORBID_IFD_ARGS
+                .iter()
+                .map(|x| x.to_string()),
+        );
+    }
+
+    match opts.subcmd {
+        Some(SubCommand::Exec(ref exec_opts)) => {
+            run_exec(
+                supports_flakes,
+                exec_opts,
+                &cmd_overrides,
+                &extra_build_args,
+                event_log.as_ref(),
+                opts.production,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::CopyFile(ref copy_file_opts)) => {
+            run_copy_file(
+                supports_flakes,
+                copy_file_opts,
+                &cmd_overrides,
+                &extra_build_args,
+                opts.production,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::DiffRemote(ref diff_remote_opts)) => {
+            run_diff_remote(
+                supports_flakes,
+                diff_remote_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Graph(ref graph_opts)) => {
+            run_graph(
+                supports_flakes,
+                graph_opts,
+                cmd_overrides.deploy_attr.as_deref(),
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Bundle(BundleOpts {
+            action: BundleAction::Create(ref create_opts),
+        })) => {
+            run_bundle_create(
+                supports_flakes,
+                create_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Bundle(BundleOpts {
+            action: BundleAction::Apply(ref apply_opts),
+        })) => {
+            run_bundle_apply(apply_opts, &cmd_overrides, opts.production).await?;
+
+            return Ok(());
         }
+        Some(SubCommand::State(_))
+        | Some(SubCommand::DiffRuns(_))
+        | Some(SubCommand::VerifyReceipt(_))
+        | Some(SubCommand::Pause(_))
+        | Some(SubCommand::Import(_)) => {
+            unreachable!("State commands are run before flake support is tested")
+        }
+        Some(SubCommand::Verify(ref verify_opts)) => {
+            run_verify(
+                supports_flakes,
+                verify_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::CachePush(ref cache_push_opts)) => {
+            run_cache_push(
+                supports_flakes,
+                cache_push_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Facts(ref facts_opts)) => {
+            run_facts(
+                supports_flakes,
+                facts_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::GenBootstrap(ref bootstrap_opts)) => {
+            run_gen_bootstrap(
+                supports_flakes,
+                bootstrap_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Adopt(ref adopt_opts)) => {
+            run_adopt(
+                supports_flakes,
+                adopt_opts,
+                &cmd_overrides,
+                &extra_build_args,
+                opts.production,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::BenchCopy(ref bench_opts)) => {
+            run_bench_copy(
+                supports_flakes,
+                bench_opts,
+                &cmd_overrides,
+                &extra_build_args,
+            )
+            .await?;
+
+            return Ok(());
+        }
+        Some(SubCommand::Promote(_)) | Some(SubCommand::Resume(_)) | None => (),
     }
+
     let result_path = opts.result_path.as_deref();
-    let data = get_deployment_data(supports_flakes, &deploy_flakes, &opts.extra_build_args).await?;
-    run_deploy(
+    let timings = deploy::timings::Timings::default();
+
+    let eval_started = std::time::Instant::now();
+    let mut data = get_deployment_data(
+        supports_flakes,
+        &deploy_flakes,
+        cmd_overrides.deploy_attr.as_deref(),
+        &extra_build_args,
+    )
+    .await?;
+    timings.record_eval(eval_started.elapsed());
+
+    for (selector, data) in selectors.iter().zip(&mut data) {
+        if let Some(selector) = selector {
+            selector.select(data)?;
+        }
+    }
+
+    if let Some(ref nodes_from) = opts.nodes_from {
+        let list = match nodes_from.as_str() {
+            "-" => {
+                let mut list = String::new();
+                stdin()
+                    .read_to_string(&mut list)
+                    .map(|_| list)
+                    .map_err(RunError::NodesFromRead)?
+            }
+            path => std::fs::read_to_string(path).map_err(RunError::NodesFromRead)?,
+        };
+        let node_names =
+            deploy::selector::parse_node_list(&list).map_err(RunError::NodesFromParse)?;
+
+        let not_found: Vec<String> = node_names
+            .iter()
+            .filter(|node_name| !data.iter().any(|data| data.nodes.contains_key(*node_name)))
+            .map(|node_name| format!("`{}`", node_name))
+            .collect();
+
+        if !not_found.is_empty() {
+            return Err(RunError::NodesFromNotFound(not_found.join(", ")));
+        }
+
+        info!("Deploying the {} nodes given", node_names.len());
+
+        // A target naming a node keeps it
+        for (deploy_flake, data) in deploy_flakes.iter().zip(&mut data) {
+            if deploy_flake.node.is_none() {
+                data.nodes
+                    .retain(|node_name, _| node_names.contains(node_name));
+            }
+        }
+    }
+
+    if let Some(ref run) = opts.rollback_all {
+        // Snapshots taken by the run are restored along with its profiles
+        cmd_overrides.deployment_id = Some(run.clone());
+
+        run_rollback_all(
+            data,
+            run,
+            &cmd_overrides,
+            log_level >= log::LevelFilter::Debug,
+            opts.log_dir.as_deref(),
+            event_log.as_ref(),
+            opts.production,
+        )
+        .await?;
+
+        return Ok(());
+    }
+
+    // The closures are taken from the state, the flake is only evaluated for the nodes' settings
+    if let Some(SubCommand::Promote(ref promote_opts)) = opts.subcmd {
+        let confirmed = deploy::state::state_dir()
+            .and_then(|dir| deploy::channel::load_channel(&dir, &promote_opts.from))
+            .map_err(deploy::channel::PromoteError::State)?;
+
+        for data in &mut data {
+            deploy::channel::promote(data, &promote_opts.from, &confirmed, &promote_opts.to)?;
+        }
+    }
+
+    if let Some(canary) = opts.canary {
+        for (deploy_flake, data) in deploy_flakes.iter().zip(&mut data) {
+            if deploy_flake.node.is_some() {
+                continue;
+            }
+
+            // The same revision picks the same nodes, so a canary deployment can be repeated or picked up again
+            let seed = match supports_flakes {
+                true => {
+                    deploy::bundle::flake_revision(
+                        &deploy::transport::SystemTransport,
+                        deploy_flake.repo,
+                        cmd_overrides.offline,
+                    )
+                    .await
+                }
+                false => None,
+            };
+            let seed = seed.unwrap_or_else(|| {
+                warn!(
+                    "`{}` has no revision, picking canaries by its path instead",
+                    deploy_flake.repo
+                );
+                deploy_flake.repo.to_string()
+            });
+
+            let canaries =
+                deploy::rollout::select_canaries(&data.nodes, canary, opts.weight.as_ref(), &seed);
+
+            if canaries.is_empty() {
+                return Err(RunError::NoCanaries(deploy_flake.repo.to_string()));
+            }
+
+            info!(
+                "Deploying to {} of {} nodes of `{}` first: {}",
+                canaries.len(),
+                data.nodes.len(),
+                deploy_flake.repo,
+                canaries.join(", ")
+            );
+
+            data.nodes
+                .retain(|node_name, _| canaries.contains(node_name));
+        }
+    }
+
+    // Checks are selected after evaluating, as nodes can require some of them to run
+    let mut checks: Vec<CheckSelection> = Vec::new();
+
+    for (deploy_flake, data) in deploy_flakes.iter().zip(&data) {
+        checks.push(select_checks(
+            opts.skip_checks,
+            &opts.checks,
+            required_checks(deploy_flake, data),
+        ));
+    }
+    let preflight = !opts.skip_preflight;
+    let pull = match (&opts.pull_descriptors, &opts.pull_sign_key) {
+        (Some(descriptors), Some(sign_key)) => Some(PullSettings {
+            descriptors,
+            copy_to: opts.pull_copy_to.as_deref(),
+            substituters: &opts.pull_substituter,
+            sign_key,
+            signer: opts.pull_signer.clone().unwrap_or_else(whoami::username),
+        }),
+        _ => None,
+    };
+    // Runs which activate something are recorded, for `deploy diff-runs`
+    let event_log = match opts.pull_descriptors.is_none()
+        && !opts.dry_activate
+        && !opts.diff_etc
+        && !capabilities.read_only
+    {
+        true => Some(EventLog::recording_run(event_log)),
+        false => event_log,
+    };
+    let run_id = event_log.as_ref().and_then(start_run);
+
+    cmd_overrides.deployment_id = Some(match run_id {
+        Some(ref x) => x.clone(),
+        None => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0)
+            .to_string(),
+    });
+
+    let result = run_deploy(
         deploy_flakes,
         data,
-        supports_flakes,
-        opts.checksigs,
-        opts.interactive,
         &cmd_overrides,
-        opts.keep_result,
-        result_path,
-        &opts.extra_build_args,
-        opts.debug_logs,
-        opts.dry_activate,
-        &opts.log_dir,
-        opts.rollback_succeeded.unwrap_or(true),
+        DeployRunOptions {
+            supports_flakes,
+            interactive: opts.interactive,
+            keep_result: opts.keep_result,
+            result_path,
+            extra_build_args: &extra_build_args,
+            debug_logs: log_level >= log::LevelFilter::Debug,
+            dry_activate: opts.dry_activate,
+            diff_etc: opts.diff_etc,
+            closure_diff: opts.closure_diff,
+            log_dir: &opts.log_dir,
+            rollback_succeeded: opts.rollback_succeeded.unwrap_or(true),
+            event_log: event_log.as_ref(),
+            pull,
+            outside_window: match (opts.defer, opts.force) {
+                (true, _) => OutsideWindow::Defer,
+                (_, true) => OutsideWindow::Force,
+                _ => OutsideWindow::Refuse,
+            },
+            checks: &checks,
+            preflight,
+            run_id: run_id.as_deref(),
+            production: opts.production,
+            deny_warnings: opts.deny_warnings,
+            timings: match opts.timings {
+                true => Some(&timings),
+                false => None,
+            },
+            read_only: capabilities.read_only,
+        },
     )
-    .await?;
+    .await;
+
+    if opts.timings {
+        info!("Timings:\n{}", timings.table());
+    }
+
+    if let Some(ref event_log) = event_log {
+        save_run(event_log, run_id.as_deref());
+    }
+
+    // A resumed run that was paused again can be resumed from where it stopped this time
+    if let Some(SubCommand::Resume(ref resume_opts)) = opts.subcmd {
+        if let Ok(()) | Err(RunDeployError::Paused(_, _)) = result {
+            if let Err(e) = deploy::state::state_dir()
+                .and_then(|dir| deploy::runs::forget_paused(&dir, &resume_opts.run))
+            {
+                warn!(
+                    "Could not forget where run `{}` was paused: {}",
+                    resume_opts.run, e
+                );
+            }
+        }
+    }
+
+    let raised = deploy::warnings::raised();
+    if !raised.is_empty() {
+        warn!("Finished with {}", deploy::warnings::summary(&raised));
+    }
+
+    result?;
+
+    if opts.deny_warnings && !raised.is_empty() {
+        return Err(RunError::DeniedWarnings(deploy::warnings::summary(&raised)));
+    }
 
     Ok(())
 }
@@ -3,8 +3,9 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use merge::Merge;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 
 #[derive(Deserialize, Debug, Clone, Merge)]
 pub struct GenericSettings {
@@ -20,20 +21,400 @@ pub struct GenericSettings {
     pub ssh_opts: Vec<String>,
     #[serde(rename(deserialize = "fastConnection"))]
     pub fast_connection: Option<bool>,
+    #[serde(rename(deserialize = "probeConnection"))]
+    pub probe_connection: Option<bool>,
+    #[serde(rename(deserialize = "substituteOnDestination"))]
+    pub substitute_on_destination: Option<SubstitutePolicy>,
+    #[serde(rename(deserialize = "copyDestinations"))]
+    pub copy_destinations: Option<Vec<String>>,
+    #[serde(rename(deserialize = "copyMode"))]
+    pub copy_mode: Option<CopyMode>,
+    #[serde(rename(deserialize = "checkSigs"))]
+    pub check_sigs: Option<bool>,
+    pub signing: Option<Signing>,
+    #[serde(rename(deserialize = "pushCache"))]
+    pub push_cache: Option<PushCache>,
+    #[serde(rename(deserialize = "serveStore"))]
+    pub serve_store: Option<bool>,
+    #[serde(rename(deserialize = "remoteBuild"))]
+    pub remote_build: Option<bool>,
+    #[serde(rename(deserialize = "buildCores"))]
+    pub build_cores: Option<u32>,
+    #[serde(rename(deserialize = "buildMaxJobs"))]
+    pub build_max_jobs: Option<u32>,
+    #[serde(rename(deserialize = "buildNice"))]
+    pub build_nice: Option<u8>,
+    #[serde(rename(deserialize = "buildOn"))]
+    pub build_on: Option<String>,
+    #[serde(rename(deserialize = "agentPort"))]
+    pub agent_port: Option<u16>,
     #[serde(rename(deserialize = "autoRollback"))]
     pub auto_rollback: Option<bool>,
+    #[serde(rename(deserialize = "skipActive"))]
+    pub skip_active: Option<bool>,
+    #[serde(rename(deserialize = "restartChangedOnly"))]
+    pub restart_changed_only: Option<bool>,
+    pub relabel: Option<bool>,
+    #[serde(rename(deserialize = "maxClockSkew"))]
+    pub max_clock_skew: Option<u64>,
+    #[serde(rename(deserialize = "pinHostKey"))]
+    pub pin_host_key: Option<bool>,
     #[serde(rename(deserialize = "confirmTimeout"))]
     pub confirm_timeout: Option<u16>,
+    #[serde(rename(deserialize = "activationTimeout"))]
+    pub activation_timeout: Option<u16>,
+    #[serde(rename(deserialize = "sshConnectTimeout"))]
+    pub ssh_connect_timeout: Option<u16>,
+    #[serde(rename(deserialize = "keepAliveInterval"))]
+    pub keep_alive_interval: Option<u16>,
     #[serde(rename(deserialize = "tempPath"))]
     pub temp_path: Option<String>,
     #[serde(rename(deserialize = "magicRollback"))]
     pub magic_rollback: Option<bool>,
     #[serde(rename(deserialize = "sudo"))]
-    pub sudo: Option<String>,
+    pub sudo: Option<SudoCommand>,
+    #[serde(rename(deserialize = "remoteNixPath"))]
+    pub remote_nix_path: Option<String>,
+    #[serde(rename(deserialize = "remoteStoreRoot"))]
+    pub remote_store_root: Option<String>,
+    #[serde(rename(deserialize = "bootstrapNix"))]
+    pub bootstrap_nix: Option<String>,
+    #[serde(rename(deserialize = "remoteProfileDir"))]
+    pub remote_profile_dir: Option<String>,
+    #[serde(rename(deserialize = "waitForUnit"))]
+    pub wait_for_unit: Option<String>,
+    #[serde(rename(deserialize = "waitForPort"))]
+    pub wait_for_port: Option<u16>,
+    #[serde(rename(deserialize = "waitForUrl"))]
+    pub wait_for_url: Option<String>,
+    #[serde(rename(deserialize = "waitTimeout"))]
+    pub wait_timeout: Option<u16>,
+    #[serde(rename(deserialize = "activationConcurrency"))]
+    pub activation_concurrency: Option<usize>,
+    #[serde(rename(deserialize = "clusterGroup"))]
+    pub cluster_group: Option<String>,
+    #[serde(rename(deserialize = "maintenanceWindow"))]
+    pub maintenance_window: Option<String>,
+    #[serde(rename(deserialize = "optimiseStore"))]
+    pub optimise_store: Option<bool>,
+    #[serde(rename(deserialize = "optimiseTimeout"))]
+    pub optimise_timeout: Option<u16>,
+    pub channel: Option<String>,
+    #[serde(rename(deserialize = "reverseTunnel"))]
+    pub reverse_tunnel: Option<ReverseTunnel>,
+    #[serde(rename(deserialize = "onProfileFailure"))]
+    pub on_profile_failure: Option<ProfileFailurePolicy>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "requiredChecks")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub required_checks: Vec<String>,
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        default,
+        rename(deserialize = "postActivateChecks")
+    )]
+    #[merge(strategy = merge::vec::append)]
+    pub post_activate_checks: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    #[merge(strategy = merge::vec::append)]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(rename(deserialize = "restoreSnapshots"))]
+    pub restore_snapshots: Option<bool>,
+    pub migrations: Option<Migrations>,
+    #[serde(default, rename(deserialize = "activationEnv"))]
+    #[merge(strategy = merge_missing)]
+    pub activation_env: HashMap<String, ActivationEnvValue>,
+    #[serde(default, rename(deserialize = "buildEnv"))]
+    #[merge(strategy = merge_missing)]
+    pub build_env: HashMap<String, String>,
+    #[serde(default, rename(deserialize = "copyEnv"))]
+    #[merge(strategy = merge_missing)]
+    pub copy_env: HashMap<String, String>,
+}
+
+/// Whether `nix copy` lets the node fetch paths from its own substituters instead of sending them
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubstitutePolicy {
+    Always,
+    Never,
+    /// Only when the node doesn't have a `fastConnection`
+    Auto,
+}
+
+/// Whether a profile is copied to every one of its node's `copyDestinations`, or only up to the first which succeeds
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyMode {
+    All,
+    FirstSuccess,
+}
+
+/// A port of the deploying machine made reachable from the node while a profile activates, through the SSH connection
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverseTunnel {
+    /// Where the node reaches it, on its loopback interface
+    pub remote_port: u16,
+    /// The port on the deploying machine, on its loopback interface
+    pub local_port: u16,
+}
+
+/// What the rest of a deployment does after one of a node's profiles fails to activate
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileFailurePolicy {
+    /// Stop deploying anything, and revoke the profiles activated before
+    Abort,
+    /// Skip the node's later profiles, but go on with other nodes
+    SkipNode,
+    /// Go on with the node's later profiles
+    Continue,
+}
+
+/// A variable for the activation environment, either given as is or fetched on the deployer: printed by a command,
+/// read from a file or taken out of a secret store, see `secrets::resolve`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ActivationEnvValue {
+    Value(String),
+    Command {
+        command: String,
+    },
+    File {
+        file: String,
+    },
+    /// A file encrypted with sops, or one of its keys
+    Sops {
+        sops: String,
+        key: Option<String>,
+    },
+    /// A field of a secret read from Vault's HTTP API
+    Vault {
+        vault: String,
+        field: String,
+    },
+    /// A secret of AWS Secrets Manager, or one of its keys if it's JSON
+    AwsSecret {
+        #[serde(rename = "awsSecret")]
+        aws_secret: String,
+        key: Option<String>,
+        region: Option<String>,
+    },
+}
+
+/// The command running commands as another user, given the user and the command. Written either as a string which
+/// is passed to the shell as it is, or as a list of arguments which are quoted for it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "SudoValue")]
+pub struct SudoCommand(String);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SudoValue {
+    Command(String),
+    Argv(Vec<String>),
+}
+
+impl TryFrom<SudoValue> for SudoCommand {
+    type Error = String;
+
+    fn try_from(value: SudoValue) -> Result<Self, Self::Error> {
+        match value {
+            SudoValue::Command(command) => Ok(SudoCommand(command)),
+            SudoValue::Argv(argv) if argv.is_empty() => {
+                Err("`sudo` needs at least the command to run".to_string())
+            }
+            SudoValue::Argv(argv) if argv.iter().any(|x| x.is_empty()) => {
+                Err("`sudo` can't have empty arguments".to_string())
+            }
+            SudoValue::Argv(argv) => Ok(SudoCommand(
+                argv.iter()
+                    .map(|x| shell_word(x))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            )),
+        }
+    }
+}
+
+impl SudoCommand {
+    pub fn new(command: String) -> Self {
+        SudoCommand(command)
+    }
+
+    /// The command as it's put in front of the user to run as
+    pub fn command(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Quotes an argument for the shell only if it needs to be, so `sudo` stays recognisable as such
+fn shell_word(s: &str) -> String {
+    if s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-+=%@:,./".contains(c))
+    {
+        s.to_string()
+    } else {
+        crate::shell_quote(s)
+    }
+}
+
+#[test]
+fn test_sudo_command() {
+    let sudo = |json: &str| serde_json::from_str::<SudoCommand>(json);
+
+    assert_eq!(sudo(r#""doas -u""#).unwrap().command(), "doas -u");
+    assert_eq!(
+        sudo(r#"["sudo", "--preserve-env=PATH", "-u"]"#)
+            .unwrap()
+            .command(),
+        "sudo --preserve-env=PATH -u"
+    );
+    assert_eq!(
+        sudo(r#"["/run/wrappers/bin/escalate as", "-u"]"#)
+            .unwrap()
+            .command(),
+        "'/run/wrappers/bin/escalate as' -u"
+    );
+    assert!(sudo("[]").is_err());
+    assert!(sudo(r#"["sudo", "", "-u"]"#).is_err());
+}
+
+/// How the profiles of a node are signed before they're copied to it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Signing {
+    #[serde(default)]
+    pub key_file: KeyFiles,
+}
+
+/// The kind of binary cache `pushCache` pushes to, and so the command pushing to it
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheType {
+    Cachix,
+    Attic,
+    /// Any store `nix copy` can copy to
+    Nix,
+}
+
+/// A binary cache profiles are pushed to right after they're built, so teammates and later runs substitute them
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PushCache {
+    #[serde(rename = "type")]
+    pub cache_type: CacheType,
+    /// The cachix cache, the attic cache as `<server>:<cache>`, or the store URI for `nix copy`
+    pub name: String,
+    /// Whether failing to push fails the deployment, rather than being warned about
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// A command migrating the state of the profile's services, run on the node before activating it
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Migrations {
+    /// Run with `sh -c` as the profile's user
+    pub command: String,
+    /// How long it may run, in seconds, `crate::deploy::DEFAULT_MIGRATION_TIMEOUT` if not given
+    pub timeout: Option<u32>,
+}
+
+/// The filesystems `snapshots` can snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotType {
+    Zfs,
+    Btrfs,
+}
+
+/// A ZFS dataset or btrfs subvolume on the node which is snapshotted before the profile is activated
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    #[serde(rename = "type")]
+    pub snapshot_type: SnapshotType,
+    /// The name of the dataset, or the path of the subvolume
+    pub path: String,
+    /// Where the snapshots of a btrfs subvolume go, see `crate::snapshot`
+    pub snapshot_dir: Option<String>,
+    /// systemd units using the dataset or subvolume, stopped while it's restored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_units: Vec<String>,
+}
+
+/// Secret keys made with `nix-store --generate-binary-cache-key`, each of which signs. Written either as the path of
+/// one key or as a list of them
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(from = "KeyFilesValue")]
+pub struct KeyFiles(pub Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyFilesValue {
+    Path(String),
+    Paths(Vec<String>),
+}
+
+impl From<KeyFilesValue> for KeyFiles {
+    fn from(value: KeyFilesValue) -> Self {
+        match value {
+            KeyFilesValue::Path(path) => KeyFiles(vec![path]),
+            KeyFilesValue::Paths(paths) => KeyFiles(paths),
+        }
+    }
+}
+
+#[test]
+fn test_signing() {
+    let signing = |json: &str| serde_json::from_str::<Signing>(json).unwrap().key_file.0;
+
+    assert_eq!(
+        signing(r#"{ "keyFile": "/run/keys/cache" }"#),
+        vec!["/run/keys/cache"]
+    );
+    assert_eq!(
+        signing(r#"{ "keyFile": ["/run/keys/old", "/run/keys/new"] }"#),
+        vec!["/run/keys/old", "/run/keys/new"]
+    );
+    assert_eq!(signing("{}"), Vec::<String>::new());
+}
+
+/// Adds the entries of `right` which `left` doesn't have, so more specific settings win
+fn merge_missing<V>(left: &mut HashMap<String, V>, right: HashMap<String, V>) {
+    for (key, value) in right {
+        left.entry(key).or_insert(value);
+    }
+}
+
+/// Where `terraform output -json` is taken from, for the nodes which take their hostnames or tags from it
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TerraformOutputs {
+    /// The directory terraform is run in, the current one if it isn't set
+    pub dir: Option<String>,
+    /// A file the outputs were saved to before, which is read instead of running terraform
+    pub output_file: Option<String>,
+}
+
+/// The terraform outputs a node takes its settings from, as references like `servers.web-1.ipv4_address`, see
+/// `terraform::lookup`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeTerraform {
+    pub hostname: Option<String>,
+    /// Added to the node's `tags`
+    pub tags: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct NodeSettings {
+    /// Left empty for a node whose hostname is a terraform output, until it's resolved
+    #[serde(default)]
     pub hostname: String,
     pub profiles: HashMap<String, Profile>,
     #[serde(
@@ -42,6 +423,44 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub tags: Vec<String>,
+    /// Only deployed to with `--production`, or by typing the node's name when asked
+    #[serde(default)]
+    pub protected: bool,
+    pub terraform: Option<NodeTerraform>,
+}
+
+/// How a profile is put into use on its node
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileType {
+    /// A Nix profile with `activate-rs` in it, which is activated by running that
+    Profile,
+    /// A systemd portable service image, which is attached with `portablectl`
+    Portable,
+    /// A netboot image (kernel, initrd and iPXE script), which is published for PXE clients to boot from
+    Netboot,
+    /// A service run as a systemd user unit, which is started next to the running one and switched over to once it's
+    /// healthy
+    #[serde(rename = "blue-green")]
+    BlueGreen,
+}
+
+/// How a "blue-green" profile's service is run and checked
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlueGreen {
+    /// The program in the closure running the service, `bin/<profile name>` if not given
+    pub command: Option<String>,
+    /// Run with `sh -c` until it succeeds, telling whether the new instance is healthy
+    pub health_check: Option<String>,
+    /// How long the new instance has to get healthy, in seconds
+    pub health_timeout: Option<u32>,
+    /// The ports given to the instances of the two slots, blue first
+    pub ports: Option<[u16; 2]>,
+    /// Where the slots and the link to the current one are kept
+    pub state_dir: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -49,6 +468,12 @@ pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    #[serde(rename(deserialize = "profileType"))]
+    pub profile_type: Option<ProfileType>,
+    #[serde(rename(deserialize = "netbootDir"))]
+    pub netboot_dir: Option<String>,
+    #[serde(rename(deserialize = "blueGreen"))]
+    pub blue_green: Option<BlueGreen>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -67,9 +492,201 @@ pub struct Node {
     pub node_settings: NodeSettings,
 }
 
+/// Nodes which are all deployed the same profiles, built once, each with its own hostname and settings
 #[derive(Deserialize, Debug, Clone)]
-pub struct Data {
+pub struct NodeGroup {
+    #[serde(flatten)]
+    pub generic_settings: GenericSettings,
+    pub profiles: HashMap<String, Profile>,
+    #[serde(default, rename(deserialize = "profilesOrder"))]
+    pub profiles_order: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub protected: bool,
+    pub hosts: HashMap<String, GroupHost>,
+}
+
+/// One node of a `NodeGroup`, its settings winning over the group's
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroupHost {
+    pub hostname: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// A host is protected if it or its group is
+    #[serde(default)]
+    pub protected: bool,
     #[serde(flatten)]
     pub generic_settings: GenericSettings,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "DataDef")]
+pub struct Data {
+    pub generic_settings: GenericSettings,
+    /// The nodes given as such, and those of every node group
     pub nodes: HashMap<String, Node>,
+    /// Names for lists of nodes, or patterns matching them, to deploy with `deploy .#@<name>`
+    pub groups: HashMap<String, Vec<String>>,
+    pub terraform: Option<TerraformOutputs>,
+}
+
+#[derive(Deserialize)]
+struct DataDef {
+    #[serde(flatten)]
+    generic_settings: GenericSettings,
+    #[serde(default)]
+    nodes: HashMap<String, Node>,
+    #[serde(default, rename(deserialize = "nodeGroups"))]
+    node_groups: HashMap<String, NodeGroup>,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    terraform: Option<TerraformOutputs>,
+}
+
+impl TryFrom<DataDef> for Data {
+    type Error = String;
+
+    fn try_from(value: DataDef) -> Result<Self, Self::Error> {
+        let mut nodes = value.nodes;
+
+        for (node_name, node) in &nodes {
+            let from_terraform = node
+                .node_settings
+                .terraform
+                .as_ref()
+                .and_then(|x| x.hostname.as_ref())
+                .is_some();
+
+            if node.node_settings.hostname.is_empty() && !from_terraform {
+                return Err(format!("node `{}` has no hostname", node_name));
+            }
+        }
+
+        for (group_name, group) in value.node_groups {
+            let NodeGroup {
+                generic_settings: group_settings,
+                profiles,
+                profiles_order,
+                tags: group_tags,
+                protected,
+                hosts,
+            } = group;
+
+            for (host_name, host) in hosts {
+                if nodes.contains_key(&host_name) {
+                    return Err(format!(
+                        "node `{}` of node group `{}` is defined more than once",
+                        host_name, group_name
+                    ));
+                }
+
+                let mut generic_settings = host.generic_settings;
+                generic_settings.merge(group_settings.clone());
+
+                // What tells the hosts apart at activation, as their closures are all the same
+                for (key, value) in &[
+                    ("DEPLOY_RS_NODE", &host_name),
+                    ("DEPLOY_RS_HOSTNAME", &host.hostname),
+                ] {
+                    generic_settings
+                        .activation_env
+                        .entry(key.to_string())
+                        .or_insert_with(|| ActivationEnvValue::Value(value.to_string()));
+                }
+
+                let mut tags = group_tags.clone();
+                tags.extend(host.tags.into_iter().filter(|x| !group_tags.contains(x)));
+
+                nodes.insert(
+                    host_name,
+                    Node {
+                        generic_settings,
+                        node_settings: NodeSettings {
+                            hostname: host.hostname,
+                            profiles: profiles.clone(),
+                            profiles_order: profiles_order.clone(),
+                            tags,
+                            protected: protected || host.protected,
+                            terraform: None,
+                        },
+                    },
+                );
+            }
+        }
+
+        Ok(Data {
+            generic_settings: value.generic_settings,
+            nodes,
+            groups: value.groups,
+            terraform: value.terraform,
+        })
+    }
+}
+
+#[test]
+fn test_node_groups() {
+    let data: Data = serde_json::from_str(
+        r#"{
+            "sshUser": "deploy",
+            "nodes": {
+                "db-1": { "hostname": "10.0.0.2", "profiles": {} }
+            },
+            "nodeGroups": {
+                "web": {
+                    "tags": ["web"],
+                    "activationEnv": { "REGION": "eu" },
+                    "profiles": { "system": { "path": "/nix/store/blah-system" } },
+                    "hosts": {
+                        "web-1": { "hostname": "10.0.1.1", "tags": ["canary"] },
+                        "web-2": {
+                            "hostname": "10.0.1.2",
+                            "protected": true,
+                            "sshUser": "admin",
+                            "activationEnv": { "REGION": "us", "TOKEN": { "command": "pass web-2" } }
+                        }
+                    }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let mut node_names: Vec<&String> = data.nodes.keys().collect();
+    node_names.sort();
+    assert_eq!(node_names, vec!["db-1", "web-1", "web-2"]);
+
+    let web_1 = &data.nodes["web-1"];
+    assert_eq!(web_1.node_settings.hostname, "10.0.1.1");
+    assert_eq!(web_1.node_settings.tags, vec!["web", "canary"]);
+    assert_eq!(web_1.generic_settings.ssh_user, None);
+    assert!(!web_1.node_settings.protected);
+    assert_eq!(
+        web_1.generic_settings.activation_env["DEPLOY_RS_NODE"],
+        ActivationEnvValue::Value("web-1".to_string())
+    );
+
+    let web_2 = &data.nodes["web-2"];
+    assert_eq!(web_2.generic_settings.ssh_user, Some("admin".to_string()));
+    assert!(web_2.node_settings.protected);
+    assert_eq!(
+        web_2.generic_settings.activation_env["REGION"],
+        ActivationEnvValue::Value("us".to_string())
+    );
+    assert_eq!(
+        web_2.generic_settings.activation_env["DEPLOY_RS_HOSTNAME"],
+        ActivationEnvValue::Value("10.0.1.2".to_string())
+    );
+    assert_eq!(
+        web_2.node_settings.profiles["system"].profile_settings.path,
+        web_1.node_settings.profiles["system"].profile_settings.path
+    );
+
+    assert!(serde_json::from_str::<Data>(
+        r#"{
+            "nodes": { "web-1": { "hostname": "10.0.1.1", "profiles": {} } },
+            "nodeGroups": { "web": { "profiles": {}, "hosts": { "web-1": { "hostname": "10.0.1.1" } } } }
+        }"#
+    )
+    .is_err());
 }
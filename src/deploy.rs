@@ -4,11 +4,17 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::borrow::Cow;
 use thiserror::Error;
-use tokio::process::Command;
 
+use crate::data::{ProfileType, ReverseTunnel};
+use crate::diff::{ClosureDiffOutput, ClosurePath};
+use crate::facts::InitSystem;
+use crate::readiness::Readiness;
+use crate::report::{CommandFailure, Diagnostic};
+use crate::timings::Step;
+use crate::transport::{InputMode, Invocation, NodeTarget, Output, RunOnNodeError};
 use crate::DeployDataDefsError;
 
 struct ActivateCommandData<'a> {
@@ -18,14 +24,106 @@ struct ActivateCommandData<'a> {
     auto_rollback: bool,
     temp_path: &'a str,
     confirm_timeout: u16,
+    activation_timeout: Option<u16>,
     magic_rollback: bool,
     debug_logs: bool,
     log_dir: Option<&'a str>,
     dry_activate: bool,
+    /// Where activate-rs reads the activation's variables from, `-` for standard input, as they can hold secrets
+    env_file: Option<&'a str>,
+    /// The `crate::snapshot::Restore` activate-rs restores when it rolls back, as JSON
+    restore_snapshots: Option<&'a str>,
+    readiness: &'a Readiness,
+    restart_changed_only: bool,
+    nix_env: NixEnv<'a>,
+    label: Option<&'a str>,
+    relabel: bool,
+}
+
+/// How Nix is run on a node: from `remoteNixPath`, for nodes which have it somewhere their SSH sessions don't look, and
+/// on the store under `remoteStoreRoot`, for rootless installs keeping theirs in e.g. `~/.nix`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NixEnv<'a> {
+    pub path: Option<&'a str>,
+    pub store_root: Option<&'a str>,
+}
+
+impl<'a> NixEnv<'a> {
+    pub fn new(settings: &'a crate::data::GenericSettings) -> Self {
+        NixEnv {
+            path: settings.remote_nix_path.as_deref(),
+            store_root: settings.remote_store_root.as_deref(),
+        }
+    }
+}
+
+/// The store under `root`, whose paths are still `/nix/store` paths to Nix
+pub fn rooted_store(root: &str) -> String {
+    format!("local?root={}", root.trim_end_matches('/'))
+}
+
+/// Runs a command on the node with `remoteNixPath` in front of its `PATH` and with Nix using the store under
+/// `remoteStoreRoot`, for those that are set
+pub(crate) fn with_nix_env(nix_env: NixEnv<'_>, command: String) -> String {
+    let mut assignments = Vec::new();
+
+    if let Some(path) = nix_env.path {
+        assignments.push(format!("PATH={}:\"$PATH\"", crate::shell_quote(path)));
+    }
+
+    if let Some(root) = nix_env.store_root {
+        assignments.push(format!(
+            "NIX_REMOTE={}",
+            crate::shell_quote(&rooted_store(root))
+        ));
+    }
+
+    match assignments.is_empty() {
+        true => command,
+        false => format!("env {} {}", assignments.join(" "), command),
+    }
+}
+
+/// activate-rs from `closure`. The paths of a store under `remoteStoreRoot` are only in `/nix/store` in the mount
+/// namespace `nix shell` runs commands in, so it's run in one there
+fn activate_rs(closure: &str, nix_env: NixEnv<'_>) -> String {
+    match nix_env.store_root {
+        Some(_) => format!(
+            "nix --extra-experimental-features nix-command shell {} --command {}/activate-rs",
+            crate::shell_quote(closure),
+            closure
+        ),
+        None => format!("{}/activate-rs", closure),
+    }
+}
+
+/// What activate-rs waits for after activating, before reporting success
+fn readiness(settings: &crate::data::GenericSettings) -> Readiness {
+    Readiness {
+        unit: settings.wait_for_unit.clone(),
+        port: settings.wait_for_port,
+        url: settings.wait_for_url.clone(),
+        timeout: settings.wait_timeout.unwrap_or(60),
+    }
+}
+
+/// How long the node is given to report a finished activation when `activationTimeout` limits the activation script:
+/// that, waiting for readiness and a little for setting the profile. Without it the waiter's own default is used
+fn wait_timeout(settings: &crate::data::GenericSettings) -> Option<u32> {
+    let readiness = readiness(settings);
+
+    settings.activation_timeout.map(|timeout| {
+        let readiness_timeout = match readiness.is_empty() {
+            true => 0,
+            false => readiness.timeout as u32,
+        };
+
+        timeout as u32 + readiness_timeout + 30
+    })
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command = activate_rs(data.closure, data.nix_env);
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
@@ -35,9 +133,14 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
+    self_activate_command = crate::with_output_style(&self_activate_command);
+
     self_activate_command = format!(
-        "{} activate '{}' '{}' --temp-path '{}'",
-        self_activate_command, data.closure, data.profile_path, data.temp_path
+        "{} activate {} {} --temp-path {}",
+        self_activate_command,
+        crate::shell_quote(data.closure),
+        crate::shell_quote(data.profile_path),
+        crate::shell_quote(data.temp_path)
     );
 
     self_activate_command = format!(
@@ -45,6 +148,13 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command, data.confirm_timeout
     );
 
+    if let Some(activation_timeout) = data.activation_timeout {
+        self_activate_command = format!(
+            "{} --activation-timeout {}",
+            self_activate_command, activation_timeout
+        );
+    }
+
     if data.magic_rollback {
         self_activate_command = format!("{} --magic-rollback", self_activate_command);
     }
@@ -57,6 +167,67 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --dry-activate", self_activate_command);
     }
 
+    if data.restart_changed_only {
+        self_activate_command = format!("{} --restart-changed-only", self_activate_command);
+    }
+
+    if data.relabel {
+        self_activate_command = format!("{} --relabel", self_activate_command);
+    }
+
+    if let Some(ref unit) = data.readiness.unit {
+        self_activate_command = format!(
+            "{} --wait-for-unit {}",
+            self_activate_command,
+            crate::shell_quote(unit)
+        );
+    }
+
+    if let Some(port) = data.readiness.port {
+        self_activate_command = format!("{} --wait-for-port {}", self_activate_command, port);
+    }
+
+    if let Some(ref url) = data.readiness.url {
+        self_activate_command = format!(
+            "{} --wait-for-url {}",
+            self_activate_command,
+            crate::shell_quote(url)
+        );
+    }
+
+    if !data.readiness.is_empty() {
+        self_activate_command = format!(
+            "{} --wait-timeout {}",
+            self_activate_command, data.readiness.timeout
+        );
+    }
+
+    if let Some(label) = data.label {
+        self_activate_command = format!(
+            "{} --label {}",
+            self_activate_command,
+            crate::shell_quote(label)
+        );
+    }
+
+    if let Some(env_file) = data.env_file {
+        self_activate_command = format!(
+            "{} --env-file {}",
+            self_activate_command,
+            crate::shell_quote(env_file)
+        );
+    }
+
+    if let Some(restore) = data.restore_snapshots {
+        self_activate_command = format!(
+            "{} --restore-snapshots {}",
+            self_activate_command,
+            crate::shell_quote(restore)
+        );
+    }
+
+    self_activate_command = with_nix_env(data.nix_env, self_activate_command);
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -85,26 +256,626 @@ fn test_activation_command_builder() {
             auto_rollback,
             temp_path,
             confirm_timeout,
+            activation_timeout: None,
             magic_rollback,
             debug_logs,
             log_dir,
-            dry_activate
+            nix_env: NixEnv::default(),
+            dry_activate,
+            env_file: None,
+            restore_snapshots: None,
+            readiness: &Readiness::default(),
+            restart_changed_only: false,
+            label: None,
+            relabel: false,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
             .to_string(),
     );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &sudo,
+            profile_path,
+            closure,
+            auto_rollback: false,
+            temp_path,
+            confirm_timeout,
+            activation_timeout: Some(1800),
+            magic_rollback: false,
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv::default(),
+            dry_activate,
+            env_file: Some("-"),
+            restore_snapshots: Some(r#"{"name":"deploy-rs-1-test","snapshots":[]}"#),
+            readiness: &Readiness {
+                unit: Some("postgresql.service".to_string()),
+                port: Some(5432),
+                url: None,
+                timeout: 90,
+            },
+            restart_changed_only: true,
+            label: Some("v1.4.0 (OPS-123)"),
+            relabel: true,
+        }),
+        r#"sudo -u test /nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --activation-timeout 1800 --restart-changed-only --relabel --wait-for-unit 'postgresql.service' --wait-for-port 5432 --wait-timeout 90 --label 'v1.4.0 (OPS-123)' --env-file '-' --restore-snapshots '{"name":"deploy-rs-1-test","snapshots":[]}'"#
+            .to_string(),
+    );
+}
+
+struct DeferCommandData<'a> {
+    sudo: &'a Option<String>,
+    /// Whether the profile user isn't root, and so has to use its own systemd instance
+    user_units: bool,
+    profile_name: &'a str,
+    /// When to activate, as a calendar event for systemd and as a UNIX timestamp for everything else
+    calendar_event: &'a str,
+    opening: u64,
+    /// Where nodes without systemd keep the pid and output of the waiting activation
+    temp_path: &'a str,
+    /// The node's init system if its facts are known, otherwise the node tells when the command runs
+    init_system: Option<InitSystem>,
+    activate_command: &'a str,
+    /// Where the activation's variables, given on standard input, wait for it
+    env_file: Option<&'a str>,
+}
+
+/// Escapes a profile name for a systemd unit name, the way `systemd-escape` does for characters units can't have
+fn unit_name_escape(name: &str) -> String {
+    let mut escaped = String::new();
+
+    for (i, b) in name.bytes().enumerate() {
+        match b {
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' | b'-' => {
+                escaped.push(b as char)
+            }
+            _ => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+
+    escaped
+}
+
+#[test]
+fn test_unit_name_escape() {
+    assert_eq!(unit_name_escape("web.example.com"), "web.example.com");
+    assert_eq!(unit_name_escape("Web-App_2"), "Web-App_2");
+    assert_eq!(unit_name_escape("my app/ü"), r"my\x20app\x2f\xc3\xbc");
+}
+
+/// Shell creating `path` for only the user running it, or making sure the one there is theirs and nobody else can write
+/// to it, the way activate-rs does before writing to its temporary directory
+///
+/// A sticky directory of root's like `/tmp` is fine too. Anything else, e.g. a `/tmp/deploy-rs-<user>` another user
+/// made first or a symlink, fails it.
+fn private_dir_script(path: &str) -> String {
+    format!(
+        "(umask 077; mkdir -p {path}) && set -- $(ls -ldn {path}) && case \"$1:$3\" in d????-??-*:\"$(id -u)\" | d????????[tT]*:\"$(id -u)\" | d????????[tT]*:0) ;; *) echo {error} >&2; false ;; esac",
+        path = crate::shell_quote(path),
+        error = crate::shell_quote(&format!("{} is writable by or belongs to another user", path)),
+    )
+}
+
+#[test]
+fn test_private_dir_script() {
+    assert_eq!(
+        private_dir_script("/tmp/deploy-rs-deploy"),
+        r#"(umask 077; mkdir -p '/tmp/deploy-rs-deploy') && set -- $(ls -ldn '/tmp/deploy-rs-deploy') && case "$1:$3" in d????-??-*:"$(id -u)" | d????????[tT]*:"$(id -u)" | d????????[tT]*:0) ;; *) echo '/tmp/deploy-rs-deploy is writable by or belongs to another user' >&2; false ;; esac"#
+    );
+}
+
+/// The script leaving something behind on the node which activates the profile once at `calendar_event`: a systemd
+/// timer where systemd runs, or a detached shell sleeping until then everywhere else (e.g. Alpine or runit)
+fn defer_script(data: &DeferCommandData) -> String {
+    let unit = format!("deploy-rs-deferred-{}", unit_name_escape(data.profile_name));
+    let systemd_args = match data.user_units {
+        true => " --user",
+        false => "",
+    };
+
+    // An earlier deferral of the same profile is replaced, its closure won't be wanted anymore
+    let systemd = format!(
+        "systemctl{args} stop {timer} 2>/dev/null; exec systemd-run{args} --unit {unit} --on-calendar {calendar} --timer-property AccuracySec=1s {activate}",
+        args = systemd_args,
+        timer = crate::shell_quote(&format!("{}.timer", unit)),
+        unit = crate::shell_quote(&unit),
+        calendar = crate::shell_quote(data.calendar_event),
+        activate = data.activate_command,
+    );
+
+    // The sleeper leads its own session, so killing that takes its `sleep` along
+    let sleeper = format!(
+        "delay=$(({} - $(date +%s))); if [ \"$delay\" -gt 0 ]; then sleep \"$delay\"; fi; exec {}",
+        data.opening, data.activate_command
+    );
+    let portable = format!(
+        "{temp} || exit 1; pidfile={pidfile}; if [ -f \"$pidfile\" ]; then kill -- -\"$(cat \"$pidfile\")\" 2>/dev/null; fi; setsid sh -c {sleeper} </dev/null >{log} 2>&1 & echo $! >\"$pidfile\"",
+        temp = private_dir_script(data.temp_path),
+        pidfile = crate::shell_quote(&format!("{}/{}.pid", data.temp_path, unit)),
+        sleeper = crate::shell_quote(&sleeper),
+        log = crate::shell_quote(&format!("{}/{}.log", data.temp_path, unit)),
+    );
+
+    let deferred = match data.init_system {
+        Some(InitSystem::Systemd) => systemd,
+        Some(InitSystem::Other) => portable,
+        None => format!(
+            "if command -v systemd-run >/dev/null 2>&1 && [ -d /run/systemd/system ]; then {}; else {}; fi",
+            systemd, portable
+        ),
+    };
+
+    // Only the last line is the variables, a sudo password sudo didn't ask for can come before them
+    match data.env_file {
+        Some(env_file) => format!(
+            "{} && (umask 077; tail -n 1 >{}) || exit 1; {}",
+            private_dir_script(data.temp_path),
+            crate::shell_quote(env_file),
+            deferred
+        ),
+        None => deferred,
+    }
+}
+
+fn build_defer_command(data: &DeferCommandData) -> String {
+    let mut self_defer_command = format!("sh -c {}", crate::shell_quote(&defer_script(data)));
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_defer_command = format!("{} {}", sudo_cmd, self_defer_command);
+    }
+
+    self_defer_command
+}
+
+#[test]
+fn test_defer_command_builder() {
+    let data = DeferCommandData {
+        sudo: &Some("sudo -u root".to_string()),
+        user_units: false,
+        profile_name: "system",
+        calendar_event: "2021-03-06 02:00:00 UTC",
+        opening: 1_614_996_000,
+        temp_path: "/tmp",
+        init_system: None,
+        activate_command:
+            "/nix/store/blah/activate-rs activate '/nix/store/blah' '/nix/var/nix/profiles/system'",
+        env_file: None,
+    };
+
+    assert_eq!(
+        defer_script(&data),
+        r#"if command -v systemd-run >/dev/null 2>&1 && [ -d /run/systemd/system ]; then systemctl stop 'deploy-rs-deferred-system.timer' 2>/dev/null; exec systemd-run --unit 'deploy-rs-deferred-system' --on-calendar '2021-03-06 02:00:00 UTC' --timer-property AccuracySec=1s /nix/store/blah/activate-rs activate '/nix/store/blah' '/nix/var/nix/profiles/system'; else (umask 077; mkdir -p '/tmp') && set -- $(ls -ldn '/tmp') && case "$1:$3" in d????-??-*:"$(id -u)" | d????????[tT]*:"$(id -u)" | d????????[tT]*:0) ;; *) echo '/tmp is writable by or belongs to another user' >&2; false ;; esac || exit 1; pidfile='/tmp/deploy-rs-deferred-system.pid'; if [ -f "$pidfile" ]; then kill -- -"$(cat "$pidfile")" 2>/dev/null; fi; setsid sh -c 'delay=$((1614996000 - $(date +%s))); if [ "$delay" -gt 0 ]; then sleep "$delay"; fi; exec /nix/store/blah/activate-rs activate '\''/nix/store/blah'\'' '\''/nix/var/nix/profiles/system'\''' </dev/null >'/tmp/deploy-rs-deferred-system.log' 2>&1 & echo $! >"$pidfile"; fi"#
+    );
+    assert_eq!(
+        build_defer_command(&data),
+        format!(
+            "sudo -u root sh -c {}",
+            crate::shell_quote(&defer_script(&data))
+        )
+    );
+
+    assert!(build_defer_command(&DeferCommandData {
+        sudo: &None,
+        user_units: true,
+        profile_name: "home",
+        activate_command: "/nix/store/blah/activate-rs",
+        ..data
+    })
+    .contains("systemctl --user stop "));
+
+    assert!(defer_script(&DeferCommandData {
+        init_system: Some(InitSystem::Systemd),
+        ..data
+    })
+    .starts_with("systemctl stop "));
+    assert!(defer_script(&DeferCommandData {
+        init_system: Some(InitSystem::Other),
+        ..data
+    })
+    .starts_with(&format!(
+        "{} || exit 1; pidfile=",
+        private_dir_script("/tmp")
+    )));
+    assert!(defer_script(&DeferCommandData {
+        init_system: Some(InitSystem::Systemd),
+        env_file: Some("/tmp/deploy-rs-env-blah-system.json"),
+        ..data
+    })
+    .starts_with(&format!(
+        "{} && (umask 077; tail -n 1 >'/tmp/deploy-rs-env-blah-system.json') || exit 1; systemctl stop ",
+        private_dir_script("/tmp")
+    )));
+}
+
+struct PortableCommandData<'a> {
+    sudo: &'a Option<String>,
+    /// The image is attached under the profile's name
+    name: &'a str,
+    profile_path: &'a str,
+    closure: &'a str,
+    auto_rollback: bool,
+}
+
+/// Rolls the image back to the previous generation of the profile, or detaches it if there is none
+fn portable_rollback_script(data: &PortableCommandData) -> String {
+    let name = crate::shell_quote(data.name);
+
+    format!(
+        "if nix-env -p {profile} --rollback; then portablectl reattach --now {name}; \
+         else portablectl detach --now --enable {name}; rm -f /var/lib/portables/{name} /var/lib/portables/{name}.raw; fi",
+        profile = crate::shell_quote(data.profile_path),
+        name = name,
+    )
+}
+
+/// Runs a script for profile types which are put into use without activate-rs
+fn script_command(sudo: &Option<String>, script: &str) -> String {
+    let mut self_script_command = format!("sh -c {}", crate::shell_quote(script));
+
+    if let Some(sudo_cmd) = sudo {
+        self_script_command = format!("{} {}", sudo_cmd, self_script_command);
+    }
+
+    self_script_command
+}
+
+/// Builds the command attaching the image, or updating it if it's already attached, and (re)starting its units.
+/// The image is linked into /var/lib/portables through the profile, so rolling the profile back rolls the image back
+fn build_portable_attach_command(data: &PortableCommandData) -> String {
+    let name = crate::shell_quote(data.name);
+
+    let attach = format!(
+        "set -e; nix-env -p {profile} --set {closure}; \
+         if [ -f {closure} ]; then link=/var/lib/portables/{name}.raw; else link=/var/lib/portables/{name}; fi; \
+         mkdir -p /var/lib/portables; ln -sfn {profile} \"$link\"; \
+         if portablectl is-attached {name} >/dev/null 2>&1; then portablectl reattach --now {name}; \
+         else portablectl attach --now --enable {name}; fi",
+        profile = crate::shell_quote(data.profile_path),
+        closure = crate::shell_quote(data.closure),
+        name = name,
+    );
+
+    let script = match data.auto_rollback {
+        true => format!(
+            "( {} ) || {{ {}; exit 1; }}",
+            attach,
+            portable_rollback_script(data)
+        ),
+        false => attach,
+    };
+
+    script_command(data.sudo, &script)
+}
+
+fn build_portable_revoke_command(data: &PortableCommandData) -> String {
+    script_command(data.sudo, &portable_rollback_script(data))
+}
+
+#[test]
+fn test_portable_command_builder() {
+    let data = PortableCommandData {
+        sudo: &None,
+        name: "billing",
+        profile_path: "/nix/var/nix/profiles/billing",
+        closure: "/nix/store/blah-billing.raw",
+        auto_rollback: false,
+    };
+
+    assert_eq!(
+        build_portable_attach_command(&data),
+        r#"sh -c 'set -e; nix-env -p '\''/nix/var/nix/profiles/billing'\'' --set '\''/nix/store/blah-billing.raw'\''; if [ -f '\''/nix/store/blah-billing.raw'\'' ]; then link=/var/lib/portables/'\''billing'\''.raw; else link=/var/lib/portables/'\''billing'\''; fi; mkdir -p /var/lib/portables; ln -sfn '\''/nix/var/nix/profiles/billing'\'' "$link"; if portablectl is-attached '\''billing'\'' >/dev/null 2>&1; then portablectl reattach --now '\''billing'\''; else portablectl attach --now --enable '\''billing'\''; fi'"#
+    );
+
+    let data = PortableCommandData {
+        sudo: &Some("sudo -u root".to_string()),
+        auto_rollback: true,
+        ..data
+    };
+
+    let attach = build_portable_attach_command(&data);
+    assert!(attach.starts_with("sudo -u root sh -c '( set -e; "));
+    assert!(attach.ends_with(
+        r#"|| { if nix-env -p '\''/nix/var/nix/profiles/billing'\'' --rollback; then portablectl reattach --now '\''billing'\''; else portablectl detach --now --enable '\''billing'\''; rm -f /var/lib/portables/'\''billing'\'' /var/lib/portables/'\''billing'\''.raw; fi; exit 1; }'"#
+    ));
+
+    assert_eq!(
+        build_portable_revoke_command(&data),
+        r#"sudo -u root sh -c 'if nix-env -p '\''/nix/var/nix/profiles/billing'\'' --rollback; then portablectl reattach --now '\''billing'\''; else portablectl detach --now --enable '\''billing'\''; rm -f /var/lib/portables/'\''billing'\'' /var/lib/portables/'\''billing'\''.raw; fi'"#
+    );
+}
+
+struct NetbootCommandData<'a> {
+    sudo: &'a Option<String>,
+    /// The image is published under the profile's name
+    name: &'a str,
+    profile_path: &'a str,
+    closure: &'a str,
+    netboot_dir: &'a str,
+    auto_rollback: bool,
+}
+
+/// Publishes whatever the profile points at. The files are copied out of the store, so a TFTP or HTTP server
+/// serving the directory doesn't need to see /nix/store, and are switched to with a single rename, so clients
+/// never boot a kernel and initrd of different generations
+fn netboot_publish_script(data: &NetbootCommandData) -> String {
+    let dir = crate::shell_quote(data.netboot_dir);
+    let name = crate::shell_quote(data.name);
+
+    format!(
+        "target=$(readlink -f {profile}); version=$(basename \"$target\"); \
+         previous=$(basename \"$(readlink {dir}/{name})\" 2>/dev/null); \
+         mkdir -p {dir}/{name}.versions; \
+         if [ ! -d {dir}/{name}.versions/\"$version\" ]; then \
+         rm -rf {dir}/{name}.versions/\"$version\".tmp; cp -RL \"$target\" {dir}/{name}.versions/\"$version\".tmp; \
+         chmod -R u+w,a+rX {dir}/{name}.versions/\"$version\".tmp; \
+         mv {dir}/{name}.versions/\"$version\".tmp {dir}/{name}.versions/\"$version\"; fi; \
+         ln -sfn {name}.versions/\"$version\" {dir}/{name}.tmp; mv -Tf {dir}/{name}.tmp {dir}/{name}; \
+         printf '#!ipxe\\nchain %s/netboot.ipxe\\n' {name} > {dir}/{name}.ipxe.tmp; \
+         mv -f {dir}/{name}.ipxe.tmp {dir}/{name}.ipxe; \
+         for v in {dir}/{name}.versions/*; do case $(basename \"$v\") in \
+         \"$version\"|\"$previous\") ;; *) rm -rf \"$v\";; esac; done",
+        profile = crate::shell_quote(data.profile_path),
+        dir = dir,
+        name = name,
+    )
+}
+
+/// Publishes the previous generation of the profile again, or unpublishes the image if there is none
+fn netboot_rollback_script(data: &NetbootCommandData) -> String {
+    let dir = crate::shell_quote(data.netboot_dir);
+    let name = crate::shell_quote(data.name);
+
+    format!(
+        "if nix-env -p {profile} --rollback; then {publish}; \
+         else rm -rf {dir}/{name} {dir}/{name}.ipxe {dir}/{name}.versions; fi",
+        profile = crate::shell_quote(data.profile_path),
+        publish = netboot_publish_script(data),
+        dir = dir,
+        name = name,
+    )
+}
+
+/// Builds the command installing the image into the profile and publishing it into the netboot directory
+fn build_netboot_publish_command(data: &NetbootCommandData) -> String {
+    let publish = format!(
+        "set -e; nix-env -p {profile} --set {closure}; {publish}",
+        profile = crate::shell_quote(data.profile_path),
+        closure = crate::shell_quote(data.closure),
+        publish = netboot_publish_script(data),
+    );
+
+    let script = match data.auto_rollback {
+        true => format!(
+            "( {} ) || {{ {}; exit 1; }}",
+            publish,
+            netboot_rollback_script(data)
+        ),
+        false => publish,
+    };
+
+    script_command(data.sudo, &script)
+}
+
+fn build_netboot_revoke_command(data: &NetbootCommandData) -> String {
+    script_command(data.sudo, &netboot_rollback_script(data))
+}
+
+#[test]
+fn test_netboot_command_builder() {
+    let data = NetbootCommandData {
+        sudo: &None,
+        name: "installer",
+        profile_path: "/nix/var/nix/profiles/installer",
+        closure: "/nix/store/blah-netboot",
+        netboot_dir: "/srv/tftp",
+        auto_rollback: false,
+    };
+
+    let publish = build_netboot_publish_command(&data);
+    assert!(publish.starts_with(
+        r#"sh -c 'set -e; nix-env -p '\''/nix/var/nix/profiles/installer'\'' --set '\''/nix/store/blah-netboot'\''; target=$(readlink -f '\''/nix/var/nix/profiles/installer'\''); "#
+    ));
+    assert!(publish.contains(
+        r#"ln -sfn '\''installer'\''.versions/"$version" '\''/srv/tftp'\''/'\''installer'\''.tmp; mv -Tf '\''/srv/tftp'\''/'\''installer'\''.tmp '\''/srv/tftp'\''/'\''installer'\''; "#
+    ));
+    assert!(
+        publish.contains(r#"printf '\''#!ipxe\nchain %s/netboot.ipxe\n'\'' '\''installer'\'' > "#)
+    );
+
+    let data = NetbootCommandData {
+        sudo: &Some("sudo -u root".to_string()),
+        auto_rollback: true,
+        ..data
+    };
+
+    assert!(build_netboot_publish_command(&data).starts_with("sudo -u root sh -c '( set -e; "));
+
+    let revoke = build_netboot_revoke_command(&data);
+    assert!(revoke.starts_with(
+        r#"sudo -u root sh -c 'if nix-env -p '\''/nix/var/nix/profiles/installer'\'' --rollback; then target="#
+    ));
+    assert!(revoke.ends_with(
+        r#"else rm -rf '\''/srv/tftp'\''/'\''installer'\'' '\''/srv/tftp'\''/'\''installer'\''.ipxe '\''/srv/tftp'\''/'\''installer'\''.versions; fi'"#
+    ));
+}
+
+struct BlueGreenCommandData<'a> {
+    sudo: &'a Option<String>,
+    /// The instances are run as `deploy-rs-<name>-blue` and `deploy-rs-<name>-green`
+    name: &'a str,
+    profile_path: &'a str,
+    closure: &'a str,
+    /// Relative to the closure
+    command: &'a str,
+    health_check: Option<&'a str>,
+    health_timeout: u32,
+    ports: Option<[u16; 2]>,
+    /// A shell word, as the default is under `$HOME`
+    state_dir: String,
+    auto_rollback: bool,
+}
+
+/// How long a blue-green instance has to get healthy by default, in seconds
+pub const DEFAULT_HEALTH_TIMEOUT: u32 = 30;
+
+/// What a blue-green profile's `blueGreen.command` is, relative to its closure
+pub fn blue_green_command(deploy_data: &super::DeployData<'_>) -> String {
+    deploy_data
+        .profile
+        .profile_settings
+        .blue_green
+        .as_ref()
+        .and_then(|x| x.command.clone())
+        .unwrap_or_else(|| format!("bin/{}", deploy_data.profile_name))
+}
+
+/// Starts the closure the shell expression `closure` gives in the slot that isn't current, waits for it to be
+/// healthy, switches the `current` link over to it with a single rename and stops the instance it replaced. A new
+/// instance that doesn't get healthy is stopped, leaving the old one running
+fn blue_green_switch_script(data: &BlueGreenCommandData, closure: &str) -> String {
+    let (blue_port, green_port) = match data.ports {
+        Some([blue, green]) => (blue.to_string(), green.to_string()),
+        None => (String::new(), String::new()),
+    };
+    let health_check = match data.health_check {
+        Some(x) => format!("sh -c {}", crate::shell_quote(x)),
+        None => "systemctl --user is-active --quiet \"deploy-rs-$name-$new\"".to_string(),
+    };
+
+    format!(
+        "export XDG_RUNTIME_DIR=\"${{XDG_RUNTIME_DIR:-/run/user/$(id -u)}}\"; \
+         name={name}; dir={dir}; closure={closure}; mkdir -p \"$dir\"; \
+         old=$(basename \"$(readlink \"$dir/current\")\" 2>/dev/null); \
+         if [ \"$old\" = blue ]; then new=green; PORT={green_port}; else new=blue; PORT={blue_port}; fi; \
+         SLOT=$new; SOCKET=\"$dir/$new/socket\"; export PORT SLOT SOCKET; \
+         systemctl --user stop \"deploy-rs-$name-$new\" 2>/dev/null; systemctl --user reset-failed \"deploy-rs-$name-$new\" 2>/dev/null; \
+         rm -rf \"$dir/$new\"; mkdir -p \"$dir/$new\"; ln -sfn \"$closure\" \"$dir/$new/closure\"; \
+         systemd-run --user --unit=\"deploy-rs-$name-$new\" --working-directory=\"$dir/$new\" \
+         --setenv=PORT=\"$PORT\" --setenv=SLOT=\"$SLOT\" --setenv=SOCKET=\"$SOCKET\" \"$closure\"/{command}; \
+         i=0; until {health_check}; do i=$((i+1)); if [ \"$i\" -ge {timeout} ]; then \
+         echo \"The $new instance didn't get healthy within {timeout} seconds\" >&2; \
+         systemctl --user stop \"deploy-rs-$name-$new\"; exit 1; fi; sleep 1; done; \
+         ln -sfn \"$new\" \"$dir/current.tmp\"; mv -Tf \"$dir/current.tmp\" \"$dir/current\"; \
+         if [ -n \"$old\" ]; then systemctl --user stop \"deploy-rs-$name-$old\"; fi",
+        name = crate::shell_quote(data.name),
+        dir = data.state_dir,
+        closure = closure,
+        blue_port = blue_port,
+        green_port = green_port,
+        command = crate::shell_quote(data.command),
+        health_check = health_check,
+        timeout = data.health_timeout,
+    )
+}
+
+/// Switches back to the previous generation of the profile, or stops both instances if there is none
+fn blue_green_rollback_script(data: &BlueGreenCommandData) -> String {
+    format!(
+        "if nix-env -p {profile} --rollback; then {switch}; \
+         else systemctl --user stop {blue} {green}; rm -f {dir}/current; fi",
+        profile = crate::shell_quote(data.profile_path),
+        switch = blue_green_switch_script(
+            data,
+            &format!("$(readlink -f {})", crate::shell_quote(data.profile_path))
+        ),
+        blue = crate::shell_quote(&format!("deploy-rs-{}-blue", data.name)),
+        green = crate::shell_quote(&format!("deploy-rs-{}-green", data.name)),
+        dir = data.state_dir,
+    )
+}
+
+/// Builds the command installing the closure into the profile and switching to it. A switch that fails leaves the
+/// old instance running, so rolling back only has to put the profile back
+fn build_blue_green_activate_command(data: &BlueGreenCommandData) -> String {
+    let activate = format!(
+        "nix-env -p {profile} --set {closure} && {{ {switch}; }}",
+        profile = crate::shell_quote(data.profile_path),
+        closure = crate::shell_quote(data.closure),
+        switch = blue_green_switch_script(data, &crate::shell_quote(data.closure)),
+    );
+
+    let script = match data.auto_rollback {
+        true => format!(
+            "( {} ) || {{ nix-env -p {} --rollback; exit 1; }}",
+            activate,
+            crate::shell_quote(data.profile_path)
+        ),
+        false => activate,
+    };
+
+    script_command(data.sudo, &script)
+}
+
+fn build_blue_green_revoke_command(data: &BlueGreenCommandData) -> String {
+    script_command(data.sudo, &blue_green_rollback_script(data))
+}
+
+#[test]
+fn test_blue_green_command_builder() {
+    let data = BlueGreenCommandData {
+        sudo: &Some("sudo -u app".to_string()),
+        name: "api",
+        profile_path: "/nix/var/nix/profiles/per-user/app/api",
+        closure: "/nix/store/blah-api",
+        command: "bin/api",
+        health_check: Some("curl -fsS http://localhost:$PORT/health"),
+        health_timeout: 20,
+        ports: Some([8001, 8002]),
+        state_dir: "'/srv/api'".to_string(),
+        auto_rollback: true,
+    };
+
+    let switch = blue_green_switch_script(&data, "'/nix/store/blah-api'");
+    assert!(switch.contains(
+        r#"name='api'; dir='/srv/api'; closure='/nix/store/blah-api'; mkdir -p "$dir"; "#
+    ));
+    assert!(switch.contains(
+        r#"if [ "$old" = blue ]; then new=green; PORT=8002; else new=blue; PORT=8001; fi; "#
+    ));
+    assert!(switch.contains(r#"--setenv=SOCKET="$SOCKET" "$closure"/'bin/api'; "#));
+    assert!(switch.contains(
+        r#"i=0; until sh -c 'curl -fsS http://localhost:$PORT/health'; do i=$((i+1)); if [ "$i" -ge 20 ]; then "#
+    ));
+    assert!(switch.ends_with(
+        r#"mv -Tf "$dir/current.tmp" "$dir/current"; if [ -n "$old" ]; then systemctl --user stop "deploy-rs-$name-$old"; fi"#
+    ));
+
+    assert!(build_blue_green_activate_command(&data).starts_with(
+        r#"sudo -u app sh -c '( nix-env -p '\''/nix/var/nix/profiles/per-user/app/api'\'' --set '\''/nix/store/blah-api'\'' && { export "#
+    ));
+    assert!(build_blue_green_activate_command(&data).ends_with(
+        r#"fi; } ) || { nix-env -p '\''/nix/var/nix/profiles/per-user/app/api'\'' --rollback; exit 1; }'"#
+    ));
+
+    let revoke = blue_green_rollback_script(&BlueGreenCommandData {
+        health_check: None,
+        ..data
+    });
+    assert!(revoke.contains(r#"closure=$(readlink -f '/nix/var/nix/profiles/per-user/app/api'); "#));
+    assert!(
+        revoke.contains(r#"until systemctl --user is-active --quiet "deploy-rs-$name-$new"; do "#)
+    );
+    assert!(revoke.ends_with(
+        "else systemctl --user stop 'deploy-rs-api-blue' 'deploy-rs-api-green'; rm -f '/srv/api'/current; fi"
+    ));
 }
 
 struct WaitCommandData<'a> {
     sudo: &'a Option<String>,
     closure: &'a str,
+    /// Unset for an activate-rs that doesn't know `--profile-path` yet
+    profile_path: Option<&'a str>,
     temp_path: &'a str,
+    timeout: Option<u32>,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    nix_env: NixEnv<'a>,
 }
 
 fn build_wait_command(data: &WaitCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command = activate_rs(data.closure, data.nix_env);
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
@@ -114,11 +885,32 @@ fn build_wait_command(data: &WaitCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
+    self_activate_command = crate::with_output_style(&self_activate_command);
+
+    self_activate_command = format!(
+        "{} wait {}",
+        self_activate_command,
+        crate::shell_quote(data.closure)
+    );
+
+    if let Some(profile_path) = data.profile_path {
+        self_activate_command = format!(
+            "{} --profile-path {}",
+            self_activate_command,
+            crate::shell_quote(profile_path)
+        );
+    }
+
     self_activate_command = format!(
-        "{} wait '{}' --temp-path '{}'",
-        self_activate_command, data.closure, data.temp_path,
+        "{} --temp-path {}",
+        self_activate_command,
+        crate::shell_quote(data.temp_path)
     );
 
+    if let Some(timeout) = data.timeout {
+        self_activate_command = format!("{} --timeout {}", self_activate_command, timeout);
+    }
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -138,13 +930,45 @@ fn test_wait_command_builder() {
         build_wait_command(&WaitCommandData {
             sudo: &sudo,
             closure,
+            profile_path: Some("/nix/var/nix/profiles/system"),
             temp_path,
+            timeout: None,
             debug_logs,
-            log_dir
+            log_dir,
+            nix_env: NixEnv::default()
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt wait '/nix/store/blah/etc' --profile-path '/nix/var/nix/profiles/system' --temp-path '/tmp'"
+            .to_string(),
+    );
+
+    assert_eq!(
+        build_wait_command(&WaitCommandData {
+            sudo: &None,
+            closure,
+            profile_path: Some("/nix/var/nix/profiles/system"),
+            temp_path,
+            timeout: Some(1890),
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv::default()
         }),
-        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt wait '/nix/store/blah/etc' --temp-path '/tmp'"
+        "/nix/store/blah/etc/activate-rs wait '/nix/store/blah/etc' --profile-path '/nix/var/nix/profiles/system' --temp-path '/tmp' --timeout 1890"
             .to_string(),
     );
+
+    assert_eq!(
+        build_wait_command(&WaitCommandData {
+            sudo: &None,
+            closure,
+            profile_path: None,
+            temp_path,
+            timeout: None,
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv::default()
+        }),
+        "/nix/store/blah/etc/activate-rs wait '/nix/store/blah/etc' --temp-path '/tmp'".to_string(),
+    );
 }
 
 struct RevokeCommandData<'a> {
@@ -153,10 +977,11 @@ struct RevokeCommandData<'a> {
     profile_path: &'a str,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    nix_env: NixEnv<'a>,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
-    let mut self_activate_command = format!("{}/activate-rs", data.closure);
+    let mut self_activate_command = activate_rs(data.closure, data.nix_env);
 
     if data.debug_logs {
         self_activate_command = format!("{} --debug-logs", self_activate_command);
@@ -166,7 +991,15 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
         self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
     }
 
-    self_activate_command = format!("{} revoke '{}'", self_activate_command, data.profile_path);
+    self_activate_command = crate::with_output_style(&self_activate_command);
+
+    self_activate_command = format!(
+        "{} revoke {}",
+        self_activate_command,
+        crate::shell_quote(data.profile_path)
+    );
+
+    self_activate_command = with_nix_env(data.nix_env, self_activate_command);
 
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
@@ -189,39 +1022,349 @@ fn test_revoke_command_builder() {
             closure,
             profile_path,
             debug_logs,
-            log_dir
+            log_dir,
+            nix_env: NixEnv::default()
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke '/nix/var/nix/per-user/user/profile'"
             .to_string(),
     );
-}
 
-#[derive(Error, Debug)]
-pub enum ConfirmProfileError {
-    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
-    SSHConfirm(std::io::Error),
-    #[error(
-        "Confirming activation over SSH resulted in a bad exit code (the server should roll back): {0:?}"
-    )]
-    SSHConfirmExit(Option<i32>),
-}
+    assert_eq!(
+        build_revoke_command(&RevokeCommandData {
+            sudo: &sudo,
+            closure,
+            profile_path,
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv {
+                path: Some("/volume1/@nix/bin"),
+                store_root: None
+            }
+        }),
+        "sudo -u test env PATH='/volume1/@nix/bin':\"$PATH\" /nix/store/blah/etc/activate-rs revoke '/nix/var/nix/per-user/user/profile'"
+            .to_string(),
+    );
 
-pub async fn confirm_profile(
-    deploy_data: &super::DeployData<'_>,
+    assert_eq!(
+        build_revoke_command(&RevokeCommandData {
+            sudo: &None,
+            closure,
+            profile_path,
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv {
+                path: None,
+                store_root: Some("/home/user/.nix")
+            }
+        }),
+        "env NIX_REMOTE='local?root=/home/user/.nix' nix --extra-experimental-features nix-command shell '/nix/store/blah/etc' --command /nix/store/blah/etc/activate-rs revoke '/nix/var/nix/per-user/user/profile'"
+            .to_string(),
+    );
+}
+
+struct AbortCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    profile_path: &'a str,
+    temp_path: &'a str,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    nix_env: NixEnv<'a>,
+}
+
+fn build_abort_command(data: &AbortCommandData) -> String {
+    let mut self_activate_command = activate_rs(data.closure, data.nix_env);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    self_activate_command = crate::with_output_style(&self_activate_command);
+
+    self_activate_command = format!(
+        "{} abort {} --profile-path {} --temp-path {}",
+        self_activate_command,
+        crate::shell_quote(data.closure),
+        crate::shell_quote(data.profile_path),
+        crate::shell_quote(data.temp_path),
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_abort_command_builder() {
+    assert_eq!(
+        build_abort_command(&AbortCommandData {
+            sudo: &Some("sudo -u test".to_string()),
+            closure: "/nix/store/blah/etc",
+            profile_path: "/nix/var/nix/profiles/system",
+            temp_path: "/tmp",
+            debug_logs: false,
+            log_dir: None,
+            nix_env: NixEnv::default(),
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs abort '/nix/store/blah/etc' --profile-path '/nix/var/nix/profiles/system' --temp-path '/tmp'"
+            .to_string(),
+    );
+}
+
+/// Whether the closure's activate-rs names canaries after the profile too, which came with `wait --profile-path`. The
+/// deployer is often newer than the `deploy-rs` a closure was built with, and its canaries are where an older one
+/// looks for them
+async fn knows_profile_canaries(
+    deploy_data: &super::DeployData<'_>,
     deploy_defs: &super::DeployDefs,
-    temp_path: Cow<'_, str>,
-    ssh_addr: &str,
-) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command.arg(ssh_addr);
+) -> bool {
+    let nix_env = NixEnv::new(&deploy_data.merged_settings);
+    let command = with_nix_env(
+        nix_env,
+        format!(
+            "{} wait --help",
+            activate_rs(&deploy_data.profile.profile_settings.path, nix_env)
+        ),
+    );
+
+    match run_on_node(deploy_data, deploy_defs, command, true).await {
+        Ok(output) if output.code == Some(0) => {
+            let knows = String::from_utf8_lossy(&output.stdout).contains("--profile-path");
+            if !knows {
+                debug!(
+                    "activate-rs of profile `{}` of node `{}` predates canaries named after profiles",
+                    deploy_data.profile_name, deploy_data.node_name
+                );
+            }
+            knows
+        }
+        // Whatever kept this from running will fail the activation too, and say why
+        _ => true,
+    }
+}
+
+/// Stops an activation the deployer gave up on, which would otherwise carry on by itself on the node
+async fn abort_activation(deploy_data: &super::DeployData<'_>, deploy_defs: &super::DeployDefs) {
+    let self_abort_command = build_abort_command(&AbortCommandData {
+        sudo: &deploy_defs.sudo,
+        closure: &deploy_data.profile.profile_settings.path,
+        profile_path: &deploy_defs.profile_path,
+        temp_path: &deploy_defs.temp_path,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        nix_env: NixEnv::new(&deploy_data.merged_settings),
+    });
+
+    debug!("Constructed abort command: {}", self_abort_command);
+
+    match run_on_node(deploy_data, deploy_defs, self_abort_command, false).await {
+        Ok(output) if output.code == Some(0) => {
+            info!("Stopped the unfinished activation on the node")
+        }
+        Ok(output) => warn!(
+            "Failed to stop the unfinished activation on the node, exit code {:?}",
+            output.code
+        ),
+        Err(err) => warn!(
+            "Failed to stop the unfinished activation on the node: {}",
+            err
+        ),
+    }
+}
+
+struct DiffCommandData<'a> {
+    sudo: &'a Option<String>,
+    closure: &'a str,
+    profile_path: &'a str,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+    subcommand: &'a str,
+    nix_env: NixEnv<'a>,
+}
+
+fn build_diff_command(data: &DiffCommandData) -> String {
+    let mut self_activate_command = activate_rs(data.closure, data.nix_env);
+
+    if data.debug_logs {
+        self_activate_command = format!("{} --debug-logs", self_activate_command);
+    }
+
+    if let Some(log_dir) = data.log_dir {
+        self_activate_command = format!("{} --log-dir {}", self_activate_command, log_dir);
+    }
+
+    self_activate_command = crate::with_output_style(&self_activate_command);
+
+    self_activate_command = format!(
+        "{} {} {} {}",
+        self_activate_command,
+        data.subcommand,
+        crate::shell_quote(data.closure),
+        crate::shell_quote(data.profile_path)
+    );
+
+    self_activate_command = with_nix_env(data.nix_env, self_activate_command);
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
+    }
+
+    self_activate_command
+}
+
+#[test]
+fn test_diff_command_builder() {
+    let sudo = Some("sudo -u test".to_string());
+    let closure = "/nix/store/blah/etc";
+    let profile_path = "/nix/var/nix/profiles/system";
+    let debug_logs = true;
+    let log_dir = Some("/tmp/something.txt");
+
+    assert_eq!(
+        build_diff_command(&DiffCommandData {
+            sudo: &sudo,
+            closure,
+            profile_path,
+            debug_logs,
+            log_dir,
+            nix_env: NixEnv::default(),
+            subcommand: "diff-etc",
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt diff-etc '/nix/store/blah/etc' '/nix/var/nix/profiles/system'"
+            .to_string(),
+    );
+}
+
+/// Runs a command on the node, through its agent if it has one and over SSH otherwise
+async fn run_on_node(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    command: String,
+    capture_stdout: bool,
+) -> Result<Output, RunOnNodeError> {
+    let target = deploy_data.node_target(deploy_defs)?;
+
+    deploy_data
+        .transport
+        .run_on_node(&target, &command, capture_stdout)
+        .await
+}
+
+/// Runs a command on the node like `run_on_node`, with `input` on its standard input
+async fn run_on_node_with_input(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    command: String,
+    input: &[u8],
+) -> Result<Output, RunOnNodeError> {
+    let target = deploy_data.node_target(deploy_defs)?;
+
+    deploy_data
+        .transport
+        .run_on_node_with_input(&target, &command, input, false)
+        .await
+}
+
+/// SSH options forwarding a profile's `reverseTunnel` from the node back to the deploying machine, failing the
+/// connection if the port can't be forwarded
+fn reverse_tunnel_opts(ssh_opts: &[String], tunnel: &ReverseTunnel) -> Vec<String> {
+    let mut opts = ssh_opts.to_vec();
+    opts.extend(vec![
+        "-o".to_string(),
+        "ExitOnForwardFailure=yes".to_string(),
+        "-R".to_string(),
+        format!("{}:127.0.0.1:{}", tunnel.remote_port, tunnel.local_port),
+    ]);
+    opts
+}
+
+#[test]
+fn test_reverse_tunnel_opts() {
+    assert_eq!(
+        reverse_tunnel_opts(
+            &["-p".to_string(), "2222".to_string()],
+            &ReverseTunnel {
+                remote_port: 5000,
+                local_port: 8080,
+            }
+        ),
+        vec![
+            "-p",
+            "2222",
+            "-o",
+            "ExitOnForwardFailure=yes",
+            "-R",
+            "5000:127.0.0.1:8080"
+        ]
+    );
+}
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
+/// Runs the activation command with `input` on its standard input, over a connection carrying the profile's `reverseTunnel`
+/// if it has one
+async fn run_activation_on_node(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    command: String,
+    input: &[u8],
+) -> Result<Output, RunOnNodeError> {
+    let tunnel_opts;
+    let mut target = deploy_data.node_target(deploy_defs)?;
+
+    if let Some(ref tunnel) = deploy_data.merged_settings.reverse_tunnel {
+        match target {
+            NodeTarget::Ssh { ref mut opts, .. } => {
+                tunnel_opts = reverse_tunnel_opts(opts, tunnel);
+                *opts = &tunnel_opts;
+            }
+            NodeTarget::Agent(_) => warn!(
+                "Node `{}` is deployed to through its agent, which can't forward `reverseTunnel`",
+                deploy_data.node_name
+            ),
+        }
+    }
+
+    deploy_data
+        .transport
+        .run_on_node_with_input(&target, &command, input, false)
+        .await
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmProfileError {
+    #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
+    SSHConfirm(RunOnNodeError),
+    #[error("Confirming activation over SSH failed with {0} (the server should roll back)")]
+    SSHConfirmExit(CommandFailure),
+}
+
+impl Diagnostic for ConfirmProfileError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        match self {
+            ConfirmProfileError::SSHConfirmExit(f) => Some(f),
+            _ => None,
+        }
     }
 
-    let lock_path = super::make_lock_path(&temp_path, &deploy_data.profile.profile_settings.path);
+    fn hint(&self) -> Option<&'static str> {
+        Some(
+            "The new profile may have cut off access to the node, e.g. through its firewall or SSH settings; \
+             check that it can still be reached once it has rolled back",
+        )
+    }
+}
 
-    let mut confirm_command = format!("rm {}", lock_path);
+pub async fn confirm_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    lock_path: &str,
+) -> Result<(), ConfirmProfileError> {
+    let mut confirm_command = format!("rm {}", crate::shell_quote(lock_path));
     if let Some(sudo_cmd) = &deploy_defs.sudo {
         confirm_command = format!("{} {}", sudo_cmd, confirm_command);
     }
@@ -231,15 +1374,18 @@ pub async fn confirm_profile(
         confirm_command
     );
 
-    let ssh_confirm_exit_status = ssh_confirm_command
-        .arg(confirm_command)
-        .status()
+    let ssh_confirm_output = run_on_node(deploy_data, deploy_defs, confirm_command.clone(), false)
         .await
         .map_err(ConfirmProfileError::SSHConfirm)?;
 
-    match ssh_confirm_exit_status.code() {
+    match ssh_confirm_output.code {
         Some(0) => (),
-        a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
+        _ => {
+            return Err(ConfirmProfileError::SSHConfirmExit(CommandFailure::new(
+                confirm_command,
+                &ssh_confirm_output,
+            )))
+        }
     };
 
     info!("Deployment confirmed.");
@@ -253,214 +1399,2187 @@ pub enum DeployProfileError {
     SSHSpawnActivate(std::io::Error),
 
     #[error("Failed to run activation command over SSH: {0}")]
-    SSHActivate(std::io::Error),
-    #[error("Activating over SSH resulted in a bad exit code: {0:?}")]
-    SSHActivateExit(Option<i32>),
+    SSHActivate(RunOnNodeError),
+    #[error("Activating over SSH failed with {0}")]
+    SSHActivateExit(CommandFailure),
+
+    #[error("Activation didn't finish within {0} seconds and was stopped on the node")]
+    ActivationTimeout(u32),
 
     #[error("Failed to run wait command over SSH: {0}")]
-    SSHWait(std::io::Error),
-    #[error("Waiting over SSH resulted in a bad exit code: {0:?}")]
-    SSHWaitExit(Option<i32>),
+    SSHWait(RunOnNodeError),
+    #[error("Waiting over SSH failed with {0}")]
+    SSHWaitExit(CommandFailure),
 
     #[error("Error confirming deployment: {0}")]
     Confirm(#[from] ConfirmProfileError),
+
+    #[error("`{0}` is not a valid name for a variable of the activation environment")]
+    ActivationEnvName(String),
+    #[error("Failed to get the value of activation variable `{0}`: {1}")]
+    ActivationEnv(String, Box<crate::secrets::SecretError>),
+
+    #[error("Failed to run post-activation check `{0}`: {1}")]
+    PostActivateCheck(String, std::io::Error),
+    #[error("Post-activation check failed with {0}, the deployment is not confirmed")]
+    PostActivateCheckExit(CommandFailure),
+
+    #[error("Failed to run the command deferring activation over SSH: {0}")]
+    SSHDefer(RunOnNodeError),
+    #[error("Deferring activation over SSH failed with {0}")]
+    SSHDeferExit(CommandFailure),
+
+    #[error("Failed to run the activation command locally: {0}")]
+    LocalActivate(std::io::Error),
+    #[error("Local activation failed with {0}")]
+    LocalActivateExit(CommandFailure),
+
+    #[error("Failed to run `migrations` over SSH: {0}")]
+    SSHMigrate(RunOnNodeError),
+    #[error("Migrating failed with {0}, nothing was activated")]
+    SSHMigrateExit(CommandFailure),
+    #[error("Migrating didn't finish within {0} seconds and was stopped, nothing was activated")]
+    MigrationTimeout(u32),
+    #[error("The profile has `migrations`, which aren't run when activation is deferred")]
+    MigrationsDeferred,
+
+    #[error("Failed to snapshot `snapshots` over SSH: {0}")]
+    SSHSnapshot(RunOnNodeError),
+    #[error("Snapshotting `snapshots` failed with {0}, nothing was activated")]
+    SSHSnapshotExit(CommandFailure),
 }
 
-pub async fn deploy_profile(
-    deploy_data: &super::DeployData<'_>,
-    deploy_defs: &super::DeployDefs,
-    dry_activate: bool,
-) -> Result<(), DeployProfileError> {
-    if !dry_activate {
-        info!(
-            "Activating profile `{}` for node `{}`",
-            deploy_data.profile_name, deploy_data.node_name
-        );
+impl Diagnostic for DeployProfileError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        match self {
+            DeployProfileError::SSHActivateExit(f)
+            | DeployProfileError::SSHWaitExit(f)
+            | DeployProfileError::PostActivateCheckExit(f)
+            | DeployProfileError::SSHDeferExit(f)
+            | DeployProfileError::LocalActivateExit(f)
+            | DeployProfileError::SSHSnapshotExit(f)
+            | DeployProfileError::SSHMigrateExit(f) => Some(f),
+            DeployProfileError::Confirm(e) => e.failure(),
+            DeployProfileError::ActivationEnv(_, e) => match **e {
+                crate::secrets::SecretError::Exit(ref f) => Some(f),
+                _ => None,
+            },
+            _ => None,
+        }
     }
 
-    let temp_path: Cow<str> = match &deploy_data.merged_settings.temp_path {
-        Some(x) => x.into(),
-        None => "/tmp".into(),
-    };
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            DeployProfileError::SSHActivateExit(_) => Some(
+                "The activation script's output is above, `--debug-logs` shows what activate-rs did on the node",
+            ),
+            DeployProfileError::SSHWaitExit(_) | DeployProfileError::ActivationTimeout(_) => Some(
+                "The node didn't report a finished activation in time, set `activationTimeout` if activating takes long",
+            ),
+            DeployProfileError::PostActivateCheckExit(_) => Some(
+                "The node rolls back by itself once `confirmTimeout` has passed, the check's output is above",
+            ),
+            DeployProfileError::SSHDeferExit(_) => Some(
+                "Deferring activation uses a systemd timer where systemd runs, which needs lingering (`loginctl enable-linger`) for profiles of users other than root, and `setsid` everywhere else",
+            ),
+            DeployProfileError::SSHMigrateExit(_) | DeployProfileError::MigrationTimeout(_) => Some(
+                "The migration's output is above, the node still runs the profile it ran before",
+            ),
+            DeployProfileError::MigrationsDeferred => Some(
+                "Activate it inside the node's maintenance window, or with --force, so its migrations run right before it",
+            ),
+            DeployProfileError::SSHSnapshotExit(_) => Some(
+                "Check that the datasets and subvolumes of `snapshots` exist, and that no snapshot of this run is there already",
+            ),
+            DeployProfileError::Confirm(e) => e.hint(),
+            _ => None,
+        }
+    }
+}
 
-    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+/// The name of the snapshots of `snapshots` taken when deploying the profile in this run, `None` when it's not known
+/// which run that is
+fn snapshot_name(deploy_data: &super::DeployData<'_>) -> Option<String> {
+    let deployment_id = deploy_data.cmd_overrides.deployment_id.as_deref()?;
 
-    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+    Some(crate::snapshot::snapshot_name(
+        deployment_id,
+        deploy_data.profile_name,
+    ))
+}
 
-    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+/// Snapshots the profile's `snapshots` as root, before it's activated, giving the name of the snapshots if it took any
+async fn take_snapshots(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<Option<String>, DeployProfileError> {
+    let snapshots = &deploy_data.merged_settings.snapshots;
 
-    let self_activate_command = build_activate_command(&ActivateCommandData {
-        sudo: &deploy_defs.sudo,
-        profile_path: &deploy_defs.profile_path,
-        closure: &deploy_data.profile.profile_settings.path,
-        auto_rollback,
-        temp_path: &temp_path,
-        confirm_timeout,
-        magic_rollback,
-        debug_logs: deploy_data.debug_logs,
-        log_dir: deploy_data.log_dir,
-        dry_activate,
+    if snapshots.is_empty() {
+        return Ok(None);
+    }
+
+    // Deploying without a run to name them after, as libraries do, the time tells them apart
+    let name = snapshot_name(deploy_data).unwrap_or_else(|| {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        crate::snapshot::snapshot_name(&now.to_string(), deploy_data.profile_name)
     });
 
-    debug!("Constructed activation command: {}", self_activate_command);
+    info!(
+        "Snapshotting {} datasets and subvolumes of node `{}` as `{}`",
+        snapshots.len(),
+        deploy_data.node_name,
+        name
+    );
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
+    let command = script_command(
+        &deploy_data.sudo_for(deploy_defs, "root"),
+        &crate::snapshot::take_script(snapshots, &name),
+    );
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    debug!("Constructed snapshot command: {}", command);
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command.arg(&ssh_addr);
+    let output = run_on_node(deploy_data, deploy_defs, command.clone(), false)
+        .await
+        .map_err(DeployProfileError::SSHSnapshot)?;
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+    match output.code {
+        Some(0) => Ok(Some(name)),
+        _ => Err(DeployProfileError::SSHSnapshotExit(CommandFailure::new(
+            command, &output,
+        ))),
     }
+}
 
-    if !magic_rollback || dry_activate {
-        let ssh_activate_exit_status = ssh_activate_command
-            .arg(self_activate_command)
-            .status()
-            .await
-            .map_err(DeployProfileError::SSHActivate)?;
+/// How long `migrations` may run by default, in seconds
+pub const DEFAULT_MIGRATION_TIMEOUT: u32 = 600;
+
+/// The exit code of `timeout` when it had to stop the command
+const TIMEOUT_EXIT: i32 = 124;
+
+fn build_migrate_command(
+    sudo: &Option<String>,
+    migrations: &crate::data::Migrations,
+    closure: &str,
+    profile_name: &str,
+) -> String {
+    let mut command = format!(
+        "env DEPLOY_PROFILE={} DEPLOY_CLOSURE={} timeout {} sh -c {}",
+        crate::shell_quote(profile_name),
+        crate::shell_quote(closure),
+        migrations.timeout.unwrap_or(DEFAULT_MIGRATION_TIMEOUT),
+        crate::shell_quote(&migrations.command)
+    );
 
-        match ssh_activate_exit_status.code() {
-            Some(0) => (),
-            a => return Err(DeployProfileError::SSHActivateExit(a)),
-        };
+    if let Some(sudo_cmd) = sudo {
+        command = format!("{} {}", sudo_cmd, command);
+    }
 
-        if dry_activate {
-            info!("Completed dry-activate!");
-        } else {
-            info!("Success activating, done!");
-        }
-    } else {
-        let self_wait_command = build_wait_command(&WaitCommandData {
-            sudo: &deploy_defs.sudo,
-            closure: &deploy_data.profile.profile_settings.path,
-            temp_path: &temp_path,
-            debug_logs: deploy_data.debug_logs,
-            log_dir: deploy_data.log_dir,
-        });
+    command
+}
 
-        debug!("Constructed wait command: {}", self_wait_command);
+#[test]
+fn test_migrate_command_builder() {
+    let migrations = crate::data::Migrations {
+        command: "$DEPLOY_CLOSURE/bin/migrate --to latest".to_string(),
+        timeout: None,
+    };
 
-        let ssh_activate = ssh_activate_command
-            .arg(self_activate_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHSpawnActivate)?;
+    assert_eq!(
+        build_migrate_command(
+            &Some("sudo -u gitea".to_string()),
+            &migrations,
+            "/nix/store/blah/etc",
+            "gitea"
+        ),
+        "sudo -u gitea env DEPLOY_PROFILE='gitea' DEPLOY_CLOSURE='/nix/store/blah/etc' timeout 600 sh -c '$DEPLOY_CLOSURE/bin/migrate --to latest'"
+    );
+}
 
-        info!("Creating activation waiter");
+/// Runs the profile's `migrations` on the node, once its closure is there and before it's activated. What it prints
+/// is logged apart from the rest, with the target `deploy::migrations`
+async fn run_migrations(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), DeployProfileError> {
+    let migrations = match deploy_data.merged_settings.migrations {
+        Some(ref x) => x,
+        None => return Ok(()),
+    };
 
-        let mut ssh_wait_command = Command::new("ssh");
-        ssh_wait_command.arg(&ssh_addr);
+    info!(
+        "Migrating profile `{}` of node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
 
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
+    let command = build_migrate_command(
+        &deploy_defs.sudo,
+        migrations,
+        &deploy_data.profile.profile_settings.path,
+        deploy_data.profile_name,
+    );
 
-        let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
-        let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
+    debug!("Constructed migrate command: {}", command);
 
-        let thread = tokio::spawn(async move {
-            let o = ssh_activate.wait_with_output().await;
+    let output = run_on_node(deploy_data, deploy_defs, command.clone(), true)
+        .await
+        .map_err(DeployProfileError::SSHMigrate)?;
 
-            let maybe_err = match o {
-                Err(x) => Some(DeployProfileError::SSHActivate(x)),
-                Ok(ref x) => match x.status.code() {
-                    Some(0) => None,
-                    a => Some(DeployProfileError::SSHActivateExit(a)),
-                },
-            };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!(
+            target: "deploy::migrations",
+            "[{}.{}] {}", deploy_data.node_name, deploy_data.profile_name, line
+        );
+    }
 
-            if let Some(err) = maybe_err {
-                send_activate.send(err).unwrap();
-            }
+    match output.code {
+        Some(0) => Ok(()),
+        Some(TIMEOUT_EXIT) => Err(DeployProfileError::MigrationTimeout(
+            migrations.timeout.unwrap_or(DEFAULT_MIGRATION_TIMEOUT),
+        )),
+        _ => Err(DeployProfileError::SSHMigrateExit(CommandFailure::new(
+            command, &output,
+        ))),
+    }
+}
 
-            send_activated.send(()).unwrap();
-        });
-        tokio::select! {
-            x = ssh_wait_command.arg(self_wait_command).status() => {
-                debug!("Wait command ended");
-                match x.map_err(DeployProfileError::SSHWait)?.code() {
-                    Some(0) => (),
-                    a => return Err(DeployProfileError::SSHWaitExit(a)),
-                };
-            },
-            x = recv_activate => {
-                debug!("Activate command exited with an error");
-                return Err(x.unwrap());
-            },
-        }
+/// Works out the variables of `activationEnv`, fetching those which need it on the deployer
+async fn resolve_activation_env(
+    deploy_data: &super::DeployData<'_>,
+) -> Result<Vec<(String, String)>, DeployProfileError> {
+    let mut env = Vec::new();
 
-        info!("Success activating, attempting to confirm activation");
+    for (key, value) in &deploy_data.merged_settings.activation_env {
+        let valid_name = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !key.starts_with(|c: char| c.is_ascii_digit());
 
-        let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
-        recv_activated.await.unwrap();
-        c?;
+        if key.is_empty() || !valid_name {
+            return Err(DeployProfileError::ActivationEnvName(key.clone()));
+        }
 
-        thread
+        let value = crate::secrets::resolve(deploy_data.transport, value)
             .await
-            .map_err(|x| DeployProfileError::SSHActivate(x.into()))?;
+            .map_err(|e| DeployProfileError::ActivationEnv(key.clone(), Box::new(e)))?;
+
+        env.push((key.clone(), value));
     }
 
-    Ok(())
+    env.sort();
+
+    Ok(env)
 }
 
-#[derive(Error, Debug)]
+/// Runs one of the `postActivateChecks` on the deployer, with the node and profile it checks in the environment
+async fn run_post_activate_check(
+    deploy_data: &super::DeployData<'_>,
+    check: &str,
+) -> Result<(), DeployProfileError> {
+    let hostname = match deploy_data.cmd_overrides.hostname {
+        Some(ref x) => x,
+        None => &deploy_data.node.node_settings.hostname,
+    };
+
+    let mut check_command = Invocation::new("sh");
+    check_command
+        .env("DEPLOY_NODE", deploy_data.node_name)
+        .env("DEPLOY_HOSTNAME", hostname)
+        .env("DEPLOY_PROFILE", deploy_data.profile_name)
+        .env("DEPLOY_CLOSURE", &deploy_data.profile.profile_settings.path)
+        .arg("-c")
+        .arg(check);
+
+    let check_output = deploy_data
+        .transport
+        .run(&check_command)
+        .await
+        .map_err(|e| DeployProfileError::PostActivateCheck(check.to_string(), e))?;
+
+    match check_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(DeployProfileError::PostActivateCheckExit(
+                CommandFailure::new(&check_command, &check_output),
+            ))
+        }
+    };
+
+    Ok(())
+}
+
+async fn run_post_activate_checks(
+    deploy_data: &super::DeployData<'_>,
+) -> Result<(), DeployProfileError> {
+    for check in &deploy_data.merged_settings.post_activate_checks {
+        info!("Running post-activation check `{}`", check);
+
+        run_post_activate_check(deploy_data, check).await?;
+    }
+
+    Ok(())
+}
+
+struct CheckCommandData<'a> {
+    sudo: &'a Option<String>,
+    profile_path: &'a str,
+    /// Only one thing to wait for, so its outcome can be told apart from the others
+    readiness: &'a Readiness,
+}
+
+/// Builds the command which has the activate-rs of the deployed profile check once whether something is up
+fn build_check_command(data: &CheckCommandData) -> String {
+    let mut self_check_command =
+        crate::with_output_style(&format!("{}/activate-rs check", data.profile_path));
+
+    if let Some(ref unit) = data.readiness.unit {
+        self_check_command = format!(
+            "{} --wait-for-unit {}",
+            self_check_command,
+            crate::shell_quote(unit)
+        );
+    }
+
+    if let Some(port) = data.readiness.port {
+        self_check_command = format!("{} --wait-for-port {}", self_check_command, port);
+    }
+
+    if let Some(ref url) = data.readiness.url {
+        self_check_command = format!(
+            "{} --wait-for-url {}",
+            self_check_command,
+            crate::shell_quote(url)
+        );
+    }
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_check_command = format!("{} {}", sudo_cmd, self_check_command);
+    }
+
+    self_check_command
+}
+
+#[test]
+fn test_check_command_builder() {
+    assert_eq!(
+        build_check_command(&CheckCommandData {
+            sudo: &Some("sudo -u root".to_string()),
+            profile_path: "/nix/var/nix/profiles/system",
+            readiness: &Readiness {
+                unit: Some("postgresql.service".to_string()),
+                ..Readiness::default()
+            },
+        }),
+        "sudo -u root /nix/var/nix/profiles/system/activate-rs check --wait-for-unit 'postgresql.service'"
+    );
+}
+
+/// How one health check of a deployed profile went
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckOutcome {
+    /// What was checked, a readiness target or one of the `postActivateChecks`
+    pub check: String,
+    /// Why the check failed, `None` if it passed
+    pub error: Option<String>,
+}
+
+/// The exit code of a failed check, along with the last thing it said about why
+fn describe_failure(failure: &CommandFailure) -> String {
+    match failure.stderr_tail.last() {
+        Some(line) => format!("{}: {}", failure, line.trim()),
+        None => failure.to_string(),
+    }
+}
+
+/// Runs the health checks of a profile against what's deployed right now, without activating anything: what
+/// activation waits for (checked once on the node), then the `postActivateChecks`
+pub async fn verify_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Vec<CheckOutcome> {
+    let mut outcomes = Vec::new();
+
+    for (check, readiness) in readiness(&deploy_data.merged_settings).split() {
+        let self_check_command = build_check_command(&CheckCommandData {
+            sudo: &deploy_defs.sudo,
+            profile_path: &deploy_defs.profile_path,
+            readiness: &readiness,
+        });
+
+        debug!("Constructed check command: {}", self_check_command);
+
+        let error =
+            match run_on_node(deploy_data, deploy_defs, self_check_command.clone(), false).await {
+                Ok(ref output) if output.code == Some(0) => None,
+                Ok(ref output) => Some(describe_failure(&CommandFailure::new(
+                    &self_check_command,
+                    output,
+                ))),
+                Err(e) => Some(e.to_string()),
+            };
+
+        outcomes.push(CheckOutcome { check, error });
+    }
+
+    for check in &deploy_data.merged_settings.post_activate_checks {
+        let error = match run_post_activate_check(deploy_data, check).await {
+            Ok(()) => None,
+            Err(DeployProfileError::PostActivateCheckExit(ref failure)) => {
+                Some(describe_failure(failure))
+            }
+            Err(e) => Some(e.to_string()),
+        };
+
+        outcomes.push(CheckOutcome {
+            check: format!("`{}`", check),
+            error,
+        });
+    }
+
+    outcomes
+}
+
+#[test]
+fn test_verify_profile() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "db-1",
+            "sshUser": "deploy",
+            "waitForUnit": "postgresql.service",
+            "waitForPort": 5432,
+            "postActivateChecks": [ "pg_isready -h $DEPLOY_HOSTNAME", "true" ],
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("--wait-for-port", Some(1), "");
+    transport.respond("pg_isready", Some(2), "");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "db-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let outcomes = runtime.block_on(verify_profile(&deploy_data, &deploy_defs));
+
+    // Every check runs, even after one failed
+    assert_eq!(
+        outcomes,
+        vec![
+            CheckOutcome {
+                check: "unit `postgresql.service`".to_string(),
+                error: None,
+            },
+            CheckOutcome {
+                check: "port 5432".to_string(),
+                error: Some("exit code 1".to_string()),
+            },
+            CheckOutcome {
+                check: "`pg_isready -h $DEPLOY_HOSTNAME`".to_string(),
+                error: Some("exit code 2".to_string()),
+            },
+            CheckOutcome {
+                check: "`true`".to_string(),
+                error: None,
+            },
+        ]
+    );
+    assert_eq!(
+        transport.commands()[1],
+        "[ssh://deploy@db-1] /nix/var/nix/profiles/per-user/deploy/system/activate-rs check --wait-for-port 5432"
+    );
+}
+
+pub async fn deploy_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    dry_activate: bool,
+) -> Result<(), DeployProfileError> {
+    if !dry_activate {
+        info!(
+            "Activating profile `{}` for node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+    }
+
+    let started = std::time::Instant::now();
+    let record = |step, started| {
+        crate::timings::record(deploy_data.timings, deploy_data.node_name, step, started)
+    };
+
+    // Snapshots go first, so they hold the state from before migrating
+    let snapshot_taken = match dry_activate {
+        true => None,
+        false => {
+            let name = take_snapshots(deploy_data, deploy_defs).await?;
+
+            run_migrations(deploy_data, deploy_defs).await?;
+            record(Step::Migrate, started);
+
+            name
+        }
+    };
+
+    let started = std::time::Instant::now();
+
+    let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
+
+    let magic_rollback = deploy_data.merged_settings.magic_rollback.unwrap_or(true);
+
+    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+
+    if let Some(self_activate_command) =
+        script_activate_command(deploy_data, deploy_defs, &deploy_defs.sudo, auto_rollback)
+    {
+        let activated = activate_with_script(
+            deploy_data,
+            deploy_defs,
+            self_activate_command,
+            dry_activate,
+        )
+        .await;
+
+        record(Step::Activate, started);
+
+        return activated;
+    }
+
+    let activation_env = resolve_activation_env(deploy_data).await?;
+
+    let closure = &deploy_data.profile.profile_settings.path;
+
+    // An older activate-rs's waiter doesn't create the directory it watches, which `/tmp` always is
+    let profile_canaries =
+        !magic_rollback || dry_activate || knows_profile_canaries(deploy_data, deploy_defs).await;
+    let (temp_path, lock_path): (Cow<str>, _) = match profile_canaries {
+        true => (
+            deploy_defs.temp_path.as_str().into(),
+            super::make_lock_path(&deploy_defs.temp_path, closure, &deploy_defs.profile_path),
+        ),
+        false => {
+            let temp_path = deploy_data
+                .merged_settings
+                .temp_path
+                .as_deref()
+                .unwrap_or("/tmp");
+            (
+                temp_path.into(),
+                super::make_legacy_lock_path(temp_path, closure),
+            )
+        }
+    };
+
+    // activate-rs puts the state back along with the profile when it rolls back by itself, which takes root
+    let restore_snapshots = match snapshot_taken {
+        Some(_)
+            if !deploy_data
+                .merged_settings
+                .restore_snapshots
+                .unwrap_or(false) =>
+        {
+            None
+        }
+        Some(_) if deploy_defs.profile_user != "root" => {
+            warn!(
+                "Profile `{}` of node `{}` isn't activated as root, only rolling it back from here restores its `snapshots`",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+            None
+        }
+        Some(name) => Some(
+            serde_json::to_string(&crate::snapshot::Restore {
+                name,
+                snapshots: deploy_data.merged_settings.snapshots.clone(),
+            })
+            .expect("snapshots always serialize"),
+        ),
+        None => None,
+    };
+
+    let self_activate_command = build_activate_command(&ActivateCommandData {
+        sudo: &deploy_defs.sudo,
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        auto_rollback,
+        temp_path: &temp_path,
+        confirm_timeout,
+        activation_timeout: deploy_data.merged_settings.activation_timeout,
+        magic_rollback,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        nix_env: NixEnv::new(&deploy_data.merged_settings),
+        label: deploy_data.cmd_overrides.label.as_deref(),
+        dry_activate,
+        env_file: match activation_env.is_empty() {
+            true => None,
+            false => Some("-"),
+        },
+        restore_snapshots: restore_snapshots.as_deref(),
+        readiness: &readiness(&deploy_data.merged_settings),
+        restart_changed_only: deploy_data
+            .merged_settings
+            .restart_changed_only
+            .unwrap_or(false),
+        relabel: deploy_data.merged_settings.relabel.unwrap_or(false),
+    });
+
+    debug!("Constructed activation command: {}", self_activate_command);
+
+    let env_input = match activation_env.is_empty() {
+        true => Vec::new(),
+        false => super::activation_env_input(&activation_env),
+    };
+
+    if !magic_rollback || dry_activate {
+        let ssh_activate = run_activation_on_node(
+            deploy_data,
+            deploy_defs,
+            self_activate_command.clone(),
+            &env_input,
+        );
+
+        // Without a waiter, the deployer keeps time itself, or an activation stuck on the node would hold it forever
+        let ssh_activate_output = match wait_timeout(&deploy_data.merged_settings) {
+            Some(timeout) => {
+                match tokio::time::timeout(
+                    std::time::Duration::from_secs(timeout as u64),
+                    ssh_activate,
+                )
+                .await
+                {
+                    Ok(x) => x,
+                    Err(_) => {
+                        abort_activation(deploy_data, deploy_defs).await;
+                        return Err(DeployProfileError::ActivationTimeout(timeout));
+                    }
+                }
+            }
+            None => ssh_activate.await,
+        }
+        .map_err(DeployProfileError::SSHActivate)?;
+
+        match ssh_activate_output.code {
+            Some(0) => (),
+            _ => {
+                return Err(DeployProfileError::SSHActivateExit(CommandFailure::new(
+                    self_activate_command,
+                    &ssh_activate_output,
+                )))
+            }
+        };
+
+        record(Step::Activate, started);
+
+        if dry_activate {
+            info!("Completed dry-activate!");
+        } else {
+            info!("Success activating, done!");
+        }
+    } else {
+        let self_wait_command = build_wait_command(&WaitCommandData {
+            sudo: &deploy_defs.sudo,
+            closure: &deploy_data.profile.profile_settings.path,
+            profile_path: match profile_canaries {
+                true => Some(&deploy_defs.profile_path),
+                false => None,
+            },
+            temp_path: &temp_path,
+            // Which came along with the profile path
+            timeout: wait_timeout(&deploy_data.merged_settings).filter(|_| profile_canaries),
+            debug_logs: deploy_data.debug_logs,
+            log_dir: deploy_data.log_dir,
+            nix_env: NixEnv::new(&deploy_data.merged_settings),
+        });
+
+        debug!("Constructed wait command: {}", self_wait_command);
+
+        let ssh_activate = run_activation_on_node(
+            deploy_data,
+            deploy_defs,
+            self_activate_command.clone(),
+            &env_input,
+        );
+        tokio::pin!(ssh_activate);
+
+        info!("Creating activation waiter");
+
+        let mut activate_finished = false;
+
+        tokio::select! {
+            x = run_on_node(deploy_data, deploy_defs, self_wait_command.clone(), false) => {
+                debug!("Wait command ended");
+                let waited = match x {
+                    Ok(wait_output) if wait_output.code == Some(0) => Ok(()),
+                    Ok(wait_output) => Err(DeployProfileError::SSHWaitExit(CommandFailure::new(
+                        &self_wait_command,
+                        &wait_output,
+                    ))),
+                    Err(e) => Err(DeployProfileError::SSHWait(e)),
+                };
+
+                // The activation is given up on, so it mustn't finish later on the node behind our back
+                if let Err(e) = waited {
+                    abort_activation(deploy_data, deploy_defs).await;
+                    return Err(e);
+                }
+            },
+            x = &mut ssh_activate => {
+                debug!("Activate command exited before the waiter");
+                activate_finished = true;
+                let activate_output = x.map_err(DeployProfileError::SSHActivate)?;
+                match activate_output.code {
+                    Some(0) => (),
+                    _ => {
+                        return Err(DeployProfileError::SSHActivateExit(CommandFailure::new(
+                            &self_activate_command,
+                            &activate_output,
+                        )))
+                    }
+                };
+            },
+        }
+
+        record(Step::Activate, started);
+
+        info!("Success activating, attempting to confirm activation");
+
+        let confirm_started = std::time::Instant::now();
+
+        // Without confirmation the node rolls back by itself, which is what a failed check should lead to
+        let c = match run_post_activate_checks(deploy_data).await {
+            Ok(()) => confirm_profile(deploy_data, deploy_defs, &lock_path)
+                .await
+                .map_err(DeployProfileError::Confirm),
+            Err(e) => Err(e),
+        };
+
+        record(Step::Confirm, confirm_started);
+
+        let activate_result = match activate_finished {
+            true => None,
+            false => Some(ssh_activate.await),
+        };
+
+        c?;
+
+        if let Some(activate_result) = activate_result {
+            let activate_output = activate_result.map_err(DeployProfileError::SSHActivate)?;
+
+            match activate_output.code {
+                Some(0) => (),
+                _ => {
+                    return Err(DeployProfileError::SSHActivateExit(CommandFailure::new(
+                        self_activate_command,
+                        &activate_output,
+                    )))
+                }
+            };
+        }
+    }
+
+    Ok(())
+}
+
+fn portable_command_data<'a>(
+    deploy_data: &'a super::DeployData<'_>,
+    deploy_defs: &'a super::DeployDefs,
+    sudo: &'a Option<String>,
+    auto_rollback: bool,
+) -> PortableCommandData<'a> {
+    PortableCommandData {
+        sudo,
+        name: deploy_data.profile_name,
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        auto_rollback,
+    }
+}
+
+fn netboot_command_data<'a>(
+    deploy_data: &'a super::DeployData<'_>,
+    deploy_defs: &'a super::DeployDefs,
+    sudo: &'a Option<String>,
+    auto_rollback: bool,
+) -> NetbootCommandData<'a> {
+    NetbootCommandData {
+        sudo,
+        name: deploy_data.profile_name,
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        netboot_dir: deploy_data
+            .profile
+            .profile_settings
+            .netboot_dir
+            .as_deref()
+            .unwrap_or("/srv/netboot"),
+        auto_rollback,
+    }
+}
+
+fn blue_green_command_data<'a>(
+    deploy_data: &'a super::DeployData<'_>,
+    deploy_defs: &'a super::DeployDefs,
+    sudo: &'a Option<String>,
+    auto_rollback: bool,
+    command: &'a str,
+) -> BlueGreenCommandData<'a> {
+    let settings = deploy_data.profile.profile_settings.blue_green.as_ref();
+
+    BlueGreenCommandData {
+        sudo,
+        name: deploy_data.profile_name,
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        command,
+        health_check: settings.and_then(|x| x.health_check.as_deref()),
+        health_timeout: settings
+            .and_then(|x| x.health_timeout)
+            .unwrap_or(DEFAULT_HEALTH_TIMEOUT),
+        ports: settings.and_then(|x| x.ports),
+        state_dir: match settings.and_then(|x| x.state_dir.as_deref()) {
+            Some(dir) => crate::shell_quote(dir),
+            None => format!(
+                "\"${{XDG_STATE_HOME:-$HOME/.local/state}}\"/deploy-rs/{}",
+                crate::shell_quote(deploy_data.profile_name)
+            ),
+        },
+        auto_rollback,
+    }
+}
+
+/// The command putting a profile into use which has no activate-rs, `None` for regular profiles
+fn script_activate_command(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    sudo: &Option<String>,
+    auto_rollback: bool,
+) -> Option<String> {
+    match deploy_data.profile.profile_settings.profile_type {
+        Some(ProfileType::Portable) => Some(build_portable_attach_command(&portable_command_data(
+            deploy_data,
+            deploy_defs,
+            sudo,
+            auto_rollback,
+        ))),
+        Some(ProfileType::Netboot) => Some(build_netboot_publish_command(&netboot_command_data(
+            deploy_data,
+            deploy_defs,
+            sudo,
+            auto_rollback,
+        ))),
+        Some(ProfileType::BlueGreen) => {
+            Some(build_blue_green_activate_command(&blue_green_command_data(
+                deploy_data,
+                deploy_defs,
+                sudo,
+                auto_rollback,
+                &blue_green_command(deploy_data),
+            )))
+        }
+        Some(ProfileType::Profile) | None => None,
+    }
+}
+
+/// Attaches a portable service image, publishes a netboot image or switches blue-green instances, there's no
+/// activate-rs in any of them to wait for confirmation with
+async fn activate_with_script(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    self_activate_command: String,
+    dry_activate: bool,
+) -> Result<(), DeployProfileError> {
+    debug!(
+        "Constructed script activation command: {}",
+        self_activate_command
+    );
+
+    if dry_activate {
+        info!(
+            "Would {} {} as `{}`",
+            match deploy_data.profile.profile_settings.profile_type {
+                Some(ProfileType::Netboot) => "publish netboot image",
+                Some(ProfileType::BlueGreen) => "switch blue-green instances to",
+                _ => "attach portable service image",
+            },
+            deploy_data.profile.profile_settings.path,
+            deploy_data.profile_name
+        );
+
+        return Ok(());
+    }
+
+    let ssh_activate_output = run_on_node(
+        deploy_data,
+        deploy_defs,
+        self_activate_command.clone(),
+        false,
+    )
+    .await
+    .map_err(DeployProfileError::SSHActivate)?;
+
+    match ssh_activate_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(DeployProfileError::SSHActivateExit(CommandFailure::new(
+                self_activate_command,
+                &ssh_activate_output,
+            )))
+        }
+    };
+
+    info!("Success activating, done!");
+
+    Ok(())
+}
+
+/// The command activating a profile without anyone around to confirm it, so without magic rollback, run as the
+/// profile's user, along with what it has to be given on standard input for activate-rs to read from `env_file`
+async fn unattended_activate_command(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    env_file: &str,
+) -> Result<(String, Vec<u8>), DeployProfileError> {
+    let activation_env = resolve_activation_env(deploy_data).await?;
+
+    let auto_rollback = deploy_data.merged_settings.auto_rollback.unwrap_or(true);
+
+    if let Some(script_command) =
+        script_activate_command(deploy_data, deploy_defs, &None, auto_rollback)
+    {
+        return Ok((script_command, Vec::new()));
+    }
+
+    let activate_command = build_activate_command(&ActivateCommandData {
+        sudo: &None,
+        profile_path: &deploy_defs.profile_path,
+        closure: &deploy_data.profile.profile_settings.path,
+        auto_rollback,
+        temp_path: &deploy_defs.temp_path,
+        confirm_timeout: deploy_data.merged_settings.confirm_timeout.unwrap_or(30),
+        activation_timeout: deploy_data.merged_settings.activation_timeout,
+        magic_rollback: false,
+        debug_logs: deploy_data.debug_logs,
+        log_dir: deploy_data.log_dir,
+        nix_env: NixEnv::new(&deploy_data.merged_settings),
+        label: deploy_data.cmd_overrides.label.as_deref(),
+        dry_activate: false,
+        env_file: match activation_env.is_empty() {
+            true => None,
+            false => Some(env_file),
+        },
+        restore_snapshots: None,
+        readiness: &readiness(&deploy_data.merged_settings),
+        restart_changed_only: deploy_data
+            .merged_settings
+            .restart_changed_only
+            .unwrap_or(false),
+        relabel: deploy_data.merged_settings.relabel.unwrap_or(false),
+    });
+
+    match activation_env.is_empty() {
+        true => Ok((activate_command, Vec::new())),
+        false => Ok((
+            activate_command,
+            super::activation_env_input(&activation_env),
+        )),
+    }
+}
+
+/// Activates a profile on the machine deploy is running on, as `deploy bundle apply --local` does on the target
+///
+/// Nobody can confirm the activation from the outside, so it doesn't use magic rollback, and it has to be run as
+/// the profile's user.
+pub async fn activate_locally(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), DeployProfileError> {
+    info!(
+        "Activating profile `{}` for node `{}` locally",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let (activate_command, input) =
+        unattended_activate_command(deploy_data, deploy_defs, "-").await?;
+
+    debug!("Constructed local activation command: {}", activate_command);
+
+    let mut local_command = Invocation::new("sh");
+    local_command.arg("-c").arg(&activate_command);
+
+    if !input.is_empty() {
+        local_command.stdin(InputMode::Secret(input));
+    }
+
+    let local_output = deploy_data
+        .transport
+        .run(&local_command)
+        .await
+        .map_err(DeployProfileError::LocalActivate)?;
+
+    match local_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(DeployProfileError::LocalActivateExit(CommandFailure::new(
+                &local_command,
+                &local_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+/// Has the node activate the already pushed profile by itself at `opening` (seconds since the epoch), when its
+/// maintenance window opens. Nobody is around to confirm it then, so magic rollback is left out
+pub async fn defer_activation(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    opening: u64,
+) -> Result<(), DeployProfileError> {
+    let calendar_event = crate::window::calendar_event(opening);
+
+    info!(
+        "Deferring activation of profile `{}` for node `{}` to {}",
+        deploy_data.profile_name, deploy_data.node_name, calendar_event
+    );
+
+    // The node activates it on its own later on, with nothing to run the migrations right before that
+    if deploy_data.merged_settings.migrations.is_some() {
+        return Err(DeployProfileError::MigrationsDeferred);
+    }
+
+    let env_file = super::make_activation_env_path(
+        &deploy_defs.temp_path,
+        &deploy_data.profile.profile_settings.path,
+        &deploy_defs.profile_path,
+    );
+    let (activate_command, input) =
+        unattended_activate_command(deploy_data, deploy_defs, &env_file).await?;
+
+    let self_defer_command = build_defer_command(&DeferCommandData {
+        sudo: &deploy_defs.sudo,
+        user_units: deploy_defs.profile_user != "root",
+        profile_name: deploy_data.profile_name,
+        calendar_event: &calendar_event,
+        opening,
+        temp_path: &deploy_defs.temp_path,
+        init_system: deploy_data.facts.as_ref().map(|x| x.init_system),
+        activate_command: &activate_command,
+        env_file: match input.is_empty() {
+            true => None,
+            false => Some(&env_file),
+        },
+    });
+
+    debug!("Constructed defer command: {}", self_defer_command);
+
+    let ssh_defer_output =
+        run_on_node_with_input(deploy_data, deploy_defs, self_defer_command.clone(), &input)
+            .await
+            .map_err(DeployProfileError::SSHDefer)?;
+
+    match ssh_defer_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(DeployProfileError::SSHDeferExit(CommandFailure::new(
+                self_defer_command,
+                &ssh_defer_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
 pub enum RevokeProfileError {
     #[error("Failed to spawn revocation command over SSH: {0}")]
     SSHSpawnRevoke(std::io::Error),
 
     #[error("Error revoking deployment: {0}")]
-    SSHRevoke(std::io::Error),
-    #[error("Revoking over SSH resulted in a bad exit code: {0:?}")]
-    SSHRevokeExit(Option<i32>),
+    SSHRevoke(RunOnNodeError),
+    #[error("Revoking over SSH failed with {0}")]
+    SSHRevokeExit(CommandFailure),
 
     #[error("Deployment data invalid: {0}")]
     InvalidDeployDataDefs(#[from] DeployDataDefsError),
+
+    #[error("Failed to restore `snapshots` over SSH: {0}")]
+    SSHRestoreSnapshots(RunOnNodeError),
+    #[error("Restoring `snapshots` failed with {0}")]
+    SSHRestoreSnapshotsExit(CommandFailure),
+}
+
+impl Diagnostic for RevokeProfileError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        match self {
+            RevokeProfileError::SSHRevokeExit(f)
+            | RevokeProfileError::SSHRestoreSnapshotsExit(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            RevokeProfileError::SSHRevokeExit(_) => Some(
+                "The node still runs the new profile, roll it back by hand with `nix-env --rollback --profile <profile path>`",
+            ),
+            RevokeProfileError::SSHRestoreSnapshotsExit(_) => Some(
+                "The profile was rolled back, but the state of its services wasn't, the snapshots are still on the node",
+            ),
+            _ => None,
+        }
+    }
+}
+/// What activating the profile did to systemd units, as activate-rs recorded it with `restartChangedOnly`
+pub async fn unit_changes(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Option<crate::units::UnitChanges> {
+    let unit_changes_path = crate::make_unit_changes_path(
+        &deploy_defs.temp_path,
+        &deploy_data.profile.profile_settings.path,
+        &deploy_defs.profile_path,
+    );
+    let command = format!("cat {}", crate::shell_quote(&unit_changes_path));
+
+    match run_on_node(deploy_data, deploy_defs, command, true).await {
+        Ok(output) if output.code == Some(0) => serde_json::from_slice(&output.stdout)
+            .map_err(|err| warn!("Failed to parse what activation did to units: {}", err))
+            .ok(),
+        Ok(output) => {
+            warn!(
+                "Failed to get what activation did to units, exit code {:?}",
+                output.code
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Failed to get what activation did to units: {}", err);
+            None
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmationWindowError {
+    #[error("Failed to look for activations awaiting confirmation over SSH: {0}")]
+    SSHCheck(RunOnNodeError),
+    #[error("Looking for activations awaiting confirmation over SSH failed with {0}")]
+    SSHCheckExit(CommandFailure),
+}
+
+/// Whether an activation on the node is waiting for its magic rollback confirmation, seen from its canary file, so
+/// the node could still roll back
+pub async fn awaiting_confirmation(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<bool, ConfirmationWindowError> {
+    let command = format!(
+        "for canary in {}/deploy-rs-canary-*; do if [ -e \"$canary\" ]; then exit 0; fi; done; exit 1",
+        crate::shell_quote(&deploy_defs.temp_path)
+    );
+
+    let output = run_on_node(deploy_data, deploy_defs, command.clone(), false)
+        .await
+        .map_err(ConfirmationWindowError::SSHCheck)?;
+
+    match output.code {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(ConfirmationWindowError::SSHCheckExit(CommandFailure::new(
+            command, &output,
+        ))),
+    }
+}
+
+#[test]
+fn test_awaiting_confirmation() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "etcd-1",
+            "sshUser": "deploy",
+            "tempPath": "/run/deploy-rs",
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let cmd_overrides = crate::CmdOverrides::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    for (code, awaiting) in &[
+        (Some(0), Some(true)),
+        (Some(1), Some(false)),
+        (Some(255), None),
+    ] {
+        let transport = crate::transport::RecordingTransport::default();
+        transport.respond("deploy-rs-canary-", *code, "");
+        let deploy_data = crate::DeployData {
+            transport: &transport,
+            ..crate::make_deploy_data(
+                &top_settings,
+                &node,
+                "etcd-1",
+                profile,
+                "system",
+                &cmd_overrides,
+                false,
+                None,
+            )
+        };
+        let deploy_defs = deploy_data.defs().unwrap();
+
+        assert_eq!(
+            runtime
+                .block_on(awaiting_confirmation(&deploy_data, &deploy_defs))
+                .ok(),
+            *awaiting
+        );
+        assert_eq!(
+            transport.commands(),
+            vec![
+                r#"[ssh://deploy@etcd-1] for canary in '/run/deploy-rs'/deploy-rs-canary-*; do if [ -e "$canary" ]; then exit 0; fi; done; exit 1"#
+            ]
+        );
+    }
+}
+
+/// Whether the profile's closure is what the profile on the node points to already, in which case activating it again
+/// would only restart services for nothing. When that can't be found out it counts as not active
+///
+/// The system profile can point to a closure the node isn't running, when it was booted into another generation or
+/// `switch-to-configuration` failed after the profile was set, so `/run/current-system` has to be on it too.
+pub async fn is_active(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> bool {
+    let mut paths = vec![deploy_defs.profile_path.as_str()];
+    if deploy_defs.profile_path == "/nix/var/nix/profiles/system" {
+        paths.push("/run/current-system");
+    }
+
+    let command = format!(
+        "readlink -f {}",
+        paths
+            .iter()
+            .map(|x| crate::shell_quote(x))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+
+    match run_on_node(deploy_data, deploy_defs, command, true).await {
+        Ok(output) if output.code == Some(0) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let closures: Vec<&str> = stdout.lines().collect();
+
+            closures.len() == paths.len()
+                && closures
+                    .iter()
+                    .all(|x| x.trim() == deploy_data.profile.profile_settings.path)
+        }
+        Ok(output) => {
+            debug!(
+                "Couldn't tell which closure profile `{}` of node `{}` points to, exit code {:?}",
+                deploy_data.profile_name, deploy_data.node_name, output.code
+            );
+            false
+        }
+        Err(err) => {
+            debug!(
+                "Couldn't tell which closure profile `{}` of node `{}` points to: {}",
+                deploy_data.profile_name, deploy_data.node_name, err
+            );
+            false
+        }
+    }
+}
+
+#[test]
+fn test_is_active() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "sshUser": "deploy",
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let cmd_overrides = crate::CmdOverrides::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    for (code, stdout, active) in &[
+        (
+            Some(0),
+            "/nix/store/00000000000000000000000000000000-system\n",
+            true,
+        ),
+        (
+            Some(0),
+            "/nix/store/11111111111111111111111111111111-system\n",
+            false,
+        ),
+        (Some(1), "", false),
+    ] {
+        let transport = crate::transport::RecordingTransport::default();
+        transport.respond("readlink -f", *code, stdout);
+        let deploy_data = crate::DeployData {
+            transport: &transport,
+            ..crate::make_deploy_data(
+                &top_settings,
+                &node,
+                "web-1",
+                profile,
+                "system",
+                &cmd_overrides,
+                false,
+                None,
+            )
+        };
+        let deploy_defs = deploy_data.defs().unwrap();
+
+        assert_eq!(
+            runtime.block_on(is_active(&deploy_data, &deploy_defs)),
+            *active
+        );
+        assert_eq!(
+            transport.commands(),
+            vec!["[ssh://deploy@web-1] readlink -f '/nix/var/nix/profiles/per-user/deploy/system'"]
+        );
+    }
+
+    // The system profile is only active when the node runs it too
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "sshUser": "root",
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+
+    for (stdout, active) in &[
+        (
+            "/nix/store/00000000000000000000000000000000-system\n/nix/store/00000000000000000000000000000000-system\n",
+            true,
+        ),
+        (
+            "/nix/store/00000000000000000000000000000000-system\n/nix/store/11111111111111111111111111111111-system\n",
+            false,
+        ),
+        ("/nix/store/00000000000000000000000000000000-system\n", false),
+    ] {
+        let transport = crate::transport::RecordingTransport::default();
+        transport.respond("readlink -f", Some(0), stdout);
+        let deploy_data = crate::DeployData {
+            transport: &transport,
+            ..crate::make_deploy_data(
+                &top_settings,
+                &node,
+                "web-1",
+                profile,
+                "system",
+                &cmd_overrides,
+                false,
+                None,
+            )
+        };
+        let deploy_defs = deploy_data.defs().unwrap();
+
+        assert_eq!(
+            runtime.block_on(is_active(&deploy_data, &deploy_defs)),
+            *active
+        );
+        assert_eq!(
+            transport.commands(),
+            vec!["[ssh://root@web-1] readlink -f '/nix/var/nix/profiles/system' '/run/current-system'"]
+        );
+    }
 }
+
 pub async fn revoke(
     deploy_data: &crate::DeployData<'_>,
     deploy_defs: &crate::DeployDefs,
 ) -> Result<(), RevokeProfileError> {
-    let self_revoke_command = build_revoke_command(&RevokeCommandData {
+    let self_revoke_command = match deploy_data.profile.profile_settings.profile_type {
+        Some(ProfileType::Portable) => build_portable_revoke_command(&portable_command_data(
+            deploy_data,
+            deploy_defs,
+            &deploy_defs.sudo,
+            true,
+        )),
+        Some(ProfileType::Netboot) => build_netboot_revoke_command(&netboot_command_data(
+            deploy_data,
+            deploy_defs,
+            &deploy_defs.sudo,
+            true,
+        )),
+        Some(ProfileType::BlueGreen) => build_blue_green_revoke_command(&blue_green_command_data(
+            deploy_data,
+            deploy_defs,
+            &deploy_defs.sudo,
+            true,
+            &blue_green_command(deploy_data),
+        )),
+        Some(ProfileType::Profile) | None => build_revoke_command(&RevokeCommandData {
+            sudo: &deploy_defs.sudo,
+            closure: &deploy_data.profile.profile_settings.path,
+            profile_path: &deploy_data.get_profile_path()?,
+            debug_logs: deploy_data.debug_logs,
+            log_dir: deploy_data.log_dir,
+            nix_env: NixEnv::new(&deploy_data.merged_settings),
+        }),
+    };
+
+    debug!("Constructed revoke command: {}", self_revoke_command);
+
+    let result = run_on_node(deploy_data, deploy_defs, self_revoke_command.clone(), false).await;
+
+    match result {
+        Err(x) => return Err(RevokeProfileError::SSHRevoke(x)),
+        Ok(ref x) => match x.code {
+            Some(0) => (),
+            _ => {
+                return Err(RevokeProfileError::SSHRevokeExit(CommandFailure::new(
+                    self_revoke_command,
+                    x,
+                )))
+            }
+        },
+    }
+
+    if deploy_data
+        .merged_settings
+        .restore_snapshots
+        .unwrap_or(false)
+    {
+        restore_snapshots(deploy_data, deploy_defs).await?;
+    }
+
+    Ok(())
+}
+
+/// Puts the profile's `snapshots` back to the snapshots taken when the run rolled back activated it
+async fn restore_snapshots(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<(), RevokeProfileError> {
+    let snapshots = &deploy_data.merged_settings.snapshots;
+
+    if snapshots.is_empty() {
+        return Ok(());
+    }
+
+    let name = match snapshot_name(deploy_data) {
+        Some(x) => x,
+        None => {
+            warn!(
+                "Not restoring the snapshots of profile `{}` of node `{}`, it isn't known which run took them",
+                deploy_data.profile_name, deploy_data.node_name
+            );
+
+            return Ok(());
+        }
+    };
+
+    info!(
+        "Restoring {} datasets and subvolumes of node `{}` to `{}`",
+        snapshots.len(),
+        deploy_data.node_name,
+        name
+    );
+
+    let command = script_command(
+        &deploy_data.sudo_for(deploy_defs, "root"),
+        &crate::snapshot::restore_script(snapshots, &name),
+    );
+
+    debug!("Constructed restore command: {}", command);
+
+    let output = run_on_node(deploy_data, deploy_defs, command.clone(), false)
+        .await
+        .map_err(RevokeProfileError::SSHRestoreSnapshots)?;
+
+    match output.code {
+        Some(0) => Ok(()),
+        _ => Err(RevokeProfileError::SSHRestoreSnapshotsExit(
+            CommandFailure::new(command, &output),
+        )),
+    }
+}
+
+/// How long `optimiseStore` may take by default, in seconds
+pub const DEFAULT_OPTIMISE_TIMEOUT: u16 = 300;
+
+fn build_optimise_command(sudo: &Option<String>, nix_env: NixEnv<'_>, timeout: u16) -> String {
+    // `timeout` exits with 124 when it stops the command, which picks up where it stopped next time
+    let command = with_nix_env(nix_env, format!("timeout {} nix-store --optimise", timeout));
+
+    match sudo {
+        Some(sudo_cmd) => format!("{} {}", sudo_cmd, command),
+        None => command,
+    }
+}
+
+#[test]
+fn test_optimise_command_builder() {
+    assert_eq!(
+        build_optimise_command(&Some("sudo -u root".to_string()), NixEnv::default(), 300),
+        "sudo -u root timeout 300 nix-store --optimise"
+    );
+    assert_eq!(
+        build_optimise_command(
+            &None,
+            NixEnv {
+                path: Some("/opt/nix/bin"),
+                store_root: None
+            },
+            60
+        ),
+        "env PATH='/opt/nix/bin':\"$PATH\" timeout 60 nix-store --optimise"
+    );
+    assert_eq!(
+        build_optimise_command(
+            &None,
+            NixEnv {
+                path: Some("/home/deploy/.nix/bin"),
+                store_root: Some("/home/deploy/.nix/")
+            },
+            60
+        ),
+        "env PATH='/home/deploy/.nix/bin':\"$PATH\" NIX_REMOTE='local?root=/home/deploy/.nix' timeout 60 nix-store --optimise"
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum OptimiseStoreError {
+    #[error("Failed to optimise the Nix store: {0}")]
+    Run(RunOnNodeError),
+    #[error("Optimising the Nix store failed with {0}")]
+    Exit(CommandFailure),
+}
+
+/// Deduplicates the Nix store of a node by hard-linking identical files, for at most its `optimiseTimeout`
+///
+/// Returns whether it finished in time.
+pub async fn optimise_store(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<bool, OptimiseStoreError> {
+    let optimise_command = build_optimise_command(
+        &deploy_defs.sudo,
+        NixEnv::new(&deploy_data.merged_settings),
+        deploy_data
+            .merged_settings
+            .optimise_timeout
+            .unwrap_or(DEFAULT_OPTIMISE_TIMEOUT),
+    );
+
+    debug!("Constructed optimise command: {}", optimise_command);
+
+    let output = run_on_node(deploy_data, deploy_defs, optimise_command.clone(), true)
+        .await
+        .map_err(OptimiseStoreError::Run)?;
+
+    match output.code {
+        Some(0) => Ok(true),
+        Some(124) => Ok(false),
+        _ => Err(OptimiseStoreError::Exit(CommandFailure::new(
+            optimise_command,
+            &output,
+        ))),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DiffEtcError {
+    #[error("Failed to run /etc diff command over SSH: {0}")]
+    SSHDiffEtc(RunOnNodeError),
+    #[error("Computing the /etc diff over SSH resulted in a bad exit code: {0:?}")]
+    SSHDiffEtcExit(Option<i32>),
+}
+
+async fn run_diff_command(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    subcommand: &str,
+) -> Result<Output, RunOnNodeError> {
+    let self_diff_command = build_diff_command(&DiffCommandData {
         sudo: &deploy_defs.sudo,
         closure: &deploy_data.profile.profile_settings.path,
-        profile_path: &deploy_data.get_profile_path()?,
+        profile_path: &deploy_defs.profile_path,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
+        nix_env: NixEnv::new(&deploy_data.merged_settings),
+        subcommand,
     });
 
-    debug!("Constructed revoke command: {}", self_revoke_command);
+    debug!("Constructed {} command: {}", subcommand, self_diff_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
+    run_on_node(deploy_data, deploy_defs, self_diff_command, true).await
+}
+
+pub async fn diff_etc(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<(), DiffEtcError> {
+    info!(
+        "Computing /etc changes for profile `{}` on node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let ssh_diff_etc_output = run_diff_command(deploy_data, deploy_defs, "diff-etc")
+        .await
+        .map_err(DiffEtcError::SSHDiffEtc)?;
+
+    match ssh_diff_etc_output.code {
+        Some(0) => (),
+        a => return Err(DiffEtcError::SSHDiffEtcExit(a)),
+    };
+
+    let diff = String::from_utf8_lossy(&ssh_diff_etc_output.stdout);
+
+    if diff.trim().is_empty() {
+        info!(
+            "No changes to /etc for profile `{}` on node `{}`",
+            deploy_data.profile_name, deploy_data.node_name
+        );
+    } else {
+        info!(
+            "Changes to /etc for profile `{}` on node `{}`:\n{}",
+            deploy_data.profile_name, deploy_data.node_name, diff
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum DiffClosuresError {
+    #[error("Failed to run closure diff command over SSH: {0}")]
+    SSHDiffClosures(RunOnNodeError),
+    #[error("Computing the closure diff over SSH resulted in a bad exit code: {0:?}")]
+    SSHDiffClosuresExit(Option<i32>),
+    #[error("Failed to parse the closure diff reported by the node: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+impl Diagnostic for DiffClosuresError {}
+
+pub async fn diff_closures(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<ClosureDiffOutput, DiffClosuresError> {
+    debug!(
+        "Computing closure changes for profile `{}` on node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let ssh_diff_closures_output = run_diff_command(deploy_data, deploy_defs, "diff-closures")
+        .await
+        .map_err(DiffClosuresError::SSHDiffClosures)?;
+
+    match ssh_diff_closures_output.code {
+        Some(0) => (),
+        a => return Err(DiffClosuresError::SSHDiffClosuresExit(a)),
     };
 
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
+    Ok(serde_json::from_slice(&ssh_diff_closures_output.stdout)?)
+}
 
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command.arg(&ssh_addr);
+#[derive(Error, Debug)]
+pub enum QueryClosureError {
+    #[error("Failed to run closure query command over SSH: {0}")]
+    SSHQuery(RunOnNodeError),
+    #[error("Querying the deployed closure over SSH failed with {0}")]
+    SSHQueryExit(CommandFailure),
+    #[error("The node reported a closure which could not be parsed")]
+    Parse,
+}
 
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
+impl Diagnostic for QueryClosureError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        match self {
+            QueryClosureError::SSHQueryExit(f) => Some(f),
+            _ => None,
+        }
     }
 
-    let ssh_revoke = ssh_activate_command
-        .arg(self_revoke_command)
-        .spawn()
-        .map_err(RevokeProfileError::SSHSpawnRevoke)?;
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            QueryClosureError::SSHQueryExit(_) => {
+                Some("Has the profile been deployed to the node yet? Its profile path has to exist")
+            }
+            _ => None,
+        }
+    }
+}
 
-    let result = ssh_revoke.wait_with_output().await;
+/// Builds the command listing the paths in the closure a profile points at, then their sizes in the same order
+fn build_query_closure_command(profile_path: &str, nix_env: NixEnv<'_>) -> String {
+    with_nix_env(
+        nix_env,
+        format!(
+            "sh -c {}",
+            crate::shell_quote(&format!(
+                "set -e; paths=$(nix-store --query --requisites {}); echo \"$paths\"; echo; nix-store --query --size $paths",
+                crate::shell_quote(profile_path)
+            ))
+        ),
+    )
+}
 
-    match result {
-        Err(x) => Err(RevokeProfileError::SSHRevoke(x)),
-        Ok(ref x) => match x.status.code() {
-            Some(0) => Ok(()),
-            a => Err(RevokeProfileError::SSHRevokeExit(a)),
-        },
+fn parse_closure_query(stdout: &str) -> Option<Vec<ClosurePath>> {
+    let mut sections = stdout.splitn(2, "\n\n");
+    let paths: Vec<&str> = sections.next()?.lines().collect();
+    let sizes = sections
+        .next()?
+        .lines()
+        .map(|x| x.trim().parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    if paths.len() != sizes.len() {
+        return None;
+    }
+
+    Some(
+        paths
+            .into_iter()
+            .zip(sizes)
+            .map(|(path, size)| ClosurePath {
+                path: path.to_string(),
+                size,
+            })
+            .collect(),
+    )
+}
+
+/// Lists the closure a profile is currently deployed with on its node, not the one it would be deployed with
+pub async fn query_deployed_closure(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Result<Vec<ClosurePath>, QueryClosureError> {
+    let self_query_command = build_query_closure_command(
+        &deploy_defs.profile_path,
+        NixEnv::new(&deploy_data.merged_settings),
+    );
+
+    debug!("Constructed closure query command: {}", self_query_command);
+
+    let ssh_query_output = run_on_node(deploy_data, deploy_defs, self_query_command.clone(), true)
+        .await
+        .map_err(QueryClosureError::SSHQuery)?;
+
+    match ssh_query_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(QueryClosureError::SSHQueryExit(CommandFailure::new(
+                self_query_command,
+                &ssh_query_output,
+            )))
+        }
+    };
+
+    parse_closure_query(&String::from_utf8_lossy(&ssh_query_output.stdout))
+        .ok_or(QueryClosureError::Parse)
+}
+
+#[test]
+fn test_query_deployed_closure() {
+    assert_eq!(
+        build_query_closure_command("/nix/var/nix/profiles/system", NixEnv::default()),
+        r#"sh -c 'set -e; paths=$(nix-store --query --requisites '\''/nix/var/nix/profiles/system'\''); echo "$paths"; echo; nix-store --query --size $paths'"#
+    );
+
+    assert_eq!(
+        parse_closure_query("/nix/store/aaaa-hello-2.10\n/nix/store/bbbb-bash-4.4\n\n100\n200\n"),
+        Some(vec![
+            ClosurePath {
+                path: "/nix/store/aaaa-hello-2.10".to_string(),
+                size: 100,
+            },
+            ClosurePath {
+                path: "/nix/store/bbbb-bash-4.4".to_string(),
+                size: 200,
+            },
+        ])
+    );
+    assert_eq!(
+        parse_closure_query("/nix/store/aaaa-hello-2.10\n/nix/store/bbbb-bash-4.4\n\n100\n"),
+        None
+    );
+    assert_eq!(parse_closure_query(""), None);
+}
+
+#[test]
+fn test_deploy_profile_orchestration() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "sshUser": "deploy",
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let cmd_overrides = crate::CmdOverrides::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    // Magic rollback runs the activation and the waiter side by side, then confirms
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            profile,
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert!(runtime
+        .block_on(deploy_profile(&deploy_data, &deploy_defs, false))
+        .is_ok());
+
+    // The fake answers instantly, so whether the waiter gets polled before activation ends is up to `select!`
+    let commands = transport.commands();
+    assert!(commands.iter().any(|x| x.starts_with(
+        "[ssh://deploy@web-1] /nix/store/00000000000000000000000000000000-system/activate-rs"
+    ) && x.contains(" activate ")));
+    assert!(commands.len() == 4 || !commands.iter().any(|x| x.contains(" wait '")));
+    assert!(
+        commands
+            .iter()
+            .any(|x| x
+                .starts_with("[ssh://deploy@web-1] rm '/tmp/deploy-rs-deploy/deploy-rs-canary-"))
+    );
+
+    // An activate-rs from before canaries were named after profiles is waited on and confirmed where it looks
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond(
+        "wait --help",
+        Some(0),
+        "USAGE:\n    activate-rs wait <closure> --temp-path <temp-path>\n",
+    );
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..deploy_data
+    };
+
+    assert!(runtime
+        .block_on(deploy_profile(&deploy_data, &deploy_defs, false))
+        .is_ok());
+
+    let commands = transport.commands();
+    assert!(commands.iter().any(|x| x.contains(" activate ")
+        && x.contains(" --temp-path '/tmp' ")
+        && x.contains(" --magic-rollback")));
+    assert!(!commands.iter().any(|x| x.contains("--profile-path")));
+    assert!(commands.contains(
+        &"[ssh://deploy@web-1] rm '/tmp/deploy-rs-canary-00000000000000000000000000000000'"
+            .to_string()
+    ));
+
+    // A failed confirmation has to surface, so the node is known to roll back
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    transport.respond("rm '/tmp/deploy-rs-deploy/deploy-rs-canary-", Some(1), "");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..deploy_data
+    };
+
+    assert!(matches!(
+        runtime.block_on(deploy_profile(&deploy_data, &deploy_defs, false)),
+        Err(DeployProfileError::Confirm(
+            ConfirmProfileError::SSHConfirmExit(CommandFailure { code: Some(1), .. })
+        ))
+    ));
+
+    // A failed post-activation check keeps the deployment from being confirmed
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "sshUser": "deploy",
+            "postActivateChecks": [ "curl -f https://$DEPLOY_HOSTNAME/health" ],
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    transport.respond("curl -f", Some(7), "");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            profile,
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+
+    assert!(matches!(
+        runtime.block_on(deploy_profile(&deploy_data, &deploy_defs, false)),
+        Err(DeployProfileError::PostActivateCheckExit(CommandFailure {
+            code: Some(7),
+            ..
+        }))
+    ));
+    assert!(transport.commands().contains(
+        &"DEPLOY_NODE=web-1 DEPLOY_HOSTNAME=web-1 DEPLOY_PROFILE=system DEPLOY_CLOSURE=/nix/store/00000000000000000000000000000000-system sh -c curl -f https://$DEPLOY_HOSTNAME/health"
+            .to_string()
+    ));
+    assert!(!transport
+        .commands()
+        .iter()
+        .any(|x| x.contains("rm '/tmp/deploy-rs-deploy/deploy-rs-canary-")));
+
+    // A failed migration stops the profile before it's activated
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "sshUser": "deploy",
+            "profiles": {
+                "system": {
+                    "path": "/nix/store/00000000000000000000000000000000-system",
+                    "migrations": { "command": "migrate", "timeout": 60 }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    transport.respond("sh -c 'migrate'", Some(TIMEOUT_EXIT), "");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            profile,
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+
+    assert!(matches!(
+        runtime.block_on(deploy_profile(&deploy_data, &deploy_defs, false)),
+        Err(DeployProfileError::MigrationTimeout(60))
+    ));
+    assert_eq!(transport.commands().len(), 1);
+    assert!(matches!(
+        runtime.block_on(defer_activation(&deploy_data, &deploy_defs, 1_700_000_000)),
+        Err(DeployProfileError::MigrationsDeferred)
+    ));
+}
+
+#[test]
+fn test_deploy_profile_quoted_names() {
+    // Names that need quoting in Nix, shells and systemd unit names alike
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web.example.com",
+            "sshUser": "root",
+            "profiles": {
+                "Tenant's App": { "path": "/nix/store/00000000000000000000000000000000-app" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web.example.com",
+            &node.node_settings.profiles["Tenant's App"],
+            "Tenant's App",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    assert!(runtime
+        .block_on(deploy_profile(&deploy_data, &deploy_defs, false))
+        .is_ok());
+
+    let commands = transport.commands();
+    assert!(commands.iter().any(|x| x.contains(
+        r#" activate '/nix/store/00000000000000000000000000000000-app' '/nix/var/nix/profiles/Tenant'\''s App' "#
+    )));
+    assert!(commands.contains(
+        &r#"[ssh://root@web.example.com] rm '/tmp/deploy-rs-canary-00000000000000000000000000000000-nix-var-nix-profiles-Tenant'\''s App'"#
+            .to_string()
+    ));
+
+    assert!(runtime
+        .block_on(defer_activation(&deploy_data, &deploy_defs, 1_614_996_000))
+        .is_ok());
+    assert!(transport
+        .commands()
+        .last()
+        .unwrap()
+        .contains(r"--unit '\''deploy-rs-deferred-Tenant\x27s\x20App'\''"));
+}
+
+#[test]
+fn test_concurrent_user_deployments() {
+    // Two teams deploying their own profiles of the same closure to one shared node at the same time
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "shared-1",
+            "sshUser": "deploy",
+            "profiles": {
+                "alice-app": { "user": "alice", "path": "/nix/store/00000000000000000000000000000000-app" },
+                "bob-app": { "user": "bob", "path": "/nix/store/00000000000000000000000000000000-app" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    transport.respond("wait --help", Some(0), "--profile-path <profile-path>");
+    let deploy_data = |profile_name: &'static str| crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "shared-1",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let (alice_data, bob_data) = (deploy_data("alice-app"), deploy_data("bob-app"));
+    let (alice_defs, bob_defs) = (alice_data.defs().unwrap(), bob_data.defs().unwrap());
+
+    let (alice, bob) =
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(futures_util::future::join(
+                deploy_profile(&alice_data, &alice_defs, false),
+                deploy_profile(&bob_data, &bob_defs, false),
+            ));
+    assert!(alice.is_ok());
+    assert!(bob.is_ok());
+
+    let commands = transport.commands();
+    for user in &["alice", "bob"] {
+        assert!(commands.iter().any(|x| x.contains(&format!(
+            "sudo -u {0} /nix/store/00000000000000000000000000000000-app/activate-rs activate '/nix/store/00000000000000000000000000000000-app' '/nix/var/nix/profiles/per-user/{0}/{0}-app' --temp-path '/tmp/deploy-rs-{0}'",
+            user
+        ))));
+        assert!(commands.contains(&format!(
+            "[ssh://deploy@shared-1] sudo -u {0} rm '/tmp/deploy-rs-{0}/deploy-rs-canary-00000000000000000000000000000000-nix-var-nix-profiles-per-user-{0}-{0}-app'",
+            user
+        )));
     }
 }
+
+#[test]
+fn test_activation_env() {
+    let top_settings = serde_json::from_str(
+        r#"{ "activationEnv": { "DEPLOY_TICKET": "OPS-1", "DEPLOY_GIT_REV": "overridden" } }"#,
+    )
+    .unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "web-1",
+            "activationEnv": { "DEPLOY_GIT_REV": { "command": "git rev-parse HEAD" } },
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let profile = &node.node_settings.profiles["system"];
+    let cmd_overrides = crate::CmdOverrides::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("git rev-parse", Some(0), "abc123\n");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            profile,
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+
+    assert_eq!(
+        runtime
+            .block_on(resolve_activation_env(&deploy_data))
+            .unwrap(),
+        vec![
+            ("DEPLOY_GIT_REV".to_string(), "abc123".to_string()),
+            ("DEPLOY_TICKET".to_string(), "OPS-1".to_string()),
+        ]
+    );
+    assert_eq!(transport.commands(), vec!["sh -c git rev-parse HEAD"]);
+}
@@ -0,0 +1,279 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A single store path of a closure, together with its NAR size
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosurePath {
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PackageChange {
+    pub name: String,
+    pub old_versions: Vec<String>,
+    pub new_versions: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClosureDiff {
+    pub changed: Vec<PackageChange>,
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub old_paths: usize,
+    pub new_paths: usize,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+/// What the target reports back to the deployer, depending on which renderer could be used
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "renderer", rename_all = "lowercase")]
+pub enum ClosureDiffOutput {
+    Builtin(ClosureDiff),
+    Nvd { text: String },
+}
+
+impl ClosureDiffOutput {
+    pub fn render(&self) -> String {
+        match self {
+            ClosureDiffOutput::Builtin(diff) => render_closure_diff(diff),
+            ClosureDiffOutput::Nvd { text } => text.trim_end().to_string(),
+        }
+    }
+}
+
+/// Splits a store path into a package name and version, the same way `builtins.parseDrvName` does
+pub fn parse_store_path_name(path: &str) -> (String, String) {
+    let base = path.strip_prefix("/nix/store/").unwrap_or(path);
+
+    // Store path basenames are `<hash>-<name>`
+    let name = match base.find('-') {
+        Some(i) => &base[i + 1..],
+        None => base,
+    };
+
+    let chars: Vec<char> = name.chars().collect();
+
+    for i in 0..chars.len() {
+        if chars[i] == '-' && matches!(chars.get(i + 1), Some(c) if !c.is_alphabetic()) {
+            return (chars[..i].iter().collect(), chars[i + 1..].iter().collect());
+        }
+    }
+
+    (name.to_string(), String::new())
+}
+
+fn versions_by_name(paths: &[ClosurePath]) -> BTreeMap<String, BTreeSet<String>> {
+    let mut versions: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for p in paths {
+        let (name, version) = parse_store_path_name(&p.path);
+        let entry = versions.entry(name).or_default();
+        if !version.is_empty() {
+            entry.insert(version);
+        }
+    }
+
+    versions
+}
+
+pub fn diff_closures(old: &[ClosurePath], new: &[ClosurePath]) -> ClosureDiff {
+    let old_versions = versions_by_name(old);
+    let new_versions = versions_by_name(new);
+
+    let mut changed = Vec::new();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+
+    for (name, new_v) in &new_versions {
+        match old_versions.get(name) {
+            Some(old_v) if old_v != new_v => changed.push(PackageChange {
+                name: name.clone(),
+                old_versions: old_v.iter().cloned().collect(),
+                new_versions: new_v.iter().cloned().collect(),
+            }),
+            Some(_) => (),
+            None => added.push(PackageChange {
+                name: name.clone(),
+                old_versions: Vec::new(),
+                new_versions: new_v.iter().cloned().collect(),
+            }),
+        }
+    }
+
+    for (name, old_v) in &old_versions {
+        if !new_versions.contains_key(name) {
+            removed.push(PackageChange {
+                name: name.clone(),
+                old_versions: old_v.iter().cloned().collect(),
+                new_versions: Vec::new(),
+            });
+        }
+    }
+
+    ClosureDiff {
+        changed,
+        added,
+        removed,
+        old_paths: old.len(),
+        new_paths: new.len(),
+        old_size: old.iter().map(|p| p.size).sum(),
+        new_size: new.iter().map(|p| p.size).sum(),
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+fn format_versions(versions: &[String]) -> String {
+    match versions {
+        [] => "∅".to_string(),
+        _ => versions.join(", "),
+    }
+}
+
+pub fn render_closure_diff(diff: &ClosureDiff) -> String {
+    let mut out = String::new();
+
+    if !diff.changed.is_empty() {
+        out.push_str("Version changes:\n");
+        for c in &diff.changed {
+            out.push_str(&format!(
+                "[C] {}: {} -> {}\n",
+                c.name,
+                format_versions(&c.old_versions),
+                format_versions(&c.new_versions)
+            ));
+        }
+    }
+
+    if !diff.added.is_empty() {
+        out.push_str("Added packages:\n");
+        for c in &diff.added {
+            out.push_str(&format!(
+                "[A] {}: {}\n",
+                c.name,
+                format_versions(&c.new_versions)
+            ));
+        }
+    }
+
+    if !diff.removed.is_empty() {
+        out.push_str("Removed packages:\n");
+        for c in &diff.removed {
+            out.push_str(&format!(
+                "[R] {}: {}\n",
+                c.name,
+                format_versions(&c.old_versions)
+            ));
+        }
+    }
+
+    let (sign, delta) = if diff.new_size >= diff.old_size {
+        ("+", diff.new_size - diff.old_size)
+    } else {
+        ("-", diff.old_size - diff.new_size)
+    };
+
+    out.push_str(&format!(
+        "Closure size: {} paths ({}) -> {} paths ({}), {}{}",
+        diff.old_paths,
+        format_size(diff.old_size),
+        diff.new_paths,
+        format_size(diff.new_size),
+        sign,
+        format_size(delta)
+    ));
+
+    out
+}
+
+#[test]
+fn test_parse_store_path_name() {
+    assert_eq!(
+        parse_store_path_name("/nix/store/0c7c9a1l8jdm9qqwwdh1ax0xw9ywcwpn-hello-2.10"),
+        ("hello".to_string(), "2.10".to_string())
+    );
+    assert_eq!(
+        parse_store_path_name(
+            "/nix/store/0c7c9a1l8jdm9qqwwdh1ax0xw9ywcwpn-nixos-system-host-21.05pre"
+        ),
+        ("nixos-system-host".to_string(), "21.05pre".to_string())
+    );
+    assert_eq!(
+        parse_store_path_name("/nix/store/0c7c9a1l8jdm9qqwwdh1ax0xw9ywcwpn-etc"),
+        ("etc".to_string(), String::new())
+    );
+    assert_eq!(
+        parse_store_path_name("/nix/store/0c7c9a1l8jdm9qqwwdh1ax0xw9ywcwpn-openssl-1.1.1k-bin"),
+        ("openssl".to_string(), "1.1.1k-bin".to_string())
+    );
+}
+
+#[test]
+fn test_diff_closures() {
+    let old = vec![
+        ClosurePath {
+            path: "/nix/store/aaaa-hello-2.10".to_string(),
+            size: 100,
+        },
+        ClosurePath {
+            path: "/nix/store/bbbb-bash-4.4".to_string(),
+            size: 200,
+        },
+        ClosurePath {
+            path: "/nix/store/cccc-removed-1.0".to_string(),
+            size: 50,
+        },
+    ];
+    let new = vec![
+        ClosurePath {
+            path: "/nix/store/dddd-hello-2.11".to_string(),
+            size: 110,
+        },
+        ClosurePath {
+            path: "/nix/store/bbbb-bash-4.4".to_string(),
+            size: 200,
+        },
+        ClosurePath {
+            path: "/nix/store/eeee-added-0.1".to_string(),
+            size: 10,
+        },
+    ];
+
+    let diff = diff_closures(&old, &new);
+
+    assert_eq!(
+        diff.changed,
+        vec![PackageChange {
+            name: "hello".to_string(),
+            old_versions: vec!["2.10".to_string()],
+            new_versions: vec!["2.11".to_string()],
+        }]
+    );
+    assert_eq!(diff.added.len(), 1);
+    assert_eq!(diff.added[0].name, "added");
+    assert_eq!(diff.removed.len(), 1);
+    assert_eq!(diff.removed[0].name, "removed");
+    assert_eq!(diff.old_size, 350);
+    assert_eq!(diff.new_size, 320);
+}
@@ -0,0 +1,379 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use flexi_logger::{writers::LogWriter, DeferredNow, Record};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::report::Diagnostic;
+use crate::runs::{RunManifest, RunRecorder};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFileFormat {
+    Json,
+    Logfmt,
+}
+
+impl std::str::FromStr for LogFileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogFileFormat::Json),
+            "logfmt" => Ok(LogFileFormat::Logfmt),
+            _ => Err(format!(
+                "unknown log format `{}`, expected `json` or `logfmt`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Push,
+    Diff,
+    Activate,
+    /// Activation was left to the node, for when its maintenance window opens
+    Defer,
+    Revoke,
+    Exec,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Started,
+    Succeeded,
+    Failed,
+    /// The profile's closure was active on the node already, so activating it was skipped
+    AlreadyActive,
+}
+
+#[derive(Serialize, Debug)]
+struct LogRecord<'a> {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    timestamp: String,
+    level: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+    /// The code of the warning this is, see `crate::warnings::Warning`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<Phase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+/// Formats a point in time as an RFC 3339 UTC timestamp with millisecond precision
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[test]
+fn test_format_timestamp() {
+    assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert_eq!(
+        format_timestamp(UNIX_EPOCH + std::time::Duration::from_millis(1_614_556_800_250)),
+        "2021-03-01T00:00:00.250Z"
+    );
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c == '\\')
+    {
+        return value.to_string();
+    }
+
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+fn format_logfmt(record: &LogRecord) -> String {
+    const FIELDS: [&str; 10] = [
+        "timestamp",
+        "level",
+        "target",
+        "warning",
+        "node",
+        "profile",
+        "phase",
+        "status",
+        "message",
+        "data",
+    ];
+
+    // Going through serde_json keeps the field names and skipping rules in one place
+    let fields = match serde_json::to_value(record) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => return String::new(),
+    };
+
+    FIELDS
+        .iter()
+        .filter_map(|k| fields.get(*k).map(|v| (k, v)))
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => format!("{}={}", k, logfmt_value(s)),
+            _ => format!("{}={}", k, logfmt_value(&v.to_string())),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[test]
+fn test_format_logfmt() {
+    let record = LogRecord {
+        schema_version: crate::schema::SCHEMA_VERSION,
+        timestamp: "1970-01-01T00:00:00.000Z".to_string(),
+        level: "info",
+        target: None,
+        warning: None,
+        node: Some("web-1"),
+        profile: Some("system"),
+        phase: Some(Phase::Push),
+        status: Some(Status::Failed),
+        message: Some("exit code \"1\"".to_string()),
+        data: None,
+    };
+
+    assert_eq!(
+        format_logfmt(&record),
+        r#"timestamp=1970-01-01T00:00:00.000Z level=info node=web-1 profile=system phase=push status=failed message="exit code \"1\"""#
+    );
+}
+
+/// A log file receiving every log record and deployment phase change as a structured record,
+/// regardless of how verbose the terminal output is
+///
+/// The phase changes of a deployment can also be put together into a run manifest, with or without a log file.
+#[derive(Clone)]
+pub struct EventLog {
+    file: Option<Arc<Mutex<File>>>,
+    format: LogFileFormat,
+    run: Option<Arc<Mutex<RunRecorder>>>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl EventLog {
+    pub fn open(path: &str, format: LogFileFormat) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(EventLog {
+            file: Some(Arc::new(Mutex::new(file))),
+            format,
+            run: None,
+        })
+    }
+
+    /// Records the phase changes of a deployment as a run manifest, besides writing them to `log` if there is one
+    pub fn recording_run(log: Option<EventLog>) -> Self {
+        let (file, format) = match log {
+            Some(log) => (log.file, log.format),
+            None => (None, LogFileFormat::Json),
+        };
+
+        EventLog {
+            file,
+            format,
+            run: Some(Arc::new(Mutex::new(RunRecorder::new(now_millis() / 1000)))),
+        }
+    }
+
+    /// Adds a profile about to be deployed to the run manifest, if one is recorded
+    pub fn plan(&self, node: &str, profile: &str, closure: &str) {
+        if let Some(ref run) = self.run {
+            run.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .plan(node, profile, closure);
+        }
+    }
+
+    /// The run manifest recorded so far, if one is recorded
+    pub fn run_manifest(&self) -> Option<RunManifest> {
+        self.run.as_ref().map(|run| {
+            run.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .finish(now_millis() / 1000)
+        })
+    }
+
+    fn write_record(&self, record: &LogRecord) -> Result<(), std::io::Error> {
+        let file = match self.file {
+            Some(ref x) => x,
+            None => return Ok(()),
+        };
+
+        let line = match self.format {
+            LogFileFormat::Json => serde_json::to_string(record)?,
+            LogFileFormat::Logfmt => format_logfmt(record),
+        };
+
+        // Each record is written with a single call while holding the lock, so lines never interleave
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file, "{}", line)
+    }
+
+    pub fn phase(
+        &self,
+        node: &str,
+        profile: Option<&str>,
+        phase: Phase,
+        status: Status,
+        message: Option<String>,
+        data: Option<serde_json::Value>,
+    ) {
+        if let (Some(ref run), Some(profile)) = (&self.run, profile) {
+            run.lock().unwrap_or_else(|e| e.into_inner()).record(
+                node,
+                profile,
+                phase,
+                status,
+                now_millis(),
+            );
+        }
+
+        let record = LogRecord {
+            schema_version: crate::schema::SCHEMA_VERSION,
+            timestamp: format_timestamp(SystemTime::now()),
+            level: match status {
+                Status::Failed => "error",
+                _ => "info",
+            },
+            target: None,
+            warning: None,
+            node: Some(node),
+            profile,
+            phase: Some(phase),
+            status: Some(status),
+            message,
+            data,
+        };
+
+        if let Err(e) = self.write_record(&record) {
+            eprintln!("Failed to write to log file: {}", e);
+        }
+    }
+}
+
+/// Records a phase change in `event_log`, if there is one
+pub fn record_phase(
+    event_log: Option<&EventLog>,
+    node: &str,
+    profile: Option<&str>,
+    phase: Phase,
+    status: Status,
+) {
+    if let Some(event_log) = event_log {
+        event_log.phase(node, profile, phase, status, None, None);
+    }
+}
+
+/// Records a failed phase in `event_log` along with its error, if there is a log
+///
+/// If the error comes from a failed command, the command and the end of its standard error are kept as data.
+pub fn record_failure(
+    event_log: Option<&EventLog>,
+    node: &str,
+    profile: Option<&str>,
+    phase: Phase,
+    error: &dyn Diagnostic,
+) {
+    if let Some(event_log) = event_log {
+        event_log.phase(
+            node,
+            profile,
+            phase,
+            Status::Failed,
+            Some(error.to_string()),
+            error
+                .failure()
+                .and_then(|failure| serde_json::to_value(failure).ok()),
+        );
+    }
+}
+
+impl LogWriter for EventLog {
+    fn write(&self, _now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+        let level = record.level().to_string().to_lowercase();
+
+        self.write_record(&LogRecord {
+            schema_version: crate::schema::SCHEMA_VERSION,
+            timestamp: format_timestamp(SystemTime::now()),
+            level: &level,
+            target: Some(record.target()),
+            warning: crate::warnings::code_of_target(record.target()),
+            node: None,
+            profile: None,
+            phase: None,
+            status: None,
+            message: Some(record.args().to_string()),
+            data: None,
+        })
+    }
+
+    fn flush(&self) -> Result<(), std::io::Error> {
+        match self.file {
+            Some(ref file) => file.lock().unwrap_or_else(|e| e.into_inner()).flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+}
@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use std::process::{ExitStatus, Stdio};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use crate::report::Diagnostic;
+
+/// Runs `command`, prefixing every line of its output with `[prefix]`, so that the output
+/// of several commands running at once stays readable
+pub async fn run_prefixed(
+    prefix: &str,
+    mut command: Command,
+) -> Result<ExitStatus, std::io::Error> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdout_lines = BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+    let mut stderr_lines = BufReader::new(child.stderr.take().expect("stderr is piped")).lines();
+
+    let stdout_task = async {
+        while let Some(line) = stdout_lines.next_line().await? {
+            println!("[{}] {}", prefix, line);
+        }
+        Ok::<(), std::io::Error>(())
+    };
+
+    let stderr_task = async {
+        while let Some(line) = stderr_lines.next_line().await? {
+            eprintln!("[{}] {}", prefix, line);
+        }
+        Ok::<(), std::io::Error>(())
+    };
+
+    let (stdout_result, stderr_result) = tokio::join!(stdout_task, stderr_task);
+    stdout_result?;
+    stderr_result?;
+
+    child.wait().await
+}
+
+#[derive(Error, Debug)]
+pub enum ExecError {
+    #[error("Failed to run command over SSH on node `{0}`: {1}")]
+    SSHExec(String, std::io::Error),
+    #[error("Command on node `{0}` resulted in a bad exit code: {1:?}")]
+    SSHExecExit(String, Option<i32>),
+}
+
+impl Diagnostic for ExecError {}
+
+pub struct ExecData<'a> {
+    pub node_name: &'a str,
+    pub hostname: &'a str,
+    pub ssh_user: &'a str,
+    pub ssh_opts: &'a [String],
+    pub command: &'a [String],
+}
+
+pub async fn exec_node(data: ExecData<'_>) -> Result<(), ExecError> {
+    let ssh_addr = format!("{}@{}", data.ssh_user, data.hostname);
+
+    let mut ssh_exec_command = Command::new("ssh");
+    ssh_exec_command.arg(&ssh_addr);
+
+    for ssh_opt in data.ssh_opts {
+        ssh_exec_command.arg(ssh_opt);
+    }
+
+    let remote_command = data.command.join(" ");
+
+    debug!(
+        "Running `{}` on node `{}` ({})",
+        remote_command, data.node_name, ssh_addr
+    );
+
+    ssh_exec_command.arg(remote_command);
+
+    let exit_status = run_prefixed(data.node_name, ssh_exec_command)
+        .await
+        .map_err(|x| ExecError::SSHExec(data.node_name.to_string(), x))?;
+
+    match exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(ExecError::SSHExecExit(data.node_name.to_string(), a)),
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CopyFileError {
+    #[error("Failed to open local file: {0}")]
+    Open(std::io::Error),
+    #[error("Invalid file mode `{0}`, expected an octal number like 0644")]
+    InvalidMode(String),
+    #[error("Failed to run copy command over SSH: {0}")]
+    SSHCopy(std::io::Error),
+    #[error("Copying over SSH resulted in a bad exit code: {0:?}")]
+    SSHCopyExit(Option<i32>),
+}
+
+pub struct CopyFileData<'a> {
+    pub source: &'a str,
+    pub destination: &'a str,
+    pub mode: Option<&'a str>,
+    pub sudo: Option<String>,
+    pub hostname: &'a str,
+    pub ssh_user: &'a str,
+    pub ssh_opts: &'a [String],
+}
+
+fn build_copy_file_command(
+    destination: &str,
+    mode: Option<&str>,
+    sudo: &Option<String>,
+) -> Result<String, CopyFileError> {
+    // Write next to the destination first, so that the file is replaced atomically
+    let temp_destination = super::shell_quote(&format!("{}.deploy-rs-tmp", destination));
+
+    let mut copy_command = format!("cat > {}", temp_destination);
+
+    if let Some(mode) = mode {
+        if u32::from_str_radix(mode, 8).is_err() {
+            return Err(CopyFileError::InvalidMode(mode.to_string()));
+        }
+
+        copy_command = format!("{} && chmod {} {}", copy_command, mode, temp_destination);
+    }
+
+    copy_command = format!(
+        "{} && mv {} {}",
+        copy_command,
+        temp_destination,
+        super::shell_quote(destination)
+    );
+
+    if let Some(sudo_cmd) = sudo {
+        copy_command = format!("{} sh -c {}", sudo_cmd, super::shell_quote(&copy_command));
+    }
+
+    Ok(copy_command)
+}
+
+#[test]
+fn test_copy_file_command_builder() {
+    assert_eq!(
+        build_copy_file_command("/etc/motd", Some("0644"), &None).unwrap(),
+        "cat > '/etc/motd.deploy-rs-tmp' && chmod 0644 '/etc/motd.deploy-rs-tmp' && mv '/etc/motd.deploy-rs-tmp' '/etc/motd'"
+    );
+    assert_eq!(
+        build_copy_file_command("/etc/motd", None, &Some("sudo -u root".to_string())).unwrap(),
+        r#"sudo -u root sh -c 'cat > '\''/etc/motd.deploy-rs-tmp'\'' && mv '\''/etc/motd.deploy-rs-tmp'\'' '\''/etc/motd'\'''"#
+    );
+    assert!(build_copy_file_command("/etc/motd", Some("rw-r--r--"), &None).is_err());
+}
+
+pub async fn copy_file(data: CopyFileData<'_>) -> Result<(), CopyFileError> {
+    let copy_command = build_copy_file_command(data.destination, data.mode, &data.sudo)?;
+
+    let source = std::fs::File::open(data.source).map_err(CopyFileError::Open)?;
+
+    let ssh_addr = format!("{}@{}", data.ssh_user, data.hostname);
+
+    let mut ssh_copy_command = Command::new("ssh");
+    ssh_copy_command.arg(&ssh_addr);
+
+    for ssh_opt in data.ssh_opts {
+        ssh_copy_command.arg(ssh_opt);
+    }
+
+    debug!("Constructed copy command: {}", copy_command);
+
+    let ssh_copy_exit_status = ssh_copy_command
+        .arg(copy_command)
+        .stdin(Stdio::from(source))
+        .status()
+        .await
+        .map_err(CopyFileError::SSHCopy)?;
+
+    match ssh_copy_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(CopyFileError::SSHCopyExit(a)),
+    }
+}
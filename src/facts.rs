@@ -0,0 +1,189 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::report::CommandFailure;
+use crate::state;
+use crate::transport::{NodeTarget, RunOnNodeError, Transport};
+
+/// How long recorded facts are gone by before they're gathered again, in seconds
+pub const FACTS_TTL: u64 = 24 * 60 * 60;
+
+/// How a node runs things in the background, which decides how deferred activations wait for their time
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InitSystem {
+    Systemd,
+    /// Anything else, e.g. OpenRC or runit
+    Other,
+}
+
+/// What deploy-rs knows about the system of a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeFacts {
+    /// As `nix --version` gives it, e.g. `2.18.1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nix_version: Option<String>,
+    /// `ID` and `VERSION_ID` from `/etc/os-release`, e.g. `nixos 23.11`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    pub init_system: InitSystem,
+    /// How the SSH user can run commands as others, `sudo` or `doas`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalation: Option<String>,
+    /// Free space on the file system of the Nix store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store_free_kib: Option<u64>,
+    /// Whether the node's Nix daemon trusts the SSH user, which lets it be copied to over `ssh-ng://` without
+    /// signatures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trusted: Option<bool>,
+    /// Seconds since the epoch
+    pub gathered_at: u64,
+}
+
+impl NodeFacts {
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.gathered_at) < FACTS_TTL
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FactsError {
+    #[error("Failed to gather facts on the node: {0}")]
+    Run(RunOnNodeError),
+    #[error("Gathering facts on the node failed with {0}")]
+    Exit(CommandFailure),
+}
+
+/// Prints one `key=value` line per fact, leaving out what the node doesn't have
+const FACTS_SCRIPT: &str = r#"echo "nix=$(nix --version 2>/dev/null | head -n 1)"; if [ -r /etc/os-release ]; then (. /etc/os-release; echo "os=$ID $VERSION_ID"); fi; if [ -d /run/systemd/system ]; then echo init=systemd; else echo init=other; fi; if command -v sudo >/dev/null 2>&1; then echo escalation=sudo; elif command -v doas >/dev/null 2>&1; then echo escalation=doas; fi; echo "store_free=$(df -Pk /nix/store 2>/dev/null | awk 'NR == 2 { print $4 }')"; echo "trusted=$(nix --extra-experimental-features nix-command store ping --store daemon 2>/dev/null | awk '/^Trusted:/ { print $2 }')""#;
+
+pub fn parse_facts(output: &str, gathered_at: u64) -> NodeFacts {
+    let mut facts = NodeFacts {
+        nix_version: None,
+        os: None,
+        init_system: InitSystem::Other,
+        escalation: None,
+        store_free_kib: None,
+        trusted: None,
+        gathered_at,
+    };
+
+    for line in output.lines() {
+        let mut parts = line.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !value.trim().is_empty() => (key, value.trim()),
+            _ => continue,
+        };
+
+        match key {
+            // `nix (Nix) 2.18.1`
+            "nix" => facts.nix_version = value.split_whitespace().last().map(|x| x.to_string()),
+            "os" => facts.os = Some(value.to_string()),
+            "init" if value == "systemd" => facts.init_system = InitSystem::Systemd,
+            "escalation" => facts.escalation = Some(value.to_string()),
+            "store_free" => facts.store_free_kib = value.parse().ok(),
+            // Nix before 2.15 doesn't tell
+            "trusted" => facts.trusted = Some(value == "1"),
+            _ => (),
+        }
+    }
+
+    facts
+}
+
+#[test]
+fn test_parse_facts() {
+    assert_eq!(
+        parse_facts(
+            "nix=nix (Nix) 2.18.1\nos=nixos 23.11\ninit=systemd\nescalation=sudo\nstore_free=12582912\ntrusted=1\n",
+            1700000000
+        ),
+        NodeFacts {
+            nix_version: Some("2.18.1".to_string()),
+            os: Some("nixos 23.11".to_string()),
+            init_system: InitSystem::Systemd,
+            escalation: Some("sudo".to_string()),
+            store_free_kib: Some(12582912),
+            trusted: Some(true),
+            gathered_at: 1700000000,
+        }
+    );
+
+    assert_eq!(
+        parse_facts(
+            "nix=\nos=alpine 3.19\ninit=other\nescalation=doas\nstore_free=\ntrusted=\n",
+            0
+        ),
+        NodeFacts {
+            nix_version: None,
+            os: Some("alpine 3.19".to_string()),
+            init_system: InitSystem::Other,
+            escalation: Some("doas".to_string()),
+            store_free_kib: None,
+            trusted: None,
+            gathered_at: 0,
+        }
+    );
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// The facts recorded for a node by an earlier run, if they're recent enough to go by
+pub fn cached_facts(node_name: &str) -> Option<NodeFacts> {
+    let node_state = state::state_dir()
+        .and_then(|dir| state::load_node_state(&dir, node_name))
+        .map_err(|e| debug!("Not using recorded facts of node `{}`: {}", node_name, e))
+        .ok()?;
+
+    node_state.facts.filter(|x| x.is_fresh(now()))
+}
+
+/// Gathers the facts of a node, and records them for later runs
+pub async fn gather_facts(
+    transport: &dyn Transport,
+    target: &NodeTarget<'_>,
+    nix_env: crate::deploy::NixEnv<'_>,
+    node_name: &str,
+) -> Result<NodeFacts, FactsError> {
+    let command = crate::deploy::with_nix_env(
+        nix_env,
+        format!("sh -c {}", crate::shell_quote(FACTS_SCRIPT)),
+    );
+
+    let output = transport
+        .run_on_node(target, &command, true)
+        .await
+        .map_err(FactsError::Run)?;
+
+    match output.code {
+        Some(0) => (),
+        _ => return Err(FactsError::Exit(CommandFailure::new(&command, &output))),
+    };
+
+    let facts = parse_facts(&String::from_utf8_lossy(&output.stdout), now());
+
+    let recorded = state::state_dir().and_then(|dir| {
+        let mut node_state = state::load_node_state(&dir, node_name)?;
+        node_state.facts = Some(facts.clone());
+        state::save_node_state(&dir, node_name, &node_state)
+    });
+
+    if let Err(e) = recorded {
+        warn!("Could not record the facts of node `{}`: {}", node_name, e);
+    }
+
+    Ok(facts)
+}
@@ -0,0 +1,196 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use crate::data::{Data, Node};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    /// Graphviz
+    Dot,
+    D2,
+}
+
+impl std::str::FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(GraphFormat::Dot),
+            "d2" => Ok(GraphFormat::D2),
+            _ => Err(format!(
+                "unknown graph format `{}`, expected `dot` or `d2`",
+                s
+            )),
+        }
+    }
+}
+
+/// Quotes a string for both Graphviz and D2, which escape the same way
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Profiles of a node in the order they're deployed in, and how many of them `profilesOrder` puts in order
+fn ordered_profiles(node: &Node) -> (Vec<&str>, usize) {
+    let mut profiles: Vec<&str> = Vec::new();
+
+    for profile_name in &node.node_settings.profiles_order {
+        if node.node_settings.profiles.contains_key(profile_name)
+            && !profiles.contains(&profile_name.as_str())
+        {
+            profiles.push(profile_name);
+        }
+    }
+
+    let ordered = profiles.len();
+
+    let mut rest: Vec<&str> = node
+        .node_settings
+        .profiles
+        .keys()
+        .map(|x| x.as_str())
+        .filter(|x| !profiles.contains(x))
+        .collect();
+    rest.sort_unstable();
+    profiles.extend(rest);
+
+    (profiles, ordered)
+}
+
+fn node_label(node_name: &str, node: &Node) -> String {
+    let mut label = format!("{}\\n({})", node_name, node.node_settings.hostname);
+
+    if !node.node_settings.tags.is_empty() {
+        label.push_str(&format!("\\ntags: {}", node.node_settings.tags.join(", ")));
+    }
+
+    label
+}
+
+/// Renders the nodes of a deployment with their profiles, and edges between the profiles `profilesOrder`
+/// deploys one after the other
+pub fn render_graph(data: &Data, format: GraphFormat) -> String {
+    let mut nodes: Vec<(&String, &Node)> = data.nodes.iter().collect();
+    nodes.sort_by_key(|(node_name, _)| *node_name);
+
+    let mut out = String::new();
+
+    if format == GraphFormat::Dot {
+        out.push_str("digraph deploy {\n  rankdir=LR;\n  node [shape=box];\n");
+    }
+
+    for (node_name, node) in nodes {
+        let (profiles, ordered) = ordered_profiles(node);
+        // Profiles are keyed by their node in Graphviz, where all names share one namespace
+        let key = |profile: &str| match format {
+            GraphFormat::Dot => quote(&format!("{}/{}", node_name, profile)),
+            GraphFormat::D2 => quote(profile),
+        };
+
+        match format {
+            GraphFormat::Dot => out.push_str(&format!(
+                "  subgraph {} {{\n    label=\"{}\";\n",
+                quote(&format!("cluster_{}", node_name)),
+                node_label(node_name, node).replace('"', "\\\"")
+            )),
+            GraphFormat::D2 => out.push_str(&format!(
+                "{}: \"{}\" {{\n",
+                quote(node_name),
+                node_label(node_name, node).replace('"', "\\\"")
+            )),
+        }
+
+        for profile in &profiles {
+            match format {
+                GraphFormat::Dot => out.push_str(&format!(
+                    "    {} [label={}];\n",
+                    key(profile),
+                    quote(profile)
+                )),
+                GraphFormat::D2 => out.push_str(&format!("  {}\n", key(profile))),
+            }
+        }
+
+        for pair in profiles[..ordered].windows(2) {
+            match format {
+                GraphFormat::Dot => {
+                    out.push_str(&format!("    {} -> {};\n", key(pair[0]), key(pair[1])))
+                }
+                GraphFormat::D2 => {
+                    out.push_str(&format!("  {} -> {}\n", key(pair[0]), key(pair[1])))
+                }
+            }
+        }
+
+        match format {
+            GraphFormat::Dot => out.push_str("  }\n"),
+            GraphFormat::D2 => out.push_str("}\n"),
+        }
+    }
+
+    if format == GraphFormat::Dot {
+        out.push_str("}\n");
+    }
+
+    out
+}
+
+#[test]
+fn test_render_graph() {
+    let data: Data = serde_json::from_str(
+        r#"{
+            "nodes": {
+                "web-1": {
+                    "hostname": "web-1.example.com",
+                    "tags": ["web", "eu"],
+                    "profilesOrder": ["system", "app"],
+                    "profiles": {
+                        "app": { "path": "/nix/store/blah-app" },
+                        "system": { "path": "/nix/store/blah-system" },
+                        "home": { "path": "/nix/store/blah-home" }
+                    }
+                },
+                "db-1": {
+                    "hostname": "10.0.0.2",
+                    "profiles": { "system": { "path": "/nix/store/blah-system" } }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        render_graph(&data, GraphFormat::Dot),
+        r#"digraph deploy {
+  rankdir=LR;
+  node [shape=box];
+  subgraph "cluster_db-1" {
+    label="db-1\n(10.0.0.2)";
+    "db-1/system" [label="system"];
+  }
+  subgraph "cluster_web-1" {
+    label="web-1\n(web-1.example.com)\ntags: web, eu";
+    "web-1/system" [label="system"];
+    "web-1/app" [label="app"];
+    "web-1/home" [label="home"];
+    "web-1/system" -> "web-1/app";
+  }
+}
+"#
+    );
+
+    assert_eq!(
+        render_graph(&data, GraphFormat::D2),
+        r#""db-1": "db-1\n(10.0.0.2)" {
+  "system"
+}
+"web-1": "web-1\n(web-1.example.com)\ntags: web, eu" {
+  "system"
+  "app"
+  "home"
+  "system" -> "app"
+}
+"#
+    );
+}
@@ -0,0 +1,206 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use thiserror::Error;
+
+use crate::transport::{Invocation, NodeTarget, OutputMode, RunOnNodeError, Transport};
+
+#[derive(Error, Debug)]
+pub enum HostKeyError {
+    #[error("Failed to create the directory for pinned host keys: {0}")]
+    CreateDir(std::io::Error),
+    #[error("Failed to connect to the node to pin its host key: {0}")]
+    Connect(RunOnNodeError),
+    #[error(
+        "The host key of node `{0}` is not the one pinned in {1}{}, the node may be impersonated. \
+         If its key was changed on purpose, `deploy state clean {0}` forgets the pinned one",
+        match .2 { Some(x) => format!(" ({})", x), None => String::new() }
+    )]
+    Changed(String, String, Option<String>),
+}
+
+/// The known hosts file the host key of `node_name` is pinned in, kept with the rest of deploy's state rather than
+/// in `~/.ssh/known_hosts`, which CI runners often start from scratch
+pub fn known_hosts_path(state_dir: &Path, node_name: &str) -> PathBuf {
+    state_dir.join("known_hosts").join(node_name)
+}
+
+/// SSH options making the first connection to a node learn its host key into `known_hosts` and every later one
+/// refuse any other key, whatever the user's own known hosts say
+pub fn pinning_opts(known_hosts: &Path) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        "StrictHostKeyChecking=accept-new".to_string(),
+        "-o".to_string(),
+        format!("UserKnownHostsFile={}", known_hosts.display()),
+    ]
+}
+
+/// Whether SSH refused to connect because the host key isn't the one it knows. With `accept-new`, unknown keys are
+/// accepted, so a failed verification means the key changed
+pub fn key_changed(stderr_tail: &[String]) -> bool {
+    stderr_tail.iter().any(|line| {
+        line.contains("REMOTE HOST IDENTIFICATION HAS CHANGED")
+            || line.contains("Host key verification failed")
+    })
+}
+
+/// The fingerprint of the first key in what `ssh-keygen -l` printed, as in `SHA256:...`
+pub fn parse_fingerprint(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .find(|x| x.starts_with("SHA256:") || x.starts_with("MD5:"))
+        .map(|x| x.to_string())
+}
+
+/// The fingerprint of the host key pinned in `known_hosts`, if there's one
+pub async fn fingerprint(transport: &dyn Transport, known_hosts: &Path) -> Option<String> {
+    let mut keygen_command = Invocation::new("ssh-keygen");
+    keygen_command
+        .arg("-l")
+        .arg("-f")
+        .arg(known_hosts.to_string_lossy())
+        .stdout(OutputMode::Capture);
+
+    match transport.run(&keygen_command).await {
+        Ok(output) if output.code == Some(0) => {
+            parse_fingerprint(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            debug!(
+                "Could not read the host key pinned in {}: {:?}",
+                known_hosts.display(),
+                output.code
+            );
+            None
+        }
+        Err(e) => {
+            debug!(
+                "Could not read the host key pinned in {}: {}",
+                known_hosts.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Connects to the node over SSH with `pinning_opts` in its options, which pins its host key in `known_hosts` the
+/// first time, returning the fingerprint of the pinned key. `pinned` is the fingerprint recorded before, shown if
+/// the key changed. Failing to connect for any other reason is left to what's done on the node afterwards to report
+pub async fn pin_host_key(
+    transport: &dyn Transport,
+    target: &NodeTarget<'_>,
+    node_name: &str,
+    known_hosts: &Path,
+    pinned: Option<&str>,
+) -> Result<Option<String>, HostKeyError> {
+    if let Some(dir) = known_hosts.parent() {
+        std::fs::create_dir_all(dir).map_err(HostKeyError::CreateDir)?;
+    }
+
+    let output = transport
+        .run_on_node(target, "true", true)
+        .await
+        .map_err(HostKeyError::Connect)?;
+
+    if output.code != Some(0) {
+        if key_changed(&output.stderr_tail) {
+            return Err(HostKeyError::Changed(
+                node_name.to_string(),
+                known_hosts.display().to_string(),
+                pinned.map(|x| x.to_string()),
+            ));
+        }
+
+        debug!(
+            "Could not connect to node `{}` to pin its host key: {:?}",
+            node_name, output.code
+        );
+        return Ok(None);
+    }
+
+    Ok(fingerprint(transport, known_hosts).await)
+}
+
+#[test]
+fn test_pin_host_key() {
+    let known_hosts = known_hosts_path(Path::new("/var/lib/ci/deploy-rs"), "web-1");
+    assert_eq!(
+        pinning_opts(&known_hosts),
+        vec![
+            "-o",
+            "StrictHostKeyChecking=accept-new",
+            "-o",
+            "UserKnownHostsFile=/var/lib/ci/deploy-rs/known_hosts/web-1",
+        ]
+    );
+
+    assert_eq!(
+        parse_fingerprint(
+            "256 SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8 10.0.0.5 (ED25519)\n"
+        )
+        .as_deref(),
+        Some("SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8")
+    );
+    assert_eq!(
+        parse_fingerprint("/tmp/known_hosts is not a public key file.\n"),
+        None
+    );
+
+    assert!(key_changed(&[
+        "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@".to_string(),
+        "@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @".to_string(),
+        "Host key verification failed.".to_string(),
+    ]));
+    assert!(!key_changed(&[
+        "ssh: connect to host 10.0.0.5 port 22: Connection refused".to_string()
+    ]));
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-hostkey-{}", std::process::id()));
+    let known_hosts = known_hosts_path(&dir, "web-1");
+    let opts = pinning_opts(&known_hosts);
+    let target = NodeTarget::Ssh {
+        address: "deploy@10.0.0.5".to_string(),
+        opts: &opts,
+        sudo_password: None,
+    };
+
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond(
+        "ssh-keygen -l",
+        Some(0),
+        "256 SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8 10.0.0.5 (ED25519)\n",
+    );
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    assert_eq!(
+        runtime
+            .block_on(pin_host_key(
+                &transport,
+                &target,
+                "web-1",
+                &known_hosts,
+                None
+            ))
+            .unwrap()
+            .as_deref(),
+        Some("SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8")
+    );
+    assert!(known_hosts.parent().unwrap().is_dir());
+
+    let commands = transport.commands();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[0], "[ssh://deploy@10.0.0.5] true");
+    assert_eq!(
+        commands[1],
+        format!("ssh-keygen -l -f {}", known_hosts.display())
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,327 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+use crate::report::CommandFailure;
+use crate::transport::{Invocation, OutputMode, Transport};
+
+/// A deployment tool whose node definitions `deploy import` translates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tool {
+    Colmena,
+    Morph,
+    NixOps,
+}
+
+impl std::str::FromStr for Tool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colmena" => Ok(Tool::Colmena),
+            "morph" => Ok(Tool::Morph),
+            "nixops" => Ok(Tool::NixOps),
+            _ => Err(format!(
+                "`{}` is not one of `colmena`, `morph` and `nixops`",
+                s
+            )),
+        }
+    }
+}
+
+impl Tool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Colmena => "colmena",
+            Tool::Morph => "morph",
+            Tool::NixOps => "nixops",
+        }
+    }
+
+    /// The attributes next to the nodes which aren't nodes themselves
+    fn reserved(self) -> &'static [&'static str] {
+        match self {
+            Tool::Colmena => &["meta", "defaults"],
+            Tool::Morph => &["network"],
+            Tool::NixOps => &["network", "defaults", "resources", "require"],
+        }
+    }
+}
+
+/// Where a node is deployed to, as its `deployment` options say
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedNode {
+    pub target_host: Option<String>,
+    pub target_user: Option<String>,
+    pub target_port: Option<u16>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("Failed to run nix-instantiate: {0}")]
+    Eval(std::io::Error),
+    #[error("Evaluating the nodes failed with {0}")]
+    EvalExit(CommandFailure),
+    #[error("Failed to parse the evaluated nodes: {0}")]
+    Parse(serde_json::Error),
+}
+
+/// Loads a network like each of the tools does, calling it if it's a function
+const NETWORK_EXPR: &str =
+    r#"let network = import file; in if builtins.isFunction network then network { } else network"#;
+
+/// The `deployment` options of one node, whose module is called with stand-ins for its arguments; a module whose
+/// `deployment` depends on its configuration can't be evaluated this way
+const DEPLOYMENT_EXPR: &str = r#"
+  let
+    value = network.${node};
+    args = {
+      name = node;
+      nodes = { };
+      config = { };
+      pkgs = { };
+      lib = { };
+      resources = { };
+      modulesPath = "";
+    };
+    module = if builtins.isFunction value then value (builtins.intersectAttrs (builtins.functionArgs value) args) else value;
+    deployment = module.deployment or { };
+  in {
+    targetHost = deployment.targetHost or null;
+    targetUser = deployment.targetUser or null;
+    targetPort = deployment.targetPort or null;
+    tags = deployment.tags or [ ];
+  }
+"#;
+
+async fn evaluate(
+    transport: &dyn Transport,
+    file: &str,
+    node: Option<&str>,
+    expr: &str,
+) -> Result<serde_json::Value, ImportError> {
+    let mut eval_command = Invocation::new("nix-instantiate");
+    eval_command
+        .arg("--eval")
+        .arg("--strict")
+        .arg("--json")
+        .arg("--expr")
+        .arg(format!(
+            "{{ file, node ? null }}: let network = {}; in {}",
+            NETWORK_EXPR, expr
+        ))
+        .arg("--argstr")
+        .arg("file")
+        .arg(file);
+
+    if let Some(node) = node {
+        eval_command.arg("--argstr").arg("node").arg(node);
+    }
+
+    eval_command.stdout(OutputMode::Capture);
+
+    debug!("Running import command: {}", eval_command);
+
+    let eval_output = transport
+        .run(&eval_command)
+        .await
+        .map_err(ImportError::Eval)?;
+
+    match eval_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(ImportError::EvalExit(CommandFailure::new(
+                &eval_command,
+                &eval_output,
+            )))
+        }
+    };
+
+    serde_json::from_slice(&eval_output.stdout).map_err(ImportError::Parse)
+}
+
+/// Evaluates the nodes of a network of `tool` in `file`, an absolute path, one at a time so that a node which can't
+/// be evaluated is left with the defaults instead of failing all of them
+pub async fn import_nodes(
+    transport: &dyn Transport,
+    tool: Tool,
+    file: &str,
+) -> Result<BTreeMap<String, ImportedNode>, ImportError> {
+    let reserved: Vec<String> = tool
+        .reserved()
+        .iter()
+        .map(|x| crate::nix_string(x))
+        .collect();
+
+    let names: Vec<String> = serde_json::from_value(
+        evaluate(
+            transport,
+            file,
+            None,
+            &format!(
+                "builtins.attrNames (removeAttrs network [ {} ])",
+                reserved.join(" ")
+            ),
+        )
+        .await?,
+    )
+    .map_err(ImportError::Parse)?;
+
+    let mut nodes = BTreeMap::new();
+
+    for name in names {
+        let node = match evaluate(transport, file, Some(&name), DEPLOYMENT_EXPR).await {
+            Ok(x) => serde_json::from_value(x).map_err(ImportError::Parse)?,
+            Err(ImportError::EvalExit(e)) => {
+                warn!(
+                    "Could not evaluate the deployment options of node `{}`, fill them in by hand: {}",
+                    name, e
+                );
+                ImportedNode::default()
+            }
+            Err(e) => return Err(e),
+        };
+
+        nodes.insert(name, node);
+    }
+
+    Ok(nodes)
+}
+
+/// The `deploy` output for the imported nodes, each with a `system` profile of the NixOS configuration of the same
+/// name, which is left to be made from the node's module
+pub fn render(
+    tool: Tool,
+    file: &str,
+    system: &str,
+    nodes: &BTreeMap<String, ImportedNode>,
+) -> String {
+    let mut lines = vec![
+        format!(
+            "# Imported from the {} network in {} by `deploy import`. Each node deploys",
+            tool.name(),
+            file
+        ),
+        "# `nixosConfigurations.<node>` of this flake, made from the node's module with `nixpkgs.lib.nixosSystem`."
+            .to_string(),
+        "deploy.nodes = {".to_string(),
+    ];
+
+    for (name, node) in nodes {
+        lines.push(format!("  {} = {{", crate::nix_attr(name)));
+        lines.push(format!(
+            "    hostname = {};",
+            crate::nix_string(node.target_host.as_deref().unwrap_or(name))
+        ));
+        // Each of the tools logs in as root unless told otherwise
+        lines.push(format!(
+            "    sshUser = {};",
+            crate::nix_string(node.target_user.as_deref().unwrap_or("root"))
+        ));
+
+        if let Some(port) = node.target_port {
+            lines.push(format!("    sshOpts = [ \"-p\" \"{}\" ];", port));
+        }
+
+        if !node.tags.is_empty() {
+            let tags: Vec<String> = node.tags.iter().map(|x| crate::nix_string(x)).collect();
+            lines.push(format!("    tags = [ {} ];", tags.join(" ")));
+        }
+
+        lines.push("    profiles.system = {".to_string());
+        lines.push("      user = \"root\";".to_string());
+        lines.push(format!(
+            "      path = deploy-rs.lib.{}.activate.nixos self.nixosConfigurations.{};",
+            system,
+            crate::nix_attr(name)
+        ));
+        lines.push("    };".to_string());
+        lines.push("  };".to_string());
+    }
+
+    lines.push("};".to_string());
+
+    lines.join("\n") + "\n"
+}
+
+#[test]
+fn test_render() {
+    assert_eq!("nixops".parse(), Ok(Tool::NixOps));
+    assert!("nixos-rebuild".parse::<Tool>().is_err());
+
+    let mut nodes = BTreeMap::new();
+    nodes.insert(
+        "web-1".to_string(),
+        ImportedNode {
+            target_host: Some("10.0.0.1".to_string()),
+            target_user: Some("deploy".to_string()),
+            target_port: Some(2222),
+            tags: vec!["web".to_string(), "eu".to_string()],
+        },
+    );
+    nodes.insert("db.internal".to_string(), ImportedNode::default());
+
+    assert_eq!(
+        render(Tool::Colmena, "/src/hive.nix", "x86_64-linux", &nodes),
+        r#"# Imported from the colmena network in /src/hive.nix by `deploy import`. Each node deploys
+# `nixosConfigurations.<node>` of this flake, made from the node's module with `nixpkgs.lib.nixosSystem`.
+deploy.nodes = {
+  "db.internal" = {
+    hostname = "db.internal";
+    sshUser = "root";
+    profiles.system = {
+      user = "root";
+      path = deploy-rs.lib.x86_64-linux.activate.nixos self.nixosConfigurations."db.internal";
+    };
+  };
+  web-1 = {
+    hostname = "10.0.0.1";
+    sshUser = "deploy";
+    sshOpts = [ "-p" "2222" ];
+    tags = [ "web" "eu" ];
+    profiles.system = {
+      user = "root";
+      path = deploy-rs.lib.x86_64-linux.activate.nixos self.nixosConfigurations.web-1;
+    };
+  };
+};
+"#
+    );
+}
+
+#[test]
+fn test_import_nodes() {
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("builtins.attrNames", Some(0), r#"["db-1","web-1"]"#);
+    transport.respond(
+        "--argstr node db-1",
+        Some(0),
+        r#"{"targetHost":"10.0.0.4","targetUser":null,"targetPort":null,"tags":["db"]}"#,
+    );
+    transport.respond("--argstr node web-1", Some(1), "");
+
+    let nodes = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(import_nodes(&transport, Tool::Morph, "/src/network.nix"))
+        .unwrap();
+
+    assert!(transport.commands()[0].contains(r#"removeAttrs network [ "network" ]"#));
+    assert_eq!(
+        nodes["db-1"],
+        ImportedNode {
+            target_host: Some("10.0.0.4".to_string()),
+            target_user: None,
+            target_port: None,
+            tags: vec!["db".to_string()],
+        }
+    );
+    assert_eq!(nodes["web-1"], ImportedNode::default());
+}
@@ -12,12 +12,183 @@ use thiserror::Error;
 
 use flexi_logger::*;
 
-pub fn make_lock_path(temp_path: &str, closure: &str) -> String {
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The canary activate-rs waits to be removed to confirm an activation, for the closure going into a profile
+///
+/// Different profiles on one node can be deployed at the same time with the same closure, so both are in the name.
+pub fn make_lock_path(temp_path: &str, closure: &str, profile_path: &str) -> String {
+    format!(
+        "{}/deploy-rs-canary-{}",
+        temp_path,
+        activation_key(closure, profile_path)
+    )
+}
+
+/// The canary an activate-rs from before canaries were named after the profile too waits to be removed
+pub fn make_legacy_lock_path(temp_path: &str, closure: &str) -> String {
     let lock_hash =
         &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
     format!("{}/deploy-rs-canary-{}", temp_path, lock_hash)
 }
 
+/// Where activate-rs records the process group of a running activation script, so an aborted deployment can stop it
+pub fn make_activation_pid_path(temp_path: &str, closure: &str, profile_path: &str) -> String {
+    format!(
+        "{}/deploy-rs-activation-{}.pid",
+        temp_path,
+        activation_key(closure, profile_path)
+    )
+}
+
+/// Where activate-rs leaves what the activation did to systemd units, for the deploying machine to pick up
+pub fn make_unit_changes_path(temp_path: &str, closure: &str, profile_path: &str) -> String {
+    format!(
+        "{}/deploy-rs-units-{}.json",
+        temp_path,
+        activation_key(closure, profile_path)
+    )
+}
+
+/// Where a deferred activation keeps its variables until activate-rs reads them, removing the file
+pub fn make_activation_env_path(temp_path: &str, closure: &str, profile_path: &str) -> String {
+    format!(
+        "{}/deploy-rs-env-{}.json",
+        temp_path,
+        activation_key(closure, profile_path)
+    )
+}
+
+/// The variables of an activation as the line activate-rs reads with `--env-file`
+///
+/// They can hold secrets, so they're handed over on standard input or in a private file rather than on the node's
+/// command line, where anyone on it could read them.
+pub fn activation_env_input(env: &[(String, String)]) -> Vec<u8> {
+    let mut input = serde_json::to_vec(env).expect("variables always serialize");
+    input.push(b'\n');
+    input
+}
+
+/// Reads the variables of `activation_env_input` back from its last line, anything before it, like a sudo password
+/// sudo didn't ask for, isn't part of them
+pub fn parse_activation_env(input: &str) -> Result<Vec<(String, String)>, serde_json::Error> {
+    match input.lines().rev().find(|x| !x.trim().is_empty()) {
+        Some(line) => serde_json::from_str(line),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[test]
+fn test_activation_env_input() {
+    let env = vec![
+        ("DEPLOY_GIT_REV".to_string(), "abc123".to_string()),
+        ("DB_PASSWORD".to_string(), "it's\nfriday".to_string()),
+    ];
+
+    let input = String::from_utf8(activation_env_input(&env)).unwrap();
+    assert_eq!(input.lines().count(), 1);
+
+    assert_eq!(parse_activation_env(&input).unwrap(), env);
+    assert_eq!(
+        parse_activation_env(&format!("hunter2\n{}", input)).unwrap(),
+        env
+    );
+    assert_eq!(parse_activation_env("").unwrap(), Vec::new());
+    assert!(parse_activation_env("hunter2\n").is_err());
+}
+
+fn activation_key(closure: &str, profile_path: &str) -> String {
+    let lock_hash = &closure["/nix/store/".len()..closure.find('-').unwrap_or(closure.len())];
+    let profile_key = profile_path.trim_matches('/').replace('/', "-");
+    format!("{}-{}", lock_hash, profile_key)
+}
+
+#[test]
+fn test_make_lock_path() {
+    assert_eq!(
+        make_lock_path(
+            "/tmp",
+            "/nix/store/aaaa-app",
+            "/nix/var/nix/profiles/per-user/alice/app"
+        ),
+        "/tmp/deploy-rs-canary-aaaa-nix-var-nix-profiles-per-user-alice-app"
+    );
+    assert_eq!(
+        make_legacy_lock_path("/tmp", "/nix/store/aaaa-app"),
+        "/tmp/deploy-rs-canary-aaaa"
+    );
+    assert_ne!(
+        make_lock_path(
+            "/tmp",
+            "/nix/store/aaaa-app",
+            "/nix/var/nix/profiles/per-user/alice/app"
+        ),
+        make_lock_path(
+            "/tmp",
+            "/nix/store/aaaa-app",
+            "/nix/var/nix/profiles/per-user/bob/app"
+        )
+    );
+    assert_eq!(
+        make_activation_pid_path(
+            "/tmp",
+            "/nix/store/aaaa-app",
+            "/nix/var/nix/profiles/system"
+        ),
+        "/tmp/deploy-rs-activation-aaaa-nix-var-nix-profiles-system.pid"
+    );
+}
+
+/// Quotes a string so that it is passed as a single word to a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("/etc/motd"), "'/etc/motd'");
+    assert_eq!(shell_quote("it's here"), r#"'it'\''s here'"#);
+}
+
+/// Quotes a string as a Nix string literal, e.g. to use a node name as an attribute name
+pub fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+/// An attribute name as it's written in an attribute path like `checks.x86_64-linux.<name>`, quoted unless it's
+/// a plain identifier
+pub fn nix_attr(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+        }
+        _ => false,
+    };
+
+    match plain {
+        true => name.to_string(),
+        false => nix_string(name),
+    }
+}
+
+#[test]
+fn test_nix_string() {
+    assert_eq!(nix_string("web.example.com"), r#""web.example.com""#);
+    assert_eq!(nix_string(r#"say "hi""#), r#""say \"hi\"""#);
+    assert_eq!(nix_string(r#"C:\${HOME}"#), r#""C:\\\${HOME}""#);
+
+    assert_eq!(nix_attr("web-vm-test"), "web-vm-test");
+    assert_eq!(nix_attr("Deploy_Schema"), "Deploy_Schema");
+    assert_eq!(nix_attr("web.example.com"), r#""web.example.com""#);
+    assert_eq!(nix_attr("1st"), r#""1st""#);
+}
+
 const fn make_emoji(level: log::Level) -> &'static str {
     match level {
         log::Level::Error => "❌",
@@ -28,36 +199,107 @@ const fn make_emoji(level: log::Level) -> &'static str {
     }
 }
 
-pub fn logger_formatter_activate(
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "unknown color choice `{}`, expected `auto`, `always` or `never`",
+                s
+            )),
+        }
+    }
+}
+
+static USE_COLOR: AtomicBool = AtomicBool::new(true);
+static USE_ASCII: AtomicBool = AtomicBool::new(false);
+
+/// Sets how log lines are styled, `ColorChoice::Auto` only uses colors when stderr is a terminal
+pub fn set_output_style(color: ColorChoice, ascii: bool) {
+    let color = match color {
+        ColorChoice::Auto => atty::is(atty::Stream::Stderr),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+
+    USE_COLOR.store(color, Ordering::Relaxed);
+    USE_ASCII.store(ascii, Ordering::Relaxed);
+}
+
+/// The environment variable activate-rs takes `--color` from when it isn't given
+pub const COLOR_ENV: &str = "DEPLOY_RS_COLOR";
+
+/// The environment variable turning on `--ascii` for activate-rs when set to anything
+pub const ASCII_ENV: &str = "DEPLOY_RS_ASCII";
+
+/// Prefixes an activate-rs `command` with the environment making it style its output like this process does
+///
+/// The activation output is relayed through SSH, where activate-rs can't see our terminal,
+/// so it keeps colors by default and has to be told otherwise. It's told through the environment
+/// rather than arguments, which the activate-rs of profiles built with older versions would reject.
+pub fn with_output_style(command: &str) -> String {
+    let mut assignments = Vec::new();
+
+    if !USE_COLOR.load(Ordering::Relaxed) {
+        assignments.push(format!("{}=never", COLOR_ENV));
+    }
+
+    if USE_ASCII.load(Ordering::Relaxed) {
+        assignments.push(format!("{}=1", ASCII_ENV));
+    }
+
+    match assignments.is_empty() {
+        true => command.to_string(),
+        false => format!("env {} {}", assignments.join(" "), command),
+    }
+}
+
+fn write_log_line(
     w: &mut dyn std::io::Write,
-    _now: &mut DeferredNow,
     record: &Record,
+    emoji: &str,
+    tag: &str,
 ) -> Result<(), std::io::Error> {
     let level = record.level();
 
-    write!(
-        w,
-        "⭐ {} [activate] [{}] {}",
-        make_emoji(level),
-        style(level, level.to_string()),
-        record.args()
-    )
+    if !USE_ASCII.load(Ordering::Relaxed) {
+        write!(w, "{} {} ", emoji, make_emoji(level))?;
+    }
+
+    if USE_COLOR.load(Ordering::Relaxed) {
+        write!(w, "[{}] [{}] ", tag, style(level, level.to_string()))?;
+    } else {
+        write!(w, "[{}] [{}] ", tag, level)?;
+    }
+
+    write!(w, "{}", record.args())
 }
 
-pub fn logger_formatter_wait(
+pub fn logger_formatter_activate(
     w: &mut dyn std::io::Write,
     _now: &mut DeferredNow,
     record: &Record,
 ) -> Result<(), std::io::Error> {
-    let level = record.level();
+    write_log_line(w, record, "⭐", "activate")
+}
 
-    write!(
-        w,
-        "👀 {} [wait] [{}] {}",
-        make_emoji(level),
-        style(level, level.to_string()),
-        record.args()
-    )
+pub fn logger_formatter_wait(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_log_line(w, record, "👀", "wait")
 }
 
 pub fn logger_formatter_revoke(
@@ -65,15 +307,15 @@ pub fn logger_formatter_revoke(
     _now: &mut DeferredNow,
     record: &Record,
 ) -> Result<(), std::io::Error> {
-    let level = record.level();
+    write_log_line(w, record, "↩️", "revoke")
+}
 
-    write!(
-        w,
-        "↩️ {} [revoke] [{}] {}",
-        make_emoji(level),
-        style(level, level.to_string()),
-        record.args()
-    )
+pub fn logger_formatter_diff(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_log_line(w, record, "🔍", "diff")
 }
 
 pub fn logger_formatter_deploy(
@@ -81,15 +323,15 @@ pub fn logger_formatter_deploy(
     _now: &mut DeferredNow,
     record: &Record,
 ) -> Result<(), std::io::Error> {
-    let level = record.level();
+    write_log_line(w, record, "🚀", "deploy")
+}
 
-    write!(
-        w,
-        "🚀 {} [deploy] [{}] {}",
-        make_emoji(level),
-        style(level, level.to_string()),
-        record.args()
-    )
+pub fn logger_formatter_agent(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write_log_line(w, record, "🛰️", "agent")
 }
 
 pub enum LoggerType {
@@ -97,72 +339,218 @@ pub enum LoggerType {
     Activate,
     Wait,
     Revoke,
+    Diff,
+    Agent,
+}
+
+/// Builds a flexi_logger specification from a base level and user supplied `module=level` filters
+pub fn make_log_spec(log_level: log::LevelFilter, log_filter: Option<&str>) -> String {
+    let mut spec = log_level.to_string().to_lowercase();
+
+    if let Some(log_filter) = log_filter {
+        for filter in log_filter
+            .split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+        {
+            spec.push_str(", ");
+            spec.push_str(filter);
+        }
+    }
+
+    spec
+}
+
+#[test]
+fn test_make_log_spec() {
+    assert_eq!(make_log_spec(log::LevelFilter::Info, None), "info");
+    assert_eq!(
+        make_log_spec(
+            log::LevelFilter::Warn,
+            Some("nix_copy=debug, deploy::deploy=trace,")
+        ),
+        "warn, nix_copy=debug, deploy::deploy=trace"
+    );
 }
 
 pub fn init_logger(
-    debug_logs: bool,
+    log_level: log::LevelFilter,
+    log_filter: Option<&str>,
     log_dir: Option<&str>,
     logger_type: &LoggerType,
+    event_log: Option<&events::EventLog>,
 ) -> Result<(), FlexiLoggerError> {
     let logger_formatter = match &logger_type {
         LoggerType::Deploy => logger_formatter_deploy,
         LoggerType::Activate => logger_formatter_activate,
         LoggerType::Wait => logger_formatter_wait,
         LoggerType::Revoke => logger_formatter_revoke,
+        LoggerType::Diff => logger_formatter_diff,
+        LoggerType::Agent => logger_formatter_agent,
     };
 
-    if let Some(log_dir) = log_dir {
-        let mut logger = Logger::with_env_or_str("debug")
-            .log_to_file()
-            .format_for_stderr(logger_formatter)
-            .set_palette("196;208;51;7;8".to_string())
-            .directory(log_dir)
-            .duplicate_to_stderr(match debug_logs {
-                true => Duplicate::Debug,
-                false => Duplicate::Info,
-            })
-            .print_message();
+    if log_dir.is_some() || event_log.is_some() {
+        // Files always get at least debug logs, only the terminal output follows the verbosity
+        let mut logger = Logger::with_env_or_str(make_log_spec(
+            log_level.max(log::LevelFilter::Debug),
+            log_filter,
+        ))
+        .format_for_stderr(logger_formatter)
+        .set_palette("196;208;51;7;8".to_string())
+        .duplicate_to_stderr(match log_level {
+            log::LevelFilter::Off => Duplicate::None,
+            log::LevelFilter::Error => Duplicate::Error,
+            log::LevelFilter::Warn => Duplicate::Warn,
+            log::LevelFilter::Info => Duplicate::Info,
+            log::LevelFilter::Debug => Duplicate::Debug,
+            log::LevelFilter::Trace => Duplicate::Trace,
+        });
+
+        logger = match (log_dir, event_log) {
+            (Some(log_dir), Some(event_log)) => logger
+                .log_target(LogTarget::FileAndWriter(Box::new(event_log.clone())))
+                .directory(log_dir)
+                .print_message(),
+            (Some(log_dir), None) => logger.log_to_file().directory(log_dir).print_message(),
+            (None, Some(event_log)) => {
+                logger.log_target(LogTarget::Writer(Box::new(event_log.clone())))
+            }
+            (None, None) => unreachable!(),
+        };
 
         match logger_type {
             LoggerType::Activate => logger = logger.discriminant("activate"),
             LoggerType::Wait => logger = logger.discriminant("wait"),
             LoggerType::Revoke => logger = logger.discriminant("revoke"),
+            LoggerType::Diff => logger = logger.discriminant("diff"),
+            LoggerType::Agent => logger = logger.discriminant("agent"),
             LoggerType::Deploy => (),
         }
 
         logger.start()?;
     } else {
-        Logger::with_env_or_str(match debug_logs {
-            true => "debug",
-            false => "info",
-        })
-        .log_target(LogTarget::StdErr)
-        .format(logger_formatter)
-        .set_palette("196;208;51;7;8".to_string())
-        .start()?;
+        Logger::with_env_or_str(make_log_spec(log_level, log_filter))
+            .log_target(LogTarget::StdErr)
+            .format(logger_formatter)
+            .set_palette("196;208;51;7;8".to_string())
+            .start()?;
     }
 
     Ok(())
 }
 
+pub mod adopt;
+pub mod agent;
+pub mod bootstrap;
+pub mod bundle;
+pub mod capability;
+pub mod channel;
 pub mod data;
 pub mod deploy;
+pub mod diff;
+pub mod events;
+pub mod exec;
+pub mod facts;
+pub mod graph;
+pub mod hostkey;
+pub mod import;
+pub mod mac;
+pub mod nixlog;
+pub mod preflight;
+pub mod probe;
+pub mod pull;
 pub mod push;
+pub mod readiness;
+pub mod report;
+pub mod rollout;
+pub mod runs;
+pub mod schema;
+pub mod secrets;
+pub mod selector;
+pub mod snapshot;
+pub mod state;
+pub mod terraform;
+pub mod timings;
+pub mod transport;
+pub mod units;
+pub mod warnings;
+pub mod window;
 pub mod cli;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
-    pub ssh_opts: Option<String>,
+    /// Replaces `sshOpts`, one argument per element
+    pub ssh_opts: Option<Vec<String>>,
     pub fast_connection: Option<bool>,
+    pub check_sigs: Option<bool>,
+    pub serve_store: Option<bool>,
+    pub remote_build: Option<bool>,
+    pub build_cores: Option<u32>,
+    pub build_max_jobs: Option<u32>,
+    pub build_nice: Option<u8>,
     pub auto_rollback: Option<bool>,
+    pub skip_active: Option<bool>,
     pub hostname: Option<String>,
     pub magic_rollback: Option<bool>,
     pub temp_path: Option<String>,
     pub confirm_timeout: Option<u16>,
+    pub activation_timeout: Option<u16>,
+    pub ssh_connect_timeout: Option<u16>,
+    pub pin_host_key: Option<bool>,
     pub sudo: Option<String>,
     pub dry_activate: bool,
+    pub agent_cert: Option<String>,
+    pub agent_key: Option<String>,
+    pub agent_ca: Option<String>,
+    /// Directory for SSH control sockets, set to share one authenticated connection per node
+    pub ssh_control_dir: Option<String>,
+    /// Program asked for sudo passwords, like `SSH_ASKPASS`
+    pub askpass: Option<String>,
+    /// Leave `NIX_SSHOPTS` from the environment out of copies
+    pub ignore_nix_sshopts: bool,
+    /// Keep Nix off the network, see `offline_args`
+    pub offline: bool,
+    /// The flake output the deployment is evaluated from, instead of looking for one
+    pub deploy_attr: Option<String>,
+    /// Attached to the profile generations made, so they can be told apart by more than their number
+    pub label: Option<String>,
+    /// The run being deployed or rolled back, which the snapshots of `snapshots` are named after
+    pub deployment_id: Option<String>,
+    /// SSH private key signing the receipts left on nodes, see `crate::bundle::sign_receipt`
+    pub receipt_key: Option<String>,
+    /// Who signs the receipts, the user running deploy if not given
+    pub receipt_signer: Option<String>,
+}
+
+/// Arguments for Nix evaluations and builds with `--offline`: no substituters and nothing downloaded before is
+/// fetched again. Flake inputs missing from the lock file fail the evaluation, rather than being looked up
+pub fn offline_args(supports_flakes: bool) -> Vec<String> {
+    let args: &[&str] = match supports_flakes {
+        true => &["--offline", "--no-update-lock-file"],
+        false => &[
+            "--option",
+            "substitute",
+            "false",
+            "--option",
+            "tarball-ttl",
+            "4294967295",
+        ],
+    };
+
+    args.iter().map(|x| x.to_string()).collect()
+}
+
+#[test]
+fn test_offline_args() {
+    assert_eq!(
+        offline_args(true),
+        vec!["--offline", "--no-update-lock-file"]
+    );
+    assert!(offline_args(false)
+        .windows(3)
+        .any(|x| x == ["--option", "substitute", "false"]));
 }
 
 #[derive(PartialEq, Debug)]
@@ -244,6 +632,131 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
     })
 }
 
+#[test]
+fn test_feed_sudo_password() {
+    let mut deploy_defs = DeployDefs {
+        ssh_user: "deploy".to_string(),
+        profile_user: "root".to_string(),
+        profile_path: "/nix/var/nix/profiles/system".to_string(),
+        sudo: Some("sudo -u root".to_string()),
+        sudo_password: None,
+        temp_path: "/tmp".to_string(),
+    };
+
+    assert!(deploy_defs.feed_sudo_password("hunter2".to_string()));
+    assert_eq!(deploy_defs.sudo.as_deref(), Some("sudo -S -p '' -u root"));
+    assert_eq!(deploy_defs.sudo_password.as_deref(), Some("hunter2"));
+
+    let mut deploy_defs = DeployDefs {
+        sudo: Some("doas -u root".to_string()),
+        sudo_password: None,
+        ..deploy_defs
+    };
+
+    assert!(!deploy_defs.feed_sudo_password("hunter2".to_string()));
+    assert_eq!(deploy_defs.sudo_password, None);
+}
+
+#[test]
+fn test_remote_profile_dir() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "nas",
+        "sshUser": "deploy",
+        "remoteProfileDir": "/volume1/@nix/profiles/",
+        "profiles": {
+            "app": { "path": "/nix/store/blah-app" },
+            "pinned": { "path": "/nix/store/blah-pinned", "profilePath": "/srv/pinned" }
+        }
+    }))
+    .unwrap();
+    let cmd_overrides = CmdOverrides::default();
+
+    let profile_path = |profile_name: &str| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "nas",
+            &node.node_settings.profiles[profile_name],
+            profile_name,
+            &cmd_overrides,
+            false,
+            None,
+        )
+        .defs()
+        .unwrap()
+        .profile_path
+    };
+
+    assert_eq!(profile_path("app"), "/volume1/@nix/profiles/app");
+    assert_eq!(profile_path("pinned"), "/srv/pinned");
+}
+
+#[test]
+fn test_timeout_overrides() {
+    let top_settings =
+        serde_json::from_str(r#"{ "confirmTimeout": 30, "sshConnectTimeout": 10 }"#).unwrap();
+    let node: data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "db-1",
+        "sshOpts": ["-p", "2222"],
+        "activationTimeout": 600,
+        "keepAliveInterval": 30,
+        "profiles": {
+            "system": { "path": "/nix/store/blah-system", "activationTimeout": 3600 }
+        }
+    }))
+    .unwrap();
+
+    let settings = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &top_settings,
+            &node,
+            "db-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        )
+        .merged_settings
+    };
+
+    let merged = settings(&CmdOverrides::default());
+    assert_eq!(merged.confirm_timeout, Some(30));
+    assert_eq!(merged.activation_timeout, Some(3600));
+    assert_eq!(
+        merged.ssh_opts,
+        vec![
+            "-p",
+            "2222",
+            "-o",
+            "ConnectTimeout=10",
+            "-o",
+            "ServerAliveInterval=30"
+        ]
+    );
+
+    let merged = settings(&CmdOverrides {
+        confirm_timeout: Some(120),
+        activation_timeout: Some(7200),
+        ssh_connect_timeout: Some(5),
+        ..CmdOverrides::default()
+    });
+    assert_eq!(merged.confirm_timeout, Some(120));
+    assert_eq!(merged.activation_timeout, Some(7200));
+    assert_eq!(
+        merged.ssh_opts,
+        vec![
+            "-p",
+            "2222",
+            "-o",
+            "ConnectTimeout=5",
+            "-o",
+            "ServerAliveInterval=30"
+        ]
+    );
+}
+
 #[test]
 fn test_parse_flake() {
     assert_eq!(
@@ -323,6 +836,14 @@ pub struct DeployData<'a> {
 
     pub debug_logs: bool,
     pub log_dir: Option<&'a str>,
+
+    pub transport: &'a dyn transport::Transport,
+
+    /// What's known about the node's system, from an earlier run or gathered before deploying
+    pub facts: Option<facts::NodeFacts>,
+
+    /// Where to record how long each step takes, with `--timings`
+    pub timings: Option<&'a timings::Timings>,
 }
 
 #[derive(Debug)]
@@ -331,19 +852,69 @@ pub struct DeployDefs {
     pub profile_user: String,
     pub profile_path: String,
     pub sudo: Option<String>,
+    /// What sudo reads from standard input when it asks for a password, see `feed_sudo_password`
+    pub sudo_password: Option<String>,
+    /// Where activation keeps its temporary files on the node, `tempPath` or a directory of the profile's user
+    pub temp_path: String,
+}
+
+impl DeployDefs {
+    /// Makes sudo read its password from standard input instead of the terminal, the transport writes it there.
+    /// Only `sudo` itself can do this, for other commands nothing is changed and `false` is returned
+    pub fn feed_sudo_password(&mut self, password: String) -> bool {
+        match self.sudo {
+            Some(ref sudo) if sudo.starts_with("sudo ") => {
+                self.sudo = Some(format!("sudo -S -p ''{}", &sudo["sudo".len()..]));
+                self.sudo_password = Some(password);
+
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What `deploy` exits with, these are kept stable so wrapper scripts can tell failures apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCode {
+    /// Anything without a more specific code, e.g. invalid arguments or settings
+    Failure = 1,
+    /// Evaluating the flake or its checks failed
+    Evaluation = 2,
+    /// Building or signing a profile failed
+    Build = 3,
+    /// Copying a profile to a node or cache failed
+    Copy = 4,
+    /// Activation failed and nothing was rolled back
+    Activation = 5,
+    /// Activation failed and the node, or the profiles activated before it, were rolled back
+    RolledBack = 6,
+    /// Some nodes or profiles failed, while others succeeded and were left as they are
+    PartialFailure = 7,
+    /// Rolling back after a failure failed as well, so nodes may be in an inconsistent state
+    RollbackFailed = 8,
+    /// The deployment was stopped by `deploy pause`, and `deploy resume` continues it
+    Paused = 9,
 }
 
 #[derive(Error, Debug)]
 pub enum DeployDataDefsError {
     #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
     NoProfileUser(String, String),
+    #[error("Node {0} is managed by an agent, but --agent-cert, --agent-key and --agent-ca were not all given")]
+    NoAgentIdentity(String),
 }
 
 impl<'a> DeployData<'a> {
-    pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
-        let ssh_user = match self.merged_settings.ssh_user {
-            Some(ref u) => u.clone(),
-            None => whoami::username(),
+    pub fn defs(&self) -> Result<DeployDefs, DeployDataDefsError> {
+        // Agents run commands as root, so that's who we're "logged in" as when deciding about sudo
+        let ssh_user = match (
+            self.merged_settings.agent_port,
+            &self.merged_settings.ssh_user,
+        ) {
+            (Some(_), _) => "root".to_string(),
+            (None, Some(u)) => u.clone(),
+            (None, None) => whoami::username(),
         };
 
         let profile_user = self.get_profile_user()?;
@@ -355,30 +926,100 @@ impl<'a> DeployData<'a> {
             _ => None,
         };
 
+        // Users sharing a node don't get in each other's way with their own directory
+        let temp_path = match self.merged_settings.temp_path {
+            Some(ref x) => x.clone(),
+            None if profile_user == "root" => "/tmp".to_string(),
+            None => format!("/tmp/deploy-rs-{}", profile_user),
+        };
+
         Ok(DeployDefs {
             ssh_user,
             profile_user,
             profile_path,
             sudo,
+            sudo_password: None,
+            temp_path,
         })
     }
 
-    fn get_profile_path(&'a self) -> Result<String, DeployDataDefsError> {
+    /// The connection to the node's `deploy-rs-agent`, if the node is managed by one
+    pub fn agent_connection(
+        &'a self,
+    ) -> Result<Option<agent::AgentConnection<'a>>, DeployDataDefsError> {
+        let port = match self.merged_settings.agent_port {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let (cert, key, ca) = match (
+            &self.cmd_overrides.agent_cert,
+            &self.cmd_overrides.agent_key,
+            &self.cmd_overrides.agent_ca,
+        ) {
+            (Some(cert), Some(key), Some(ca)) => (cert, key, ca),
+            _ => {
+                return Err(DeployDataDefsError::NoAgentIdentity(
+                    self.node_name.to_owned(),
+                ))
+            }
+        };
+
+        let hostname = match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        };
+
+        Ok(Some(agent::AgentConnection {
+            hostname,
+            port,
+            cert,
+            key,
+            ca,
+            keep_alive_interval: self.merged_settings.keep_alive_interval,
+        }))
+    }
+
+    /// Where commands for this node are run, its agent if it has one and SSH otherwise
+    pub fn node_target<'b>(
+        &'b self,
+        deploy_defs: &'b DeployDefs,
+    ) -> Result<transport::NodeTarget<'b>, DeployDataDefsError> {
+        if let Some(agent_connection) = self.agent_connection()? {
+            return Ok(transport::NodeTarget::Agent(agent_connection));
+        }
+
+        let hostname = match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        };
+
+        Ok(transport::NodeTarget::Ssh {
+            address: format!("{}@{}", deploy_defs.ssh_user, hostname),
+            opts: &self.merged_settings.ssh_opts,
+            sudo_password: deploy_defs.sudo_password.as_deref(),
+        })
+    }
+
+    fn get_profile_path(&self) -> Result<String, DeployDataDefsError> {
         let profile_user = self.get_profile_user()?;
         let profile_path = match self.profile.profile_settings.profile_path {
-            None => match &profile_user[..] {
-                "root" => format!("/nix/var/nix/profiles/{}", self.profile_name),
-                _ => format!(
+            Some(ref x) => x.clone(),
+            None => match (&self.merged_settings.remote_profile_dir, &profile_user[..]) {
+                (Some(ref dir), _) => {
+                    format!("{}/{}", dir.trim_end_matches('/'), self.profile_name)
+                }
+                (None, "root") => format!("/nix/var/nix/profiles/{}", self.profile_name),
+                (None, _) => format!(
                     "/nix/var/nix/profiles/per-user/{}/{}",
                     profile_user, self.profile_name
                 ),
             },
-            Some(ref x) => x.clone(),
         };
         Ok(profile_path)
     }
 
-    fn get_profile_user(&'a self) -> Result<String, DeployDataDefsError> {
+    fn get_profile_user(&self) -> Result<String, DeployDataDefsError> {
         let profile_user = match self.merged_settings.user {
             Some(ref x) => x.clone(),
             None => match self.merged_settings.ssh_user {
@@ -394,28 +1035,57 @@ impl<'a> DeployData<'a> {
         Ok(profile_user)
     }
 
-    fn get_sudo(&'a self) -> String {
-        return match self.merged_settings.sudo {
-           Some(ref x) => x.clone(),
-           None => "sudo -u".to_string()
-        };
+    fn get_sudo(&self) -> String {
+        escalation_command(&self.merged_settings, self.facts.as_ref())
+    }
+
+    /// The sudo command needed to run commands as `user` on the node, if any
+    pub fn sudo_for(&self, deploy_defs: &DeployDefs, user: &str) -> Option<String> {
+        match user == deploy_defs.ssh_user {
+            true => None,
+            false => Some(format!("{} {}", self.get_sudo(), user)),
+        }
     }
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
-    node: &'a data::Node,
-    node_name: &'a str,
-    profile: &'a data::Profile,
-    profile_name: &'a str,
-    cmd_overrides: &'a CmdOverrides,
-    debug_logs: bool,
-    log_dir: Option<&'a str>,
-) -> DeployData<'a> {
-    let mut merged_settings = profile.generic_settings.clone();
-    merged_settings.merge(node.generic_settings.clone());
-    merged_settings.merge(top_settings.clone());
+/// The command running commands as another user on a node, who is given after it: the `sudo` setting if there is one,
+/// otherwise whichever of sudo and doas the node's facts found
+fn escalation_command(
+    merged_settings: &data::GenericSettings,
+    facts: Option<&facts::NodeFacts>,
+) -> String {
+    match merged_settings.sudo {
+        Some(ref x) => x.command().to_string(),
+        // Nodes without sudo, like many Alpine or BSD ones, often have doas, which is given the user the same way
+        None => match facts {
+            Some(facts) if facts.escalation.as_deref() == Some("doas") => "doas -u".to_string(),
+            _ => "sudo -u".to_string(),
+        },
+    }
+}
+
+#[test]
+fn test_escalation_command() {
+    let doas = facts::NodeFacts {
+        nix_version: None,
+        os: Some("alpine 3.19".to_string()),
+        init_system: facts::InitSystem::Other,
+        escalation: Some("doas".to_string()),
+        store_free_kib: None,
+        trusted: None,
+        gathered_at: 1_614_556_800,
+    };
+
+    let settings: data::GenericSettings = serde_json::from_str("{}").unwrap();
+    assert_eq!(escalation_command(&settings, None), "sudo -u");
+    assert_eq!(escalation_command(&settings, Some(&doas)), "doas -u");
+
+    let settings: data::GenericSettings =
+        serde_json::from_str(r#"{ "sudo": "run0 --user" }"#).unwrap();
+    assert_eq!(escalation_command(&settings, Some(&doas)), "run0 --user");
+}
 
+fn apply_cmd_overrides(merged_settings: &mut data::GenericSettings, cmd_overrides: &CmdOverrides) {
     if cmd_overrides.ssh_user.is_some() {
         merged_settings.ssh_user = cmd_overrides.ssh_user.clone();
     }
@@ -423,17 +1093,212 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings.user = cmd_overrides.profile_user.clone();
     }
     if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
-        merged_settings.ssh_opts = ssh_opts.split(' ').map(|x| x.to_owned()).collect();
+        merged_settings.ssh_opts = ssh_opts.clone();
+    }
+    if let Some(ref sudo) = cmd_overrides.sudo {
+        merged_settings.sudo = Some(data::SudoCommand::new(sudo.clone()));
+    }
+    if let Some(ref control_dir) = cmd_overrides.ssh_control_dir {
+        merged_settings
+            .ssh_opts
+            .extend(transport::control_master_opts(control_dir));
+    }
+    if cmd_overrides.temp_path.is_some() {
+        merged_settings.temp_path = cmd_overrides.temp_path.clone();
     }
     if let Some(fast_connection) = cmd_overrides.fast_connection {
         merged_settings.fast_connection = Some(fast_connection);
     }
+    if let Some(check_sigs) = cmd_overrides.check_sigs {
+        merged_settings.check_sigs = Some(check_sigs);
+    }
+    if let Some(serve_store) = cmd_overrides.serve_store {
+        merged_settings.serve_store = Some(serve_store);
+    }
+    if let Some(remote_build) = cmd_overrides.remote_build {
+        merged_settings.remote_build = Some(remote_build);
+    }
+    if let Some(build_cores) = cmd_overrides.build_cores {
+        merged_settings.build_cores = Some(build_cores);
+    }
+    if let Some(build_max_jobs) = cmd_overrides.build_max_jobs {
+        merged_settings.build_max_jobs = Some(build_max_jobs);
+    }
+    if let Some(build_nice) = cmd_overrides.build_nice {
+        merged_settings.build_nice = Some(build_nice);
+    }
     if let Some(auto_rollback) = cmd_overrides.auto_rollback {
         merged_settings.auto_rollback = Some(auto_rollback);
     }
     if let Some(magic_rollback) = cmd_overrides.magic_rollback {
         merged_settings.magic_rollback = Some(magic_rollback);
     }
+    if let Some(skip_active) = cmd_overrides.skip_active {
+        merged_settings.skip_active = Some(skip_active);
+    }
+    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
+        merged_settings.confirm_timeout = Some(confirm_timeout);
+    }
+    if let Some(activation_timeout) = cmd_overrides.activation_timeout {
+        merged_settings.activation_timeout = Some(activation_timeout);
+    }
+    if let Some(ssh_connect_timeout) = cmd_overrides.ssh_connect_timeout {
+        merged_settings.ssh_connect_timeout = Some(ssh_connect_timeout);
+    }
+    if let Some(pin_host_key) = cmd_overrides.pin_host_key {
+        merged_settings.pin_host_key = Some(pin_host_key);
+    }
+
+    // An explicit `ConnectTimeout` in `sshOpts` wins, as ssh takes the first value it's given
+    if let Some(ssh_connect_timeout) = merged_settings.ssh_connect_timeout {
+        merged_settings.ssh_opts.extend(vec![
+            "-o".to_string(),
+            format!("ConnectTimeout={}", ssh_connect_timeout),
+        ]);
+    }
+
+    // Traffic on the otherwise idle connection while activation runs, for NATs and firewalls dropping idle ones
+    if let Some(keep_alive_interval) = merged_settings.keep_alive_interval {
+        merged_settings.ssh_opts.extend(vec![
+            "-o".to_string(),
+            format!("ServerAliveInterval={}", keep_alive_interval),
+        ]);
+    }
+}
+
+/// Makes SSH check the host key of `node_name` against the one pinned in deploy's state with `pinHostKey`, see
+/// `hostkey::pinning_opts`. Without a state directory nothing is pinned, which pinning the keys before deploying
+/// fails on
+///
+/// SSH goes by the first value it's given for an option, so these come before `sshOpts`, where e.g. a
+/// `StrictHostKeyChecking=no` would otherwise turn pinning off without a word.
+fn apply_host_key_pinning(merged_settings: &mut data::GenericSettings, node_name: &str) {
+    if merged_settings.pin_host_key != Some(true) {
+        return;
+    }
+
+    if let Ok(dir) = state::state_dir() {
+        let mut ssh_opts = hostkey::pinning_opts(&hostkey::known_hosts_path(&dir, node_name));
+        ssh_opts.append(&mut merged_settings.ssh_opts);
+        merged_settings.ssh_opts = ssh_opts;
+    }
+}
+
+#[test]
+fn test_apply_host_key_pinning() {
+    let mut settings: data::GenericSettings = serde_json::from_str(
+        r#"{ "pinHostKey": true, "sshOpts": [ "-o", "StrictHostKeyChecking=no", "-p", "2222" ] }"#,
+    )
+    .unwrap();
+    apply_host_key_pinning(&mut settings, "web-1");
+
+    if state::state_dir().is_ok() {
+        assert_eq!(
+            settings.ssh_opts[..2],
+            ["-o", "StrictHostKeyChecking=accept-new"]
+        );
+        assert!(settings.ssh_opts[3].starts_with("UserKnownHostsFile="));
+        assert_eq!(
+            settings.ssh_opts[4..],
+            ["-o", "StrictHostKeyChecking=no", "-p", "2222"]
+        );
+    }
+}
+
+/// A node with its merged settings, for operations which aren't tied to a single profile
+#[derive(Debug, Clone)]
+pub struct NodeData<'a> {
+    pub node_name: &'a str,
+    pub node: &'a data::Node,
+
+    pub cmd_overrides: &'a CmdOverrides,
+
+    pub merged_settings: data::GenericSettings,
+
+    /// What's known about the node's system from an earlier run
+    pub facts: Option<facts::NodeFacts>,
+}
+
+impl<'a> NodeData<'a> {
+    pub fn ssh_user(&self) -> String {
+        match self.merged_settings.ssh_user {
+            Some(ref u) => u.clone(),
+            None => whoami::username(),
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        match self.cmd_overrides.hostname {
+            Some(ref x) => x,
+            None => &self.node.node_settings.hostname,
+        }
+    }
+
+    pub fn ssh_addr(&self) -> String {
+        format!("{}@{}", self.ssh_user(), self.hostname())
+    }
+
+    /// The sudo command needed to run commands as `user` on the node, if any
+    pub fn sudo_for(&self, user: &str) -> Option<String> {
+        if user == self.ssh_user() {
+            return None;
+        }
+
+        Some(format!(
+            "{} {}",
+            escalation_command(&self.merged_settings, self.facts.as_ref()),
+            user
+        ))
+    }
+}
+
+pub fn make_node_data<'a>(
+    top_settings: &data::GenericSettings,
+    node: &'a data::Node,
+    node_name: &'a str,
+    cmd_overrides: &'a CmdOverrides,
+) -> NodeData<'a> {
+    let mut merged_settings = node.generic_settings.clone();
+    merged_settings.merge(top_settings.clone());
+
+    apply_cmd_overrides(&mut merged_settings, cmd_overrides);
+    apply_host_key_pinning(&mut merged_settings, node_name);
+
+    // Nix uploaded by deploy isn't where SSH sessions look for it
+    if merged_settings.bootstrap_nix.is_some() && merged_settings.remote_nix_path.is_none() {
+        merged_settings.remote_nix_path = Some(bootstrap::NIX_DIR.to_string());
+    }
+
+    NodeData {
+        node_name,
+        node,
+        cmd_overrides,
+        merged_settings,
+        facts: None,
+    }
+}
+
+pub fn make_deploy_data<'a, 's>(
+    top_settings: &'s data::GenericSettings,
+    node: &'a data::Node,
+    node_name: &'a str,
+    profile: &'a data::Profile,
+    profile_name: &'a str,
+    cmd_overrides: &'a CmdOverrides,
+    debug_logs: bool,
+    log_dir: Option<&'a str>,
+) -> DeployData<'a> {
+    let mut merged_settings = profile.generic_settings.clone();
+    merged_settings.merge(node.generic_settings.clone());
+    merged_settings.merge(top_settings.clone());
+
+    apply_cmd_overrides(&mut merged_settings, cmd_overrides);
+    apply_host_key_pinning(&mut merged_settings, node_name);
+
+    // Nix uploaded by deploy isn't where SSH sessions look for it
+    if merged_settings.bootstrap_nix.is_some() && merged_settings.remote_nix_path.is_none() {
+        merged_settings.remote_nix_path = Some(bootstrap::NIX_DIR.to_string());
+    }
 
     DeployData {
         node_name,
@@ -444,5 +1309,8 @@ pub fn make_deploy_data<'a, 's>(
         merged_settings,
         debug_logs,
         log_dir,
+        transport: &transport::SystemTransport,
+        facts: None,
+        timings: None,
     }
 }
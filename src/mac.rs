@@ -0,0 +1,156 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use thiserror::Error;
+use tokio::process::Command;
+
+/// A mandatory access control system enforced on a node, which can deny activation scripts what they do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mac {
+    SELinux,
+    AppArmor,
+}
+
+impl std::fmt::Display for Mac {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Mac::SELinux => "SELinux",
+            Mac::AppArmor => "AppArmor",
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MacError {
+    #[error("Failed to query the closure to relabel: {0}")]
+    Query(std::io::Error),
+    #[error("Querying the closure to relabel resulted in a bad exit code: {0:?}")]
+    QueryExit(Option<i32>),
+    #[error("Failed to run restorecon: {0}")]
+    Restorecon(std::io::Error),
+    #[error("restorecon resulted in a bad exit code: {0:?}")]
+    RestoreconExit(Option<i32>),
+}
+
+/// The access control system this machine enforces, if any. SELinux in permissive mode only logs, so it isn't
+pub fn enforced() -> Option<Mac> {
+    let read = |path: &str| std::fs::read_to_string(path).map(|x| x.trim().to_string());
+
+    if read("/sys/fs/selinux/enforce").ok().as_deref() == Some("1") {
+        return Some(Mac::SELinux);
+    }
+
+    if read("/sys/module/apparmor/parameters/enabled")
+        .ok()
+        .as_deref()
+        == Some("Y")
+    {
+        return Some(Mac::AppArmor);
+    }
+
+    None
+}
+
+/// The lines of kernel and audit logs which are denials by `mac`
+pub fn denials(log: &str, mac: Mac) -> Vec<String> {
+    log.lines()
+        .filter(|line| match mac {
+            Mac::SELinux => line.contains("avc:") && line.contains("denied"),
+            Mac::AppArmor => line.contains("apparmor=\"DENIED\""),
+        })
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// The denials by `mac` logged since `since`, in seconds since the epoch, as the journal has them. Nothing if they
+/// can't be read, as they're only looked for to explain a failure
+pub async fn recent_denials(mac: Mac, since: u64) -> Vec<String> {
+    let output = Command::new("journalctl")
+        .arg("--no-pager")
+        .arg("--output=cat")
+        .arg(format!("--since=@{}", since))
+        .arg("_TRANSPORT=audit")
+        .arg("+")
+        .arg("_TRANSPORT=kernel")
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            denials(&String::from_utf8_lossy(&output.stdout), mac)
+        }
+        Ok(output) => {
+            debug!(
+                "Could not read the journal for {} denials: {:?}",
+                mac,
+                output.status.code()
+            );
+            Vec::new()
+        }
+        Err(e) => {
+            debug!("Could not read the journal for {} denials: {}", mac, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Gives the store paths of `closure` the labels the SELinux policy has for them, which paths copied in from
+/// elsewhere don't always have. AppArmor goes by paths rather than labels, so there's nothing to do for it
+pub async fn relabel(mac: Mac, closure: &str) -> Result<(), MacError> {
+    if mac != Mac::SELinux {
+        debug!("{} doesn't label files, nothing to relabel", mac);
+        return Ok(());
+    }
+
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .arg(closure)
+        .output()
+        .await
+        .map_err(MacError::Query)?;
+
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(MacError::QueryExit(a)),
+    };
+
+    let paths: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|x| x.to_string())
+        .collect();
+
+    let status = Command::new("restorecon")
+        .arg("-R")
+        .arg("-F")
+        .args(&paths)
+        .status()
+        .await
+        .map_err(MacError::Restorecon)?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(MacError::RestoreconExit(a)),
+    }
+}
+
+#[test]
+fn test_denials() {
+    let log = r#"audit: type=1400 audit(1700000000.123:456): avc:  denied  { execute } for  pid=1234 comm="deploy-rs-activ" name="activate" dev="dm-0" ino=789 scontext=system_u:system_r:unconfined_service_t:s0 tcontext=system_u:object_r:default_t:s0 tclass=file permissive=0
+audit: type=1400 audit(1700000000.124:457): avc:  granted  { setenforce } for  pid=1
+audit: type=1400 audit(1700000001.000:458): apparmor="DENIED" operation="open" profile="/usr/sbin/nginx" name="/nix/store/blah-nginx.conf" pid=99
+systemd[1]: Started nginx.service."#;
+
+    let selinux = denials(log, Mac::SELinux);
+    assert_eq!(selinux.len(), 1);
+    assert!(selinux[0].contains("tcontext=system_u:object_r:default_t:s0"));
+
+    assert_eq!(
+        denials(log, Mac::AppArmor),
+        vec![
+            r#"audit: type=1400 audit(1700000001.000:458): apparmor="DENIED" operation="open" profile="/usr/sbin/nginx" name="/nix/store/blah-nginx.conf" pid=99"#
+        ]
+    );
+}
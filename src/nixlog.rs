@@ -0,0 +1,294 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::Deserialize;
+
+/// Makes Nix write its log as `@nix {...}` lines of JSON, which `render` turns back into what it would have printed
+pub const LOG_FORMAT_ARGS: [&str; 2] = ["--log-format", "internal-json"];
+
+/// Where the lines of `internal-json` logs start
+const PREFIX: &str = "@nix ";
+
+/// The activity result type of a line a builder printed
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+
+/// One line of an `internal-json` log. Activities starting and stopping are left out of what's shown, like Nix does
+/// without a progress bar
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Message {
+    Msg {
+        msg: String,
+    },
+    Result {
+        #[serde(rename = "type")]
+        result_type: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// What a line Nix wrote to standard error would have said without `internal-json`, if anything. Lines that aren't
+/// JSON log lines are kept as they are
+pub fn render(line: &str) -> Option<String> {
+    let json = match line.strip_prefix(PREFIX) {
+        Some(x) => x,
+        None => return Some(line.to_string()),
+    };
+
+    match serde_json::from_str(json) {
+        Ok(Message::Msg { msg }) => Some(msg),
+        Ok(Message::Result {
+            result_type: RESULT_BUILD_LOG_LINE,
+            fields,
+        }) => fields
+            .first()
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string()),
+        Ok(_) => None,
+        Err(_) => Some(line.to_string()),
+    }
+}
+
+/// Why a Nix command failed, going by the errors it logged
+#[derive(Debug, Clone, PartialEq)]
+pub enum NixFailure {
+    /// Nix code couldn't be evaluated
+    Eval,
+    /// A derivation, the store path of which is given, failed to build
+    Build(String),
+    /// Something couldn't be fetched from a substituter or another host
+    Network,
+    /// The connection to the local Nix daemon was lost or couldn't be made, e.g. while the daemon was restarted
+    Daemon,
+}
+
+/// What Nix mentions when it's the local daemon's socket it couldn't talk over. A bare `unexpected end-of-file` or
+/// `Connection reset by peer` could as well be from a fetcher or an SSH store, and isn't enough to go by
+const DAEMON_ERRORS: [&str; 1] = ["daemon-socket/socket"];
+
+/// What Nix says when it couldn't reach something
+const NETWORK_ERRORS: [&str; 7] = [
+    "unable to download",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "Timeout was reached",
+    "Connection timed out",
+    "Failed to connect",
+    "Connection refused",
+];
+
+/// What Nix prints along with evaluation errors
+const EVAL_ERRORS: [&str; 6] = [
+    "while evaluating",
+    "undefined variable",
+    "infinite recursion",
+    "called without required argument",
+    "evaluation aborted",
+    "does not provide attribute",
+];
+
+/// The derivation a build error is about, the first `.drv` store path in quotes. Newer Nix puts the outputs it's
+/// about after it, as in `'/nix/store/...drv^out'`
+fn failed_derivation(line: &str) -> Option<String> {
+    let start = line.find("'/nix/store/")? + 1;
+    let end = start + line[start..].find(".drv")? + ".drv".len();
+
+    match line[end..].starts_with('\'') || line[end..].starts_with('^') {
+        true => Some(line[start..end].to_string()),
+        false => None,
+    }
+}
+
+/// Classifies a failure by the last lines a Nix command wrote to standard error, as `render` gave them. A failed
+/// build wins over a lost daemon and over a network error it was caused by, and all of them over evaluation errors
+pub fn classify(stderr_tail: &[String]) -> Option<NixFailure> {
+    let build = stderr_tail
+        .iter()
+        .filter(|line| {
+            line.contains("builder for '")
+                || line.contains("Cannot build '")
+                || line.contains("build of '")
+        })
+        .find_map(|line| failed_derivation(line));
+
+    if let Some(derivation) = build {
+        return Some(NixFailure::Build(derivation));
+    }
+
+    if stderr_tail
+        .iter()
+        .any(|line| DAEMON_ERRORS.iter().any(|x| line.contains(x)))
+    {
+        return Some(NixFailure::Daemon);
+    }
+
+    if stderr_tail
+        .iter()
+        .any(|line| NETWORK_ERRORS.iter().any(|x| line.contains(x)))
+    {
+        return Some(NixFailure::Network);
+    }
+
+    if stderr_tail
+        .iter()
+        .any(|line| EVAL_ERRORS.iter().any(|x| line.contains(x)))
+    {
+        return Some(NixFailure::Eval);
+    }
+
+    None
+}
+
+/// Makes Nix warn about every derivation it builds to import from while evaluating, which it takes to be an unknown
+/// setting before 2.16
+pub const TRACE_IFD_ARGS: [&str; 3] = ["--option", "trace-import-from-derivation", "true"];
+
+/// Makes Nix fail evaluations which would build something to import from, for `--forbid-ifd`
+pub const FORBID_IFD_ARGS: [&str; 3] = ["--option", "allow-import-from-derivation", "false"];
+
+/// The derivations built to import from, going by the warnings `TRACE_IFD_ARGS` makes Nix log
+pub fn imports_from_derivation(stderr_tail: &[String]) -> Vec<String> {
+    let mut derivations: Vec<String> = stderr_tail
+        .iter()
+        .filter(|line| line.contains("during evaluation due to an import from derivation"))
+        .filter_map(|line| failed_derivation(line))
+        .collect();
+
+    derivations.dedup();
+    derivations
+}
+
+/// The derivation an evaluation failed to import from because `FORBID_IFD_ARGS` was given
+pub fn forbidden_import(stderr_tail: &[String]) -> Option<String> {
+    stderr_tail
+        .iter()
+        .filter(|line| line.contains("'allow-import-from-derivation' is disabled"))
+        .find_map(|line| failed_derivation(line))
+}
+
+/// The environment variables making Nix write statistics about an evaluation to a file as JSON when it exits
+pub fn stats_env(path: &str) -> [(&'static str, String); 2] {
+    [
+        ("NIX_SHOW_STATS", "1".to_string()),
+        ("NIX_SHOW_STATS_PATH", path.to_string()),
+    ]
+}
+
+/// How big the heap of an evaluation got, in bytes, from the statistics `stats_env` makes Nix write
+pub fn heap_size(stats: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(stats)
+        .ok()?
+        .get("gc")?
+        .get("heapSize")?
+        .as_u64()
+}
+
+#[test]
+fn test_render() {
+    assert_eq!(
+        render("warning: Git tree is dirty").as_deref(),
+        Some("warning: Git tree is dirty")
+    );
+    assert_eq!(
+        render(r#"@nix {"action":"msg","level":0,"msg":"error: undefined variable 'pkgs'"}"#)
+            .as_deref(),
+        Some("error: undefined variable 'pkgs'")
+    );
+    assert_eq!(
+        render(
+            r#"@nix {"action":"result","id":12,"type":101,"fields":["checking for gcc... yes"]}"#
+        )
+        .as_deref(),
+        Some("checking for gcc... yes")
+    );
+    assert_eq!(
+        render(
+            r#"@nix {"action":"start","id":12,"level":3,"type":105,"text":"building '/nix/store/abc-hello.drv'","fields":["/nix/store/abc-hello.drv","",1,1],"parent":0}"#
+        ),
+        None
+    );
+    assert_eq!(render(r#"@nix {"action":"stop","id":12}"#), None);
+}
+
+#[test]
+fn test_classify() {
+    let lines = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+
+    assert_eq!(
+        classify(&lines(&[
+            "checking for gcc... no",
+            "error: builder for '/nix/store/abc-hello-2.12.drv' failed with exit code 1;",
+            "error: 1 dependencies of derivation '/nix/store/def-system.drv' failed to build",
+        ])),
+        Some(NixFailure::Build(
+            "/nix/store/abc-hello-2.12.drv".to_string()
+        ))
+    );
+    assert_eq!(
+        classify(&lines(&[
+            "error: unable to download 'https://cache.nixos.org/abc.narinfo': Couldn't resolve host name (6)"
+        ])),
+        Some(NixFailure::Network)
+    );
+    assert_eq!(
+        classify(&lines(&[
+            "error:",
+            "       … while evaluating the attribute 'deploy'",
+            "       error: undefined variable 'pkgs'",
+        ])),
+        Some(NixFailure::Eval)
+    );
+    assert_eq!(
+        classify(&lines(&[
+            "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused"
+        ])),
+        Some(NixFailure::Daemon)
+    );
+    assert_eq!(
+        classify(&lines(&[
+            "error: builder for '/nix/store/abc-src.drv' failed with exit code 1;",
+            "       > curl: (56) Recv failure: Connection reset by peer",
+        ])),
+        Some(NixFailure::Build("/nix/store/abc-src.drv".to_string()))
+    );
+    assert_eq!(classify(&lines(&["error: out of memory"])), None);
+}
+
+#[test]
+fn test_import_from_derivation() {
+    let lines = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+
+    assert_eq!(
+        imports_from_derivation(&lines(&[
+            "warning: built '/nix/store/abc-cargo-deps.drv' during evaluation due to an import from derivation",
+            "warning: Git tree is dirty",
+            "warning: built '/nix/store/abc-cargo-deps.drv' during evaluation due to an import from derivation",
+        ])),
+        vec!["/nix/store/abc-cargo-deps.drv"]
+    );
+    assert_eq!(
+        forbidden_import(&lines(&[
+            "error:",
+            "       … while evaluating the attribute 'deploy'",
+            "       error: cannot build '/nix/store/abc-cargo-deps.drv^out' during evaluation because the option 'allow-import-from-derivation' is disabled",
+        ]))
+        .as_deref(),
+        Some("/nix/store/abc-cargo-deps.drv")
+    );
+    assert_eq!(
+        forbidden_import(&lines(&["error: undefined variable 'pkgs'"])),
+        None
+    );
+
+    assert_eq!(
+        heap_size(
+            r#"{"cpuTime":12.5,"gc":{"heapSize":5368709120,"totalBytes":9000000000},"nrThunks":1}"#
+        ),
+        Some(5368709120)
+    );
+    assert_eq!(heap_size(r#"{"cpuTime":12.5}"#), None);
+}
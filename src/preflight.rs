@@ -0,0 +1,344 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::report::{CommandFailure, Diagnostic};
+use crate::transport::{Invocation, NodeTarget, OutputMode, RunOnNodeError};
+use crate::warnings::{raise, Warning, DEFAULT_MAX_CLOCK_SKEW};
+
+/// How long connecting to a node's SSH port or agent may take before it counts as unreachable
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum PreflightError {
+    #[error("{0}")]
+    DeployDataDefs(#[from] crate::DeployDataDefsError),
+    #[error("Failed to run ssh -G to look up where the node is: {0}")]
+    SshConfig(std::io::Error),
+    #[error("Looking up where the node is with ssh -G failed with {0}")]
+    SshConfigExit(CommandFailure),
+    #[error("Could not resolve `{0}`: {1}")]
+    Resolve(String, std::io::Error),
+    #[error("Could not connect to {0}: {1}")]
+    Connect(String, std::io::Error),
+    #[error("Checking the connection stopped unexpectedly: {0}")]
+    ConnectTask(tokio::task::JoinError),
+    #[error("Failed to log in to the node: {0}")]
+    Login(RunOnNodeError),
+    #[error("Logging in to the node failed with {0}")]
+    LoginExit(CommandFailure),
+    #[error("Failed to run sudo on the node: {0}")]
+    Sudo(RunOnNodeError),
+    #[error("Running `{0}` on the node failed with {1}")]
+    SudoExit(String, CommandFailure),
+    #[error("The node's clock is {0} seconds off from this machine's, more than `maxClockSkew` ({1}) allows")]
+    ClockSkew(u64, u64),
+}
+
+impl Diagnostic for PreflightError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        match self {
+            PreflightError::SshConfigExit(f)
+            | PreflightError::LoginExit(f)
+            | PreflightError::SudoExit(_, f) => Some(f),
+            _ => None,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            PreflightError::Resolve(_, _) => {
+                Some("Check the node's `hostname`, or give the address to use with `--hostname`")
+            }
+            PreflightError::Connect(_, _) => Some(
+                "The node may be down or behind a firewall, `sshOpts` or `agentPort` set the port connected to",
+            ),
+            PreflightError::LoginExit(_) => Some(
+                "Check `sshUser`, and that the node accepts one of the deploying machine's SSH keys",
+            ),
+            PreflightError::SudoExit(_, _) => Some(
+                "The SSH user needs to be able to run commands as the profile's `user` without a password, or pass `--askpass`",
+            ),
+            PreflightError::ClockSkew(_, _) => Some(
+                "Check that NTP (e.g. `services.timesyncd`) runs on the node and on this machine",
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Where ssh connects to according to the `ssh -G` output given, `None` when that's through a proxy
+pub fn ssh_destination(config: &str) -> Option<(String, u16)> {
+    let mut hostname = None;
+    let mut port = 22;
+
+    for line in config.lines() {
+        let mut words = line.splitn(2, ' ');
+
+        match (words.next(), words.next()) {
+            (Some("hostname"), Some(x)) => hostname = Some(x.to_string()),
+            (Some("port"), Some(x)) => port = x.parse().ok()?,
+            (Some("proxycommand"), Some(x)) | (Some("proxyjump"), Some(x)) if x != "none" => {
+                return None
+            }
+            _ => (),
+        }
+    }
+
+    hostname.map(|x| (x, port))
+}
+
+#[test]
+fn test_ssh_destination() {
+    assert_eq!(
+        ssh_destination("user deploy\nhostname 10.0.0.2\nport 2222\nproxycommand none\n"),
+        Some(("10.0.0.2".to_string(), 2222))
+    );
+    assert_eq!(
+        ssh_destination("hostname web-1.example.com\nproxyjump bastion.example.com\n"),
+        None
+    );
+    assert_eq!(
+        ssh_destination("hostname web-1.example.com\n"),
+        Some(("web-1.example.com".to_string(), 22))
+    );
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// How many seconds the node's clock, as `date +%s` printed it between `before` and `after` on this machine's clock,
+/// is off by at least. The time the command took can't tell either way, so it's taken off
+pub fn clock_skew(before: u64, node_time: &str, after: u64) -> Option<u64> {
+    let node_time: u64 = node_time.trim().parse().ok()?;
+
+    Some(match node_time {
+        x if x < before => before - x,
+        x if x > after => x - after,
+        _ => 0,
+    })
+}
+
+#[test]
+fn test_clock_skew() {
+    assert_eq!(clock_skew(1000, "1001\n", 1002), Some(0));
+    assert_eq!(clock_skew(1000, "880", 1002), Some(120));
+    assert_eq!(clock_skew(1000, "1032", 1002), Some(30));
+    assert_eq!(clock_skew(1000, "", 1002), None);
+}
+
+/// Resolves `host` and connects to `port` on one of its addresses
+async fn connect(host: String, port: u16) -> Result<(), PreflightError> {
+    tokio::task::spawn_blocking(move || {
+        let addrs: Vec<SocketAddr> = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|e| PreflightError::Resolve(host.clone(), e))?
+            .collect();
+
+        let mut error = std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses");
+
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(_) => return Ok(()),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(PreflightError::Connect(format!("{}:{}", host, port), error))
+    })
+    .await
+    .map_err(PreflightError::ConnectTask)?
+}
+
+/// Checks that a node can be deployed to: that its address resolves, its SSH port or agent accepts connections, it
+/// can be logged in to, its clock isn't off by more than `maxClockSkew`, and each of `sudo_commands` works without
+/// asking for anything
+pub async fn preflight_node(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+    sudo_commands: &[String],
+) -> Result<(), PreflightError> {
+    let target = deploy_data.node_target(deploy_defs)?;
+
+    let destination = match target {
+        NodeTarget::Ssh {
+            ref address, opts, ..
+        } => {
+            let mut config = Invocation::new("ssh");
+            config
+                .arg("-G")
+                .args(opts)
+                .arg(address)
+                .stdout(OutputMode::Capture);
+
+            let output = deploy_data
+                .transport
+                .run(&config)
+                .await
+                .map_err(PreflightError::SshConfig)?;
+
+            match output.code {
+                Some(0) => (),
+                _ => {
+                    return Err(PreflightError::SshConfigExit(CommandFailure::new(
+                        &config, &output,
+                    )))
+                }
+            };
+
+            ssh_destination(&String::from_utf8_lossy(&output.stdout))
+        }
+        NodeTarget::Agent(ref connection) => {
+            Some((connection.hostname.to_string(), connection.port))
+        }
+    };
+
+    // Nodes behind a proxy can only be reached through it, so they're only checked by logging in
+    match destination {
+        Some((host, port)) => connect(host, port).await?,
+        None => debug!(
+            "Node `{}` is reached through a proxy, not connecting to it directly",
+            deploy_data.node_name
+        ),
+    }
+
+    // Logging in also reads the node's clock
+    let before = now();
+    let login = deploy_data
+        .transport
+        .run_on_node(&target, "date +%s", true)
+        .await
+        .map_err(PreflightError::Login)?;
+    let after = now();
+
+    match login.code {
+        Some(0) => (),
+        _ => {
+            return Err(PreflightError::LoginExit(CommandFailure::new(
+                "date +%s", &login,
+            )))
+        }
+    };
+
+    let max_clock_skew = deploy_data.merged_settings.max_clock_skew;
+
+    match clock_skew(before, &String::from_utf8_lossy(&login.stdout), after) {
+        None => debug!(
+            "Could not read the clock of node `{}`",
+            deploy_data.node_name
+        ),
+        Some(skew) => match max_clock_skew {
+            Some(max) if skew > max => return Err(PreflightError::ClockSkew(skew, max)),
+            None if skew > DEFAULT_MAX_CLOCK_SKEW => raise(
+                Warning::ClockSkew,
+                format_args!(
+                    "The clock of node `{}` is {} seconds off from this machine's, which throws off magic rollback and TLS certificate checks",
+                    deploy_data.node_name, skew
+                ),
+            ),
+            _ => debug!(
+                "The clock of node `{}` is {} seconds off",
+                deploy_data.node_name, skew
+            ),
+        },
+    }
+
+    // Agents run everything as root
+    if let NodeTarget::Agent(_) = target {
+        return Ok(());
+    }
+
+    for sudo in sudo_commands {
+        let command = format!("{} true", sudo);
+
+        let output = deploy_data
+            .transport
+            .run_on_node(&target, &command, true)
+            .await
+            .map_err(PreflightError::Sudo)?;
+
+        match output.code {
+            Some(0) => (),
+            _ => {
+                return Err(PreflightError::SudoExit(
+                    sudo.clone(),
+                    CommandFailure::new(&command, &output),
+                ))
+            }
+        };
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_preflight_node() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_str(
+        r#"{
+            "hostname": "db-1",
+            "sshUser": "deploy",
+            "sshOpts": ["-p", "2222"],
+            "profiles": {
+                "system": { "path": "/nix/store/00000000000000000000000000000000-system" }
+            }
+        }"#,
+    )
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond(
+        "ssh -G",
+        Some(0),
+        &format!("user deploy\nhostname 127.0.0.1\nport {}\n", port),
+    );
+    transport.respond("sudo -u postgres true", Some(1), "");
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "db-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    let result = runtime.block_on(preflight_node(
+        &deploy_data,
+        &deploy_defs,
+        &["sudo -u root".to_string(), "sudo -u postgres".to_string()],
+    ));
+
+    match result {
+        Err(PreflightError::SudoExit(sudo, _)) => assert_eq!(sudo, "sudo -u postgres"),
+        x => panic!("expected sudo to fail, got {:?}", x),
+    }
+
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "ssh -G -p 2222 deploy@db-1",
+            "[ssh://deploy@db-1] date +%s",
+            "[ssh://deploy@db-1] sudo -u root true",
+            "[ssh://deploy@db-1] sudo -u postgres true",
+        ]
+    );
+}
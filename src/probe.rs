@@ -0,0 +1,408 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::report::CommandFailure;
+use crate::transport::{InputMode, Invocation, OutputMode, Transport};
+
+/// How much is sent to the node to measure throughput
+const PROBE_SIZE: usize = 4 * 1024 * 1024;
+
+/// How well a node can be reached from the deploying machine
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkMeasurement {
+    /// How long connecting over SSH and running a command takes, a few round trips
+    pub connect_ms: u64,
+    pub throughput_kib_s: u64,
+    /// Seconds since the epoch
+    pub measured_at: u64,
+}
+
+impl LinkMeasurement {
+    /// On links this fast sending the whole closure beats having the node substitute it
+    pub fn is_fast(&self) -> bool {
+        self.throughput_kib_s >= 10 * 1024
+    }
+
+    /// On links this slow compressing the closure is quicker than sending it as is
+    pub fn wants_compression(&self) -> bool {
+        self.throughput_kib_s < 2 * 1024
+    }
+}
+
+#[test]
+fn test_link_classification() {
+    let link = |throughput_kib_s| LinkMeasurement {
+        connect_ms: 100,
+        throughput_kib_s,
+        measured_at: 0,
+    };
+
+    assert!(link(100 * 1024).is_fast());
+    assert!(!link(100 * 1024).wants_compression());
+    assert!(!link(5 * 1024).is_fast());
+    assert!(!link(5 * 1024).wants_compression());
+    assert!(link(512).wants_compression());
+}
+
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    #[error("Failed to write the probe payload: {0}")]
+    Payload(std::io::Error),
+    #[error("Failed to run SSH to probe the connection: {0}")]
+    SSH(std::io::Error),
+    #[error("Probing the connection over SSH failed with {0}")]
+    SSHExit(CommandFailure),
+    #[error("Failed to add the sample closure to the Nix store: {0}")]
+    AddSample(std::io::Error),
+    #[error("Adding the sample closure to the Nix store failed with {0}")]
+    AddSampleExit(CommandFailure),
+    #[error("Failed to run Nix copy command: {0}")]
+    Copy(std::io::Error),
+    #[error("Nix copy command failed with {0}")]
+    CopyExit(CommandFailure),
+}
+
+/// Bytes that don't compress, so SSH compression can't make the link look faster than it is
+fn probe_payload(size: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+    (0..size)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+async fn timed_ssh(
+    transport: &dyn Transport,
+    ssh_addr: &str,
+    ssh_opts: &[String],
+    command: &str,
+    stdin: Option<&Path>,
+) -> Result<Duration, ProbeError> {
+    let mut ssh_command = Invocation::new("ssh");
+    ssh_command
+        .arg(ssh_addr)
+        .args(ssh_opts)
+        .arg(command)
+        .stdout(OutputMode::Null);
+
+    if let Some(stdin) = stdin {
+        ssh_command.stdin(InputMode::File(stdin.to_path_buf()));
+    }
+
+    let start = Instant::now();
+
+    let ssh_output = transport.run(&ssh_command).await.map_err(ProbeError::SSH)?;
+
+    match ssh_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(ProbeError::SSHExit(CommandFailure::new(
+                &ssh_command,
+                &ssh_output,
+            )))
+        }
+    };
+
+    Ok(start.elapsed())
+}
+
+/// Measures the connection to a node by connecting once without sending anything and once sending `PROBE_SIZE` bytes
+pub async fn measure_link(
+    transport: &dyn Transport,
+    ssh_addr: &str,
+    ssh_opts: &[String],
+) -> Result<LinkMeasurement, ProbeError> {
+    let payload_path = std::env::temp_dir().join(format!(
+        "deploy-rs-probe-{}-{}",
+        std::process::id(),
+        ssh_addr
+    ));
+
+    std::fs::write(&payload_path, probe_payload(PROBE_SIZE)).map_err(ProbeError::Payload)?;
+
+    let connect = timed_ssh(transport, ssh_addr, ssh_opts, "true", None).await;
+    let transfer = timed_ssh(
+        transport,
+        ssh_addr,
+        ssh_opts,
+        "cat > /dev/null",
+        Some(&payload_path),
+    )
+    .await;
+
+    let _ = std::fs::remove_file(&payload_path);
+
+    let (connect, transfer) = (connect?, transfer?);
+
+    // Connecting is part of both, what's left of the second one is sending the payload
+    let sending = transfer
+        .checked_sub(connect)
+        .unwrap_or_default()
+        .max(Duration::from_millis(1));
+
+    let measurement = LinkMeasurement {
+        connect_ms: connect.as_millis() as u64,
+        throughput_kib_s: ((PROBE_SIZE / 1024) as f64 / sending.as_secs_f64()) as u64,
+        measured_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0),
+    };
+
+    debug!("Measured the connection to {}: {:?}", ssh_addr, measurement);
+
+    Ok(measurement)
+}
+
+/// How much the closures `deploy bench-copy` sends to the node add up to
+const SAMPLE_SIZE: usize = 8 * 1024 * 1024;
+
+/// A way of copying closures to a node, of which `deploy bench-copy` records the quickest as the node's default
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyStrategy {
+    /// `nix copy` sending every path as it is
+    Plain,
+    /// `nix copy --substitute-on-destination`, the node fetching what it can from its own substituters first
+    SubstituteOnDestination,
+    /// `nix copy` sending every path with SSH compressing it
+    Compressed,
+}
+
+pub const COPY_STRATEGIES: [CopyStrategy; 3] = [
+    CopyStrategy::Plain,
+    CopyStrategy::SubstituteOnDestination,
+    CopyStrategy::Compressed,
+];
+
+impl CopyStrategy {
+    pub fn name(self) -> &'static str {
+        match self {
+            CopyStrategy::Plain => "plain",
+            CopyStrategy::SubstituteOnDestination => "substitute-on-destination",
+            CopyStrategy::Compressed => "compressed",
+        }
+    }
+}
+
+/// The quickest of the measured strategies, the first of them on a tie
+pub fn fastest(measured: &[(CopyStrategy, Duration)]) -> Option<CopyStrategy> {
+    measured
+        .iter()
+        .fold(
+            None,
+            |fastest: Option<&(CopyStrategy, Duration)>, x| match fastest {
+                Some(y) if y.1 <= x.1 => Some(y),
+                _ => Some(x),
+            },
+        )
+        .map(|(strategy, _)| *strategy)
+}
+
+#[test]
+fn test_fastest() {
+    assert_eq!(fastest(&[]), None);
+    assert_eq!(
+        fastest(&[
+            (CopyStrategy::Plain, Duration::from_millis(900)),
+            (
+                CopyStrategy::SubstituteOnDestination,
+                Duration::from_millis(1200)
+            ),
+            (CopyStrategy::Compressed, Duration::from_millis(400)),
+        ]),
+        Some(CopyStrategy::Compressed)
+    );
+    assert_eq!(
+        fastest(&[
+            (CopyStrategy::Plain, Duration::from_millis(400)),
+            (CopyStrategy::Compressed, Duration::from_millis(400)),
+        ]),
+        Some(CopyStrategy::Plain)
+    );
+}
+
+/// Like a closure of a profile half of it compresses and half of it doesn't, and `nonce` makes it new to the node
+fn sample_payload(nonce: &str) -> Vec<u8> {
+    let mut payload = format!("{}\n", nonce).into_bytes();
+    payload.extend(probe_payload(SAMPLE_SIZE / 2));
+
+    let text = b"#!/nix/store/00000000000000000000000000000000-bash/bin/bash\nexec \"$@\"\n";
+    while payload.len() < SAMPLE_SIZE {
+        payload.extend_from_slice(text);
+    }
+    payload.truncate(SAMPLE_SIZE);
+
+    payload
+}
+
+/// Adds a sample to the local Nix store, which no substituter has, and returns its store path
+async fn add_sample(transport: &dyn Transport, nonce: &str) -> Result<String, ProbeError> {
+    let sample_path = std::env::temp_dir().join(format!("deploy-rs-bench-{}", nonce));
+
+    std::fs::write(&sample_path, sample_payload(nonce)).map_err(ProbeError::AddSample)?;
+
+    let mut add_command = Invocation::new("nix-store");
+    add_command
+        .arg("--add")
+        .arg(sample_path.to_string_lossy())
+        .stdout(OutputMode::Capture);
+
+    let add_output = transport.run(&add_command).await;
+
+    let _ = std::fs::remove_file(&sample_path);
+
+    let add_output = add_output.map_err(ProbeError::AddSample)?;
+
+    match add_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(ProbeError::AddSampleExit(CommandFailure::new(
+                &add_command,
+                &add_output,
+            )))
+        }
+    };
+
+    Ok(String::from_utf8_lossy(&add_output.stdout)
+        .trim()
+        .to_string())
+}
+
+/// Copies a sample closure to `node_store` with each of `COPY_STRATEGIES`, and tells how long each of them took
+///
+/// Each strategy copies a sample of its own, so none of them finds the node already having it. No substituter has
+/// them either, so substituting on the node is measured by what asking its substituters first costs.
+pub async fn bench_copy(
+    transport: &dyn Transport,
+    node_store: &str,
+    nix_sshopts: &str,
+    check_sigs: bool,
+) -> Result<Vec<(CopyStrategy, Duration)>, ProbeError> {
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or(0);
+
+    let mut measured = Vec::new();
+
+    for strategy in COPY_STRATEGIES.iter() {
+        let nonce = format!("{}-{}-{}", std::process::id(), started_at, strategy.name());
+        let sample = add_sample(transport, &nonce).await?;
+
+        let mut copy_command = Invocation::new("nix");
+        copy_command.arg("copy");
+
+        if *strategy == CopyStrategy::SubstituteOnDestination {
+            copy_command.arg("--substitute-on-destination");
+        }
+
+        if !check_sigs {
+            copy_command.arg("--no-check-sigs");
+        }
+
+        let sshopts = match strategy {
+            CopyStrategy::Compressed => format!("{} -C", nix_sshopts).trim().to_string(),
+            _ => nix_sshopts.to_string(),
+        };
+
+        copy_command
+            .arg("--to")
+            .arg(node_store)
+            .arg(&sample)
+            .env("NIX_SSHOPTS", &sshopts);
+
+        debug!(target: "nix_copy", "Running copy command: {}", copy_command);
+
+        let start = Instant::now();
+
+        let copy_output = transport
+            .run(&copy_command)
+            .await
+            .map_err(ProbeError::Copy)?;
+
+        match copy_output.code {
+            Some(0) => (),
+            _ => {
+                return Err(ProbeError::CopyExit(CommandFailure::new(
+                    &copy_command,
+                    &copy_output,
+                )))
+            }
+        };
+
+        measured.push((*strategy, start.elapsed()));
+    }
+
+    Ok(measured)
+}
+
+#[test]
+fn test_bench_copy() {
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("nix-store --add", Some(0), "/nix/store/aaa-sample\n");
+    transport.respond("nix-store --add", Some(0), "/nix/store/bbb-sample\n");
+    transport.respond("nix-store --add", Some(0), "/nix/store/ccc-sample\n");
+
+    let measured = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(bench_copy(
+            &transport,
+            "ssh://deploy@web-1",
+            "-p 2222",
+            false,
+        ))
+        .unwrap();
+
+    assert_eq!(
+        measured.iter().map(|(x, _)| *x).collect::<Vec<_>>(),
+        COPY_STRATEGIES.to_vec()
+    );
+
+    let copies: Vec<String> = transport
+        .commands()
+        .into_iter()
+        .filter(|x| x.contains("nix copy"))
+        .collect();
+    assert_eq!(
+        copies,
+        vec![
+            "NIX_SSHOPTS=-p 2222 nix copy --no-check-sigs --to ssh://deploy@web-1 /nix/store/aaa-sample",
+            "NIX_SSHOPTS=-p 2222 nix copy --substitute-on-destination --no-check-sigs --to ssh://deploy@web-1 /nix/store/bbb-sample",
+            "NIX_SSHOPTS=-p 2222 -C nix copy --no-check-sigs --to ssh://deploy@web-1 /nix/store/ccc-sample",
+        ]
+    );
+}
+
+#[test]
+fn test_measure_link() {
+    let transport = crate::transport::RecordingTransport::default();
+
+    let measurement = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(measure_link(
+            &transport,
+            "deploy@web-1",
+            &["-p".to_string(), "2222".to_string()],
+        ))
+        .unwrap();
+
+    let commands = transport.commands();
+    assert_eq!(commands[0], "ssh deploy@web-1 -p 2222 true");
+    assert!(commands[1].starts_with("ssh deploy@web-1 -p 2222 cat > /dev/null < "));
+    assert!(measurement.throughput_kib_s > 0);
+}
@@ -0,0 +1,339 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::report::CommandFailure;
+use crate::schema::Versioned;
+use crate::transport::{InputMode, Invocation, OutputMode, Transport};
+
+/// Namespace of descriptor signatures, so a signature made for anything else doesn't pass for one on a descriptor
+pub const DESCRIPTOR_NAMESPACE: &str = "deploy-rs-pull-descriptor";
+
+/// Everything a node needs to fetch and activate a profile by itself
+///
+/// Nodes only act on descriptors signed by a deployer they allow, as the descriptor decides which of the closures
+/// signed by a trusted key is activated, and into which profile, which could otherwise be an older one or the wrong one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PullDescriptor {
+    pub node: String,
+    pub profile: String,
+    pub closure: String,
+    pub profile_path: String,
+    #[serde(default)]
+    pub substituters: Vec<String>,
+    /// Who signed the descriptor, as named in the allowed signers file nodes check it against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+    /// SSH signature of the descriptor without this field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+pub fn descriptor_path(dir: &str, node_name: &str, profile_name: &str) -> String {
+    format!("{}/{}/{}.json", dir, node_name, profile_name)
+}
+
+#[test]
+fn test_descriptor_path() {
+    assert_eq!(
+        descriptor_path("./descriptors", "web-1", "system"),
+        "./descriptors/web-1/system.json"
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum WriteDescriptorError {
+    #[error("Failed to create the descriptor directory: {0}")]
+    CreateDir(std::io::Error),
+    #[error("Failed to serialize the descriptor: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to write the descriptor: {0}")]
+    Write(std::io::Error),
+}
+
+/// Writes the descriptor for a profile into `dir`, returning the path it was written to
+pub async fn write_descriptor(
+    dir: &str,
+    descriptor: &PullDescriptor,
+) -> Result<String, WriteDescriptorError> {
+    let path = descriptor_path(dir, &descriptor.node, &descriptor.profile);
+
+    tokio::fs::create_dir_all(format!("{}/{}", dir, descriptor.node))
+        .await
+        .map_err(WriteDescriptorError::CreateDir)?;
+
+    let contents = serde_json::to_string_pretty(&crate::schema::Versioned::new(descriptor))?;
+
+    // Write next to the final location and rename, so nodes polling the directory never see a partial file
+    let tmp_path = format!("{}.tmp", path);
+
+    tokio::fs::write(&tmp_path, contents)
+        .await
+        .map_err(WriteDescriptorError::Write)?;
+    tokio::fs::rename(&tmp_path, &path)
+        .await
+        .map_err(WriteDescriptorError::Write)?;
+
+    Ok(path)
+}
+
+#[derive(Error, Debug)]
+pub enum DescriptorSignatureError {
+    #[error("The descriptor isn't signed")]
+    Unsigned,
+    #[error("Failed to parse the descriptor: {0}")]
+    Parse(serde_json::Error),
+    #[error("Failed to write the descriptor to sign or verify it: {0}")]
+    Write(std::io::Error),
+    #[error("Failed to run ssh-keygen to sign the descriptor: {0}")]
+    Sign(std::io::Error),
+    #[error("Signing the descriptor failed with {0}")]
+    SignExit(CommandFailure),
+    #[error("Failed to read the signature of the descriptor: {0}")]
+    ReadSignature(std::io::Error),
+    #[error("Failed to run ssh-keygen to verify the descriptor: {0}")]
+    Verify(std::io::Error),
+    #[error("The descriptor's signature isn't valid, or not by an allowed signer: {0}")]
+    VerifyExit(CommandFailure),
+}
+
+/// What a descriptor's signature is made over: the descriptor as written, without the signature
+fn signed_payload(
+    descriptor: &Versioned<PullDescriptor>,
+) -> Result<Vec<u8>, DescriptorSignatureError> {
+    let mut unsigned = descriptor.clone();
+    unsigned.inner.signature = None;
+
+    serde_json::to_vec(&unsigned).map_err(DescriptorSignatureError::Parse)
+}
+
+/// A new file only this user can read, to hand ssh-keygen what it signs or verifies. Nodes verify as root, so a file
+/// someone else put there first, or a link, is never written to
+fn payload_file(payload: &[u8]) -> Result<PathBuf, DescriptorSignatureError> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+
+    let path = std::env::temp_dir().join(format!(
+        "deploy-rs-descriptor-{}-{}",
+        std::process::id(),
+        nanos
+    ));
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut x| x.write_all(payload))
+        .map_err(DescriptorSignatureError::Write)?;
+
+    Ok(path)
+}
+
+/// Signs a descriptor with an SSH private key as `signer`, which `activate-rs pull --allowed-signers` checks
+pub async fn sign_descriptor(
+    transport: &dyn Transport,
+    descriptor: &PullDescriptor,
+    key: &str,
+    signer: &str,
+) -> Result<PullDescriptor, DescriptorSignatureError> {
+    let mut signed = PullDescriptor {
+        signer: Some(signer.to_string()),
+        signature: None,
+        ..descriptor.clone()
+    };
+
+    let path = payload_file(&signed_payload(&Versioned::new(signed.clone()))?)?;
+    let signature_path = format!("{}.sig", path.to_string_lossy());
+
+    let mut sign_command = Invocation::new("ssh-keygen");
+    sign_command
+        .arg("-Y")
+        .arg("sign")
+        .arg("-f")
+        .arg(key)
+        .arg("-n")
+        .arg(DESCRIPTOR_NAMESPACE)
+        .arg(path.to_string_lossy())
+        .stdout(OutputMode::Null);
+
+    let result = transport.run(&sign_command).await;
+    let signature = std::fs::read_to_string(&signature_path);
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&signature_path);
+
+    let sign_output = result.map_err(DescriptorSignatureError::Sign)?;
+
+    match sign_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(DescriptorSignatureError::SignExit(CommandFailure::new(
+                &sign_command,
+                &sign_output,
+            )))
+        }
+    };
+
+    signed.signature = Some(signature.map_err(DescriptorSignatureError::ReadSignature)?);
+
+    Ok(signed)
+}
+
+/// Checks the signature of a descriptor against an allowed signers file (see `ssh-keygen(1)`), returning the
+/// descriptor
+pub async fn verify_descriptor(
+    transport: &dyn Transport,
+    descriptor_json: &str,
+    allowed_signers: &str,
+) -> Result<Versioned<PullDescriptor>, DescriptorSignatureError> {
+    let descriptor: Versioned<PullDescriptor> =
+        serde_json::from_str(descriptor_json).map_err(DescriptorSignatureError::Parse)?;
+
+    let (signer, signature) = match (&descriptor.inner.signer, &descriptor.inner.signature) {
+        (Some(signer), Some(signature)) => (signer, signature),
+        _ => return Err(DescriptorSignatureError::Unsigned),
+    };
+
+    let path = payload_file(&signed_payload(&descriptor)?)?;
+    let signature_path = format!("{}.sig", path.to_string_lossy());
+
+    let mut verify_command = Invocation::new("ssh-keygen");
+    verify_command
+        .arg("-Y")
+        .arg("verify")
+        .arg("-f")
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(signer)
+        .arg("-n")
+        .arg(DESCRIPTOR_NAMESPACE)
+        .arg("-s")
+        .arg(&signature_path)
+        .stdin(InputMode::File(path.clone()))
+        .stdout(OutputMode::Null);
+
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&signature_path)
+        .and_then(|mut x| std::io::Write::write_all(&mut x, signature.as_bytes()));
+
+    // A signature file that was there already isn't ours to remove
+    let result = match written {
+        Ok(()) => {
+            let result = transport
+                .run(&verify_command)
+                .await
+                .map_err(DescriptorSignatureError::Verify);
+
+            let _ = std::fs::remove_file(&signature_path);
+
+            result
+        }
+        Err(e) => Err(DescriptorSignatureError::Write(e)),
+    };
+
+    let _ = std::fs::remove_file(&path);
+
+    let verify_output = result?;
+
+    match verify_output.code {
+        Some(0) => Ok(descriptor),
+        _ => Err(DescriptorSignatureError::VerifyExit(CommandFailure::new(
+            &verify_command,
+            &verify_output,
+        ))),
+    }
+}
+
+#[test]
+fn test_verify_descriptor() {
+    let transport = crate::transport::RecordingTransport::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let descriptor = PullDescriptor {
+        node: "web-1".to_string(),
+        profile: "system".to_string(),
+        closure: "/nix/store/blah-system".to_string(),
+        profile_path: "/nix/var/nix/profiles/system".to_string(),
+        substituters: Vec::new(),
+        signer: None,
+        signature: None,
+    };
+
+    let unsigned = serde_json::to_string(&Versioned::new(descriptor.clone())).unwrap();
+    assert!(matches!(
+        runtime.block_on(verify_descriptor(
+            &transport,
+            &unsigned,
+            "/etc/allowed_signers"
+        )),
+        Err(DescriptorSignatureError::Unsigned)
+    ));
+
+    let signed = serde_json::to_string(&Versioned::new(PullDescriptor {
+        signer: Some("alice".to_string()),
+        signature: Some("-----BEGIN SSH SIGNATURE-----".to_string()),
+        ..descriptor
+    }))
+    .unwrap();
+
+    transport.respond("ssh-keygen -Y verify", Some(255), "");
+    assert!(matches!(
+        runtime.block_on(verify_descriptor(
+            &transport,
+            &signed,
+            "/etc/allowed_signers"
+        )),
+        Err(DescriptorSignatureError::VerifyExit(_))
+    ));
+
+    let commands = transport.commands();
+    assert!(commands[0].starts_with(
+        "ssh-keygen -Y verify -f /etc/allowed_signers -I alice -n deploy-rs-pull-descriptor -s "
+    ));
+}
+
+#[derive(Error, Debug)]
+pub enum CopyToCacheError {
+    #[error("Failed to run Nix copy command: {0}")]
+    Copy(std::io::Error),
+    #[error("Nix copy command resulted in a bad exit code: {0:?}")]
+    CopyExit(Option<i32>),
+}
+
+/// Copies a closure to the binary cache the nodes are going to substitute from
+pub async fn copy_to_cache(closure: &str, store_uri: &str) -> Result<(), CopyToCacheError> {
+    info!("Copying `{}` to `{}`", closure, store_uri);
+
+    let mut copy_command = Command::new("nix");
+    copy_command
+        .arg("copy")
+        .arg("--to")
+        .arg(store_uri)
+        .arg(closure);
+
+    debug!("Running copy command: {:?}", copy_command);
+
+    let copy_exit_status = copy_command
+        .status()
+        .await
+        .map_err(CopyToCacheError::Copy)?;
+
+    match copy_exit_status.code() {
+        Some(0) => Ok(()),
+        a => Err(CopyToCacheError::CopyExit(a)),
+    }
+}
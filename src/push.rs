@@ -2,19 +2,32 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
+use log::{debug, info, log_enabled, warn};
 use std::collections::HashMap;
 use std::path::Path;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use thiserror::Error;
+
+use crate::agent::{self, AgentConnection, AgentRequest};
+use crate::data::{CacheType, CopyMode, GenericSettings, PushCache, SubstitutePolicy};
+use crate::diff::format_size;
+use crate::nixlog::NixFailure;
+use crate::probe::{self, CopyStrategy, LinkMeasurement};
+use crate::report::{CommandFailure, Diagnostic};
+use crate::state;
+use crate::timings::{self, Step};
+use crate::transport::{Invocation, Output, OutputMode, RunOnNodeError, Transport};
+use crate::warnings::{raise, Warning};
 use tokio::process::Command;
 
 #[derive(Error, Debug)]
 pub enum PushProfileError {
     #[error("Failed to run Nix show-derivation command: {0}")]
     ShowDerivation(std::io::Error),
-    #[error("Nix show-derivation command resulted in a bad exit code: {0:?}")]
-    ShowDerivationExit(Option<i32>),
+    #[error("Nix show-derivation command failed with {0}")]
+    ShowDerivationExit(CommandFailure),
     #[error("Nix show-derivation command output contained an invalid UTF-8 sequence: {0}")]
     ShowDerivationUtf8(std::str::Utf8Error),
     #[error("Failed to parse the output of nix show-derivation: {0}")]
@@ -23,8 +36,12 @@ pub enum PushProfileError {
     ShowDerivationEmpty,
     #[error("Failed to run Nix build command: {0}")]
     Build(std::io::Error),
-    #[error("Nix build command resulted in a bad exit code: {0:?}")]
-    BuildExit(Option<i32>),
+    #[error("Nix build command failed with {0}")]
+    BuildExit(CommandFailure),
+    #[error("Building {0} failed with {1}")]
+    BuildDerivationExit(String, Box<CommandFailure>),
+    #[error("Building the profile failed to fetch something with {0}")]
+    BuildNetworkExit(CommandFailure),
     #[error(
         "Activation script deploy-rs-activate does not exist in profile.\n\
              Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?"
@@ -33,19 +50,195 @@ pub enum PushProfileError {
     #[error("Activation script activate-rs does not exist in profile.\n\
              Is there a mismatch in deploy-rs used in the flake you're deploying and deploy-rs command you're running?")]
     ActivateRsDoesntExist,
+    #[error("Netboot image does not contain `{0}`, it needs bzImage, initrd and netboot.ipxe at its top level")]
+    NetbootFileMissing(String),
+    #[error("The closure does not contain `{0}`, which `blueGreen.command` runs")]
+    BlueGreenCommandMissing(String),
     #[error("Failed to run Nix sign command: {0}")]
     Sign(std::io::Error),
-    #[error("Nix sign command resulted in a bad exit code: {0:?}")]
-    SignExit(Option<i32>),
+    #[error("Nix sign command failed with {0}")]
+    SignExit(CommandFailure),
     #[error("Failed to run Nix copy command: {0}")]
     Copy(std::io::Error),
-    #[error("Nix copy command resulted in a bad exit code: {0:?}")]
-    CopyExit(Option<i32>),
+    #[error("Nix copy command failed with {0}")]
+    CopyExit(CommandFailure),
+    #[error("Failed to serve the Nix store with nix-serve: {0}")]
+    ServeStore(std::io::Error),
+    #[error("nix-serve exited before accepting connections, with {0}")]
+    ServeStoreExit(CommandFailure),
+    #[error("nix-serve did not start accepting connections on port {0}")]
+    ServeStoreNotReady(u16),
+    #[error("Failed to run the substitution command over SSH: {0}")]
+    ServeStoreRealise(std::io::Error),
+    #[error("Substituting from the served Nix store failed with {0}")]
+    ServeStoreRealiseExit(CommandFailure),
+    #[error("{0}")]
+    DeployDataDefs(#[from] crate::DeployDataDefsError),
+    #[error("Failed to run Nix command to query the closure: {0}")]
+    QueryClosure(std::io::Error),
+    #[error("Nix command to query the closure failed with {0}")]
+    QueryClosureExit(CommandFailure),
+    #[error("Failed to parse the output of nix path-info: {0}")]
+    QueryClosureParse(serde_json::Error),
+    #[error("Failed to export the closure for the agent: {0}")]
+    Export(std::io::Error),
+    #[error("Exporting the closure for the agent failed with {0}")]
+    ExportExit(CommandFailure),
+    #[error("Failed to push the closure through the agent: {0}")]
+    Agent(#[from] crate::agent::AgentError),
+    #[error("Agent could not query the closure, exit code: {0:?}")]
+    AgentQueryExit(Option<i32>),
+    #[error("Agent could not import the closure, exit code: {0:?}")]
+    AgentImportExit(Option<i32>),
+    #[error("`remoteBuild` needs the node to be reached over SSH, not through its agent")]
+    RemoteBuildAgent,
+    #[error("Failed to build the profile on the node: {0}")]
+    RemoteBuild(RunOnNodeError),
+    #[error("Building the profile on the node failed with {0}")]
+    RemoteBuildExit(CommandFailure),
+    #[error("Failed to run the build on {0}: {1}")]
+    ServiceBuild(String, std::io::Error),
+    #[error("Building the profile on the build service failed with {0}")]
+    ServiceBuildExit(CommandFailure),
+    #[error("Failed to have the node fetch the profile from {0}: {1}")]
+    ServiceFetch(String, RunOnNodeError),
+    #[error("Fetching the profile from the build service on the node failed with {0}")]
+    ServiceFetchExit(CommandFailure),
+    #[error("Failed to push the profile to its cache: {0}")]
+    CachePush(std::io::Error),
+    #[error("Pushing the profile to its cache failed with {0}")]
+    CachePushExit(CommandFailure),
+}
+
+impl PushProfileError {
+    pub fn exit_code(&self) -> crate::ExitCode {
+        use PushProfileError::*;
+
+        match self {
+            ShowDerivation(_)
+            | ShowDerivationExit(_)
+            | ShowDerivationUtf8(_)
+            | ShowDerivationParse(_)
+            | ShowDerivationEmpty
+            | Build(_)
+            | BuildExit(_)
+            | BuildDerivationExit(_, _)
+            | BuildNetworkExit(_)
+            | DeployRsActivateDoesntExist
+            | ActivateRsDoesntExist
+            | NetbootFileMissing(_)
+            | BlueGreenCommandMissing(_)
+            | Sign(_)
+            | SignExit(_)
+            | RemoteBuildAgent
+            | RemoteBuild(_)
+            | RemoteBuildExit(_)
+            | ServiceBuild(_, _)
+            | ServiceBuildExit(_) => crate::ExitCode::Build,
+            Copy(_)
+            | CopyExit(_)
+            | ServeStore(_)
+            | ServeStoreExit(_)
+            | ServeStoreNotReady(_)
+            | ServeStoreRealise(_)
+            | ServeStoreRealiseExit(_)
+            | QueryClosure(_)
+            | QueryClosureExit(_)
+            | QueryClosureParse(_)
+            | Export(_)
+            | ExportExit(_)
+            | Agent(_)
+            | AgentQueryExit(_)
+            | AgentImportExit(_)
+            | ServiceFetch(_, _)
+            | ServiceFetchExit(_)
+            | CachePush(_)
+            | CachePushExit(_) => crate::ExitCode::Copy,
+            DeployDataDefs(_) => crate::ExitCode::Failure,
+        }
+    }
+}
+
+impl Diagnostic for PushProfileError {
+    fn failure(&self) -> Option<&CommandFailure> {
+        use PushProfileError::*;
+
+        match self {
+            ShowDerivationExit(f)
+            | BuildExit(f)
+            | BuildNetworkExit(f)
+            | SignExit(f)
+            | CopyExit(f)
+            | ServeStoreExit(f)
+            | ServeStoreRealiseExit(f)
+            | QueryClosureExit(f)
+            | ExportExit(f)
+            | RemoteBuildExit(f)
+            | ServiceBuildExit(f)
+            | ServiceFetchExit(f)
+            | CachePushExit(f) => Some(f),
+            BuildDerivationExit(_, f) => Some(f),
+            _ => None,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        use PushProfileError::*;
+
+        match self {
+            ShowDerivationExit(_) => Some(
+                "The profile's path has to be the output of a derivation which can be evaluated here, \
+                 check that `nix show-derivation` works on it",
+            ),
+            BuildDerivationExit(_, _) => Some(
+                "The build log is above; `nix log` on the derivation shows all of it",
+            ),
+            BuildNetworkExit(_) => Some(
+                "Check that the substituters and the hosts sources are fetched from can be reached, then try again",
+            ),
+            BuildExit(_) => Some(
+                "The build log is above; run the command yourself, or `nix log` on the failed derivation, to see it again",
+            ),
+            SignExit(_) => Some(
+                "Check that `signing.keyFile` (or `LOCAL_KEY`) points to a readable secret key made with \
+                 `nix-store --generate-binary-cache-key`",
+            ),
+            CopyExit(_) => Some(
+                "Check that `ssh` to the node works without a password prompt and that the node trusts the closure, \
+                 either by signing it with `signing.keyFile` or by making the SSH user a trusted user of its Nix daemon",
+            ),
+            ServeStore(_) | ServeStoreExit(_) | ServeStoreNotReady(_) => {
+                Some("`serveStore` needs `nix-serve` on the deploying machine")
+            }
+            ServeStoreRealiseExit(_) => Some(
+                "Check that the node's SSH server allows remote port forwarding and that the node trusts the closure's signature",
+            ),
+            AgentQueryExit(_) | AgentImportExit(_) => Some(
+                "The agent's log on the node (`journalctl -u deploy-rs-agent`) tells more; \
+                 closures without a signature by one of its `trustedKeys` are refused",
+            ),
+            ExportExit(_) => Some("Check that the temporary directory has room for the closure"),
+            RemoteBuildExit(_) => Some(
+                "The build log is above; the SSH user has to be allowed to build with the node's Nix daemon",
+            ),
+            ServiceBuildExit(_) => Some(
+                "The build log is above; check that `ssh` to the build service works with your key",
+            ),
+            CachePushExit(_) => Some(
+                "Check that the cache's CLI is logged in and allowed to push to it, e.g. with `cachix authtoken` or \
+                 `attic login`",
+            ),
+            ServiceFetchExit(_) => Some(
+                "The node's SSH user needs a key the build service accepts, and has to be trusted by the node's Nix \
+                 daemon unless the service signs the closure with a key the node trusts",
+            ),
+            _ => None,
+        }
+    }
 }
 
 pub struct PushProfileData<'a> {
     pub supports_flakes: bool,
-    pub check_sigs: bool,
     pub repo: &'a str,
     pub deploy_data: &'a super::DeployData<'a>,
     pub deploy_defs: &'a super::DeployDefs,
@@ -54,27 +247,81 @@ pub struct PushProfileData<'a> {
     pub extra_build_args: &'a [String],
 }
 
-pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+/// A local build command running `program`, at the niceness `buildNice` if that's set. Idle IO priority only exists on
+/// Linux, where it's asked for as well
+fn build_invocation(program: &str, settings: &GenericSettings) -> Invocation {
+    let niceness = match settings.build_nice {
+        Some(x) => x,
+        None => return Invocation::new(program),
+    };
+
+    let mut invocation = Invocation::new("nice");
+    invocation.arg("-n").arg(niceness.to_string());
+
+    if cfg!(target_os = "linux") {
+        invocation.arg("ionice").arg("-c").arg("3");
+    }
+
+    invocation.arg(program);
+    invocation
+}
+
+/// The error of a local build which failed, as specific as the errors Nix logged allow
+fn build_failure(build_command: &Invocation, build_output: &Output) -> PushProfileError {
+    let failure = CommandFailure::new(build_command, build_output);
+
+    match crate::nixlog::classify(&build_output.stderr_tail) {
+        Some(NixFailure::Build(derivation)) => {
+            PushProfileError::BuildDerivationExit(derivation, Box::new(failure))
+        }
+        Some(NixFailure::Network) => PushProfileError::BuildNetworkExit(failure),
+        Some(NixFailure::Eval) | Some(NixFailure::Daemon) | None => {
+            PushProfileError::BuildExit(failure)
+        }
+    }
+}
+
+/// Passes `buildCores` and `buildMaxJobs` on to a local build command
+fn limit_build(build_command: &mut Invocation, settings: &GenericSettings) {
+    if let Some(cores) = settings.build_cores {
+        build_command.arg("--cores").arg(cores.to_string());
+    }
+
+    if let Some(max_jobs) = settings.build_max_jobs {
+        build_command.arg("--max-jobs").arg(max_jobs.to_string());
+    }
+}
+
+/// The derivation a profile's path is the output of
+async fn find_deriver(data: &PushProfileData<'_>) -> Result<String, PushProfileError> {
     debug!(
         "Finding the deriver of store path for {}",
         &data.deploy_data.profile.profile_settings.path
     );
 
+    let transport = data.deploy_data.transport;
+
     // `nix-store --query --deriver` doesn't work on invalid paths, so we parse output of show-derivation :(
-    let mut show_derivation_command = Command::new("nix");
+    let mut show_derivation_command = Invocation::new("nix");
 
     show_derivation_command
         .arg("show-derivation")
-        .arg(&data.deploy_data.profile.profile_settings.path);
+        .arg(&data.deploy_data.profile.profile_settings.path)
+        .stdout(OutputMode::Capture);
 
-    let show_derivation_output = show_derivation_command
-        .output()
+    let show_derivation_output = transport
+        .run(&show_derivation_command)
         .await
         .map_err(PushProfileError::ShowDerivation)?;
 
-    match show_derivation_output.status.code() {
+    match show_derivation_output.code {
         Some(0) => (),
-        a => return Err(PushProfileError::ShowDerivationExit(a)),
+        _ => {
+            return Err(PushProfileError::ShowDerivationExit(CommandFailure::new(
+                &show_derivation_command,
+                &show_derivation_output,
+            )))
+        }
     };
 
     let derivation_info: HashMap<&str, serde_json::value::Value> = serde_json::from_str(
@@ -83,31 +330,52 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
     )
     .map_err(PushProfileError::ShowDerivationParse)?;
 
-    let derivation_name = derivation_info
+    derivation_info
         .keys()
         .next()
-        .ok_or(PushProfileError::ShowDerivationEmpty)?;
+        .map(|x| x.to_string())
+        .ok_or(PushProfileError::ShowDerivationEmpty)
+}
+
+/// Builds (and signs, if it has signing keys) a profile, without copying it anywhere
+pub async fn build_profile(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
+    let transport = data.deploy_data.transport;
+
+    let derivation_name = find_deriver(data).await?;
 
     info!(
+        target: "nix_build",
         "Building profile `{}` for node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
+    let settings = &data.deploy_data.merged_settings;
+
     let mut build_command = if data.supports_flakes {
-        Command::new("nix")
+        build_invocation("nix", settings)
     } else {
-        Command::new("nix-build")
+        build_invocation("nix-build", settings)
     };
 
     if data.supports_flakes {
-        build_command.arg("build").arg(derivation_name)
+        build_command.arg("build").arg(&derivation_name)
     } else {
-        build_command.arg(derivation_name)
+        build_command.arg(&derivation_name)
     };
 
     match (data.keep_result, data.supports_flakes) {
         (true, _) => {
-            let result_path = data.result_path.unwrap_or("./.deploy-gc");
+            // Kept with the rest of the state rather than in whichever directory deploy happens to run in
+            let result_path = match data.result_path {
+                Some(x) => x.to_string(),
+                None => match state::state_dir() {
+                    Ok(dir) => state::gc_roots_dir(&dir).to_string_lossy().to_string(),
+                    Err(e) => {
+                        warn!("Keeping build results in ./.deploy-gc instead: {}", e);
+                        "./.deploy-gc".to_string()
+                    }
+                },
+            };
 
             build_command.arg("--out-link").arg(format!(
                 "{}/{}/{}",
@@ -118,112 +386,1918 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
         (false, true) => build_command.arg("--no-link"),
     };
 
+    limit_build(&mut build_command, settings);
+
+    // Parsed back by the transport, which lets failures be told apart by what Nix logged. Nix without flakes may be
+    // too old to log like this
+    if data.supports_flakes {
+        build_command.args(&crate::nixlog::LOG_FORMAT_ARGS);
+    }
+
     for extra_arg in data.extra_build_args {
         build_command.arg(extra_arg);
     }
 
-    let build_exit_status = build_command
-        // Logging should be in stderr, this just stops the store path from printing for no reason
-        .stdout(Stdio::null())
-        .status()
+    set_nix_env(
+        &mut build_command,
+        &data.deploy_data.merged_settings.build_env,
+    );
+
+    // Logging should be in stderr, this just stops the store path from printing for no reason
+    build_command.stdout(OutputMode::Null);
+
+    debug!(target: "nix_build", "Running build command: {}", build_command);
+
+    let started = std::time::Instant::now();
+
+    let build_output = transport
+        .run(&build_command)
         .await
         .map_err(PushProfileError::Build)?;
 
-    match build_exit_status.code() {
+    timings::record(
+        data.deploy_data.timings,
+        data.deploy_data.node_name,
+        Step::Build,
+        started,
+    );
+
+    match build_output.code {
         Some(0) => (),
-        a => return Err(PushProfileError::BuildExit(a)),
+        _ => return Err(build_failure(&build_command, &build_output)),
     };
 
-    if !Path::new(
-        format!(
-            "{}/deploy-rs-activate",
-            data.deploy_data.profile.profile_settings.path
-        )
-        .as_str(),
-    )
-    .exists()
-    {
-        return Err(PushProfileError::DeployRsActivateDoesntExist);
+    // Portable service images are attached with portablectl, netboot images are published as files and blue-green
+    // services are run as user units, none carries deploy-rs' activation
+    match data.deploy_data.profile.profile_settings.profile_type {
+        Some(crate::data::ProfileType::Portable) => (),
+        Some(crate::data::ProfileType::Netboot) => {
+            for file in &["bzImage", "initrd", "netboot.ipxe"] {
+                if !Path::new(&data.deploy_data.profile.profile_settings.path)
+                    .join(file)
+                    .exists()
+                {
+                    return Err(PushProfileError::NetbootFileMissing(file.to_string()));
+                }
+            }
+        }
+        Some(crate::data::ProfileType::BlueGreen) => {
+            let command = crate::deploy::blue_green_command(data.deploy_data);
+
+            if !Path::new(&data.deploy_data.profile.profile_settings.path)
+                .join(&command)
+                .exists()
+            {
+                return Err(PushProfileError::BlueGreenCommandMissing(command));
+            }
+        }
+        Some(crate::data::ProfileType::Profile) | None => {
+            if !Path::new(
+                format!(
+                    "{}/deploy-rs-activate",
+                    data.deploy_data.profile.profile_settings.path
+                )
+                .as_str(),
+            )
+            .exists()
+            {
+                return Err(PushProfileError::DeployRsActivateDoesntExist);
+            }
+
+            if !Path::new(
+                format!(
+                    "{}/activate-rs",
+                    data.deploy_data.profile.profile_settings.path
+                )
+                .as_str(),
+            )
+            .exists()
+            {
+                return Err(PushProfileError::ActivateRsDoesntExist);
+            }
+        }
     }
 
-    if !Path::new(
-        format!(
-            "{}/activate-rs",
-            data.deploy_data.profile.profile_settings.path
-        )
-        .as_str(),
-    )
-    .exists()
-    {
-        return Err(PushProfileError::ActivateRsDoesntExist);
+    let (keys, from_local_key) = signing_keys(
+        &data.deploy_data.merged_settings,
+        std::env::var("LOCAL_KEY").ok(),
+    );
+
+    if from_local_key && !WARNED_LOCAL_KEY.swap(true, Ordering::Relaxed) {
+        raise(
+            Warning::DeprecatedLocalKey,
+            format_args!(
+                "Signing with the key `LOCAL_KEY` points to is deprecated, set `signing.keyFile` instead"
+            ),
+        );
     }
 
-    if let Ok(local_key) = std::env::var("LOCAL_KEY") {
+    for key in &keys {
         info!(
-            "Signing key present! Signing profile `{}` for node `{}`",
-            data.deploy_data.profile_name, data.deploy_data.node_name
+            "Signing profile `{}` for node `{}` with `{}`",
+            data.deploy_data.profile_name, data.deploy_data.node_name, key
         );
 
-        let sign_exit_status = Command::new("nix")
+        let mut sign_command = Invocation::new("nix");
+        sign_command
             .arg("sign-paths")
             .arg("-r")
             .arg("-k")
-            .arg(local_key)
-            .arg(&data.deploy_data.profile.profile_settings.path)
-            .status()
+            .arg(key)
+            .arg(&data.deploy_data.profile.profile_settings.path);
+
+        let started = std::time::Instant::now();
+
+        let sign_output = transport
+            .run(&sign_command)
             .await
             .map_err(PushProfileError::Sign)?;
 
-        match sign_exit_status.code() {
+        timings::record(
+            data.deploy_data.timings,
+            data.deploy_data.node_name,
+            Step::Sign,
+            started,
+        );
+
+        match sign_output.code {
             Some(0) => (),
-            a => return Err(PushProfileError::SignExit(a)),
+            _ => {
+                return Err(PushProfileError::SignExit(CommandFailure::new(
+                    &sign_command,
+                    &sign_output,
+                )))
+            }
         };
     }
 
+    if keys.is_empty() && data.deploy_data.merged_settings.check_sigs == Some(true) {
+        raise(
+            Warning::NoSigningKey,
+            format_args!(
+                "Node `{}` checks signatures, but there's no `signing.keyFile` to sign profile `{}` with",
+                data.deploy_data.node_name, data.deploy_data.profile_name
+            ),
+        );
+    }
+
+    if let Some(ref cache) = data.deploy_data.merged_settings.push_cache {
+        match push_to_cache(
+            transport,
+            cache,
+            &data.deploy_data.profile.profile_settings.path,
+        )
+        .await
+        {
+            Ok(()) => (),
+            Err(e) if cache.required => return Err(e),
+            Err(e) => raise(
+                Warning::CachePushFailed,
+                format_args!(
+                    "Could not push profile `{}` of node `{}` to `{}`: {}",
+                    data.deploy_data.profile_name, data.deploy_data.node_name, cache.name, e
+                ),
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Pushes a closure that was just built to a `pushCache`, with the cache's own CLI
+pub async fn push_to_cache(
+    transport: &dyn Transport,
+    cache: &PushCache,
+    closure: &str,
+) -> Result<(), PushProfileError> {
+    let mut push_command = match cache.cache_type {
+        CacheType::Cachix => Invocation::new("cachix"),
+        CacheType::Attic => Invocation::new("attic"),
+        CacheType::Nix => Invocation::new("nix"),
+    };
+
+    match cache.cache_type {
+        CacheType::Cachix | CacheType::Attic => push_command.arg("push").arg(&cache.name),
+        CacheType::Nix => push_command.arg("copy").arg("--to").arg(&cache.name),
+    };
+
+    push_command.arg(closure).stdout(OutputMode::Null);
+
+    info!(target: "nix_copy", "Pushing {} to `{}`", closure, cache.name);
+    debug!(target: "nix_copy", "Running push command: {}", push_command);
+
+    let push_output = transport
+        .run(&push_command)
+        .await
+        .map_err(PushProfileError::CachePush)?;
+
+    match push_output.code {
+        Some(0) => Ok(()),
+        _ => Err(PushProfileError::CachePushExit(CommandFailure::new(
+            &push_command,
+            &push_output,
+        ))),
+    }
+}
+
+/// Whether using `LOCAL_KEY` was warned about already, which is only done once
+static WARNED_LOCAL_KEY: AtomicBool = AtomicBool::new(false);
+
+/// The keys to sign a node's profiles with, those of `signing.keyFile` or else the one `local_key` (`LOCAL_KEY`)
+/// points to, and whether it's the latter
+fn signing_keys(settings: &GenericSettings, local_key: Option<String>) -> (Vec<String>, bool) {
+    match (&settings.signing, local_key) {
+        (Some(signing), _) if !signing.key_file.0.is_empty() => (signing.key_file.0.clone(), false),
+        (_, Some(local_key)) if !local_key.is_empty() => (vec![local_key], true),
+        _ => (Vec::new(), false),
+    }
+}
+
+#[test]
+fn test_signing_keys() {
+    let settings = |json: &str| -> GenericSettings { serde_json::from_str(json).unwrap() };
+    let with_keys = settings(r#"{ "signing": { "keyFile": ["/run/keys/a", "/run/keys/b"] } }"#);
+
+    assert_eq!(
+        signing_keys(&with_keys, Some("/etc/cache.key".to_string())),
+        (
+            vec!["/run/keys/a".to_string(), "/run/keys/b".to_string()],
+            false
+        )
+    );
+    assert_eq!(
+        signing_keys(&settings("{}"), Some("/etc/cache.key".to_string())),
+        (vec!["/etc/cache.key".to_string()], true)
+    );
+    assert_eq!(signing_keys(&settings("{}"), None), (Vec::new(), false));
+    assert_eq!(
+        signing_keys(&settings("{}"), Some(String::new())),
+        (Vec::new(), false)
+    );
+}
+
+/// How much of a closure its node has already, as found out before copying it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClosurePresence {
+    pub paths: usize,
+    pub missing: usize,
+    pub size: u64,
+    pub missing_size: u64,
+}
+
+impl ClosurePresence {
+    fn new(path_infos: &[agent::PathInfo], invalid: &[&str]) -> Self {
+        let missing: Vec<&agent::PathInfo> = path_infos
+            .iter()
+            .filter(|x| invalid.contains(&x.path.as_str()))
+            .collect();
+
+        ClosurePresence {
+            paths: path_infos.len(),
+            missing: missing.len(),
+            size: path_infos.iter().map(|x| x.nar_size).sum(),
+            missing_size: missing.iter().map(|x| x.nar_size).sum(),
+        }
+    }
+}
+
+#[test]
+fn test_closure_presence() {
+    let path_infos = agent::parse_path_info(
+        r#"{
+            "/nix/store/aaaa-hello-2.10": { "narHash": "sha256:aaaa", "narSize": 100 },
+            "/nix/store/bbbb-bash-4.4": { "narHash": "sha256:bbbb", "narSize": 300 }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        ClosurePresence::new(&path_infos, &["/nix/store/bbbb-bash-4.4"]),
+        ClosurePresence {
+            paths: 2,
+            missing: 1,
+            size: 400,
+            missing_size: 300,
+        }
+    );
+    assert_eq!(ClosurePresence::new(&path_infos, &[]).missing, 0);
+}
+
+async fn query_presence(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<ClosurePresence, String> {
+    let mut path_info_command = Invocation::new("nix");
+    path_info_command
+        .arg("path-info")
+        .arg("--json")
+        .arg("--recursive")
+        .arg(&deploy_data.profile.profile_settings.path)
+        .stdout(OutputMode::Capture);
+
+    let path_info_output = deploy_data
+        .transport
+        .run(&path_info_command)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match path_info_output.code {
+        Some(0) => (),
+        _ => return Err(CommandFailure::new(&path_info_command, &path_info_output).to_string()),
+    };
+
+    let path_infos = agent::parse_path_info(&String::from_utf8_lossy(&path_info_output.stdout))
+        .map_err(|e| e.to_string())?;
+
+    // Store paths never need quoting
+    let check_command = crate::deploy::with_nix_env(
+        crate::deploy::NixEnv::new(&deploy_data.merged_settings),
+        format!(
+            "nix-store --check-validity --print-invalid {}",
+            path_infos
+                .iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        ),
+    );
+
+    let target = deploy_data
+        .node_target(deploy_defs)
+        .map_err(|e| e.to_string())?;
+
+    let check_output = deploy_data
+        .transport
+        .run_on_node(&target, &check_command, true)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    match check_output.code {
+        Some(0) => (),
+        _ => return Err(CommandFailure::new(&check_command, &check_output).to_string()),
+    };
+
+    let invalid = String::from_utf8_lossy(&check_output.stdout);
+
+    Ok(ClosurePresence::new(
+        &path_infos,
+        &invalid.lines().map(|x| x.trim()).collect::<Vec<&str>>(),
+    ))
+}
+
+/// Asks the node which paths of the already built closure it's missing, for profiles which are copied to it with
+/// `nix copy` over SSH
+///
+/// This only lets copies be skipped and tells how much is going to be sent, so anything going wrong is just a warning.
+pub async fn prefetch_presence(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Option<ClosurePresence> {
+    if !matches!(deploy_data.agent_connection(), Ok(None))
+        || deploy_data.merged_settings.serve_store == Some(true)
+        || builds_remotely(deploy_data)
+    {
+        return None;
+    }
+
+    match query_presence(deploy_data, deploy_defs).await {
+        Ok(presence) => {
+            info!(
+                target: "nix_copy",
+                "Node `{}` already has {} of {} paths of profile `{}` ({}%), {} of {} left to copy",
+                deploy_data.node_name,
+                presence.paths - presence.missing,
+                presence.paths,
+                deploy_data.profile_name,
+                match presence.paths {
+                    0 => 100,
+                    paths => (paths - presence.missing) * 100 / paths,
+                },
+                format_size(presence.missing_size),
+                format_size(presence.size)
+            );
+
+            Some(presence)
+        }
+        Err(e) => {
+            warn!(
+                "Could not find out which paths of profile `{}` node `{}` is missing: {}",
+                deploy_data.profile_name, deploy_data.node_name, e
+            );
+
+            None
+        }
+    }
+}
+
+/// The build services `buildOn` knows by name, with their SSH stores
+const BUILD_SERVICES: [(&str, &str); 1] = [("nixbuild", "ssh-ng://eu.nixbuild.net")];
+
+/// The store of the build service a profile is built on with `buildOn`, if it isn't built here
+pub fn build_service(settings: &GenericSettings) -> Option<String> {
+    let build_on = match settings.build_on.as_deref() {
+        None | Some("local") => return None,
+        Some(x) => x,
+    };
+
+    match BUILD_SERVICES.iter().find(|(name, _)| *name == build_on) {
+        Some((_, store)) => Some(store.to_string()),
+        None => Some(build_on.to_string()),
+    }
+}
+
+/// Whether a profile is built somewhere other than here, on its node or on its `buildOn` service, see
+/// `build_remotely`
+pub fn builds_remotely(deploy_data: &crate::DeployData<'_>) -> bool {
+    deploy_data.merged_settings.remote_build.unwrap_or(false)
+        || build_service(&deploy_data.merged_settings).is_some()
+}
+
+/// Builds a profile on the build service `service` from its derivation, and has the node fetch the closure from the
+/// service directly, so that it never passes through here
+async fn build_on_service(
+    data: &PushProfileData<'_>,
+    service: &str,
+) -> Result<(), PushProfileError> {
+    let derivation_name = find_deriver(data).await?;
+    let settings = &data.deploy_data.merged_settings;
+
     info!(
-        "Copying profile `{}` to node `{}`",
+        target: "nix_build",
+        "Building profile `{}` for node `{}` on {}",
+        data.deploy_data.profile_name, data.deploy_data.node_name, service
+    );
+
+    // The derivation is evaluated here and copied to the service along with its inputs, which the service builds
+    let mut build_command = Invocation::new("nix");
+    build_command
+        .arg("build")
+        .arg("--no-link")
+        .arg("--eval-store")
+        .arg("auto")
+        .arg("--store")
+        .arg(service)
+        .arg(format!("{}^*", derivation_name))
+        .args(data.extra_build_args)
+        .stdout(OutputMode::Null);
+
+    set_nix_env(&mut build_command, &settings.build_env);
+
+    debug!(target: "nix_build", "Running build command: {}", build_command);
+
+    let started = std::time::Instant::now();
+
+    let build_output = data
+        .deploy_data
+        .transport
+        .run(&build_command)
+        .await
+        .map_err(|e| PushProfileError::ServiceBuild(service.to_string(), e))?;
+
+    timings::record(
+        data.deploy_data.timings,
+        data.deploy_data.node_name,
+        Step::Build,
+        started,
+    );
+
+    match build_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::ServiceBuildExit(CommandFailure::new(
+                &build_command,
+                &build_output,
+            )))
+        }
+    };
+
+    info!(
+        target: "nix_copy",
+        "Fetching profile `{}` on node `{}` from {}",
+        data.deploy_data.profile_name, data.deploy_data.node_name, service
+    );
+
+    let mut fetch_command = format!(
+        "nix --extra-experimental-features nix-command copy --from {}",
+        crate::shell_quote(service)
+    );
+
+    // Unlike copying from here, this takes paths from a third party, so it's only without signatures when asked for
+    if settings.check_sigs == Some(false) {
+        warn!(
+            "Node `{}` fetches profile `{}` from {} without checking signatures, as `checkSigs` is off",
+            data.deploy_data.node_name, data.deploy_data.profile_name, service
+        );
+        fetch_command = format!("{} --no-check-sigs", fetch_command);
+    }
+
+    let fetch_command = crate::deploy::with_nix_env(
+        crate::deploy::NixEnv::new(settings),
+        format!(
+            "{} {}",
+            fetch_command, data.deploy_data.profile.profile_settings.path
+        ),
+    );
+
+    debug!(target: "nix_copy", "Running fetch command on the node: {}", fetch_command);
+
+    let target = data.deploy_data.node_target(data.deploy_defs)?;
+
+    let fetch_output = data
+        .deploy_data
+        .transport
+        .run_on_node(&target, &fetch_command, false)
+        .await
+        .map_err(|e| PushProfileError::ServiceFetch(service.to_string(), e))?;
+
+    match fetch_output.code {
+        Some(0) => Ok(()),
+        _ => Err(PushProfileError::ServiceFetchExit(CommandFailure::new(
+            &fetch_command,
+            &fetch_output,
+        ))),
+    }
+}
+
+/// Copies the derivation of a profile, with everything needed to build it, to its node and builds it there, the
+/// build log showing here as it goes. For outputs much larger than their sources, which then never leave the node.
+/// Profiles with a `buildOn` service are built there instead
+pub async fn build_remotely(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
+    if let Some(service) = build_service(&data.deploy_data.merged_settings) {
+        return build_on_service(data, &service).await;
+    }
+
+    if data.deploy_data.agent_connection()?.is_some() {
+        return Err(PushProfileError::RemoteBuildAgent);
+    }
+
+    let derivation_name = find_deriver(data).await?;
+
+    info!(
+        target: "nix_build",
+        "Building profile `{}` on node `{}`",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    let mut copy_command = Command::new("nix");
-    copy_command.arg("copy");
+    let settings = &data.deploy_data.merged_settings;
+    let trusted = trusts_ssh_user(data.deploy_data);
 
-    if data.deploy_data.merged_settings.fast_connection != Some(true) {
+    let mut copy_command = Invocation::new("nix");
+    copy_command.arg("copy").arg("--derivation");
+
+    if !data.deploy_data.cmd_overrides.offline && substitute_on_destination(settings) {
         copy_command.arg("--substitute-on-destination");
     }
 
-    if !data.check_sigs {
+    if !trusted && !settings.check_sigs.unwrap_or(false) {
         copy_command.arg("--no-check-sigs");
     }
 
-    let ssh_opts_str = data
+    copy_command
+        .arg("--to")
+        .arg(match trusted {
+            true => trusted_node_store(settings, &ssh_addr(data)),
+            false => node_store(settings, &ssh_addr(data)),
+        })
+        .arg(&derivation_name)
+        .env(
+            "NIX_SSHOPTS",
+            &nix_sshopts(&settings.ssh_opts, data.deploy_data.cmd_overrides),
+        );
+
+    set_nix_env(&mut copy_command, &settings.copy_env);
+
+    debug!(target: "nix_copy", "Running copy command: {}", copy_command);
+
+    let copy_output = data
         .deploy_data
-        .merged_settings
-        .ssh_opts
-        // This should provide some extra safety, but it also breaks for some reason, oh well
-        // .iter()
-        // .map(|x| format!("'{}'", x))
-        // .collect::<Vec<String>>()
-        .join(" ");
+        .transport
+        .run(&copy_command)
+        .await
+        .map_err(PushProfileError::Copy)?;
 
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
+    match copy_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::CopyExit(CommandFailure::new(
+                &copy_command,
+                &copy_output,
+            )))
+        }
     };
 
-    let copy_exit_status = copy_command
-        .arg("--to")
-        .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
-        .arg(&data.deploy_data.profile.profile_settings.path)
-        .env("NIX_SSHOPTS", ssh_opts_str)
-        .status()
+    let realise_command = crate::deploy::with_nix_env(
+        crate::deploy::NixEnv::new(settings),
+        format!("nix-store --realise {}", derivation_name),
+    );
+
+    debug!(target: "nix_build", "Running build command on the node: {}", realise_command);
+
+    let target = data.deploy_data.node_target(data.deploy_defs)?;
+
+    let started = std::time::Instant::now();
+
+    // The output paths printed at the end aren't of interest, the log is on standard error
+    let realise_output = data
+        .deploy_data
+        .transport
+        .run_on_node(&target, &realise_command, true)
         .await
-        .map_err(PushProfileError::Copy)?;
+        .map_err(PushProfileError::RemoteBuild)?;
+
+    timings::record(
+        data.deploy_data.timings,
+        data.deploy_data.node_name,
+        Step::Build,
+        started,
+    );
 
-    match copy_exit_status.code() {
+    match realise_output.code {
         Some(0) => (),
-        a => return Err(PushProfileError::CopyExit(a)),
+        _ => {
+            return Err(PushProfileError::RemoteBuildExit(CommandFailure::new(
+                &realise_command,
+                &realise_output,
+            )))
+        }
     };
 
     Ok(())
 }
+
+pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+    if builds_remotely(data.deploy_data) {
+        return build_remotely(&data).await;
+    }
+
+    build_profile(&data).await?;
+
+    let presence = prefetch_presence(data.deploy_data, data.deploy_defs).await;
+
+    copy_profile(&data, presence.as_ref()).await
+}
+
+/// Copies an already built profile to its node, skipping the copy if `presence` shows the node has all of it
+pub async fn copy_profile(
+    data: &PushProfileData<'_>,
+    presence: Option<&ClosurePresence>,
+) -> Result<(), PushProfileError> {
+    if builds_remotely(data.deploy_data) {
+        debug!(
+            "Profile `{}` of node `{}` wasn't built here, there's nothing to copy",
+            data.deploy_data.profile_name, data.deploy_data.node_name
+        );
+        return Ok(());
+    }
+
+    if let Some(agent_connection) = data.deploy_data.agent_connection()? {
+        return push_through_agent(data, &agent_connection).await;
+    }
+
+    if data.deploy_data.merged_settings.serve_store == Some(true) {
+        return serve_profile(data).await;
+    }
+
+    info!(
+        target: "nix_copy",
+        "Copying profile `{}` to node `{}`",
+        data.deploy_data.profile_name, data.deploy_data.node_name
+    );
+
+    let ssh_addr = ssh_addr(data);
+
+    let mut settings = data.deploy_data.merged_settings.clone();
+
+    // What `deploy bench-copy` found beats guessing from the connection, but not settings that are set explicitly
+    let copy_strategy = match (settings.substitute_on_destination, settings.fast_connection) {
+        (None, None) => recorded_copy_strategy(data.deploy_data.node_name),
+        _ => None,
+    };
+
+    if let Some(copy_strategy) = copy_strategy {
+        debug!(
+            target: "nix_copy",
+            "Copying to node `{}` the recorded quickest way, {}",
+            data.deploy_data.node_name,
+            copy_strategy.name()
+        );
+
+        apply_copy_strategy(&mut settings, copy_strategy);
+    }
+
+    // A `fastConnection` that's set explicitly always wins over the measurement
+    let link = match (
+        settings.probe_connection,
+        settings.fast_connection,
+        copy_strategy,
+    ) {
+        (Some(true), None, None) => known_link(data.deploy_data, &ssh_addr).await,
+        _ => None,
+    };
+
+    if let Some(ref link) = link {
+        settings.fast_connection = Some(link.is_fast());
+
+        raise(
+            Warning::GuessedFastConnection,
+            format_args!(
+                "Going by its measured connection, node `{}` counts as having a {} connection, set `fastConnection` to be sure",
+                data.deploy_data.node_name,
+                match link.is_fast() {
+                    true => "fast",
+                    false => "slow",
+                }
+            ),
+        );
+
+        if link.wants_compression() && !settings.ssh_opts.iter().any(|x| x == "-C") {
+            settings.ssh_opts.push("-C".to_string());
+        }
+    }
+
+    let trusted = trusts_ssh_user(data.deploy_data);
+    let node_store = match trusted {
+        true => trusted_node_store(&settings, &ssh_addr),
+        false => node_store(&settings, &ssh_addr),
+    };
+    let destinations = copy_destinations(&settings, &node_store);
+    let copy_mode = settings.copy_mode.unwrap_or(CopyMode::All);
+
+    let mut last_error = None;
+
+    for destination in &destinations {
+        let result = match presence {
+            Some(presence) if presence.missing == 0 && *destination == node_store => {
+                info!(
+                    target: "nix_copy",
+                    "Node `{}` already has all of profile `{}`, not copying it",
+                    data.deploy_data.node_name, data.deploy_data.profile_name
+                );
+                Ok(())
+            }
+            _ => {
+                copy_to(
+                    data,
+                    &settings,
+                    destination,
+                    trusted && *destination == node_store,
+                )
+                .await
+            }
+        };
+
+        match result {
+            Ok(()) if copy_mode == CopyMode::FirstSuccess => return Ok(()),
+            Ok(()) => (),
+            Err(e) if copy_mode == CopyMode::FirstSuccess => {
+                warn!(
+                    "Copying profile `{}` to `{}` failed, trying the next destination: {}",
+                    data.deploy_data.profile_name, destination, e
+                );
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    // With `first-success`, getting here means every destination failed
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// `user@host` of the node, as `nix copy` connects to it
+fn ssh_addr(data: &PushProfileData<'_>) -> String {
+    let hostname = match data.deploy_data.cmd_overrides.hostname {
+        Some(ref x) => x,
+        None => &data.deploy_data.node.node_settings.hostname,
+    };
+
+    format!("{}@{}", data.deploy_defs.ssh_user, hostname)
+}
+
+/// Percent-encodes what would end a value in the query of a store URI
+fn query_value(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('?', "%3F")
+        .replace('&', "%26")
+        .replace('=', "%3D")
+        .replace(' ', "%20")
+}
+
+/// The query of the store URI of a node: `program` run from `remoteNixPath` and the store under `remoteStoreRoot`, for
+/// those that are set
+fn node_store_query(settings: &GenericSettings, program: &str) -> String {
+    let mut params = Vec::new();
+
+    if let Some(ref dir) = settings.remote_nix_path {
+        params.push(format!(
+            "remote-program={}/{}",
+            dir.trim_end_matches('/'),
+            program
+        ));
+    }
+
+    if let Some(ref root) = settings.remote_store_root {
+        params.push(format!(
+            "remote-store={}",
+            query_value(&crate::deploy::rooted_store(root))
+        ));
+    }
+
+    match params.is_empty() {
+        true => String::new(),
+        false => format!("?{}", params.join("&")),
+    }
+}
+
+/// The store of a node over SSH, running `nix-store` from `remoteNixPath` if that's set
+pub fn node_store(settings: &GenericSettings, ssh_addr: &str) -> String {
+    format!(
+        "ssh://{}{}",
+        ssh_addr,
+        node_store_query(settings, "nix-store")
+    )
+}
+
+/// The store of a node over the Nix daemon's `ssh-ng` protocol, running `nix-daemon` from `remoteNixPath` if that's
+/// set
+pub fn trusted_node_store(settings: &GenericSettings, ssh_addr: &str) -> String {
+    format!(
+        "ssh-ng://{}{}",
+        ssh_addr,
+        node_store_query(settings, "nix-daemon")
+    )
+}
+
+/// Whether the node's Nix daemon trusts the SSH user, going by the facts gathered in the preflight. It's then copied
+/// to over `ssh-ng://`, taking paths without signatures as they are instead of needing `--no-check-sigs`
+fn trusts_ssh_user(deploy_data: &crate::DeployData<'_>) -> bool {
+    deploy_data.facts.as_ref().and_then(|x| x.trusted) == Some(true)
+}
+
+/// Store URIs to copy a profile to, `node` in `copyDestinations` standing for the node itself over SSH
+fn copy_destinations(settings: &GenericSettings, node_store: &str) -> Vec<String> {
+    match settings.copy_destinations {
+        Some(ref destinations) if !destinations.is_empty() => destinations
+            .iter()
+            .map(|x| match x.as_str() {
+                "node" => node_store.to_string(),
+                _ => x.clone(),
+            })
+            .collect(),
+        _ => vec![node_store.to_string()],
+    }
+}
+
+#[test]
+fn test_copy_destinations() {
+    let settings = |json: &str| -> GenericSettings { serde_json::from_str(json).unwrap() };
+
+    assert_eq!(
+        copy_destinations(&settings("{}"), "ssh://deploy@web-1"),
+        vec!["ssh://deploy@web-1"]
+    );
+    assert_eq!(
+        copy_destinations(
+            &settings(r#"{ "copyDestinations": ["node", "ssh://cache.local"] }"#),
+            "ssh-ng://deploy@web-1"
+        ),
+        vec!["ssh-ng://deploy@web-1", "ssh://cache.local"]
+    );
+    assert_eq!(
+        copy_destinations(
+            &settings(r#"{ "copyDestinations": ["file:///mnt/shared-store"] }"#),
+            "ssh://deploy@web-1"
+        ),
+        vec!["file:///mnt/shared-store"]
+    );
+
+    let remote_nix_path = settings(r#"{ "remoteNixPath": "/volume1/@nix/bin/" }"#);
+    assert_eq!(
+        node_store(&remote_nix_path, "deploy@nas"),
+        "ssh://deploy@nas?remote-program=/volume1/@nix/bin/nix-store"
+    );
+    assert_eq!(
+        trusted_node_store(&remote_nix_path, "deploy@nas"),
+        "ssh-ng://deploy@nas?remote-program=/volume1/@nix/bin/nix-daemon"
+    );
+    assert_eq!(
+        trusted_node_store(&settings("{}"), "deploy@web-1"),
+        "ssh-ng://deploy@web-1"
+    );
+    assert_eq!(
+        node_store(
+            &settings(
+                r#"{ "remoteNixPath": "/home/deploy/.nix/bin", "remoteStoreRoot": "/home/deploy/.nix" }"#
+            ),
+            "deploy@web-1"
+        ),
+        "ssh://deploy@web-1?remote-program=/home/deploy/.nix/bin/nix-store&remote-store=local%3Froot%3D/home/deploy/.nix"
+    );
+}
+
+/// Copies the profile to `destination`, the node's `ssh-ng://` store with `trusted`
+async fn copy_to(
+    data: &PushProfileData<'_>,
+    settings: &GenericSettings,
+    destination: &str,
+    trusted: bool,
+) -> Result<(), PushProfileError> {
+    let mut copy_command = Invocation::new("nix");
+    copy_command.arg("copy");
+
+    // The node would be as cut off from its substituters as this machine, so it's sent everything instead
+    if !data.deploy_data.cmd_overrides.offline && substitute_on_destination(settings) {
+        copy_command.arg("--substitute-on-destination");
+    }
+
+    if !trusted && !data.deploy_data.merged_settings.check_sigs.unwrap_or(false) {
+        copy_command.arg("--no-check-sigs");
+    }
+
+    // Let `--log-filter nix_copy=debug` show what nix is doing, without also making builds verbose
+    if log_enabled!(target: "nix_copy", log::Level::Trace) {
+        copy_command.arg("-vv");
+    } else if log_enabled!(target: "nix_copy", log::Level::Debug) {
+        copy_command.arg("-v");
+    }
+
+    let ssh_opts_str = nix_sshopts(&settings.ssh_opts, data.deploy_data.cmd_overrides);
+
+    copy_command
+        .arg("--to")
+        .arg(destination)
+        .arg(&data.deploy_data.profile.profile_settings.path)
+        .env("NIX_SSHOPTS", &ssh_opts_str);
+
+    set_nix_env(&mut copy_command, &settings.copy_env);
+
+    debug!(target: "nix_copy", "Running copy command: {}", copy_command);
+
+    let copy_output = data
+        .deploy_data
+        .transport
+        .run(&copy_command)
+        .await
+        .map_err(PushProfileError::Copy)?;
+
+    match copy_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::CopyExit(CommandFailure::new(
+                &copy_command,
+                &copy_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+/// Copies built closures, signatures included, to a binary cache for nodes to substitute from later
+pub async fn copy_to_cache(
+    transport: &dyn Transport,
+    cache: &str,
+    closures: &[&str],
+) -> Result<(), PushProfileError> {
+    let mut copy_command = Invocation::new("nix");
+    copy_command.arg("copy").arg("--to").arg(cache);
+
+    for closure in closures {
+        copy_command.arg(closure);
+    }
+
+    debug!(target: "nix_copy", "Running copy command: {}", copy_command);
+
+    let copy_output = transport
+        .run(&copy_command)
+        .await
+        .map_err(PushProfileError::Copy)?;
+
+    match copy_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::CopyExit(CommandFailure::new(
+                &copy_command,
+                &copy_output,
+            )))
+        }
+    };
+
+    Ok(())
+}
+
+/// `NIX_SSHOPTS` for `nix copy` to a node, its `sshOpts` followed by those already in the environment unless
+/// `--ignore-nix-sshopts` is given
+///
+/// SSH takes the first value it's given for an option, so `sshOpts` win over the environment.
+pub fn nix_sshopts(ssh_opts: &[String], cmd_overrides: &crate::CmdOverrides) -> String {
+    // Nix splits `NIX_SSHOPTS` at whitespace, SSH run by deploy itself gets the arguments as they are
+    if let Some(ssh_opt) = ssh_opts.iter().find(|x| x.contains(char::is_whitespace)) {
+        warn!(
+            "`nix copy` splits the SSH option `{}` at its spaces, set it in an SSH config file to copy with it",
+            ssh_opt
+        );
+    }
+
+    let inherited = match cmd_overrides.ignore_nix_sshopts {
+        true => None,
+        false => std::env::var("NIX_SSHOPTS").ok(),
+    };
+
+    merge_nix_sshopts(ssh_opts, inherited.as_deref())
+}
+
+fn merge_nix_sshopts(ssh_opts: &[String], inherited: Option<&str>) -> String {
+    let mut merged: Vec<&str> = ssh_opts.iter().map(|x| x.as_str()).collect();
+
+    if let Some(inherited) = inherited {
+        merged.extend(inherited.split_whitespace());
+    }
+
+    merged.join(" ")
+}
+
+#[test]
+fn test_merge_nix_sshopts() {
+    let ssh_opts = vec!["-p".to_string(), "2222".to_string()];
+
+    assert_eq!(merge_nix_sshopts(&ssh_opts, None), "-p 2222");
+    assert_eq!(
+        merge_nix_sshopts(&ssh_opts, Some("-o ProxyCommand=corp-proxy  -C")),
+        "-p 2222 -o ProxyCommand=corp-proxy -C"
+    );
+    assert_eq!(merge_nix_sshopts(&[], Some("-C")), "-C");
+    assert_eq!(merge_nix_sshopts(&[], Some("")), "");
+
+    // `nix copy` goes through the connection `--serial-auth` shares, rather than authenticating again
+    assert_eq!(
+        merge_nix_sshopts(
+            &crate::transport::control_master_opts("/tmp/deploy-rs-ssh-1"),
+            None
+        ),
+        "-o ControlMaster=auto -o ControlPath=/tmp/deploy-rs-ssh-1/%C -o ControlPersist=60"
+    );
+}
+
+/// Sets a node's `buildEnv` or `copyEnv` for one nix invocation, `NIX_CONFIG` being added to the one deploy runs with
+/// instead of replacing it
+pub fn set_nix_env(command: &mut Invocation, env: &HashMap<String, String>) {
+    let mut keys: Vec<&String> = env.keys().collect();
+    keys.sort();
+
+    for key in keys {
+        match key.as_str() {
+            "NIX_CONFIG" => command.env(
+                key,
+                &merge_nix_config(&env[key], std::env::var("NIX_CONFIG").ok().as_deref()),
+            ),
+            _ => command.env(key, &env[key]),
+        };
+    }
+}
+
+fn merge_nix_config(config: &str, inherited: Option<&str>) -> String {
+    match inherited {
+        // Nix goes by the last value given for a setting
+        Some(inherited) if !inherited.trim().is_empty() => {
+            format!("{}\n{}", inherited.trim_end(), config)
+        }
+        _ => config.to_string(),
+    }
+}
+
+#[test]
+fn test_merge_nix_config() {
+    assert_eq!(
+        merge_nix_config("substituters = s3://cache", None),
+        "substituters = s3://cache"
+    );
+    assert_eq!(
+        merge_nix_config(
+            "substituters = s3://cache",
+            Some("experimental-features = nix-command flakes\n")
+        ),
+        "experimental-features = nix-command flakes\nsubstituters = s3://cache"
+    );
+}
+
+/// The copy strategy `deploy bench-copy` recorded for a node, if it was run for it
+fn recorded_copy_strategy(node_name: &str) -> Option<CopyStrategy> {
+    state::state_dir()
+        .and_then(|dir| state::load_node_state(&dir, node_name))
+        .map_err(|e| {
+            debug!(
+                "Not using the recorded copy strategy of node `{}`: {}",
+                node_name, e
+            )
+        })
+        .ok()?
+        .copy_strategy
+}
+
+fn apply_copy_strategy(settings: &mut GenericSettings, copy_strategy: CopyStrategy) {
+    settings.substitute_on_destination = Some(match copy_strategy {
+        CopyStrategy::SubstituteOnDestination => SubstitutePolicy::Always,
+        CopyStrategy::Plain | CopyStrategy::Compressed => SubstitutePolicy::Never,
+    });
+
+    if copy_strategy == CopyStrategy::Compressed && !settings.ssh_opts.iter().any(|x| x == "-C") {
+        settings.ssh_opts.push("-C".to_string());
+    }
+}
+
+#[test]
+fn test_apply_copy_strategy() {
+    let mut settings: GenericSettings =
+        serde_json::from_str(r#"{ "sshOpts": ["-p", "2222"] }"#).unwrap();
+
+    apply_copy_strategy(&mut settings, CopyStrategy::SubstituteOnDestination);
+    assert!(substitute_on_destination(&settings));
+    assert_eq!(settings.ssh_opts, vec!["-p", "2222"]);
+
+    apply_copy_strategy(&mut settings, CopyStrategy::Compressed);
+    apply_copy_strategy(&mut settings, CopyStrategy::Compressed);
+    assert!(!substitute_on_destination(&settings));
+    assert_eq!(settings.ssh_opts, vec!["-p", "2222", "-C"]);
+}
+
+/// The measured connection to a node, probing it if this is the first time it's contacted
+///
+/// Probing is only an optimisation, so anything going wrong is just a warning.
+async fn known_link(
+    deploy_data: &crate::DeployData<'_>,
+    ssh_addr: &str,
+) -> Option<LinkMeasurement> {
+    let node_name = deploy_data.node_name;
+
+    let state_dir = match state::state_dir() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Not probing the connection to node `{}`: {}", node_name, e);
+            return None;
+        }
+    };
+
+    let mut node_state = match state::load_node_state(&state_dir, node_name) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Not probing the connection to node `{}`: {}", node_name, e);
+            return None;
+        }
+    };
+
+    if let Some(link) = node_state.link {
+        debug!(target: "nix_copy", "Using the recorded connection to node `{}`: {:?}", node_name, link);
+        return Some(link);
+    }
+
+    info!(target: "nix_copy", "Probing the connection to node `{}`", node_name);
+
+    let link = match probe::measure_link(
+        deploy_data.transport,
+        ssh_addr,
+        &deploy_data.merged_settings.ssh_opts,
+    )
+    .await
+    {
+        Ok(x) => x,
+        Err(e) => {
+            warn!(
+                "Could not probe the connection to node `{}`: {}",
+                node_name, e
+            );
+            return None;
+        }
+    };
+
+    info!(
+        target: "nix_copy",
+        "Connection to node `{}`: {} KiB/s, {} ms to connect, copying as a {} connection",
+        node_name,
+        link.throughput_kib_s,
+        link.connect_ms,
+        if link.is_fast() { "fast" } else { "slow" }
+    );
+
+    node_state.link = Some(link.clone());
+
+    if let Err(e) = state::save_node_state(&state_dir, node_name, &node_state) {
+        warn!(
+            "Could not record the connection to node `{}`: {}",
+            node_name, e
+        );
+    }
+
+    Some(link)
+}
+
+fn substitute_on_destination(settings: &GenericSettings) -> bool {
+    match settings
+        .substitute_on_destination
+        .unwrap_or(SubstitutePolicy::Auto)
+    {
+        SubstitutePolicy::Always => true,
+        SubstitutePolicy::Never => false,
+        SubstitutePolicy::Auto => settings.fast_connection != Some(true),
+    }
+}
+
+#[test]
+fn test_substitute_on_destination() {
+    let settings = |json: &str| -> GenericSettings { serde_json::from_str(json).unwrap() };
+
+    assert!(substitute_on_destination(&settings("{}")));
+    assert!(!substitute_on_destination(&settings(
+        r#"{ "fastConnection": true }"#
+    )));
+    assert!(substitute_on_destination(&settings(
+        r#"{ "fastConnection": true, "substituteOnDestination": "always" }"#
+    )));
+    assert!(!substitute_on_destination(&settings(
+        r#"{ "substituteOnDestination": "never" }"#
+    )));
+}
+
+fn build_realise_command(closure: &str, port: u16, check_sigs: bool) -> String {
+    let mut realise_command = format!(
+        "nix-store --realise '{}' --option substituters http://127.0.0.1:{}",
+        closure, port
+    );
+
+    if !check_sigs {
+        realise_command = format!("{} --option require-sigs false", realise_command);
+    }
+
+    realise_command
+}
+
+#[test]
+fn test_realise_command_builder() {
+    assert_eq!(
+        build_realise_command("/nix/store/blah/etc", 41234, false),
+        "nix-store --realise '/nix/store/blah/etc' --option substituters http://127.0.0.1:41234 --option require-sigs false"
+            .to_string(),
+    );
+    assert_eq!(
+        build_realise_command("/nix/store/blah/etc", 41234, true),
+        "nix-store --realise '/nix/store/blah/etc' --option substituters http://127.0.0.1:41234"
+            .to_string(),
+    );
+}
+
+/// Serves the local store over a reverse SSH tunnel and has the node substitute the profile from it,
+/// which copes with high-latency links a lot better than `nix copy`
+async fn serve_profile(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map_err(PushProfileError::ServeStore)?
+        .port();
+
+    info!(
+        target: "nix_copy",
+        "Serving profile `{}` to node `{}` from a temporary binary cache",
+        data.deploy_data.profile_name, data.deploy_data.node_name
+    );
+
+    let mut serve_command = Command::new("nix-serve");
+    serve_command
+        .arg("--listen")
+        .arg(format!("127.0.0.1:{}", port))
+        .stdout(Stdio::null())
+        .kill_on_drop(true);
+
+    debug!(target: "nix_copy", "Running serve command: {:?}", serve_command);
+
+    let mut server = serve_command
+        .spawn()
+        .map_err(PushProfileError::ServeStore)?;
+
+    let mut attempts = 0;
+
+    while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if let Some(status) = server.try_wait().map_err(PushProfileError::ServeStore)? {
+            return Err(PushProfileError::ServeStoreExit(CommandFailure::new(
+                format!("nix-serve --listen 127.0.0.1:{}", port),
+                &Output {
+                    code: status.code(),
+                    ..Output::default()
+                },
+            )));
+        }
+
+        attempts += 1;
+        if attempts > 100 {
+            return Err(PushProfileError::ServeStoreNotReady(port));
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let hostname = match data.deploy_data.cmd_overrides.hostname {
+        Some(ref x) => x,
+        None => &data.deploy_data.node.node_settings.hostname,
+    };
+
+    let ssh_addr = format!("{}@{}", data.deploy_defs.ssh_user, hostname);
+
+    let mut ssh_realise_command = Invocation::new("ssh");
+    ssh_realise_command
+        .arg("-o")
+        .arg("ExitOnForwardFailure=yes")
+        .arg("-R")
+        .arg(format!("{0}:127.0.0.1:{0}", port))
+        .arg(&ssh_addr);
+
+    ssh_realise_command.args(&data.deploy_data.merged_settings.ssh_opts);
+
+    let realise_command = crate::deploy::with_nix_env(
+        crate::deploy::NixEnv::new(&data.deploy_data.merged_settings),
+        build_realise_command(
+            &data.deploy_data.profile.profile_settings.path,
+            port,
+            data.deploy_data.merged_settings.check_sigs.unwrap_or(false),
+        ),
+    );
+
+    debug!(
+        target: "nix_copy",
+        "Running `{}` on node `{}` ({})",
+        realise_command, data.deploy_data.node_name, ssh_addr
+    );
+
+    ssh_realise_command
+        .arg(realise_command)
+        // `nix-store --realise` prints the realised path, which we already know
+        .stdout(OutputMode::Null);
+
+    let realise_output = data
+        .deploy_data
+        .transport
+        .run(&ssh_realise_command)
+        .await
+        .map_err(PushProfileError::ServeStoreRealise)?;
+
+    match realise_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::ServeStoreRealiseExit(
+                CommandFailure::new(&ssh_realise_command, &realise_output),
+            ))
+        }
+    };
+
+    Ok(())
+}
+
+/// Sends the paths of the closure which the node doesn't have yet through its agent
+async fn push_through_agent(
+    data: &PushProfileData<'_>,
+    agent_connection: &AgentConnection<'_>,
+) -> Result<(), PushProfileError> {
+    let closure = &data.deploy_data.profile.profile_settings.path;
+
+    info!(
+        target: "nix_copy",
+        "Copying profile `{}` to node `{}` through its agent",
+        data.deploy_data.profile_name, data.deploy_data.node_name
+    );
+
+    let mut path_info_command = Invocation::new("nix");
+    path_info_command
+        .arg("path-info")
+        .arg("--json")
+        .arg("--sigs")
+        .arg("--recursive")
+        .arg(closure)
+        .stdout(OutputMode::Capture);
+
+    let path_info_output = data
+        .deploy_data
+        .transport
+        .run(&path_info_command)
+        .await
+        .map_err(PushProfileError::QueryClosure)?;
+
+    match path_info_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::QueryClosureExit(CommandFailure::new(
+                &path_info_command,
+                &path_info_output,
+            )))
+        }
+    };
+
+    let path_infos = agent::parse_path_info(&String::from_utf8_lossy(&path_info_output.stdout))
+        .map_err(PushProfileError::QueryClosureParse)?;
+
+    let query_output = agent::request(
+        agent_connection,
+        &AgentRequest::QueryInvalid {
+            paths: path_infos.iter().map(|x| x.path.clone()).collect(),
+        },
+        None,
+        true,
+    )
+    .await?;
+
+    match query_output.code {
+        Some(0) => (),
+        a => return Err(PushProfileError::AgentQueryExit(a)),
+    };
+
+    if query_output.invalid.is_empty() {
+        debug!(target: "nix_copy", "Node already has the whole closure");
+        return Ok(());
+    }
+
+    debug!(
+        target: "nix_copy",
+        "Exporting {} store paths for the agent",
+        query_output.invalid.len()
+    );
+
+    // The size has to be known up front, so the binary cache is put together in a temporary directory
+    let cache_dir = std::env::temp_dir().join(format!(
+        "deploy-rs-agent-cache-{}-{}-{}",
+        std::process::id(),
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name
+    ));
+    let archive_path = cache_dir.with_extension("tar");
+
+    let result = match export_cache(
+        data.deploy_data.transport,
+        &cache_dir,
+        &archive_path,
+        &path_infos,
+        &query_output.invalid,
+    )
+    .await
+    {
+        Ok(size) => agent::request(
+            agent_connection,
+            &AgentRequest::Import {
+                size,
+                paths: query_output.invalid,
+            },
+            Some(&archive_path.to_string_lossy()),
+            false,
+        )
+        .await
+        .map_err(PushProfileError::from)
+        .and_then(|output| match output.code {
+            Some(0) => Ok(()),
+            a => Err(PushProfileError::AgentImportExit(a)),
+        }),
+        Err(e) => Err(e),
+    };
+
+    let _ = std::fs::remove_dir_all(&cache_dir);
+    let _ = std::fs::remove_file(&archive_path);
+
+    result
+}
+
+/// Writes a `file://` binary cache with narinfos for the whole closure but only the NARs the node is missing,
+/// and packs it into a tar archive, returning its size
+pub(crate) async fn export_cache(
+    transport: &dyn Transport,
+    cache_dir: &Path,
+    archive_path: &Path,
+    path_infos: &[agent::PathInfo],
+    invalid: &[String],
+) -> Result<u64, PushProfileError> {
+    std::fs::create_dir_all(cache_dir.join("nar")).map_err(PushProfileError::Export)?;
+
+    std::fs::write(cache_dir.join("nix-cache-info"), "StoreDir: /nix/store\n")
+        .map_err(PushProfileError::Export)?;
+
+    for path_info in path_infos {
+        std::fs::write(
+            cache_dir.join(agent::narinfo_file(&path_info.path)),
+            agent::format_narinfo(path_info),
+        )
+        .map_err(PushProfileError::Export)?;
+    }
+
+    for path in invalid {
+        let mut dump_command = Invocation::new("nix-store");
+        dump_command
+            .arg("--dump")
+            .arg(path)
+            .stdout(OutputMode::File(cache_dir.join(agent::nar_file(path))));
+
+        let dump_output = transport
+            .run(&dump_command)
+            .await
+            .map_err(PushProfileError::Export)?;
+
+        match dump_output.code {
+            Some(0) => (),
+            _ => {
+                return Err(PushProfileError::ExportExit(CommandFailure::new(
+                    &dump_command,
+                    &dump_output,
+                )))
+            }
+        };
+    }
+
+    let mut tar_command = Invocation::new("tar");
+    tar_command
+        .arg("-C")
+        .arg(cache_dir.to_string_lossy())
+        .arg("-cf")
+        .arg(archive_path.to_string_lossy())
+        .arg(".");
+
+    let tar_output = transport
+        .run(&tar_command)
+        .await
+        .map_err(PushProfileError::Export)?;
+
+    match tar_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(PushProfileError::ExportExit(CommandFailure::new(
+                &tar_command,
+                &tar_output,
+            )))
+        }
+    };
+
+    Ok(std::fs::metadata(archive_path)
+        .map_err(PushProfileError::Export)?
+        .len())
+}
+
+#[test]
+fn test_push_profile_orchestration() {
+    // The build checks the profile for its activation scripts, so they have to exist somewhere
+    let closure = std::env::temp_dir().join(format!("deploy-rs-test-push-{}", std::process::id()));
+    std::fs::create_dir_all(&closure).unwrap();
+    std::fs::write(closure.join("deploy-rs-activate"), "").unwrap();
+    std::fs::write(closure.join("activate-rs"), "").unwrap();
+    let closure = closure.to_string_lossy().to_string();
+
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web-1",
+        "sshUser": "deploy",
+        "checkSigs": true,
+        "buildEnv": { "AWS_PROFILE": "build" },
+        "copyEnv": { "AWS_PROFILE": "copy" },
+        "profiles": { "system": { "path": closure } }
+    }))
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    transport.respond(
+        "nix show-derivation",
+        Some(0),
+        r#"{"/nix/store/00000000000000000000000000000000-system.drv": {}}"#,
+    );
+    transport.respond(
+        "nix path-info",
+        Some(0),
+        r#"{"/nix/store/aaaa-hello-2.10": { "narHash": "sha256:aaaa", "narSize": 100 }, "/nix/store/bbbb-bash-4.4": { "narHash": "sha256:bbbb", "narSize": 300 }}"#,
+    );
+    transport.respond(
+        "nix-store --check-validity",
+        Some(0),
+        "/nix/store/bbbb-bash-4.4\n",
+    );
+
+    let result = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(push_profile(PushProfileData {
+            supports_flakes: true,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }));
+
+    assert!(result.is_ok());
+    assert_eq!(
+        transport
+            .commands()
+            .into_iter()
+            .filter(|x| !x.contains("sign-paths"))
+            .collect::<Vec<String>>(),
+        vec![
+            format!("nix show-derivation {}", closure),
+            "AWS_PROFILE=build nix build /nix/store/00000000000000000000000000000000-system.drv --no-link --log-format internal-json"
+                .to_string(),
+            format!("nix path-info --json --recursive {}", closure),
+            "[ssh://deploy@web-1] nix-store --check-validity --print-invalid /nix/store/aaaa-hello-2.10 /nix/store/bbbb-bash-4.4".to_string(),
+            format!(
+                "NIX_SSHOPTS= AWS_PROFILE=copy nix copy --substitute-on-destination --to ssh://deploy@web-1 {}",
+                closure
+            ),
+        ]
+    );
+
+    // Nothing is copied when the node has every path already
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..deploy_data
+    };
+
+    transport.respond(
+        "nix show-derivation",
+        Some(0),
+        r#"{"/nix/store/00000000000000000000000000000000-system.drv": {}}"#,
+    );
+    transport.respond(
+        "nix path-info",
+        Some(0),
+        r#"{"/nix/store/aaaa-hello-2.10": { "narHash": "sha256:aaaa", "narSize": 100 }}"#,
+    );
+
+    let result = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(push_profile(PushProfileData {
+            supports_flakes: true,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }));
+
+    assert!(result.is_ok());
+    assert!(!transport.commands().iter().any(|x| x.contains("nix copy")));
+
+    // With `first-success`, a failed destination falls through to the next one, and the rest are skipped
+    let node: crate::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web-1",
+        "sshUser": "deploy",
+        "checkSigs": true,
+        "copyDestinations": ["ssh://cache.local", "node", "file:///mnt/shared-store"],
+        "copyMode": "first-success",
+        "profiles": { "system": { "path": closure } }
+    }))
+    .unwrap();
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+
+    transport.respond(
+        "nix show-derivation",
+        Some(0),
+        r#"{"/nix/store/00000000000000000000000000000000-system.drv": {}}"#,
+    );
+    transport.respond("--to ssh://cache.local", Some(1), "");
+
+    let result = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(push_profile(PushProfileData {
+            supports_flakes: true,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }));
+
+    let _ = std::fs::remove_dir_all(&closure);
+
+    assert!(result.is_ok());
+    assert_eq!(
+        transport
+            .commands()
+            .into_iter()
+            .filter(|x| x.contains("nix copy"))
+            .collect::<Vec<String>>(),
+        vec![
+            format!(
+                "NIX_SSHOPTS= nix copy --substitute-on-destination --to ssh://cache.local {}",
+                closure
+            ),
+            format!(
+                "NIX_SSHOPTS= nix copy --substitute-on-destination --to ssh://deploy@web-1 {}",
+                closure
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_build_remotely() {
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web-1",
+        "sshUser": "deploy",
+        "remoteBuild": true,
+        "remoteNixPath": "/opt/nix/bin",
+        "profiles": { "system": { "path": "/nix/store/00000000000000000000000000000000-system" } }
+    }))
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    transport.respond(
+        "nix show-derivation",
+        Some(0),
+        r#"{"/nix/store/11111111111111111111111111111111-system.drv": {}}"#,
+    );
+
+    let result = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(push_profile(PushProfileData {
+            supports_flakes: true,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }));
+
+    assert!(result.is_ok());
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "nix show-derivation /nix/store/00000000000000000000000000000000-system",
+            "NIX_SSHOPTS= nix copy --derivation --substitute-on-destination --no-check-sigs --to ssh://deploy@web-1?remote-program=/opt/nix/bin/nix-store /nix/store/11111111111111111111111111111111-system.drv",
+            "[ssh://deploy@web-1] env PATH='/opt/nix/bin':\"$PATH\" nix-store --realise /nix/store/11111111111111111111111111111111-system.drv",
+        ]
+    );
+}
+
+#[test]
+fn test_build_invocation() {
+    let settings: GenericSettings =
+        serde_json::from_str(r#"{ "buildCores": 4, "buildMaxJobs": 2, "buildNice": 10 }"#).unwrap();
+
+    let mut build_command = build_invocation("nix", &settings);
+    build_command.arg("build");
+    limit_build(&mut build_command, &settings);
+
+    assert_eq!(
+        build_command.to_string(),
+        match cfg!(target_os = "linux") {
+            true => "nice -n 10 ionice -c 3 nix build --cores 4 --max-jobs 2",
+            false => "nice -n 10 nix build --cores 4 --max-jobs 2",
+        }
+    );
+    assert_eq!(
+        build_invocation("nix-build", &serde_json::from_str("{}").unwrap()).to_string(),
+        "nix-build"
+    );
+}
+
+#[test]
+fn test_push_to_cache() {
+    let cache = |json: &str| -> PushCache { serde_json::from_str(json).unwrap() };
+    let transport = crate::transport::RecordingTransport::default();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let closure = "/nix/store/00000000000000000000000000000000-system";
+
+    for json in &[
+        r#"{ "type": "cachix", "name": "my-team" }"#,
+        r#"{ "type": "attic", "name": "ci:main", "required": true }"#,
+        r#"{ "type": "nix", "name": "s3://cache?region=eu-west-1" }"#,
+    ] {
+        runtime
+            .block_on(push_to_cache(&transport, &cache(json), closure))
+            .unwrap();
+    }
+
+    assert_eq!(
+        transport.commands(),
+        vec![
+            format!("cachix push my-team {}", closure),
+            format!("attic push ci:main {}", closure),
+            format!("nix copy --to s3://cache?region=eu-west-1 {}", closure),
+        ]
+    );
+
+    transport.respond("cachix push", Some(1), "");
+    assert!(matches!(
+        runtime.block_on(push_to_cache(
+            &transport,
+            &cache(r#"{ "type": "cachix", "name": "my-team" }"#),
+            closure
+        )),
+        Err(PushProfileError::CachePushExit(_))
+    ));
+}
+
+#[test]
+fn test_build_on_service() {
+    let settings = |json: &str| -> GenericSettings { serde_json::from_str(json).unwrap() };
+
+    assert_eq!(build_service(&settings("{}")), None);
+    assert_eq!(build_service(&settings(r#"{ "buildOn": "local" }"#)), None);
+    assert_eq!(
+        build_service(&settings(
+            r#"{ "buildOn": "ssh-ng://builder.example.com" }"#
+        ))
+        .as_deref(),
+        Some("ssh-ng://builder.example.com")
+    );
+
+    let top_settings = serde_json::from_str("{}").unwrap();
+    let node: crate::data::Node = serde_json::from_value(serde_json::json!({
+        "hostname": "web-1",
+        "sshUser": "deploy",
+        "buildOn": "nixbuild",
+        "profiles": { "system": { "path": "/nix/store/00000000000000000000000000000000-system" } }
+    }))
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let transport = crate::transport::RecordingTransport::default();
+    let deploy_data = crate::DeployData {
+        transport: &transport,
+        ..crate::make_deploy_data(
+            &top_settings,
+            &node,
+            "web-1",
+            &node.node_settings.profiles["system"],
+            "system",
+            &cmd_overrides,
+            false,
+            None,
+        )
+    };
+    let deploy_defs = deploy_data.defs().unwrap();
+
+    transport.respond(
+        "nix show-derivation",
+        Some(0),
+        r#"{"/nix/store/11111111111111111111111111111111-system.drv": {}}"#,
+    );
+
+    let result = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(push_profile(PushProfileData {
+            supports_flakes: true,
+            repo: ".",
+            deploy_data: &deploy_data,
+            deploy_defs: &deploy_defs,
+            keep_result: false,
+            result_path: None,
+            extra_build_args: &[],
+        }));
+
+    assert!(result.is_ok());
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "nix show-derivation /nix/store/00000000000000000000000000000000-system",
+            "nix build --no-link --eval-store auto --store ssh-ng://eu.nixbuild.net /nix/store/11111111111111111111111111111111-system.drv^*",
+            "[ssh://deploy@web-1] nix --extra-experimental-features nix-command copy --from 'ssh-ng://eu.nixbuild.net' /nix/store/00000000000000000000000000000000-system",
+        ]
+    );
+}
@@ -0,0 +1,279 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::process::Command;
+
+/// What has to be up on the node before its activation counts as successful
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Readiness {
+    /// A systemd unit which has to be active
+    pub unit: Option<String>,
+    /// A port on localhost which has to accept connections
+    pub port: Option<u16>,
+    /// An `http://` URL which has to answer with a 2xx or 3xx status
+    pub url: Option<String>,
+    /// How many seconds to wait for all of it
+    pub timeout: u16,
+}
+
+impl Readiness {
+    pub fn is_empty(&self) -> bool {
+        self.unit.is_none() && self.port.is_none() && self.url.is_none()
+    }
+
+    /// Each of the things to wait for on its own, described like `ReadinessError::TimedOut` lists them
+    pub fn split(&self) -> Vec<(String, Readiness)> {
+        let single = Readiness {
+            unit: None,
+            port: None,
+            url: None,
+            timeout: self.timeout,
+        };
+        let mut checks = Vec::new();
+
+        if let Some(ref unit) = self.unit {
+            checks.push((
+                format!("unit `{}`", unit),
+                Readiness {
+                    unit: Some(unit.clone()),
+                    ..single.clone()
+                },
+            ));
+        }
+
+        if let Some(port) = self.port {
+            checks.push((
+                format!("port {}", port),
+                Readiness {
+                    port: Some(port),
+                    ..single.clone()
+                },
+            ));
+        }
+
+        if let Some(ref url) = self.url {
+            checks.push((
+                format!("`{}`", url),
+                Readiness {
+                    url: Some(url.clone()),
+                    ..single
+                },
+            ));
+        }
+
+        checks
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReadinessError {
+    #[error("Only http:// URLs can be waited for, not `{0}`")]
+    Url(String),
+    #[error("Failed to run systemctl to check on unit `{0}`: {1}")]
+    Systemctl(String, std::io::Error),
+    #[error("Unit `{0}` failed")]
+    UnitFailed(String),
+    #[error("Still waiting for {1} after {0} seconds")]
+    TimedOut(u16, String),
+}
+
+/// Splits an `http://` URL into the host, port and path to request
+fn parse_http_url(url: &str) -> Result<(String, u16, String), ReadinessError> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err(ReadinessError::Url(url.to_string())),
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => match authority[i + 1..].parse::<u16>() {
+            Ok(port) => (&authority[..i], port),
+            Err(_) => return Err(ReadinessError::Url(url.to_string())),
+        },
+        None => (authority, 80),
+    };
+
+    if host.is_empty() {
+        return Err(ReadinessError::Url(url.to_string()));
+    }
+
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+fn connect(host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let mut last_error = std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("`{}` has no addresses", host),
+    );
+
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+fn url_answers(host: &str, port: u16, path: &str) -> std::io::Result<bool> {
+    let mut stream = connect(host, port)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // In one piece, a server closing the connection before it read all of the request would reset it
+    stream.write_all(
+        format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+        .as_bytes(),
+    )?;
+
+    // Only the status code is of interest, `HTTP/1.1 200`
+    let mut status_line = [0u8; 12];
+    stream.read_exact(&mut status_line)?;
+
+    Ok(status_line.starts_with(b"HTTP/") && matches!(status_line[9], b'2' | b'3'))
+}
+
+/// Whether the unit is active, `None` while it's still getting there
+async fn unit_active(unit: &str) -> Result<Option<bool>, ReadinessError> {
+    let output = Command::new("systemctl")
+        .arg("is-active")
+        .arg(unit)
+        .output()
+        .await
+        .map_err(|e| ReadinessError::Systemctl(unit.to_string(), e))?;
+
+    Ok(match String::from_utf8_lossy(&output.stdout).trim() {
+        "active" => Some(true),
+        "failed" => Some(false),
+        _ => None,
+    })
+}
+
+/// Checks everything in `readiness` once a second, until all of it is up or its timeout has passed
+pub async fn wait_until_ready(readiness: &Readiness) -> Result<(), ReadinessError> {
+    let url = match readiness.url {
+        Some(ref url) => Some((url, parse_http_url(url)?)),
+        None => None,
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(readiness.timeout as u64);
+
+    loop {
+        let mut pending = Vec::new();
+
+        if let Some(ref unit) = readiness.unit {
+            match unit_active(unit).await? {
+                Some(true) => (),
+                Some(false) => return Err(ReadinessError::UnitFailed(unit.clone())),
+                None => pending.push(format!("unit `{}`", unit)),
+            }
+        }
+
+        if let Some(port) = readiness.port {
+            if connect("localhost", port).is_err() {
+                pending.push(format!("port {}", port));
+            }
+        }
+
+        if let Some((url, (ref host, port, ref path))) = url {
+            if !url_answers(host, port, path).unwrap_or(false) {
+                pending.push(format!("`{}`", url));
+            }
+        }
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(ReadinessError::TimedOut(
+                readiness.timeout,
+                pending.join(", "),
+            ));
+        }
+
+        debug!("Still waiting for {}", pending.join(", "));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+#[test]
+fn test_parse_http_url() {
+    assert_eq!(
+        parse_http_url("http://localhost:8080/health").unwrap(),
+        ("localhost".to_string(), 8080, "/health".to_string())
+    );
+    assert_eq!(
+        parse_http_url("http://127.0.0.1").unwrap(),
+        ("127.0.0.1".to_string(), 80, "/".to_string())
+    );
+    assert!(matches!(
+        parse_http_url("https://localhost/health"),
+        Err(ReadinessError::Url(_))
+    ));
+    assert!(matches!(
+        parse_http_url("http://localhost:http/"),
+        Err(ReadinessError::Url(_))
+    ));
+}
+
+#[test]
+fn test_wait_until_ready() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+            .unwrap();
+    });
+
+    assert!(runtime
+        .block_on(wait_until_ready(&Readiness {
+            unit: None,
+            port: None,
+            url: Some(format!("http://127.0.0.1:{}/health", port)),
+            timeout: 5,
+        }))
+        .is_ok());
+
+    server.join().unwrap();
+
+    // Nothing listens anymore
+    assert!(matches!(
+        runtime.block_on(wait_until_ready(
+            &Readiness {
+                unit: None,
+                port: Some(port),
+                url: None,
+                timeout: 0,
+            })),
+        Err(ReadinessError::TimedOut(0, ref pending)) if pending == &format!("port {}", port)
+    ));
+}
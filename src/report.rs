@@ -0,0 +1,148 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::Serialize;
+use std::fmt;
+
+use crate::transport::Output;
+
+/// A command which ran, but didn't succeed
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandFailure {
+    pub command: String,
+    pub code: Option<i32>,
+    pub stderr_tail: Vec<String>,
+}
+
+impl CommandFailure {
+    pub fn new<C: fmt::Display>(command: C, output: &Output) -> Self {
+        CommandFailure {
+            command: command.to_string(),
+            code: output.code,
+            stderr_tail: output.stderr_tail.clone(),
+        }
+    }
+}
+
+impl fmt::Display for CommandFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "exit code {}", code),
+            None => write!(f, "no exit code (was it killed?)"),
+        }
+    }
+}
+
+/// What an error can tell about itself beyond its message
+pub trait Diagnostic: std::error::Error {
+    /// The command whose failure caused the error
+    fn failure(&self) -> Option<&CommandFailure> {
+        None
+    }
+
+    /// Something the user can try to fix the error
+    fn hint(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// An error from deploying a profile, reported together with where it happened and how to go on
+#[derive(Debug)]
+pub struct Reported<E> {
+    pub error: E,
+    pub node: String,
+    pub profile: String,
+}
+
+impl<E> Reported<E> {
+    pub fn new(error: E, deploy_data: &crate::DeployData<'_>) -> Self {
+        Reported {
+            error,
+            node: deploy_data.node_name.to_string(),
+            profile: deploy_data.profile_name.to_string(),
+        }
+    }
+}
+
+impl<E: Diagnostic> fmt::Display for Reported<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        write!(f, "\n  node:    {}", self.node)?;
+        write!(f, "\n  profile: {}", self.profile)?;
+
+        if let Some(failure) = self.error.failure() {
+            write!(f, "\n  command: {}", failure.command)?;
+
+            if !failure.stderr_tail.is_empty() {
+                write!(f, "\n  stderr:")?;
+
+                for line in &failure.stderr_tail {
+                    write!(f, "\n    | {}", line)?;
+                }
+            }
+        }
+
+        if let Some(hint) = self.error.hint() {
+            write!(f, "\n  help:    {}", hint)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<E: Diagnostic + 'static> std::error::Error for Reported<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+#[test]
+fn test_report_rendering() {
+    #[derive(thiserror::Error, Debug)]
+    #[error("Nix copy command failed with {0}")]
+    struct CopyError(CommandFailure);
+
+    impl Diagnostic for CopyError {
+        fn failure(&self) -> Option<&CommandFailure> {
+            Some(&self.0)
+        }
+
+        fn hint(&self) -> Option<&'static str> {
+            Some("Check that the node can be reached")
+        }
+    }
+
+    let report = Reported {
+        error: CopyError(CommandFailure::new(
+            "nix copy --to ssh://deploy@web-1 /nix/store/blah",
+            &Output {
+                code: Some(1),
+                stdout: Vec::new(),
+                stderr_tail: vec![
+                    "error: cannot connect to 'deploy@web-1'".to_string(),
+                    "error: unexpected end-of-file".to_string(),
+                ],
+            },
+        )),
+        node: "web-1".to_string(),
+        profile: "system".to_string(),
+    };
+
+    assert_eq!(
+        report.to_string(),
+        "Nix copy command failed with exit code 1\n  \
+         node:    web-1\n  \
+         profile: system\n  \
+         command: nix copy --to ssh://deploy@web-1 /nix/store/blah\n  \
+         stderr:\n    \
+         | error: cannot connect to 'deploy@web-1'\n    \
+         | error: unexpected end-of-file\n  \
+         help:    Check that the node can be reached"
+    );
+    assert_eq!(
+        CommandFailure::new("activate-rs", &Output::default()).to_string(),
+        "no exit code (was it killed?)"
+    );
+}
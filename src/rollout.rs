@@ -0,0 +1,217 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::collections::HashMap;
+
+use crate::data::Node;
+
+/// A share of the nodes to deploy to first, e.g. `10%`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentage(pub u8);
+
+impl std::str::FromStr for Percentage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim_end_matches('%').parse::<u8>() {
+            Ok(x) if (1..=100).contains(&x) => Ok(Percentage(x)),
+            _ => Err(format!(
+                "`{}` is not a percentage between 1% and 100%, e.g. `10%`",
+                s
+            )),
+        }
+    }
+}
+
+/// How likely nodes are to be picked, by the value of one of their `key=value` tags, e.g. `region=eu:2,us:1`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weights {
+    pub key: String,
+    /// Values not listed have no weight, so their nodes aren't picked
+    pub values: Vec<(String, u32)>,
+}
+
+impl std::str::FromStr for Weights {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "`{}` is not a tag key with weighted values, e.g. `region=eu:2,us:1`",
+                s
+            )
+        };
+
+        let i = s.find('=').ok_or_else(invalid)?;
+        let key = &s[..i];
+
+        let values = s[i + 1..]
+            .split(',')
+            .map(|value| match value.rfind(':') {
+                Some(j) => value[j + 1..]
+                    .parse()
+                    .map(|weight| (value[..j].to_string(), weight))
+                    .map_err(|_| invalid()),
+                None => Ok((value.to_string(), 1)),
+            })
+            .collect::<Result<Vec<(String, u32)>, String>>()?;
+
+        if key.is_empty() || values.iter().any(|(value, _)| value.is_empty()) {
+            return Err(invalid());
+        }
+
+        Ok(Weights {
+            key: key.to_string(),
+            values,
+        })
+    }
+}
+
+impl Weights {
+    fn weight(&self, tags: &[String]) -> u32 {
+        let prefix = format!("{}=", self.key);
+
+        tags.iter()
+            .filter(|tag| tag.starts_with(&prefix))
+            .filter_map(|tag| {
+                self.values
+                    .iter()
+                    .find(|(value, _)| *value == tag[prefix.len()..])
+            })
+            .map(|(_, weight)| *weight)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[test]
+fn test_parse_rollout_selectors() {
+    assert_eq!("10%".parse(), Ok(Percentage(10)));
+    assert_eq!("25".parse(), Ok(Percentage(25)));
+    assert!("0%".parse::<Percentage>().is_err());
+    assert!("150%".parse::<Percentage>().is_err());
+
+    assert_eq!(
+        "region=eu:2,us:1,ap".parse(),
+        Ok(Weights {
+            key: "region".to_string(),
+            values: vec![
+                ("eu".to_string(), 2),
+                ("us".to_string(), 1),
+                ("ap".to_string(), 1)
+            ],
+        })
+    );
+    assert!("region".parse::<Weights>().is_err());
+    assert!("region=eu:x".parse::<Weights>().is_err());
+    assert!("=eu:2".parse::<Weights>().is_err());
+}
+
+/// FNV-1a, which unlike the standard library's hashers is guaranteed to stay the same between releases
+fn stable_hash(seed: &str, name: &str) -> u64 {
+    seed.bytes()
+        .chain(std::iter::once(0))
+        .chain(name.bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Picks `percentage` of the nodes (at least one) to deploy to first, the same ones for the same `seed`
+///
+/// With `weights`, nodes are picked with a likelihood in proportion to their weight, and only nodes with a weight
+/// count towards the share. This is weighted sampling by giving each node the key `u^(1/weight)` for a `u` between 0
+/// and 1 taken from the seed and its name, and picking the nodes with the highest keys.
+pub fn select_canaries(
+    nodes: &HashMap<String, Node>,
+    percentage: Percentage,
+    weights: Option<&Weights>,
+    seed: &str,
+) -> Vec<String> {
+    let mut candidates: Vec<(f64, &String)> = nodes
+        .iter()
+        .filter_map(|(name, node)| {
+            let weight = match weights {
+                Some(weights) => weights.weight(&node.node_settings.tags),
+                None => 1,
+            };
+
+            if weight == 0 {
+                return None;
+            }
+
+            let u = ((stable_hash(seed, name) >> 11) + 1) as f64 / ((1u64 << 53) + 1) as f64;
+
+            Some((u.powf(1.0 / weight as f64), name))
+        })
+        .collect();
+
+    // Rounded up, so that any share of some nodes is at least one of them
+    let count = (candidates.len() as f64 * percentage.0 as f64 / 100.0).ceil() as usize;
+
+    candidates.sort_by(|a, b| {
+        b.0.partial_cmp(&a.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.1.cmp(b.1))
+    });
+
+    let mut selected: Vec<String> = candidates
+        .into_iter()
+        .take(count)
+        .map(|(_, name)| name.clone())
+        .collect();
+    selected.sort();
+
+    selected
+}
+
+#[test]
+fn test_select_canaries() {
+    let nodes: HashMap<String, Node> = (0..40)
+        .map(|i| {
+            let region = match i % 4 {
+                0 => "ap",
+                1 => "eu",
+                _ => "us",
+            };
+
+            (
+                format!("web-{}", i),
+                serde_json::from_value(serde_json::json!({
+                    "hostname": format!("10.0.0.{}", i),
+                    "tags": [format!("region={}", region)],
+                    "profiles": {},
+                }))
+                .unwrap(),
+            )
+        })
+        .collect();
+
+    let canaries = select_canaries(&nodes, Percentage(10), None, "abc123");
+    assert_eq!(canaries.len(), 4);
+    assert_eq!(
+        canaries,
+        select_canaries(&nodes, Percentage(10), None, "abc123")
+    );
+    assert_ne!(
+        canaries,
+        select_canaries(&nodes, Percentage(10), None, "def456")
+    );
+    assert_eq!(
+        select_canaries(&nodes, Percentage(1), None, "abc123").len(),
+        1
+    );
+    assert_eq!(
+        select_canaries(&nodes, Percentage(100), None, "abc123").len(),
+        40
+    );
+
+    // Only the 30 nodes in Europe and the US have a weight, and nodes in Asia are never picked
+    let weights: Weights = "region=eu:2,us:1".parse().unwrap();
+    let canaries = select_canaries(&nodes, Percentage(10), Some(&weights), "abc123");
+    assert_eq!(canaries.len(), 3);
+    assert!(canaries
+        .iter()
+        .all(|x| nodes[x].node_settings.tags != vec!["region=ap"]));
+}
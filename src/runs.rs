@@ -0,0 +1,658 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::events::{Phase, Status};
+use crate::state::StateError;
+
+/// How many run manifests are kept, older ones are removed
+pub const RUNS_KEPT: usize = 50;
+
+/// How much slower a phase has to get to count as a regression, as a fraction of how long it took before
+const REGRESSION_FACTOR: f64 = 1.25;
+/// And in milliseconds, so short phases jittering by a second aren't reported
+const REGRESSION_MIN_MS: u64 = 5_000;
+
+/// How a profile ended up in a run
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunOutcome {
+    Activated,
+    /// Activation was left to the node, for when its maintenance window opens
+    Deferred,
+    /// Activated, and rolled back again because of a failure elsewhere
+    Revoked,
+    Failed,
+    /// Never activated, because the run stopped before getting to it
+    Skipped,
+    /// Not activated, because its closure was active on the node already
+    AlreadyActive,
+}
+
+impl std::fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RunOutcome::Activated => "activated",
+            RunOutcome::Deferred => "deferred",
+            RunOutcome::Revoked => "revoked",
+            RunOutcome::Failed => "failed",
+            RunOutcome::Skipped => "skipped",
+            RunOutcome::AlreadyActive => "already active",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RunProfile {
+    pub node: String,
+    pub profile: String,
+    pub closure: String,
+    pub outcome: RunOutcome,
+    /// How long building and copying the profile took, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub push_ms: Option<u64>,
+    /// How long activating or deferring the profile took, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activate_ms: Option<u64>,
+}
+
+/// What a deployment run did to each of the profiles it deployed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RunManifest {
+    /// Seconds since the epoch
+    pub started_at: u64,
+    /// Seconds since the epoch
+    pub finished_at: u64,
+    /// In the order they were deployed in
+    pub profiles: Vec<RunProfile>,
+}
+
+/// Puts a run manifest together from the phase changes of a deployment
+#[derive(Debug)]
+pub struct RunRecorder {
+    manifest: RunManifest,
+    /// When phases that haven't finished yet started, in milliseconds since the epoch
+    started: HashMap<(String, String, Phase), u64>,
+}
+
+impl RunRecorder {
+    pub fn new(started_at: u64) -> Self {
+        RunRecorder {
+            manifest: RunManifest {
+                started_at,
+                finished_at: started_at,
+                profiles: Vec::new(),
+            },
+            started: HashMap::new(),
+        }
+    }
+
+    /// Adds a profile that's going to be deployed, it counts as skipped until it's activated
+    pub fn plan(&mut self, node: &str, profile: &str, closure: &str) {
+        if self.find(node, profile).is_none() {
+            self.manifest.profiles.push(RunProfile {
+                node: node.to_string(),
+                profile: profile.to_string(),
+                closure: closure.to_string(),
+                outcome: RunOutcome::Skipped,
+                push_ms: None,
+                activate_ms: None,
+            });
+        }
+    }
+
+    fn find(&mut self, node: &str, profile: &str) -> Option<&mut RunProfile> {
+        self.manifest
+            .profiles
+            .iter_mut()
+            .find(|x| x.node == node && x.profile == profile)
+    }
+
+    /// Records a phase change of a planned profile, `now` being in milliseconds since the epoch
+    pub fn record(&mut self, node: &str, profile: &str, phase: Phase, status: Status, now: u64) {
+        let key = (node.to_string(), profile.to_string(), phase);
+
+        if status == Status::Started {
+            self.started.insert(key, now);
+            return;
+        }
+
+        let took = self.started.remove(&key).map(|x| now.saturating_sub(x));
+
+        let run_profile = match self.find(node, profile) {
+            Some(x) => x,
+            None => return,
+        };
+
+        match (phase, status) {
+            (Phase::Push, Status::Failed) => run_profile.outcome = RunOutcome::Failed,
+            (Phase::Activate, Status::Succeeded) => run_profile.outcome = RunOutcome::Activated,
+            (Phase::Defer, Status::Succeeded) => run_profile.outcome = RunOutcome::Deferred,
+            (Phase::Activate, Status::AlreadyActive) => {
+                run_profile.outcome = RunOutcome::AlreadyActive
+            }
+            (Phase::Activate, Status::Failed) | (Phase::Defer, Status::Failed) => {
+                run_profile.outcome = RunOutcome::Failed
+            }
+            (Phase::Revoke, Status::Succeeded) => run_profile.outcome = RunOutcome::Revoked,
+            _ => (),
+        }
+
+        match phase {
+            Phase::Push => run_profile.push_ms = took,
+            Phase::Activate | Phase::Defer => run_profile.activate_ms = took,
+            _ => (),
+        }
+    }
+
+    pub fn finish(&self, finished_at: u64) -> RunManifest {
+        RunManifest {
+            finished_at,
+            ..self.manifest.clone()
+        }
+    }
+}
+
+fn runs_dir(dir: &Path) -> PathBuf {
+    dir.join("runs")
+}
+
+/// The ids of the recorded runs, oldest first
+pub fn known_runs(dir: &Path) -> Result<Vec<String>, StateError> {
+    let entries = match std::fs::read_dir(runs_dir(dir)) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut runs: Vec<(u64, String)> = Vec::new();
+
+    for entry in entries {
+        let file_name = entry?.file_name().to_string_lossy().to_string();
+
+        if let Some(id) = file_name.strip_suffix(".json") {
+            // Ids start with when the run started, which is what they're sorted by
+            let started_at = id.split('-').next().and_then(|x| x.parse().ok());
+
+            if let Some(started_at) = started_at {
+                runs.push((started_at, id.to_string()));
+            }
+        }
+    }
+
+    runs.sort();
+
+    Ok(runs.into_iter().map(|(_, id)| id).collect())
+}
+
+/// Loads a run by its id, or from the manifest file at `run` if it isn't one
+pub fn load_run(dir: &Path, run: &str) -> Result<RunManifest, StateError> {
+    let recorded = runs_dir(dir).join(format!("{}.json", run));
+
+    let path = match recorded.exists() {
+        true => recorded,
+        false => PathBuf::from(run),
+    };
+
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+fn run_file(dir: &Path, id: &str, extension: &str) -> PathBuf {
+    runs_dir(dir).join(format!("{}.{}", id, extension))
+}
+
+/// Picks the id a run starting at `started_at` is recorded as, and marks it as in progress so it can be paused
+pub fn start_run(dir: &Path, started_at: u64) -> Result<String, StateError> {
+    std::fs::create_dir_all(runs_dir(dir))?;
+
+    // Runs starting within the same second get a suffix
+    let mut id = started_at.to_string();
+    let mut n = 1;
+    while run_file(dir, &id, "json").exists() || run_file(dir, &id, "running").exists() {
+        n += 1;
+        id = format!("{}-{}", started_at, n);
+    }
+
+    std::fs::write(
+        run_file(dir, &id, "running"),
+        std::process::id().to_string(),
+    )?;
+
+    Ok(id)
+}
+
+/// Records a run started with `start_run`, if it deployed anything, forgetting the oldest ones beyond `RUNS_KEPT`
+pub fn finish_run(dir: &Path, id: &str, manifest: Option<&RunManifest>) -> Result<(), StateError> {
+    for extension in &["running", "pause"] {
+        match std::fs::remove_file(run_file(dir, id, extension)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+
+    let manifest = match manifest {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let path = run_file(dir, id, "json");
+    let temp_path = path.with_extension("json.tmp");
+
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(manifest)?)?;
+    std::fs::rename(&temp_path, &path)?;
+
+    let known = known_runs(dir)?;
+    if known.len() > RUNS_KEPT {
+        for old in &known[..known.len() - RUNS_KEPT] {
+            std::fs::remove_file(run_file(dir, old, "json"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Records a run, forgetting the oldest ones beyond `RUNS_KEPT`, and returns its id
+pub fn save_run(dir: &Path, manifest: &RunManifest) -> Result<String, StateError> {
+    let id = start_run(dir, manifest.started_at)?;
+    finish_run(dir, &id, Some(manifest))?;
+
+    Ok(id)
+}
+
+/// Asks the run in progress with id `id` to stop after the profiles it's activating now, false if there's no such run
+pub fn pause_run(dir: &Path, id: &str) -> Result<bool, StateError> {
+    if !run_file(dir, id, "running").exists() {
+        return Ok(false);
+    }
+
+    std::fs::write(run_file(dir, id, "pause"), "")?;
+
+    Ok(true)
+}
+
+/// Whether `pause_run` was called for the run with id `id`
+pub fn pause_requested(dir: &Path, id: &str) -> bool {
+    run_file(dir, id, "pause").exists()
+}
+
+/// Where a paused run stopped, for resuming it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PausedRun {
+    /// The flake fragments of the profiles left to activate, e.g. `.#"web-2"."system"`, in the order of the run
+    pub targets: Vec<String>,
+}
+
+pub fn save_paused(dir: &Path, id: &str, paused: &PausedRun) -> Result<(), StateError> {
+    std::fs::create_dir_all(runs_dir(dir))?;
+    std::fs::write(
+        run_file(dir, id, "paused"),
+        serde_json::to_vec_pretty(paused)?,
+    )?;
+
+    Ok(())
+}
+
+/// Where the run with id `id` was paused, if it was and hasn't been resumed yet
+pub fn load_paused(dir: &Path, id: &str) -> Result<Option<PausedRun>, StateError> {
+    match std::fs::read(run_file(dir, id, "paused")) {
+        Ok(x) => Ok(Some(serde_json::from_slice(&x)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Forgets where the run with id `id` was paused, once it was resumed
+pub fn forget_paused(dir: &Path, id: &str) -> Result<(), StateError> {
+    match std::fs::remove_file(run_file(dir, id, "paused")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[test]
+fn test_pause_run() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-pause-{}", std::process::id()));
+
+    let id = start_run(&dir, 1614556800).unwrap();
+    assert_eq!(id, "1614556800");
+    // A run in progress isn't recorded yet, but its id is taken
+    assert_eq!(known_runs(&dir).unwrap(), Vec::<String>::new());
+    assert_eq!(start_run(&dir, 1614556800).unwrap(), "1614556800-2");
+
+    assert!(!pause_requested(&dir, &id));
+    assert!(pause_run(&dir, &id).unwrap());
+    assert!(pause_requested(&dir, &id));
+    assert!(!pause_run(&dir, "1614556799").unwrap());
+
+    let paused = PausedRun {
+        targets: vec![r#".#"web-2"."system""#.to_string()],
+    };
+    save_paused(&dir, &id, &paused).unwrap();
+
+    let manifest = RunManifest {
+        started_at: 1614556800,
+        finished_at: 1614556900,
+        profiles: Vec::new(),
+    };
+    finish_run(&dir, &id, Some(&manifest)).unwrap();
+
+    // Once the run is over it can't be paused anymore, but it can be resumed
+    assert!(!pause_requested(&dir, &id));
+    assert!(!pause_run(&dir, &id).unwrap());
+    assert_eq!(known_runs(&dir).unwrap(), vec![id.clone()]);
+    assert_eq!(load_paused(&dir, &id).unwrap(), Some(paused));
+
+    forget_paused(&dir, &id).unwrap();
+    assert_eq!(load_paused(&dir, &id).unwrap(), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A difference between two runs for one profile
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunChange {
+    /// Deployed by the second run only
+    Added {
+        closure: String,
+    },
+    /// Deployed by the first run only
+    Removed {
+        closure: String,
+    },
+    ClosureChanged {
+        from: String,
+        to: String,
+    },
+    /// Not activated by the second run, with how it ended up in the first
+    Skipped {
+        before: RunOutcome,
+    },
+    OutcomeChanged {
+        from: RunOutcome,
+        to: RunOutcome,
+    },
+    /// A phase took noticeably longer, in milliseconds
+    Slower {
+        phase: &'static str,
+        from_ms: u64,
+        to_ms: u64,
+    },
+}
+
+impl std::fmt::Display for RunChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunChange::Added { closure } => write!(f, "only in the second run, {}", closure),
+            RunChange::Removed { closure } => write!(f, "only in the first run, {}", closure),
+            RunChange::ClosureChanged { from, to } => {
+                write!(f, "closure changed from {} to {}", from, to)
+            }
+            RunChange::Skipped { before } => write!(f, "skipped, {} before", before),
+            RunChange::OutcomeChanged { from, to } => write!(f, "{}, {} before", to, from),
+            RunChange::Slower {
+                phase,
+                from_ms,
+                to_ms,
+            } => write!(
+                f,
+                "{} took {:.1}s, {:.1}s before",
+                phase,
+                *to_ms as f64 / 1000.0,
+                *from_ms as f64 / 1000.0
+            ),
+        }
+    }
+}
+
+fn slower(phase: &'static str, from: Option<u64>, to: Option<u64>) -> Option<RunChange> {
+    match (from, to) {
+        (Some(from_ms), Some(to_ms))
+            if to_ms as f64 > from_ms as f64 * REGRESSION_FACTOR
+                && to_ms - from_ms >= REGRESSION_MIN_MS =>
+        {
+            Some(RunChange::Slower {
+                phase,
+                from_ms,
+                to_ms,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// What changed from run `a` to run `b`, by node and profile in the order `b` deployed them in, followed by the
+/// profiles only `a` deployed
+pub fn diff_runs(a: &RunManifest, b: &RunManifest) -> Vec<(String, String, RunChange)> {
+    let mut changes = Vec::new();
+
+    for new in &b.profiles {
+        let mut push = |change| changes.push((new.node.clone(), new.profile.clone(), change));
+
+        let old = match a
+            .profiles
+            .iter()
+            .find(|x| x.node == new.node && x.profile == new.profile)
+        {
+            Some(x) => x,
+            None => {
+                push(RunChange::Added {
+                    closure: new.closure.clone(),
+                });
+                continue;
+            }
+        };
+
+        if old.closure != new.closure {
+            push(RunChange::ClosureChanged {
+                from: old.closure.clone(),
+                to: new.closure.clone(),
+            });
+        }
+
+        match (old.outcome, new.outcome) {
+            (from, to) if from == to => (),
+            (from, RunOutcome::Skipped) => push(RunChange::Skipped { before: from }),
+            (from, to) => push(RunChange::OutcomeChanged { from, to }),
+        }
+
+        if let Some(change) = slower("pushing", old.push_ms, new.push_ms) {
+            push(change);
+        }
+        if let Some(change) = slower("activating", old.activate_ms, new.activate_ms) {
+            push(change);
+        }
+    }
+
+    for old in &a.profiles {
+        if !b
+            .profiles
+            .iter()
+            .any(|x| x.node == old.node && x.profile == old.profile)
+        {
+            changes.push((
+                old.node.clone(),
+                old.profile.clone(),
+                RunChange::Removed {
+                    closure: old.closure.clone(),
+                },
+            ));
+        }
+    }
+
+    changes
+}
+
+#[test]
+fn test_run_recorder() {
+    let mut recorder = RunRecorder::new(1_614_556_800);
+    recorder.plan("web-1", "system", "/nix/store/blah-system");
+    recorder.plan("web-1", "app", "/nix/store/blah-app");
+    recorder.plan("db-1", "system", "/nix/store/blah-db");
+
+    recorder.record("web-1", "system", Phase::Push, Status::Started, 1_000);
+    recorder.record("web-1", "system", Phase::Push, Status::Succeeded, 4_000);
+    recorder.record("web-1", "app", Phase::Push, Status::Started, 1_000);
+    recorder.record("web-1", "app", Phase::Push, Status::Succeeded, 2_500);
+    recorder.record("web-1", "system", Phase::Activate, Status::Started, 5_000);
+    recorder.record("web-1", "system", Phase::Activate, Status::Succeeded, 9_000);
+    recorder.record("web-1", "app", Phase::Activate, Status::Started, 9_000);
+    recorder.record("web-1", "app", Phase::Activate, Status::Failed, 9_500);
+    recorder.record("web-1", "system", Phase::Revoke, Status::Started, 9_500);
+    recorder.record("web-1", "system", Phase::Revoke, Status::Succeeded, 10_000);
+
+    let manifest = recorder.finish(1_614_556_810);
+
+    assert_eq!(manifest.finished_at, 1_614_556_810);
+    assert_eq!(
+        manifest
+            .profiles
+            .iter()
+            .map(|x| (x.profile.as_str(), x.outcome, x.push_ms, x.activate_ms))
+            .collect::<Vec<_>>(),
+        vec![
+            ("system", RunOutcome::Revoked, Some(3_000), Some(4_000)),
+            ("app", RunOutcome::Failed, Some(1_500), Some(500)),
+            ("system", RunOutcome::Skipped, None, None),
+        ]
+    );
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-runs-{}", std::process::id()));
+
+    let first = save_run(&dir, &manifest).unwrap();
+    let second = save_run(&dir, &manifest).unwrap();
+    assert_eq!(first, "1614556800");
+    assert_eq!(second, "1614556800-2");
+    assert_eq!(known_runs(&dir).unwrap(), vec![first.clone(), second]);
+    assert_eq!(load_run(&dir, &first).unwrap(), manifest);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_diff_runs() {
+    let profile = |node: &str, closure: &str, outcome, push_ms, activate_ms| RunProfile {
+        node: node.to_string(),
+        profile: "system".to_string(),
+        closure: closure.to_string(),
+        outcome,
+        push_ms,
+        activate_ms,
+    };
+
+    let a = RunManifest {
+        started_at: 0,
+        finished_at: 60,
+        profiles: vec![
+            profile(
+                "web-1",
+                "/nix/store/a",
+                RunOutcome::Activated,
+                Some(10_000),
+                Some(20_000),
+            ),
+            profile(
+                "web-2",
+                "/nix/store/a",
+                RunOutcome::Activated,
+                Some(10_000),
+                Some(20_000),
+            ),
+            profile(
+                "db-1",
+                "/nix/store/d",
+                RunOutcome::Activated,
+                None,
+                Some(1_000),
+            ),
+        ],
+    };
+    let b = RunManifest {
+        started_at: 100,
+        finished_at: 200,
+        profiles: vec![
+            profile(
+                "web-1",
+                "/nix/store/b",
+                RunOutcome::Activated,
+                Some(12_000),
+                Some(60_000),
+            ),
+            profile(
+                "web-2",
+                "/nix/store/b",
+                RunOutcome::Skipped,
+                Some(10_000),
+                None,
+            ),
+            profile("cache-1", "/nix/store/c", RunOutcome::Failed, None, None),
+        ],
+    };
+
+    let changes: Vec<String> = diff_runs(&a, &b)
+        .into_iter()
+        .map(|(node, profile, change)| format!("{}.{}: {}", node, profile, change))
+        .collect();
+
+    assert_eq!(
+        changes,
+        vec![
+            "web-1.system: closure changed from /nix/store/a to /nix/store/b",
+            "web-1.system: activating took 60.0s, 20.0s before",
+            "web-2.system: closure changed from /nix/store/a to /nix/store/b",
+            "web-2.system: skipped, activated before",
+            "cache-1.system: only in the second run, /nix/store/c",
+            "db-1.system: only in the first run, /nix/store/d",
+        ]
+    );
+}
+
+/// The profiles a run left activated, in the order to roll them back in, the last one activated first
+pub fn rollback_order(manifest: &RunManifest) -> Vec<&RunProfile> {
+    manifest
+        .profiles
+        .iter()
+        .rev()
+        .filter(|x| x.outcome == RunOutcome::Activated)
+        .collect()
+}
+
+#[test]
+fn test_rollback_order() {
+    let profile = |node: &str, profile: &str, outcome: RunOutcome| RunProfile {
+        node: node.to_string(),
+        profile: profile.to_string(),
+        closure: format!("/nix/store/{}-{}", node, profile),
+        outcome,
+        push_ms: None,
+        activate_ms: None,
+    };
+
+    let manifest = RunManifest {
+        started_at: 1614556800,
+        finished_at: 1614556900,
+        profiles: vec![
+            profile("web-1", "system", RunOutcome::Activated),
+            profile("web-1", "app", RunOutcome::Activated),
+            profile("web-2", "system", RunOutcome::AlreadyActive),
+            profile("web-3", "system", RunOutcome::Deferred),
+            profile("db-1", "system", RunOutcome::Activated),
+            profile("db-2", "system", RunOutcome::Revoked),
+            profile("db-3", "system", RunOutcome::Skipped),
+        ],
+    };
+
+    assert_eq!(
+        rollback_order(&manifest)
+            .iter()
+            .map(|x| format!("{}.{}", x.node, x.profile))
+            .collect::<Vec<_>>(),
+        vec!["db-1.system", "web-1.app", "web-1.system"]
+    );
+}
@@ -0,0 +1,67 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the JSON deploy-rs writes for other tools: event log records, pull descriptors and
+/// `deploy diff-remote --json`
+///
+/// Fields are only ever added within a version. Removing or renaming one, or changing what it means, bumps it.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// JSON Schema of all of those outputs, printed by `deploy --output-schema`
+pub const OUTPUT_SCHEMA: &str = include_str!("../output-schema.json");
+
+/// Outputs from before there were schema versions are the same as the first version
+fn first_version() -> u32 {
+    1
+}
+
+/// An output along with the schema version it's written in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Versioned<T> {
+    #[serde(rename = "schemaVersion", default = "first_version")]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub inner: T,
+}
+
+impl<T> Versioned<T> {
+    pub fn new(inner: T) -> Self {
+        Versioned {
+            schema_version: SCHEMA_VERSION,
+            inner,
+        }
+    }
+}
+
+#[test]
+fn test_versioned() {
+    let descriptor = crate::pull::PullDescriptor {
+        node: "web-1".to_string(),
+        profile: "system".to_string(),
+        closure: "/nix/store/blah-system".to_string(),
+        profile_path: "/nix/var/nix/profiles/system".to_string(),
+        substituters: Vec::new(),
+        signer: None,
+        signature: None,
+    };
+
+    let json = serde_json::to_value(Versioned::new(descriptor.clone())).unwrap();
+    assert_eq!(json["schemaVersion"], SCHEMA_VERSION);
+    assert_eq!(json["node"], "web-1");
+
+    // Descriptors written before schema versions existed still parse
+    let unversioned: Versioned<crate::pull::PullDescriptor> = serde_json::from_str(
+        r#"{"node":"web-1","profile":"system","closure":"/nix/store/blah-system","profile_path":"/nix/var/nix/profiles/system"}"#,
+    )
+    .unwrap();
+    assert_eq!(unversioned, Versioned::new(descriptor));
+
+    let schema: serde_json::Value = serde_json::from_str(OUTPUT_SCHEMA).unwrap();
+    assert_eq!(
+        schema["definitions"]["schema_version"]["const"],
+        SCHEMA_VERSION
+    );
+}
@@ -0,0 +1,218 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::debug;
+use thiserror::Error;
+
+use crate::data::ActivationEnvValue;
+use crate::report::CommandFailure;
+use crate::transport::{InputMode, Invocation, OutputMode, Transport};
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("Failed to run the command fetching the value: {0}")]
+    Run(std::io::Error),
+    #[error("Fetching the value failed with {0}")]
+    Exit(CommandFailure),
+    #[error("Failed to read `{0}`: {1}")]
+    Read(String, std::io::Error),
+    #[error("Reading from Vault needs {0}")]
+    VaultEnv(&'static str),
+    #[error("Failed to parse the secret as JSON: {0}")]
+    Parse(serde_json::Error),
+    #[error("The secret has no `{0}`")]
+    MissingKey(String),
+}
+
+/// Takes the value at a dot-separated `key` out of a JSON document, strings as they are and anything else as JSON
+fn extract_key(json: &str, key: &str) -> Result<String, SecretError> {
+    let document: serde_json::Value = serde_json::from_str(json).map_err(SecretError::Parse)?;
+
+    let value = key
+        .split('.')
+        .try_fold(&document, |value, part| value.get(part))
+        .ok_or_else(|| SecretError::MissingKey(key.to_string()))?;
+
+    Ok(match value {
+        serde_json::Value::String(s) => s.clone(),
+        value => value.to_string(),
+    })
+}
+
+#[test]
+fn test_extract_key() {
+    let json = r#"{ "db": { "password": "hunter2", "port": 5432 } }"#;
+
+    assert_eq!(extract_key(json, "db.password").unwrap(), "hunter2");
+    assert_eq!(extract_key(json, "db.port").unwrap(), "5432");
+    assert!(matches!(
+        extract_key(json, "db.user"),
+        Err(SecretError::MissingKey(key)) if key == "db.user"
+    ));
+}
+
+async fn run(transport: &dyn Transport, command: &mut Invocation) -> Result<String, SecretError> {
+    command.stdout(OutputMode::Capture);
+
+    let output = transport.run(command).await.map_err(SecretError::Run)?;
+
+    match output.code {
+        Some(0) => (),
+        _ => return Err(SecretError::Exit(CommandFailure::new(command, &output))),
+    };
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches('\n')
+        .to_string())
+}
+
+/// The token to read from Vault with, `VAULT_TOKEN` or what `vault login` left in `~/.vault-token`
+fn vault_token() -> Result<String, SecretError> {
+    if let Some(token) = std::env::var("VAULT_TOKEN").ok().filter(|x| !x.is_empty()) {
+        return Ok(token);
+    }
+
+    let path = std::env::var_os("HOME")
+        .map(|home| std::path::PathBuf::from(home).join(".vault-token"))
+        .ok_or(SecretError::VaultEnv("VAULT_TOKEN or ~/.vault-token"))?;
+
+    match std::fs::read_to_string(&path) {
+        Ok(token) => Ok(token.trim().to_string()),
+        Err(_) => Err(SecretError::VaultEnv("VAULT_TOKEN or ~/.vault-token")),
+    }
+}
+
+/// Works out a value of `activationEnv` on the deployer, fetching it from wherever it's kept
+pub async fn resolve(
+    transport: &dyn Transport,
+    value: &ActivationEnvValue,
+) -> Result<String, SecretError> {
+    match value {
+        ActivationEnvValue::Value(value) => Ok(value.clone()),
+        ActivationEnvValue::Command { command } => {
+            debug!("Running `{}` for an activation variable", command);
+
+            run(transport, Invocation::new("sh").arg("-c").arg(command)).await
+        }
+        ActivationEnvValue::File { file } => std::fs::read_to_string(file)
+            .map(|x| x.trim_end_matches('\n').to_string())
+            .map_err(|e| SecretError::Read(file.clone(), e)),
+        ActivationEnvValue::Sops { sops, key } => {
+            let mut decrypt_command = Invocation::new("sops");
+            decrypt_command.arg("--decrypt");
+
+            // Any format sops understands can be turned into JSON to take a key out of
+            if key.is_some() {
+                decrypt_command.arg("--output-type").arg("json");
+            }
+
+            let decrypted = run(transport, decrypt_command.arg(sops)).await?;
+
+            match key {
+                Some(key) => extract_key(&decrypted, key),
+                None => Ok(decrypted),
+            }
+        }
+        ActivationEnvValue::Vault { vault, field } => {
+            let address = std::env::var("VAULT_ADDR")
+                .ok()
+                .filter(|x| !x.is_empty())
+                .ok_or(SecretError::VaultEnv("VAULT_ADDR"))?;
+
+            // The token goes in through standard input, so it's never on a command line
+            let mut read_command = Invocation::new("curl");
+            read_command
+                .arg("--silent")
+                .arg("--show-error")
+                .arg("--fail")
+                .arg("--header")
+                .arg("@-")
+                .arg(format!(
+                    "{}/v1/{}",
+                    address.trim_end_matches('/'),
+                    vault.trim_start_matches('/')
+                ))
+                .stdin(InputMode::Secret(
+                    format!("X-Vault-Token: {}\n", vault_token()?).into_bytes(),
+                ));
+
+            let response = run(transport, &mut read_command).await?;
+
+            // Version 2 of the key/value engine nests the secret's fields one level deeper
+            extract_key(&response, &format!("data.data.{}", field))
+                .or_else(|_| extract_key(&response, &format!("data.{}", field)))
+        }
+        ActivationEnvValue::AwsSecret {
+            aws_secret,
+            key,
+            region,
+        } => {
+            let mut get_command = Invocation::new("aws");
+            get_command
+                .arg("secretsmanager")
+                .arg("get-secret-value")
+                .arg("--secret-id")
+                .arg(aws_secret)
+                .arg("--query")
+                .arg("SecretString")
+                .arg("--output")
+                .arg("text");
+
+            if let Some(region) = region {
+                get_command.arg("--region").arg(region);
+            }
+
+            let secret = run(transport, &mut get_command).await?;
+
+            match key {
+                Some(key) => extract_key(&secret, key),
+                None => Ok(secret),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_resolve() {
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond("sops", Some(0), r#"{"api":{"key":"s3cr3t"}}"#);
+    transport.respond(
+        "curl",
+        Some(0),
+        r#"{"data":{"data":{"password":"hunter2"},"metadata":{}}}"#,
+    );
+    transport.respond("aws secretsmanager", Some(0), "{\"token\":\"t0k3n\"}\n");
+
+    let file = std::env::temp_dir().join(format!("deploy-rs-test-secret-{}", std::process::id()));
+    std::fs::write(&file, "from a file\n").unwrap();
+
+    std::env::set_var("VAULT_ADDR", "https://vault.example.com/");
+    std::env::set_var("VAULT_TOKEN", "s.token");
+
+    let values: Vec<ActivationEnvValue> = serde_json::from_value(serde_json::json!([
+        { "sops": "secrets.yaml", "key": "api.key" },
+        { "vault": "secret/data/db", "field": "password" },
+        { "awsSecret": "prod/app", "key": "token", "region": "eu-west-1" },
+        { "file": file.to_string_lossy() },
+    ]))
+    .unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let resolved: Vec<String> = values
+        .iter()
+        .map(|value| runtime.block_on(resolve(&transport, value)).unwrap())
+        .collect();
+
+    std::fs::remove_file(&file).unwrap();
+
+    assert_eq!(resolved, vec!["s3cr3t", "hunter2", "t0k3n", "from a file"]);
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "sops --decrypt --output-type json secrets.yaml",
+            "curl --silent --show-error --fail --header @- https://vault.example.com/v1/secret/data/db < (secret)",
+            "aws secretsmanager get-secret-value --secret-id prod/app --query SecretString --output text --region eu-west-1",
+        ]
+    );
+}
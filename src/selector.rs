@@ -0,0 +1,388 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use thiserror::Error;
+
+use crate::data::Data;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SelectorError {
+    #[error("`{0}` has a brace that isn't closed")]
+    UnbalancedBraces(String),
+    #[error("No group named `{0}` is defined in `groups`")]
+    UnknownGroup(String),
+    #[error("No node matches `{0}`")]
+    NoMatch(String),
+}
+
+/// Nodes picked by a pattern rather than by name, e.g. `web-{01..10}`, `db-*` or the group `@frontend`, and optionally
+/// one of their profiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    pub nodes: String,
+    pub profile: Option<String>,
+}
+
+impl Selector {
+    /// Takes the selector out of a target like `.#web-*.system`, leaving the flake, or nothing if the target names a
+    /// node the usual way
+    pub fn split(target: &str) -> Result<Option<(String, Selector)>, SelectorError> {
+        let (repo, fragment) = match target.find('#') {
+            Some(i) => (&target[..i], &target[i + 1..]),
+            None => return Ok(None),
+        };
+
+        // The dots of a range like `{01..10}` don't end the node part
+        let mut depth = 0;
+        let mut end = fragment.len();
+        for (i, c) in fragment.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth > 0 => depth -= 1,
+                '.' if depth == 0 => {
+                    end = i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        if depth > 0 {
+            return Err(SelectorError::UnbalancedBraces(target.to_string()));
+        }
+
+        let nodes = &fragment[..end];
+        if !nodes.starts_with('@') && !nodes.contains(|c| "*?[{".contains(c)) {
+            return Ok(None);
+        }
+
+        let profile = match end < fragment.len() {
+            true => Some(fragment[end + 1..].trim_matches('"').to_string()),
+            false => None,
+        };
+
+        Ok(Some((
+            repo.to_string(),
+            Selector {
+                nodes: nodes.to_string(),
+                profile,
+            },
+        )))
+    }
+
+    /// Leaves only the selected nodes, and profiles if one is selected, in `data`
+    pub fn select(&self, data: &mut Data) -> Result<(), SelectorError> {
+        let patterns = match self.nodes.strip_prefix('@') {
+            Some(group) => data
+                .groups
+                .get(group)
+                .cloned()
+                .ok_or_else(|| SelectorError::UnknownGroup(group.to_string()))?,
+            None => vec![self.nodes.clone()],
+        };
+
+        let mut globs = Vec::new();
+        for pattern in &patterns {
+            globs.extend(expand_braces(pattern)?);
+        }
+
+        data.nodes
+            .retain(|node_name, _| globs.iter().any(|glob| glob_match(glob, node_name)));
+
+        if let Some(ref profile_name) = self.profile {
+            data.nodes
+                .retain(|_, node| node.node_settings.profiles.contains_key(profile_name));
+
+            for node in data.nodes.values_mut() {
+                node.node_settings
+                    .profiles
+                    .retain(|name, _| name == profile_name);
+                node.node_settings
+                    .profiles_order
+                    .retain(|name| name == profile_name);
+            }
+        }
+
+        if data.nodes.is_empty() {
+            return Err(SelectorError::NoMatch(self.nodes.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Expands the braces of a pattern like a shell does, `{a,b}` into each of its parts and `{01..10}` into the
+/// numbers, keeping their leading zeros
+pub fn expand_braces(pattern: &str) -> Result<Vec<String>, SelectorError> {
+    let start = match pattern.find('{') {
+        Some(x) => x,
+        None => return Ok(vec![pattern.to_string()]),
+    };
+
+    let mut depth = 0;
+    let mut end = None;
+    let mut parts = Vec::new();
+    let mut part_start = start + 1;
+
+    for (i, c) in pattern[start..].char_indices().map(|(i, c)| (start + i, c)) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    parts.push(&pattern[part_start..i]);
+                    end = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => {
+                parts.push(&pattern[part_start..i]);
+                part_start = i + 1;
+            }
+            _ => (),
+        }
+    }
+
+    let end = end.ok_or_else(|| SelectorError::UnbalancedBraces(pattern.to_string()))?;
+    let (prefix, suffix) = (&pattern[..start], &pattern[end + 1..]);
+
+    // Numbers are expanded already, while the parts between commas can have braces of their own
+    let (alternatives, nested): (Vec<String>, bool) = match (parts.len(), parts[0].find("..")) {
+        (1, Some(i)) => match (
+            parts[0][..i].parse::<i64>(),
+            parts[0][i + 2..].parse::<i64>(),
+        ) {
+            (Ok(from), Ok(to)) => {
+                let (from_s, to_s) = (&parts[0][..i], &parts[0][i + 2..]);
+                let width = match from_s.starts_with('0') || to_s.starts_with('0') {
+                    true => from_s.len().max(to_s.len()),
+                    false => 0,
+                };
+
+                let numbers: Vec<i64> = match from <= to {
+                    true => (from..=to).collect(),
+                    false => (to..=from).rev().collect(),
+                };
+
+                let numbers = numbers
+                    .into_iter()
+                    .map(|n| format!("{:0width$}", n, width = width))
+                    .collect();
+
+                (numbers, false)
+            }
+            _ => (vec![format!("{{{}}}", parts[0])], false),
+        },
+        // Like in a shell, braces without a comma or range in them are just braces
+        (1, None) => (vec![format!("{{{}}}", parts[0])], false),
+        _ => (parts.iter().map(|x| x.to_string()).collect(), true),
+    };
+
+    let rest = expand_braces(suffix)?;
+    let mut expanded = Vec::new();
+
+    for alternative in alternatives {
+        let heads = match nested {
+            true => expand_braces(&alternative)?,
+            false => vec![alternative],
+        };
+
+        for head in heads {
+            for tail in &rest {
+                expanded.push(format!("{}{}{}", prefix, head, tail));
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Whether `name` matches a glob with `*`, `?` and character classes like `[0-9]`
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    fn class_match(class: &[char], c: char) -> bool {
+        let (negated, class) = match class.first() {
+            Some('!') | Some('^') => (true, &class[1..]),
+            _ => (false, class),
+        };
+
+        let mut i = 0;
+        let mut found = false;
+        while i < class.len() {
+            if i + 2 < class.len() && class[i + 1] == '-' {
+                found |= class[i] <= c && c <= class[i + 2];
+                i += 3;
+            } else {
+                found |= class[i] == c;
+                i += 1;
+            }
+        }
+
+        found != negated
+    }
+
+    fn matches(glob: &[char], name: &[char]) -> bool {
+        match glob.first() {
+            None => name.is_empty(),
+            Some('*') => (0..=name.len()).any(|i| matches(&glob[1..], &name[i..])),
+            Some('?') => !name.is_empty() && matches(&glob[1..], &name[1..]),
+            Some('[') => match glob.iter().position(|x| *x == ']') {
+                Some(close) if close > 1 => {
+                    !name.is_empty()
+                        && class_match(&glob[1..close], name[0])
+                        && matches(&glob[close + 1..], &name[1..])
+                }
+                _ => name.first() == Some(&'[') && matches(&glob[1..], &name[1..]),
+            },
+            Some(c) => name.first() == Some(c) && matches(&glob[1..], &name[1..]),
+        }
+    }
+
+    matches(&glob, &name)
+}
+
+#[test]
+fn test_expand_braces() {
+    assert_eq!(expand_braces("web-1").unwrap(), vec!["web-1"]);
+    assert_eq!(
+        expand_braces("web-{01..03}").unwrap(),
+        vec!["web-01", "web-02", "web-03"]
+    );
+    assert_eq!(
+        expand_braces("db-{3..1}").unwrap(),
+        vec!["db-3", "db-2", "db-1"]
+    );
+    assert_eq!(
+        expand_braces("{web,db}-{1,2}").unwrap(),
+        vec!["web-1", "web-2", "db-1", "db-2"]
+    );
+    assert_eq!(
+        expand_braces("{lb,web-{8..10}}").unwrap(),
+        vec!["lb", "web-8", "web-9", "web-10"]
+    );
+    assert_eq!(expand_braces("odd{}").unwrap(), vec!["odd{}"]);
+    assert_eq!(
+        expand_braces("web-{1,2"),
+        Err(SelectorError::UnbalancedBraces("web-{1,2".to_string()))
+    );
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("db-*", "db-1"));
+    assert!(glob_match("db-*", "db-"));
+    assert!(!glob_match("db-*", "web-db-1"));
+    assert!(glob_match("web-?", "web-1"));
+    assert!(!glob_match("web-?", "web-10"));
+    assert!(glob_match("web-[0-4]*", "web-3a"));
+    assert!(!glob_match("web-[!0-4]", "web-3"));
+    assert!(glob_match("*.example.com", "web.example.com"));
+}
+
+#[test]
+fn test_selector() {
+    assert_eq!(Selector::split(".#web-1").unwrap(), None);
+    assert_eq!(Selector::split(".").unwrap(), None);
+    assert_eq!(
+        Selector::split("github:me/infra#web-{01..10}.system").unwrap(),
+        Some((
+            "github:me/infra".to_string(),
+            Selector {
+                nodes: "web-{01..10}".to_string(),
+                profile: Some("system".to_string()),
+            }
+        ))
+    );
+    assert_eq!(
+        Selector::split(".#@frontend").unwrap(),
+        Some((
+            ".".to_string(),
+            Selector {
+                nodes: "@frontend".to_string(),
+                profile: None,
+            }
+        ))
+    );
+
+    let data = || -> Data {
+        serde_json::from_value(serde_json::json!({
+            "groups": { "frontend": ["lb", "web-{01..02}"] },
+            "nodes": {
+                "lb": { "hostname": "10.0.0.1", "profiles": { "system": { "path": "/nix/store/a" } } },
+                "web-01": {
+                    "hostname": "10.0.0.2",
+                    "profiles": { "system": { "path": "/nix/store/b" }, "app": { "path": "/nix/store/c" } }
+                },
+                "web-02": { "hostname": "10.0.0.3", "profiles": { "system": { "path": "/nix/store/b" } } },
+                "db-1": { "hostname": "10.0.0.4", "profiles": { "system": { "path": "/nix/store/d" } } },
+            },
+        }))
+        .unwrap()
+    };
+
+    let selected = |selector: &str| {
+        let (_, selector) = Selector::split(selector).unwrap().unwrap();
+        let mut data = data();
+        selector.select(&mut data).map(|_| {
+            let mut nodes: Vec<String> = data
+                .nodes
+                .iter()
+                .flat_map(|(node_name, node)| {
+                    node.node_settings
+                        .profiles
+                        .keys()
+                        .map(move |profile_name| format!("{}.{}", node_name, profile_name))
+                })
+                .collect();
+            nodes.sort();
+            nodes
+        })
+    };
+
+    assert_eq!(
+        selected(".#@frontend").unwrap(),
+        vec!["lb.system", "web-01.app", "web-01.system", "web-02.system"]
+    );
+    assert_eq!(selected(".#web-*.app").unwrap(), vec!["web-01.app"]);
+    assert_eq!(
+        selected(".#@backend"),
+        Err(SelectorError::UnknownGroup("backend".to_string()))
+    );
+    assert_eq!(
+        selected(".#cache-*"),
+        Err(SelectorError::NoMatch("cache-*".to_string()))
+    );
+}
+
+/// The node names of a list like `--nodes-from` reads, a JSON array of them or one per line
+///
+/// Empty lines and lines starting with `#` are left out.
+pub fn parse_node_list(list: &str) -> Result<Vec<String>, serde_json::Error> {
+    if list.trim_start().starts_with('[') {
+        return serde_json::from_str(list);
+    }
+
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.to_string())
+        .collect())
+}
+
+#[test]
+fn test_parse_node_list() {
+    assert_eq!(
+        parse_node_list("web-1\n\n# the database\n  db-1  \n").unwrap(),
+        vec!["web-1", "db-1"]
+    );
+    assert_eq!(
+        parse_node_list(" [\"web-1\", \"db-1\"]\n").unwrap(),
+        vec!["web-1", "db-1"]
+    );
+    assert!(parse_node_list("[\"web-1\"").is_err());
+    assert_eq!(parse_node_list("").unwrap(), Vec::<String>::new());
+}
@@ -0,0 +1,174 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Snapshot, SnapshotType};
+
+/// The snapshots activate-rs restores when it rolls the profile back by itself, given to it as JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Restore {
+    pub name: String,
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// What the snapshots taken before activating a profile are called, the same on every dataset and subvolume. Only
+/// characters both ZFS and btrfs take in the name are kept
+pub fn snapshot_name(deployment_id: &str, profile_name: &str) -> String {
+    format!("deploy-rs-{}-{}", deployment_id, profile_name)
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '-' | '_' | '.' | ':' => c,
+            _ => '-',
+        })
+        .collect()
+}
+
+/// Where the btrfs snapshots of `snapshot` go, its `snapshotDir` or a `.deploy-rs-snapshots` directory next to the
+/// subvolume, which keeps them out of the subvolume's own snapshots
+fn snapshot_dir(snapshot: &Snapshot) -> String {
+    if let Some(ref dir) = snapshot.snapshot_dir {
+        return dir.clone();
+    }
+
+    let path = snapshot.path.trim_end_matches('/');
+
+    match path.rfind('/') {
+        Some(0) | None => "/.deploy-rs-snapshots".to_string(),
+        Some(i) => format!("{}/.deploy-rs-snapshots", &path[..i]),
+    }
+}
+
+/// The script taking a snapshot called `name` of each of `snapshots`, stopping at the first that fails
+pub fn take_script(snapshots: &[Snapshot], name: &str) -> String {
+    let mut lines = vec!["set -e".to_string()];
+
+    for snapshot in snapshots {
+        match snapshot.snapshot_type {
+            SnapshotType::Zfs => lines.push(format!(
+                "zfs snapshot {}",
+                crate::shell_quote(&format!("{}@{}", snapshot.path, name))
+            )),
+            SnapshotType::Btrfs => {
+                let dir = snapshot_dir(snapshot);
+
+                lines.push(format!("mkdir -p {}", crate::shell_quote(&dir)));
+                lines.push(format!(
+                    "btrfs subvolume snapshot -r {} {}",
+                    crate::shell_quote(&snapshot.path),
+                    crate::shell_quote(&format!("{}/{}", dir, name))
+                ));
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// The script putting each of `snapshots` back to its snapshot called `name`. ZFS datasets are rolled back, dropping
+/// the snapshots taken since. btrfs subvolumes are moved aside, keeping what they held, and replaced by a writable
+/// snapshot of the one taken. Their `stopUnits` are stopped meanwhile, and started again however it went
+pub fn restore_script(snapshots: &[Snapshot], name: &str) -> String {
+    let mut lines = vec!["set -e".to_string()];
+
+    let mut units: Vec<String> = Vec::new();
+    for unit in snapshots.iter().flat_map(|x| &x.stop_units) {
+        let unit = crate::shell_quote(unit);
+
+        if !units.contains(&unit) {
+            units.push(unit);
+        }
+    }
+
+    if !units.is_empty() {
+        lines.push(format!(
+            "trap {} EXIT",
+            crate::shell_quote(&format!("systemctl start {}", units.join(" ")))
+        ));
+        lines.push(format!("systemctl stop {}", units.join(" ")));
+    }
+
+    for snapshot in snapshots {
+        match snapshot.snapshot_type {
+            SnapshotType::Zfs => lines.push(format!(
+                "zfs rollback -r {}",
+                crate::shell_quote(&format!("{}@{}", snapshot.path, name))
+            )),
+            SnapshotType::Btrfs => {
+                let path = snapshot.path.trim_end_matches('/');
+
+                lines.push(format!(
+                    "mv {} {}",
+                    crate::shell_quote(path),
+                    crate::shell_quote(&format!("{}.replaced-{}", path, name))
+                ));
+                lines.push(format!(
+                    "btrfs subvolume snapshot {} {}",
+                    crate::shell_quote(&format!("{}/{}", snapshot_dir(snapshot), name)),
+                    crate::shell_quote(path)
+                ));
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+#[test]
+fn test_snapshot_scripts() {
+    let snapshots: Vec<Snapshot> = serde_json::from_value(serde_json::json!([
+        { "type": "zfs", "path": "rpool/safe/postgres" },
+        { "type": "btrfs", "path": "/srv/data/" },
+        { "type": "btrfs", "path": "/var/lib/gitea", "snapshotDir": "/snapshots" },
+    ]))
+    .unwrap();
+
+    let name = snapshot_name("1700000000", "db system");
+    assert_eq!(name, "deploy-rs-1700000000-db-system");
+
+    assert_eq!(
+        take_script(&snapshots, &name),
+        "set -e
+zfs snapshot 'rpool/safe/postgres@deploy-rs-1700000000-db-system'
+mkdir -p '/srv/.deploy-rs-snapshots'
+btrfs subvolume snapshot -r '/srv/data/' '/srv/.deploy-rs-snapshots/deploy-rs-1700000000-db-system'
+mkdir -p '/snapshots'
+btrfs subvolume snapshot -r '/var/lib/gitea' '/snapshots/deploy-rs-1700000000-db-system'"
+    );
+    assert_eq!(
+        restore_script(&snapshots[..2], &name),
+        "set -e
+zfs rollback -r 'rpool/safe/postgres@deploy-rs-1700000000-db-system'
+mv '/srv/data' '/srv/data.replaced-deploy-rs-1700000000-db-system'
+btrfs subvolume snapshot '/srv/.deploy-rs-snapshots/deploy-rs-1700000000-db-system' '/srv/data'"
+    );
+
+    let snapshots: Vec<Snapshot> = serde_json::from_value(serde_json::json!([
+        { "type": "zfs", "path": "rpool/safe/postgres", "stopUnits": [ "postgresql.service" ] },
+        { "type": "zfs", "path": "rpool/safe/wal", "stopUnits": [ "postgresql.service", "pgbouncer.service" ] },
+    ]))
+    .unwrap();
+
+    assert_eq!(
+        restore_script(&snapshots, &name),
+        "set -e
+trap 'systemctl start '\\''postgresql.service'\\'' '\\''pgbouncer.service'\\''' EXIT
+systemctl stop 'postgresql.service' 'pgbouncer.service'
+zfs rollback -r 'rpool/safe/postgres@deploy-rs-1700000000-db-system'
+zfs rollback -r 'rpool/safe/wal@deploy-rs-1700000000-db-system'"
+    );
+
+    let restore = Restore {
+        name: name.clone(),
+        snapshots: snapshots[..1].to_vec(),
+    };
+    assert_eq!(
+        serde_json::to_string(&restore).unwrap(),
+        r#"{"name":"deploy-rs-1700000000-db-system","snapshots":[{"type":"zfs","path":"rpool/safe/postgres","snapshotDir":null,"stopUnits":["postgresql.service"]}]}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Restore>(&serde_json::to_string(&restore).unwrap()).unwrap(),
+        restore
+    );
+}
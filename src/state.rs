@@ -0,0 +1,398 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::facts::NodeFacts;
+use crate::probe::{CopyStrategy, LinkMeasurement};
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error(
+        "Could not find a directory to keep state in, neither $XDG_STATE_HOME nor $HOME is set"
+    )]
+    NoStateDir,
+    #[error("Failed to read or write node state: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to parse or serialize node state: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Where deploy-rs remembers things about nodes between runs, `$XDG_STATE_HOME/deploy-rs`
+///
+/// It holds a JSON file per node in `nodes/`, the manifests of recent runs in `runs/`, what was confirmed in each
+/// channel in `channels/`, the deployments of recently evaluated flakes in `evaluations/` and, with `--keep-result`, the build results of each node's profiles in
+/// `gcroots/<node>/<profile>` unless `--result-path` puts them elsewhere.
+pub fn state_dir() -> Result<PathBuf, StateError> {
+    match (std::env::var_os("XDG_STATE_HOME"), std::env::var_os("HOME")) {
+        (Some(state_home), _) if !state_home.is_empty() => {
+            Ok(PathBuf::from(state_home).join("deploy-rs"))
+        }
+        (_, Some(home)) if !home.is_empty() => {
+            Ok(PathBuf::from(home).join(".local/state/deploy-rs"))
+        }
+        _ => Err(StateError::NoStateDir),
+    }
+}
+
+/// How many deployments are remembered for each node, older ones are forgotten
+pub const HISTORY_LENGTH: usize = 50;
+
+/// A profile deployed to a node from this machine
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Deployment {
+    pub profile: String,
+    pub closure: String,
+    /// Seconds since the epoch
+    pub deployed_at: u64,
+    /// Whether activation was left to the node, for when its maintenance window opens
+    #[serde(default)]
+    pub deferred: bool,
+    /// What the deployment was labelled with by `--label`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Whether it was made by another tool and found on the node by `deploy adopt`, rather than deployed from here
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+/// What is known about a node from earlier runs
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<LinkMeasurement>,
+    /// Oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<Deployment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub facts: Option<NodeFacts>,
+    /// The quickest way of copying to the node `deploy bench-copy` found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_strategy: Option<CopyStrategy>,
+    /// The fingerprint of the host key pinned with `pinHostKey`, as in `SHA256:...`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
+}
+
+fn node_state_path(dir: &Path, node_name: &str) -> PathBuf {
+    dir.join("nodes").join(format!("{}.json", node_name))
+}
+
+/// Where `--keep-result` links the build results of a node's profiles
+pub fn gc_roots_dir(dir: &Path) -> PathBuf {
+    dir.join("gcroots")
+}
+
+/// The nodes anything is known about, sorted by name
+pub fn known_nodes(dir: &Path) -> Result<Vec<String>, StateError> {
+    let mut nodes = Vec::new();
+
+    for subdir in &[dir.join("nodes"), gc_roots_dir(dir)] {
+        let entries = match std::fs::read_dir(subdir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            let node_name = match file_name.strip_suffix(".json") {
+                Some(x) => x.to_string(),
+                None if subdir.ends_with("gcroots") => file_name,
+                // Left behind by an interrupted `save_node_state`
+                None => continue,
+            };
+
+            if !nodes.contains(&node_name) {
+                nodes.push(node_name);
+            }
+        }
+    }
+
+    nodes.sort();
+
+    Ok(nodes)
+}
+
+/// Loads the state of a node, which is empty if nothing was recorded for it yet
+pub fn load_node_state(dir: &Path, node_name: &str) -> Result<NodeState, StateError> {
+    match std::fs::read(node_state_path(dir, node_name)) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(NodeState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save_node_state(dir: &Path, node_name: &str, state: &NodeState) -> Result<(), StateError> {
+    let path = node_state_path(dir, node_name);
+    let temp_path = path.with_extension("json.tmp");
+
+    std::fs::create_dir_all(dir.join("nodes"))?;
+
+    // Written next to the real file first, so a run that's interrupted can't leave half of it behind
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(state)?)?;
+    std::fs::rename(&temp_path, &path)?;
+
+    Ok(())
+}
+
+/// Adds a deployment to the node's history
+pub fn record_deployment(
+    dir: &Path,
+    node_name: &str,
+    deployment: Deployment,
+) -> Result<(), StateError> {
+    let mut state = load_node_state(dir, node_name)?;
+
+    state.history.push(deployment);
+
+    if state.history.len() > HISTORY_LENGTH {
+        let excess = state.history.len() - HISTORY_LENGTH;
+        state.history.drain(..excess);
+    }
+
+    save_node_state(dir, node_name, &state)
+}
+
+/// What the deployment of a flake was evaluated from, its evaluation is only reused when all of it is the same
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluationKey {
+    /// The `narHash` the flake is locked to, which covers its `flake.lock` and so every input it evaluates
+    pub nar_hash: String,
+    pub attr: String,
+    /// The function the deployment is narrowed down to the selected nodes and profiles with
+    pub selection: String,
+    pub extra_build_args: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Evaluation {
+    key: EvaluationKey,
+    deployment: serde_json::Value,
+}
+
+/// How many evaluations are kept to be reused, the least recently made ones are forgotten
+pub const EVALUATION_CACHE_LENGTH: usize = 20;
+
+fn evaluations_dir(dir: &Path) -> PathBuf {
+    dir.join("evaluations")
+}
+
+fn evaluation_path(dir: &Path, key: &EvaluationKey) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    evaluations_dir(dir).join(format!("{:016x}.json", hasher.finish()))
+}
+
+/// The deployment evaluated for `key` by an earlier run, if one is kept
+pub fn load_evaluation(
+    dir: &Path,
+    key: &EvaluationKey,
+) -> Result<Option<serde_json::Value>, StateError> {
+    match std::fs::read(evaluation_path(dir, key)) {
+        Ok(contents) => {
+            let evaluation: Evaluation = serde_json::from_slice(&contents)?;
+
+            // The file name is only a hash of the key, so the key itself is what tells it's the same
+            Ok(match evaluation.key == *key {
+                true => Some(evaluation.deployment),
+                false => None,
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keeps the deployment evaluated for `key` for later runs, forgetting the oldest evaluations beyond
+/// `EVALUATION_CACHE_LENGTH`
+pub fn save_evaluation(
+    dir: &Path,
+    key: &EvaluationKey,
+    deployment: &serde_json::Value,
+) -> Result<(), StateError> {
+    let path = evaluation_path(dir, key);
+    let temp_path = path.with_extension(format!("json.{}.tmp", std::process::id()));
+
+    std::fs::create_dir_all(evaluations_dir(dir))?;
+
+    std::fs::write(
+        &temp_path,
+        serde_json::to_vec(&Evaluation {
+            key: key.clone(),
+            deployment: deployment.clone(),
+        })?,
+    )?;
+    std::fs::rename(&temp_path, &path)?;
+
+    let mut evaluations = Vec::new();
+    for entry in std::fs::read_dir(evaluations_dir(dir))? {
+        let entry = entry?;
+
+        if entry.path().extension() == Some("json".as_ref()) {
+            evaluations.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+
+    if evaluations.len() > EVALUATION_CACHE_LENGTH {
+        evaluations.sort();
+
+        for (_, path) in &evaluations[..evaluations.len() - EVALUATION_CACHE_LENGTH] {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets everything about a node, or about all of them, including the build results kept for it
+pub fn clean(dir: &Path, node_name: Option<&str>) -> Result<(), StateError> {
+    let paths = match node_name {
+        Some(node_name) => vec![
+            node_state_path(dir, node_name),
+            gc_roots_dir(dir).join(node_name),
+            crate::hostkey::known_hosts_path(dir, node_name),
+        ],
+        None => vec![dir.to_path_buf()],
+    };
+
+    for path in paths {
+        let result = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+            Ok(_) => std::fs::remove_file(&path),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_node_state() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-state-{}", std::process::id()));
+
+    assert_eq!(
+        load_node_state(&dir, "web-1").unwrap(),
+        NodeState::default()
+    );
+
+    let state = NodeState {
+        link: Some(LinkMeasurement {
+            connect_ms: 120,
+            throughput_kib_s: 40_000,
+            measured_at: 1_614_556_800,
+        }),
+        history: Vec::new(),
+        facts: Some(NodeFacts {
+            nix_version: Some("2.18.1".to_string()),
+            os: Some("nixos 23.11".to_string()),
+            init_system: crate::facts::InitSystem::Systemd,
+            escalation: Some("sudo".to_string()),
+            store_free_kib: None,
+            trusted: Some(false),
+            gathered_at: 1_614_556_800,
+        }),
+        copy_strategy: Some(CopyStrategy::Compressed),
+        host_key: Some("SHA256:nThbg6kXUpJWGl7E1IGOCspRomTxdCARLviKw6E5SY8".to_string()),
+    };
+    save_node_state(&dir, "web-1", &state).unwrap();
+
+    assert_eq!(load_node_state(&dir, "web-1").unwrap(), state);
+    assert_eq!(load_node_state(&dir, "db-1").unwrap(), NodeState::default());
+
+    for deployed_at in 0..HISTORY_LENGTH as u64 + 2 {
+        record_deployment(
+            &dir,
+            "web-1",
+            Deployment {
+                profile: "system".to_string(),
+                closure: "/nix/store/blah-system".to_string(),
+                deployed_at,
+                deferred: false,
+                label: None,
+                adopted: false,
+            },
+        )
+        .unwrap();
+    }
+
+    let history = load_node_state(&dir, "web-1").unwrap().history;
+    assert_eq!(history.len(), HISTORY_LENGTH);
+    assert_eq!(history[0].deployed_at, 2);
+
+    std::fs::create_dir_all(gc_roots_dir(&dir).join("db-1")).unwrap();
+    assert_eq!(known_nodes(&dir).unwrap(), vec!["db-1", "web-1"]);
+
+    let known_hosts = crate::hostkey::known_hosts_path(&dir, "web-1");
+    std::fs::create_dir_all(known_hosts.parent().unwrap()).unwrap();
+    std::fs::write(&known_hosts, "10.0.0.5 ssh-ed25519 AAAA\n").unwrap();
+
+    clean(&dir, Some("web-1")).unwrap();
+    assert_eq!(known_nodes(&dir).unwrap(), vec!["db-1"]);
+    assert!(!known_hosts.exists());
+
+    clean(&dir, None).unwrap();
+    assert!(!dir.exists());
+}
+
+#[test]
+fn test_evaluation_cache() {
+    let dir =
+        std::env::temp_dir().join(format!("deploy-rs-test-evaluations-{}", std::process::id()));
+
+    let key = EvaluationKey {
+        nar_hash: "sha256-VnbzeFGGCMCYDBqaHgXdkYO7ptaBE1ylEpcFGVTBqco=".to_string(),
+        attr: "deploy".to_string(),
+        selection: "deploy: deploy".to_string(),
+        extra_build_args: Vec::new(),
+    };
+    let deployment = serde_json::json!({ "nodes": { "web-1": { "hostname": "10.0.0.5" } } });
+
+    assert_eq!(load_evaluation(&dir, &key).unwrap(), None);
+
+    save_evaluation(&dir, &key, &deployment).unwrap();
+    assert_eq!(
+        load_evaluation(&dir, &key).unwrap(),
+        Some(deployment.clone())
+    );
+
+    let other_key = EvaluationKey {
+        extra_build_args: vec![
+            "--override-input".to_string(),
+            "nixpkgs".to_string(),
+            "../nixpkgs".to_string(),
+        ],
+        ..key.clone()
+    };
+    assert_eq!(load_evaluation(&dir, &other_key).unwrap(), None);
+
+    for i in 0..EVALUATION_CACHE_LENGTH + 2 {
+        let key = EvaluationKey {
+            nar_hash: format!("sha256-{}", i),
+            ..key.clone()
+        };
+        save_evaluation(&dir, &key, &deployment).unwrap();
+    }
+    assert_eq!(
+        std::fs::read_dir(evaluations_dir(&dir)).unwrap().count(),
+        EVALUATION_CACHE_LENGTH
+    );
+
+    clean(&dir, None).unwrap();
+}
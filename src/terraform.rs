@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::data::{Data, TerraformOutputs};
+use crate::report::CommandFailure;
+use crate::transport::{Invocation, OutputMode, Transport};
+
+#[derive(Error, Debug)]
+pub enum TerraformError {
+    #[error("Failed to run terraform: {0}")]
+    Run(std::io::Error),
+    #[error("`terraform output` failed with {0}")]
+    Exit(CommandFailure),
+    #[error("Failed to read the terraform outputs in {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("Failed to parse the terraform outputs: {0}")]
+    Parse(serde_json::Error),
+    #[error("Node `{0}` takes its {1} from the terraform output `{2}`, which there isn't")]
+    Missing(String, &'static str, String),
+    #[error("Node `{0}` takes its {1} from the terraform output `{2}`, which isn't {3}")]
+    WrongType(String, &'static str, String, &'static str),
+}
+
+/// Loads the outputs of `terraform output -json`, run in the configured directory or saved to a file before
+pub async fn outputs(
+    transport: &dyn Transport,
+    settings: &TerraformOutputs,
+) -> Result<serde_json::Value, TerraformError> {
+    if let Some(ref output_file) = settings.output_file {
+        let output =
+            std::fs::read(output_file).map_err(|e| TerraformError::Read(output_file.clone(), e))?;
+
+        return serde_json::from_slice(&output).map_err(TerraformError::Parse);
+    }
+
+    let mut output_command = Invocation::new("terraform");
+    if let Some(ref dir) = settings.dir {
+        output_command.arg(format!("-chdir={}", dir));
+    }
+    output_command
+        .arg("output")
+        .arg("-json")
+        .stdout(OutputMode::Capture);
+
+    debug!("Running terraform command: {}", output_command);
+
+    let output = transport
+        .run(&output_command)
+        .await
+        .map_err(TerraformError::Run)?;
+
+    match output.code {
+        Some(0) => (),
+        _ => {
+            return Err(TerraformError::Exit(CommandFailure::new(
+                &output_command,
+                &output,
+            )))
+        }
+    };
+
+    serde_json::from_slice(&output.stdout).map_err(TerraformError::Parse)
+}
+
+/// The value a reference like `web_ips.0` or `servers.web-1.ipv4_address` points to: the output named by its first
+/// part, and then the keys of objects and indices of lists named by the rest
+pub fn lookup<'a>(
+    outputs: &'a serde_json::Value,
+    reference: &str,
+) -> Option<&'a serde_json::Value> {
+    let mut parts = reference.split('.');
+    let mut value = outputs.get(parts.next()?)?.get("value")?;
+
+    for part in parts {
+        value = match value {
+            serde_json::Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => value.get(part)?,
+        };
+    }
+
+    Some(value)
+}
+
+/// Sets the hostnames and adds the tags the nodes of `data` take from `outputs`
+pub fn apply(outputs: &serde_json::Value, data: &mut Data) -> Result<(), TerraformError> {
+    for (node_name, node) in data.nodes.iter_mut() {
+        let references = match node.node_settings.terraform {
+            Some(ref x) => x.clone(),
+            None => continue,
+        };
+
+        let find = |setting, reference: &str| {
+            lookup(outputs, reference).ok_or_else(|| {
+                TerraformError::Missing(node_name.clone(), setting, reference.to_string())
+            })
+        };
+
+        if let Some(ref reference) = references.hostname {
+            node.node_settings.hostname = match find("hostname", reference)? {
+                serde_json::Value::String(x) => x.clone(),
+                _ => {
+                    return Err(TerraformError::WrongType(
+                        node_name.clone(),
+                        "hostname",
+                        reference.clone(),
+                        "a string",
+                    ))
+                }
+            };
+        }
+
+        if let Some(ref reference) = references.tags {
+            let tags: Vec<String> = match find("tags", reference)? {
+                serde_json::Value::String(x) => vec![x.clone()],
+                x => serde_json::from_value(x.clone()).map_err(|_| {
+                    TerraformError::WrongType(
+                        node_name.clone(),
+                        "tags",
+                        reference.clone(),
+                        "a string or a list of them",
+                    )
+                })?,
+            };
+
+            for tag in tags {
+                if !node.node_settings.tags.contains(&tag) {
+                    node.node_settings.tags.push(tag);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the hostnames and tags of the nodes of `data` which take them from terraform, running it only if any do
+pub async fn resolve(transport: &dyn Transport, data: &mut Data) -> Result<(), TerraformError> {
+    if !data
+        .nodes
+        .values()
+        .any(|node| node.node_settings.terraform.is_some())
+    {
+        return Ok(());
+    }
+
+    info!("Resolving hostnames and tags from the terraform outputs");
+
+    let outputs = outputs(transport, &data.terraform.clone().unwrap_or_default()).await?;
+
+    apply(&outputs, data)
+}
+
+#[test]
+fn test_lookup() {
+    let outputs = serde_json::json!({
+        "web_ips": { "sensitive": false, "type": ["list", "string"], "value": ["10.0.1.1", "10.0.1.2"] },
+        "servers": {
+            "sensitive": false,
+            "type": ["map", ["object", { "ipv4_address": "string" }]],
+            "value": { "db-1": { "ipv4_address": "10.0.0.4", "labels": ["db", "eu"] } }
+        }
+    });
+
+    assert_eq!(
+        lookup(&outputs, "web_ips.1"),
+        Some(&serde_json::json!("10.0.1.2"))
+    );
+    assert_eq!(
+        lookup(&outputs, "servers.db-1.ipv4_address"),
+        Some(&serde_json::json!("10.0.0.4"))
+    );
+    assert_eq!(lookup(&outputs, "web_ips.2"), None);
+    assert_eq!(lookup(&outputs, "web_ips.first"), None);
+    assert_eq!(lookup(&outputs, "lb_ip"), None);
+}
+
+#[test]
+fn test_resolve() {
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "terraform": { "dir": "infra" },
+        "nodes": {
+            "db-1": {
+                "tags": ["db"],
+                "terraform": { "hostname": "servers.db-1.ipv4_address", "tags": "servers.db-1.labels" },
+                "profiles": {}
+            },
+            "lb": { "hostname": "10.0.0.1", "profiles": {} }
+        }
+    }))
+    .unwrap();
+
+    let transport = crate::transport::RecordingTransport::default();
+    transport.respond(
+        "output -json",
+        Some(0),
+        r#"{ "servers": { "value": { "db-1": { "ipv4_address": "10.0.0.4", "labels": ["db", "eu"] } } } }"#,
+    );
+
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(resolve(&transport, &mut data))
+        .unwrap();
+
+    assert_eq!(
+        transport.commands(),
+        vec!["terraform -chdir=infra output -json"]
+    );
+    assert_eq!(data.nodes["db-1"].node_settings.hostname, "10.0.0.4");
+    assert_eq!(data.nodes["db-1"].node_settings.tags, vec!["db", "eu"]);
+    assert_eq!(data.nodes["lb"].node_settings.hostname, "10.0.0.1");
+
+    let outputs = serde_json::json!({ "servers": { "value": { "db-1": { "ipv4_address": 4 } } } });
+    assert!(matches!(
+        apply(&outputs, &mut data),
+        Err(TerraformError::WrongType(_, "hostname", _, _))
+    ));
+
+    assert!(serde_json::from_value::<Data>(serde_json::json!({
+        "nodes": { "db-1": { "terraform": { "tags": "servers.db-1.labels" }, "profiles": {} } }
+    }))
+    .is_err());
+}
@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A step of deploying a profile that `--timings` tells the time of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    Build,
+    Sign,
+    Copy,
+    /// Running the profile's `migrations`
+    Migrate,
+    Activate,
+    /// Running `postActivateChecks` and confirming the activation for magic rollback
+    Confirm,
+}
+
+const STEPS: [(Step, &str); 6] = [
+    (Step::Build, "build"),
+    (Step::Sign, "sign"),
+    (Step::Copy, "copy"),
+    (Step::Migrate, "migrate"),
+    (Step::Activate, "activate"),
+    (Step::Confirm, "confirm"),
+];
+
+/// How long each step of a deployment took on each node, added up over its profiles
+///
+/// Nothing of it leaves this machine, it's only printed at the end of the run.
+#[derive(Debug, Default)]
+pub struct Timings {
+    eval: Mutex<Option<Duration>>,
+    nodes: Mutex<BTreeMap<String, BTreeMap<Step, Duration>>>,
+}
+
+impl Timings {
+    pub fn record_eval(&self, took: Duration) {
+        *self.eval.lock().unwrap_or_else(|e| e.into_inner()) = Some(took);
+    }
+
+    pub fn record_step(&self, node: &str, step: Step, took: Duration) {
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+
+        *nodes
+            .entry(node.to_string())
+            .or_default()
+            .entry(step)
+            .or_default() += took;
+    }
+
+    /// The breakdown as a table with a row for each node, and the steps it didn't take left empty
+    pub fn table(&self) -> String {
+        let eval = *self.eval.lock().unwrap_or_else(|e| e.into_inner());
+        let nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+
+        let seconds = |x: Option<&Duration>| match x {
+            Some(x) => format!("{:.1}s", x.as_secs_f64()),
+            None => "-".to_string(),
+        };
+
+        let mut rows: Vec<Vec<String>> = vec![std::iter::once("node".to_string())
+            .chain(STEPS.iter().map(|(_, name)| name.to_string()))
+            .collect()];
+
+        for (node, steps) in nodes.iter() {
+            rows.push(
+                std::iter::once(node.clone())
+                    .chain(STEPS.iter().map(|(step, _)| seconds(steps.get(step))))
+                    .collect(),
+            );
+        }
+
+        let widths: Vec<usize> = (0..rows[0].len())
+            .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+            .collect();
+
+        let mut lines = vec![format!("evaluation: {}", seconds(eval.as_ref()))];
+
+        for row in rows {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .enumerate()
+                .map(|(i, (cell, width))| match i {
+                    0 => format!("{:<width$}", cell, width = width),
+                    _ => format!("{:>width$}", cell, width = width),
+                })
+                .collect();
+
+            lines.push(cells.join("  ").trim_end().to_string());
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// Records how long `step` took on `node` since `started`, if timings are recorded
+pub fn record(timings: Option<&Timings>, node: &str, step: Step, started: Instant) {
+    if let Some(timings) = timings {
+        timings.record_step(node, step, started.elapsed());
+    }
+}
+
+#[test]
+fn test_timings_table() {
+    let timings = Timings::default();
+
+    timings.record_eval(Duration::from_millis(12_300));
+    timings.record_step("web-1", Step::Build, Duration::from_millis(45_000));
+    timings.record_step("web-1", Step::Copy, Duration::from_millis(3_100));
+    timings.record_step("web-1", Step::Copy, Duration::from_millis(1_000));
+    timings.record_step("web-1", Step::Activate, Duration::from_millis(10_000));
+    timings.record_step("web-1", Step::Confirm, Duration::from_millis(1_200));
+    timings.record_step("db-1", Step::Activate, Duration::from_millis(2_500));
+
+    assert_eq!(
+        timings.table(),
+        "evaluation: 12.3s\n\
+         node   build  sign  copy  migrate  activate  confirm\n\
+         db-1       -     -     -        -      2.5s        -\n\
+         web-1  45.0s     -  4.1s        -     10.0s     1.2s"
+    );
+}
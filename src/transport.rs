@@ -0,0 +1,698 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use futures_util::future::BoxFuture;
+use log::{debug, warn};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+use crate::agent::{self, AgentConnection, AgentRequest};
+use crate::report::CommandFailure;
+use crate::DeployDataDefsError;
+
+/// How many of the last lines a command printed to standard error are kept for reporting its failure
+pub const STDERR_TAIL_LINES: usize = 50;
+
+/// What to do with the standard output of an invocation, standard error is always passed through
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputMode {
+    Inherit,
+    Null,
+    Capture,
+    File(PathBuf),
+}
+
+/// What an invocation reads, without one it shares our standard input
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputMode {
+    File(PathBuf),
+    /// Written to the process, but never shown when the invocation is printed
+    Secret(Vec<u8>),
+}
+
+/// A process to run on the deploying machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub stdin: Option<InputMode>,
+    pub stdout: OutputMode,
+}
+
+impl Invocation {
+    pub fn new(program: &str) -> Self {
+        Invocation {
+            program: program.to_string(),
+            args: Vec::new(),
+            env: Vec::new(),
+            stdin: None,
+            stdout: OutputMode::Inherit,
+        }
+    }
+
+    pub fn arg<S: AsRef<str>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(&mut self, args: &[S]) -> &mut Self {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Feeds the process from somewhere other than our standard input
+    pub fn stdin(&mut self, stdin: InputMode) -> &mut Self {
+        self.stdin = Some(stdin);
+        self
+    }
+
+    pub fn stdout(&mut self, stdout: OutputMode) -> &mut Self {
+        self.stdout = stdout;
+        self
+    }
+}
+
+impl std::fmt::Display for Invocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (key, value) in &self.env {
+            write!(f, "{}={} ", key, value)?;
+        }
+
+        write!(f, "{}", self.program)?;
+
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+
+        match self.stdin {
+            Some(InputMode::File(ref path)) => write!(f, " < {}", path.display())?,
+            Some(InputMode::Secret(_)) => write!(f, " < (secret)")?,
+            None => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// What a process exited with, what it printed if that was asked for, and the end of its standard error
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Output {
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr_tail: Vec<String>,
+}
+
+/// Adds a line to `tail`, dropping the oldest one once there are `STDERR_TAIL_LINES`
+pub fn push_stderr_line(tail: &mut Vec<String>, line: String) {
+    if tail.len() == STDERR_TAIL_LINES {
+        tail.remove(0);
+    }
+
+    tail.push(line);
+}
+
+/// Passes standard error of a process through to ours, keeping its last lines. Nix logs in `internal-json` are shown
+/// and kept as Nix would have printed them
+async fn relay_stderr<R: AsyncRead + Unpin>(stderr: R) -> Result<Vec<String>, std::io::Error> {
+    let mut reader = BufReader::new(stderr);
+    let mut our_stderr = tokio::io::stderr();
+    let mut tail = Vec::new();
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        if let Some(rendered) = crate::nixlog::render(String::from_utf8_lossy(&line).trim_end()) {
+            our_stderr.write_all(rendered.as_bytes()).await?;
+            our_stderr.write_all(b"\n").await?;
+
+            for rendered_line in rendered.lines() {
+                push_stderr_line(&mut tail, rendered_line.to_string());
+            }
+        }
+
+        line.clear();
+    }
+
+    our_stderr.flush().await?;
+
+    Ok(tail)
+}
+
+/// How commands reach a node
+#[derive(Debug, Clone)]
+pub enum NodeTarget<'a> {
+    Ssh {
+        address: String,
+        opts: &'a [String],
+        /// Given to the command on standard input, for `sudo -S`
+        sudo_password: Option<&'a str>,
+    },
+    Agent(AgentConnection<'a>),
+}
+
+impl std::fmt::Display for NodeTarget<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeTarget::Ssh { address, .. } => write!(f, "ssh://{}", address),
+            NodeTarget::Agent(connection) => {
+                write!(f, "agent://{}:{}", connection.hostname, connection.port)
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunOnNodeError {
+    #[error("{0}")]
+    SSH(#[from] std::io::Error),
+    #[error("{0}")]
+    Agent(#[from] crate::agent::AgentError),
+    #[error("{0}")]
+    DeployDataDefs(#[from] DeployDataDefsError),
+    #[error("Failed to read the activation's variables to send them to the agent: {0}")]
+    AgentEnv(serde_json::Error),
+    #[error("Agents only take standard input along with activations")]
+    AgentInput,
+}
+
+/// SSH options which share one connection per node through control sockets in `dir`,
+/// so a node only has to be authenticated to once
+pub fn control_master_opts(dir: &str) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        format!("ControlPath={}/%C", dir),
+        "-o".to_string(),
+        "ControlPersist=60".to_string(),
+    ]
+}
+
+/// Whether the SSH agent holds keys on a security token (`sk-*` keys), which want a touch for every connection
+pub async fn agent_has_security_keys(transport: &dyn Transport) -> bool {
+    let mut list_command = Invocation::new("ssh-add");
+    list_command.arg("-L").stdout(OutputMode::Capture);
+
+    match transport.run(&list_command).await {
+        Ok(output) if output.code == Some(0) => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|key| key.starts_with("sk-")),
+        _ => false,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AskpassError {
+    #[error("Failed to run the askpass program: {0}")]
+    Run(std::io::Error),
+    #[error("The askpass program failed with {0}")]
+    Exit(CommandFailure),
+}
+
+/// Asks the askpass program for a secret, like SSH does with `SSH_ASKPASS`: it is given the prompt as its only
+/// argument and prints the answer
+pub async fn ask_credential(
+    transport: &dyn Transport,
+    program: &str,
+    prompt: &str,
+) -> Result<String, AskpassError> {
+    let mut askpass_command = Invocation::new(program);
+    askpass_command.arg(prompt).stdout(OutputMode::Capture);
+
+    let askpass_output = transport
+        .run(&askpass_command)
+        .await
+        .map_err(AskpassError::Run)?;
+
+    match askpass_output.code {
+        Some(0) => (),
+        _ => {
+            return Err(AskpassError::Exit(CommandFailure::new(
+                &askpass_command,
+                &askpass_output,
+            )))
+        }
+    };
+
+    Ok(String::from_utf8_lossy(&askpass_output.stdout)
+        .trim_end_matches(&['\n', '\r'][..])
+        .to_string())
+}
+
+/// Everything deploy-rs runs, locally or on nodes, goes through a transport
+///
+/// `SystemTransport` actually runs things, `RecordingTransport` lets tests check what would have been run.
+pub trait Transport: Send + Sync + std::fmt::Debug {
+    fn run<'a>(
+        &'a self,
+        invocation: &'a Invocation,
+    ) -> BoxFuture<'a, Result<Output, std::io::Error>>;
+
+    fn run_on_node<'a>(
+        &'a self,
+        target: &'a NodeTarget<'a>,
+        command: &'a str,
+        capture_stdout: bool,
+    ) -> BoxFuture<'a, Result<Output, RunOnNodeError>> {
+        self.run_on_node_with_input(target, command, &[], capture_stdout)
+    }
+
+    /// Runs a command on a node with `input` on its standard input, after the sudo password if there is one
+    ///
+    /// The input is for what mustn't be on the command line, so it's never logged.
+    fn run_on_node_with_input<'a>(
+        &'a self,
+        target: &'a NodeTarget<'a>,
+        command: &'a str,
+        input: &'a [u8],
+        capture_stdout: bool,
+    ) -> BoxFuture<'a, Result<Output, RunOnNodeError>>;
+}
+
+/// Runs an invocation on this machine once
+async fn run_once(invocation: &Invocation) -> Result<Output, std::io::Error> {
+    let mut command = Command::new(&invocation.program);
+    command.args(&invocation.args);
+
+    for (key, value) in &invocation.env {
+        command.env(key, value);
+    }
+
+    command.stdin(match invocation.stdin {
+        Some(InputMode::File(ref path)) => Stdio::from(std::fs::File::open(path)?),
+        Some(InputMode::Secret(_)) => Stdio::piped(),
+        None => Stdio::inherit(),
+    });
+
+    command.stdout(match invocation.stdout {
+        OutputMode::Inherit => Stdio::inherit(),
+        OutputMode::Null => Stdio::null(),
+        OutputMode::Capture => Stdio::piped(),
+        OutputMode::File(ref path) => Stdio::from(std::fs::File::create(path)?),
+    });
+
+    // Standard error is still shown as it comes, but its end is kept in case the command fails
+    command.stderr(Stdio::piped());
+
+    let mut child = command.spawn()?;
+    let stderr = child.stderr.take();
+    let stdin = child.stdin.take();
+
+    let (output, stderr_tail, written) = tokio::join!(
+        child.wait_with_output(),
+        async move {
+            match stderr {
+                Some(stderr) => relay_stderr(stderr).await,
+                None => Ok(Vec::new()),
+            }
+        },
+        async move {
+            // Dropping standard input once the secret is written lets the process see its end
+            match (stdin, &invocation.stdin) {
+                (Some(mut stdin), Some(InputMode::Secret(secret))) => stdin.write_all(secret).await,
+                _ => Ok(()),
+            }
+        }
+    );
+
+    let output = output?;
+    written?;
+
+    Ok(Output {
+        code: output.status.code(),
+        stdout: output.stdout,
+        stderr_tail: stderr_tail?,
+    })
+}
+
+/// How often a local Nix command that lost its connection to the Nix daemon is run again
+const DAEMON_RETRIES: u32 = 3;
+
+/// Whether an invocation runs Nix, possibly through `nice` and `ionice`
+fn runs_nix(invocation: &Invocation) -> bool {
+    std::iter::once(&invocation.program)
+        .chain(invocation.args.iter())
+        .find(|x| {
+            !matches!(x.as_str(), "nice" | "ionice" | "-n" | "-c") && x.parse::<i32>().is_err()
+        })
+        .map(|x| x.starts_with("nix"))
+        == Some(true)
+}
+
+/// Whether a Nix command failed because it lost its connection to the Nix daemon, rather than for what it was doing,
+/// e.g. as the daemon was restarted by a system update
+pub fn lost_daemon(output: &Output) -> bool {
+    output.code != Some(0)
+        && crate::nixlog::classify(&output.stderr_tail) == Some(crate::nixlog::NixFailure::Daemon)
+}
+
+#[derive(Debug)]
+pub struct SystemTransport;
+
+impl Transport for SystemTransport {
+    fn run<'a>(
+        &'a self,
+        invocation: &'a Invocation,
+    ) -> BoxFuture<'a, Result<Output, std::io::Error>> {
+        Box::pin(async move {
+            let mut attempt = 0;
+
+            loop {
+                let output = run_once(invocation).await?;
+
+                // Nix commands deploy runs can all be run again, picking up where they stopped
+                if attempt == DAEMON_RETRIES || !runs_nix(invocation) || !lost_daemon(&output) {
+                    return Ok(output);
+                }
+
+                attempt += 1;
+
+                warn!(
+                    "`{}` lost its connection to the Nix daemon, running it again in {}s ({}/{})",
+                    invocation.program,
+                    2u64.pow(attempt),
+                    attempt,
+                    DAEMON_RETRIES
+                );
+
+                tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(attempt))).await;
+            }
+        })
+    }
+
+    fn run_on_node_with_input<'a>(
+        &'a self,
+        target: &'a NodeTarget<'a>,
+        command: &'a str,
+        input: &'a [u8],
+        capture_stdout: bool,
+    ) -> BoxFuture<'a, Result<Output, RunOnNodeError>> {
+        Box::pin(async move {
+            match target {
+                NodeTarget::Ssh {
+                    address,
+                    opts,
+                    sudo_password,
+                } => {
+                    let mut ssh_invocation = Invocation::new("ssh");
+                    ssh_invocation.arg(address).args(opts).arg(command).stdout(
+                        match capture_stdout {
+                            true => OutputMode::Capture,
+                            false => OutputMode::Inherit,
+                        },
+                    );
+
+                    let mut stdin = match sudo_password {
+                        Some(password) => format!("{}\n", password).into_bytes(),
+                        None => Vec::new(),
+                    };
+                    stdin.extend_from_slice(input);
+
+                    if !stdin.is_empty() {
+                        ssh_invocation.stdin(InputMode::Secret(stdin));
+                    }
+
+                    Ok(self.run(&ssh_invocation).await?)
+                }
+                NodeTarget::Agent(connection) => {
+                    // Agents only run commands they're sent when allowed to, activations are sent as what they are
+                    let mut request =
+                        agent::typed_request(command).unwrap_or_else(|| AgentRequest::Run {
+                            command: command.to_string(),
+                        });
+
+                    // Agents have no standard input to give, activations get their variables along with the request
+                    if !input.is_empty() {
+                        match request {
+                            AgentRequest::Activate { ref mut env, .. } => env.0.extend(
+                                crate::parse_activation_env(&String::from_utf8_lossy(input))
+                                    .map_err(RunOnNodeError::AgentEnv)?,
+                            ),
+                            _ => return Err(RunOnNodeError::AgentInput),
+                        }
+                    }
+
+                    let output = agent::request(connection, &request, None, capture_stdout).await?;
+
+                    Ok(Output {
+                        code: output.code,
+                        stdout: output.stdout,
+                        stderr_tail: output.stderr_tail,
+                    })
+                }
+            }
+        })
+    }
+}
+
+/// A transport which runs nothing, but records what it was asked to run and answers with canned output
+#[derive(Debug, Default)]
+pub struct RecordingTransport {
+    commands: Mutex<Vec<String>>,
+    responses: Mutex<Vec<(String, Output)>>,
+}
+
+impl RecordingTransport {
+    /// Answers the next command containing `pattern` with the given output, anything else succeeds silently
+    pub fn respond(&self, pattern: &str, code: Option<i32>, stdout: &str) {
+        self.responses.lock().unwrap().push((
+            pattern.to_string(),
+            Output {
+                code,
+                stdout: stdout.as_bytes().to_vec(),
+                stderr_tail: Vec::new(),
+            },
+        ));
+    }
+
+    /// Everything run so far, local invocations as command lines and node commands prefixed with their target
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    fn record(&self, command: String) -> Output {
+        debug!("Recording `{}`", command);
+
+        let mut responses = self.responses.lock().unwrap();
+        let output = match responses
+            .iter()
+            .position(|(p, _)| command.contains(p.as_str()))
+        {
+            Some(i) => responses.remove(i).1,
+            None => Output {
+                code: Some(0),
+                ..Output::default()
+            },
+        };
+
+        self.commands.lock().unwrap().push(command);
+
+        output
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn run<'a>(
+        &'a self,
+        invocation: &'a Invocation,
+    ) -> BoxFuture<'a, Result<Output, std::io::Error>> {
+        let output = self.record(invocation.to_string());
+        Box::pin(async move { Ok(output) })
+    }
+
+    fn run_on_node_with_input<'a>(
+        &'a self,
+        target: &'a NodeTarget<'a>,
+        command: &'a str,
+        input: &'a [u8],
+        _capture_stdout: bool,
+    ) -> BoxFuture<'a, Result<Output, RunOnNodeError>> {
+        let input = match target {
+            NodeTarget::Ssh {
+                sudo_password: Some(_),
+                ..
+            } => " < (secret)",
+            _ if !input.is_empty() => " < (secret)",
+            _ => "",
+        };
+
+        let output = self.record(format!("[{}] {}{}", target, command, input));
+        Box::pin(async move { Ok(output) })
+    }
+}
+
+#[test]
+fn test_recording_transport() {
+    let transport = RecordingTransport::default();
+    transport.respond("nix path-info", Some(0), "[]");
+    transport.respond("rm /tmp", Some(1), "");
+
+    let target = NodeTarget::Ssh {
+        address: "deploy@web-1".to_string(),
+        opts: &[],
+        sudo_password: None,
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (path_info, confirm, build) = runtime.block_on(async {
+        (
+            transport
+                .run(Invocation::new("nix").arg("path-info").arg("--json"))
+                .await
+                .unwrap(),
+            transport
+                .run_on_node(&target, "rm /tmp/deploy-rs-canary", false)
+                .await
+                .unwrap(),
+            transport.run(&Invocation::new("nix-build")).await.unwrap(),
+        )
+    });
+    runtime
+        .block_on(transport.run_on_node_with_input(
+            &target,
+            "activate-rs activate --env-file -",
+            b"[[\"DB_PASSWORD\",\"hunter2\"]]\n",
+            false,
+        ))
+        .unwrap();
+
+    assert_eq!(path_info.stdout, b"[]");
+    assert_eq!(confirm.code, Some(1));
+    assert_eq!(build.code, Some(0));
+    assert_eq!(
+        transport.commands(),
+        vec![
+            "nix path-info --json",
+            "[ssh://deploy@web-1] rm /tmp/deploy-rs-canary",
+            "nix-build",
+            "[ssh://deploy@web-1] activate-rs activate --env-file - < (secret)"
+        ]
+    );
+}
+
+#[test]
+fn test_security_key_detection() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let transport = RecordingTransport::default();
+    transport.respond(
+        "ssh-add -L",
+        Some(0),
+        "ssh-ed25519 AAAAC3Nza user@laptop\nsk-ssh-ed25519@openssh.com AAAAGnNr user@laptop\n",
+    );
+    assert!(runtime.block_on(agent_has_security_keys(&transport)));
+
+    let transport = RecordingTransport::default();
+    transport.respond("ssh-add -L", Some(0), "ssh-ed25519 AAAAC3Nza user@laptop\n");
+    assert!(!runtime.block_on(agent_has_security_keys(&transport)));
+
+    // No agent running
+    let transport = RecordingTransport::default();
+    transport.respond("ssh-add -L", Some(2), "");
+    assert!(!runtime.block_on(agent_has_security_keys(&transport)));
+
+    assert_eq!(
+        control_master_opts("/tmp/deploy-rs-ssh").join(" "),
+        "-o ControlMaster=auto -o ControlPath=/tmp/deploy-rs-ssh/%C -o ControlPersist=60"
+    );
+}
+
+#[test]
+fn test_stderr_tail() {
+    let mut tail = Vec::new();
+
+    for i in 0..STDERR_TAIL_LINES + 5 {
+        push_stderr_line(&mut tail, format!("line {}", i));
+    }
+
+    assert_eq!(tail.len(), STDERR_TAIL_LINES);
+    assert_eq!(tail[0], "line 5");
+    assert_eq!(
+        tail[STDERR_TAIL_LINES - 1],
+        format!("line {}", STDERR_TAIL_LINES + 4)
+    );
+}
+
+#[test]
+fn test_lost_daemon() {
+    let failed = |line: &str| Output {
+        code: Some(1),
+        stdout: Vec::new(),
+        stderr_tail: vec![line.to_string()],
+    };
+
+    assert!(lost_daemon(&failed(
+        "error: cannot connect to socket at '/nix/var/nix/daemon-socket/socket': Connection refused"
+    )));
+    assert!(!lost_daemon(&failed(
+        "error: cannot open connection to remote store 'ssh://web-1': unexpected end-of-file"
+    )));
+    assert!(!lost_daemon(&failed(
+        "curl: (56) Recv failure: Connection reset by peer"
+    )));
+    assert!(!lost_daemon(&failed(
+        "error: builder for '/nix/store/abc-system.drv' failed with exit code 2"
+    )));
+
+    let mut nice_build = Invocation::new("nice");
+    nice_build.args(&["-n", "10", "ionice", "-c", "3", "nix", "build"]);
+    assert!(runs_nix(&nice_build));
+    assert!(runs_nix(Invocation::new("nix-build").arg("default.nix")));
+    assert!(!runs_nix(Invocation::new("ssh").arg("nix-store")));
+}
+
+#[test]
+fn test_askpass() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let transport = RecordingTransport::default();
+    transport.respond("pass-helper", Some(0), "correct horse\n");
+    assert_eq!(
+        runtime
+            .block_on(ask_credential(
+                &transport,
+                "pass-helper",
+                "[sudo] password for deploy on web-1: "
+            ))
+            .unwrap(),
+        "correct horse"
+    );
+
+    transport.respond("pass-helper", Some(1), "");
+    assert!(matches!(
+        runtime.block_on(ask_credential(&transport, "pass-helper", "Password: ")),
+        Err(AskpassError::Exit(_))
+    ));
+
+    let target = NodeTarget::Ssh {
+        address: "deploy@web-1".to_string(),
+        opts: &[],
+        sudo_password: Some("correct horse"),
+    };
+    runtime
+        .block_on(transport.run_on_node(&target, "sudo -S -p '' -u root true", false))
+        .unwrap();
+
+    let mut ssh_command = Invocation::new("ssh");
+    ssh_command
+        .arg("deploy@web-1")
+        .stdin(InputMode::Secret(b"correct horse\n".to_vec()));
+
+    assert_eq!(
+        transport.commands()[2],
+        "[ssh://deploy@web-1] sudo -S -p '' -u root true < (secret)"
+    );
+    assert_eq!(ssh_command.to_string(), "ssh deploy@web-1 < (secret)");
+}
@@ -0,0 +1,203 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// What `switch-to-configuration` did to systemd units while activating a NixOS profile
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UnitChanges {
+    /// Units whose files changed between the profiles, worked out before activating
+    #[serde(default)]
+    pub changed: Vec<String>,
+    #[serde(default)]
+    pub stopped: Vec<String>,
+    #[serde(default)]
+    pub restarted: Vec<String>,
+    #[serde(default)]
+    pub reloaded: Vec<String>,
+    #[serde(default)]
+    pub started: Vec<String>,
+}
+
+impl UnitChanges {
+    /// Picks up what a line of `switch-to-configuration` output says it's doing to units, if it's about that
+    pub fn parse_line(&mut self, line: &str) {
+        let (units, prefix) = match line.find(" the following units: ") {
+            Some(i) => (&line[i + " the following units: ".len()..], &line[..i]),
+            None => match line.find("the following new units were started: ") {
+                Some(i) => (
+                    &line[i + "the following new units were started: ".len()..],
+                    "starting",
+                ),
+                None => return,
+            },
+        };
+
+        let list = match prefix {
+            "stopping" => &mut self.stopped,
+            "restarting" => &mut self.restarted,
+            "reloading" => &mut self.reloaded,
+            "starting" => &mut self.started,
+            _ => return,
+        };
+
+        for unit in units.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            if !list.iter().any(|x| x == unit) {
+                list.push(unit.to_string());
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for UnitChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts: Vec<String> = vec![
+            ("stopped", &self.stopped),
+            ("restarted", &self.restarted),
+            ("reloaded", &self.reloaded),
+            ("started", &self.started),
+        ]
+        .into_iter()
+        .filter(|(_, units)| !units.is_empty())
+        .map(|(action, units)| format!("{} {}", action, units.join(", ")))
+        .collect();
+
+        match parts.is_empty() {
+            true => f.write_str("left all units alone"),
+            false => f.write_str(&parts.join("; ")),
+        }
+    }
+}
+
+#[test]
+fn test_parse_unit_changes() {
+    let mut changes = UnitChanges::default();
+
+    for line in &[
+        "activating the configuration...",
+        "stopping the following units: old-worker.service",
+        "restarting the following units: nginx.service, sshd.service",
+        "reloading the following units: dbus.service",
+        "restarting the following units: nginx.service",
+        "the following new units were started: new-worker.service",
+        "starting the following units: timers.target",
+    ] {
+        changes.parse_line(line);
+    }
+
+    assert_eq!(
+        changes,
+        UnitChanges {
+            changed: vec![],
+            stopped: vec!["old-worker.service".to_string()],
+            restarted: vec!["nginx.service".to_string(), "sshd.service".to_string()],
+            reloaded: vec!["dbus.service".to_string()],
+            started: vec![
+                "new-worker.service".to_string(),
+                "timers.target".to_string()
+            ],
+        }
+    );
+    assert_eq!(
+        changes.to_string(),
+        "stopped old-worker.service; restarted nginx.service, sshd.service; reloaded dbus.service; started new-worker.service, timers.target"
+    );
+    assert_eq!(UnitChanges::default().to_string(), "left all units alone");
+}
+
+/// The unit files of a profile, by name, resolved to where they are in the store
+fn unit_files(profile: &Path) -> BTreeMap<String, std::path::PathBuf> {
+    let dir = match std::fs::read_dir(profile.join("etc/systemd/system")) {
+        Ok(x) => x,
+        Err(_) => return BTreeMap::new(),
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            std::fs::canonicalize(entry.path())
+                .ok()
+                .map(|target| (name, target))
+        })
+        .collect()
+}
+
+/// The units whose files were added, removed or changed going from profile `current` to `new`
+pub fn changed_units(current: &Path, new: &Path) -> Vec<String> {
+    let current = unit_files(current);
+    let new = unit_files(new);
+
+    let mut changed: Vec<String> = new
+        .iter()
+        .filter(|(name, target)| current.get(*name) != Some(target))
+        .map(|(name, _)| name.clone())
+        .chain(
+            current
+                .keys()
+                .filter(|name| !new.contains_key(*name))
+                .cloned(),
+        )
+        .collect();
+    changed.sort_unstable();
+
+    changed
+}
+
+#[test]
+fn test_changed_units() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-units-{}", std::process::id()));
+    let store = dir.join("store");
+    std::fs::create_dir_all(&store).unwrap();
+
+    for (name, contents) in &[
+        ("nginx-a", "a"),
+        ("nginx-b", "b"),
+        ("sshd", "sshd"),
+        ("old", "old"),
+        ("new", "new"),
+    ] {
+        std::fs::write(store.join(name), contents).unwrap();
+    }
+
+    for (profile, units) in &[
+        (
+            "current",
+            vec![
+                ("nginx.service", "nginx-a"),
+                ("sshd.service", "sshd"),
+                ("old.service", "old"),
+            ],
+        ),
+        (
+            "next",
+            vec![
+                ("nginx.service", "nginx-b"),
+                ("sshd.service", "sshd"),
+                ("new.service", "new"),
+            ],
+        ),
+    ] {
+        let units_dir = dir.join(profile).join("etc/systemd/system");
+        std::fs::create_dir_all(&units_dir).unwrap();
+
+        for (unit, target) in units {
+            std::os::unix::fs::symlink(store.join(target), units_dir.join(unit)).unwrap();
+        }
+    }
+
+    assert_eq!(
+        changed_units(&dir.join("current"), &dir.join("next")),
+        vec!["new.service", "nginx.service", "old.service"]
+    );
+    assert_eq!(
+        changed_units(&dir.join("missing"), &dir.join("current")),
+        vec!["nginx.service", "old.service", "sshd.service"]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
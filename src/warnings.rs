@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The log target warnings are logged with, followed by `::` and their code
+pub const TARGET_PREFIX: &str = "deploy::warning";
+
+/// Something about a deployment that's worth looking into, but doesn't stop it like an error does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Warning {
+    /// A node runs a version of Nix older than `MIN_NIX_VERSION`
+    OldNix,
+    /// A node has little space left for its Nix store
+    LowStoreSpace,
+    /// A profile is copied to a node that checks signatures, without a key to sign it with
+    NoSigningKey,
+    /// Profiles are signed with the key `LOCAL_KEY` points to, instead of the `signing.keyFile` setting
+    DeprecatedLocalKey,
+    /// `fastConnection` wasn't set for a node, and was guessed from how fast its connection was measured to be
+    GuessedFastConnection,
+    /// A node's facts couldn't be gathered, so it's deployed to going by its settings alone
+    NoFacts,
+    /// A profile couldn't be pushed to its `pushCache`, which isn't `required`
+    CachePushFailed,
+    /// Evaluating a deployment built derivations to import Nix from, which `--forbid-ifd` doesn't allow
+    ImportFromDerivation,
+    /// The heap of an evaluation grew past `LARGE_EVALUATION_BYTES`
+    LargeEvaluation,
+    /// A node's clock is further off than `DEFAULT_MAX_CLOCK_SKEW`, and `maxClockSkew` isn't set
+    ClockSkew,
+}
+
+const WARNINGS: [Warning; 10] = [
+    Warning::OldNix,
+    Warning::LowStoreSpace,
+    Warning::NoSigningKey,
+    Warning::DeprecatedLocalKey,
+    Warning::GuessedFastConnection,
+    Warning::NoFacts,
+    Warning::CachePushFailed,
+    Warning::ImportFromDerivation,
+    Warning::LargeEvaluation,
+    Warning::ClockSkew,
+];
+
+/// How many warnings of each kind were raised, in the order of `WARNINGS`
+static RAISED: [AtomicUsize; 10] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// The oldest version of Nix on nodes that isn't warned about
+pub const MIN_NIX_VERSION: (u32, u32) = (2, 4);
+
+/// How big the heap of an evaluation may get before it's warned about, 4 GiB
+pub const LARGE_EVALUATION_BYTES: u64 = 4 << 30;
+
+/// How far, in seconds, a node's clock may be off before it's warned about when `maxClockSkew` isn't set
+pub const DEFAULT_MAX_CLOCK_SKEW: u64 = 60;
+
+impl Warning {
+    /// What the warning is called in the logs and in `--log-file` records
+    pub fn code(self) -> &'static str {
+        match self {
+            Warning::OldNix => "old-nix",
+            Warning::LowStoreSpace => "low-store-space",
+            Warning::NoSigningKey => "no-signing-key",
+            Warning::DeprecatedLocalKey => "deprecated-local-key",
+            Warning::GuessedFastConnection => "guessed-fast-connection",
+            Warning::NoFacts => "no-facts",
+            Warning::CachePushFailed => "cache-push-failed",
+            Warning::ImportFromDerivation => "import-from-derivation",
+            Warning::LargeEvaluation => "large-evaluation",
+            Warning::ClockSkew => "clock-skew",
+        }
+    }
+
+    fn index(self) -> usize {
+        WARNINGS.iter().position(|x| *x == self).unwrap_or(0)
+    }
+
+    /// The log target of this warning, which `--log-file` records keep as its code
+    pub fn target(self) -> String {
+        format!("{}::{}", TARGET_PREFIX, self.code())
+    }
+}
+
+/// The code of the warning a log record with target `target` is, if it's one
+pub fn code_of_target(target: &str) -> Option<&str> {
+    target
+        .strip_prefix(TARGET_PREFIX)
+        .and_then(|x| x.strip_prefix("::"))
+}
+
+/// Logs a warning and counts it, for the summary at the end and `--deny-warnings`
+pub fn raise(warning: Warning, message: std::fmt::Arguments) {
+    RAISED[warning.index()].fetch_add(1, Ordering::Relaxed);
+
+    log::warn!(target: &warning.target(), "{}", message);
+}
+
+/// How many warnings were raised so far, by kind, leaving out the kinds that weren't
+pub fn raised() -> Vec<(Warning, usize)> {
+    WARNINGS
+        .iter()
+        .map(|warning| (*warning, RAISED[warning.index()].load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+/// A summary of the warnings raised, like `2 warnings: old-nix (1), no-facts (1)`
+pub fn summary(raised: &[(Warning, usize)]) -> String {
+    let total: usize = raised.iter().map(|(_, count)| count).sum();
+
+    format!(
+        "{} warning{}: {}",
+        total,
+        if total == 1 { "" } else { "s" },
+        raised
+            .iter()
+            .map(|(warning, count)| format!("{} ({})", warning.code(), count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+/// Whether a version of Nix, as `nix --version` gives it, is older than `MIN_NIX_VERSION`
+pub fn is_old_nix(version: &str) -> bool {
+    let mut parts = version.split('.').map(|x| x.parse::<u32>().ok());
+
+    match (parts.next().flatten(), parts.next().flatten()) {
+        (Some(major), Some(minor)) => (major, minor) < MIN_NIX_VERSION,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_warnings() {
+    assert!(is_old_nix("2.3.16"));
+    assert!(!is_old_nix("2.4"));
+    assert!(!is_old_nix("2.18.1"));
+    assert!(!is_old_nix("unknown"));
+
+    assert_eq!(code_of_target("deploy::warning::old-nix"), Some("old-nix"));
+    assert_eq!(code_of_target("deploy::push"), None);
+
+    assert_eq!(
+        summary(&[(Warning::OldNix, 1), (Warning::NoFacts, 2)]),
+        "3 warnings: old-nix (1), no-facts (2)"
+    );
+    assert_eq!(
+        summary(&[(Warning::NoSigningKey, 1)]),
+        "1 warning: no-signing-key (1)"
+    );
+}
@@ -0,0 +1,193 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::str::FromStr;
+use thiserror::Error;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+const MINUTES_PER_WEEK: u64 = 7 * MINUTES_PER_DAY;
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ParseWindowError {
+    #[error("Maintenance window `{0}` is not of the form `Sat 02:00-04:00 UTC`")]
+    Format(String),
+    #[error("Unknown day `{0}` in maintenance window, expected one of Mon, Tue, Wed, Thu, Fri, Sat or Sun")]
+    Day(String),
+    #[error("Invalid time `{0}` in maintenance window, expected HH:MM")]
+    Time(String),
+    #[error("Maintenance window is in `{0}`, but only UTC is supported")]
+    TimeZone(String),
+}
+
+/// When a node may be activated, e.g. `Sat,Sun 02:00-04:00 UTC`
+///
+/// A window which ends before it starts runs over midnight into the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    /// Days the window opens on, Monday being 0
+    pub days: Vec<u64>,
+    /// Minutes after midnight the window opens at
+    pub start: u64,
+    /// How many minutes the window stays open
+    pub length: u64,
+}
+
+fn parse_time(s: &str) -> Result<u64, ParseWindowError> {
+    let mut parts = s.splitn(2, ':');
+
+    let hours = parts.next().and_then(|x| x.parse::<u64>().ok());
+    let minutes = parts.next().and_then(|x| x.parse::<u64>().ok());
+
+    match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => Ok(hours * 60 + minutes),
+        _ => Err(ParseWindowError::Time(s.to_string())),
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = ParseWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+
+        let (days, times, zone) = match fields[..] {
+            [days, times, zone] => (days, times, zone),
+            _ => return Err(ParseWindowError::Format(s.to_string())),
+        };
+
+        if zone != "UTC" {
+            return Err(ParseWindowError::TimeZone(zone.to_string()));
+        }
+
+        let days = days
+            .split(',')
+            .map(
+                |day| match DAYS.iter().position(|x| x.eq_ignore_ascii_case(day)) {
+                    Some(i) => Ok(i as u64),
+                    None => Err(ParseWindowError::Day(day.to_string())),
+                },
+            )
+            .collect::<Result<Vec<u64>, ParseWindowError>>()?;
+
+        let mut times = times.splitn(2, '-');
+        let (start, end) = match (times.next(), times.next()) {
+            (Some(start), Some(end)) => (parse_time(start)?, parse_time(end)?),
+            _ => return Err(ParseWindowError::Format(s.to_string())),
+        };
+
+        Ok(MaintenanceWindow {
+            days,
+            start,
+            length: match (end + MINUTES_PER_DAY - start) % MINUTES_PER_DAY {
+                0 => MINUTES_PER_DAY,
+                x => x,
+            },
+        })
+    }
+}
+
+/// Minutes since Monday 00:00 UTC of the week `timestamp` (seconds since the epoch) falls in
+fn minute_of_week(timestamp: u64) -> u64 {
+    // The epoch was a Thursday
+    (timestamp / 60 + 3 * MINUTES_PER_DAY) % MINUTES_PER_WEEK
+}
+
+impl MaintenanceWindow {
+    fn openings(&self) -> impl Iterator<Item = u64> + '_ {
+        self.days
+            .iter()
+            .map(move |day| day * MINUTES_PER_DAY + self.start)
+    }
+
+    pub fn contains(&self, timestamp: u64) -> bool {
+        let now = minute_of_week(timestamp);
+
+        self.openings()
+            .any(|opening| (now + MINUTES_PER_WEEK - opening) % MINUTES_PER_WEEK < self.length)
+    }
+
+    /// When the window opens next after `timestamp`, in seconds since the epoch
+    pub fn next_opening(&self, timestamp: u64) -> u64 {
+        let now = minute_of_week(timestamp);
+
+        let wait = self
+            .openings()
+            .map(
+                |opening| match (opening + MINUTES_PER_WEEK - now) % MINUTES_PER_WEEK {
+                    0 => MINUTES_PER_WEEK,
+                    x => x,
+                },
+            )
+            .min()
+            .unwrap_or(0);
+
+        (timestamp / 60 + wait) * 60
+    }
+}
+
+/// Formats a timestamp as a `systemd.time` calendar event, `2021-03-06 02:00:00 UTC`
+pub fn calendar_event(timestamp: u64) -> String {
+    // Converting days to a civil date, from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds = timestamp % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[test]
+fn test_maintenance_window() {
+    let window: MaintenanceWindow = "Sat 02:00-04:00 UTC".parse().unwrap();
+    assert_eq!(
+        window,
+        MaintenanceWindow {
+            days: vec![5],
+            start: 120,
+            length: 120,
+        }
+    );
+
+    // Saturday 2021-03-06 03:00 UTC, and the Friday before it at the same time
+    assert!(window.contains(1_614_999_600));
+    assert!(!window.contains(1_614_913_200));
+    assert_eq!(window.next_opening(1_614_913_200), 1_614_996_000);
+    assert_eq!(calendar_event(1_614_996_000), "2021-03-06 02:00:00 UTC");
+
+    // Running over midnight, Saturday 2021-03-06 23:30 UTC
+    let window: MaintenanceWindow = "fri,sat 22:00-01:00 UTC".parse().unwrap();
+    assert!(window.contains(1_615_073_400));
+    assert_eq!(window.next_opening(1_615_073_400), 1_615_586_400);
+
+    assert_eq!(
+        "Sat 02:00-04:00 CET".parse::<MaintenanceWindow>(),
+        Err(ParseWindowError::TimeZone("CET".to_string()))
+    );
+    assert_eq!(
+        "Sab 02:00-04:00 UTC".parse::<MaintenanceWindow>(),
+        Err(ParseWindowError::Day("Sab".to_string()))
+    );
+    assert_eq!(
+        "Sat 02:00-25:00 UTC".parse::<MaintenanceWindow>(),
+        Err(ParseWindowError::Time("25:00".to_string()))
+    );
+}